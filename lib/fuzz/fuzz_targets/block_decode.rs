@@ -0,0 +1,18 @@
+#![no_main]
+use btclib::encoding::{Decode, Encode};
+use btclib::types::Block;
+use btclib::util::Saveable;
+use libfuzzer_sys::fuzz_target;
+
+// blocks come from peers in the canonical encoding and from files and
+// the database as CBOR; neither may panic on bad input, and the
+// canonical encoding of a block is unique
+fuzz_target!(|data: &[u8]| {
+    if let Ok(block) = Block::from_bytes(data) {
+        assert_eq!(block.to_bytes(), data);
+        block.hash();
+    }
+    if let Ok(block) = Block::load(data) {
+        Block::from_bytes(&block.to_bytes()).expect("encoded block decodes");
+    }
+});
@@ -0,0 +1,15 @@
+#![no_main]
+use btclib::network::{Envelope, WireFormat};
+use libfuzzer_sys::fuzz_target;
+
+// whatever a peer sends must decode or be refused without panicking,
+// and what decodes must survive another trip in either format
+fuzz_target!(|data: &[u8]| {
+    let Ok(envelope) = Envelope::decode(data) else {
+        return;
+    };
+    let cbor = envelope.encode_as(WireFormat::Cbor).expect("decoded envelope encodes");
+    Envelope::decode(&cbor).expect("encoded envelope decodes");
+    let compact = envelope.encode_as(WireFormat::Compact).expect("decoded envelope encodes");
+    Envelope::decode(&compact).expect("encoded envelope decodes");
+});
@@ -0,0 +1,224 @@
+use crate::crypto::PublicKey;
+use crate::error::{BtcError, Result};
+use crate::params::{ChainParams, Network};
+use bech32::{Bech32m, Hrp};
+use ripemd::Ripemd160;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use alloc::string::String;
+use alloc::vec;
+use core::str::FromStr;
+
+/// How addresses are written out. Both formats are always accepted, this
+/// only picks the one addresses are shown in.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AddressFormat {
+    /// Bitcoin-style Base58Check, e.g. `1…`
+    #[default]
+    Base58,
+    /// Bech32m with the chain's human-readable part, e.g. `grp1…`
+    Bech32,
+}
+
+/// What an address pays to, independent of how and for which network
+/// it is written
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Address {
+    /// RIPEMD160(SHA256) of a compressed public key
+    Key([u8; 20]),
+    /// RIPEMD160(SHA256) of a multisig policy
+    Multisig([u8; 20]),
+}
+
+const BECH32_KEY: u8 = 0x00;
+const BECH32_MULTISIG: u8 = 0x05;
+
+/// RIPEMD160(SHA256) of `payload`, the 20 byte hash addresses carry
+pub(crate) fn hash160(payload: &[u8]) -> [u8; 20] {
+    Ripemd160::digest(Sha256::digest(payload)).into()
+}
+
+impl Address {
+    pub fn from_public_key(public_key: &PublicKey) -> Self {
+        Address::Key(hash160(&public_key.to_compressed_bytes()))
+    }
+
+    /// Parse an address in either format, returning the network it
+    /// belongs to
+    pub fn decode(s: &str) -> Result<(Network, Self)> {
+        match bech32::decode(s) {
+            Ok((hrp, data)) => Self::from_bech32(hrp, &data),
+            Err(_) => Self::from_base58(s),
+        }
+    }
+
+    /// Parse an address of any known network
+    pub fn parse(s: &str) -> Result<Self> {
+        Self::decode(s).map(|(_, address)| address)
+    }
+
+    /// Parse an address, which must belong to the chain of `params`
+    pub fn parse_for(s: &str, params: &ChainParams) -> Result<Self> {
+        let (network, address) = Self::decode(s)?;
+        if network != params.network {
+            return Err(BtcError::WrongNetwork);
+        }
+        Ok(address)
+    }
+
+    fn from_base58(s: &str) -> Result<(Network, Self)> {
+        let decoded = bs58::decode(s)
+            .into_vec()
+            .map_err(|_| BtcError::InvalidAddress)?;
+        // version + hash + checksum
+        if decoded.len() != 25 {
+            return Err(BtcError::InvalidAddress);
+        }
+        let (version_and_hash, checksum) = decoded.split_at(21);
+        if checksum != &Sha256::digest(Sha256::digest(version_and_hash))[..4] {
+            return Err(BtcError::InvalidAddress);
+        }
+        let (version, hash) = (version_and_hash[0], &version_and_hash[1..]);
+        for network in Network::ALL {
+            let params = network.params();
+            if version == params.address_version {
+                return Ok((network, Address::Key(Self::hash_from(hash)?)));
+            }
+            if version == params.multisig_address_version {
+                return Ok((network, Address::Multisig(Self::hash_from(hash)?)));
+            }
+        }
+        Err(BtcError::InvalidAddress)
+    }
+
+    // the data is a kind byte, the mainnet version byte of the address
+    // kind, followed by the hash
+    fn from_bech32(hrp: Hrp, data: &[u8]) -> Result<(Network, Self)> {
+        // all-uppercase addresses are valid bech32 too
+        let hrp = hrp.to_lowercase();
+        let network = Network::ALL
+            .into_iter()
+            .find(|network| network.params().bech32_hrp == hrp)
+            .ok_or(BtcError::InvalidAddress)?;
+        let (&kind, hash) = data.split_first().ok_or(BtcError::InvalidAddress)?;
+        let hash = Self::hash_from(hash)?;
+        match kind {
+            BECH32_KEY => Ok((network, Address::Key(hash))),
+            BECH32_MULTISIG => Ok((network, Address::Multisig(hash))),
+            _ => Err(BtcError::InvalidAddress),
+        }
+    }
+
+    fn hash_from(bytes: &[u8]) -> Result<[u8; 20]> {
+        bytes.try_into().map_err(|_| BtcError::InvalidAddress)
+    }
+
+    fn hash(&self) -> &[u8; 20] {
+        match self {
+            Address::Key(hash) | Address::Multisig(hash) => hash,
+        }
+    }
+
+    /// Base58Check of the chain's version byte and the hash
+    pub fn to_base58(&self, params: &ChainParams) -> String {
+        let version = match self {
+            Address::Key(_) => params.address_version,
+            Address::Multisig(_) => params.multisig_address_version,
+        };
+        let mut bytes = vec![version];
+        bytes.extend_from_slice(self.hash());
+        // checksum: first 4 bytes of double SHA256 of version + hash
+        let checksum = Sha256::digest(Sha256::digest(&bytes));
+        bytes.extend_from_slice(&checksum[..4]);
+        bs58::encode(&bytes).into_string()
+    }
+
+    /// Bech32m of the kind byte and hash under the chain's
+    /// human-readable part
+    pub fn to_bech32(&self, params: &ChainParams) -> String {
+        let hrp = Hrp::parse(params.bech32_hrp).expect("chain params have a valid bech32 hrp");
+        let kind = match self {
+            Address::Key(_) => BECH32_KEY,
+            Address::Multisig(_) => BECH32_MULTISIG,
+        };
+        let mut data = vec![kind];
+        data.extend_from_slice(self.hash());
+        bech32::encode::<Bech32m>(hrp, &data).expect("address fits in a bech32 string")
+    }
+
+    pub fn encode(&self, format: AddressFormat, params: &ChainParams) -> String {
+        match format {
+            AddressFormat::Base58 => self.to_base58(params),
+            AddressFormat::Bech32 => self.to_bech32(params),
+        }
+    }
+
+    /// Whether two address strings pay to the same thing, whatever
+    /// format or network each is written for
+    pub fn same(a: &str, b: &str) -> bool {
+        a == b
+            || matches!((Address::parse(a), Address::parse(b)), (Ok(a), Ok(b)) if a == b)
+    }
+}
+
+impl FromStr for Address {
+    type Err = BtcError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Address::parse(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::PrivateKey;
+
+    #[test]
+    fn test_formats_round_trip() {
+        let address = Address::from_public_key(&PrivateKey::new_key().public_key());
+        let mainnet = ChainParams::mainnet();
+        let bech32 = address.to_bech32(&mainnet);
+        assert!(bech32.starts_with("grp1"));
+        assert!(address.to_bech32(&ChainParams::testnet()).starts_with("tgrp1"));
+
+        assert_eq!(Address::parse(&bech32).unwrap(), address);
+        assert_eq!(Address::parse(&address.to_base58(&mainnet)).unwrap(), address);
+        assert!(Address::same(&bech32, &address.to_base58(&mainnet)));
+        assert!(Address::parse(&bech32.to_uppercase()).is_ok());
+    }
+
+    #[test]
+    fn test_addresses_belong_to_one_network() {
+        let address = Address::from_public_key(&PrivateKey::new_key().public_key());
+        let (mainnet, testnet) = (ChainParams::mainnet(), ChainParams::testnet());
+        let testnet_base58 = address.to_base58(&testnet);
+        assert_ne!(testnet_base58, address.to_base58(&mainnet));
+        assert!(testnet_base58.starts_with(['m', 'n']));
+
+        for encoded in [testnet_base58, address.to_bech32(&testnet)] {
+            assert_eq!(Address::decode(&encoded).unwrap(), (Network::Testnet, address));
+            assert_eq!(Address::parse_for(&encoded, &testnet).unwrap(), address);
+            assert!(matches!(
+                Address::parse_for(&encoded, &mainnet),
+                Err(BtcError::WrongNetwork)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_rejects_invalid_addresses() {
+        let address = Address::from_public_key(&PrivateKey::new_key().public_key());
+        let mut bech32 = address.to_bech32(&ChainParams::mainnet());
+        // flip the last checksum character
+        let last = if bech32.ends_with('q') { 'p' } else { 'q' };
+        bech32.pop();
+        bech32.push(last);
+        assert!(Address::parse(&bech32).is_err());
+
+        let hrp = Hrp::parse("btc").unwrap();
+        let other_chain = bech32::encode::<Bech32m>(hrp, &[0; 21]).unwrap();
+        assert!(Address::parse(&other_chain).is_err());
+        assert!(Address::parse("not an address").is_err());
+    }
+}
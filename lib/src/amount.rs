@@ -0,0 +1,117 @@
+//! Exact formatting and parsing of amounts. Amounts are always kept
+//! as integer satoshis, floats are never involved.
+
+use crate::error::{BtcError, Result};
+use alloc::format;
+use alloc::string::{String, ToString};
+
+pub const SATS_PER_BTC: u64 = 100_000_000;
+// decimal places of a BTC amount
+const BTC_DECIMALS: usize = 8;
+
+// group the digits of an integer in thousands, e.g. 1234567 -> 1,234,567
+fn group_thousands(value: u64) -> String {
+    let digits = value.to_string();
+    let mut grouped = String::with_capacity(digits.len() + digits.len() / 3);
+    for (idx, digit) in digits.chars().enumerate() {
+        if idx > 0 && (digits.len() - idx).is_multiple_of(3) {
+            grouped.push(',');
+        }
+        grouped.push(digit);
+    }
+    grouped
+}
+
+/// Format satoshis as BTC with exactly 8 decimal places and thousands
+/// separators, e.g. 123456789012 -> "1,234.56789012"
+pub fn format_btc(sats: u64) -> String {
+    format!(
+        "{}.{:0width$}",
+        group_thousands(sats / SATS_PER_BTC),
+        sats % SATS_PER_BTC,
+        width = BTC_DECIMALS
+    )
+}
+
+/// Format satoshis with thousands separators, e.g. 1234567 -> "1,234,567"
+pub fn format_sats(sats: u64) -> String {
+    group_thousands(sats)
+}
+
+// parse digits, allowing the thousands separators format_* produces
+fn parse_integer(s: &str) -> Result<u64> {
+    let digits: String = s.chars().filter(|c| *c != ',').collect();
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return Err(BtcError::InvalidAmount);
+    }
+    digits.parse().map_err(|_| BtcError::InvalidAmount)
+}
+
+/// Parse a BTC amount with up to 8 decimal places into satoshis.
+/// Accepts the output of `format_btc`.
+pub fn parse_btc(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let (whole, fraction) = s.split_once('.').unwrap_or((s, ""));
+    if fraction.len() > BTC_DECIMALS || !fraction.chars().all(|c| c.is_ascii_digit()) {
+        return Err(BtcError::InvalidAmount);
+    }
+    let whole = if whole.is_empty() && !fraction.is_empty() {
+        0
+    } else {
+        parse_integer(whole)?
+    };
+    let fraction = if fraction.is_empty() {
+        0
+    } else {
+        format!("{:0<width$}", fraction, width = BTC_DECIMALS)
+            .parse::<u64>()
+            .map_err(|_| BtcError::InvalidAmount)?
+    };
+    whole
+        .checked_mul(SATS_PER_BTC)
+        .and_then(|sats| sats.checked_add(fraction))
+        .ok_or(BtcError::InvalidAmount)
+}
+
+/// Parse a whole number of satoshis. Accepts the output of
+/// `format_sats`.
+pub fn parse_sats(s: &str) -> Result<u64> {
+    parse_integer(s.trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_btc() {
+        assert_eq!(format_btc(0), "0.00000000");
+        assert_eq!(format_btc(1), "0.00000001");
+        assert_eq!(format_btc(50 * SATS_PER_BTC), "50.00000000");
+        assert_eq!(format_btc(123_456_789_012), "1,234.56789012");
+        assert_eq!(format_btc(u64::MAX), "184,467,440,737.09551615");
+        assert_eq!(format_sats(1_234_567), "1,234,567");
+        assert_eq!(format_sats(999), "999");
+    }
+
+    #[test]
+    fn test_parse_btc() {
+        assert_eq!(parse_btc("1").unwrap(), SATS_PER_BTC);
+        assert_eq!(parse_btc("0.5").unwrap(), 50_000_000);
+        assert_eq!(parse_btc(".00000001").unwrap(), 1);
+        assert_eq!(parse_btc(" 1,234.5 ").unwrap(), 123_450_000_000);
+        for invalid in ["", ".", "-1", "1.000000001", "1e8", "abc", "1.2.3", "184467440738"] {
+            assert!(parse_btc(invalid).is_err(), "{invalid:?} should not parse");
+        }
+        assert_eq!(parse_sats("1,000").unwrap(), 1000);
+        assert!(parse_sats("1.5").is_err());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for sats in [0, 1, 99_999_999, 100_000_000, 123_456_789_012, u64::MAX] {
+            assert_eq!(parse_btc(&format_btc(sats)).unwrap(), sats);
+            assert_eq!(parse_sats(&format_sats(sats)).unwrap(), sats);
+        }
+    }
+}
@@ -0,0 +1,186 @@
+//! Protocol between a wallet and a signer running in another process,
+//! which holds the keys and asks its user before signing anything, the
+//! way a hardware wallet does. The wallet connects over a Unix socket
+//! (`unix:<path>`) or TCP (`<host>:<port>`), sends one
+//! `SigningRequest` and reads one `SigningResponse`, each as a CBOR
+//! value behind a 8 byte big endian length.
+use crate::amount::format_btc;
+use crate::crypto::{PublicKey, Signature};
+use crate::sha256::Hash;
+use crate::types::PartiallySignedTransaction;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+/// Longest message either side takes
+pub const MAX_MESSAGE: u64 = 1 << 20;
+
+/// One signature asked for. Inputs sign the hash of the output they
+/// spend, which the signer may check against the summary.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Sighash {
+    pub hash: Hash,
+    pub key: PublicKey,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SigningRequest {
+    /// What the transaction does, shown to the user as is
+    pub summary: String,
+    /// Every signature the transaction still needs, by any key. The
+    /// signer answers for the keys it holds.
+    pub sighashes: Vec<Sighash>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum SigningResponse {
+    /// Signatures for some of the requested sighashes, by index
+    Approved(Vec<(usize, Signature)>),
+    Rejected(String),
+}
+
+impl SigningRequest {
+    /// Ask for the signatures `psbt` is missing
+    pub fn for_psbt(psbt: &PartiallySignedTransaction) -> Self {
+        let mut sighashes = vec![];
+        for (input, psbt_input) in psbt.unsigned.inputs.iter().zip(&psbt.inputs) {
+            if psbt_input.final_signatures.is_some() {
+                continue;
+            }
+            let keys = match &input.multisig {
+                Some(policy) => policy.public_keys().to_vec(),
+                None => vec![input.public_key.clone()],
+            };
+            for key in keys {
+                if psbt_input.partial_signatures.iter().all(|(signed, _)| *signed != key) {
+                    sighashes.push(Sighash {
+                        hash: input.prev_transaction_output_hash,
+                        key,
+                    });
+                }
+            }
+        }
+        Self {
+            summary: summarize(psbt),
+            sighashes,
+        }
+    }
+}
+
+/// The spent outputs, the payments and the fee of a transaction
+pub fn summarize(psbt: &PartiallySignedTransaction) -> String {
+    let mut summary = String::new();
+    let _ = writeln!(summary, "Spending {} inputs:", psbt.inputs.len());
+    for input in &psbt.inputs {
+        let _ = writeln!(summary, "  {}: {} BTC", input.utxo.address, format_btc(input.utxo.value));
+    }
+    let _ = writeln!(summary, "Paying:");
+    for output in &psbt.unsigned.outputs {
+        let _ = writeln!(summary, "  {}: {} BTC", output.address, format_btc(output.value));
+    }
+    let fee = psbt
+        .unsigned
+        .input_value()
+        .saturating_sub(psbt.unsigned.output_value());
+    let _ = write!(summary, "Fee: {} BTC", format_btc(fee));
+    summary
+}
+
+pub fn write_message<T: Serialize>(stream: &mut impl Write, message: &T) -> io::Result<()> {
+    let mut bytes = vec![];
+    ciborium::into_writer(message, &mut bytes).map_err(io::Error::other)?;
+    stream.write_all(&(bytes.len() as u64).to_be_bytes())?;
+    stream.write_all(&bytes)?;
+    stream.flush()
+}
+
+pub fn read_message<T: DeserializeOwned>(stream: &mut impl Read) -> io::Result<T> {
+    let mut len = [0u8; 8];
+    stream.read_exact(&mut len)?;
+    let len = u64::from_be_bytes(len);
+    if len > MAX_MESSAGE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "message too long"));
+    }
+    let mut bytes = vec![0u8; len as usize];
+    stream.read_exact(&mut bytes)?;
+    ciborium::from_reader(bytes.as_slice()).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Send a request to the signer at `address` and wait up to `timeout`
+/// for the user to decide
+pub fn request_signatures(address: &str, request: &SigningRequest, timeout: Duration) -> io::Result<SigningResponse> {
+    if let Some(path) = address.strip_prefix("unix:") {
+        #[cfg(unix)]
+        {
+            let mut stream = std::os::unix::net::UnixStream::connect(path)?;
+            stream.set_read_timeout(Some(timeout))?;
+            write_message(&mut stream, request)?;
+            return read_message(&mut stream);
+        }
+        #[cfg(not(unix))]
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("no Unix sockets on this platform for {path}"),
+        ));
+    }
+    let mut stream = TcpStream::connect(address)?;
+    stream.set_read_timeout(Some(timeout))?;
+    write_message(&mut stream, request)?;
+    read_message(&mut stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::PrivateKey;
+    use crate::types::{TransactionOutput, UnsignedInput, UnsignedTransaction};
+    use uuid::Uuid;
+
+    #[test]
+    fn test_request_asks_for_missing_signatures() {
+        let (a, b) = (PrivateKey::new_key(), PrivateKey::new_key());
+        let utxos: Vec<TransactionOutput> = [&a, &b]
+            .iter()
+            .map(|key| TransactionOutput {
+                value: 1000,
+                unique_id: Uuid::new_v4(),
+                address: key.public_key().to_address(),
+            })
+            .collect();
+        let inputs = [&a, &b]
+            .iter()
+            .zip(&utxos)
+            .map(|(key, utxo)| UnsignedInput {
+                prev_transaction_output_hash: utxo.hash(),
+                public_key: key.public_key(),
+                value: utxo.value,
+                multisig: None,
+            })
+            .collect();
+        let outputs = vec![TransactionOutput {
+            value: 1500,
+            unique_id: Uuid::new_v4(),
+            address: a.public_key().to_address(),
+        }];
+        let mut psbt = PartiallySignedTransaction::new(UnsignedTransaction { inputs, outputs }, utxos.clone()).unwrap();
+        psbt.sign(&a);
+
+        let request = SigningRequest::for_psbt(&psbt);
+        assert_eq!(
+            request.sighashes,
+            [Sighash {
+                hash: utxos[1].hash(),
+                key: b.public_key(),
+            }]
+        );
+        assert!(request.summary.ends_with("Fee: 0.00000500 BTC"));
+
+        let mut bytes = vec![];
+        write_message(&mut bytes, &request).unwrap();
+        let decoded: SigningRequest = read_message(&mut bytes.as_slice()).unwrap();
+        assert_eq!(decoded.sighashes, request.sighashes);
+    }
+}
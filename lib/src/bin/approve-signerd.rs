@@ -0,0 +1,99 @@
+use btclib::approval::{SigningRequest, SigningResponse, read_message, write_message};
+use btclib::crypto::{PrivateKey, Signature};
+use btclib::util::Saveable;
+use std::env;
+use std::io::{self, Read, Write};
+use std::net::TcpListener;
+
+const USAGE: &str = "Usage: approve-signerd <unix:<socket path>|<host>:<port>> <private key>...";
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", USAGE);
+    eprintln!("Error: {}", message);
+    std::process::exit(1);
+}
+
+/// Show a request on this terminal and sign what our keys can sign if
+/// the user agrees
+fn answer(request: &SigningRequest, keys: &[PrivateKey]) -> SigningResponse {
+    let signable: Vec<(usize, &PrivateKey)> = request
+        .sighashes
+        .iter()
+        .enumerate()
+        .filter_map(|(i, sighash)| {
+            keys.iter()
+                .find(|key| key.public_key() == sighash.key)
+                .map(|key| (i, key))
+        })
+        .collect();
+    if signable.is_empty() {
+        println!("Ignoring a request none of our keys can sign");
+        return SigningResponse::Rejected("no keys for this transaction".to_string());
+    }
+
+    println!("\n{}", request.summary);
+    print!("Sign {} of {} inputs? [y/N] ", signable.len(), request.sighashes.len());
+    io::stdout().flush().unwrap();
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).expect("Failed to read input");
+    if !answer.trim().eq_ignore_ascii_case("y") {
+        println!("Rejected");
+        return SigningResponse::Rejected("rejected by the signer".to_string());
+    }
+    let signatures = signable
+        .into_iter()
+        .map(|(i, key)| (i, Signature::sign_output(&request.sighashes[i].hash, key)))
+        .collect();
+    println!("Signed");
+    SigningResponse::Approved(signatures)
+}
+
+fn serve(mut stream: impl Read + Write, keys: &[PrivateKey]) {
+    let request: SigningRequest = match read_message(&mut stream) {
+        Ok(request) => request,
+        Err(e) => {
+            eprintln!("Bad request: {e}");
+            return;
+        }
+    };
+    if let Err(e) = write_message(&mut stream, &answer(&request, keys)) {
+        eprintln!("Failed to answer the wallet: {e}");
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() < 2 {
+        fail("expected an address to listen on and at least one key");
+    }
+    let keys: Vec<PrivateKey> = args[1..]
+        .iter()
+        .map(|path| {
+            PrivateKey::load_from_file(path).unwrap_or_else(|e| fail(&format!("failed to load {path}: {e}")))
+        })
+        .collect();
+
+    // requests are taken one at a time, there is only one terminal to
+    // approve them on
+    if let Some(path) = args[0].strip_prefix("unix:") {
+        #[cfg(unix)]
+        {
+            let _ = std::fs::remove_file(path);
+            let listener = std::os::unix::net::UnixListener::bind(path)
+                .unwrap_or_else(|e| fail(&format!("failed to listen on {path}: {e}")));
+            println!("Waiting for signing requests on {path}");
+            for stream in listener.incoming().flatten() {
+                serve(stream, &keys);
+            }
+        }
+        #[cfg(not(unix))]
+        fail(&format!("no Unix sockets on this platform for {path}"));
+    } else {
+        let listener =
+            TcpListener::bind(&args[0]).unwrap_or_else(|e| fail(&format!("failed to listen on {}: {e}", args[0])));
+        println!("Waiting for signing requests on {}", args[0]);
+        for stream in listener.incoming().flatten() {
+            serve(stream, &keys);
+        }
+    }
+}
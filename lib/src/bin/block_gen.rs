@@ -1,49 +1,121 @@
 use btclib::{
+    amount::{format_btc, parse_btc},
     crypto::PrivateKey,
+    network::{Envelope, Message},
     sha256::Hash,
     types::{Block, BlockHeader, Transaction, TransactionOutput},
     util::{MerkleRoot, Saveable},
+    U256,
 };
 use chrono::Utc;
 use std::env;
+use std::fs::{self, File};
+use std::net::TcpStream;
 use uuid::Uuid;
 
+const USAGE: &str = "Usage: block_gen <path to block file> <path to private key> \
+    [--prev <hash>] [--target <hex>] [--txs <dir>] [--height <n>] [--fees <BTC>] [--submit <node address>]";
+// nonces tried between progress reports
+const MINE_STEPS: usize = 1_000_000;
+const DEFAULT_TTL: u8 = 8;
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", USAGE);
+    eprintln!("Error: {}", message);
+    std::process::exit(1);
+}
+
+/// Every transaction file in `dir`, in file name order
+fn load_transactions(dir: &str) -> Vec<Transaction> {
+    let mut paths: Vec<_> = fs::read_dir(dir)
+        .unwrap_or_else(|e| fail(&format!("Failed to read {dir}: {e}")))
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| path.is_file())
+        .collect();
+    paths.sort();
+    paths
+        .iter()
+        .map(|path| {
+            let file = File::open(path).expect("Failed to open transaction file");
+            Transaction::load(file)
+                .unwrap_or_else(|_| fail(&format!("{} is not a transaction", path.display())))
+        })
+        .collect()
+}
+
 fn main() {
-    let path = if let Some(arg) = env::args().nth(1) {
-        arg
-    } else {
-        eprintln!("Usage: block_gen <path to block file> <path to private key>");
-        std::process::exit(1);
+    let mut args = env::args().skip(1);
+    let (Some(path), Some(key_path)) = (args.next(), args.next()) else {
+        fail("Block and private key file paths are required");
     };
 
-    let key_path = if let Some(key_path) = env::args().nth(2) {
-        key_path
-    } else {
-        eprintln!("Usage: block_gen <path to block file> <path to private key>");
-        eprintln!("Error: Private key file path is required");
-        std::process::exit(1);
-    };
+    let mut prev = Hash::zero();
+    let mut target = btclib::MIN_TARGET;
+    let mut transactions = vec![];
+    let mut height = 0;
+    let mut fees = 0;
+    let mut submit = None;
+    while let Some(arg) = args.next() {
+        let value = args.next().unwrap_or_else(|| fail(&format!("{arg} needs a value")));
+        match arg.as_str() {
+            "--prev" => prev = value.parse().unwrap_or_else(|_| fail(&format!("Invalid hash {value}"))),
+            "--target" => {
+                target = U256::from_str_radix(&value, 16)
+                    .unwrap_or_else(|_| fail(&format!("Invalid target {value}, expected hex")))
+            }
+            "--txs" => transactions.extend(load_transactions(&value)),
+            "--height" => height = value.parse().unwrap_or_else(|_| fail(&format!("Invalid height {value}"))),
+            "--fees" => fees = parse_btc(&value).unwrap_or_else(|_| fail(&format!("Invalid fees {value}"))),
+            "--submit" => submit = Some(value),
+            _ => fail(&format!("Unknown option {arg}")),
+        }
+    }
 
     let private_key = PrivateKey::load_from_file(&key_path)
         .expect("Failed to load private key from file");
     let public_key = private_key.public_key();
     let address = public_key.to_address();
-    let transactions = vec![Transaction::new(
-        vec![],
-        vec![TransactionOutput {
-            unique_id: Uuid::new_v4(),
-            value: btclib::INITIAL_REWARD * 10u64.pow(8),
-            address,
-        }],
-    )];
+    // the coinbase claims the reward at `height` and the fees of the
+    // transactions, which only the spent outputs could tell
+    let reward = btclib::params::ChainParams::reward_at_height(height);
+    transactions.insert(
+        0,
+        Transaction::new(
+            vec![],
+            vec![TransactionOutput {
+                unique_id: Uuid::new_v4(),
+                value: reward + fees,
+                address,
+            }],
+        ),
+    );
 
     let merkle_root = MerkleRoot::calculate(&transactions);
-    let block = Block::new(
-        BlockHeader::new(Utc::now(), 0, Hash::zero(), merkle_root, btclib::MIN_TARGET),
+    let mut block = Block::new(
+        BlockHeader::new(Utc::now(), 0, prev, merkle_root, target),
         transactions,
     );
+    while !block.header.mine(MINE_STEPS) {
+        println!("mining, nonce {}", block.header.nonce);
+    }
+    println!(
+        "Mined block {} at height {} paying {} BTC, nonce {}",
+        block.hash(),
+        height,
+        format_btc(reward + fees),
+        block.header.nonce
+    );
 
     block
         .save_to_file(path)
         .expect("Failed to save block to file");
+
+    // submitted like a miner's template, the node checks it as any block
+    if let Some(node) = submit {
+        let mut stream = TcpStream::connect(&node).expect("Failed to connect to node");
+        Envelope::new(Uuid::new_v4().to_string(), DEFAULT_TTL, Message::SubmitTemplate(block))
+            .send(&mut stream)
+            .expect("Failed to submit block");
+        println!("Submitted to {node}");
+    }
 }
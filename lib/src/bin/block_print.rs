@@ -1,16 +1,40 @@
 use std::{env, fs::File};
-use btclib::{types::Block, util::Saveable};
+use btclib::{amount::format_btc, types::Block, util::{PrintFormat, Saveable}};
+
+const USAGE: &str = "Usage: block_print [--format json|toml|debug] [--compact] <path to block file>";
 
 fn main() {
-    let path = if let Some(arg) = env::args().nth(1) {
-        arg
-    } else {
-        eprint!("Usage: block_print <path to block file>");
+    let mut format = PrintFormat::Debug;
+    let mut compact = false;
+    let mut path = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => {
+                format = args.next().unwrap_or_default().parse().unwrap_or_else(|e| {
+                    eprintln!("{e}\n{USAGE}");
+                    std::process::exit(1);
+                })
+            }
+            "--compact" => compact = true,
+            _ => path = Some(arg),
+        }
+    }
+    let Some(path) = path else {
+        eprintln!("{USAGE}");
         std::process::exit(1);
     };
 
     if let Ok(file) = File::open(path) {
         let block = Block::load(file).expect("Failed to load block");
-        println!("{:#?}", block);
+        println!("{}", format.render(&block, compact).expect("Failed to format block"));
+        // JSON and TOML stay parseable
+        if format != PrintFormat::Debug {
+            return;
+        }
+        for transaction in &block.transactions {
+            let total: u64 = transaction.outputs.iter().map(|output| output.value).sum();
+            println!("{}: {} BTC", transaction.hash(), format_btc(total));
+        }
     }
-}
\ No newline at end of file
+}
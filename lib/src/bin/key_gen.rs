@@ -1,83 +1,141 @@
 use btclib::crypto::PrivateKey;
+use btclib::params::Network;
 use btclib::util::Saveable;
-use bip39::{Mnemonic, Language};
-use rand::RngCore;
-use std::io::{self, Write};
+use zeroize::Zeroizing;
+use std::env;
+use std::fs;
+use std::io::{self, BufRead};
 use std::path::PathBuf;
 
-fn main() {
-    println!("=== Deterministic Wallet Key Generator ===\n");
-
-    // Generate a new BIP39 mnemonic (12 words = 128 bits of entropy)
-    let mut entropy = [0u8; 16]; // 128 bits for 12-word mnemonic
-    rand::rng().fill_bytes(&mut entropy);
-    
-    let mnemonic = Mnemonic::from_entropy_in(Language::English, &entropy)
-        .expect("Failed to generate mnemonic");
-    let mnemonic_phrase = mnemonic.to_string();
-
-    println!("Generated mnemonic phrase:");
-    println!("{}\n", mnemonic_phrase);
-    println!("⚠️  IMPORTANT: Save this mnemonic phrase in a secure location!");
-    println!("   You will need it to recover your keys.\n");
-
-    // Derive private key from mnemonic
-    let private_key = PrivateKey::from_mnemonic(&mnemonic_phrase)
-        .expect("Failed to derive key from mnemonic");
-    let public_key = private_key.public_key();
-
-    // Display public address (Bitcoin-style)
-    println!("Public Address: {}", public_key.to_address());
-    println!();
-
-    // Prompt for directory to save keys
-    print!("Enter directory path to save keys (default: ./keys): ");
-    io::stdout().flush().unwrap();
+const USAGE: &str = "Usage: key_gen [--out-dir <dir>] [--name <name>] [--format pem|cbor|hex] \
+    [--mnemonic <words>|-] [--network mainnet|testnet] [--address] [--config] [--force]";
 
-    let mut input = String::new();
-    io::stdin()
-        .read_line(&mut input)
-        .expect("Failed to read input");
+fn fail(message: &str) -> ! {
+    eprintln!("{}", USAGE);
+    eprintln!("Error: {}", message);
+    std::process::exit(1);
+}
 
-    let wallet_dir = if input.trim().is_empty() {
-        PathBuf::from("keys")
-    } else {
-        PathBuf::from(input.trim())
-    };
+/// How both keys are written. The wallet reads the default, a CBOR
+/// private key and a PEM public key.
+#[derive(Clone, Copy)]
+enum KeyFormat {
+    Pem,
+    Cbor,
+    Hex,
+}
 
-    // Prompt for key name
-    print!("Enter a name for this key pair (default: default): ");
-    io::stdout().flush().unwrap();
+impl KeyFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            KeyFormat::Pem => "pem",
+            KeyFormat::Cbor => "cbor",
+            KeyFormat::Hex => "hex",
+        }
+    }
+}
 
-    let mut name_input = String::new();
-    io::stdin()
-        .read_line(&mut name_input)
-        .expect("Failed to read input");
+fn main() {
+    let mut out_dir = PathBuf::from("keys");
+    let mut name = "default".to_string();
+    let mut format = None;
+    let mut mnemonic = None;
+    let mut network = Network::Mainnet;
+    let (mut write_address, mut print_config, mut force) = (false, false, false);
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| fail(&format!("{arg} needs a value")));
+        match arg.as_str() {
+            "--out-dir" => out_dir = PathBuf::from(value()),
+            "--name" => name = value(),
+            "--format" => {
+                format = Some(match value().as_str() {
+                    "pem" => KeyFormat::Pem,
+                    "cbor" => KeyFormat::Cbor,
+                    "hex" => KeyFormat::Hex,
+                    other => fail(&format!("Unknown format {other}, expected pem, cbor or hex")),
+                })
+            }
+            // `-` reads the words from stdin, keeping them out of the
+            // shell history
+            "--mnemonic" => mnemonic = Some(Zeroizing::new(value())),
+            "--network" => network = value().parse().unwrap_or_else(|e: String| fail(&e)),
+            "--address" => write_address = true,
+            "--config" => print_config = true,
+            "--force" => force = true,
+            _ => fail(&format!("Unknown option {arg}")),
+        }
+    }
 
-    let name = if name_input.trim().is_empty() {
-        "default".to_string()
-    } else {
-        name_input.trim().to_string()
+    let mnemonic_phrase = match mnemonic {
+        Some(words) if words.as_str() == "-" => {
+            let mut line = Zeroizing::new(String::new());
+            io::stdin()
+                .lock()
+                .read_line(&mut line)
+                .expect("Failed to read mnemonic");
+            Zeroizing::new(line.trim().to_string())
+        }
+        Some(words) => words,
+        None => {
+            // 12 words = 128 bits of entropy
+            let phrase = Zeroizing::new(PrivateKey::generate_mnemonic());
+            println!("Generated mnemonic phrase:");
+            println!("{}\n", phrase.as_str());
+            println!("⚠️  IMPORTANT: Save this mnemonic phrase in a secure location!");
+            println!("   You will need it to recover your keys.\n");
+            phrase
+        }
     };
+    let private_key = PrivateKey::from_mnemonic(&mnemonic_phrase).unwrap_or_else(|e| fail(&e));
+    let public_key = private_key.public_key();
+    let address = public_key.to_address_for(&network.params());
 
-    // Create directory if it doesn't exist
-    std::fs::create_dir_all(&wallet_dir)
-        .expect("Failed to create wallet directory");
+    let private_format = format.unwrap_or(KeyFormat::Cbor);
+    let public_format = format.unwrap_or(KeyFormat::Pem);
+    let private_key_file = out_dir.join(format!("{}.priv.{}", name, private_format.extension()));
+    let public_key_file = out_dir.join(format!("{}.pub.{}", name, public_format.extension()));
+    for file in [&private_key_file, &public_key_file] {
+        if file.exists() && !force {
+            fail(&format!("{} exists, pass --force to replace it", file.display()));
+        }
+    }
+    fs::create_dir_all(&out_dir).expect("Failed to create key directory");
 
-    // Save keys
-    let public_key_file = wallet_dir.join(format!("{}.pub.pem", name));
-    let private_key_file = wallet_dir.join(format!("{}.priv.cbor", name));
+    match private_format {
+        KeyFormat::Cbor => private_key.save_to_file(&private_key_file),
+        KeyFormat::Pem => fs::write(&private_key_file, private_key.to_pem().expect("Failed to encode private key")),
+        KeyFormat::Hex => fs::write(&private_key_file, private_key.to_hex()),
+    }
+    .expect("Failed to save private key");
+    match public_format {
+        KeyFormat::Pem => public_key.save_to_file(&public_key_file),
+        KeyFormat::Cbor => {
+            let file = fs::File::create(&public_key_file).expect("Failed to create public key file");
+            ciborium::into_writer(&public_key, file).map_err(io::Error::other)
+        }
+        KeyFormat::Hex => fs::write(&public_key_file, public_key.to_hex()),
+    }
+    .expect("Failed to save public key");
 
-    private_key
-        .save_to_file(&private_key_file)
-        .expect("Failed to save private key");
-    public_key
-        .save_to_file(&public_key_file)
-        .expect("Failed to save public key");
+    println!("✓ Keys saved successfully!");
+    println!("  Private key: {}", private_key_file.display());
+    println!("  Public key: {}", public_key_file.display());
+    println!("Public Address: {}", address);
+    if write_address {
+        let address_file = out_dir.join(format!("{}.address", name));
+        fs::write(&address_file, format!("{address}\n")).expect("Failed to save address");
+        println!("  Address: {}", address_file.display());
+    }
 
-    println!("\n✓ Keys saved successfully!");
-    println!("  Private key: {:?}", private_key_file);
-    println!("  Public key: {:?}", public_key_file);
-    println!("\nMnemonic phrase: {}", mnemonic_phrase);
-    println!("Public Address: {}", public_key.to_address());
+    if print_config {
+        if format.is_some() {
+            eprintln!("Note: the wallet only loads keys saved without --format");
+        }
+        println!("\n# add to the wallet config");
+        println!("[[my_keys]]");
+        println!("name = {:?}", name);
+        println!("public = {:?}", public_key_file.display().to_string());
+        println!("private = {:?}", private_key_file.display().to_string());
+    }
 }
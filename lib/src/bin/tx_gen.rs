@@ -1,38 +1,135 @@
-use btclib::types::{Transaction, TransactionOutput};
+use btclib::address::Address;
+use btclib::amount::{format_btc, parse_btc};
 use btclib::crypto::PrivateKey;
+use btclib::sha256::Hash;
+use btclib::types::{Transaction, TransactionOutput, UnsignedInput, UnsignedTransaction};
 use btclib::util::Saveable;
 use std::env;
+use std::fs;
 use uuid::Uuid;
 
-fn main() {
-    let path = if let Some(arg) = env::args().nth(1) {
-        arg
-    } else {
-        eprintln!("Usage: tx_gen <path to transaction file> <path to private key>");
-        std::process::exit(1);
-    };
+const USAGE: &str = "Usage: tx_gen <path to transaction file> <path to private key> \
+    [--spend <utxo hash:BTC>]... [--utxos <outputs.json>] [--to <address:BTC>]... [--fee <BTC>]";
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", USAGE);
+    eprintln!("Error: {}", message);
+    std::process::exit(1);
+}
+
+/// Split `left:amount` with the amount in BTC
+fn parse_entry(entry: &str) -> Option<(&str, u64)> {
+    let (left, amount) = entry.rsplit_once(':')?;
+    Some((left, parse_btc(amount).ok()?))
+}
+
+/// Outputs of the key in a JSON array of outputs, such as
+/// `tx_print --format json` prints under `outputs`
+fn load_utxos(path: &str, address: &str) -> Vec<(Hash, u64)> {
+    let json = fs::read_to_string(path).unwrap_or_else(|e| fail(&format!("Failed to read {path}: {e}")));
+    let outputs: Vec<TransactionOutput> =
+        serde_json::from_str(&json).unwrap_or_else(|e| fail(&format!("Invalid outputs in {path}: {e}")));
+    outputs
+        .iter()
+        .filter(|output| Address::same(&output.address, address))
+        .map(|output| (output.hash(), output.value))
+        .collect()
+}
 
-    let key_path = if let Some(key_path) = env::args().nth(2) {
-        key_path
-    } else {
-        eprintln!("Usage: tx_gen <path to transaction file> <path to private key>");
-        eprintln!("Error: Private key file path is required");
-        std::process::exit(1);
+fn main() {
+    let mut args = env::args().skip(1);
+    let (Some(path), Some(key_path)) = (args.next(), args.next()) else {
+        fail("Transaction and private key file paths are required");
     };
 
     let private_key = PrivateKey::load_from_file(&key_path)
         .expect("Failed to load private key from file");
     let public_key = private_key.public_key();
     let address = public_key.to_address();
-    let transaction = Transaction::new(
-        vec![],
-        vec![TransactionOutput {
+
+    let mut spends: Vec<(Hash, u64)> = vec![];
+    let mut outputs: Vec<TransactionOutput> = vec![];
+    let mut fee = 0;
+    while let Some(arg) = args.next() {
+        let value = args.next().unwrap_or_else(|| fail(&format!("{arg} needs a value")));
+        match arg.as_str() {
+            "--spend" => {
+                let (hash, value) = parse_entry(&value)
+                    .and_then(|(hash, value)| Some((hash.parse().ok()?, value)))
+                    .unwrap_or_else(|| fail(&format!("Invalid spend {value}, expected <utxo hash:BTC>")));
+                spends.push((hash, value));
+            }
+            "--utxos" => spends.extend(load_utxos(&value, &address)),
+            "--to" => {
+                let (to, value) = parse_entry(&value)
+                    .filter(|(to, _)| Address::parse(to).is_ok())
+                    .unwrap_or_else(|| fail(&format!("Invalid output {value}, expected <address:BTC>")));
+                outputs.push(TransactionOutput {
+                    unique_id: Uuid::new_v4(),
+                    value,
+                    address: to.to_string(),
+                });
+            }
+            "--fee" => fee = parse_btc(&value).unwrap_or_else(|_| fail(&format!("Invalid fee {value}"))),
+            _ => fail(&format!("Unknown option {arg}")),
+        }
+    }
+
+    // without spends, a coinbase like transaction paying the reward
+    if spends.is_empty() {
+        if !outputs.is_empty() {
+            fail("--to needs outputs to spend, given with --spend or --utxos");
+        }
+        let transaction = Transaction::new(
+            vec![],
+            vec![TransactionOutput {
+                unique_id: Uuid::new_v4(),
+                value: btclib::INITIAL_REWARD * 10u64.pow(8),
+                address,
+            }]
+        );
+        transaction.save_to_file(path)
+            .expect("Failed to save transaction to file");
+        return;
+    }
+
+    let input_value: u64 = spends.iter().map(|(_, value)| value).sum();
+    let needed = outputs.iter().map(|output| output.value).sum::<u64>() + fee;
+    if input_value < needed {
+        fail(&format!(
+            "Spending {} BTC but paying {} BTC with the fee",
+            format_btc(input_value),
+            format_btc(needed)
+        ));
+    }
+    // whatever isn't paid out or left as fee returns to the key
+    if input_value > needed {
+        outputs.push(TransactionOutput {
             unique_id: Uuid::new_v4(),
-            value: btclib::INITIAL_REWARD * 10u64.pow(8),
+            value: input_value - needed,
             address,
-        }]
+        });
+    }
+    let inputs = spends
+        .iter()
+        .map(|(hash, value)| UnsignedInput {
+            prev_transaction_output_hash: *hash,
+            public_key: public_key.clone(),
+            value: *value,
+            multisig: None,
+        })
+        .collect();
+    let transaction = UnsignedTransaction { inputs, outputs }
+        .sign(std::slice::from_ref(&private_key))
+        .expect("Failed to sign transaction");
+    println!(
+        "Spending {} BTC from {} outputs, fee {} BTC",
+        format_btc(input_value),
+        spends.len(),
+        format_btc(fee)
     );
+    println!("Transaction {}", transaction.hash());
 
     transaction.save_to_file(path)
         .expect("Failed to save transaction to file");
-}
\ No newline at end of file
+}
@@ -1,16 +1,41 @@
 use std::{env, fs::File};
-use btclib::{types::Transaction, util::Saveable};
+use btclib::{amount::format_btc, types::Transaction, util::{PrintFormat, Saveable}};
+
+const USAGE: &str = "Usage: tx_print [--format json|toml|debug] [--compact] <path to transaction file>";
 
 fn main() {
-    let path = if let Some(arg) = env::args().nth(1) {
-        arg
-    } else {
-        eprint!("Usage: tx_print <path to transaction file>");
+    let mut format = PrintFormat::Debug;
+    let mut compact = false;
+    let mut path = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => {
+                format = args.next().unwrap_or_default().parse().unwrap_or_else(|e| {
+                    eprintln!("{e}\n{USAGE}");
+                    std::process::exit(1);
+                })
+            }
+            "--compact" => compact = true,
+            _ => path = Some(arg),
+        }
+    }
+    let Some(path) = path else {
+        eprintln!("{USAGE}");
         std::process::exit(1);
     };
 
     if let Ok(file) = File::open(path) {
         let transaction = Transaction::load(file).expect("Failed to load transaction");
-        println!("{:#?}", transaction);
+        println!("{}", format.render(&transaction, compact).expect("Failed to format transaction"));
+        // JSON and TOML stay parseable
+        if format != PrintFormat::Debug {
+            return;
+        }
+        for output in &transaction.outputs {
+            println!("{}: {} BTC", output.address, format_btc(output.value));
+        }
+        let total: u64 = transaction.outputs.iter().map(|output| output.value).sum();
+        println!("total output: {} BTC", format_btc(total));
     }
-}
\ No newline at end of file
+}
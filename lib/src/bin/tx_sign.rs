@@ -0,0 +1,51 @@
+use btclib::approval;
+use btclib::crypto::PrivateKey;
+use btclib::types::PartiallySignedTransaction;
+use btclib::util::Saveable;
+use std::env;
+use std::io::{self, Write};
+
+const USAGE: &str = "Usage: tx_sign <transaction file> <signed output file> <private key>...";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() < 3 {
+        eprintln!("{}", USAGE);
+        std::process::exit(1);
+    }
+    let (psbt_path, signed_path, key_paths) = (&args[0], &args[1], &args[2..]);
+
+    let mut psbt = PartiallySignedTransaction::load_from_file(psbt_path)
+        .expect("Failed to load partially signed transaction");
+    let keys: Vec<PrivateKey> = key_paths
+        .iter()
+        .map(|path| PrivateKey::load_from_file(path).expect("Failed to load private key"))
+        .collect();
+
+    // show what is being signed, the signer may be the only place the
+    // transaction can be checked before it leaves the air gap
+    println!("{}", approval::summarize(&psbt));
+
+    print!("Sign this transaction? [y/N] ");
+    io::stdout().flush().unwrap();
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).expect("Failed to read input");
+    if !answer.trim().eq_ignore_ascii_case("y") {
+        println!("Not signed");
+        std::process::exit(1);
+    }
+
+    let signed: usize = keys.iter().map(|key| psbt.sign(key)).sum();
+    if signed == 0 {
+        eprintln!("None of the given keys signs an input of this transaction");
+        std::process::exit(1);
+    }
+    psbt.save_to_file(signed_path)
+        .expect("Failed to save signed transaction");
+    println!("Added {} signatures, saved to {}", signed, signed_path);
+    if psbt.is_complete() {
+        println!("The transaction is fully signed and can be broadcast");
+    } else {
+        println!("More signatures are needed before the transaction can be broadcast");
+    }
+}
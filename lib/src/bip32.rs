@@ -0,0 +1,246 @@
+//! BIP32 hierarchical deterministic keys: any number of keys derived
+//! from the seed of one mnemonic, so the mnemonic alone restores them
+use crate::crypto::{PrivateKey, PublicKey};
+use crate::error::BtcError;
+use crate::params::Network;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::str::FromStr;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::Sha512;
+#[cfg(feature = "std")]
+use crate::util::Saveable;
+#[cfg(feature = "std")]
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
+use zeroize::Zeroizing;
+
+/// Child indexes from this one up are hardened: their keys can't be
+/// derived from the parent's public key
+pub const HARDENED: u32 = 1 << 31;
+
+/// Path from the master key to a derived key, written like
+/// `m/44'/0'/0'/0/7` with `'` marking hardened indexes
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct DerivationPath(Vec<u32>);
+
+impl DerivationPath {
+    /// `m/44'/coin'/account'`, the BIP44 account key with the coin type
+    /// 0 on mainnet and 1 on testnet. Its children 0 and 1 derive the
+    /// receive and change addresses.
+    pub fn bip44_account(network: Network, account: u32) -> Self {
+        let coin_type = match network {
+            Network::Mainnet => 0,
+            Network::Testnet => 1,
+        };
+        DerivationPath(alloc::vec![44 | HARDENED, coin_type | HARDENED, account | HARDENED])
+    }
+
+    /// The path of the `index`th child of this one
+    pub fn child(&self, index: u32) -> Self {
+        let mut indexes = self.0.clone();
+        indexes.push(index);
+        DerivationPath(indexes)
+    }
+
+    pub fn indexes(&self) -> &[u32] {
+        &self.0
+    }
+}
+
+impl fmt::Display for DerivationPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "m")?;
+        for index in &self.0 {
+            match index & HARDENED {
+                0 => write!(f, "/{}", index)?,
+                _ => write!(f, "/{}'", index & !HARDENED)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for DerivationPath {
+    type Err = BtcError;
+
+    fn from_str(s: &str) -> Result<Self, BtcError> {
+        let mut parts = s.trim().split('/');
+        if parts.next() != Some("m") {
+            return Err(BtcError::InvalidDerivationPath);
+        }
+        parts
+            .map(|part| {
+                let (number, hardened) = match part.strip_suffix(['\'', 'h']) {
+                    Some(number) => (number, HARDENED),
+                    None => (part, 0),
+                };
+                match number.parse::<u32>() {
+                    Ok(index) if index < HARDENED => Ok(index | hardened),
+                    _ => Err(BtcError::InvalidDerivationPath),
+                }
+            })
+            .collect::<Result<_, _>>()
+            .map(DerivationPath)
+    }
+}
+
+impl Serialize for DerivationPath {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for DerivationPath {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// A private key with the chain code its children are derived with
+#[derive(Clone)]
+pub struct ExtendedPrivateKey {
+    key: PrivateKey,
+    chain_code: Zeroizing<[u8; 32]>,
+}
+
+impl fmt::Debug for ExtendedPrivateKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ExtendedPrivateKey").field(&"<redacted>").finish()
+    }
+}
+
+impl ExtendedPrivateKey {
+    /// The master key of a BIP39 seed
+    pub fn from_seed(seed: &[u8]) -> Result<Self, BtcError> {
+        Self::from_hmac(b"Bitcoin seed", &[seed])
+    }
+
+    /// The master key of a BIP39 mnemonic, without a passphrase
+    pub fn from_mnemonic(mnemonic: &str) -> Result<Self, BtcError> {
+        let mnemonic = bip39::Mnemonic::parse_in_normalized(bip39::Language::English, mnemonic)
+            .map_err(|_| BtcError::InvalidMnemonic)?;
+        Self::from_seed(Zeroizing::new(mnemonic.to_seed("")).as_ref())
+    }
+
+    // split HMAC-SHA512 of `data` into a key and a chain code
+    fn from_hmac(key: &[u8], data: &[&[u8]]) -> Result<Self, BtcError> {
+        let (tweak, chain_code) = hmac_halves(key, data);
+        let key = PrivateKey::from_scalar_bytes(tweak.as_ref()).ok_or(BtcError::InvalidPrivateKey)?;
+        Ok(ExtendedPrivateKey { key, chain_code })
+    }
+
+    /// The child at `index`, hardened from `HARDENED` up. Fails for the
+    /// about 1 in 2^127 indexes without a valid key, BIP32 skips those.
+    pub fn child(&self, index: u32) -> Result<Self, BtcError> {
+        let index_bytes = index.to_be_bytes();
+        let (tweak, chain_code) = if index >= HARDENED {
+            let secret = self.key.to_scalar_bytes();
+            hmac_halves(self.chain_code.as_ref(), &[&[0], secret.as_ref(), &index_bytes])
+        } else {
+            let public = self.key.public_key().to_compressed_bytes();
+            hmac_halves(self.chain_code.as_ref(), &[&public, &index_bytes])
+        };
+        let key = self.key.add_tweak(&tweak).ok_or(BtcError::InvalidPrivateKey)?;
+        Ok(ExtendedPrivateKey { key, chain_code })
+    }
+
+    /// The key at `path` below this one
+    pub fn derive(&self, path: &DerivationPath) -> Result<Self, BtcError> {
+        path.indexes().iter().try_fold(self.clone(), |key, index| key.child(*index))
+    }
+
+    pub fn private_key(&self) -> &PrivateKey {
+        &self.key
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        self.key.public_key()
+    }
+}
+
+// the key and chain code in CBOR, like a private key file
+#[cfg(feature = "std")]
+impl Saveable for ExtendedPrivateKey {
+    fn load<I: Read>(reader: I) -> IoResult<Self> {
+        let (key, chain_code): (PrivateKey, [u8; 32]) = ciborium::de::from_reader(reader)
+            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to deserialize ExtendedPrivateKey"))?;
+        Ok(ExtendedPrivateKey {
+            key,
+            chain_code: Zeroizing::new(chain_code),
+        })
+    }
+
+    fn save<O: Write>(&self, writer: O) -> IoResult<()> {
+        ciborium::ser::into_writer(&(&self.key, *self.chain_code), writer)
+            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to serialize ExtendedPrivateKey"))
+    }
+}
+
+fn hmac_halves(key: &[u8], data: &[&[u8]]) -> (Zeroizing<[u8; 32]>, Zeroizing<[u8; 32]>) {
+    let mut mac = Hmac::<Sha512>::new_from_slice(key).expect("HMAC takes keys of any length");
+    for part in data {
+        mac.update(part);
+    }
+    let output = Zeroizing::new(<[u8; 64]>::from(mac.finalize().into_bytes()));
+    let mut left = Zeroizing::new([0; 32]);
+    let mut right = Zeroizing::new([0; 32]);
+    left.copy_from_slice(&output[..32]);
+    right.copy_from_slice(&output[32..]);
+    (left, right)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // test vector 1 of BIP32
+    #[test]
+    fn test_vector() {
+        let seed = hex::decode("000102030405060708090a0b0c0d0e0f").unwrap();
+        let master = ExtendedPrivateKey::from_seed(&seed).unwrap();
+        assert_eq!(
+            master.private_key().to_hex().as_str(),
+            "e8f32e723decf4051aefac8e2c93c9c5b214313817cdb01a1494b917c8436b35"
+        );
+        let hardened = master.derive(&"m/0'".parse().unwrap()).unwrap();
+        assert_eq!(
+            hardened.private_key().to_hex().as_str(),
+            "edb2e14f9ee77d26dd93b4ecede8d16ed408ce149b6cd80b0715a2d911a0afea"
+        );
+        let normal = master.derive(&"m/0'/1".parse().unwrap()).unwrap();
+        assert_eq!(
+            normal.private_key().to_hex().as_str(),
+            "3c6cb8d0f6a264c91ea8b5030fadaa8e538b020f0a387421a12de9319dc93368"
+        );
+        let deep = master.derive(&"m/0'/1/2'/2/1000000000".parse().unwrap()).unwrap();
+        assert_eq!(
+            deep.private_key().to_hex().as_str(),
+            "471b76e389e528d6de6d816857e012c5455051cad6660850e58372a6c3e6e7c8"
+        );
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let master = ExtendedPrivateKey::from_seed(&[7; 64]).unwrap();
+        let mut bytes = vec![];
+        master.save(&mut bytes).unwrap();
+        let loaded = ExtendedPrivateKey::load(bytes.as_slice()).unwrap();
+        let path: DerivationPath = "m/44'/0'/3'/1/2".parse().unwrap();
+        assert_eq!(loaded.derive(&path).unwrap().public_key(), master.derive(&path).unwrap().public_key());
+    }
+
+    #[test]
+    fn test_paths() {
+        let path: DerivationPath = "m/44'/1h/0'/0/7".parse().unwrap();
+        assert_eq!(path, DerivationPath::bip44_account(Network::Testnet, 0).child(0).child(7));
+        assert_eq!(path.to_string(), "m/44'/1'/0'/0/7");
+        assert_eq!("m".parse::<DerivationPath>().unwrap(), DerivationPath::default());
+        for invalid in ["", "44'/0'", "m/x", "m/2147483648", "m//1"] {
+            assert!(invalid.parse::<DerivationPath>().is_err(), "{invalid}");
+        }
+    }
+}
@@ -1,17 +1,29 @@
+use crate::address::{Address, AddressFormat};
+use crate::error::BtcError;
+use crate::params::ChainParams;
 use crate::sha256::Hash;
+#[cfg(feature = "std")]
 use crate::util::Saveable;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
 use ecdsa::{
     Signature as ECDSASignature, SigningKey, VerifyingKey,
     signature::{Signer, Verifier},
 };
-use k256::{Secp256k1, pkcs8::EncodePublicKey};
-use rand_core::OsRng;
+use k256::Secp256k1;
+#[cfg(feature = "std")]
+use k256::pkcs8::{EncodePrivateKey, EncodePublicKey};
+use rand_core::CryptoRngCore;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+#[cfg(feature = "std")]
 use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
-use std::fmt;
 use bip39::{Mnemonic, Language};
-use sha2::{Sha256, Digest};
-use ripemd::Ripemd160;
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+const MESSAGE_PREFIX: &[u8] = b"Grapheno Signed Message:\n";
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Signature(ECDSASignature<Secp256k1>);
@@ -31,6 +43,34 @@ impl Signature {
             .verify(&output_hash.as_bytes(), &self.0)
             .is_ok()
     }
+
+    /// Sign a message shown to people rather than an output. The
+    /// message is hashed behind a prefix, so the signature can't be
+    /// passed off as one spending an output.
+    pub fn sign_message(message: &str, private_key: &PrivateKey) -> Self {
+        Self::sign_output(&message_hash(message), private_key)
+    }
+
+    pub fn verify_message(&self, message: &str, public_key: &PublicKey) -> bool {
+        self.verify(&message_hash(message), public_key)
+    }
+
+    /// Fixed-size encoding, r followed by s
+    pub fn to_bytes(&self) -> [u8; 64] {
+        self.0.to_bytes().into()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, BtcError> {
+        ECDSASignature::from_slice(bytes)
+            .map(Signature)
+            .map_err(|_| BtcError::InvalidSignature)
+    }
+}
+
+fn message_hash(message: &str) -> Hash {
+    let mut bytes = MESSAGE_PREFIX.to_vec();
+    bytes.extend_from_slice(message.as_bytes());
+    Hash::hash_bytes(&bytes)
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Ord, PartialOrd)]
@@ -50,82 +90,54 @@ impl PublicKey {
     /// 4. Double SHA256 of (version + hash), take first 4 bytes as checksum
     /// 5. Base58 encode (version + hash + checksum)
     pub fn to_address(&self) -> String {
-        // Step 1: Get compressed public key bytes
-        let encoded_point = self.0.to_encoded_point(true);
-        let pub_key_bytes = encoded_point.as_bytes();
-        
-        // Step 2: SHA256 hash
-        let mut hasher = Sha256::new();
-        hasher.update(pub_key_bytes);
-        let sha256_hash = hasher.finalize();
-        
-        // Step 3: RIPEMD160 hash (20 bytes)
-        let mut ripemd_hasher = Ripemd160::new();
-        ripemd_hasher.update(&sha256_hash);
-        let pub_key_hash = ripemd_hasher.finalize();
-        
-        // Step 4: Add version byte (0x00 for mainnet-style addresses)
-        let version: u8 = 0x00;
-        let mut versioned_hash = vec![version];
-        versioned_hash.extend_from_slice(&pub_key_hash);
-        
-        // Step 5: Calculate checksum (first 4 bytes of double SHA256)
-        let mut checksum_hasher = Sha256::new();
-        checksum_hasher.update(&versioned_hash);
-        let first_hash = checksum_hasher.finalize();
-        
-        let mut checksum_hasher2 = Sha256::new();
-        checksum_hasher2.update(&first_hash);
-        let second_hash = checksum_hasher2.finalize();
-        
-        let checksum = &second_hash[..4];
-        
-        // Step 6: Combine version + hash + checksum and Base58 encode
-        let mut address_bytes = versioned_hash;
-        address_bytes.extend_from_slice(checksum);
-        
-        bs58::encode(&address_bytes).into_string()
+        self.to_address_for(&ChainParams::mainnet())
     }
 
-    /// Validate a Bitcoin-style address format
-    /// Returns true if the address is valid Base58Check format
-    pub fn validate_address(address: &str) -> Result<bool, String> {
-        // Decode Base58
-        let decoded = bs58::decode(address)
-            .into_vec()
-            .map_err(|e| format!("Invalid Base58 encoding: {}", e))?;
-
-        // Address should be at least 25 bytes (version + hash + checksum)
-        if decoded.len() < 25 {
-            return Ok(false);
-        }
-
-        // Split into version+hash and checksum
-        let version_and_hash = &decoded[..decoded.len() - 4];
-        let provided_checksum = &decoded[decoded.len() - 4..];
+    /// The key's Base58 address on the chain of `params`
+    pub fn to_address_for(&self, params: &ChainParams) -> String {
+        Address::from_public_key(self).to_base58(params)
+    }
 
-        // Calculate expected checksum (double SHA256)
-        let mut hasher = Sha256::new();
-        hasher.update(version_and_hash);
-        let first_hash = hasher.finalize();
+    /// The key's address in the given format
+    pub fn to_address_as(&self, format: AddressFormat, params: &ChainParams) -> String {
+        Address::from_public_key(self).encode(format, params)
+    }
 
-        let mut hasher2 = Sha256::new();
-        hasher2.update(&first_hash);
-        let second_hash = hasher2.finalize();
+    /// The key in PEM, as public keys are saved
+    #[cfg(feature = "std")]
+    pub fn to_pem(&self) -> Result<String, BtcError> {
+        self.0
+            .to_public_key_pem(Default::default())
+            .map_err(|_| BtcError::InvalidPublicKey)
+    }
 
-        let expected_checksum = &second_hash[..4];
+    /// SEC1 compressed encoding of the key
+    pub fn to_compressed_bytes(&self) -> Vec<u8> {
+        self.0.to_encoded_point(true).as_bytes().to_vec()
+    }
 
-        // Verify checksum matches
-        if provided_checksum != expected_checksum {
-            return Ok(false);
+    pub fn from_compressed_bytes(bytes: &[u8]) -> Result<Self, BtcError> {
+        // from_sec1_bytes also takes uncompressed keys, which would give
+        // a second encoding of the same key
+        if bytes.len() != 33 {
+            return Err(BtcError::InvalidPublicKey);
         }
-
-        // Verify version byte is 0x00 (mainnet-style)
-        if decoded[0] != 0x00 {
-            return Ok(false);
+        VerifyingKey::from_sec1_bytes(bytes)
+            .map(PublicKey)
+            .map_err(|_| BtcError::InvalidPublicKey)
+    }
+
+    /// Validate an address, in Base58Check or bech32 format
+    /// Returns true if it is a well-formed address of the chain of
+    /// `params`, and an error if it belongs to another network
+    pub fn validate_address(address: &str, params: &ChainParams) -> Result<bool, String> {
+        match Address::parse_for(address, params) {
+            Ok(_) => Ok(true),
+            Err(BtcError::WrongNetwork) => {
+                Err(format!("{} is not a {} address", address, params.network))
+            }
+            Err(_) => Ok(false),
         }
-
-        Ok(true)
     }
 }
 
@@ -135,52 +147,114 @@ impl fmt::Display for PublicKey {
     }
 }
 
+/// A secp256k1 signing key. The secret scalar is wiped when the key is
+/// dropped, and neither Debug nor serialization buffers expose it.
 #[derive(Serialize, Deserialize, Clone)]
 pub struct PrivateKey(#[serde(with = "signkey_serde")] SigningKey<Secp256k1>);
 
+// SigningKey zeroizes its secret scalar in its own Drop
+impl ZeroizeOnDrop for PrivateKey {}
+
+impl fmt::Debug for PrivateKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("PrivateKey").field(&"<redacted>").finish()
+    }
+}
+
 impl PrivateKey {
     /// Generate a new random private key (non-deterministic)
     /// Deprecated: Use from_mnemonic() for deterministic key generation
+    #[cfg(feature = "std")]
     pub fn new_key() -> Self {
-        PrivateKey(SigningKey::random(&mut OsRng))
+        Self::random(&mut rand_core::OsRng)
+    }
+
+    /// Generate a new private key from the given randomness, for
+    /// targets without an operating system RNG
+    pub fn random<R: CryptoRngCore>(rng: &mut R) -> Self {
+        PrivateKey(SigningKey::random(rng))
+    }
+
+    /// Generate a new random 12-word BIP39 mnemonic phrase
+    #[cfg(feature = "std")]
+    pub fn generate_mnemonic() -> String {
+        use rand::RngCore;
+        // 128 bits of entropy give a 12-word mnemonic
+        let mut entropy = Zeroizing::new([0u8; 16]);
+        rand::rng().fill_bytes(entropy.as_mut());
+        Mnemonic::from_entropy_in(Language::English, entropy.as_ref())
+            .expect("16 bytes is a valid entropy length")
+            .to_string()
     }
 
     /// Generate a private key from a BIP39 mnemonic phrase
     pub fn from_mnemonic(mnemonic: &str) -> Result<Self, String> {
         let mnemonic = Mnemonic::parse_in_normalized(Language::English, mnemonic)
             .map_err(|e| format!("Invalid mnemonic: {}", e))?;
-        let seed = mnemonic.to_seed("");
-        Self::from_seed(&seed)
+        let seed = Zeroizing::new(mnemonic.to_seed(""));
+        Self::from_seed(seed.as_ref())
     }
 
     /// Generate a private key from a seed (64 bytes)
     pub fn from_seed(seed: &[u8]) -> Result<Self, String> {
         // Use SHA256 of the seed to derive the private key deterministically
-        use sha256::digest;
-        let seed_hash = digest(seed);
-        let seed_bytes = hex::decode(seed_hash)
-            .map_err(|e| format!("Failed to decode hash: {}", e))?;
+        let seed_bytes = Zeroizing::new(<[u8; 32]>::from(Sha256::digest(seed)));
         
         // Take first 32 bytes for the private key
-        let key_bytes: [u8; 32] = seed_bytes[..32]
+        let mut key_bytes: [u8; 32] = seed_bytes[..32]
             .try_into()
             .map_err(|_| "Failed to convert to 32-byte array")?;
         
         // Ensure the key is valid for secp256k1 (must be < curve order)
         // k256::SigningKey handles this validation
         let signing_key = SigningKey::from_slice(&key_bytes)
-            .map_err(|e| format!("Failed to create signing key from seed: {}", e))?;
+            .map_err(|e| format!("Failed to create signing key from seed: {}", e));
+        key_bytes.zeroize();
         
-        Ok(PrivateKey(signing_key))
+        Ok(PrivateKey(signing_key?))
     }
 
     pub fn public_key(&self) -> PublicKey {
-        PublicKey(self.0.verifying_key().clone())
+        PublicKey(*self.0.verifying_key())
+    }
+
+    /// The key as a PKCS#8 PEM document
+    #[cfg(feature = "std")]
+    pub fn to_pem(&self) -> Result<Zeroizing<String>, BtcError> {
+        self.0
+            .to_pkcs8_pem(Default::default())
+            .map_err(|_| BtcError::InvalidPrivateKey)
+    }
+
+    /// The secret scalar as hex
+    pub fn to_hex(&self) -> Zeroizing<String> {
+        Zeroizing::new(hex::encode(self.0.to_bytes()))
+    }
+
+    /// The key for a secret scalar, None unless it is in 1..n
+    pub(crate) fn from_scalar_bytes(bytes: &[u8]) -> Option<Self> {
+        SigningKey::from_slice(bytes).ok().map(PrivateKey)
+    }
+
+    pub(crate) fn to_scalar_bytes(&self) -> Zeroizing<[u8; 32]> {
+        Zeroizing::new(self.0.to_bytes().into())
+    }
+
+    /// The key plus `tweak` modulo the curve order, None when the tweak
+    /// isn't below the order or the sum is zero
+    pub(crate) fn add_tweak(&self, tweak: &[u8; 32]) -> Option<Self> {
+        use k256::elliptic_curve::PrimeField;
+        let tweak = Option::<k256::Scalar>::from(k256::Scalar::from_repr((*tweak).into()))?;
+        let sum = *self.0.as_nonzero_scalar().as_ref() + tweak;
+        let sum = Option::<k256::NonZeroScalar>::from(k256::NonZeroScalar::new(sum))?;
+        Some(PrivateKey(SigningKey::from(sum)))
     }
 }
 
 mod signkey_serde {
+    use alloc::vec::Vec;
     use serde::Deserialize;
+    use zeroize::Zeroizing;
     pub fn serialize<S>(
         key: &super::SigningKey<super::Secp256k1>,
         serializer: S,
@@ -188,7 +262,8 @@ mod signkey_serde {
     where
         S: serde::Serializer,
     {
-        serializer.serialize_bytes(&key.to_bytes())
+        let bytes = Zeroizing::new(key.to_bytes());
+        serializer.serialize_bytes(&bytes)
     }
     pub fn deserialize<'de, D>(
         deserializer: D,
@@ -196,11 +271,13 @@ mod signkey_serde {
     where
         D: serde::Deserializer<'de>,
     {
-        let bytes: Vec<u8> = Vec::<u8>::deserialize(deserializer)?;
-        Ok(super::SigningKey::from_slice(&bytes).unwrap())
+        let bytes = Zeroizing::new(Vec::<u8>::deserialize(deserializer)?);
+        super::SigningKey::from_slice(&bytes)
+            .map_err(|_| serde::de::Error::custom("invalid private key bytes"))
     }
 }
 
+#[cfg(feature = "std")]
 impl Saveable for PrivateKey {
     fn load<I: Read>(reader: I) -> IoResult<Self> {
         ciborium::de::from_reader(reader)
@@ -216,6 +293,7 @@ impl Saveable for PrivateKey {
 }
 
 // save and load as PEM
+#[cfg(feature = "std")]
 impl Saveable for PublicKey {
     fn load<I: Read>(mut reader: I) -> IoResult<Self> {
         // read PEM-encoded public key into string
@@ -230,8 +308,7 @@ impl Saveable for PublicKey {
 
     fn save<O: Write>(&self, mut writer: O) -> IoResult<()> {
         let s = self
-            .0
-            .to_public_key_pem(Default::default())
+            .to_pem()
             .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to serialize PublicKey"))?;
         writer.write_all(s.as_bytes())?;
         Ok(())
@@ -243,6 +320,17 @@ mod tests {
     use super::*;
     use crate::sha256::Hash;
 
+    #[test]
+    fn test_sign_message() {
+        let key = PrivateKey::new_key();
+        let signature = Signature::sign_message("hello", &key);
+        assert!(signature.verify_message("hello", &key.public_key()));
+        assert!(!signature.verify_message("hello!", &key.public_key()));
+        assert!(!signature.verify_message("hello", &PrivateKey::new_key().public_key()));
+        // not valid for the raw hash of the message
+        assert!(!signature.verify(&Hash::hash_bytes(b"hello"), &key.public_key()));
+    }
+
     #[test]
     fn test_from_mnemonic_valid() {
         // Test with a valid BIP39 mnemonic (12 words)
@@ -343,6 +431,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::len_zero)]
     fn test_public_key_to_hex() {
         // Test that to_hex() produces a valid hex string
         let key = PrivateKey::new_key();
@@ -464,4 +553,38 @@ mod tests {
         
         assert_eq!(address1, address2, "Same public key should produce same address");
     }
+
+    #[test]
+    fn test_private_key_debug_is_redacted() {
+        let key = PrivateKey::new_key();
+        let secret = hex::encode(key.0.to_bytes());
+        let debug = format!("{:?}", key);
+        assert!(!debug.contains(&secret));
+        assert_eq!(debug, "PrivateKey(\"<redacted>\")");
+    }
+
+    #[test]
+    fn test_private_key_round_trip() {
+        let key = PrivateKey::new_key();
+        let mut bytes = Vec::new();
+        key.save(&mut bytes).unwrap();
+        let loaded = PrivateKey::load(bytes.as_slice()).unwrap();
+        assert_eq!(loaded.public_key(), key.public_key());
+        // corrupted key bytes are an error rather than a panic
+        assert!(PrivateKey::load(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_key_export_formats() {
+        use k256::pkcs8::DecodePrivateKey;
+        let key = PrivateKey::new_key();
+        let pem = key.to_pem().unwrap();
+        let decoded: SigningKey<Secp256k1> = SigningKey::from_pkcs8_pem(&pem).unwrap();
+        assert_eq!(PrivateKey(decoded).public_key(), key.public_key());
+        assert_eq!(hex::decode(key.to_hex().as_str()).unwrap(), &key.0.to_bytes()[..]);
+
+        let public_pem = key.public_key().to_pem().unwrap();
+        let loaded = PublicKey::load(public_pem.as_bytes()).unwrap();
+        assert_eq!(loaded, key.public_key());
+    }
 }
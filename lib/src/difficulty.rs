@@ -0,0 +1,433 @@
+//! Targets and difficulties, and retargeting: how the target of the
+//! next block follows the time the last blocks took. `ChainParams`
+//! selects one of the algorithms of [`DifficultyAdjustment`].
+use crate::U256;
+use crate::params::DifficultyAdjustment;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use chrono::{DateTime, Utc};
+
+// solve times are clamped to this many ideal block times, so one block
+// with a timestamp far ahead can't collapse the difficulty
+const MAX_SOLVE_TIMES: i64 = 6;
+
+/// The target of the block at `height`, given the timestamps and
+/// targets of the blocks before it, oldest first, as many as
+/// `adjustment.history()` asks for. Stays at `current` when no
+/// retarget is due.
+pub fn next_target(
+    adjustment: DifficultyAdjustment,
+    height: u64,
+    current: U256,
+    recent: &[(DateTime<Utc>, U256)],
+) -> U256 {
+    let target = match adjustment {
+        DifficultyAdjustment::Interval => interval_target(height, current, recent),
+        DifficultyAdjustment::Lwma { .. } => lwma_target(current, recent),
+        DifficultyAdjustment::Ema { window } => ema_target(window, current, recent),
+    };
+    // if the new target is more than the minimum target, set it to the minimum target
+    target.min(crate::MIN_TARGET)
+}
+
+/// The median of the last `MEDIAN_TIME_SPAN` timestamps, or of all of
+/// them if there are fewer
+pub fn median_time_past(timestamps: impl IntoIterator<Item = DateTime<Utc>>) -> Option<DateTime<Utc>> {
+    let mut timestamps: Vec<_> = timestamps.into_iter().collect();
+    let skip = timestamps.len().saturating_sub(crate::MEDIAN_TIME_SPAN as usize);
+    let mut last = timestamps.split_off(skip);
+    last.sort();
+    last.get(last.len() / 2).copied()
+}
+
+// 2^64 as a float, the weight of a word of a U256 over the one below
+const WORD: f64 = 18_446_744_073_709_551_616.0;
+
+/// A U256 as the nearest float, losing all but the top 53 bits
+pub fn u256_to_f64(value: U256) -> f64 {
+    value.0.iter().rev().fold(0.0, |float, word| float * WORD + *word as f64)
+}
+
+/// A float as a U256, truncated, saturating at `U256::MAX` and 0 for
+/// anything below 1, NaN included
+pub fn f64_to_u256(value: f64) -> U256 {
+    if value.is_nan() || value < 1.0 {
+        return U256::zero();
+    }
+    let bits = value.to_bits();
+    let exponent = ((bits >> 52) & 0x7ff) as i32 - 1075;
+    let mantissa = (bits & ((1 << 52) - 1)) | (1 << 52);
+    if exponent > 203 {
+        U256::MAX
+    } else if exponent >= 0 {
+        U256::from(mantissa) << exponent as usize
+    } else {
+        U256::from(mantissa >> -exponent)
+    }
+}
+
+/// How many times harder than at `MIN_TARGET` meeting a target is, 1
+/// at the minimum difficulty
+pub fn difficulty_from_target(target: U256) -> f64 {
+    u256_to_f64(crate::MIN_TARGET) / u256_to_f64(target)
+}
+
+/// The target of a difficulty, `MIN_TARGET` for 1 and below
+pub fn target_from_difficulty(difficulty: f64) -> U256 {
+    if difficulty.is_nan() || difficulty <= 1.0 {
+        return crate::MIN_TARGET;
+    }
+    f64_to_u256(u256_to_f64(crate::MIN_TARGET) / difficulty).min(crate::MIN_TARGET)
+}
+
+/// The work a block meeting a target adds to its chain: the number of
+/// hashes it takes to meet, on average, 2^256 / (target + 1)
+pub fn work_from_target(target: U256) -> U256 {
+    if target == U256::MAX {
+        return U256::one();
+    }
+    // 2^256 doesn't fit, (2^256 - target - 1) / (target + 1) + 1 is the same
+    !target / (target + 1) + 1
+}
+
+/// A target in Bitcoin's compact `bits` form: its length in bytes and
+/// its top three bytes, the highest bit of which is a sign bit left
+/// clear. Precision below the top 23 bits is lost.
+pub fn target_to_compact(target: U256) -> u32 {
+    let mut size = target.bits().div_ceil(8);
+    let mut mantissa = if size <= 3 {
+        target.low_u32() << (8 * (3 - size))
+    } else {
+        (target >> (8 * (size - 3))).low_u32()
+    };
+    if mantissa & 0x0080_0000 != 0 {
+        mantissa >>= 8;
+        size += 1;
+    }
+    mantissa | (size as u32) << 24
+}
+
+/// The target of compact `bits`, None if negative or past 256 bits
+pub fn target_from_compact(compact: u32) -> Option<U256> {
+    let size = (compact >> 24) as usize;
+    let mantissa = compact & 0x007f_ffff;
+    if mantissa == 0 {
+        return Some(U256::zero());
+    }
+    if compact & 0x0080_0000 != 0 {
+        return None;
+    }
+    if size <= 3 {
+        return Some(U256::from(mantissa >> (8 * (3 - size))));
+    }
+    let target = U256::from(mantissa);
+    // the mantissa's highest set bit has to stay below bit 256
+    if target.bits() + 8 * (size - 3) > 256 {
+        return None;
+    }
+    Some(target << (8 * (size - 3)))
+}
+
+/// A difficulty with an SI prefix, like `1.50` or `2.31 M`
+pub fn format_difficulty(difficulty: f64) -> String {
+    const PREFIXES: [&str; 8] = ["K", "M", "G", "T", "P", "E", "Z", "Y"];
+    if difficulty.is_nan() || difficulty < 1000.0 {
+        return format!("{difficulty:.2}");
+    }
+    let mut value = difficulty;
+    let mut prefix = 0;
+    while value >= 1000.0 && prefix < PREFIXES.len() {
+        value /= 1000.0;
+        prefix += 1;
+    }
+    format!("{value:.2} {}", PREFIXES[prefix - 1])
+}
+
+// every DIFFICULTY_UPDATE_INTERVAL blocks, scale by the time the
+// interval took between the median times past at either end. A lying
+// miner moves neither median, and the time is clamped to 4x either way
+// of the ideal anyway.
+fn interval_target(height: u64, current: U256, recent: &[(DateTime<Utc>, U256)]) -> U256 {
+    if height == 0 || !height.is_multiple_of(crate::DIFFICULTY_UPDATE_INTERVAL) {
+        return current;
+    }
+    let timestamps = || recent.iter().map(|(timestamp, _)| *timestamp);
+    let split = recent.len().saturating_sub(crate::DIFFICULTY_UPDATE_INTERVAL as usize).max(1);
+    let (Some(start_time), Some(end_time)) =
+        (median_time_past(timestamps().take(split)), median_time_past(timestamps()))
+    else {
+        return current;
+    };
+    // calculate the ideal number of seconds
+    let target_seconds = (crate::IDEAL_BLOCK_TIME * crate::DIFFICULTY_UPDATE_INTERVAL) as i64;
+    let time_diff_seconds = (end_time - start_time)
+        .num_seconds()
+        .clamp(target_seconds / 4, target_seconds * 4);
+    // multiply the current target by actual time divided by ideal time
+    current
+        .checked_mul(U256::from(time_diff_seconds as u64))
+        .map_or(crate::MIN_TARGET, |scaled| scaled / target_seconds as u64)
+}
+
+// seconds between two blocks, at least 1 and at most MAX_SOLVE_TIMES
+// ideal block times
+fn solve_time(before: DateTime<Utc>, after: DateTime<Utc>) -> u64 {
+    let ideal = crate::IDEAL_BLOCK_TIME as i64;
+    (after - before).num_seconds().clamp(1, MAX_SOLVE_TIMES * ideal) as u64
+}
+
+// every block, scale the average target of the window by its solve
+// times, weighting recent ones more: the latest by n, the oldest by 1
+fn lwma_target(current: U256, recent: &[(DateTime<Utc>, U256)]) -> U256 {
+    let n = recent.len().saturating_sub(1) as u64;
+    if n == 0 {
+        return current;
+    }
+    let mut weighted = 0;
+    let mut targets = U256::zero();
+    for (weight, pair) in (1..).zip(recent.windows(2)) {
+        weighted += weight * solve_time(pair[0].0, pair[1].0);
+        // the genesis target isn't checked against the chain's, so cap them
+        targets += pair[1].1.min(crate::MIN_TARGET);
+    }
+    let average = targets / n;
+    let ideal_weighted = n * (n + 1) / 2 * crate::IDEAL_BLOCK_TIME;
+    (average / ideal_weighted)
+        .checked_mul(U256::from(weighted))
+        .unwrap_or(crate::MIN_TARGET)
+}
+
+// every block, move the target by the last solve time's distance from
+// the ideal, as one in `window` blocks
+fn ema_target(window: u64, current: U256, recent: &[(DateTime<Utc>, U256)]) -> U256 {
+    let [.., (before, _), (after, _)] = recent else {
+        return current;
+    };
+    let ideal = crate::IDEAL_BLOCK_TIME;
+    let span = window * ideal;
+    (current / span)
+        .checked_mul(U256::from(span - ideal + solve_time(*before, *after)))
+        .unwrap_or(crate::MIN_TARGET)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeDelta;
+
+    // blocks mined under `hashrate`, in hashes per second, against the
+    // algorithm's targets. Solve times are the expected ones, spread
+    // with a fixed sequence standing in for the luck of mining.
+    fn simulate(adjustment: DifficultyAdjustment, hashrate: impl Fn(u64) -> u64, blocks: u64) -> Vec<i64> {
+        let start = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let mut chain: Vec<(DateTime<Utc>, U256)> = vec![(start, crate::MIN_TARGET)];
+        let mut target = crate::MIN_TARGET;
+        let mut seed = 0x2545_f491_4f6c_dd1du64;
+        let mut times = vec![];
+        for height in 1..blocks {
+            // 2^256 / target hashes per block, on average
+            let hashes = (U256::MAX / target).low_u64();
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            let luck = 0.5 + (seed % 1000) as f64 / 1000.0;
+            let seconds = ((hashes as f64 / hashrate(height) as f64) * luck).max(1.0) as i64;
+            let time = chain.last().unwrap().0 + TimeDelta::seconds(seconds);
+            chain.push((time, target));
+            times.push(seconds);
+            let history = adjustment.history().min(chain.len() as u64) as usize;
+            target = next_target(adjustment, chain.len() as u64, target, &chain[chain.len() - history..]);
+        }
+        times
+    }
+
+    // mean distance of the solve times from the ideal, past a warm up
+    fn deviation(times: &[i64]) -> f64 {
+        let times = &times[100..];
+        let ideal = crate::IDEAL_BLOCK_TIME as f64;
+        times.iter().map(|&t| (t as f64 - ideal).abs()).sum::<f64>() / times.len() as f64
+    }
+
+    // hashrate jumping every 100 blocks between a lone laptop and a GPU
+    // joining the class
+    fn swinging(height: u64) -> u64 {
+        if (height / 100).is_multiple_of(2) { 10_000 } else { 400_000 }
+    }
+
+    #[test]
+    fn test_per_block_adjustment_is_steadier() {
+        let interval = deviation(&simulate(DifficultyAdjustment::Interval, swinging, 1200));
+        let lwma = deviation(&simulate(DifficultyAdjustment::lwma(), swinging, 1200));
+        let ema = deviation(&simulate(DifficultyAdjustment::ema(), swinging, 1200));
+        assert!(lwma * 2.0 < interval, "lwma {lwma} interval {interval}");
+        assert!(ema * 2.0 < interval, "ema {ema} interval {interval}");
+    }
+
+    #[test]
+    fn test_per_block_adjustment_converges() {
+        for adjustment in [DifficultyAdjustment::lwma(), DifficultyAdjustment::ema()] {
+            let times = simulate(adjustment, |_| 100_000, 600);
+            let tail = &times[400..];
+            let mean = tail.iter().sum::<i64>() as f64 / tail.len() as f64;
+            let ideal = crate::IDEAL_BLOCK_TIME as f64;
+            assert!((mean - ideal).abs() < ideal * 0.2, "{adjustment}: mean block time {mean}");
+        }
+    }
+
+    #[test]
+    fn test_solve_times_are_clamped() {
+        let start = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let target = crate::MIN_TARGET / 1000;
+        // a block claiming to have taken a day eases by at most 6x
+        let recent = [(start, target), (start + TimeDelta::days(1), target)];
+        let eased = next_target(DifficultyAdjustment::Lwma { window: 1 }, 2, target, &recent);
+        assert_eq!(eased, target / crate::IDEAL_BLOCK_TIME * (6 * crate::IDEAL_BLOCK_TIME));
+        let eased = next_target(DifficultyAdjustment::Ema { window: 10 }, 2, target, &recent);
+        assert!(eased <= target / 100 * 150);
+        // and never past the minimum difficulty
+        let recent = [(start, crate::MIN_TARGET), (start + TimeDelta::days(1), crate::MIN_TARGET)];
+        let eased = next_target(DifficultyAdjustment::lwma(), 2, crate::MIN_TARGET, &recent);
+        assert_eq!(eased, crate::MIN_TARGET);
+    }
+
+    #[test]
+    fn test_difficulty_of_target() {
+        assert_eq!(difficulty_from_target(crate::MIN_TARGET), 1.0);
+        assert_eq!(difficulty_from_target(crate::MIN_TARGET / 1000), 1000.0);
+        assert_eq!(target_from_difficulty(1.0), crate::MIN_TARGET);
+        assert_eq!(target_from_difficulty(0.5), crate::MIN_TARGET);
+        assert_eq!(target_from_difficulty(f64::NAN), crate::MIN_TARGET);
+        // MIN_TARGET is 2^240 - 1, which a float rounds to 2^240
+        assert_eq!(target_from_difficulty(256.0), U256::one() << 232);
+        let target = crate::MIN_TARGET / 12345;
+        let round_trip = target_from_difficulty(difficulty_from_target(target));
+        // a float keeps 53 bits
+        assert!(round_trip.max(target) - round_trip.min(target) < target >> 50);
+
+        assert_eq!(f64_to_u256(u256_to_f64(U256::from(u64::MAX))), U256::from(u64::MAX) + 1);
+        assert_eq!(f64_to_u256(0.9), U256::zero());
+        assert_eq!(f64_to_u256(1e100), U256::MAX);
+    }
+
+    #[test]
+    fn test_work_from_target() {
+        assert_eq!(work_from_target(U256::MAX), U256::one());
+        assert_eq!(work_from_target(U256::MAX >> 1), U256::from(2u64));
+        assert_eq!(work_from_target(crate::MIN_TARGET), U256::one() << 16);
+        assert_eq!(work_from_target(crate::MIN_TARGET >> 4), U256::one() << 20);
+    }
+
+    #[test]
+    fn test_compact_targets() {
+        // Bitcoin's genesis bits
+        let target = target_from_compact(0x1d00_ffff).unwrap();
+        assert_eq!(target, U256::from(0xffffu64) << 208);
+        assert_eq!(target_to_compact(target), 0x1d00_ffff);
+        assert_eq!(target_to_compact(crate::MIN_TARGET), 0x1f00_ffff);
+        assert_eq!(target_from_compact(0x1f00_ffff), Some(U256::from(0xffffu64) << 224));
+        // the sign bit moves into the length
+        assert_eq!(target_to_compact(U256::from(0x80u64)), 0x0200_8000);
+        assert_eq!(target_from_compact(0x0200_8000), Some(U256::from(0x80u64)));
+        assert_eq!(target_to_compact(U256::zero()), 0);
+        assert_eq!(target_from_compact(0x0180_0001), None);
+        assert_eq!(target_from_compact(0x2101_0000), None);
+        assert_eq!(target_from_compact(0x2100_ffff), Some(U256::from(0xffffu64) << 240));
+    }
+
+    #[test]
+    fn test_format_difficulty() {
+        assert_eq!(format_difficulty(1.0), "1.00");
+        assert_eq!(format_difficulty(999.994), "999.99");
+        assert_eq!(format_difficulty(1500.0), "1.50 K");
+        assert_eq!(format_difficulty(2.31e6), "2.31 M");
+        assert_eq!(format_difficulty(4.2e30), "4200000.00 Y");
+    }
+
+    // a chain of `count` blocks at `target` spaced by the ideal block time
+    fn steady(count: u64, target: U256) -> Vec<(DateTime<Utc>, U256)> {
+        let start = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let ideal = crate::IDEAL_BLOCK_TIME as i64;
+        (0..count).map(|i| (start + TimeDelta::seconds(i as i64 * ideal), target)).collect()
+    }
+
+    #[test]
+    fn test_median_time_past() {
+        let recent = steady(20, crate::MIN_TARGET);
+        let timestamps = recent.iter().map(|(timestamp, _)| *timestamp);
+        assert_eq!(median_time_past(timestamps.clone()), Some(recent[14].0));
+        assert_eq!(median_time_past(timestamps.take(3)), Some(recent[1].0));
+        assert_eq!(median_time_past([]), None);
+    }
+
+    #[test]
+    fn test_interval_ignores_outlying_timestamps() {
+        let target = crate::MIN_TARGET / 1000;
+        let history = DifficultyAdjustment::Interval.history();
+        let height = 2 * crate::DIFFICULTY_UPDATE_INTERVAL;
+        let mut recent = steady(history, target);
+        assert_eq!(next_target(DifficultyAdjustment::Interval, height, target, &recent), target);
+
+        // the last block claiming a day has passed changes nothing
+        recent.last_mut().unwrap().0 += TimeDelta::days(1);
+        assert_eq!(next_target(DifficultyAdjustment::Interval, height, target, &recent), target);
+        // and neither does the first claiming to be a day old
+        recent[0].0 -= TimeDelta::days(1);
+        assert_eq!(next_target(DifficultyAdjustment::Interval, height, target, &recent), target);
+
+        // most of the interval's miners lying eases it by 4x at most
+        let lying = recent.len() - crate::MEDIAN_TIME_SPAN as usize / 2 - 1;
+        for (timestamp, _) in &mut recent[lying..] {
+            *timestamp += TimeDelta::days(365);
+        }
+        assert_eq!(next_target(DifficultyAdjustment::Interval, height, target, &recent), target * 4);
+        // and backwards by 4x at most
+        let recent: Vec<_> = steady(history, target)
+            .into_iter()
+            .enumerate()
+            .map(|(i, (timestamp, target))| (timestamp - TimeDelta::seconds(i as i64 * 9), target))
+            .collect();
+        assert_eq!(next_target(DifficultyAdjustment::Interval, height, target, &recent), target / 4);
+    }
+
+    #[test]
+    fn test_adversarial_timestamps_are_bounded() {
+        let ideal = crate::IDEAL_BLOCK_TIME as i64;
+        for adjustment in [DifficultyAdjustment::Interval, DifficultyAdjustment::lwma(), DifficultyAdjustment::ema()] {
+            let mut chain = steady(1, crate::MIN_TARGET / 1_000_000);
+            let mut target = chain[0].1;
+            let mut seed = 0x9e37_79b9_7f4a_7c15u64;
+            for _ in 0..500 {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                // an attacker picks any timestamp past the last block's
+                let step = match seed % 4 {
+                    0 => 1,
+                    1 => ideal * 1000,
+                    2 => 86_400 * 365,
+                    _ => (seed % 100) as i64 + 1,
+                };
+                let time = chain.last().unwrap().0 + TimeDelta::seconds(step);
+                chain.push((time, target));
+                let history = adjustment.history().min(chain.len() as u64) as usize;
+                let recent = &chain[chain.len() - history..];
+                let next = next_target(adjustment, chain.len() as u64, target, recent);
+                let bound = match adjustment {
+                    DifficultyAdjustment::Interval => target * 4,
+                    // six ideal solve times over the window's average target
+                    DifficultyAdjustment::Lwma { .. } => {
+                        recent[1..].iter().map(|(_, target)| *target).max().unwrap() * MAX_SOLVE_TIMES as u64
+                    }
+                    DifficultyAdjustment::Ema { window } => target / window * (window + MAX_SOLVE_TIMES as u64 - 1),
+                };
+                assert!(next <= bound, "{adjustment} eased {target} to {next}");
+                assert!(next <= crate::MIN_TARGET);
+                if adjustment == DifficultyAdjustment::Interval {
+                    assert!(next >= target / 4, "{adjustment} hardened {target} to {next}");
+                }
+                target = next;
+            }
+        }
+    }
+}
@@ -0,0 +1,528 @@
+//! Canonical byte encoding of transactions, also used for blocks in the
+//! compact wire format.
+//!
+//! Transaction ids and output hashes are SHA-256 over this encoding
+//! instead of over the serde representation, so they don't change when
+//! fields are renamed or reordered. Every value has exactly one
+//! encoding; decoding rejects anything else.
+//!
+//! Integers are little-endian. Counts and lengths are varints in
+//! Bitcoin's CompactSize form: below 0xfd a single byte, otherwise a
+//! 0xfd, 0xfe or 0xff marker followed by a u16, u32 or u64, always the
+//! shortest that fits.
+//!
+//! ```text
+//! transaction  version: u8 = ENCODING_VERSION
+//!              varint input count, inputs
+//!              varint output count, outputs
+//! input        previous output hash: 32 bytes, as `Hash::as_bytes`
+//!              public key: 33 bytes, SEC1 compressed
+//!              signature: 64 bytes, r || s
+//!              multisig policy: 0x00 if none, else 0x01 followed by
+//!                varint threshold, varint key count, keys (33 bytes each)
+//!              varint cosignature count, cosignatures (key, signature)
+//! output       value: u64
+//!              unique id: 16 bytes
+//!              address: varint length, UTF-8 bytes
+//! block        header, varint transaction count, transactions
+//! header       timestamp: i64 seconds, u32 nanoseconds
+//!              nonce: u64
+//!              previous block hash, merkle root: 32 bytes each
+//!              target: 32 bytes
+//! proof        header, varint height, transaction hash,
+//!              varint index, varint branch length, branch hashes
+//! ```
+
+use crate::crypto::{PublicKey, Signature};
+use crate::error::{BtcError, Result};
+use crate::multisig::MultisigPolicy;
+use crate::sha256::Hash;
+use crate::types::{
+    Block, BlockHeader, Transaction, TransactionInput, TransactionOutput, TransactionProof,
+};
+use crate::util::{MerkleProof, MerkleRoot};
+use crate::U256;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use chrono::DateTime;
+use uuid::Uuid;
+
+/// Version byte leading every encoded transaction
+pub const ENCODING_VERSION: u8 = 1;
+
+pub trait Encode {
+    fn encode(&self, out: &mut Vec<u8>);
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out = vec![];
+        self.encode(&mut out);
+        out
+    }
+}
+
+pub trait Decode: Sized {
+    /// Decode from the start of `input`, advancing past the bytes read
+    fn decode(input: &mut &[u8]) -> Result<Self>;
+
+    /// Decode a value that must span all of `bytes`
+    fn from_bytes(mut bytes: &[u8]) -> Result<Self> {
+        let value = Self::decode(&mut bytes)?;
+        if !bytes.is_empty() {
+            return Err(BtcError::InvalidEncoding);
+        }
+        Ok(value)
+    }
+}
+
+pub fn write_varint(out: &mut Vec<u8>, n: u64) {
+    match n {
+        0..0xfd => out.push(n as u8),
+        0xfd..=0xffff => {
+            out.push(0xfd);
+            out.extend((n as u16).to_le_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            out.push(0xfe);
+            out.extend((n as u32).to_le_bytes());
+        }
+        _ => {
+            out.push(0xff);
+            out.extend(n.to_le_bytes());
+        }
+    }
+}
+
+pub fn read_varint(input: &mut &[u8]) -> Result<u64> {
+    let (n, min) = match read_array::<1>(input)?[0] {
+        0xfd => (u16::from_le_bytes(read_array(input)?) as u64, 0xfd),
+        0xfe => (u32::from_le_bytes(read_array(input)?) as u64, 0x1_0000),
+        0xff => (u64::from_le_bytes(read_array(input)?), 0x1_0000_0000),
+        n => (n as u64, 0),
+    };
+    // a longer form than needed would be a second encoding
+    if n < min {
+        return Err(BtcError::InvalidEncoding);
+    }
+    Ok(n)
+}
+
+pub(crate) fn read_array<const N: usize>(input: &mut &[u8]) -> Result<[u8; N]> {
+    let (bytes, rest) = input
+        .split_first_chunk::<N>()
+        .ok_or(BtcError::InvalidEncoding)?;
+    *input = rest;
+    Ok(*bytes)
+}
+
+pub(crate) fn read_len(input: &mut &[u8]) -> Result<usize> {
+    usize::try_from(read_varint(input)?).map_err(|_| BtcError::InvalidEncoding)
+}
+
+pub(crate) fn encode_list<T: Encode>(out: &mut Vec<u8>, items: &[T]) {
+    write_varint(out, items.len() as u64);
+    for item in items {
+        item.encode(out);
+    }
+}
+
+pub(crate) fn decode_list<T: Decode>(input: &mut &[u8]) -> Result<Vec<T>> {
+    let len = read_len(input)?;
+    // no capacity up front, the count is not trusted
+    let mut items = vec![];
+    for _ in 0..len {
+        items.push(T::decode(input)?);
+    }
+    Ok(items)
+}
+
+impl Encode for String {
+    fn encode(&self, out: &mut Vec<u8>) {
+        write_varint(out, self.len() as u64);
+        out.extend(self.as_bytes());
+    }
+}
+
+impl Decode for String {
+    fn decode(input: &mut &[u8]) -> Result<Self> {
+        let len = read_len(input)?;
+        if input.len() < len {
+            return Err(BtcError::InvalidEncoding);
+        }
+        let (bytes, rest) = input.split_at(len);
+        let string = String::from_utf8(bytes.to_vec()).map_err(|_| BtcError::InvalidEncoding)?;
+        *input = rest;
+        Ok(string)
+    }
+}
+
+impl Encode for Hash {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend(self.as_bytes());
+    }
+}
+
+impl Decode for Hash {
+    fn decode(input: &mut &[u8]) -> Result<Self> {
+        read_array(input).map(Hash::from_bytes)
+    }
+}
+
+impl Encode for PublicKey {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend(self.to_compressed_bytes());
+    }
+}
+
+impl Decode for PublicKey {
+    fn decode(input: &mut &[u8]) -> Result<Self> {
+        PublicKey::from_compressed_bytes(&read_array::<33>(input)?)
+    }
+}
+
+impl Encode for Signature {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend(self.to_bytes());
+    }
+}
+
+impl Decode for Signature {
+    fn decode(input: &mut &[u8]) -> Result<Self> {
+        Signature::from_bytes(&read_array::<64>(input)?)
+    }
+}
+
+impl<A: Encode, B: Encode> Encode for (A, B) {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.0.encode(out);
+        self.1.encode(out);
+    }
+}
+
+impl<A: Decode, B: Decode> Decode for (A, B) {
+    fn decode(input: &mut &[u8]) -> Result<Self> {
+        Ok((A::decode(input)?, B::decode(input)?))
+    }
+}
+
+impl Encode for bool {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(*self as u8);
+    }
+}
+
+impl Decode for bool {
+    fn decode(input: &mut &[u8]) -> Result<Self> {
+        match read_array::<1>(input)?[0] {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(BtcError::InvalidEncoding),
+        }
+    }
+}
+
+impl Encode for MultisigPolicy {
+    fn encode(&self, out: &mut Vec<u8>) {
+        write_varint(out, self.threshold() as u64);
+        encode_list(out, self.public_keys());
+    }
+}
+
+impl Decode for MultisigPolicy {
+    fn decode(input: &mut &[u8]) -> Result<Self> {
+        let threshold = read_len(input)?;
+        let public_keys: Vec<PublicKey> = decode_list(input)?;
+        // policies keep their keys sorted, anything else is not canonical
+        if !public_keys.is_sorted_by(|a, b| a < b) {
+            return Err(BtcError::InvalidEncoding);
+        }
+        MultisigPolicy::new(threshold, public_keys)
+    }
+}
+
+impl Encode for TransactionInput {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.prev_transaction_output_hash.encode(out);
+        self.public_key.encode(out);
+        self.signature.encode(out);
+        match &self.multisig {
+            Some(policy) => {
+                out.push(1);
+                policy.encode(out);
+            }
+            None => out.push(0),
+        }
+        encode_list(out, &self.cosignatures);
+    }
+}
+
+impl Decode for TransactionInput {
+    fn decode(input: &mut &[u8]) -> Result<Self> {
+        let prev_transaction_output_hash = Hash::decode(input)?;
+        let public_key = PublicKey::decode(input)?;
+        let signature = Signature::decode(input)?;
+        let multisig = match read_array::<1>(input)?[0] {
+            0 => None,
+            1 => Some(MultisigPolicy::decode(input)?),
+            _ => return Err(BtcError::InvalidEncoding),
+        };
+        Ok(TransactionInput {
+            prev_transaction_output_hash,
+            public_key,
+            signature,
+            multisig,
+            cosignatures: decode_list(input)?,
+        })
+    }
+}
+
+impl Encode for TransactionOutput {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend(self.value.to_le_bytes());
+        out.extend(self.unique_id.as_bytes());
+        self.address.encode(out);
+    }
+}
+
+impl Decode for TransactionOutput {
+    fn decode(input: &mut &[u8]) -> Result<Self> {
+        let value = u64::from_le_bytes(read_array(input)?);
+        let unique_id = Uuid::from_bytes(read_array(input)?);
+        Ok(TransactionOutput {
+            value,
+            unique_id,
+            address: String::decode(input)?,
+        })
+    }
+}
+
+impl Encode for Transaction {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(ENCODING_VERSION);
+        encode_list(out, &self.inputs);
+        encode_list(out, &self.outputs);
+    }
+}
+
+impl Decode for Transaction {
+    fn decode(input: &mut &[u8]) -> Result<Self> {
+        if read_array::<1>(input)?[0] != ENCODING_VERSION {
+            return Err(BtcError::InvalidEncoding);
+        }
+        let inputs = decode_list(input)?;
+        let outputs = decode_list(input)?;
+        Ok(Transaction { inputs, outputs })
+    }
+}
+
+impl Encode for BlockHeader {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend(self.timestamp.timestamp().to_le_bytes());
+        out.extend(self.timestamp.timestamp_subsec_nanos().to_le_bytes());
+        out.extend(self.nonce.to_le_bytes());
+        self.prev_block_hash.encode(out);
+        self.merkle_root.hash().encode(out);
+        out.extend(self.target.to_little_endian());
+    }
+}
+
+impl Decode for BlockHeader {
+    fn decode(input: &mut &[u8]) -> Result<Self> {
+        let seconds = i64::from_le_bytes(read_array(input)?);
+        let nanos = u32::from_le_bytes(read_array(input)?);
+        // nanoseconds past a second would be a leap second, which
+        // chrono would otherwise accept
+        if nanos >= 1_000_000_000 {
+            return Err(BtcError::InvalidEncoding);
+        }
+        let timestamp = DateTime::from_timestamp(seconds, nanos).ok_or(BtcError::InvalidEncoding)?;
+        Ok(BlockHeader {
+            timestamp,
+            nonce: u64::from_le_bytes(read_array(input)?),
+            prev_block_hash: Hash::decode(input)?,
+            merkle_root: MerkleRoot::from_hash(Hash::decode(input)?),
+            target: U256::from_little_endian(&read_array::<32>(input)?),
+        })
+    }
+}
+
+impl Encode for Block {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.header.encode(out);
+        encode_list(out, &self.transactions);
+    }
+}
+
+impl Decode for Block {
+    fn decode(input: &mut &[u8]) -> Result<Self> {
+        let header = BlockHeader::decode(input)?;
+        Ok(Block::new(header, decode_list(input)?))
+    }
+}
+
+impl Encode for TransactionProof {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.header.encode(out);
+        write_varint(out, self.height);
+        self.txid.encode(out);
+        write_varint(out, self.proof.index as u64);
+        encode_list(out, &self.proof.branch);
+    }
+}
+
+impl Decode for TransactionProof {
+    fn decode(input: &mut &[u8]) -> Result<Self> {
+        Ok(TransactionProof {
+            header: BlockHeader::decode(input)?,
+            height: read_varint(input)?,
+            txid: Hash::decode(input)?,
+            proof: MerkleProof {
+                index: read_len(input)?,
+                branch: decode_list(input)?,
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::PrivateKey;
+
+    // a transaction built only from fixed data, signatures are
+    // deterministic (RFC 6979)
+    fn golden_transaction() -> Transaction {
+        let key = PrivateKey::from_seed(&[7; 64]).unwrap();
+        let prev_transaction_output_hash = Hash::hash_bytes(b"previous output");
+        Transaction::new(
+            vec![TransactionInput {
+                prev_transaction_output_hash,
+                public_key: key.public_key(),
+                signature: Signature::sign_output(&prev_transaction_output_hash, &key),
+                multisig: None,
+                cosignatures: vec![],
+            }],
+            vec![TransactionOutput {
+                value: 5_000_000_000,
+                unique_id: Uuid::from_u128(0x0123_4567_89ab_cdef_0123_4567_89ab_cdef),
+                address: key.public_key().to_address(),
+            }],
+        )
+    }
+
+    #[test]
+    fn test_varints() {
+        for (n, encoded) in [
+            (0, "00"),
+            (0xfc, "fc"),
+            (0xfd, "fdfd00"),
+            (0xffff, "fdffff"),
+            (0x1_0000, "fe00000100"),
+            (0x1_0000_0000, "ff0000000001000000"),
+        ] {
+            let mut out = vec![];
+            write_varint(&mut out, n);
+            assert_eq!(hex::encode(&out), encoded);
+            assert_eq!(read_varint(&mut out.as_slice()).unwrap(), n);
+        }
+        // 1 written in three bytes
+        assert!(read_varint(&mut [0xfd, 1, 0].as_slice()).is_err());
+        assert!(read_varint(&mut [0xfe, 1].as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_golden_output() {
+        let output = &golden_transaction().outputs[0];
+        assert_eq!(
+            hex::encode(output.to_bytes()),
+            concat!(
+                "00f2052a01000000",
+                "0123456789abcdef0123456789abcdef",
+                "22",
+                // 1KJMWcAeAJQ55xL1FNPTWvsyJFW96p4wCX
+                "314b4a4d57634165414a513535784c31464e5054577673794a465739367034774358",
+            )
+        );
+        assert_eq!(
+            output.hash().to_string(),
+            "b114c3f2de4ff76b0b59408a5a8aa6adb71fe00c985e468217ed52b1a6e305d"
+        );
+    }
+
+    #[test]
+    fn test_golden_transaction() {
+        let transaction = golden_transaction();
+        assert_eq!(
+            hex::encode(transaction.to_bytes()),
+            concat!(
+                // version, one input
+                "0101",
+                // previous output hash, public key, signature
+                "4e27e00e522e63a2b63b7f1dc0dfb199a68ad2acb81e56b4eb4437f5c270929e",
+                "03f3322790212a45add515ee786297272bfe86fe0d51e9a1f360e9d030179efd49",
+                "1264d3af680e80ea50785de9c44cd4c5bd7404486ff9e3087290cc8807f4d7eb4a991f0e216bc9c818e1e0e026a0761958da1c76676209b4ee400013e8555f4c",
+                // no multisig policy, no cosignatures
+                "00",
+                "00",
+                // one output, as in test_golden_output
+                "01",
+                "00f2052a010000000123456789abcdef0123456789abcdef22314b4a4d57634165414a513535784c31464e5054577673794a465739367034774358",
+            )
+        );
+        assert_eq!(
+            transaction.hash().to_string(),
+            "d73e004d78d2ab9dbb73362e2f5db3009ffc38860fce54f3f2d8ab01c48cd36f"
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut transaction = golden_transaction();
+        let keys: Vec<_> = (0..3).map(|_| PrivateKey::new_key()).collect();
+        let policy =
+            MultisigPolicy::new(2, keys.iter().map(|key| key.public_key()).collect()).unwrap();
+        let hash = Hash::hash_bytes(b"multisig output");
+        transaction.inputs.push(TransactionInput {
+            prev_transaction_output_hash: hash,
+            public_key: keys[0].public_key(),
+            signature: Signature::sign_output(&hash, &keys[0]),
+            multisig: Some(policy),
+            cosignatures: vec![(keys[1].public_key(), Signature::sign_output(&hash, &keys[1]))],
+        });
+
+        let bytes = transaction.to_bytes();
+        let decoded = Transaction::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.to_bytes(), bytes);
+        assert_eq!(decoded.hash(), transaction.hash());
+
+        assert!(Transaction::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(Transaction::from_bytes(&trailing).is_err());
+        let mut other_version = bytes;
+        other_version[0] = ENCODING_VERSION + 1;
+        assert!(Transaction::from_bytes(&other_version).is_err());
+    }
+
+    #[test]
+    fn test_transaction_proof_round_trip() {
+        let transactions = vec![golden_transaction(); 3];
+        let header = BlockHeader::new(
+            DateTime::from_timestamp(1_700_000_000, 123).unwrap(),
+            42,
+            Hash::hash_bytes(b"previous block"),
+            MerkleRoot::calculate(&transactions),
+            U256::MAX,
+        );
+        let block = Block::new(header, transactions);
+        let proof = block.transaction_proof(7, golden_transaction().hash()).unwrap();
+
+        let bytes = proof.to_bytes();
+        let decoded = TransactionProof::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.to_bytes(), bytes);
+        assert_eq!(decoded.header_hash(), block.header.hash());
+        assert_eq!(decoded.height, 7);
+        assert!(decoded.verify());
+        assert!(TransactionProof::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+}
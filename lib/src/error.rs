@@ -5,14 +5,42 @@ pub enum BtcError {
     InvalidTransaction,
     #[error("Invalid block")]
     InvalidBlock,
+    #[error("Block's parent is unknown")]
+    UnknownParent,
+    #[error("Block is already known")]
+    KnownBlock,
+    #[error("Block is unknown")]
+    UnknownBlock,
+    #[error("Block is too deep in the chain to take off")]
+    IrreversibleBlock,
+    #[error("Block contradicts a checkpoint")]
+    CheckpointMismatch,
+    #[error("Checkpoint isn't signed by the network's checkpoint key")]
+    UntrustedCheckpoint,
     #[error("Invalid block header")]
     InvalidBlockHeader,
+    #[error("Block's target isn't the one the chain requires at its height")]
+    WrongTarget,
+    #[error("Block does not start with a coinbase transaction")]
+    MissingCoinbase,
+    #[error("Coinbase transaction outside the start of a block")]
+    UnexpectedCoinbase,
+    #[error("Coinbase transaction has no outputs")]
+    EmptyCoinbase,
+    #[error("Coinbase claims {claimed} but the reward and fees come to {allowed}")]
+    CoinbaseTooLarge { claimed: u64, allowed: u64 },
+    #[error("Transaction is already in the chain")]
+    DuplicateTransaction,
+    #[error("Output is already unspent in the chain")]
+    DuplicateOutput,
     #[error("Invalid transaction input")]
     InvalidTransactionInput,
     #[error("Invalid transaction output")]
     InvalidTransactionOutput,
     #[error("Invalid Merkle root")]
     InvalidMerkleRoot,
+    #[error("Invalid amount")]
+    InvalidAmount,
     #[error("Invalid hash")]
     InvalidHash,
     #[error("Invalid signature")]
@@ -21,6 +49,24 @@ pub enum BtcError {
     InvalidPublicKey,
     #[error("Invalid private key")]
     InvalidPrivateKey,
+    #[error("Invalid mnemonic")]
+    InvalidMnemonic,
+    #[error("Invalid derivation path")]
+    InvalidDerivationPath,
+    #[error("No private key for a transaction input")]
+    MissingSigningKey,
+    #[error("Transaction is not fully signed")]
+    IncompleteSignatures,
+    #[error("Partially signed transactions are for different transactions")]
+    PsbtMismatch,
+    #[error("Invalid multisig policy")]
+    InvalidMultisigPolicy,
+    #[error("Invalid address")]
+    InvalidAddress,
+    #[error("Address belongs to another network")]
+    WrongNetwork,
+    #[error("Invalid encoding")]
+    InvalidEncoding,
 }
 
-pub type Result<T> = std::result::Result<T, BtcError>;
\ No newline at end of file
+pub type Result<T> = core::result::Result<T, BtcError>;
\ No newline at end of file
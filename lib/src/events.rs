@@ -0,0 +1,123 @@
+//! Events nodes publish to subscribers as the chain and mempool change
+use crate::types::{Block, Transaction};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// Something that happened to the chain or the mempool. Hashes are
+/// in the hex form hashes are displayed in.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChainEvent {
+    /// A block was connected at the tip
+    Block {
+        height: u64,
+        hash: String,
+        timestamp: DateTime<Utc>,
+        transactions: usize,
+    },
+    /// A transaction entered the mempool
+    Transaction {
+        hash: String,
+        /// None when the node can't tell, e.g. for a transaction
+        /// spending outputs it doesn't know
+        fee: Option<u64>,
+        size: usize,
+    },
+    /// The blocks above `fork_height` were replaced by another branch
+    Reorg {
+        fork_height: u64,
+        old_tip: String,
+        new_tip: String,
+    },
+}
+
+impl ChainEvent {
+    pub fn block(height: u64, block: &Block) -> Self {
+        ChainEvent::Block {
+            height,
+            hash: block.hash().to_string(),
+            timestamp: block.header.timestamp,
+            transactions: block.transactions.len(),
+        }
+    }
+
+    pub fn transaction(transaction: &Transaction, fee: Option<u64>) -> Self {
+        ChainEvent::Transaction {
+            hash: transaction.hash().to_string(),
+            fee,
+            size: transaction.size(),
+        }
+    }
+
+    pub fn topic(&self) -> Topic {
+        match self {
+            ChainEvent::Block { .. } => Topic::Blocks,
+            ChainEvent::Transaction { .. } => Topic::Transactions,
+            ChainEvent::Reorg { .. } => Topic::Reorgs,
+        }
+    }
+}
+
+/// Kinds of events a subscriber can filter on
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Topic {
+    Blocks,
+    Transactions,
+    Reorgs,
+}
+
+impl Topic {
+    pub const ALL: [Topic; 3] = [Topic::Blocks, Topic::Transactions, Topic::Reorgs];
+}
+
+impl fmt::Display for Topic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Topic::Blocks => write!(f, "blocks"),
+            Topic::Transactions => write!(f, "transactions"),
+            Topic::Reorgs => write!(f, "reorgs"),
+        }
+    }
+}
+
+impl FromStr for Topic {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Topic::ALL
+            .into_iter()
+            .find(|topic| topic.to_string() == s.trim().to_ascii_lowercase())
+            .ok_or_else(|| format!("unknown topic {s:?}, expected blocks, transactions or reorgs"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_events_are_tagged_json() {
+        let event = ChainEvent::Reorg {
+            fork_height: 3,
+            old_tip: "ab".to_string(),
+            new_tip: "cd".to_string(),
+        };
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["type"], "reorg");
+        assert_eq!(json["fork_height"], 3);
+        assert_eq!(serde_json::from_value::<ChainEvent>(json).unwrap(), event);
+        assert_eq!(event.topic(), Topic::Reorgs);
+    }
+
+    #[test]
+    fn test_topics_parse() {
+        for topic in Topic::ALL {
+            assert_eq!(topic.to_string().parse::<Topic>(), Ok(topic));
+        }
+        assert_eq!(" Blocks".parse::<Topic>(), Ok(Topic::Blocks));
+        assert!("utxos".parse::<Topic>().is_err());
+    }
+}
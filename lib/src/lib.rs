@@ -1,11 +1,34 @@
+// the U256 expansion from construct_uint! trips this lint
+#![allow(clippy::manual_div_ceil)]
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
 use serde::{Deserialize, Serialize};
 use uint::construct_uint;
 
+pub mod address;
+pub mod amount;
+#[cfg(feature = "std")]
+pub mod approval;
+pub mod bip32;
 pub mod crypto;
+pub mod difficulty;
+pub mod encoding;
 pub mod error;
+#[cfg(feature = "network")]
+pub mod events;
+#[cfg(feature = "std")]
+pub mod mining;
+pub mod multisig;
+pub mod muhash;
+pub mod params;
 pub mod sha256;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 pub mod types;
 pub mod util;
+#[cfg(feature = "network")]
 pub mod network;
 
 construct_uint! {
@@ -15,6 +38,31 @@ construct_uint! {
     pub struct U256(4);
 }
 
+/// Serde helpers writing a U256 in hex for human readable formats,
+/// and as the derived impls do otherwise
+pub(crate) mod u256_hex {
+    use super::U256;
+    use alloc::string::String;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &U256, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.collect_str(&format_args!("{value:x}"))
+        } else {
+            value.serialize(serializer)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<U256, D::Error> {
+        if deserializer.is_human_readable() {
+            let hex = String::deserialize(deserializer)?;
+            U256::from_str_radix(&hex, 16).map_err(serde::de::Error::custom)
+        } else {
+            U256::deserialize(deserializer)
+        }
+    }
+}
+
 // initial reward in bitcoin - multiply by 10^8 to get satoshis
 pub const INITIAL_REWARD: u64 = 50;
 
@@ -33,7 +81,12 @@ pub const MIN_TARGET: U256 = U256([
 ]);
 pub const DIFFICULTY_UPDATE_INTERVAL: u64 = 50;
 
-// maximum mempool transaction age in seconds
+// blocks whose median timestamp stands for the time at the last of
+// them, so a few miners lying about the time can't move it
+pub const MEDIAN_TIME_SPAN: u64 = 11;
+
+// default maximum mempool transaction age in seconds, nodes may
+// configure another
 pub const MAX_MEMPOOL_TRANSACTION_AGE: u64 = 600;
 
 pub const BLOCK_TRANSACTION_CAP: usize = 20;
\ No newline at end of file
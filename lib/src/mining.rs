@@ -0,0 +1,254 @@
+//! Mining threads working on a shared template, and the hashrate and
+//! block statistics they keep. Used by the standalone miner and by
+//! nodes mining in-process.
+use crate::types::Block;
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Hashes tried between looks at the template
+pub const MINE_STEPS: usize = 100_000;
+
+/// Counters updated by the mining threads and whoever hands them
+/// templates
+pub struct Stats {
+    started: Instant,
+    // hashes tried by each mining thread
+    hashes: Vec<AtomicU64>,
+    accepted: AtomicU64,
+    stale: AtomicU64,
+    template_fetched: Mutex<Option<Instant>>,
+    // the last report, with rates over the interval before it
+    report: Mutex<Report>,
+}
+
+/// What is logged and shown of the stats
+#[derive(Serialize, Clone, Default, Debug)]
+pub struct Report {
+    /// Hashes per second of all threads
+    pub hashrate: f64,
+    pub thread_hashrates: Vec<f64>,
+    pub total_hashes: u64,
+    /// Blocks found that the node built on
+    pub accepted: u64,
+    /// Blocks found that another block beat to the node's chain
+    pub stale: u64,
+    /// Seconds since the template being mined was fetched
+    pub template_age: Option<u64>,
+    pub uptime: u64,
+}
+
+impl Stats {
+    pub fn new(threads: usize) -> Self {
+        Self {
+            started: Instant::now(),
+            hashes: (0..threads).map(|_| AtomicU64::new(0)).collect(),
+            accepted: AtomicU64::new(0),
+            stale: AtomicU64::new(0),
+            template_fetched: Mutex::new(None),
+            report: Mutex::new(Report::default()),
+        }
+    }
+
+    /// Number of mining threads counted
+    pub fn threads(&self) -> usize {
+        self.hashes.len()
+    }
+
+    pub fn add_hashes(&self, thread: usize, hashes: u64) {
+        self.hashes[thread].fetch_add(hashes, Ordering::Relaxed);
+    }
+
+    pub fn block_accepted(&self) {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn block_stale(&self) {
+        self.stale.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn template_fetched(&self) {
+        *self.template_fetched.lock().unwrap() = Some(Instant::now());
+    }
+
+    fn template_age(&self) -> Option<u64> {
+        self.template_fetched.lock().unwrap().map(|fetched| fetched.elapsed().as_secs())
+    }
+
+    /// Work out the rates since the previous report from the hashes
+    /// counted then, and keep the report for `current`
+    pub fn report(&self, previous: &mut Vec<u64>, elapsed: Duration) -> Report {
+        let hashes: Vec<u64> = self.hashes.iter().map(|count| count.load(Ordering::Relaxed)).collect();
+        let seconds = elapsed.as_secs_f64().max(f64::EPSILON);
+        let thread_hashrates: Vec<f64> = hashes
+            .iter()
+            .zip(previous.iter().chain(std::iter::repeat(&0)))
+            .map(|(now, before)| (now - before) as f64 / seconds)
+            .collect();
+        let report = Report {
+            hashrate: thread_hashrates.iter().sum(),
+            thread_hashrates,
+            total_hashes: hashes.iter().sum(),
+            accepted: self.accepted.load(Ordering::Relaxed),
+            stale: self.stale.load(Ordering::Relaxed),
+            template_age: self.template_age(),
+            uptime: self.started.elapsed().as_secs(),
+        };
+        *previous = hashes;
+        *self.report.lock().unwrap() = report.clone();
+        report
+    }
+
+    /// The rates of the last report with the counts and template age
+    /// as of now
+    pub fn current(&self) -> Report {
+        let mut report = self.report.lock().unwrap().clone();
+        report.total_hashes = self.hashes.iter().map(|count| count.load(Ordering::Relaxed)).sum();
+        report.accepted = self.accepted.load(Ordering::Relaxed);
+        report.stale = self.stale.load(Ordering::Relaxed);
+        report.template_age = self.template_age();
+        report.uptime = self.started.elapsed().as_secs();
+        report
+    }
+}
+
+impl Report {
+    /// One line summary, as logged
+    pub fn summary(&self) -> String {
+        let threads: Vec<String> = self.thread_hashrates.iter().map(|rate| format_hashrate(*rate)).collect();
+        let template_age = match self.template_age {
+            Some(age) => format!("template {}s old", age),
+            None => "no template".to_string(),
+        };
+        format!(
+            "Hashrate {} (threads: {}), {} accepted, {} stale, {}",
+            format_hashrate(self.hashrate),
+            threads.join(", "),
+            self.accepted,
+            self.stale,
+            template_age
+        )
+    }
+}
+
+/// Hashes per second with a unit, e.g. `1.25 MH/s`
+pub fn format_hashrate(rate: f64) -> String {
+    let units = ["H/s", "kH/s", "MH/s", "GH/s", "TH/s"];
+    let mut rate = rate;
+    let mut unit = 0;
+    while rate >= 1000.0 && unit < units.len() - 1 {
+        rate /= 1000.0;
+        unit += 1;
+    }
+    format!("{:.2} {}", rate, units[unit])
+}
+
+/// The template the mining threads work on
+#[derive(Default)]
+pub struct Work {
+    template: Mutex<Option<Block>>,
+    // bumped for every new template so the threads start over on it
+    generation: AtomicU64,
+    mining: AtomicBool,
+    closed: AtomicBool,
+}
+
+impl Work {
+    /// Have the threads start over on `template`
+    pub fn set_template(&self, template: Block) {
+        *self.template.lock().unwrap() = Some(template);
+        self.generation.fetch_add(1, Ordering::Relaxed);
+        self.mining.store(true, Ordering::Relaxed);
+    }
+
+    pub fn template(&self) -> Option<Block> {
+        self.template.lock().unwrap().clone()
+    }
+
+    pub fn is_mining(&self) -> bool {
+        self.mining.load(Ordering::Relaxed)
+    }
+
+    /// Have the threads idle until the next template
+    pub fn stop(&self) {
+        self.mining.store(false, Ordering::Relaxed);
+    }
+
+    /// Have the threads exit
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+    }
+
+    /// Mine the current template under the extranonce `thread`, so
+    /// every thread searches the whole nonce range of its own block.
+    /// The first thread to find a block stops the others and hands
+    /// it to `found`.
+    pub fn spawn_thread(
+        self: &Arc<Self>,
+        thread: usize,
+        stats: Arc<Stats>,
+        found: impl Fn(Block) + Send + 'static,
+    ) -> thread::JoinHandle<()> {
+        let work = self.clone();
+        thread::spawn(move || {
+            let mut mined: Option<(u64, Block)> = None;
+            while !work.closed.load(Ordering::Relaxed) {
+                if work.is_mining() {
+                    let current = work.generation.load(Ordering::Relaxed);
+                    if mined.as_ref().is_none_or(|(generation, _)| *generation != current)
+                        && let Some(mut block) = work.template()
+                    {
+                        if let Some(branch) = block.coinbase_branch()
+                            && let Err(e) = block.set_extranonce(thread as u64, &branch)
+                        {
+                            tracing::warn!("can't set the extranonce of the template: {}", e);
+                        }
+                        mined = Some((current, block));
+                    }
+                    if let Some((_, block)) = &mut mined {
+                        let nonce = block.header.nonce;
+                        let solved = block.header.mine(MINE_STEPS);
+                        stats.add_hashes(thread, block.header.nonce.wrapping_sub(nonce).max(1));
+                        // another thread may have found one meanwhile
+                        if solved && work.mining.swap(false, Ordering::Relaxed) {
+                            found(block.clone());
+                        }
+                    }
+                }
+                thread::yield_now();
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_rates() {
+        let stats = Stats::new(2);
+        stats.add_hashes(0, 3_000);
+        stats.add_hashes(1, 1_000);
+        stats.block_accepted();
+        let mut previous = vec![];
+        let report = stats.report(&mut previous, Duration::from_secs(2));
+        assert_eq!(report.thread_hashrates, vec![1_500.0, 500.0]);
+        assert_eq!((report.hashrate, report.total_hashes), (2_000.0, 4_000));
+        assert_eq!((report.accepted, report.stale, report.template_age), (1, 0, None));
+
+        // rates only count the hashes since the previous report
+        stats.add_hashes(1, 500);
+        stats.template_fetched();
+        let report = stats.report(&mut previous, Duration::from_secs(1));
+        assert_eq!(report.thread_hashrates, vec![0.0, 500.0]);
+        assert_eq!(report.template_age, Some(0));
+        stats.block_stale();
+        let current = stats.current();
+        assert_eq!((current.total_hashes, current.stale, current.hashrate), (4_500, 1, 500.0));
+        assert_eq!(format_hashrate(report.hashrate), "500.00 H/s");
+        assert_eq!(format_hashrate(1_250_000.0), "1.25 MH/s");
+    }
+}
@@ -0,0 +1,127 @@
+//! A hash of a set kept up to date as elements come and go, in the
+//! manner of MuHash: every element is hashed to a number modulo a
+//! prime and the set hashes to their product, so the order elements
+//! were added in doesn't matter and removing one divides it back out.
+//! Additions and removals are multiplied up separately, so an update
+//! costs one multiplication and the division waits for `finalize`.
+//!
+//! The prime is 2^256 - 189, which keeps the numbers `U256`s. That
+//! tells sets apart that diverged by accident or a bug; it is not
+//! meant to hold up against sets crafted to collide.
+use crate::U256;
+use crate::sha256::Hash;
+use sha2::{Digest, Sha256};
+use uint::construct_uint;
+
+construct_uint! {
+    // holds the product of two U256s before it is reduced
+    struct U512(8);
+}
+
+// 2^256 - 189, the largest prime below 2^256
+const PRIME: U256 = U256([
+    0xFFFF_FFFF_FFFF_FF43,
+    0xFFFF_FFFF_FFFF_FFFF,
+    0xFFFF_FFFF_FFFF_FFFF,
+    0xFFFF_FFFF_FFFF_FFFF,
+]);
+
+fn widen(value: U256) -> U512 {
+    let mut words = [0; 8];
+    words[..4].copy_from_slice(&value.0);
+    U512(words)
+}
+
+fn mul_mod(a: U256, b: U256) -> U256 {
+    let reduced = widen(a) * widen(b) % widen(PRIME);
+    let mut words = [0; 4];
+    words.copy_from_slice(&reduced.0[..4]);
+    U256(words)
+}
+
+// a^-1, as a^(p-2) by Fermat's little theorem
+fn inverse(a: U256) -> U256 {
+    let mut exponent = PRIME - 2;
+    let mut base = a;
+    let mut result = U256::one();
+    while !exponent.is_zero() {
+        if exponent.bit(0) {
+            result = mul_mod(result, base);
+        }
+        base = mul_mod(base, base);
+        exponent >>= 1;
+    }
+    result
+}
+
+// the number an element multiplies the set hash by, never 0
+fn to_field(element: &[u8]) -> U256 {
+    let digest: [u8; 32] = Sha256::digest(element).into();
+    let value = U256::from_big_endian(&digest) % PRIME;
+    if value.is_zero() { U256::one() } else { value }
+}
+
+/// A set hash updated one element at a time
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MuHash {
+    numerator: U256,
+    denominator: U256,
+}
+
+impl Default for MuHash {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MuHash {
+    /// The hash of the empty set
+    pub fn new() -> Self {
+        Self {
+            numerator: U256::one(),
+            denominator: U256::one(),
+        }
+    }
+
+    pub fn insert(&mut self, element: &[u8]) {
+        self.numerator = mul_mod(self.numerator, to_field(element));
+    }
+
+    /// Take out an element inserted before. Removing one that isn't
+    /// in the set leaves a hash no set has.
+    pub fn remove(&mut self, element: &[u8]) {
+        self.denominator = mul_mod(self.denominator, to_field(element));
+    }
+
+    /// The hash of the set as it is now
+    pub fn finalize(&self) -> Hash {
+        let value = mul_mod(self.numerator, inverse(self.denominator));
+        Hash::hash_bytes(&value.to_big_endian())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_order_and_removal_dont_matter() {
+        let mut forward = MuHash::new();
+        for element in [&b"a"[..], b"b", b"c"] {
+            forward.insert(element);
+        }
+        let mut backward = MuHash::new();
+        for element in [&b"c"[..], b"d", b"b", b"a"] {
+            backward.insert(element);
+        }
+        assert_ne!(forward.finalize(), backward.finalize());
+        backward.remove(b"d");
+        assert_eq!(forward.finalize(), backward.finalize());
+
+        for element in [&b"a"[..], b"b", b"c"] {
+            forward.remove(element);
+        }
+        assert_eq!(forward.finalize(), MuHash::new().finalize());
+        assert_eq!(mul_mod(inverse(to_field(b"x")), to_field(b"x")), U256::one());
+    }
+}
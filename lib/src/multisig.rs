@@ -0,0 +1,125 @@
+use crate::address::{Address, hash160};
+use crate::crypto::{PublicKey, Signature};
+use crate::error::{BtcError, Result};
+use crate::params::ChainParams;
+use crate::sha256::Hash;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use serde::{Deserialize, Serialize};
+
+/// Largest number of keys a multisig policy may have
+pub const MAX_MULTISIG_KEYS: usize = 15;
+
+/// An m-of-n policy: outputs paid to its address can be spent with
+/// signatures from any `threshold` of its keys
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MultisigPolicy {
+    threshold: usize,
+    /// Sorted, so the same keys give the same address in any order
+    public_keys: Vec<PublicKey>,
+}
+
+impl MultisigPolicy {
+    pub fn new(threshold: usize, mut public_keys: Vec<PublicKey>) -> Result<Self> {
+        public_keys.sort();
+        public_keys.dedup();
+        if threshold == 0 || threshold > public_keys.len() || public_keys.len() > MAX_MULTISIG_KEYS
+        {
+            return Err(BtcError::InvalidMultisigPolicy);
+        }
+        Ok(Self {
+            threshold,
+            public_keys,
+        })
+    }
+
+    pub fn threshold(&self) -> usize {
+        self.threshold
+    }
+
+    pub fn public_keys(&self) -> &[PublicKey] {
+        &self.public_keys
+    }
+
+    pub fn contains(&self, public_key: &PublicKey) -> bool {
+        self.public_keys.contains(public_key)
+    }
+
+    /// Mainnet address outputs spendable under this policy are paid to
+    pub fn to_address(&self) -> String {
+        self.to_address_for(&ChainParams::mainnet())
+    }
+
+    /// Address on the chain of `params`: the threshold byte followed by
+    /// the compressed keys, hashed like a single key address but with
+    /// the multisig version byte
+    pub fn to_address_for(&self, params: &ChainParams) -> String {
+        let mut payload = vec![self.threshold as u8];
+        for public_key in &self.public_keys {
+            payload.extend(public_key.to_compressed_bytes());
+        }
+        Address::Multisig(hash160(&payload)).to_base58(params)
+    }
+
+    /// Whether the signatures over `hash` include valid ones from at
+    /// least `threshold` distinct keys of the policy
+    pub fn is_satisfied<'a>(
+        &self,
+        hash: &Hash,
+        signatures: impl IntoIterator<Item = (&'a PublicKey, &'a Signature)>,
+    ) -> bool {
+        let mut signers: Vec<&PublicKey> = signatures
+            .into_iter()
+            .filter(|(key, signature)| self.contains(key) && signature.verify(hash, key))
+            .map(|(key, _)| key)
+            .collect();
+        signers.sort();
+        signers.dedup();
+        signers.len() >= self.threshold
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::PrivateKey;
+
+    #[test]
+    fn test_policy_address_ignores_key_order() {
+        let keys: Vec<_> = (0..3).map(|_| PrivateKey::new_key().public_key()).collect();
+        let policy = MultisigPolicy::new(2, keys.clone()).unwrap();
+        let reversed = MultisigPolicy::new(2, keys.iter().rev().cloned().collect()).unwrap();
+        assert_eq!(policy.to_address(), reversed.to_address());
+        assert_ne!(policy.to_address(), MultisigPolicy::new(1, keys).unwrap().to_address());
+        assert!(PublicKey::validate_address(&policy.to_address(), &ChainParams::mainnet()).unwrap());
+    }
+
+    #[test]
+    fn test_invalid_thresholds() {
+        let key = PrivateKey::new_key().public_key();
+        assert!(MultisigPolicy::new(0, vec![key.clone()]).is_err());
+        // duplicate keys count once
+        assert!(MultisigPolicy::new(2, vec![key.clone(), key]).is_err());
+    }
+
+    #[test]
+    fn test_threshold_signatures() {
+        let keys: Vec<_> = (0..3).map(|_| PrivateKey::new_key()).collect();
+        let policy =
+            MultisigPolicy::new(2, keys.iter().map(|key| key.public_key()).collect()).unwrap();
+        let hash = Hash::hash(&"output");
+        let signatures: Vec<_> = keys
+            .iter()
+            .map(|key| (key.public_key(), Signature::sign_output(&hash, key)))
+            .collect();
+
+        let one = signatures[..1].iter().map(|(key, sig)| (key, sig));
+        assert!(!policy.is_satisfied(&hash, one));
+        // the same signature twice is still one signer
+        let repeated = [&signatures[0], &signatures[0]].map(|(key, sig)| (key, sig));
+        assert!(!policy.is_satisfied(&hash, repeated));
+        let two = signatures[1..].iter().map(|(key, sig)| (key, sig));
+        assert!(policy.is_satisfied(&hash, two));
+    }
+}
@@ -1,17 +1,207 @@
-use crate::types::{Block, Transaction, TransactionOutput};
+use crate::encoding::{Decode, Encode, decode_list, encode_list, read_array, read_varint, write_varint};
+use crate::crypto::{PublicKey, Signature};
+use crate::error::{BtcError, Result as BtcResult};
+use crate::params::{Checkpoint, SignedCheckpoint};
+use crate::sha256::Hash;
+use crate::types::{Block, BlockHeader, Transaction, TransactionOutput, TransactionProof};
 use serde::{Deserialize, Serialize};
 use std::io::{Error as IoError, Read, Write};
+#[cfg(feature = "tokio")]
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use uuid::Uuid;
 
 /// Unique identifier for a node in the network.
 pub type NodeId = String;
 
+/// Version of the peer-to-peer protocol spoken by this crate
+pub const PROTOCOL_VERSION: u32 = 7;
+
+/// First protocol version whose peers understand compact messages
+pub const COMPACT_PROTOCOL_VERSION: u32 = 2;
+
+/// First protocol version announcing service flags
+pub const SERVICES_PROTOCOL_VERSION: u32 = 4;
+
+/// First protocol version whose peers take new blocks as compact blocks
+pub const COMPACT_BLOCKS_PROTOCOL_VERSION: u32 = 5;
+
+/// First protocol version announcing the UTXO set hash in the version
+/// handshake
+pub const UTXO_HASH_PROTOCOL_VERSION: u32 = 6;
+
+/// First protocol version whose nodes answer FetchTransactionProof
+pub const PROOF_PROTOCOL_VERSION: u32 = 7;
+
+// compact frames start with this byte, which never starts a CBOR
+// encoded envelope (always a map)
+const COMPACT_MARKER: u8 = 0x00;
+
+/// How envelopes are written on the wire. Both are always accepted when
+/// receiving; compact is only sent to peers that announced a protocol
+/// version supporting it, so older peers keep working.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WireFormat {
+    #[default]
+    Cbor,
+    /// The canonical encoding of `crate::encoding`, with varints for
+    /// counts and values
+    Compact,
+}
+
+impl WireFormat {
+    pub fn for_peer(protocol_version: u32) -> Self {
+        if protocol_version >= COMPACT_PROTOCOL_VERSION {
+            WireFormat::Compact
+        } else {
+            WireFormat::Cbor
+        }
+    }
+}
+
+/// What a node offers its peers, a bitfield announced in the version
+/// handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct Services(u64);
+
+impl Services {
+    pub const NONE: Services = Services(0);
+    /// Serves every block from genesis up
+    pub const FULL_BLOCKS: Services = Services(1);
+    /// Prunes old blocks, serving only those from `lowest_block` up,
+    /// which moves as it prunes
+    pub const PRUNED: Services = Services(1 << 1);
+    /// Builds block templates for miners
+    pub const MINING: Services = Services(1 << 2);
+    /// Answers the UTXO and transaction queries of wallets
+    pub const WALLET: Services = Services(1 << 3);
+
+    const NAMES: [(Services, &'static str); 4] = [
+        (Services::FULL_BLOCKS, "full-blocks"),
+        (Services::PRUNED, "pruned"),
+        (Services::MINING, "mining"),
+        (Services::WALLET, "wallet"),
+    ];
+
+    pub fn from_bits(bits: u64) -> Self {
+        Services(bits)
+    }
+
+    pub fn bits(self) -> u64 {
+        self.0
+    }
+
+    pub fn contains(self, other: Services) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+/// What peers speaking protocol versions without service flags did
+impl Default for Services {
+    fn default() -> Self {
+        Services::FULL_BLOCKS | Services::MINING | Services::WALLET
+    }
+}
+
+impl std::ops::BitOr for Services {
+    type Output = Services;
+
+    fn bitor(self, other: Services) -> Services {
+        Services(self.0 | other.0)
+    }
+}
+
+impl std::ops::BitOrAssign for Services {
+    fn bitor_assign(&mut self, other: Services) {
+        self.0 |= other.0;
+    }
+}
+
+impl std::fmt::Display for Services {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names: Vec<&str> = Services::NAMES
+            .iter()
+            .filter(|(service, _)| self.contains(*service))
+            .map(|(_, name)| *name)
+            .collect();
+        match names.is_empty() {
+            true => write!(f, "none"),
+            false => write!(f, "{}", names.join(", ")),
+        }
+    }
+}
+
+/// What a node tells its peers about itself when connecting
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct VersionInfo {
+    pub protocol_version: u32,
+    pub height: u64,
+    /// Lowest height the node serves full blocks for. Non-zero for
+    /// pruned nodes and nodes still backfilling a snapshot.
+    pub lowest_block: u64,
+    /// Missing from peers older than `SERVICES_PROTOCOL_VERSION`,
+    /// which offered everything
+    #[serde(default)]
+    pub services: Services,
+    /// Random bytes the receiver proves its identity key with, by
+    /// answering with `Message::Identity`. Fresh for every connection
+    /// so answers can't be replayed.
+    #[serde(default)]
+    pub challenge: Option<[u8; 32]>,
+    /// `Blockchain::utxo_set_hash` at `height`, which peers at the
+    /// same height and tip should share. Compact messages only carry
+    /// it after a challenge, as nodes send; peers older than
+    /// `UTXO_HASH_PROTOCOL_VERSION` can't read it there.
+    #[serde(default)]
+    pub utxo_set_hash: Option<Hash>,
+}
+
+/// A new block as relayed to peers speaking
+/// `COMPACT_BLOCKS_PROTOCOL_VERSION`: the header, the coinbase and the
+/// ids of the other transactions, which peers mostly have in their
+/// mempool already
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CompactBlock {
+    pub header: BlockHeader,
+    pub coinbase: Transaction,
+    pub txids: Vec<Hash>,
+}
+
+impl CompactBlock {
+    /// None for a block without transactions, which has no coinbase
+    pub fn new(block: &Block) -> Option<Self> {
+        let (coinbase, transactions) = block.transactions.split_first()?;
+        Some(CompactBlock {
+            header: block.header.clone(),
+            coinbase: coinbase.clone(),
+            txids: transactions.iter().map(Transaction::hash).collect(),
+        })
+    }
+
+    /// The block's transactions, coinbase first, with those `find`
+    /// doesn't know left out
+    pub fn transactions(&self, find: impl Fn(&Hash) -> Option<Transaction>) -> Vec<Option<Transaction>> {
+        let mut transactions = vec![Some(self.coinbase.clone())];
+        transactions.extend(self.txids.iter().map(find));
+        transactions
+    }
+}
+
+/// What a node signs to answer a peer's identity challenge
+pub fn identity_digest(challenge: &[u8; 32]) -> Hash {
+    let mut bytes = b"grapheno peer identity".to_vec();
+    bytes.extend(challenge);
+    Hash::hash_bytes(&bytes)
+}
+
 // TODO implement gRPC for the network
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum Message {
-    /// Fetch all UTXOs belonging to an address
-    FetchUTXOs(String),
+    /// Fetch all UTXOs belonging to an address, optionally only
+    /// those with at least the given number of confirmations
+    #[serde(serialize_with = "serialize_fetch_utxos")]
+    #[serde(deserialize_with = "deserialize_fetch_utxos")]
+    FetchUTXOs(String, Option<u64>),
     /// UTXOs belonging to an address. Bool determines if marked
     UTXOs(Vec<(TransactionOutput, bool)>),
     /// Send a transaction to the network
@@ -51,6 +241,79 @@ pub enum Message {
     AllBlocks(Vec<Block>),
     /// Broadcast a new block to other nodes
     NewBlock(Block),
+    /// Ask a node for up to `count` blocks starting at
+    /// the given height
+    FetchBlocks(u64, u64),
+    /// Response to FetchBlocks, holding the consecutive blocks
+    /// starting at the given height that the node has
+    Blocks(u64, Vec<Block>),
+    /// Handshake sent by the connecting node, answered once
+    /// with the receiver's own version
+    Version(VersionInfo),
+    /// Ask a node to send the block with the specified hash
+    FetchBlockByHash(Hash),
+    /// Ask a node for a confirmed transaction by its hash
+    FetchTransaction(Hash),
+    /// Response to FetchTransaction, holding the height of the
+    /// block the transaction is in, if the node has it
+    TransactionInfo(Hash, Option<(u64, Transaction)>),
+    /// Answer to the challenge of a peer's version: the node's
+    /// identity key and its signature of `identity_digest`
+    Identity(PublicKey, Signature),
+    /// A checkpoint from the holder of the network's checkpoint key,
+    /// relayed by the nodes taking it
+    Checkpoint(SignedCheckpoint),
+    /// Ask a node for up to `count` headers of its main chain
+    /// starting at the given height
+    FetchHeaders(u64, u64),
+    /// Response to FetchHeaders, holding the consecutive headers
+    /// starting at the given height that the node has
+    Headers(u64, Vec<BlockHeader>),
+    /// Ask a node whether each of the addresses ever received an output
+    FetchAddressUsage(Vec<String>),
+    /// Response to FetchAddressUsage, in the order of its addresses
+    AddressUsage(Vec<bool>),
+    /// Broadcast a new block to peers taking compact blocks
+    CompactBlock(CompactBlock),
+    /// Ask the peer that relayed a compact block for the transactions
+    /// at the given positions of the block whose header has the hash
+    FetchBlockTransactions(Hash, Vec<u64>),
+    /// Response to FetchBlockTransactions, in the order asked for
+    BlockTransactions(Hash, Vec<Transaction>),
+    /// Ask a node to prove a confirmed transaction is in its main chain
+    FetchTransactionProof(Hash),
+    /// Response to FetchTransactionProof, None if the node has no
+    /// stored block holding the transaction
+    TransactionProof(Hash, Option<TransactionProof>),
+}
+
+// FetchUTXOs used to hold just the address, which is still what goes
+// on the wire without a minimum so either side may be older
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FetchUtxosRepr {
+    Address(String),
+    WithMinimum(String, Option<u64>),
+}
+
+fn serialize_fetch_utxos<S: serde::Serializer>(
+    address: &String,
+    min_confirmations: &Option<u64>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match min_confirmations {
+        None => address.serialize(serializer),
+        Some(_) => (address, min_confirmations).serialize(serializer),
+    }
+}
+
+fn deserialize_fetch_utxos<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<(String, Option<u64>), D::Error> {
+    Ok(match FetchUtxosRepr::deserialize(deserializer)? {
+        FetchUtxosRepr::Address(address) => (address, None),
+        FetchUtxosRepr::WithMinimum(address, min_confirmations) => (address, min_confirmations),
+    })
 }
 
 /// Envelope carries a message with routing metadata for loop prevention.
@@ -74,13 +337,28 @@ impl Envelope {
     }
 
     pub fn encode(&self) -> Result<Vec<u8>, ciborium::ser::Error<IoError>> {
+        self.encode_as(WireFormat::Cbor)
+    }
+
+    pub fn encode_as(&self, format: WireFormat) -> Result<Vec<u8>, ciborium::ser::Error<IoError>> {
         let mut bytes = Vec::new();
-        ciborium::into_writer(self, &mut bytes)?;
+        match format {
+            WireFormat::Cbor => ciborium::into_writer(self, &mut bytes)?,
+            WireFormat::Compact => {
+                bytes.push(COMPACT_MARKER);
+                Encode::encode(self, &mut bytes);
+            }
+        }
         Ok(bytes)
     }
 
+    /// Decode an envelope in either wire format
     pub fn decode(data: &[u8]) -> Result<Self, ciborium::de::Error<IoError>> {
-        ciborium::from_reader(data)
+        match data.split_first() {
+            Some((&COMPACT_MARKER, compact)) => <Self as Decode>::from_bytes(compact)
+                .map_err(|e| ciborium::de::Error::Semantic(None, e.to_string())),
+            _ => ciborium::from_reader(data),
+        }
     }
 
     pub fn send(&self, stream: &mut impl Write) -> Result<(), ciborium::ser::Error<IoError>> {
@@ -100,32 +378,94 @@ impl Envelope {
         Self::decode(&data)
     }
 
+    #[cfg(feature = "tokio")]
     pub async fn send_async(
         &self,
         stream: &mut (impl AsyncWrite + Unpin),
     ) -> Result<(), ciborium::ser::Error<IoError>> {
-        let bytes = self.encode()?;
+        self.send_async_as(stream, WireFormat::Cbor).await.map(|_| ())
+    }
+
+    /// Send in the given format, returning the bytes written with the
+    /// length prefix
+    #[cfg(feature = "tokio")]
+    pub async fn send_async_as(
+        &self,
+        stream: &mut (impl AsyncWrite + Unpin),
+        format: WireFormat,
+    ) -> Result<usize, ciborium::ser::Error<IoError>> {
+        let bytes = self.encode_as(format)?;
         let len = bytes.len() as u64;
         stream.write_all(&len.to_be_bytes()).await?;
         stream.write_all(&bytes).await?;
-        Ok(())
+        Ok(bytes.len() + 8)
     }
 
+    #[cfg(feature = "tokio")]
     pub async fn receive_async(
         stream: &mut (impl AsyncRead + Unpin),
     ) -> Result<Self, ciborium::de::Error<IoError>> {
+        Self::receive_async_sized(stream).await.map(|(env, _)| env)
+    }
+
+    /// Like `receive_async`, also returning the bytes read with the
+    /// length prefix
+    #[cfg(feature = "tokio")]
+    pub async fn receive_async_sized(
+        stream: &mut (impl AsyncRead + Unpin),
+    ) -> Result<(Self, usize), ciborium::de::Error<IoError>> {
         let mut len_bytes = [0u8; 8];
         stream.read_exact(&mut len_bytes).await?;
         let len = u64::from_be_bytes(len_bytes) as usize;
         let mut data = vec![0u8; len];
         stream.read_exact(&mut data).await?;
-        Self::decode(&data)
+        Ok((Self::decode(&data)?, len + 8))
     }
 }
 
 // We are going to use length-prefixed encoding for message
 // And we are going to use ciborium (CBOR) for serialization
 impl Message {
+    /// The variant's name, for logs and traffic statistics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Message::FetchUTXOs(..) => "FetchUTXOs",
+            Message::UTXOs(_) => "UTXOs",
+            Message::SubmitTransaction(_) => "SubmitTransaction",
+            Message::NewTransaction(_) => "NewTransaction",
+            Message::FetchTemplate(_) => "FetchTemplate",
+            Message::Template(_) => "Template",
+            Message::ValidateTemplate(_) => "ValidateTemplate",
+            Message::TemplateValidity(_) => "TemplateValidity",
+            Message::SubmitTemplate(_) => "SubmitTemplate",
+            Message::DiscoverNodes => "DiscoverNodes",
+            Message::NodeList(_) => "NodeList",
+            Message::AskDifference(_) => "AskDifference",
+            Message::Difference(_) => "Difference",
+            Message::FetchBlock(_) => "FetchBlock",
+            Message::FetchAllBlocks => "FetchAllBlocks",
+            Message::AllBlocks(_) => "AllBlocks",
+            Message::NewBlock(_) => "NewBlock",
+            Message::FetchBlocks(..) => "FetchBlocks",
+            Message::Blocks(..) => "Blocks",
+            Message::Version(_) => "Version",
+            Message::FetchBlockByHash(_) => "FetchBlockByHash",
+            Message::FetchTransaction(_) => "FetchTransaction",
+            Message::TransactionInfo(..) => "TransactionInfo",
+            Message::Identity(..) => "Identity",
+            Message::Checkpoint(_) => "Checkpoint",
+            Message::FetchHeaders(..) => "FetchHeaders",
+            Message::Headers(..) => "Headers",
+            Message::FetchAddressUsage(_) => "FetchAddressUsage",
+            Message::AddressUsage(_) => "AddressUsage",
+            Message::CompactBlock(_) => "CompactBlock",
+            Message::FetchBlockTransactions(..) => "FetchBlockTransactions",
+            Message::BlockTransactions(..) => "BlockTransactions",
+            Message::FetchTransactionProof(_) => "FetchTransactionProof",
+            Message::TransactionProof(..) => "TransactionProof",
+        }
+    }
+
     pub fn encode(&self) -> Result<Vec<u8>, ciborium::ser::Error<IoError>> {
         let mut bytes = Vec::new();
         ciborium::into_writer(self, &mut bytes)?;
@@ -153,6 +493,7 @@ impl Message {
         Self::decode(&data)
     }
 
+    #[cfg(feature = "tokio")]
     pub async fn send_async(
         &self,
         stream: &mut (impl AsyncWrite + Unpin),
@@ -164,6 +505,7 @@ impl Message {
         Ok(())
     }
 
+    #[cfg(feature = "tokio")]
     pub async fn receive_async(
         stream: &mut (impl AsyncRead + Unpin),
     ) -> Result<Self, ciborium::de::Error<IoError>> {
@@ -175,3 +517,479 @@ impl Message {
         Self::decode(&data)
     }
 }
+
+// Compact encoding: the envelope's id (16 bytes), origin, ttl (u8) and
+// message. A message is a tag byte, its position in the enum, followed
+// by its fields. Integers are varints, i32 zigzag encoded.
+impl Encode for Envelope {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend(self.id.as_bytes());
+        self.origin.encode(out);
+        out.push(self.ttl);
+        Encode::encode(&self.msg, out);
+    }
+}
+
+impl Decode for Envelope {
+    fn decode(input: &mut &[u8]) -> BtcResult<Self> {
+        Ok(Envelope {
+            id: Uuid::from_bytes(read_array(input)?),
+            origin: String::decode(input)?,
+            ttl: read_array::<1>(input)?[0],
+            msg: <Message as Decode>::decode(input)?,
+        })
+    }
+}
+
+impl Encode for VersionInfo {
+    fn encode(&self, out: &mut Vec<u8>) {
+        write_varint(out, self.protocol_version as u64);
+        write_varint(out, self.height);
+        write_varint(out, self.lowest_block);
+        write_varint(out, self.services.bits());
+        if let Some(challenge) = &self.challenge {
+            out.extend(challenge);
+            if let Some(hash) = &self.utxo_set_hash {
+                hash.encode(out);
+            }
+        }
+    }
+}
+
+impl Decode for VersionInfo {
+    fn decode(input: &mut &[u8]) -> BtcResult<Self> {
+        Ok(VersionInfo {
+            protocol_version: read_int(input)?,
+            height: read_varint(input)?,
+            lowest_block: read_varint(input)?,
+            // the message ends the envelope
+            services: match input.is_empty() {
+                true => Services::default(),
+                false => Services::from_bits(read_varint(input)?),
+            },
+            challenge: match input.is_empty() {
+                true => None,
+                false => Some(read_array::<32>(input)?),
+            },
+            utxo_set_hash: match input.is_empty() {
+                true => None,
+                false => Some(Hash::decode(input)?),
+            },
+        })
+    }
+}
+
+fn read_int<T: TryFrom<u64>>(input: &mut &[u8]) -> BtcResult<T> {
+    T::try_from(read_varint(input)?).map_err(|_| BtcError::InvalidEncoding)
+}
+
+impl Encode for Message {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Message::FetchUTXOs(address, min_confirmations) => {
+                out.push(0);
+                address.encode(out);
+                // left out when unset, as written by older wallets
+                if let Some(min_confirmations) = min_confirmations {
+                    write_varint(out, *min_confirmations);
+                }
+            }
+            Message::UTXOs(utxos) => {
+                out.push(1);
+                encode_list(out, utxos);
+            }
+            Message::SubmitTransaction(transaction) => {
+                out.push(2);
+                transaction.encode(out);
+            }
+            Message::NewTransaction(transaction) => {
+                out.push(3);
+                transaction.encode(out);
+            }
+            Message::FetchTemplate(address) => {
+                out.push(4);
+                address.encode(out);
+            }
+            Message::Template(block) => {
+                out.push(5);
+                block.encode(out);
+            }
+            Message::ValidateTemplate(block) => {
+                out.push(6);
+                block.encode(out);
+            }
+            Message::TemplateValidity(valid) => {
+                out.push(7);
+                valid.encode(out);
+            }
+            Message::SubmitTemplate(block) => {
+                out.push(8);
+                block.encode(out);
+            }
+            Message::DiscoverNodes => out.push(9),
+            Message::NodeList(nodes) => {
+                out.push(10);
+                encode_list(out, nodes);
+            }
+            Message::AskDifference(height) => {
+                out.push(11);
+                write_varint(out, *height as u64);
+            }
+            Message::Difference(difference) => {
+                out.push(12);
+                write_varint(out, ((difference << 1) ^ (difference >> 31)) as u32 as u64);
+            }
+            Message::FetchBlock(height) => {
+                out.push(13);
+                write_varint(out, *height as u64);
+            }
+            Message::FetchAllBlocks => out.push(14),
+            Message::AllBlocks(blocks) => {
+                out.push(15);
+                encode_list(out, blocks);
+            }
+            Message::NewBlock(block) => {
+                out.push(16);
+                block.encode(out);
+            }
+            Message::FetchBlocks(start, count) => {
+                out.push(17);
+                write_varint(out, *start);
+                write_varint(out, *count);
+            }
+            Message::Blocks(start, blocks) => {
+                out.push(18);
+                write_varint(out, *start);
+                encode_list(out, blocks);
+            }
+            Message::Version(version) => {
+                out.push(19);
+                version.encode(out);
+            }
+            Message::FetchBlockByHash(hash) => {
+                out.push(20);
+                hash.encode(out);
+            }
+            Message::FetchTransaction(hash) => {
+                out.push(21);
+                hash.encode(out);
+            }
+            Message::TransactionInfo(hash, found) => {
+                out.push(22);
+                hash.encode(out);
+                found.is_some().encode(out);
+                if let Some((height, transaction)) = found {
+                    write_varint(out, *height);
+                    transaction.encode(out);
+                }
+            }
+            Message::Identity(public_key, signature) => {
+                out.push(23);
+                public_key.encode(out);
+                signature.encode(out);
+            }
+            Message::Checkpoint(signed) => {
+                out.push(24);
+                write_varint(out, signed.checkpoint.height);
+                signed.checkpoint.hash.encode(out);
+                signed.signature.encode(out);
+            }
+            Message::FetchHeaders(start, count) => {
+                out.push(25);
+                write_varint(out, *start);
+                write_varint(out, *count);
+            }
+            Message::Headers(start, headers) => {
+                out.push(26);
+                write_varint(out, *start);
+                encode_list(out, headers);
+            }
+            Message::FetchAddressUsage(addresses) => {
+                out.push(27);
+                encode_list(out, addresses);
+            }
+            Message::AddressUsage(used) => {
+                out.push(28);
+                encode_list(out, used);
+            }
+            Message::CompactBlock(compact) => {
+                out.push(29);
+                compact.header.encode(out);
+                compact.coinbase.encode(out);
+                encode_list(out, &compact.txids);
+            }
+            Message::FetchBlockTransactions(hash, positions) => {
+                out.push(30);
+                hash.encode(out);
+                write_varint(out, positions.len() as u64);
+                for position in positions {
+                    write_varint(out, *position);
+                }
+            }
+            Message::BlockTransactions(hash, transactions) => {
+                out.push(31);
+                hash.encode(out);
+                encode_list(out, transactions);
+            }
+            Message::FetchTransactionProof(hash) => {
+                out.push(32);
+                hash.encode(out);
+            }
+            Message::TransactionProof(hash, proof) => {
+                out.push(33);
+                hash.encode(out);
+                proof.is_some().encode(out);
+                if let Some(proof) = proof {
+                    proof.encode(out);
+                }
+            }
+        }
+    }
+}
+
+impl Decode for Message {
+    fn decode(input: &mut &[u8]) -> BtcResult<Self> {
+        let message = match read_array::<1>(input)?[0] {
+            0 => {
+                let address = String::decode(input)?;
+                // the message ends the envelope
+                let min_confirmations = match input.is_empty() {
+                    true => None,
+                    false => Some(read_varint(input)?),
+                };
+                Message::FetchUTXOs(address, min_confirmations)
+            }
+            1 => Message::UTXOs(decode_list(input)?),
+            2 => Message::SubmitTransaction(Transaction::decode(input)?),
+            3 => Message::NewTransaction(Transaction::decode(input)?),
+            4 => Message::FetchTemplate(String::decode(input)?),
+            5 => Message::Template(Block::decode(input)?),
+            6 => Message::ValidateTemplate(Block::decode(input)?),
+            7 => Message::TemplateValidity(bool::decode(input)?),
+            8 => Message::SubmitTemplate(Block::decode(input)?),
+            9 => Message::DiscoverNodes,
+            10 => Message::NodeList(decode_list(input)?),
+            11 => Message::AskDifference(read_int(input)?),
+            12 => {
+                let zigzag: u32 = read_int(input)?;
+                Message::Difference((zigzag >> 1) as i32 ^ -((zigzag & 1) as i32))
+            }
+            13 => Message::FetchBlock(read_int(input)?),
+            14 => Message::FetchAllBlocks,
+            15 => Message::AllBlocks(decode_list(input)?),
+            16 => Message::NewBlock(Block::decode(input)?),
+            17 => Message::FetchBlocks(read_varint(input)?, read_varint(input)?),
+            18 => Message::Blocks(read_varint(input)?, decode_list(input)?),
+            19 => Message::Version(VersionInfo::decode(input)?),
+            20 => Message::FetchBlockByHash(Hash::decode(input)?),
+            21 => Message::FetchTransaction(Hash::decode(input)?),
+            22 => {
+                let hash = Hash::decode(input)?;
+                let found = match bool::decode(input)? {
+                    true => Some((read_varint(input)?, Transaction::decode(input)?)),
+                    false => None,
+                };
+                Message::TransactionInfo(hash, found)
+            }
+            23 => Message::Identity(PublicKey::decode(input)?, Signature::decode(input)?),
+            24 => Message::Checkpoint(SignedCheckpoint {
+                checkpoint: Checkpoint {
+                    height: read_varint(input)?,
+                    hash: Hash::decode(input)?,
+                },
+                signature: Signature::decode(input)?,
+            }),
+            25 => Message::FetchHeaders(read_varint(input)?, read_varint(input)?),
+            26 => Message::Headers(read_varint(input)?, decode_list(input)?),
+            27 => Message::FetchAddressUsage(decode_list(input)?),
+            28 => Message::AddressUsage(decode_list(input)?),
+            29 => Message::CompactBlock(CompactBlock {
+                header: BlockHeader::decode(input)?,
+                coinbase: Transaction::decode(input)?,
+                txids: decode_list(input)?,
+            }),
+            30 => {
+                let hash = Hash::decode(input)?;
+                let mut positions = vec![];
+                for _ in 0..read_varint(input)? {
+                    positions.push(read_varint(input)?);
+                }
+                Message::FetchBlockTransactions(hash, positions)
+            }
+            31 => Message::BlockTransactions(Hash::decode(input)?, decode_list(input)?),
+            32 => Message::FetchTransactionProof(Hash::decode(input)?),
+            33 => {
+                let hash = Hash::decode(input)?;
+                let proof = match bool::decode(input)? {
+                    true => Some(TransactionProof::decode(input)?),
+                    false => None,
+                };
+                Message::TransactionProof(hash, proof)
+            }
+            _ => return Err(BtcError::InvalidEncoding),
+        };
+        Ok(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::PrivateKey;
+    use crate::util::MerkleRoot;
+
+    fn block() -> Block {
+        let transactions = vec![Transaction::new(
+            vec![],
+            (0..3)
+                .map(|value| TransactionOutput {
+                    value,
+                    unique_id: Uuid::new_v4(),
+                    address: PrivateKey::new_key().public_key().to_address(),
+                })
+                .collect(),
+        )];
+        Block::new(
+            BlockHeader::new(
+                chrono::Utc::now(),
+                42,
+                Hash::hash_bytes(b"previous block"),
+                MerkleRoot::calculate(&transactions),
+                crate::MIN_TARGET,
+            ),
+            transactions,
+        )
+    }
+
+    #[test]
+    fn test_both_formats_decode() {
+        let envelope = Envelope::new("node".to_string(), 8, Message::NewBlock(block()));
+        let cbor = envelope.encode_as(WireFormat::Cbor).unwrap();
+        let compact = envelope.encode_as(WireFormat::Compact).unwrap();
+        assert!(compact.len() < cbor.len());
+
+        for bytes in [cbor, compact] {
+            let decoded = Envelope::decode(&bytes).unwrap();
+            assert_eq!(decoded.id, envelope.id);
+            let (Message::NewBlock(decoded), Message::NewBlock(block)) = (decoded.msg, &envelope.msg)
+            else {
+                panic!("decoded another message");
+            };
+            assert_eq!(decoded.hash(), block.hash());
+        }
+    }
+
+    #[test]
+    fn test_compact_messages_round_trip() {
+        let key = PrivateKey::new_key();
+        let messages = [
+            Message::Difference(-3),
+            Message::Difference(i32::MIN),
+            Message::FetchBlocks(1, u64::MAX),
+            Message::NodeList(vec!["127.0.0.1:9000".to_string()]),
+            Message::FetchBlockByHash(Hash::hash_bytes(b"block")),
+            Message::TransactionInfo(Hash::zero(), None),
+            Message::TransactionInfo(
+                Hash::hash_bytes(b"transaction"),
+                Some((7, block().transactions.remove(0))),
+            ),
+            Message::Version(VersionInfo {
+                protocol_version: PROTOCOL_VERSION,
+                height: 300,
+                lowest_block: 0,
+                services: Services::PRUNED | Services::WALLET,
+                challenge: Some([7; 32]),
+                utxo_set_hash: Some(Hash::hash_bytes(b"utxos")),
+            }),
+            Message::Identity(key.public_key(), Signature::sign_output(&identity_digest(&[7; 32]), &key)),
+            Message::Checkpoint(SignedCheckpoint::sign(
+                Checkpoint {
+                    height: 120,
+                    hash: Hash::hash_bytes(b"block"),
+                },
+                &key,
+            )),
+            Message::FetchHeaders(4, 2000),
+            Message::Headers(4, vec![block().header]),
+            Message::FetchAddressUsage(vec!["address".to_string(), String::new()]),
+            Message::AddressUsage(vec![true, false]),
+            Message::CompactBlock(CompactBlock::new(&block()).unwrap()),
+            Message::FetchBlockTransactions(Hash::hash_bytes(b"header"), vec![1, 300]),
+            Message::BlockTransactions(Hash::hash_bytes(b"header"), block().transactions),
+            Message::FetchTransactionProof(Hash::hash_bytes(b"transaction")),
+            Message::TransactionProof(Hash::zero(), None),
+            {
+                let block = block();
+                let txid = block.transactions[0].hash();
+                Message::TransactionProof(txid, block.transaction_proof(12, txid))
+            },
+        ];
+        for message in messages {
+            let bytes = message.to_bytes();
+            let decoded = Message::from_bytes(&bytes).unwrap();
+            assert_eq!(decoded.to_bytes(), bytes);
+            assert_eq!(format!("{:?}", decoded), format!("{:?}", message));
+        }
+    }
+
+    #[test]
+    fn test_fetch_utxos_minimum_is_optional() {
+        for min_confirmations in [None, Some(0), Some(6)] {
+            let message = Message::FetchUTXOs("address".to_string(), min_confirmations);
+            let envelope = Envelope::new("wallet".to_string(), 8, message);
+            for format in [WireFormat::Cbor, WireFormat::Compact] {
+                let bytes = envelope.encode_as(format).unwrap();
+                let Message::FetchUTXOs(address, decoded) = Envelope::decode(&bytes).unwrap().msg
+                else {
+                    panic!("wrong message decoded");
+                };
+                assert_eq!(address, "address");
+                assert_eq!(decoded, min_confirmations);
+            }
+        }
+    }
+
+    #[test]
+    fn test_services_default_for_older_peers() {
+        let version = VersionInfo {
+            protocol_version: 3,
+            height: 10,
+            lowest_block: 0,
+            services: Services::NONE,
+            challenge: None,
+            utxo_set_hash: None,
+        };
+        let mut bytes = vec![];
+        version.encode(&mut bytes);
+        // written by a peer that didn't know about services
+        bytes.pop();
+        let decoded = VersionInfo::decode(&mut bytes.as_slice()).unwrap();
+        assert_eq!(decoded.services, Services::default());
+        assert!(decoded.services.contains(Services::FULL_BLOCKS | Services::WALLET));
+        assert!(!decoded.services.contains(Services::PRUNED));
+        assert_eq!(Services::NONE.to_string(), "none");
+        assert_eq!((Services::PRUNED | Services::MINING).to_string(), "pruned, mining");
+    }
+
+    #[test]
+    fn test_compact_block_transactions() {
+        let mut block = block();
+        let extra = Transaction::new(vec![], block.transactions[0].outputs.clone());
+        block.transactions.extend([extra.clone(), Transaction::new(vec![], vec![])]);
+        let compact = CompactBlock::new(&block).unwrap();
+        assert_eq!(compact.txids.len(), 2);
+
+        let transactions = compact.transactions(|txid| (*txid == extra.hash()).then(|| extra.clone()));
+        assert_eq!(transactions.len(), 3);
+        assert_eq!(transactions[0].as_ref().unwrap().hash(), block.transactions[0].hash());
+        assert_eq!(transactions[1].as_ref().unwrap().hash(), extra.hash());
+        assert!(transactions[2].is_none());
+        assert!(CompactBlock::new(&Block::new(block.header, vec![])).is_none());
+    }
+
+    #[test]
+    fn test_wire_format_negotiation() {
+        assert_eq!(WireFormat::for_peer(1), WireFormat::Cbor);
+        assert_eq!(WireFormat::for_peer(PROTOCOL_VERSION), WireFormat::Compact);
+    }
+}
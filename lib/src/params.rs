@@ -0,0 +1,432 @@
+use crate::crypto::{PrivateKey, PublicKey, Signature};
+use crate::sha256::Hash;
+use serde::{Deserialize, Serialize};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::str::FromStr;
+
+/// The chains a node or wallet can be run on
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Network {
+    #[default]
+    Mainnet,
+    Testnet,
+}
+
+impl Network {
+    pub const ALL: [Network; 2] = [Network::Mainnet, Network::Testnet];
+
+    pub fn params(self) -> ChainParams {
+        match self {
+            Network::Mainnet => ChainParams::mainnet(),
+            Network::Testnet => ChainParams::testnet(),
+        }
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Network::Mainnet => write!(f, "mainnet"),
+            Network::Testnet => write!(f, "testnet"),
+        }
+    }
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "mainnet" => Ok(Network::Mainnet),
+            "testnet" => Ok(Network::Testnet),
+            _ => Err(format!("unknown network {s:?}, expected mainnet or testnet")),
+        }
+    }
+}
+
+/// A block the chain is known to contain at a given height
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub height: u64,
+    pub hash: Hash,
+}
+
+impl fmt::Display for Checkpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.height, self.hash)
+    }
+}
+
+// parsed from "<height>:<hex hash>"
+impl FromStr for Checkpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (height, hash) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected <height>:<hash>, got {s:?}"))?;
+        let height = height
+            .parse()
+            .map_err(|e| format!("invalid checkpoint height {height:?}: {e}"))?;
+        let hash = hash
+            .parse()
+            .map_err(|_| format!("invalid checkpoint hash {hash:?}"))?;
+        Ok(Checkpoint { height, hash })
+    }
+}
+
+/// A checkpoint announced by the holder of a network's checkpoint key,
+/// which nodes of the network take as if it were built in
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SignedCheckpoint {
+    pub checkpoint: Checkpoint,
+    pub signature: Signature,
+}
+
+impl SignedCheckpoint {
+    pub fn sign(checkpoint: Checkpoint, key: &PrivateKey) -> Self {
+        let signature = Signature::sign_output(&Self::digest(&checkpoint), key);
+        Self { checkpoint, signature }
+    }
+
+    // what gets signed, so checkpoint signatures can't pass for
+    // signatures of anything else
+    fn digest(checkpoint: &Checkpoint) -> Hash {
+        let mut bytes = b"grapheno checkpoint".to_vec();
+        bytes.extend(checkpoint.height.to_be_bytes());
+        bytes.extend(checkpoint.hash.as_bytes());
+        Hash::hash_bytes(&bytes)
+    }
+
+    /// Whether `key` signed the checkpoint
+    pub fn verify(&self, key: &PublicKey) -> bool {
+        self.signature.verify(&Self::digest(&self.checkpoint), key)
+    }
+}
+
+/// How the target follows the time blocks take
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DifficultyAdjustment {
+    /// Every `DIFFICULTY_UPDATE_INTERVAL` blocks, by the time the
+    /// interval took between median timestamps, at most 4x either way
+    #[default]
+    Interval,
+    /// Every block, by the linearly weighted average solve time of the
+    /// last `window` blocks, recent ones weighing more. Reacts within a
+    /// few blocks when hashrate comes and goes.
+    Lwma { window: u64 },
+    /// Every block, by the last solve time, as one in `window` blocks
+    Ema { window: u64 },
+}
+
+impl DifficultyAdjustment {
+    pub const DEFAULT_LWMA_WINDOW: u64 = 45;
+    pub const DEFAULT_EMA_WINDOW: u64 = 20;
+
+    pub fn lwma() -> Self {
+        DifficultyAdjustment::Lwma { window: Self::DEFAULT_LWMA_WINDOW }
+    }
+
+    pub fn ema() -> Self {
+        DifficultyAdjustment::Ema { window: Self::DEFAULT_EMA_WINDOW }
+    }
+
+    /// How many of the last blocks the next target depends on
+    pub fn history(&self) -> u64 {
+        match self {
+            DifficultyAdjustment::Interval => crate::DIFFICULTY_UPDATE_INTERVAL + crate::MEDIAN_TIME_SPAN,
+            DifficultyAdjustment::Lwma { window } => window + 1,
+            DifficultyAdjustment::Ema { .. } => 2,
+        }
+    }
+}
+
+impl fmt::Display for DifficultyAdjustment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DifficultyAdjustment::Interval => write!(f, "interval"),
+            DifficultyAdjustment::Lwma { window } => write!(f, "lwma:{window}"),
+            DifficultyAdjustment::Ema { window } => write!(f, "ema:{window}"),
+        }
+    }
+}
+
+// `interval`, or `lwma` and `ema` with an optional `:window`
+impl FromStr for DifficultyAdjustment {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, window) = match s.split_once(':') {
+            Some((name, window)) => {
+                let window = window.parse::<u64>().map_err(|e| format!("bad window {window:?}: {e}"))?;
+                (name, Some(window))
+            }
+            None => (s, None),
+        };
+        let adjustment = match (name.to_ascii_lowercase().as_str(), window) {
+            ("interval", None) => DifficultyAdjustment::Interval,
+            ("lwma", window) => DifficultyAdjustment::Lwma {
+                window: window.unwrap_or(Self::DEFAULT_LWMA_WINDOW),
+            },
+            ("ema", window) => DifficultyAdjustment::Ema {
+                window: window.unwrap_or(Self::DEFAULT_EMA_WINDOW),
+            },
+            _ => {
+                return Err(format!(
+                    "unknown difficulty adjustment {s:?}, expected interval, lwma[:N] or ema[:N]"
+                ));
+            }
+        };
+        match adjustment {
+            // pruned chains and snapshots keep the timestamps the interval retarget needs
+            DifficultyAdjustment::Lwma { window } if window == 0 || window > crate::DIFFICULTY_UPDATE_INTERVAL => {
+                Err(format!("the lwma window must be 1 to {} blocks", crate::DIFFICULTY_UPDATE_INTERVAL))
+            }
+            DifficultyAdjustment::Ema { window: 0 } => Err("the ema window must be at least 1 block".into()),
+            adjustment => Ok(adjustment),
+        }
+    }
+}
+
+/// Consensus parameters of a chain that are not fixed constants
+#[derive(Clone, Debug)]
+pub struct ChainParams {
+    pub network: Network,
+    /// Blocks every valid chain contains. Chains contradicting any of
+    /// them are rejected, and signatures below the last one may be
+    /// skipped during sync.
+    pub checkpoints: Vec<Checkpoint>,
+    /// Version byte of Base58 addresses paying to a single key
+    pub address_version: u8,
+    /// Version byte of Base58 addresses paying to a multisig policy
+    pub multisig_address_version: u8,
+    /// Human-readable part of the chain's bech32 addresses
+    pub bech32_hrp: &'static str,
+    /// Hostnames resolving to nodes of the network, as `host` or
+    /// `host:port`, asked by nodes that know no peers. Not part of
+    /// consensus.
+    pub dns_seeds: Vec<String>,
+    /// Key whose signed checkpoints nodes accept from peers, so the
+    /// runner of a private network can protect it from rewrites by
+    /// whoever has the most hashrate. None on public networks.
+    pub checkpoint_key: Option<PublicKey>,
+    /// How the target is retargeted
+    pub difficulty_adjustment: DifficultyAdjustment,
+}
+
+impl ChainParams {
+    pub fn mainnet() -> Self {
+        ChainParams {
+            network: Network::Mainnet,
+            // no checkpoints yet
+            checkpoints: vec![],
+            address_version: 0x00,
+            multisig_address_version: 0x05,
+            bech32_hrp: "grp",
+            // no seeds run yet
+            dns_seeds: vec![],
+            checkpoint_key: None,
+            difficulty_adjustment: DifficultyAdjustment::Interval,
+        }
+    }
+
+    // version bytes as on Bitcoin's testnet, so addresses start with m/n/2
+    pub fn testnet() -> Self {
+        ChainParams {
+            network: Network::Testnet,
+            checkpoints: vec![],
+            address_version: 0x6f,
+            multisig_address_version: 0xc4,
+            bech32_hrp: "tgrp",
+            dns_seeds: vec![],
+            checkpoint_key: None,
+            difficulty_adjustment: DifficultyAdjustment::Interval,
+        }
+    }
+
+    /// Add checkpoints on top of the built-in ones
+    pub fn with_checkpoints(mut self, checkpoints: impl IntoIterator<Item = Checkpoint>) -> Self {
+        self.checkpoints.extend(checkpoints);
+        self
+    }
+
+    /// Add DNS seeds on top of the built-in ones
+    pub fn with_dns_seeds(mut self, seeds: impl IntoIterator<Item = String>) -> Self {
+        self.dns_seeds.extend(seeds);
+        self
+    }
+
+    /// Accept checkpoints signed by this key
+    pub fn with_checkpoint_key(mut self, key: PublicKey) -> Self {
+        self.checkpoint_key = Some(key);
+        self
+    }
+
+    /// Retarget with another algorithm
+    pub fn with_difficulty_adjustment(mut self, adjustment: DifficultyAdjustment) -> Self {
+        self.difficulty_adjustment = adjustment;
+        self
+    }
+
+    /// Hash the block at the given height must have, if checkpointed
+    pub fn checkpoint_at(&self, height: u64) -> Option<Hash> {
+        self.checkpoints
+            .iter()
+            .find(|checkpoint| checkpoint.height == height)
+            .map(|checkpoint| checkpoint.hash)
+    }
+
+    pub fn last_checkpoint_height(&self) -> Option<u64> {
+        self.checkpoints.iter().map(|checkpoint| checkpoint.height).max()
+    }
+
+    /// Satoshis the coinbase of the block at `height` may create on top
+    /// of the fees, halving every `HALVING_INTERVAL` blocks until it is 0
+    pub fn reward_at_height(height: u64) -> u64 {
+        let initial = crate::INITIAL_REWARD * 10u64.pow(8);
+        u32::try_from(height / crate::HALVING_INTERVAL)
+            .ok()
+            .and_then(|halvings| initial.checked_shr(halvings))
+            .unwrap_or(0)
+    }
+
+    /// Satoshis created by the rewards of the blocks up to and
+    /// including the one at `height`
+    pub fn total_supply_at(height: u64) -> u64 {
+        let mut supply = 0;
+        let mut era_start = 0;
+        while era_start <= height {
+            let reward = Self::reward_at_height(era_start);
+            if reward == 0 {
+                break;
+            }
+            let blocks = (height - era_start).min(crate::HALVING_INTERVAL - 1) + 1;
+            supply += reward * blocks;
+            era_start += crate::HALVING_INTERVAL;
+        }
+        supply
+    }
+
+    /// Satoshis there will ever be, once the reward reaches 0
+    pub fn max_supply() -> u64 {
+        let mut supply = 0;
+        let mut reward = crate::INITIAL_REWARD * 10u64.pow(8);
+        while reward > 0 {
+            supply += reward * crate::HALVING_INTERVAL;
+            reward >>= 1;
+        }
+        supply
+    }
+}
+
+impl Default for ChainParams {
+    fn default() -> Self {
+        Self::mainnet()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checkpoint_round_trip() {
+        let checkpoint = Checkpoint {
+            height: 42,
+            hash: Hash::hash(&"block"),
+        };
+        let parsed: Checkpoint = checkpoint.to_string().parse().unwrap();
+        assert_eq!(parsed, checkpoint);
+        assert!("42".parse::<Checkpoint>().is_err());
+        assert!("x:00".parse::<Checkpoint>().is_err());
+        assert!("42:zz".parse::<Checkpoint>().is_err());
+    }
+
+    #[test]
+    fn test_difficulty_adjustment_parsing() {
+        for adjustment in [
+            DifficultyAdjustment::Interval,
+            DifficultyAdjustment::lwma(),
+            DifficultyAdjustment::Ema { window: 7 },
+        ] {
+            assert_eq!(adjustment.to_string().parse::<DifficultyAdjustment>(), Ok(adjustment));
+        }
+        assert_eq!("LWMA".parse(), Ok(DifficultyAdjustment::lwma()));
+        assert!("lwma:0".parse::<DifficultyAdjustment>().is_err());
+        assert!("lwma:51".parse::<DifficultyAdjustment>().is_err());
+        assert!("ema:0".parse::<DifficultyAdjustment>().is_err());
+        assert!("interval:5".parse::<DifficultyAdjustment>().is_err());
+    }
+
+    #[test]
+    fn test_reward_schedule() {
+        let initial = crate::INITIAL_REWARD * 10u64.pow(8);
+        let interval = crate::HALVING_INTERVAL;
+        assert_eq!(ChainParams::reward_at_height(0), initial);
+        assert_eq!(ChainParams::reward_at_height(interval - 1), initial);
+        assert_eq!(ChainParams::reward_at_height(interval), initial / 2);
+        assert_eq!(ChainParams::reward_at_height(2 * interval + 1), initial / 4);
+        assert_eq!(ChainParams::reward_at_height(64 * interval), 0);
+        assert_eq!(ChainParams::reward_at_height(u64::MAX), 0);
+
+        assert_eq!(ChainParams::total_supply_at(0), initial);
+        assert_eq!(ChainParams::total_supply_at(interval - 1), initial * interval);
+        assert_eq!(ChainParams::total_supply_at(interval), initial * interval + initial / 2);
+        assert_eq!(
+            ChainParams::total_supply_at(2 * interval - 1),
+            initial * interval + initial / 2 * interval
+        );
+        // the supply stops growing once the reward is gone
+        let max = ChainParams::max_supply();
+        assert!(max < 2 * initial * interval);
+        assert_eq!(ChainParams::total_supply_at(64 * interval), max);
+        assert_eq!(ChainParams::total_supply_at(u64::MAX), max);
+        let last = (0..64).map(|era| era * interval).rfind(|&h| ChainParams::reward_at_height(h) > 0).unwrap();
+        assert_eq!(ChainParams::total_supply_at(last + interval - 1), max);
+        assert!(ChainParams::total_supply_at(last + interval - 2) < max);
+    }
+
+    #[test]
+    fn test_last_checkpoint() {
+        let hash = Hash::zero();
+        let params = ChainParams::mainnet().with_checkpoints([
+            Checkpoint { height: 10, hash },
+            Checkpoint { height: 3, hash },
+        ]);
+        assert_eq!(params.last_checkpoint_height(), Some(10));
+        assert_eq!(params.checkpoint_at(3), Some(hash));
+        assert_eq!(params.checkpoint_at(4), None);
+    }
+
+    #[test]
+    fn test_chain_rejects_checkpoint_mismatch() {
+        use crate::types::{Block, BlockHeader, Blockchain};
+        use crate::util::MerkleRoot;
+
+        let genesis = Block::new(
+            BlockHeader::new(
+                chrono::Utc::now(),
+                0,
+                Hash::zero(),
+                MerkleRoot::calculate(&[]),
+                crate::MIN_TARGET,
+            ),
+            vec![],
+        );
+        let checkpoint = |hash| Checkpoint { height: 0, hash };
+
+        let mut blockchain = Blockchain::new();
+        blockchain.set_params(ChainParams::mainnet().with_checkpoints([checkpoint(Hash::zero())]));
+        assert!(matches!(
+            blockchain.add_block(genesis.clone()),
+            Err(crate::error::BtcError::CheckpointMismatch)
+        ));
+
+        blockchain.set_params(ChainParams::mainnet().with_checkpoints([checkpoint(genesis.hash())]));
+        assert!(blockchain.add_block(genesis).is_ok());
+    }
+}
@@ -1,22 +1,57 @@
 use crate::U256;
-use serde::{Deserialize, Serialize};
-use sha256::digest;
-use std::fmt;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::str::FromStr;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
 
-#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq, Hash)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub struct Hash(U256);
 
+// the encoding of the derived impls, kept for CBOR as everything
+// hashed or stored is CBOR
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "Hash")]
+struct RawHash(U256);
+
+// human readable formats like JSON get the hex form of Display
+impl Serialize for Hash {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.collect_str(self)
+        } else {
+            RawHash(self.0).serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Hash {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            let hex = String::deserialize(deserializer)?;
+            hex.parse().map_err(serde::de::Error::custom)
+        } else {
+            RawHash::deserialize(deserializer).map(|raw| Hash(raw.0))
+        }
+    }
+}
+
 impl Hash {
     // hash anything that can be serde Serialized via ciborium
+    #[allow(clippy::self_named_constructors)]
     pub fn hash<T: serde::Serialize>(data: &T) -> Self {
         let mut serialized: Vec<u8> = vec![];
         if let Err(e) = ciborium::into_writer(data, &mut serialized) {
             panic!("Failed to serialize data: {:?}. This should not happen", e);
         }
-        let hash = digest(&serialized);
-        let hash_bytes = hex::decode(hash).unwrap();
-        let hash_array: [u8; 32] = hash_bytes.as_slice().try_into().unwrap();
+        Self::hash_bytes(&serialized)
+    }
 
+    // hash bytes that are already encoded
+    pub fn hash_bytes(bytes: &[u8]) -> Self {
+        let hash_array: [u8; 32] = Sha256::digest(bytes).into();
         Hash(U256::from_big_endian(&hash_array))
     }
 
@@ -33,6 +68,11 @@ impl Hash {
     pub fn as_bytes(&self) -> [u8; 32] {
         self.0.to_little_endian()
     }
+
+    // inverse of as_bytes
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Hash(U256::from_little_endian(&bytes))
+    }
 }
 
 impl fmt::Display for Hash {
@@ -40,3 +80,12 @@ impl fmt::Display for Hash {
         write!(f, "{:x}", self.0)
     }
 }
+
+// parses the hex form produced by Display
+impl FromStr for Hash {
+    type Err = uint::FromStrRadixErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        U256::from_str_radix(s, 16).map(Hash)
+    }
+}
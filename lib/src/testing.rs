@@ -0,0 +1,347 @@
+//! Proptest strategies for keys, transactions, blocks and valid chains,
+//! and checks of the invariants every chain must keep, so consensus
+//! changes can be tested against many generated cases instead of a few
+//! hand written ones. Enabled by the `testing` feature.
+use crate::crypto::{PrivateKey, Signature};
+use crate::params::ChainParams;
+use crate::sha256::Hash;
+use crate::types::{Block, BlockHeader, Blockchain, Transaction, TransactionInput, TransactionOutput};
+use crate::util::MerkleRoot;
+use crate::U256;
+use chrono::{DateTime, Duration, Utc};
+use proptest::collection::vec;
+use proptest::prelude::*;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+// blocks of generated chains are this far apart, from 2025-01-01
+const GENESIS_TIME: i64 = 1_735_689_600;
+
+/// Keys derived from an arbitrary seed
+pub fn private_key() -> impl Strategy<Value = PrivateKey> {
+    any::<[u8; 32]>().prop_filter_map("seed is not a valid key", |seed| PrivateKey::from_seed(&seed).ok())
+}
+
+pub fn hash() -> impl Strategy<Value = Hash> {
+    any::<[u8; 32]>().prop_map(Hash::from_bytes)
+}
+
+pub fn transaction_output() -> impl Strategy<Value = TransactionOutput> {
+    (any::<u64>(), any::<u128>(), private_key()).prop_map(|(value, id, key)| TransactionOutput {
+        value,
+        unique_id: Uuid::from_u128(id),
+        address: key.public_key().to_address(),
+    })
+}
+
+/// An input spending an arbitrary output with a valid signature
+pub fn transaction_input() -> impl Strategy<Value = TransactionInput> {
+    (hash(), private_key()).prop_map(|(hash, key)| TransactionInput {
+        prev_transaction_output_hash: hash,
+        public_key: key.public_key(),
+        signature: Signature::sign_output(&hash, &key),
+        multisig: None,
+        cosignatures: vec![],
+    })
+}
+
+/// Well formed transactions, spending outputs that need not exist
+pub fn transaction() -> impl Strategy<Value = Transaction> {
+    (vec(transaction_input(), 1..4), vec(transaction_output(), 1..4))
+        .prop_map(|(inputs, outputs)| Transaction::new(inputs, outputs))
+}
+
+/// Well formed blocks starting with a coinbase, which need not meet
+/// their target or fit any chain
+pub fn block() -> impl Strategy<Value = Block> {
+    (
+        0..4_000_000_000i64,
+        0..1_000_000_000u32,
+        any::<u64>(),
+        hash(),
+        any::<[u64; 4]>(),
+        vec(transaction_output(), 1..3),
+        vec(transaction(), 0..4),
+    )
+        .prop_map(|(seconds, nanos, nonce, prev_block_hash, target, coinbase, spends)| {
+            let mut transactions = vec![Transaction::new(vec![], coinbase)];
+            transactions.extend(spends);
+            let timestamp = DateTime::from_timestamp(seconds, nanos).expect("timestamp in range");
+            let header = BlockHeader::new(
+                timestamp,
+                nonce,
+                prev_block_hash,
+                MerkleRoot::calculate(&transactions),
+                U256(target),
+            );
+            Block::new(header, transactions)
+        })
+}
+
+/// What a generated block spends: for each transaction, the unspent
+/// output to spend, by position among them sorted by hash and modulo
+/// their number, the number of outputs to split it into and the fee
+pub type BlockPlan = Vec<(usize, usize, u64)>;
+
+pub fn block_plan() -> impl Strategy<Value = BlockPlan> {
+    vec((any::<usize>(), 1..4usize, 0..1000u64), 0..4)
+}
+
+/// Valid chains of 1 to `max_blocks` blocks paying to one key, whose
+/// blocks after the genesis spend outputs of earlier ones. Every block
+/// is mined, so keep `max_blocks` small.
+pub fn chain(max_blocks: usize) -> impl Strategy<Value = Blockchain> {
+    (private_key(), vec(block_plan(), 0..max_blocks.max(1)))
+        .prop_map(|(key, plans)| build_chain(&key, &plans))
+}
+
+/// An unmined block without transactions, for storage and relay tests
+/// that don't need it to fit a chain. `nonce` tells such blocks apart.
+pub fn empty_block(nonce: u64) -> Block {
+    Block::new(
+        BlockHeader::new(Utc::now(), nonce, Hash::zero(), MerkleRoot::calculate(&[]), crate::MIN_TARGET),
+        vec![],
+    )
+}
+
+/// Build a chain with a genesis block paying its reward to `key`, then
+/// one block per plan. Each planned spend goes through the mempool and
+/// the block takes its template, with a coinbase claiming the whole
+/// reward and fees.
+pub fn build_chain(key: &PrivateKey, plans: &[BlockPlan]) -> Blockchain {
+    let address = key.public_key().to_address();
+    let mut ids = 0u128;
+    let mut pay = |value| {
+        ids += 1;
+        TransactionOutput {
+            value,
+            unique_id: Uuid::from_u128(ids),
+            address: address.clone(),
+        }
+    };
+    let mut blockchain = Blockchain::new();
+    let coinbase = vec![Transaction::new(vec![], vec![pay(ChainParams::reward_at_height(0))])];
+    let genesis = Block::new(
+        BlockHeader::new(
+            block_time(0),
+            0,
+            Hash::zero(),
+            MerkleRoot::calculate(&coinbase),
+            blockchain.target(),
+        ),
+        coinbase,
+    );
+    blockchain.add_block(genesis).expect("genesis block");
+    blockchain.rebuild_utxos();
+
+    for plan in plans {
+        let mut unspent: Vec<(Hash, u64)> = blockchain
+            .utxos()
+            .iter()
+            .map(|(hash, (_, _, output))| (*hash, output.value))
+            .collect();
+        unspent.sort_by_key(|(hash, _)| hash.as_bytes());
+        let mut fees = 0;
+        for (index, splits, fee) in plan {
+            if unspent.is_empty() {
+                break;
+            }
+            let (spent, value) = unspent.remove(index % unspent.len());
+            let fee = (*fee).min(value);
+            let splits = (*splits).max(1);
+            let part = (value - fee) / splits as u64;
+            let mut outputs: Vec<TransactionOutput> = (0..splits).map(|_| pay(part)).collect();
+            outputs[0].value += (value - fee) % splits as u64;
+            let input = TransactionInput {
+                prev_transaction_output_hash: spent,
+                public_key: key.public_key(),
+                signature: Signature::sign_output(&spent, key),
+                multisig: None,
+                cosignatures: vec![],
+            };
+            blockchain
+                .add_to_mempool(Transaction::new(vec![input], outputs))
+                .expect("planned spend is valid");
+            fees += fee;
+        }
+
+        let height = blockchain.block_height();
+        let mut transactions = vec![Transaction::new(
+            vec![],
+            vec![pay(ChainParams::reward_at_height(height) + fees)],
+        )];
+        transactions.extend(blockchain.template_transactions(crate::BLOCK_TRANSACTION_CAP));
+        let mut block = Block::new(
+            BlockHeader::new(
+                block_time(height),
+                0,
+                blockchain.tip_hash().expect("chain has a tip"),
+                MerkleRoot::calculate(&transactions),
+                blockchain.target(),
+            ),
+            transactions,
+        );
+        while !block.header.mine(100_000) {}
+        blockchain.add_block(block).expect("generated block is valid");
+        blockchain.rebuild_utxos();
+    }
+    blockchain
+}
+
+fn block_time(height: u64) -> DateTime<Utc> {
+    DateTime::from_timestamp(GENESIS_TIME, 0).expect("timestamp in range")
+        + Duration::seconds((height * crate::IDEAL_BLOCK_TIME) as i64)
+}
+
+/// Check that the UTXO set holds exactly the outputs the blocks created
+/// and didn't spend, at the height they were created, and that its
+/// mempool marks agree with the reservations. The chain must store
+/// every block from the genesis.
+pub fn check_utxos(chain: &Blockchain) -> Result<(), String> {
+    if chain.base().is_some() {
+        return Err("the chain doesn't store its blocks from the genesis".to_string());
+    }
+    let mut unspent: HashMap<Hash, u64> = HashMap::new();
+    for (height, block) in chain.blocks().enumerate() {
+        for transaction in &block.transactions {
+            for input in &transaction.inputs {
+                if unspent.remove(&input.prev_transaction_output_hash).is_none() {
+                    return Err(format!(
+                        "block {} spends {} which isn't unspent",
+                        height, input.prev_transaction_output_hash
+                    ));
+                }
+            }
+            for output in &transaction.outputs {
+                unspent.insert(output.hash(), height as u64);
+            }
+        }
+    }
+    if unspent.len() != chain.utxos().len() {
+        return Err(format!(
+            "the blocks leave {} outputs unspent but the UTXO set has {}",
+            unspent.len(),
+            chain.utxos().len()
+        ));
+    }
+    for (hash, (_, height, output)) in chain.utxos() {
+        if output.hash() != *hash {
+            return Err(format!("{} is stored under {}", output.hash(), hash));
+        }
+        match unspent.get(hash) {
+            Some(created) if created == height => {}
+            Some(created) => {
+                return Err(format!("{} was created at {} but is stored at {}", hash, created, height));
+            }
+            None => return Err(format!("{} is in the UTXO set but not unspent", hash)),
+        }
+    }
+    chain.check_reservations()
+}
+
+/// Check that coins are only created by coinbases, within the reward
+/// and fees of their block, and only destroyed as fees: the unspent
+/// outputs add up to what the coinbases created less the fees. The
+/// genesis coinbase is not validated, so it counts as it is. The chain
+/// must store every block from the genesis.
+pub fn check_supply(chain: &Blockchain) -> Result<(), String> {
+    if chain.base().is_some() {
+        return Err("the chain doesn't store its blocks from the genesis".to_string());
+    }
+    let mut values: HashMap<Hash, u64> = HashMap::new();
+    let (mut created, mut fees, mut allowed) = (0u128, 0u128, 0u128);
+    for (height, block) in chain.blocks().enumerate() {
+        let mut block_fees = 0u128;
+        for transaction in block.transactions.iter().skip(1) {
+            let mut spent = 0u128;
+            for input in &transaction.inputs {
+                let value = values
+                    .get(&input.prev_transaction_output_hash)
+                    .ok_or_else(|| format!("block {} spends an unknown output", height))?;
+                spent += *value as u128;
+            }
+            let paid: u128 = transaction.outputs.iter().map(|output| output.value as u128).sum();
+            block_fees += spent
+                .checked_sub(paid)
+                .ok_or_else(|| format!("transaction {} pays more than it spends", transaction.hash()))?;
+        }
+        for transaction in &block.transactions {
+            for output in &transaction.outputs {
+                values.insert(output.hash(), output.value);
+            }
+        }
+        let coinbase = block
+            .transactions
+            .first()
+            .ok_or_else(|| format!("block {} has no coinbase", height))?;
+        let claimed: u128 = coinbase.outputs.iter().map(|output| output.value as u128).sum();
+        let limit = match height {
+            0 => claimed,
+            _ => ChainParams::reward_at_height(height as u64) as u128 + block_fees,
+        };
+        if claimed > limit {
+            return Err(format!("block {} claims {} but may claim {}", height, claimed, limit));
+        }
+        created += claimed;
+        fees += block_fees;
+        allowed += limit;
+    }
+    let unspent: u128 = chain.utxos().values().map(|(_, _, output)| output.value as u128).sum();
+    if unspent != created - fees {
+        return Err(format!(
+            "{} is unspent but coinbases created {} and fees took {}",
+            unspent, created, fees
+        ));
+    }
+    if created > allowed {
+        return Err(format!("coinbases created {} of at most {}", created, allowed));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::{Decode, Encode};
+
+    proptest! {
+        #[test]
+        fn test_transaction_encoding_round_trips(transaction in transaction()) {
+            let decoded = Transaction::from_bytes(&transaction.to_bytes()).unwrap();
+            prop_assert_eq!(decoded.hash(), transaction.hash());
+            prop_assert_eq!(decoded.to_bytes(), transaction.to_bytes());
+        }
+
+        #[test]
+        fn test_block_encoding_round_trips(block in block()) {
+            let decoded = Block::from_bytes(&block.to_bytes()).unwrap();
+            prop_assert_eq!(decoded.hash(), block.hash());
+            prop_assert_eq!(decoded.to_bytes(), block.to_bytes());
+        }
+    }
+
+    proptest! {
+        // every case mines its blocks
+        #![proptest_config(ProptestConfig::with_cases(8))]
+
+        #[test]
+        fn test_generated_chains_keep_invariants(chain in chain(4)) {
+            prop_assert_eq!(check_utxos(&chain), Ok(()));
+            prop_assert_eq!(check_supply(&chain), Ok(()));
+            let mut rebuilt = chain.clone();
+            rebuilt.rebuild_utxos();
+            prop_assert_eq!(check_utxos(&rebuilt), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_build_chain() {
+        let key = PrivateKey::from_seed(b"testing").unwrap();
+        let chain = build_chain(&key, &[vec![(0, 2, 10)], vec![(1, 1, 0), (5, 3, 7)]]);
+        assert_eq!(chain.block_height(), 3);
+        // the genesis output split in two, then both spent again
+        assert_eq!(chain.utxos().len(), 2 + 1 + 3);
+        assert_eq!(check_utxos(&chain), Ok(()));
+        assert_eq!(check_supply(&chain), Ok(()));
+    }
+}
@@ -1,7 +1,17 @@
 mod block;
+#[cfg(feature = "std")]
 mod blockchain;
+mod psbt;
+#[cfg(feature = "std")]
+mod snapshot;
 mod transaction;
 
-pub use block::{Block, BlockHeader};
-pub use blockchain::Blockchain;
-pub use transaction::{Transaction, TransactionInput, TransactionOutput};
\ No newline at end of file
+pub use block::{Block, BlockHeader, TransactionProof};
+#[cfg(feature = "std")]
+pub use blockchain::{Blockchain, ChainTip, ChainUpdate, MempoolCleanup, Reservation, SideBlock, TipStatus};
+pub use psbt::{PartiallySignedTransaction, PsbtInput};
+#[cfg(feature = "std")]
+pub use snapshot::{ChainBase, Snapshot};
+pub use transaction::{
+    Transaction, TransactionInput, TransactionOutput, UnsignedInput, UnsignedTransaction,
+};
\ No newline at end of file
@@ -1,16 +1,21 @@
-use super::{Transaction, TransactionOutput};
-use crate::{
-    U256,
-    error::{BtcError, Result},
-    sha256::Hash,
-    util::MerkleRoot,
-};
+use super::Transaction;
+#[cfg(feature = "std")]
+use super::TransactionOutput;
+use crate::error::{BtcError, Result};
+use crate::util::MerkleProof;
+use crate::{U256, sha256::Hash, util::MerkleRoot};
+#[cfg(feature = "std")]
 use crate::util::Saveable;
+use alloc::vec::Vec;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+#[cfg(feature = "std")]
 use std::collections::HashMap;
+#[cfg(feature = "std")]
 use std::io::{Read, Write, Result as IoResult, Error as IoError, ErrorKind as IoErrorKind};
+#[cfg(feature = "std")]
 use tracing::warn;
+use uuid::Uuid;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Block {
@@ -21,8 +26,8 @@ pub struct Block {
 impl Block {
     pub fn new(header: BlockHeader, transactions: Vec<Transaction>) -> Self {
         Block {
-            header: header,
-            transactions: transactions,
+            header,
+            transactions,
         }
     }
 
@@ -30,27 +35,69 @@ impl Block {
         Hash::hash(self)
     }
 
+    /// The extranonce, kept in the low 8 bytes of the unique id of the
+    /// coinbase's first output. Templates set it to 0 under a random
+    /// prefix in the high 8 bytes, so miners can split the search
+    /// between them by extranonce once the 64-bit nonce isn't enough.
+    pub fn extranonce(&self) -> Option<u64> {
+        let output = self.transactions.first()?.outputs.first()?;
+        Some(output.unique_id.as_u64_pair().1)
+    }
+
+    /// Merkle branch of the coinbase, to recompute the merkle root for
+    /// a new extranonce without hashing every transaction again
+    pub fn coinbase_branch(&self) -> Option<MerkleProof> {
+        MerkleRoot::proof(&self.transactions, 0)
+    }
+
+    /// Set the extranonce and the merkle root that follows from it,
+    /// using the branch `coinbase_branch` returned for this block
+    pub fn set_extranonce(&mut self, extranonce: u64, coinbase_branch: &MerkleProof) -> Result<()> {
+        let coinbase = self.transactions.first_mut().ok_or(BtcError::MissingCoinbase)?;
+        let output = coinbase.outputs.first_mut().ok_or(BtcError::EmptyCoinbase)?;
+        let (prefix, _) = output.unique_id.as_u64_pair();
+        output.unique_id = Uuid::from_u64_pair(prefix, extranonce);
+        self.header.merkle_root = coinbase_branch.root(coinbase.hash());
+        Ok(())
+    }
+
+    /// Proof that the transaction with the given hash is in this block,
+    /// None if it isn't
+    pub fn transaction_proof(&self, height: u64, txid: Hash) -> Option<TransactionProof> {
+        let index = self.transactions.iter().position(|tx| tx.hash() == txid)?;
+        Some(TransactionProof {
+            header: self.header.clone(),
+            height,
+            txid,
+            proof: MerkleRoot::proof(&self.transactions, index)?,
+        })
+    }
+
+    // signatures may be skipped for blocks committed to by a checkpoint
+    #[cfg(feature = "std")]
     pub fn verify_transactions(
         &self,
         predicted_block_height: u64,
-        utxos: &HashMap<Hash, (bool, TransactionOutput)>,
+        utxos: &HashMap<Hash, (bool, u64, TransactionOutput)>,
+        verify_signatures: bool,
     ) -> Result<()> {
         let mut inputs: HashMap<Hash, TransactionOutput> = HashMap::new();
 
-        if self.transactions.is_empty() {
-            return Err(BtcError::InvalidTransaction);
-        }
-
         self.verify_coinbase_transaction(predicted_block_height, utxos)?;
 
+        // outputs of earlier transactions in the block may be spent by
+        // later ones, so a child can be mined along with its parent
+        let mut created: HashMap<Hash, &TransactionOutput> = HashMap::new();
+
         for transaction in self.transactions.iter().skip(1) {
-            let mut input_value = 0;
-            let mut output_value = 0;
+            let mut input_value = 0u64;
+            let mut output_value = 0u64;
 
             for input in &transaction.inputs {
                 let prev_output = utxos
                     .get(&input.prev_transaction_output_hash)
-                    .map(|(_, output)| output);
+                    .map(|(_, _, output)| output)
+                    .or_else(|| created.get(&input.prev_transaction_output_hash).copied());
 
                 if prev_output.is_none() {
                     warn!("Previous output not found");
@@ -63,27 +110,20 @@ impl Block {
                     return Err(BtcError::InvalidTransactionInput);
                 }
 
-                // Verify address matches: hash the input's public key and compare with output address
-                let computed_address = input.public_key.to_address();
-                if computed_address != prev_output.address {
-                    warn!("Address mismatch: computed {} != output {}", computed_address, prev_output.address);
-                    return Err(BtcError::InvalidSignature);
-                }
-
-                // Verify signature
-                if !input
-                    .signature
-                    .verify(&input.prev_transaction_output_hash, &input.public_key)
-                {
-                    return Err(BtcError::InvalidSignature);
+                // Verify the input's key (or multisig policy) owns the
+                // output and, unless skipped, its signatures
+                if let Err(e) = input.verify(prev_output, verify_signatures) {
+                    warn!("Input {} cannot spend output of {}", input.address(), prev_output.address);
+                    return Err(e);
                 }
 
-                input_value += prev_output.value;
+                input_value = input_value.checked_add(prev_output.value).ok_or(BtcError::InvalidAmount)?;
                 inputs.insert(input.prev_transaction_output_hash, prev_output.clone());
             }
 
             for output in &transaction.outputs {
-                output_value += output.value;
+                output_value = output_value.checked_add(output.value).ok_or(BtcError::InvalidAmount)?;
+                created.insert(output.hash(), output);
             }
 
             if input_value < output_value {
@@ -94,47 +134,63 @@ impl Block {
         Ok(())
     }
 
+    #[cfg(feature = "std")]
     pub fn verify_coinbase_transaction(
         &self,
         predicted_block_height: u64,
-        utxos: &HashMap<Hash, (bool, TransactionOutput)>,
+        utxos: &HashMap<Hash, (bool, u64, TransactionOutput)>,
     ) -> Result<()> {
-        // coinbase tx is the first transaction in the block
-        let coinbase_transaction = &self.transactions[0];
-        if coinbase_transaction.inputs.len() != 0 {
-            return Err(BtcError::InvalidTransaction);
+        // coinbase tx is the first transaction in the block, and the
+        // only one without inputs
+        let coinbase_transaction = match self.transactions.first() {
+            Some(transaction) if transaction.inputs.is_empty() => transaction,
+            _ => {
+                warn!("Block does not start with a coinbase transaction");
+                return Err(BtcError::MissingCoinbase);
+            }
+        };
+        if coinbase_transaction.outputs.is_empty() {
+            return Err(BtcError::EmptyCoinbase);
         }
-        if coinbase_transaction.outputs.len() == 0 {
-            return Err(BtcError::InvalidTransaction);
+        if self.transactions[1..].iter().any(|transaction| transaction.inputs.is_empty()) {
+            warn!("Block has more than one coinbase transaction");
+            return Err(BtcError::UnexpectedCoinbase);
         }
         let miner_fees = self.calculate_miner_fees(utxos)?;
-        let block_reward = crate::INITIAL_REWARD * 10u64.pow(8)
-            / 2u64.pow((predicted_block_height / crate::HALVING_INTERVAL) as u32);
-        let total_coinbase_outputs: u64 = coinbase_transaction
+        let block_reward = crate::params::ChainParams::reward_at_height(predicted_block_height);
+        let claimed = coinbase_transaction
             .outputs
             .iter()
-            .map(|output| output.value)
-            .sum();
-        if total_coinbase_outputs != block_reward + miner_fees {
-            return Err(BtcError::InvalidTransaction);
+            .try_fold(0u64, |total, output| total.checked_add(output.value))
+            .ok_or(BtcError::InvalidAmount)?;
+        let allowed = block_reward.saturating_add(miner_fees);
+        // miners may claim less than they are owed, never more
+        if claimed > allowed {
+            warn!("Coinbase creates {} but the reward and fees are only {}", claimed, allowed);
+            return Err(BtcError::CoinbaseTooLarge { claimed, allowed });
         }
         Ok(())
     }
 
+    #[cfg(feature = "std")]
     pub fn calculate_miner_fees(
         &self,
-        utxos: &HashMap<Hash, (bool, TransactionOutput)>,
+        utxos: &HashMap<Hash, (bool, u64, TransactionOutput)>,
     ) -> Result<u64> {
         // todo - get rid of hashmaps as we only need the values
         let mut inputs: HashMap<Hash, TransactionOutput> = HashMap::new();
         let mut outputs: HashMap<Hash, TransactionOutput> = HashMap::new();
+        let mut fees = 0u64;
         // Check every transaction after coinbase
         for transaction in self.transactions.iter().skip(1) {
+            let mut input_value = 0u64;
+            let mut output_value = 0u64;
             for input in &transaction.inputs {
                 // inputs do not contain the values of the outputs so we need to match inputs to outputs
                 let prev_output = utxos
                     .get(&input.prev_transaction_output_hash)
-                    .map(|(_, output)| output);
+                    .map(|(_, _, output)| output)
+                    .or_else(|| outputs.get(&input.prev_transaction_output_hash));
 
                 if prev_output.is_none() {
                     return Err(BtcError::InvalidTransaction);
@@ -143,21 +199,26 @@ impl Block {
                 if inputs.contains_key(&input.prev_transaction_output_hash) {
                     return Err(BtcError::InvalidTransaction);
                 }
+                input_value = input_value.checked_add(prev_output.value).ok_or(BtcError::InvalidAmount)?;
                 inputs.insert(input.prev_transaction_output_hash, prev_output.clone());
             }
             for output in &transaction.outputs {
                 if outputs.contains_key(&output.hash()) {
                     return Err(BtcError::InvalidTransaction);
                 }
+                output_value = output_value.checked_add(output.value).ok_or(BtcError::InvalidAmount)?;
                 outputs.insert(output.hash(), output.clone());
             }
+            // this runs before each transaction is verified, so one
+            // spending more than it has must not wrap the fees around
+            let fee = input_value.checked_sub(output_value).ok_or(BtcError::InvalidTransactionOutput)?;
+            fees = fees.checked_add(fee).ok_or(BtcError::InvalidAmount)?;
         }
-        let input_value: u64 = inputs.values().map(|output| output.value).sum();
-        let output_value: u64 = outputs.values().map(|output| output.value).sum();
-        Ok(input_value - output_value)
+        Ok(fees)
     }
 }
 
+#[cfg(feature = "std")]
 impl Saveable for Block {
     fn load<I: Read>(reader: I) -> IoResult<Self> {
         ciborium::de::from_reader(reader).map_err(|_| {
@@ -179,6 +240,7 @@ pub struct BlockHeader {
     pub prev_block_hash: Hash,
     pub merkle_root: MerkleRoot,
     /// A number, which has to be higher than the hash of this block for it to be considered valid.
+    #[serde(with = "crate::u256_hex")]
     pub target: U256,
 }
 
@@ -203,6 +265,7 @@ impl BlockHeader {
         Hash::hash(self)
     }
 
+    #[cfg(feature = "std")]
     pub fn mine(&mut self, steps: usize) -> bool {
         // if the block already matches target, return early
         if self.hash().matches_target(self.target) {
@@ -222,3 +285,142 @@ impl BlockHeader {
         false
     }
 }
+
+/// A transaction's merkle branch along with the header of the block
+/// holding it, to check the transaction was confirmed without the rest
+/// of the block. The height is only a hint where to look the header up.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TransactionProof {
+    pub header: BlockHeader,
+    pub height: u64,
+    pub txid: Hash,
+    pub proof: MerkleProof,
+}
+
+impl TransactionProof {
+    /// Hash of the proven block's header. Blocks are looked up by the
+    /// hash of the whole block, which a proof can't give.
+    pub fn header_hash(&self) -> Hash {
+        self.header.hash()
+    }
+
+    /// Whether the branch leads from the transaction to the header's
+    /// merkle root. Whether the header is on the main chain is up to
+    /// the caller.
+    pub fn verify(&self) -> bool {
+        self.proof.verify(self.txid, &self.header.merkle_root)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{PrivateKey, Signature};
+    use crate::types::TransactionInput;
+
+    fn output(key: &PrivateKey, value: u64) -> TransactionOutput {
+        TransactionOutput {
+            value,
+            unique_id: Uuid::new_v4(),
+            address: key.public_key().to_address(),
+        }
+    }
+
+    // a block spending `prev` from the UTXO set into `values`, after a
+    // coinbase claiming nothing but the reward
+    fn spending_block(key: &PrivateKey, prev: &TransactionOutput, values: &[u64]) -> Block {
+        let hash = prev.hash();
+        let transactions = vec![
+            Transaction::new(vec![], vec![output(key, 1)]),
+            Transaction::new(
+                vec![TransactionInput {
+                    prev_transaction_output_hash: hash,
+                    public_key: key.public_key(),
+                    signature: Signature::sign_output(&hash, key),
+                    multisig: None,
+                    cosignatures: vec![],
+                }],
+                values.iter().map(|value| output(key, *value)).collect(),
+            ),
+        ];
+        let header = BlockHeader::new(Utc::now(), 0, Hash::zero(), MerkleRoot::calculate(&transactions), U256::MAX);
+        Block::new(header, transactions)
+    }
+
+    #[test]
+    fn test_overspending_is_rejected_before_fees() {
+        let key = PrivateKey::new_key();
+        let prev = output(&key, 10);
+        let utxos = HashMap::from([(prev.hash(), (false, 0, prev.clone()))]);
+
+        let fair = spending_block(&key, &prev, &[4, 5]);
+        assert_eq!(fair.calculate_miner_fees(&utxos).unwrap(), 1);
+        assert!(fair.verify_transactions(1, &utxos, true).is_ok());
+
+        let overspending = spending_block(&key, &prev, &[20]);
+        assert!(matches!(overspending.calculate_miner_fees(&utxos), Err(BtcError::InvalidTransactionOutput)));
+        assert!(matches!(
+            overspending.verify_transactions(1, &utxos, true),
+            Err(BtcError::InvalidTransactionOutput)
+        ));
+
+        let overflowing = spending_block(&key, &prev, &[u64::MAX, 1]);
+        assert!(matches!(overflowing.calculate_miner_fees(&utxos), Err(BtcError::InvalidAmount)));
+        assert!(matches!(overflowing.verify_transactions(1, &utxos, true), Err(BtcError::InvalidAmount)));
+    }
+
+    #[test]
+    fn test_set_extranonce() {
+        let key = PrivateKey::new_key();
+        let transactions: Vec<Transaction> = (0..5)
+            .map(|value| {
+                Transaction::new(
+                    vec![],
+                    vec![TransactionOutput {
+                        value,
+                        unique_id: Uuid::from_u64_pair(7, 0),
+                        address: key.public_key().to_address(),
+                    }],
+                )
+            })
+            .collect();
+        let header = BlockHeader::new(Utc::now(), 0, Hash::zero(), MerkleRoot::calculate(&transactions), U256::MAX);
+        let mut block = Block::new(header, transactions);
+        assert_eq!(block.extranonce(), Some(0));
+
+        let branch = block.coinbase_branch().unwrap();
+        block.set_extranonce(42, &branch).unwrap();
+        assert_eq!(block.extranonce(), Some(42));
+        assert_eq!(block.transactions[0].outputs[0].unique_id.as_u64_pair(), (7, 42));
+        assert_eq!(block.header.merkle_root, MerkleRoot::calculate(&block.transactions));
+    }
+
+    #[test]
+    fn test_transaction_proof() {
+        let key = PrivateKey::new_key();
+        let transactions: Vec<Transaction> = (0..5)
+            .map(|value| {
+                Transaction::new(
+                    vec![],
+                    vec![TransactionOutput {
+                        value,
+                        unique_id: Uuid::new_v4(),
+                        address: key.public_key().to_address(),
+                    }],
+                )
+            })
+            .collect();
+        let header = BlockHeader::new(Utc::now(), 0, Hash::zero(), MerkleRoot::calculate(&transactions), U256::MAX);
+        let block = Block::new(header, transactions);
+        for transaction in &block.transactions {
+            let proof = block.transaction_proof(3, transaction.hash()).unwrap();
+            assert!(proof.verify());
+            assert_eq!(proof.header_hash(), block.header.hash());
+        }
+        assert!(block.transaction_proof(3, Hash::zero()).is_none());
+
+        let mut forged = block.transaction_proof(3, block.transactions[4].hash()).unwrap();
+        forged.txid = block.transactions[3].hash();
+        assert!(!forged.verify());
+    }
+}
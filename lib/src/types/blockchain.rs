@@ -1,4 +1,8 @@
-use super::{Block, Transaction, TransactionOutput};
+use super::{Block, ChainBase, Snapshot, Transaction, TransactionOutput};
+use crate::address::Address;
+use crate::difficulty::work_from_target;
+use crate::muhash::MuHash;
+use crate::params::{ChainParams, Checkpoint, SignedCheckpoint};
 use crate::util::Saveable;
 use crate::{
     U256,
@@ -6,69 +10,1370 @@ use crate::{
     sha256::Hash,
     util::MerkleRoot,
 };
-use bigdecimal::BigDecimal;
 use chrono::{DateTime, Utc};
 use hex;
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::io::{Read, Write, Result as IoResult, Error as IoError, ErrorKind as IoErrorKind};
 use tracing::{instrument, warn, error, info};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Blockchain {
-    utxos: HashMap<Hash, (bool, TransactionOutput)>,
+    // every unspent output by its own hash, which covers its unique
+    // id, so the outputs of a transaction never share a key. Inputs
+    // name the output they spend by the same hash.
+    utxos: HashMap<Hash, (bool, u64, TransactionOutput)>,
+    // hash of the unspent outputs with their heights, marks left out,
+    // kept up to date with every change to `utxos`
+    #[serde(default, skip)]
+    utxo_hash: MuHash,
     target: U256,
     blocks: Vec<Block>,
     #[serde(default, skip_deserializing)]
-    pub mempool: Vec<(DateTime<Utc>, Transaction)>,
+    mempool: Vec<(DateTime<Utc>, Transaction)>,
+    // unspent outputs spent by mempool transactions, the only source
+    // of the marks kept in `utxos`
+    #[serde(default, skip)]
+    reservations: HashMap<Hash, Reservation>,
+    /// Set when the chain was started from a snapshot, until all
+    /// blocks below it have been backfilled
+    #[serde(default)]
+    base: Option<ChainBase>,
+    // backfilled blocks below the base, newest first
+    #[serde(default, skip)]
+    backfill: Vec<Block>,
+    // backfill blocks that arrived before their successor
+    #[serde(default, skip)]
+    backfill_pending: HashMap<Hash, Block>,
+    #[serde(default, skip)]
+    params: ChainParams,
+    // verify signatures even below the last checkpoint
+    #[serde(default, skip)]
+    full_verification: bool,
+    // seconds a transaction may wait in the mempool
+    #[serde(skip, default = "default_max_mempool_age")]
+    max_mempool_age: u64,
+    // checkpoints taken from the holder of the checkpoint key, also
+    // in the checkpoints of `params`
+    #[serde(default, skip)]
+    signed_checkpoints: Vec<SignedCheckpoint>,
+    // height of every locally stored block by hash
+    #[serde(default, skip)]
+    block_index: HashMap<Hash, u64>,
+    // height of the block and position within it of every locally
+    // stored transaction by hash
+    #[serde(default, skip)]
+    transaction_index: HashMap<Hash, (u64, usize)>,
+    // work of the chain up to and including each stored block
+    #[serde(default, skip)]
+    chain_work: Vec<U256>,
+    // what connecting each block changed, to take it off in a reorg
+    #[serde(default, skip)]
+    undo: HashMap<Hash, BlockUndo>,
+    // blocks of branches off the main chain, by hash
+    #[serde(default, skip)]
+    side_blocks: HashMap<Hash, SideBlock>,
+    // blocks waiting for their parent, by the parent's hash
+    #[serde(default, skip)]
+    orphans: HashMap<Hash, Vec<Block>>,
+    // blocks that failed validation, and blocks building on them
+    #[serde(default, skip)]
+    invalid: HashSet<Hash>,
+}
+
+// what connecting a block changed
+#[derive(Clone, Debug)]
+struct BlockUndo {
+    // the chain's target before the block
+    target: U256,
+    // unspent outputs the block spent, with the heights they were
+    // created at
+    spent: Vec<(Hash, u64, TransactionOutput)>,
+}
+
+/// A block on a branch off the main chain
+#[derive(Clone, Debug)]
+pub struct SideBlock {
+    pub block: Block,
+    pub height: u64,
+    /// Work of the branch up to and including the block
+    pub chain_work: U256,
+}
+
+/// The last block of the main chain or of a branch off it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChainTip {
+    pub hash: Hash,
+    pub height: u64,
+    /// Blocks from where the branch leaves the main chain, 0 for the
+    /// main chain's tip
+    pub branch_length: u64,
+    pub chain_work: U256,
+    pub status: TipStatus,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TipStatus {
+    /// The tip of the main chain
+    Active,
+    /// A branch with less work, whose transactions are checked if it
+    /// ever gets the most
+    ValidFork,
+    /// A branch with a block that failed validation
+    Invalid,
+}
+
+impl fmt::Display for TipStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TipStatus::Active => write!(f, "active"),
+            TipStatus::ValidFork => write!(f, "valid-fork"),
+            TipStatus::Invalid => write!(f, "invalid"),
+        }
+    }
+}
+
+/// How adding a block changed the main chain. The blocks from
+/// `fork_height` up are new, which is none when the block went on a
+/// branch with less work.
+#[derive(Clone, Debug, Default)]
+pub struct ChainUpdate {
+    pub fork_height: u64,
+    /// Blocks a reorg took off the main chain, oldest first
+    pub disconnected: Vec<Block>,
+}
+
+/// An unspent output set aside for the mempool transaction spending it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Reservation {
+    pub txid: Hash,
+    /// When the transaction leaves the mempool unless it is mined first
+    pub expires: DateTime<Utc>,
+}
+
+/// What a mempool cleanup removed
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MempoolCleanup {
+    /// Transactions older than the maximum age
+    pub expired: usize,
+    /// Their unconfirmed descendants, which can't be mined without them
+    pub descendants: usize,
+    /// Unspent outputs no longer reserved
+    pub unmarked: usize,
+}
+
+// out of order backfill blocks kept around before giving up on them
+const MAX_BACKFILL_PENDING: usize = 64;
+
+// blocks kept waiting for their parent before giving up on them
+const MAX_ORPHANS: usize = 256;
+
+fn default_max_mempool_age() -> u64 {
+    crate::MAX_MEMPOOL_TRANSACTION_AGE
+}
+
+impl Default for Blockchain {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Blockchain {
     pub fn new() -> Self {
         Self {
             utxos: HashMap::new(),
+            utxo_hash: MuHash::new(),
             target: crate::MIN_TARGET,
             blocks: vec![],
             mempool: vec![],
+            reservations: HashMap::new(),
+            base: None,
+            backfill: vec![],
+            backfill_pending: HashMap::new(),
+            params: ChainParams::default(),
+            full_verification: false,
+            max_mempool_age: crate::MAX_MEMPOOL_TRANSACTION_AGE,
+            signed_checkpoints: vec![],
+            block_index: HashMap::new(),
+            transaction_index: HashMap::new(),
+            chain_work: vec![],
+            undo: HashMap::new(),
+            side_blocks: HashMap::new(),
+            orphans: HashMap::new(),
+            invalid: HashSet::new(),
         }
     }
 
-    // utxos
-    pub fn utxos(&self) -> &HashMap<Hash, (bool, TransactionOutput)> {
+    /// Restore a pruned chain from storage. Blocks and UTXOs come from
+    /// the node's own database, so they are not revalidated.
+    pub fn restore_pruned(
+        base: ChainBase,
+        blocks: Vec<Block>,
+        utxos: HashMap<Hash, (bool, u64, TransactionOutput)>,
+        target: U256,
+    ) -> Self {
+        let mut blockchain = Self {
+            utxo_hash: utxo_set_hash(&utxos),
+            utxos,
+            target,
+            blocks,
+            mempool: vec![],
+            reservations: HashMap::new(),
+            base: Some(base),
+            backfill: vec![],
+            backfill_pending: HashMap::new(),
+            params: ChainParams::default(),
+            full_verification: false,
+            max_mempool_age: crate::MAX_MEMPOOL_TRANSACTION_AGE,
+            signed_checkpoints: vec![],
+            block_index: HashMap::new(),
+            transaction_index: HashMap::new(),
+            chain_work: vec![],
+            undo: HashMap::new(),
+            side_blocks: HashMap::new(),
+            orphans: HashMap::new(),
+            invalid: HashSet::new(),
+        };
+        blockchain.reindex();
+        blockchain.sync_reservations();
+        blockchain
+    }
+
+    /// Start a chain from a snapshot. The snapshot signature must be
+    /// checked by the caller.
+    pub fn from_snapshot(snapshot: Snapshot) -> Self {
+        let utxos: HashMap<_, _> = snapshot
+            .utxos
+            .into_iter()
+            .map(|(hash, height, output)| (hash, (false, height, output)))
+            .collect();
+        let mut blockchain = Self {
+            utxo_hash: utxo_set_hash(&utxos),
+            utxos,
+            target: snapshot.base.target,
+            blocks: vec![],
+            mempool: vec![],
+            reservations: HashMap::new(),
+            base: Some(snapshot.base),
+            backfill: vec![],
+            backfill_pending: HashMap::new(),
+            params: ChainParams::default(),
+            full_verification: false,
+            max_mempool_age: crate::MAX_MEMPOOL_TRANSACTION_AGE,
+            signed_checkpoints: vec![],
+            block_index: HashMap::new(),
+            transaction_index: HashMap::new(),
+            chain_work: vec![],
+            undo: HashMap::new(),
+            side_blocks: HashMap::new(),
+            orphans: HashMap::new(),
+            invalid: HashSet::new(),
+        };
+        blockchain.sync_reservations();
+        blockchain
+    }
+
+    pub fn params(&self) -> &ChainParams {
+        &self.params
+    }
+
+    pub fn set_params(&mut self, params: ChainParams) {
+        let signed: Vec<Checkpoint> = self
+            .signed_checkpoints
+            .iter()
+            .map(|signed| signed.checkpoint)
+            .filter(|checkpoint| params.checkpoint_at(checkpoint.height).is_none())
+            .collect();
+        self.params = params.with_checkpoints(signed);
+    }
+
+    /// Take a checkpoint signed with the network's checkpoint key,
+    /// returning whether it is new. A chain already contradicting it
+    /// stays as it is, but no block contradicting it is added.
+    pub fn add_signed_checkpoint(&mut self, signed: SignedCheckpoint) -> Result<bool> {
+        let trusted = self.params.checkpoint_key.as_ref().is_some_and(|key| signed.verify(key));
+        if !trusted {
+            return Err(BtcError::UntrustedCheckpoint);
+        }
+        let checkpoint = signed.checkpoint;
+        match self.params.checkpoint_at(checkpoint.height) {
+            Some(hash) if hash == checkpoint.hash => return Ok(false),
+            Some(hash) => {
+                warn!("Checkpoint {} contradicts checkpoint {}", checkpoint, hash);
+                return Err(BtcError::CheckpointMismatch);
+            }
+            None => {}
+        }
+        if let Some(block) = self.block_at(checkpoint.height)
+            && block.hash() != checkpoint.hash
+        {
+            warn!("The chain contradicts checkpoint {}, it has to be synced again", checkpoint);
+        }
+        self.params.checkpoints.push(checkpoint);
+        self.signed_checkpoints.push(signed);
+        Ok(true)
+    }
+
+    /// Checkpoints taken with `add_signed_checkpoint`, to pass on
+    pub fn signed_checkpoints(&self) -> &[SignedCheckpoint] {
+        &self.signed_checkpoints
+    }
+
+    /// Verify every signature, including those in blocks below the
+    /// last checkpoint
+    pub fn set_full_verification(&mut self, full_verification: bool) {
+        self.full_verification = full_verification;
+    }
+
+    /// Seconds a transaction may wait in the mempool before cleanups
+    /// drop it, `MAX_MEMPOOL_TRANSACTION_AGE` unless set
+    pub fn max_mempool_age(&self) -> u64 {
+        self.max_mempool_age
+    }
+
+    /// Keep mempool transactions for this many seconds. Reservations
+    /// are made again to expire accordingly.
+    pub fn set_max_mempool_age(&mut self, seconds: u64) {
+        self.max_mempool_age = seconds;
+        self.sync_reservations();
+    }
+
+    /// Whether a transaction that entered the mempool at `added` is
+    /// too old to keep at `now`
+    pub fn mempool_expired(&self, added: DateTime<Utc>, now: DateTime<Utc>) -> bool {
+        now - added > chrono::Duration::seconds(self.max_mempool_age as i64)
+    }
+
+    // reject blocks that contradict a checkpoint at their height
+    fn check_checkpoint(&self, height: u64, block: &Block) -> Result<()> {
+        match self.params.checkpoint_at(height) {
+            Some(hash) if hash != block.hash() => {
+                warn!("Block at height {} does not match checkpoint {}", height, hash);
+                Err(BtcError::CheckpointMismatch)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // utxos with their mempool marks and the height of the block
+    // that created them
+    pub fn utxos(&self) -> &HashMap<Hash, (bool, u64, TransactionOutput)> {
         &self.utxos
     }
+
+    /// Number of confirmations of the block at `height`: 1 for the
+    /// tip, 0 if the chain doesn't reach it yet
+    pub fn confirmations(&self, height: u64) -> u64 {
+        self.block_height().saturating_sub(height)
+    }
+
+    /// Number of confirmations of an unspent output, None if it is
+    /// not in the UTXO set
+    pub fn utxo_confirmations(&self, hash: &Hash) -> Option<u64> {
+        let (_, height, _) = self.utxos.get(hash)?;
+        Some(self.confirmations(*height))
+    }
+
+    /// Whether each address was ever paid by an output of the stored
+    /// blocks, the UTXO set or the mempool, whatever format it is in
+    pub fn addresses_used(&self, addresses: &[String]) -> Vec<bool> {
+        let parsed: Vec<Option<Address>> = addresses.iter().map(|address| Address::parse(address).ok()).collect();
+        let mut used = vec![false; addresses.len()];
+        let mut mark = |output: &TransactionOutput| {
+            let paid = Address::parse(&output.address).ok();
+            for (index, address) in addresses.iter().enumerate() {
+                if *address == output.address || (paid.is_some() && parsed[index] == paid) {
+                    used[index] = true;
+                }
+            }
+        };
+        // outputs of pruned blocks only survive in the UTXO set
+        let confirmed = self.blocks.iter().flat_map(|block| &block.transactions);
+        let pending = self.mempool.iter().map(|(_, transaction)| transaction);
+        confirmed.chain(pending).flat_map(|transaction| &transaction.outputs).for_each(&mut mark);
+        self.utxos.values().for_each(|(_, _, output)| mark(output));
+        used
+    }
     // target
     pub fn target(&self) -> U256 {
         self.target
     }
-    // blocks
+    // blocks stored locally, starting at base_height()
     pub fn blocks(&self) -> impl Iterator<Item = &Block> {
         self.blocks.iter()
     }
 
     pub fn block_height(&self) -> u64 {
-        self.blocks.len() as u64
+        self.base_height() + self.blocks.len() as u64
+    }
+
+    // snapshot the chain was started from, if still backfilling
+    pub fn base(&self) -> Option<&ChainBase> {
+        self.base.as_ref()
+    }
+
+    // height of the first locally stored block
+    pub fn base_height(&self) -> u64 {
+        self.base.as_ref().map_or(0, |base| base.height)
+    }
+
+    pub fn block_at(&self, height: u64) -> Option<&Block> {
+        let index = height.checked_sub(self.base_height())?;
+        self.blocks.get(index as usize)
+    }
+
+    /// Locally stored block with the given hash
+    pub fn block_by_hash(&self, hash: &Hash) -> Option<&Block> {
+        self.block_at(self.height_of(hash)?)
+    }
+
+    /// Height of the locally stored block with the given hash
+    pub fn height_of(&self, hash: &Hash) -> Option<u64> {
+        self.block_index.get(hash).copied()
+    }
+
+    /// Height of the block holding the transaction with the given
+    /// hash, and the transaction's position within it
+    pub fn transaction_by_id(&self, id: &Hash) -> Option<(u64, usize)> {
+        self.transaction_index.get(id).copied()
+    }
+
+    fn index_block(&mut self, height: u64, block: &Block) {
+        self.block_index.insert(block.hash(), height);
+        for (position, transaction) in block.transactions.iter().enumerate() {
+            self.transaction_index
+                .insert(transaction.hash(), (height, position));
+        }
+    }
+
+    // rebuild the lookup indexes and the chain work from the stored
+    // blocks
+    fn reindex(&mut self) {
+        self.block_index.clear();
+        self.transaction_index.clear();
+        self.chain_work.clear();
+        let mut work = self.base.as_ref().map_or(U256::zero(), |base| base.chain_work);
+        let blocks = std::mem::take(&mut self.blocks);
+        for (offset, block) in blocks.iter().enumerate() {
+            self.index_block(self.base_height() + offset as u64, block);
+            work += work_from_target(block.header.target);
+            self.chain_work.push(work);
+        }
+        self.blocks = blocks;
+    }
+
+    /// Work of the main chain up to and including the block at
+    /// `height`, if the chain still knows it
+    pub fn chain_work_at(&self, height: u64) -> Option<U256> {
+        match height.checked_sub(self.base_height()) {
+            Some(index) => self.chain_work.get(index as usize).copied(),
+            None => self.base.as_ref().filter(|base| height + 1 == base.height).map(|base| base.chain_work),
+        }
+    }
+
+    /// Work of the main chain: the hashes it took to mine, on average.
+    /// Of two chains the one with more is followed. Counted from the
+    /// base for chains started from a snapshot made before it was kept.
+    pub fn chain_work(&self) -> U256 {
+        match self.chain_work.last() {
+            Some(work) => *work,
+            None => self.base.as_ref().map_or(U256::zero(), |base| base.chain_work),
+        }
+    }
+
+    /// Blocks of branches off the main chain, which may overtake it
+    pub fn side_blocks(&self) -> impl Iterator<Item = &SideBlock> {
+        self.side_blocks.values()
+    }
+
+    /// Whether a block or one it builds on failed validation
+    pub fn is_invalid(&self, hash: &Hash) -> bool {
+        self.invalid.contains(hash)
+    }
+
+    /// The main chain's tip and the last block of every branch off it,
+    /// the main chain's first
+    pub fn chain_tips(&self) -> Vec<ChainTip> {
+        let mut tips = vec![];
+        if let Some(hash) = self.tip_hash() {
+            tips.push(ChainTip {
+                hash,
+                height: self.block_height() - 1,
+                branch_length: 0,
+                chain_work: self.chain_work(),
+                status: TipStatus::Active,
+            });
+        }
+        let parents: HashSet<Hash> = self.side_blocks.values().map(|side| side.block.header.prev_block_hash).collect();
+        let mut branches: Vec<ChainTip> = self
+            .side_blocks
+            .iter()
+            .filter(|(hash, _)| !parents.contains(*hash))
+            .map(|(hash, side)| {
+                let mut branch_length = 0;
+                let mut cursor = *hash;
+                while let Some(side) = self.side_blocks.get(&cursor) {
+                    branch_length += 1;
+                    cursor = side.block.header.prev_block_hash;
+                }
+                ChainTip {
+                    hash: *hash,
+                    height: side.height,
+                    branch_length,
+                    chain_work: side.chain_work,
+                    status: if self.invalid.contains(hash) { TipStatus::Invalid } else { TipStatus::ValidFork },
+                }
+            })
+            .collect();
+        branches.sort_by_cached_key(|tip| (std::cmp::Reverse(tip.height), tip.hash.to_string()));
+        tips.extend(branches);
+        tips
+    }
+
+    // hash of the last block, None for an empty chain
+    pub fn tip_hash(&self) -> Option<Hash> {
+        match self.blocks.last() {
+            Some(block) => Some(block.hash()),
+            None => self.base.as_ref().map(|base| base.block_hash),
+        }
+    }
+
+    // timestamp of the block at the given height, if known
+    pub fn timestamp_at(&self, height: u64) -> Option<DateTime<Utc>> {
+        if let Some(block) = self.block_at(height) {
+            return Some(block.header.timestamp);
+        }
+        let base = self.base.as_ref()?;
+        let offset = base.height.checked_sub(height)?;
+        let index = base.timestamps.len().checked_sub(offset as usize)?;
+        base.timestamps.get(index).copied()
+    }
+
+    // height of the next block to backfill, None when complete
+    pub fn next_backfill_height(&self) -> Option<u64> {
+        let base = self.base.as_ref().filter(|base| !base.pruned)?;
+        Some(base.height - self.backfill.len() as u64 - 1)
+    }
+
+    /// Offer a block below the snapshot base. Blocks are linked
+    /// backwards from the base hash, so they are trusted through the
+    /// hash chain rather than re-validated. Returns true once the
+    /// chain reaches genesis and the base is dropped.
+    #[instrument(skip(self, block))]
+    pub fn backfill_block(&mut self, block: Block) -> Result<bool> {
+        let Some(base) = self.base.as_ref().filter(|base| !base.pruned) else {
+            return Err(BtcError::InvalidBlock);
+        };
+        if MerkleRoot::calculate(&block.transactions) != block.header.merkle_root {
+            warn!("Backfill block merkle root does not match its header");
+            return Err(BtcError::InvalidMerkleRoot);
+        }
+        if self.backfill_pending.len() >= MAX_BACKFILL_PENDING {
+            self.backfill_pending.clear();
+        }
+        self.backfill_pending.insert(block.hash(), block);
+
+        let height = base.height;
+        loop {
+            let expected = match self.backfill.last() {
+                Some(block) => block.header.prev_block_hash,
+                None => base.block_hash,
+            };
+            match self.backfill_pending.remove(&expected) {
+                Some(block) => {
+                    let block_height = height - self.backfill.len() as u64 - 1;
+                    if let Err(e) = self.check_checkpoint(block_height, &block) {
+                        self.backfill.clear();
+                        return Err(e);
+                    }
+                    self.backfill.push(block)
+                }
+                None => break,
+            }
+        }
+
+        if (self.backfill.len() as u64) < height {
+            return Ok(false);
+        }
+        if self.backfill.last().map(|genesis| genesis.header.prev_block_hash)
+            != Some(Hash::zero())
+        {
+            warn!("Backfilled chain does not end in a genesis block");
+            self.backfill.clear();
+            return Err(BtcError::InvalidBlock);
+        }
+
+        let mut blocks: Vec<Block> = self.backfill.drain(..).rev().collect();
+        blocks.append(&mut self.blocks);
+        self.blocks = blocks;
+        self.base = None;
+        self.backfill_pending.clear();
+        self.reindex();
+        info!("backfill complete, chain is now anchored at genesis");
+        Ok(true)
     }
 
     pub fn mempool(&self) -> &[(DateTime<Utc>, Transaction)] {
         &self.mempool
     }
 
-    #[instrument(skip(self, block))]
+    fn mempool_transaction(&self, hash: &Hash) -> Option<&Transaction> {
+        self.mempool
+            .iter()
+            .map(|(_, transaction)| transaction)
+            .find(|transaction| transaction.hash() == *hash)
+    }
+
+    // mempool transaction creating the given output
+    fn mempool_creator(&self, output_hash: &Hash) -> Option<(&Transaction, &TransactionOutput)> {
+        self.mempool.iter().find_map(|(_, transaction)| {
+            let output = transaction
+                .outputs
+                .iter()
+                .find(|output| output.hash() == *output_hash)?;
+            Some((transaction, output))
+        })
+    }
+
+    // mempool transaction spending the given output
+    fn mempool_spender(&self, output_hash: &Hash) -> Option<&Transaction> {
+        self.mempool
+            .iter()
+            .map(|(_, transaction)| transaction)
+            .find(|transaction| {
+                transaction
+                    .inputs
+                    .iter()
+                    .any(|input| input.prev_transaction_output_hash == *output_hash)
+            })
+    }
+
+    // an output mempool transactions may spend: unspent on chain, or
+    // created by a transaction still in the mempool
+    fn spendable_output(&self, output_hash: &Hash) -> Option<&TransactionOutput> {
+        match self.utxos.get(output_hash) {
+            Some((_, _, output)) => Some(output),
+            None => self.mempool_creator(output_hash).map(|(_, output)| output),
+        }
+    }
+
+    /// Fee paid by a transaction spending unspent outputs or outputs
+    /// of mempool transactions, None if an input is unknown
+    pub fn transaction_fee(&self, transaction: &Transaction) -> Option<u64> {
+        let inputs = transaction
+            .inputs
+            .iter()
+            .map(|input| {
+                self.spendable_output(&input.prev_transaction_output_hash)
+                    .map(|output| output.value)
+            })
+            .sum::<Option<u64>>()?;
+        let outputs: u64 = transaction.outputs.iter().map(|output| output.value).sum();
+        inputs.checked_sub(outputs)
+    }
+
+    /// Unconfirmed transactions `transaction` spends outputs of,
+    /// directly or through other mempool transactions, parents first
+    pub fn mempool_ancestors(&self, transaction: &Transaction) -> Vec<&Transaction> {
+        let mut ancestors = vec![];
+        self.collect_ancestors(transaction, &mut HashSet::new(), &mut ancestors);
+        ancestors
+    }
+
+    fn collect_ancestors<'a>(
+        &'a self,
+        transaction: &Transaction,
+        seen: &mut HashSet<Hash>,
+        ancestors: &mut Vec<&'a Transaction>,
+    ) {
+        for input in &transaction.inputs {
+            let Some((parent, _)) = self.mempool_creator(&input.prev_transaction_output_hash)
+            else {
+                continue;
+            };
+            if seen.insert(parent.hash()) {
+                self.collect_ancestors(parent, seen, ancestors);
+                ancestors.push(parent);
+            }
+        }
+    }
+
+    /// Hashes of the mempool transactions spending outputs of the
+    /// given one, directly or through other mempool transactions
+    pub fn mempool_descendants(&self, hash: &Hash) -> Vec<Hash> {
+        let mut descendants = vec![];
+        let mut pending = vec![*hash];
+        while let Some(hash) = pending.pop() {
+            let Some(parent) = self.mempool_transaction(&hash) else {
+                continue;
+            };
+            let outputs: HashSet<Hash> = parent.outputs.iter().map(|output| output.hash()).collect();
+            for (_, transaction) in &self.mempool {
+                let spends_parent = transaction
+                    .inputs
+                    .iter()
+                    .any(|input| outputs.contains(&input.prev_transaction_output_hash));
+                let child = transaction.hash();
+                if spends_parent && !descendants.contains(&child) {
+                    descendants.push(child);
+                    pending.push(child);
+                }
+            }
+        }
+        descendants
+    }
+
+    // total fee and size of a set of mempool transactions
+    fn package_fee_and_size<'a>(
+        &self,
+        package: impl IntoIterator<Item = &'a Transaction>,
+    ) -> (u64, usize) {
+        package.into_iter().fold((0, 0), |(fee, size), transaction| {
+            (
+                fee + self.transaction_fee(transaction).unwrap_or(0),
+                size + transaction.size(),
+            )
+        })
+    }
+
+    /// Fee and size of a mempool transaction together with its
+    /// unconfirmed ancestors, which a miner has to include with it.
+    /// Their ratio is the ancestor fee rate.
+    pub fn ancestor_package(&self, transaction: &Transaction) -> (u64, usize) {
+        let ancestors = self.mempool_ancestors(transaction);
+        self.package_fee_and_size(ancestors.into_iter().chain(std::iter::once(transaction)))
+    }
+
+    /// Pick up to `cap` mempool transactions for a block template,
+    /// highest ancestor fee rate first, so a well paying child pulls
+    /// in its parents. Parents are placed before their children.
+    pub fn template_transactions(&self, cap: usize) -> Vec<Transaction> {
+        let mut selected: Vec<&Transaction> = vec![];
+        let mut included = HashSet::new();
+        loop {
+            // each remaining transaction with its ancestors not picked yet
+            let best = self
+                .mempool
+                .iter()
+                .map(|(_, transaction)| transaction)
+                .filter(|transaction| !included.contains(&transaction.hash()))
+                .map(|transaction| {
+                    let package: Vec<&Transaction> = self
+                        .mempool_ancestors(transaction)
+                        .into_iter()
+                        .filter(|ancestor| !included.contains(&ancestor.hash()))
+                        .chain(std::iter::once(transaction))
+                        .collect();
+                    let (fee, size) = self.package_fee_and_size(package.iter().copied());
+                    (package, fee, size)
+                })
+                .filter(|(package, _, _)| selected.len() + package.len() <= cap)
+                // compare fee rates without dividing
+                .max_by(|(_, fee_a, size_a), (_, fee_b, size_b)| {
+                    (*fee_a as u128 * *size_b as u128).cmp(&(*fee_b as u128 * *size_a as u128))
+                });
+            let Some((package, _, _)) = best else {
+                break;
+            };
+            for transaction in package {
+                included.insert(transaction.hash());
+                selected.push(transaction);
+            }
+        }
+        selected.into_iter().cloned().collect()
+    }
+
+    // drop a mempool transaction and everything spending its outputs,
+    // releasing the unspent outputs they had reserved
+    fn evict_from_mempool(&mut self, hash: &Hash) {
+        let mut evicted: HashSet<Hash> = self.mempool_descendants(hash).into_iter().collect();
+        evicted.insert(*hash);
+        let mut released = vec![];
+        self.mempool.retain(|(_, transaction)| {
+            if !evicted.contains(&transaction.hash()) {
+                return true;
+            }
+            released.push(transaction.clone());
+            false
+        });
+        for transaction in &released {
+            self.release(transaction);
+        }
+    }
+
+    /// Unspent outputs spent by mempool transactions, by output hash
+    pub fn reservations(&self) -> &HashMap<Hash, Reservation> {
+        &self.reservations
+    }
+
+    // reserve the unspent outputs a transaction entering the mempool
+    // at `added` spends
+    fn reserve(&mut self, transaction: &Transaction, added: DateTime<Utc>) {
+        let reservation = Reservation {
+            txid: transaction.hash(),
+            expires: added + chrono::Duration::seconds(self.max_mempool_age as i64),
+        };
+        for input in &transaction.inputs {
+            if let Some((marked, _, _)) = self.utxos.get_mut(&input.prev_transaction_output_hash) {
+                *marked = true;
+                self.reservations
+                    .insert(input.prev_transaction_output_hash, reservation);
+            }
+        }
+    }
+
+    // release the outputs a transaction leaving the mempool reserved
+    fn release(&mut self, transaction: &Transaction) {
+        let txid = transaction.hash();
+        for input in &transaction.inputs {
+            let outpoint = input.prev_transaction_output_hash;
+            if self.reservations.get(&outpoint).is_some_and(|reservation| reservation.txid == txid) {
+                self.reservations.remove(&outpoint);
+                if let Some((marked, _, _)) = self.utxos.get_mut(&outpoint) {
+                    *marked = false;
+                }
+            }
+        }
+    }
+
+    // bring the mempool and the reservations in line with a changed
+    // UTXO set: drop transactions whose inputs are gone and reserve
+    // again, from scratch, what the rest spend
+    fn sync_reservations(&mut self) {
+        let conflicting: Vec<Hash> = self
+            .mempool
+            .iter()
+            .filter(|(_, transaction)| {
+                transaction
+                    .inputs
+                    .iter()
+                    .any(|input| self.spendable_output(&input.prev_transaction_output_hash).is_none())
+            })
+            .map(|(_, transaction)| transaction.hash())
+            .collect();
+        for hash in conflicting {
+            if self.mempool_transaction(&hash).is_some() {
+                warn!("Dropping mempool transaction {} that spends spent outputs", hash);
+                self.evict_from_mempool(&hash);
+            }
+        }
+        self.reservations.clear();
+        for (marked, _, _) in self.utxos.values_mut() {
+            *marked = false;
+        }
+        let mempool = self.mempool.clone();
+        for (added, transaction) in &mempool {
+            self.reserve(transaction, *added);
+        }
+        debug_assert_eq!(self.check_reservations(), Ok(()));
+    }
+
+    /// Check that the marks in the UTXO set and the reservations agree
+    /// with the mempool: an output is marked exactly when it is
+    /// reserved, by a mempool transaction spending it, and every
+    /// unspent output a mempool transaction spends is reserved.
+    /// Checked after every change in debug builds.
+    pub fn check_reservations(&self) -> std::result::Result<(), String> {
+        for (outpoint, (marked, _, _)) in &self.utxos {
+            if *marked != self.reservations.contains_key(outpoint) {
+                return Err(format!("{} is marked={} but reserved={}", outpoint, marked, !marked));
+            }
+        }
+        for (outpoint, reservation) in &self.reservations {
+            if !self.utxos.contains_key(outpoint) {
+                return Err(format!("{} is reserved but not unspent", outpoint));
+            }
+            let spends = self.mempool_transaction(&reservation.txid).is_some_and(|transaction| {
+                transaction
+                    .inputs
+                    .iter()
+                    .any(|input| input.prev_transaction_output_hash == *outpoint)
+            });
+            if !spends {
+                return Err(format!(
+                    "{} is reserved by {}, which is not a mempool transaction spending it",
+                    outpoint, reservation.txid
+                ));
+            }
+        }
+        for (_, transaction) in &self.mempool {
+            for input in &transaction.inputs {
+                let outpoint = input.prev_transaction_output_hash;
+                if self.utxos.contains_key(&outpoint) && !self.reservations.contains_key(&outpoint) {
+                    return Err(format!("{} spent by {} is not reserved", outpoint, transaction.hash()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop the blocks below `height`, keeping the UTXO set. The chain
+    /// can no longer serve those blocks and will not backfill them.
+    #[instrument(skip(self))]
+    pub fn prune(&mut self, height: u64) -> Result<()> {
+        if height <= self.base_height() {
+            return Ok(());
+        }
+        // the tip is always kept
+        let (Some(last_pruned), Some(first_kept)) =
+            (self.block_at(height - 1), self.block_at(height))
+        else {
+            return Err(BtcError::InvalidBlock);
+        };
+        let block_hash = last_pruned.hash();
+        let target = first_kept.header.target;
+        let window = (crate::DIFFICULTY_UPDATE_INTERVAL + crate::MEDIAN_TIME_SPAN).min(height);
+        let timestamps = (height - window..height)
+            .map(|h| self.timestamp_at(h))
+            .collect::<Option<Vec<_>>>()
+            .ok_or(BtcError::InvalidBlock)?;
+
+        let chain_work = self.chain_work_at(height - 1).ok_or(BtcError::InvalidBlock)?;
+
+        let pruned = (height - self.base_height()) as usize;
+        for block in self.blocks.drain(..pruned) {
+            let hash = block.hash();
+            self.block_index.remove(&hash);
+            self.undo.remove(&hash);
+            for transaction in &block.transactions {
+                self.transaction_index.remove(&transaction.hash());
+            }
+        }
+        self.chain_work.drain(..pruned);
+        // branches forking below the base can't be switched to anymore
+        self.side_blocks.retain(|_, side| side.height > height);
+        self.base = Some(ChainBase {
+            height,
+            block_hash,
+            target,
+            timestamps,
+            pruned: true,
+            chain_work,
+        });
+        self.backfill.clear();
+        self.backfill_pending.clear();
+        Ok(())
+    }
+
+    /// Add a block like `submit_block`, without telling how the main
+    /// chain changed
     pub fn add_block(&mut self, block: Block) -> Result<()> {
-        if self.blocks.is_empty() {
-            // Genesis block validation
-            if block.header.prev_block_hash != Hash::zero() {
-                warn!("Genesis block must have a hash of 0");
-                return Err(BtcError::InvalidBlock);
+        self.submit_block(block).map(|_| ())
+    }
+
+    /// Add a block to the main chain, or to a branch off it. A branch
+    /// with more work than the main chain replaces it. A block whose
+    /// parent is unknown waits for it, failing with `UnknownParent`.
+    #[instrument(skip(self, block))]
+    pub fn submit_block(&mut self, block: Block) -> Result<ChainUpdate> {
+        let mut update = ChainUpdate {
+            fork_height: self.block_height(),
+            disconnected: vec![],
+        };
+        let hash = block.hash();
+        let result = self.store_block(block, &mut update);
+        if result.is_ok() {
+            // orphans waiting for the block, then for those
+            let mut parents = vec![hash];
+            while let Some(parent) = parents.pop() {
+                for orphan in self.orphans.remove(&parent).unwrap_or_default() {
+                    let hash = orphan.hash();
+                    match self.store_block(orphan, &mut update) {
+                        Ok(()) => parents.push(hash),
+                        Err(e) => warn!("Orphan block {} rejected: {}", hash, e),
+                    }
+                }
             }
+        }
+        self.finish_update(&mut update);
+        result.map(|()| update)
+    }
+
+    /// Mark a block and every block built on it invalid, switching the
+    /// main chain to the valid branch with the most work if it held
+    /// the block. Blocks invalidated this way stay so until
+    /// `reconsider_block`.
+    #[instrument(skip(self))]
+    pub fn invalidate_block(&mut self, hash: &Hash) -> Result<ChainUpdate> {
+        let mut update = ChainUpdate {
+            fork_height: self.block_height(),
+            disconnected: vec![],
+        };
+        if let Some(height) = self.height_of(hash) {
+            let undoable = height > 0
+                && height >= self.base_height()
+                && self
+                    .blocks()
+                    .skip((height - self.base_height()) as usize)
+                    .all(|block| self.undo.contains_key(&block.hash()));
+            if !undoable {
+                return Err(BtcError::IrreversibleBlock);
+            }
+            for side in self.disconnect_to(height) {
+                update.disconnected.push(side.block.clone());
+                self.side_blocks.insert(side.block.hash(), side);
+            }
+            update.fork_height = height;
+        } else if !self.side_blocks.contains_key(hash) {
+            return Err(BtcError::UnknownBlock);
+        }
+        info!("Block {} invalidated", hash);
+        self.mark_invalid(*hash);
+        self.activate_best_branch(&mut update);
+        self.finish_update(&mut update);
+        Ok(update)
+    }
+
+    /// Take back the invalid marks of a block, of the blocks it builds
+    /// on and of those built on it, switching to its branch if it has
+    /// the most work. A block that fails validation again is marked
+    /// invalid again.
+    #[instrument(skip(self))]
+    pub fn reconsider_block(&mut self, hash: &Hash) -> Result<ChainUpdate> {
+        if !self.invalid.contains(hash) && !self.side_blocks.contains_key(hash) && self.height_of(hash).is_none() {
+            return Err(BtcError::UnknownBlock);
+        }
+        let mut update = ChainUpdate {
+            fork_height: self.block_height(),
+            disconnected: vec![],
+        };
+        let mut cursor = *hash;
+        while let Some(side) = self.side_blocks.get(&cursor) {
+            self.invalid.remove(&cursor);
+            cursor = side.block.header.prev_block_hash;
+        }
+        self.invalid.remove(hash);
+        let descendants: Vec<Hash> = self
+            .side_blocks
+            .keys()
+            .filter(|candidate| {
+                let mut cursor = **candidate;
+                while let Some(side) = self.side_blocks.get(&cursor) {
+                    if cursor == *hash {
+                        return true;
+                    }
+                    cursor = side.block.header.prev_block_hash;
+                }
+                false
+            })
+            .copied()
+            .collect();
+        for descendant in descendants {
+            self.invalid.remove(&descendant);
+        }
+        info!("Block {} reconsidered", hash);
+        self.activate_best_branch(&mut update);
+        self.finish_update(&mut update);
+        Ok(update)
+    }
+
+    // switch to the valid branch with the most work while it has more
+    // than the main chain, falling back to the next if one turns out
+    // invalid
+    fn activate_best_branch(&mut self, update: &mut ChainUpdate) {
+        // a branch forking too deep to switch to stays a branch
+        let mut tried = HashSet::new();
+        loop {
+            let best = self
+                .side_blocks
+                .iter()
+                .filter(|(hash, side)| {
+                    !self.invalid.contains(*hash) && !tried.contains(*hash) && side.chain_work > self.chain_work()
+                })
+                .max_by_key(|(_, side)| side.chain_work)
+                .map(|(hash, _)| *hash);
+            let Some(best) = best else {
+                return;
+            };
+            tried.insert(best);
+            if let Err(e) = self.reorganize(best, update) {
+                warn!("Branch to {} couldn't be switched to: {}", best, e);
+            }
+        }
+    }
+
+    // after the main chain changed, bring the reservations up to date
+    // and put the transactions of the blocks taken off it back in the
+    // mempool
+    fn finish_update(&mut self, update: &mut ChainUpdate) {
+        // a failed reorg puts the old blocks back
+        update.disconnected.retain(|block| !self.block_index.contains_key(&block.hash()));
+        update.fork_height = update.fork_height.min(self.block_height());
+        if update.fork_height < self.block_height() || !update.disconnected.is_empty() {
+            self.sync_reservations();
+        }
+        // transactions of the replaced blocks may be mined again
+        for block in &update.disconnected {
+            for transaction in block.transactions.iter().skip(1) {
+                if self.transaction_index.contains_key(&transaction.hash()) {
+                    continue;
+                }
+                if let Err(e) = self.add_to_mempool(transaction.clone()) {
+                    info!("Transaction {} of a replaced block dropped: {}", transaction.hash(), e);
+                }
+            }
+        }
+    }
+
+    // put a block on the main chain or on a branch, switching to the
+    // branch if it gets the most work
+    fn store_block(&mut self, block: Block, update: &mut ChainUpdate) -> Result<()> {
+        let hash = block.hash();
+        if self.block_index.contains_key(&hash) || self.side_blocks.contains_key(&hash) {
+            return Err(BtcError::KnownBlock);
+        }
+        let prev = block.header.prev_block_hash;
+        if self.invalid.contains(&hash) || self.invalid.contains(&prev) {
+            warn!("Block {} builds on an invalid block", hash);
+            self.invalid.insert(hash);
+            return Err(BtcError::InvalidBlock);
+        }
+        if self.tip_hash().is_none_or(|tip| tip == prev) {
+            return self.connect_block(block);
+        }
+
+        let (parent_height, parent_work, parent_time) = match self.height_of(&prev) {
+            Some(height) => (
+                height,
+                self.chain_work_at(height).ok_or(BtcError::InvalidBlock)?,
+                self.timestamp_at(height).ok_or(BtcError::InvalidBlock)?,
+            ),
+            None if self.base.as_ref().is_some_and(|base| base.block_hash == prev) => {
+                let height = self.base_height() - 1;
+                (
+                    height,
+                    self.chain_work_at(height).ok_or(BtcError::InvalidBlock)?,
+                    self.timestamp_at(height).ok_or(BtcError::InvalidBlock)?,
+                )
+            }
+            None => match self.side_blocks.get(&prev) {
+                Some(side) => (side.height, side.chain_work, side.block.header.timestamp),
+                None => {
+                    self.add_orphan(block);
+                    return Err(BtcError::UnknownParent);
+                }
+            },
+        };
+        let height = parent_height + 1;
+        self.check_checkpoint(height, &block)?;
+        if self.branch_target(prev, height) != Some(block.header.target) {
+            warn!("Block target does not match the target of its branch");
+            return Err(BtcError::WrongTarget);
+        }
+        if !block.header.hash().matches_target(block.header.target) {
+            warn!("Block hash does not match the target");
+            return Err(BtcError::InvalidBlock);
+        }
+        if MerkleRoot::calculate(&block.transactions) != block.header.merkle_root {
+            warn!("Calculated merkle root does not match the block header merkle root");
+            return Err(BtcError::InvalidMerkleRoot);
+        }
+        if block.header.timestamp <= parent_time {
+            warn!("Timestamp is not greater than the parent block timestamp");
+            return Err(BtcError::InvalidBlock);
+        }
+        // transactions are checked once the branch is switched to
+        let chain_work = parent_work + work_from_target(block.header.target);
+        self.side_blocks.insert(hash, SideBlock { block, height, chain_work });
+        if chain_work > self.chain_work() {
+            self.reorganize(hash, update)?;
         } else {
-            let last_block = self.blocks.last().unwrap();
-            if block.header.prev_block_hash != last_block.hash() {
+            info!("Block {} at height {} went on a branch with less work", hash, height);
+        }
+        Ok(())
+    }
+
+    // keep a block until its parent arrives
+    fn add_orphan(&mut self, block: Block) {
+        if self.orphans.values().map(Vec::len).sum::<usize>() >= MAX_ORPHANS {
+            self.orphans.clear();
+        }
+        let waiting = self.orphans.entry(block.header.prev_block_hash).or_default();
+        if !waiting.iter().any(|orphan| orphan.hash() == block.hash()) {
+            waiting.push(block);
+        }
+    }
+
+    // switch the main chain to the branch ending in `tip`, putting the
+    // old chain back if a block of the branch turns out invalid
+    fn reorganize(&mut self, tip: Hash, update: &mut ChainUpdate) -> Result<()> {
+        let mut branch = vec![];
+        let mut cursor = tip;
+        while let Some(side) = self.side_blocks.get(&cursor) {
+            branch.push(cursor);
+            cursor = side.block.header.prev_block_hash;
+        }
+        branch.reverse();
+        let fork_height = self.side_blocks[&branch[0]].height;
+        let undoable = fork_height >= self.base_height()
+            && self
+                .blocks()
+                .skip((fork_height - self.base_height()) as usize)
+                .all(|block| self.undo.contains_key(&block.hash()));
+        if !undoable {
+            warn!("Branch to {} forks at height {}, below what the chain can take off", tip, fork_height);
+            return Ok(());
+        }
+
+        let old_tip = self.tip_hash();
+        let disconnected = self.disconnect_to(fork_height);
+        for hash in &branch {
+            let side = self.side_blocks.remove(hash).expect("BUG: branch block vanished");
+            if let Err(e) = self.connect_block(side.block.clone()) {
+                warn!("Block {} of the branch to {} is invalid: {}", hash, tip, e);
+                self.side_blocks.insert(*hash, side);
+                self.mark_invalid(*hash);
+                let rolled_back = self.disconnect_to(fork_height);
+                for side in disconnected {
+                    self.connect_block(side.block).expect("BUG: the old main chain was valid");
+                }
+                for side in rolled_back {
+                    for transaction in side.block.transactions.iter().skip(1) {
+                        let _ = self.add_to_mempool(transaction.clone());
+                    }
+                    self.side_blocks.insert(side.block.hash(), side);
+                }
+                return Err(e);
+            }
+        }
+        info!(
+            "Reorganized from {} to {}, replacing {} blocks from height {}",
+            old_tip.map(|hash| hash.to_string()).unwrap_or_default(),
+            tip,
+            disconnected.len(),
+            fork_height
+        );
+        update.fork_height = update.fork_height.min(fork_height);
+        for side in disconnected {
+            update.disconnected.push(side.block.clone());
+            self.side_blocks.insert(side.block.hash(), side);
+        }
+        Ok(())
+    }
+
+    // take blocks off the tip down to `height`, undoing what they did
+    // to the UTXO set and the target. Returns them oldest first.
+    fn disconnect_to(&mut self, height: u64) -> Vec<SideBlock> {
+        let mut disconnected = vec![];
+        while self.block_height() > height.max(self.base_height()) {
+            let block = self.blocks.pop().expect("BUG: height above the base");
+            let chain_work = self.chain_work.pop().expect("BUG: chain work of every block");
+            let hash = block.hash();
+            let undo = self.undo.remove(&hash).expect("BUG: disconnecting a block without undo data");
+            let mut spent: HashMap<_, _> = undo
+                .spent
+                .into_iter()
+                .map(|(outpoint, created, output)| (outpoint, (created, output)))
+                .collect();
+            // last transaction first, so an output spent later in the
+            // block is put back before the transaction creating it
+            // takes it out again
+            for transaction in block.transactions.iter().rev() {
+                for output in &transaction.outputs {
+                    self.remove_utxo(&output.hash());
+                }
+                for input in &transaction.inputs {
+                    let outpoint = input.prev_transaction_output_hash;
+                    if let Some((created, output)) = spent.remove(&outpoint) {
+                        self.add_utxo(outpoint, created, output);
+                    }
+                }
+                self.transaction_index.remove(&transaction.hash());
+            }
+            self.target = undo.target;
+            self.block_index.remove(&hash);
+            disconnected.push(SideBlock {
+                block,
+                height: self.block_height(),
+                chain_work,
+            });
+        }
+        disconnected.reverse();
+        disconnected
+    }
+
+    // mark a side block invalid along with every side block on it
+    fn mark_invalid(&mut self, hash: Hash) {
+        self.invalid.insert(hash);
+        loop {
+            let descendants: Vec<Hash> = self
+                .side_blocks
+                .iter()
+                .filter(|(hash, side)| {
+                    !self.invalid.contains(*hash) && self.invalid.contains(&side.block.header.prev_block_hash)
+                })
+                .map(|(hash, _)| *hash)
+                .collect();
+            if descendants.is_empty() {
+                break;
+            }
+            self.invalid.extend(descendants);
+        }
+    }
+
+    // spend the inputs and add the outputs of a block at `height`,
+    // returning the unspent outputs it spent
+    fn apply_utxos(&mut self, height: u64, block: &Block) -> Vec<(Hash, u64, TransactionOutput)> {
+        let mut spent = vec![];
+        for transaction in &block.transactions {
+            for input in &transaction.inputs {
+                if let Some((_, created, output)) = self.remove_utxo(&input.prev_transaction_output_hash) {
+                    spent.push((input.prev_transaction_output_hash, created, output));
+                }
+            }
+            for output in &transaction.outputs {
+                self.add_utxo(output.hash(), height, output.clone());
+            }
+        }
+        spent
+    }
+
+    // add an unspent output unmarked, replacing one under the same
+    // hash, and update the UTXO set hash along
+    fn add_utxo(&mut self, outpoint: Hash, height: u64, output: TransactionOutput) {
+        let element = utxo_element(&outpoint, height, &output);
+        if let Some((_, created, replaced)) = self.utxos.insert(outpoint, (false, height, output)) {
+            self.utxo_hash.remove(&utxo_element(&outpoint, created, &replaced));
+        }
+        self.utxo_hash.insert(&element);
+    }
+
+    fn remove_utxo(&mut self, outpoint: &Hash) -> Option<(bool, u64, TransactionOutput)> {
+        let removed = self.utxos.remove(outpoint)?;
+        self.utxo_hash.remove(&utxo_element(outpoint, removed.1, &removed.2));
+        Some(removed)
+    }
+
+    /// Hash of the UTXO set: every unspent output with its hash and
+    /// the height of the block that created it, whatever the mempool
+    /// spends. Nodes agreeing on the chain agree on it.
+    pub fn utxo_set_hash(&self) -> Hash {
+        self.utxo_hash.finalize()
+    }
+
+    /// The UTXO set hash computed again from every unspent output.
+    /// It differs from `utxo_set_hash`, which is updated output by
+    /// output, only if an update went wrong.
+    pub fn compute_utxo_set_hash(&self) -> Hash {
+        utxo_set_hash(&self.utxos).finalize()
+    }
+
+    // validate a block extending the tip and connect it
+    fn connect_block(&mut self, block: Block) -> Result<()> {
+        self.check_checkpoint(self.block_height(), &block)?;
+
+        if let Some(tip_hash) = self.tip_hash() {
+            if block.header.prev_block_hash != tip_hash {
                 warn!("Previous block hash does not match the last block hash");
                 return Err(BtcError::InvalidBlock);
             }
 
+            if block.header.target != self.target {
+                warn!("Block target does not match the chain's target");
+                return Err(BtcError::WrongTarget);
+            }
+
             if !block.header.hash().matches_target(block.header.target) {
                 warn!("Block hash does not match the target");
                 return Err(BtcError::InvalidBlock);
@@ -80,94 +1385,162 @@ impl Blockchain {
                 return Err(BtcError::InvalidMerkleRoot);
             }
 
-            if block.header.timestamp <= last_block.header.timestamp {
+            let last_timestamp = self
+                .timestamp_at(self.block_height() - 1)
+                .ok_or(BtcError::InvalidBlock)?;
+            if block.header.timestamp <= last_timestamp {
                 warn!("Timestamp is not greater than the last block timestamp");
                 return Err(BtcError::InvalidBlock);
             }
 
+            let verify_signatures = self.full_verification
+                || self
+                    .params
+                    .last_checkpoint_height()
+                    .is_none_or(|last| self.block_height() > last);
             block
-                .verify_transactions(self.block_height(), &self.utxos)
+                .verify_transactions(self.block_height(), &self.utxos, verify_signatures)
                 .map_err(|e| {
                     error!("Transaction verification failed: {:?}", e);
                     e
                 })?;
+        } else {
+            // Genesis block validation
+            if block.header.prev_block_hash != Hash::zero() {
+                warn!("Genesis block must have a hash of 0");
+                return Err(BtcError::InvalidBlock);
+            }
         }
 
+        self.check_duplicates(&block)?;
+
         let block_transactions: HashSet<_> =
             block.transactions.iter().map(|tx| tx.hash()).collect();
 
         self.mempool
             .retain(|(_, tx)| !block_transactions.contains(&tx.hash()));
+        let height = self.block_height();
+        let undo = BlockUndo {
+            target: self.target,
+            spent: self.apply_utxos(height, &block),
+        };
+        let work = self.chain_work() + work_from_target(block.header.target);
+        self.undo.insert(block.hash(), undo);
+        self.index_block(height, &block);
+        self.chain_work.push(work);
         self.blocks.push(block);
         self.try_adjust_target();
 
         Ok(())
     }
 
+    // a transaction or output seen twice would overwrite the first in
+    // the indexes and the UTXO set, so neither may repeat one already
+    // in the chain or earlier in the block
+    fn check_duplicates(&self, block: &Block) -> Result<()> {
+        let mut transactions = HashSet::new();
+        let mut outputs = HashSet::new();
+        for transaction in &block.transactions {
+            let hash = transaction.hash();
+            if self.transaction_index.contains_key(&hash) || !transactions.insert(hash) {
+                warn!("Transaction {} is already in the chain", hash);
+                return Err(BtcError::DuplicateTransaction);
+            }
+            for output in &transaction.outputs {
+                let output_hash = output.hash();
+                if self.utxos.contains_key(&output_hash) || !outputs.insert(output_hash) {
+                    warn!("Output {} is already unspent", output_hash);
+                    return Err(BtcError::DuplicateOutput);
+                }
+            }
+        }
+        Ok(())
+    }
+
     #[instrument(skip(self))]
     pub fn rebuild_utxos(&mut self) {
-        for block in &self.blocks {
+        let base_height = self.base_height();
+        let blocks = std::mem::take(&mut self.blocks);
+        for (offset, block) in blocks.iter().enumerate() {
+            let height = base_height + offset as u64;
             for transaction in &block.transactions {
                 for input in &transaction.inputs {
-                    self.utxos.remove(&input.prev_transaction_output_hash);
+                    self.remove_utxo(&input.prev_transaction_output_hash);
                 }
 
                 for output in &transaction.outputs {
-                    let output_hash = output.hash();
-                    self.utxos
-                        .insert(output_hash, (false, output.clone()));
+                    self.add_utxo(output.hash(), height, output.clone());
                 }
             }
         }
+        self.blocks = blocks;
+        self.sync_reservations();
     }
 
-    #[instrument(skip(self))]
-    pub fn try_adjust_target(&mut self) {
-        if self.blocks.is_empty() {
-            return;
+    // target the block at `height` was mined against, as far as the
+    // chain still knows it
+    fn target_at(&self, height: u64) -> Option<U256> {
+        match self.block_at(height) {
+            Some(block) => Some(block.header.target),
+            None => self.base.as_ref().filter(|base| height < base.height).map(|base| base.target),
         }
+    }
 
-        if self.block_height() % crate::DIFFICULTY_UPDATE_INTERVAL != 0 {
-            return;
+    // target a block at `height` must have on the branch ending in
+    // `prev`, worked out the way `try_adjust_target` does on the main
+    // chain from the blocks before it on the branch
+    fn branch_target(&self, prev: Hash, height: u64) -> Option<U256> {
+        let adjustment = self.params.difficulty_adjustment;
+        let history = adjustment.history().min(height);
+        let mut recent = vec![];
+        let mut cursor = Some(prev);
+        for h in (height - history..height).rev() {
+            // below the last side block the branch is the main chain
+            match cursor.and_then(|hash| self.side_blocks.get(&hash)) {
+                Some(side) => {
+                    recent.push(Some((side.block.header.timestamp, side.block.header.target)));
+                    cursor = Some(side.block.header.prev_block_hash);
+                }
+                None => {
+                    recent.push(self.timestamp_at(h).zip(self.target_at(h)));
+                    cursor = None;
+                }
+            }
         }
+        let current = match self.side_blocks.get(&prev) {
+            Some(side) => side.block.header.target,
+            None => self.target_at(height - 1)?,
+        };
+        match recent.into_iter().rev().collect::<Option<Vec<_>>>() {
+            Some(recent) => Some(crate::difficulty::next_target(adjustment, height, current, &recent)),
+            None => Some(current),
+        }
+    }
 
-        // measure the time it took to mine the last crate::DIFFICULTY_UPDATE_INTERVAL blocks with chrono
-        let start_time = self.blocks
-            [(self.block_height() - crate::DIFFICULTY_UPDATE_INTERVAL) as usize]
-            .header
-            .timestamp;
-        let end_time = self.blocks.last().unwrap().header.timestamp;
-        let time_diff = end_time - start_time;
-        // convert time_diff to seconds
-        let time_diff_seconds = time_diff.num_seconds();
-        // calculate the ideal number of seconds
-        let target_seconds = crate::IDEAL_BLOCK_TIME * crate::DIFFICULTY_UPDATE_INTERVAL;
-        // multiply the current target by actual time divided by ideal time
-        let new_target = BigDecimal::parse_bytes(&self.target.to_string().as_bytes(), 10)
-            .expect("BUG: impossible")
-            * (BigDecimal::from(time_diff_seconds) / BigDecimal::from(target_seconds));
-        let new_target_str = new_target
-            .to_string()
-            .split('.')
-            .next()
-            .expect("BUG: Expected a decimal point")
-            .to_owned();
-        let new_target: U256 = U256::from_str_radix(&new_target_str, 10).expect("BUG: impossible");
-        // clamp new_target to be within the range of 4 * self.target and self.target / 4
-        let new_target = if new_target < self.target / 4 {
-            self.target / 4
-        } else if new_target > self.target * 4 {
-            self.target * 4
-        } else {
-            new_target
+    #[instrument(skip(self))]
+    pub fn try_adjust_target(&mut self) {
+        let height = self.block_height();
+        let adjustment = self.params.difficulty_adjustment;
+        let history = adjustment.history().min(height);
+        let Some(recent) = (height - history..height)
+            .map(|h| Some((self.timestamp_at(h)?, self.target_at(h)?)))
+            .collect::<Option<Vec<_>>>()
+        else {
+            warn!("Missing timestamps for difficulty adjustment");
+            return;
         };
+        self.target = crate::difficulty::next_target(adjustment, height, self.target, &recent);
+    }
 
-        // if the new target is more than the minimum target, set it to the minimum target
-        self.target = new_target.min(crate::MIN_TARGET);
+    pub fn add_to_mempool(&mut self, transaction: Transaction) -> Result<()> {
+        self.add_to_mempool_at(transaction, Utc::now())
     }
 
+    /// Validate and add a transaction that entered the mempool at
+    /// `added`, which is when it expires from. Used to put back the
+    /// transactions of a saved mempool without resetting their age.
     #[instrument(skip(self, transaction))]
-    pub fn add_to_mempool(&mut self, transaction: Transaction) -> Result<()> {
+    pub fn add_to_mempool_at(&mut self, transaction: Transaction, added: DateTime<Utc>) -> Result<()> {
         info!("Validating transaction: {}", transaction.hash());
         info!("Transaction has {} inputs, {} outputs", transaction.inputs.len(), transaction.outputs.len());
         
@@ -178,12 +1551,38 @@ impl Blockchain {
         utxo_hashes.sort_by_key(|h| format!("{}", h));
         info!("Available UTXO hashes in blockchain (first 10):");
         for (idx, hash) in utxo_hashes.iter().take(10).enumerate() {
-            if let Some((marked, output)) = self.utxos.get(hash) {
+            if let Some((marked, _, output)) = self.utxos.get(hash) {
                 info!("  {}: hash={}, value={}, marked={}, address={}", 
                     idx, hash, output.value, marked, output.address);
             }
         }
         
+        // refuse to relay payments to addresses of another network,
+        // the coins would be lost
+        for output in &transaction.outputs {
+            if let Err(e) = Address::parse_for(&output.address, &self.params) {
+                warn!("Transaction pays to {} which is not a {} address", output.address, self.params.network);
+                return Err(e);
+            }
+        }
+
+        // only blocks create coins
+        if transaction.inputs.is_empty() {
+            warn!("Transaction {} has no inputs", transaction.hash());
+            return Err(BtcError::UnexpectedCoinbase);
+        }
+        if self.transaction_index.contains_key(&transaction.hash()) {
+            warn!("Transaction {} is already in the chain", transaction.hash());
+            return Err(BtcError::DuplicateTransaction);
+        }
+        for output in &transaction.outputs {
+            let output_hash = output.hash();
+            if self.utxos.contains_key(&output_hash) || self.mempool_creator(&output_hash).is_some() {
+                warn!("Output {} already exists", output_hash);
+                return Err(BtcError::DuplicateOutput);
+            }
+        }
+
         let mut known_inputs = HashSet::new();
 
         for (idx, input) in transaction.inputs.iter().enumerate() {
@@ -192,20 +1591,20 @@ impl Blockchain {
             info!("Input public key address: {}", input.public_key.to_address());
             info!("Input hash bytes (hex): {}", hex::encode(input.prev_transaction_output_hash.as_bytes()));
             
-            if !self.utxos.contains_key(&input.prev_transaction_output_hash) {
+            if self.spendable_output(&input.prev_transaction_output_hash).is_none() {
                 error!("Transaction input {} references non-existent UTXO: {}", idx, input.prev_transaction_output_hash);
                 error!("  Input hash bytes (hex): {}", hex::encode(input.prev_transaction_output_hash.as_bytes()));
                 warn!("  Searching for similar UTXOs...");
                 
                 // Try to find UTXOs with the same address
-                let input_address = input.public_key.to_address();
+                let input_address = input.address();
                 let matching_utxos: Vec<_> = self.utxos.iter()
-                    .filter(|(_, (_, output))| output.address == input_address)
+                    .filter(|(_, (_, _, output))| Address::same(&output.address, &input_address))
                     .collect();
                 
                 if !matching_utxos.is_empty() {
                     warn!("  Found {} UTXOs with matching address {}:", matching_utxos.len(), input_address);
-                    for (hash, (marked, output)) in matching_utxos.iter().take(10) {
+                    for (hash, (marked, _, output)) in matching_utxos.iter().take(10) {
                         warn!("    hash={}, value={}, marked={}, address={}, unique_id={}", 
                             hash, output.value, marked, output.address, output.unique_id);
                         warn!("      hash bytes (hex): {}", hex::encode(hash.as_bytes()));
@@ -253,15 +1652,23 @@ impl Blockchain {
                 return Err(BtcError::InvalidTransaction);
             }
             known_inputs.insert(input.prev_transaction_output_hash);
+            // confirmed outputs may be double spent to replace a mempool
+            // transaction, unconfirmed ones can only be spent once
+            if !self.utxos.contains_key(&input.prev_transaction_output_hash)
+                && self.mempool_spender(&input.prev_transaction_output_hash).is_some()
+            {
+                warn!("Unconfirmed output {} is already spent in the mempool", input.prev_transaction_output_hash);
+                return Err(BtcError::InvalidTransaction);
+            }
             
             // Log the UTXO we found
-            if let Some((marked, output)) = self.utxos.get(&input.prev_transaction_output_hash) {
+            if let Some((marked, _, output)) = self.utxos.get(&input.prev_transaction_output_hash) {
                 info!("  Input {} UTXO found: value={}, marked={}, address={}, unique_id={}", 
                     idx, output.value, marked, output.address, output.unique_id);
                 
                 // Verify the address matches
-                let input_address = input.public_key.to_address();
-                if input_address != output.address {
+                let input_address = input.address();
+                if !Address::same(&input_address, &output.address) {
                     warn!("  Address mismatch! Input address: {}, UTXO address: {}", 
                         input_address, output.address);
                 }
@@ -273,10 +1680,8 @@ impl Blockchain {
             .inputs
             .iter()
             .map(|input| {
-                self.utxos
-                    .get(&input.prev_transaction_output_hash)
+                self.spendable_output(&input.prev_transaction_output_hash)
                     .expect("BUG: impossible")
-                    .1
                     .value
             })
             .sum();
@@ -289,66 +1694,25 @@ impl Blockchain {
             })?;
 
         for input in &transaction.inputs {
-            if let Some((true, _)) = self.utxos.get(&input.prev_transaction_output_hash) {
-                // find the transaction that references the utxo we are trying to reference
-                let referencing_transaction =
-                    self.mempool
-                        .iter()
-                        .enumerate()
-                        .find(|(_, (_, transaction))| {
-                            transaction
-                                .outputs
-                                .iter()
-                                .any(|output| output.hash() == input.prev_transaction_output_hash)
-                        });
-
-                // if we have found on, unmark all of its utxos
-                if let Some((idx, (_, referencing_transaction))) = referencing_transaction {
-                    let referencing_fee_inputs = referencing_transaction
-                        .inputs
-                        .iter()
-                        .map(|input| {
-                            self.utxos
-                                .get(&input.prev_transaction_output_hash)
-                                .expect("BUG: impossible")
-                                .1
-                                .value
-                        })
-                        .sum::<u64>();
-                    let referencing_fee_outputs: u64 = referencing_transaction
-                        .outputs
-                        .iter()
-                        .map(|output| output.value)
-                        .sum();
-                    let referencing_fee = referencing_fee_inputs
-                        .checked_sub(referencing_fee_outputs)
-                        .ok_or(BtcError::InvalidTransaction)?;
-
-                    // If the new transaction fee is less than the referencing transaction fee, the new transaction is rejected
-                    if new_transaction_fee <= referencing_fee {
-                        warn!("Transaction fee too low: new_fee={}, existing_fee={}", new_transaction_fee, referencing_fee);
-                        return Err(BtcError::InvalidTransaction);
-                    }
+            // find the transaction that reserved the utxo we are trying to spend
+            let Some(reservation) = self.reservations.get(&input.prev_transaction_output_hash) else {
+                continue;
+            };
+            let replaced_hash = reservation.txid;
+            // the replacement evicts the transaction and everything
+            // spending its outputs, so it must pay more than all of them
+            let replaced_fee = std::iter::once(replaced_hash)
+                .chain(self.mempool_descendants(&replaced_hash))
+                .filter_map(|hash| self.mempool_transaction(&hash))
+                .map(|tx| self.transaction_fee(tx).ok_or(BtcError::InvalidTransaction))
+                .sum::<Result<u64>>()?;
 
-                    for input in &referencing_transaction.inputs {
-                        // set all utxos from this transaction to false
-                        self.utxos
-                            .entry(input.prev_transaction_output_hash)
-                            .and_modify(|(marked, _)| {
-                                *marked = false;
-                            });
-                        // remove the transaction from the mempool
-                    }
-                    self.mempool.remove(idx);
-                } else {
-                    // if, somehow, there's no matching tx. set this utxo to false
-                    self.utxos
-                        .entry(input.prev_transaction_output_hash)
-                        .and_modify(|(marked, _)| {
-                            *marked = false;
-                        });
-                }
+            // If the new transaction fee is less than the replaced fees, the new transaction is rejected
+            if new_transaction_fee <= replaced_fee {
+                warn!("Transaction fee too low: new_fee={}, existing_fee={}", new_transaction_fee, replaced_fee);
+                return Err(BtcError::InvalidTransaction);
             }
+            self.evict_from_mempool(&replaced_hash);
         }
 
         // all inputs must be greater than or equal to all outputs
@@ -359,79 +1723,89 @@ impl Blockchain {
             return Err(BtcError::InvalidTransaction);
         }
 
-        // mark the utxos as used
-        for input in &transaction.inputs {
-            self.utxos
-                .entry(input.prev_transaction_output_hash)
-                .and_modify(|(marked, _)| *marked = true);
-        }
-
-        self.mempool.push((Utc::now(), transaction));
+        self.reserve(&transaction, added);
+        self.mempool.push((added, transaction));
         // sort by miner fee
-        self.mempool.sort_by_key(|(_, transaction)| {
-            let all_inputs = transaction
-                .inputs
-                .iter()
-                .map(|input| {
-                    self.utxos
-                        .get(&input.prev_transaction_output_hash)
-                        .expect("BUG: impossible")
-                        .1
-                        .value
-                })
-                .sum::<u64>();
-            let all_outputs: u64 = transaction.outputs.iter().map(|output| output.value).sum();
-            let miner_fee = all_inputs - all_outputs;
-            miner_fee
-        });
+        let fees: HashMap<Hash, u64> = self
+            .mempool
+            .iter()
+            .map(|(_, transaction)| (transaction.hash(), self.transaction_fee(transaction).unwrap_or(0)))
+            .collect();
+        self.mempool.sort_by_key(|(_, transaction)| fees[&transaction.hash()]);
+        debug_assert_eq!(self.check_reservations(), Ok(()));
 
         Ok(())
     }
 
-    // Cleanup mempool - remove transactions older than
-    // MAX_MEMPOOL_TRANSACTION_AGE
+    // Cleanup mempool - remove transactions older than the maximum
+    // mempool age
     #[instrument(skip(self))]
-    pub fn cleanup_mempool(&mut self) {
-        let now = Utc::now();
-        let mut utxo_hashes_to_unmark: Vec<Hash> = vec![];
-        self.mempool.retain(|(timestamp, transaction)| {
-            if now - *timestamp
-                > chrono::Duration::seconds(crate::MAX_MEMPOOL_TRANSACTION_AGE as i64)
-            {
-                // push all utxos to unmark to the vector
-                // so we can unmark them later
-                utxo_hashes_to_unmark.extend(
-                    transaction
-                        .inputs
-                        .iter()
-                        .map(|input| input.prev_transaction_output_hash),
-                );
-                false
-            } else {
-                true
-            }
-        });
-        // unmark all of the UTXOs
-        for hash in utxo_hashes_to_unmark {
-            self.utxos.entry(hash).and_modify(|(marked, _)| {
-                *marked = false;
-            });
+    pub fn cleanup_mempool(&mut self) -> MempoolCleanup {
+        self.cleanup_mempool_at(Utc::now())
+    }
+
+    /// Remove the transactions that are too old at `now`, like
+    /// `cleanup_mempool`. Replaying a journal runs it at the recorded
+    /// times.
+    pub fn cleanup_mempool_at(&mut self, now: DateTime<Utc>) -> MempoolCleanup {
+        let (transactions, reservations) = (self.mempool.len(), self.reservations.len());
+        let expired: Vec<Hash> = self
+            .mempool
+            .iter()
+            .filter(|(timestamp, _)| self.mempool_expired(*timestamp, now))
+            .map(|(_, transaction)| transaction.hash())
+            .collect();
+        // children can't be mined without their parents, so they go too
+        for hash in &expired {
+            self.evict_from_mempool(hash);
         }
+        debug_assert_eq!(self.check_reservations(), Ok(()));
+        MempoolCleanup {
+            expired: expired.len(),
+            descendants: transactions - self.mempool.len() - expired.len(),
+            unmarked: reservations - self.reservations.len(),
+        }
+    }
+
+    /// Drop every mempool transaction, releasing the outputs they
+    /// spend. Returns how many transactions and outputs that was.
+    pub fn clear_mempool(&mut self) -> (usize, usize) {
+        let cleared = (self.mempool.len(), self.reservations.len());
+        self.mempool.clear();
+        self.sync_reservations();
+        cleared
     }
 
     #[instrument(skip(self))]
     pub fn calculate_block_reward(&self) -> u64 {
-        let block_height = self.block_height();
-        let halvings = block_height / crate::HALVING_INTERVAL;
+        ChainParams::reward_at_height(self.block_height())
+    }
+}
+
+// what an unspent output adds to the UTXO set hash
+fn utxo_element(outpoint: &Hash, height: u64, output: &TransactionOutput) -> Vec<u8> {
+    let mut element = Vec::new();
+    ciborium::into_writer(&(outpoint, height, output), &mut element).expect("serializing into a Vec doesn't fail");
+    element
+}
 
-        (crate::INITIAL_REWARD * 10u64.pow(8)) >> halvings
+// the UTXO set hash computed from scratch
+fn utxo_set_hash(utxos: &HashMap<Hash, (bool, u64, TransactionOutput)>) -> MuHash {
+    let mut hash = MuHash::new();
+    for (outpoint, (_, height, output)) in utxos {
+        hash.insert(&utxo_element(outpoint, *height, output));
     }
+    hash
 }
 
 impl Saveable for Blockchain {
     fn load<I: Read>(reader: I) -> IoResult<Self> {
-        ciborium::de::from_reader(reader)
-            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to deserialize blockchain"))
+        let mut blockchain: Self = ciborium::de::from_reader(reader)
+            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to deserialize blockchain"))?;
+        blockchain.utxo_hash = utxo_set_hash(&blockchain.utxos);
+        blockchain.reindex();
+        blockchain.sync_reservations();
+        Ok(blockchain)
     }
 
     fn save<O: Write>(&self, writer: O) -> IoResult<()> {
@@ -439,3 +1813,565 @@ impl Saveable for Blockchain {
             .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to serialize blockchain"))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{PrivateKey, Signature};
+    use crate::types::{BlockHeader, TransactionInput};
+    use uuid::Uuid;
+
+    fn output(key: &PrivateKey, value: u64) -> TransactionOutput {
+        TransactionOutput {
+            value,
+            unique_id: Uuid::new_v4(),
+            address: key.public_key().to_address(),
+        }
+    }
+
+    fn spend(key: &PrivateKey, prev: &TransactionOutput, value: u64) -> Transaction {
+        let hash = prev.hash();
+        Transaction::new(
+            vec![TransactionInput {
+                prev_transaction_output_hash: hash,
+                public_key: key.public_key(),
+                signature: Signature::sign_output(&hash, key),
+                multisig: None,
+                cosignatures: vec![],
+            }],
+            vec![output(key, value)],
+        )
+    }
+
+    // a chain whose genesis block pays 1000 to `key`
+    fn chain(key: &PrivateKey) -> (Blockchain, TransactionOutput) {
+        let coinbase = output(key, 1000);
+        let transactions = vec![Transaction::new(vec![], vec![coinbase.clone()])];
+        let genesis = Block::new(
+            BlockHeader::new(
+                Utc::now(),
+                0,
+                Hash::zero(),
+                MerkleRoot::calculate(&transactions),
+                crate::MIN_TARGET,
+            ),
+            transactions,
+        );
+        let mut blockchain = Blockchain::new();
+        blockchain.add_block(genesis).unwrap();
+        blockchain.rebuild_utxos();
+        (blockchain, coinbase)
+    }
+
+    // a mined block on top of the chain's tip
+    fn next_block(blockchain: &Blockchain, transactions: Vec<Transaction>) -> Block {
+        let timestamp = Utc::now() + chrono::Duration::seconds(blockchain.block_height() as i64);
+        mined_block(blockchain.tip_hash().unwrap(), timestamp, transactions)
+    }
+
+    fn mined_block(prev: Hash, timestamp: DateTime<Utc>, transactions: Vec<Transaction>) -> Block {
+        mined_block_at(prev, timestamp, crate::MIN_TARGET, transactions)
+    }
+
+    fn mined_block_at(prev: Hash, timestamp: DateTime<Utc>, target: U256, transactions: Vec<Transaction>) -> Block {
+        let mut block = Block::new(
+            BlockHeader::new(timestamp, 0, prev, MerkleRoot::calculate(&transactions), target),
+            transactions,
+        );
+        while !block.header.mine(100_000) {}
+        block
+    }
+
+    // a block with only a coinbase, on any block
+    fn branch_block(key: &PrivateKey, prev: &Block, extra: Vec<Transaction>) -> Block {
+        let mut transactions = vec![Transaction::new(vec![], vec![output(key, 1)])];
+        transactions.extend(extra);
+        mined_block(prev.hash(), prev.header.timestamp + chrono::Duration::seconds(1), transactions)
+    }
+
+    #[test]
+    fn test_child_pays_for_parent() {
+        let key = PrivateKey::new_key();
+        let (mut blockchain, coinbase) = chain(&key);
+        let other = spend(&key, &output(&key, 500), 400);
+        let parent = spend(&key, &coinbase, 999);
+        let child = spend(&key, &parent.outputs[0], 900);
+        blockchain.add_to_mempool(parent.clone()).unwrap();
+        blockchain.add_to_mempool(child.clone()).unwrap();
+        // spending the same unconfirmed output twice is not a replacement
+        assert!(blockchain.add_to_mempool(spend(&key, &parent.outputs[0], 800)).is_err());
+        // neither is spending an output nobody created
+        assert!(blockchain.add_to_mempool(other).is_err());
+
+        assert_eq!(blockchain.transaction_fee(&child), Some(99));
+        let ancestors = blockchain.mempool_ancestors(&child);
+        assert_eq!(ancestors.len(), 1);
+        assert_eq!(ancestors[0].hash(), parent.hash());
+        assert_eq!(blockchain.mempool_descendants(&parent.hash()), vec![child.hash()]);
+        assert_eq!(blockchain.ancestor_package(&child).0, 100);
+
+        // the child pulls its parent into the template, parent first
+        let template = blockchain.template_transactions(crate::BLOCK_TRANSACTION_CAP);
+        let hashes: Vec<_> = template.iter().map(Transaction::hash).collect();
+        assert_eq!(hashes, vec![parent.hash(), child.hash()]);
+        // which is a valid block, the child spending an output created
+        // earlier in it
+        let reward = blockchain.calculate_block_reward() + 100;
+        let mut transactions = vec![Transaction::new(vec![], vec![output(&key, reward)])];
+        transactions.extend(template);
+        let header = BlockHeader::new(
+            Utc::now(),
+            0,
+            Hash::zero(),
+            MerkleRoot::calculate(&transactions),
+            crate::MIN_TARGET,
+        );
+        let block = Block::new(header, transactions);
+        block.verify_transactions(1, blockchain.utxos(), true).unwrap();
+        // without room for both, the child can't go in without its parent
+        let template = blockchain.template_transactions(1);
+        assert_eq!(template.len(), 1);
+        assert_eq!(template[0].hash(), parent.hash());
+    }
+
+    #[test]
+    fn test_replacing_parent_evicts_descendants() {
+        let key = PrivateKey::new_key();
+        let (mut blockchain, coinbase) = chain(&key);
+        let parent = spend(&key, &coinbase, 990);
+        let child = spend(&key, &parent.outputs[0], 980);
+        blockchain.add_to_mempool(parent).unwrap();
+        blockchain.add_to_mempool(child).unwrap();
+
+        // 20 in fees are at stake, a replacement has to beat them
+        assert!(blockchain.add_to_mempool(spend(&key, &coinbase, 985)).is_err());
+        let replacement = spend(&key, &coinbase, 970);
+        blockchain.add_to_mempool(replacement.clone()).unwrap();
+        assert_eq!(blockchain.mempool().len(), 1);
+        assert_eq!(blockchain.mempool()[0].1.hash(), replacement.hash());
+    }
+
+    #[test]
+    fn test_reservations_survive_rebuild_and_expire() {
+        let key = PrivateKey::new_key();
+        let (mut blockchain, coinbase) = chain(&key);
+        let added = Utc::now() - chrono::Duration::seconds(crate::MAX_MEMPOOL_TRANSACTION_AGE as i64 + 60);
+        let parent = spend(&key, &coinbase, 990);
+        blockchain.add_to_mempool_at(parent.clone(), added).unwrap();
+        let reservation = blockchain.reservations()[&coinbase.hash()];
+        assert_eq!(reservation.txid, parent.hash());
+        assert!(blockchain.utxos()[&coinbase.hash()].0);
+
+        // rebuilding the UTXO set used to clear the marks of outputs
+        // spent in the mempool
+        blockchain.rebuild_utxos();
+        assert!(blockchain.utxos()[&coinbase.hash()].0);
+        assert_eq!(blockchain.reservations()[&coinbase.hash()], reservation);
+        assert_eq!(blockchain.check_reservations(), Ok(()));
+
+        assert!(reservation.expires < Utc::now());
+        blockchain.cleanup_mempool();
+        assert!(blockchain.mempool().is_empty());
+        assert!(blockchain.reservations().is_empty());
+        assert!(!blockchain.utxos()[&coinbase.hash()].0);
+        assert_eq!(blockchain.check_reservations(), Ok(()));
+    }
+
+    #[test]
+    fn test_mempool_age_and_clear() {
+        let key = PrivateKey::new_key();
+        let (mut blockchain, coinbase) = chain(&key);
+        let now = Utc::now();
+        let parent = spend(&key, &coinbase, 990);
+        let child = spend(&key, &parent.outputs[0], 980);
+        blockchain.add_to_mempool_at(parent.clone(), now - chrono::Duration::seconds(60)).unwrap();
+        blockchain.add_to_mempool_at(child, now).unwrap();
+        assert_eq!(blockchain.cleanup_mempool_at(now), MempoolCleanup::default());
+
+        // a shorter age moves the reservation forward
+        blockchain.set_max_mempool_age(30);
+        assert_eq!(
+            blockchain.reservations()[&coinbase.hash()].expires,
+            now - chrono::Duration::seconds(30)
+        );
+        let cleanup = blockchain.cleanup_mempool_at(now);
+        assert_eq!(
+            cleanup,
+            MempoolCleanup {
+                expired: 1,
+                descendants: 1,
+                unmarked: 1
+            }
+        );
+        assert!(blockchain.mempool().is_empty());
+
+        blockchain.add_to_mempool_at(parent, now).unwrap();
+        assert_eq!(blockchain.clear_mempool(), (1, 1));
+        assert!(blockchain.mempool().is_empty());
+        assert!(!blockchain.utxos()[&coinbase.hash()].0);
+        assert_eq!(blockchain.check_reservations(), Ok(()));
+    }
+
+    #[test]
+    fn test_signed_checkpoints() {
+        let key = PrivateKey::new_key();
+        let authority = PrivateKey::new_key();
+        let (mut blockchain, _) = chain(&key);
+        let coinbase = |value| vec![Transaction::new(vec![], vec![output(&key, value)])];
+        let (block, rival) = (next_block(&blockchain, coinbase(1)), next_block(&blockchain, coinbase(2)));
+        let checkpoint = |block: &Block| Checkpoint {
+            height: 1,
+            hash: block.hash(),
+        };
+
+        // only networks with a checkpoint key take signed checkpoints
+        let signed = SignedCheckpoint::sign(checkpoint(&block), &authority);
+        assert!(matches!(
+            blockchain.add_signed_checkpoint(signed.clone()),
+            Err(BtcError::UntrustedCheckpoint)
+        ));
+        blockchain.set_params(ChainParams::testnet().with_checkpoint_key(authority.public_key()));
+        assert!(matches!(
+            blockchain.add_signed_checkpoint(SignedCheckpoint::sign(checkpoint(&block), &key)),
+            Err(BtcError::UntrustedCheckpoint)
+        ));
+        assert!(blockchain.add_signed_checkpoint(signed.clone()).unwrap());
+        assert!(!blockchain.add_signed_checkpoint(signed).unwrap());
+        assert!(matches!(
+            blockchain.add_signed_checkpoint(SignedCheckpoint::sign(checkpoint(&rival), &authority)),
+            Err(BtcError::CheckpointMismatch)
+        ));
+
+        // and keep them when the params change
+        blockchain.set_params(ChainParams::testnet().with_checkpoint_key(authority.public_key()));
+        assert_eq!(blockchain.params().checkpoint_at(1), Some(block.hash()));
+        assert_eq!(blockchain.signed_checkpoints().len(), 1);
+        assert!(matches!(blockchain.add_block(rival), Err(BtcError::CheckpointMismatch)));
+        blockchain.add_block(block).unwrap();
+    }
+
+    #[test]
+    fn test_coinbase_rules() {
+        let key = PrivateKey::new_key();
+        let (blockchain, coinbase) = chain(&key);
+        let reward = ChainParams::reward_at_height(1);
+        let block = |claimed: u64| {
+            let transactions = vec![
+                Transaction::new(vec![], vec![output(&key, claimed)]),
+                spend(&key, &coinbase, 900),
+            ];
+            let header = BlockHeader::new(
+                Utc::now(),
+                0,
+                Hash::zero(),
+                MerkleRoot::calculate(&transactions),
+                crate::MIN_TARGET,
+            );
+            Block::new(header, transactions)
+        };
+        block(reward + 100).verify_transactions(1, blockchain.utxos(), true).unwrap();
+        block(reward).verify_transactions(1, blockchain.utxos(), true).unwrap();
+        assert!(matches!(
+            block(reward + 101).verify_transactions(1, blockchain.utxos(), true),
+            Err(BtcError::CoinbaseTooLarge { claimed, allowed }) if claimed == reward + 101 && allowed == reward + 100
+        ));
+
+        let mut reordered = block(reward);
+        reordered.transactions.reverse();
+        assert!(matches!(
+            reordered.verify_transactions(1, blockchain.utxos(), true),
+            Err(BtcError::MissingCoinbase)
+        ));
+        let mut second = block(reward);
+        second.transactions.push(Transaction::new(vec![], vec![output(&key, 0)]));
+        assert!(matches!(
+            second.verify_transactions(1, blockchain.utxos(), true),
+            Err(BtcError::UnexpectedCoinbase)
+        ));
+        let mut empty = block(reward);
+        empty.transactions[0].outputs.clear();
+        assert!(matches!(
+            empty.verify_transactions(1, blockchain.utxos(), true),
+            Err(BtcError::EmptyCoinbase)
+        ));
+        let mut blockchain = blockchain;
+        assert!(matches!(
+            blockchain.add_to_mempool(Transaction::new(vec![], vec![output(&key, 0)])),
+            Err(BtcError::UnexpectedCoinbase)
+        ));
+    }
+
+    #[test]
+    fn test_duplicates_are_rejected() {
+        let key = PrivateKey::new_key();
+        let (mut blockchain, coinbase) = chain(&key);
+        let genesis_coinbase = blockchain.block_at(0).unwrap().transactions[0].clone();
+        // replaying the genesis coinbase would mint its output again
+        let replayed = next_block(&blockchain, vec![genesis_coinbase]);
+        assert!(matches!(blockchain.add_block(replayed), Err(BtcError::DuplicateTransaction)));
+        let copied = next_block(&blockchain, vec![Transaction::new(
+            vec![],
+            vec![coinbase.clone(), output(&key, 1)],
+        )]);
+        assert!(matches!(blockchain.add_block(copied), Err(BtcError::DuplicateOutput)));
+        let mut copy = spend(&key, &coinbase, 900);
+        copy.outputs.push(coinbase.clone());
+        assert!(matches!(blockchain.add_to_mempool(copy), Err(BtcError::DuplicateOutput)));
+
+        blockchain.add_block(next_block(&blockchain, vec![Transaction::new(vec![], vec![output(&key, 1)])])).unwrap();
+        assert_eq!(blockchain.block_height(), 2);
+    }
+
+    #[test]
+    fn test_every_output_is_tracked() {
+        let key = PrivateKey::new_key();
+        let (mut blockchain, coinbase) = chain(&key);
+        let mut split = spend(&key, &coinbase, 600);
+        split.outputs.push(output(&key, 400));
+        let block = next_block(
+            &blockchain,
+            vec![Transaction::new(vec![], vec![output(&key, 1)]), split.clone()],
+        );
+        blockchain.add_block(block).unwrap();
+        blockchain.rebuild_utxos();
+        assert!(blockchain.utxos().contains_key(&split.outputs[0].hash()));
+        assert!(blockchain.utxos().contains_key(&split.outputs[1].hash()));
+
+        // the second output is as spendable as the first
+        let second = spend(&key, &split.outputs[1], 390);
+        blockchain.add_to_mempool(second.clone()).unwrap();
+        let block = next_block(
+            &blockchain,
+            vec![Transaction::new(vec![], vec![output(&key, 10)]), second.clone()],
+        );
+        blockchain.add_block(block).unwrap();
+        blockchain.rebuild_utxos();
+        assert!(blockchain.mempool().is_empty());
+        assert!(blockchain.utxos().contains_key(&split.outputs[0].hash()));
+        assert!(!blockchain.utxos().contains_key(&split.outputs[1].hash()));
+        assert!(blockchain.utxos().contains_key(&second.outputs[0].hash()));
+        assert_eq!(blockchain.check_reservations(), Ok(()));
+    }
+
+    #[test]
+    fn test_most_work_branch_wins() {
+        let key = PrivateKey::new_key();
+        let (mut blockchain, coinbase) = chain(&key);
+        let genesis = blockchain.block_at(0).unwrap().clone();
+        let payment = spend(&key, &coinbase, 900);
+        let a1 = branch_block(&key, &genesis, vec![payment.clone()]);
+        blockchain.add_block(a1.clone()).unwrap();
+        blockchain.rebuild_utxos();
+        let work = work_from_target(crate::MIN_TARGET);
+        assert_eq!(blockchain.chain_work(), work * 2);
+
+        // as much work as the main chain isn't enough to switch
+        let b1 = branch_block(&key, &genesis, vec![]);
+        let update = blockchain.submit_block(b1.clone()).unwrap();
+        assert!(update.disconnected.is_empty());
+        assert_eq!(blockchain.tip_hash(), Some(a1.hash()));
+        assert_eq!(blockchain.side_blocks().count(), 1);
+        assert!(matches!(blockchain.add_block(b1.clone()), Err(BtcError::KnownBlock)));
+
+        let b2 = branch_block(&key, &b1, vec![]);
+        let update = blockchain.submit_block(b2.clone()).unwrap();
+        blockchain.rebuild_utxos();
+        assert_eq!(update.fork_height, 1);
+        assert_eq!(update.disconnected.len(), 1);
+        assert_eq!(update.disconnected[0].hash(), a1.hash());
+        assert_eq!(blockchain.tip_hash(), Some(b2.hash()));
+        assert_eq!(blockchain.chain_work(), work * 3);
+        assert_eq!(blockchain.chain_work_at(1), Some(work * 2));
+        // the replaced block's payment waits to be mined again
+        assert!(blockchain.utxos().contains_key(&coinbase.hash()));
+        assert!(!blockchain.utxos().contains_key(&a1.transactions[0].outputs[0].hash()));
+        assert_eq!(blockchain.mempool().len(), 1);
+        assert_eq!(blockchain.mempool()[0].1.hash(), payment.hash());
+        assert_eq!(blockchain.transaction_by_id(&payment.hash()), None);
+        assert!(blockchain.side_blocks().any(|side| side.block.hash() == a1.hash()));
+        assert_eq!(blockchain.check_reservations(), Ok(()));
+    }
+
+    #[test]
+    fn test_reorg_undoes_outputs_spent_in_the_same_block() {
+        let key = PrivateKey::new_key();
+        let (mut blockchain, coinbase) = chain(&key);
+        let genesis = blockchain.block_at(0).unwrap().clone();
+        let parent = spend(&key, &coinbase, 990);
+        let child = spend(&key, &parent.outputs[0], 980);
+        let a1 = branch_block(&key, &genesis, vec![parent.clone(), child.clone()]);
+        blockchain.add_block(a1).unwrap();
+        assert!(!blockchain.utxos().contains_key(&parent.outputs[0].hash()));
+
+        let b1 = branch_block(&key, &genesis, vec![]);
+        blockchain.add_block(b1.clone()).unwrap();
+        let update = blockchain.submit_block(branch_block(&key, &b1, vec![])).unwrap();
+        assert_eq!(update.disconnected.len(), 1);
+        assert!(blockchain.utxos().contains_key(&coinbase.hash()));
+        assert!(!blockchain.utxos().contains_key(&parent.outputs[0].hash()));
+        assert!(!blockchain.utxos().contains_key(&child.outputs[0].hash()));
+
+        let incremental = blockchain.utxo_set_hash();
+        blockchain.rebuild_utxos();
+        assert_eq!(blockchain.utxo_set_hash(), incremental);
+    }
+
+    #[test]
+    fn test_invalid_branch_is_rolled_back() {
+        let key = PrivateKey::new_key();
+        let (mut blockchain, _) = chain(&key);
+        let genesis = blockchain.block_at(0).unwrap().clone();
+        let a1 = branch_block(&key, &genesis, vec![]);
+        blockchain.add_block(a1.clone()).unwrap();
+        let b1 = branch_block(&key, &genesis, vec![]);
+        blockchain.add_block(b1.clone()).unwrap();
+        // spends an output that never existed, which only shows once
+        // the branch is connected
+        let bad = branch_block(&key, &b1, vec![spend(&key, &output(&key, 5), 5)]);
+        assert!(blockchain.add_block(bad.clone()).is_err());
+        blockchain.rebuild_utxos();
+        assert_eq!(blockchain.tip_hash(), Some(a1.hash()));
+        assert!(blockchain.is_invalid(&bad.hash()));
+        assert!(blockchain.side_blocks().any(|side| side.block.hash() == b1.hash()));
+        assert!(blockchain.utxos().contains_key(&a1.transactions[0].outputs[0].hash()));
+        assert!(!blockchain.utxos().contains_key(&b1.transactions[0].outputs[0].hash()));
+
+        let after = branch_block(&key, &bad, vec![]);
+        assert!(matches!(blockchain.add_block(after.clone()), Err(BtcError::InvalidBlock)));
+        assert!(blockchain.is_invalid(&after.hash()));
+        assert_eq!(blockchain.block_height(), 2);
+    }
+
+    #[test]
+    fn test_orphans_connect_with_their_parent() {
+        let key = PrivateKey::new_key();
+        let (mut blockchain, _) = chain(&key);
+        let genesis = blockchain.block_at(0).unwrap().clone();
+        let first = branch_block(&key, &genesis, vec![]);
+        let second = branch_block(&key, &first, vec![]);
+        assert!(matches!(blockchain.add_block(second.clone()), Err(BtcError::UnknownParent)));
+        assert_eq!(blockchain.block_height(), 1);
+
+        blockchain.add_block(first).unwrap();
+        assert_eq!(blockchain.block_height(), 3);
+        assert_eq!(blockchain.tip_hash(), Some(second.hash()));
+    }
+
+    #[test]
+    fn test_addresses_used() {
+        let key = PrivateKey::new_key();
+        let other = PrivateKey::new_key();
+        let (mut blockchain, coinbase) = chain(&key);
+        let addresses = vec![key.public_key().to_address(), other.public_key().to_address(), "junk".to_string()];
+        assert_eq!(blockchain.addresses_used(&addresses), vec![true, false, false]);
+
+        let mut payment = spend(&key, &coinbase, 900);
+        payment.outputs[0] = output(&other, 900);
+        blockchain.add_to_mempool(payment).unwrap();
+        assert_eq!(blockchain.addresses_used(&addresses), vec![true, true, false]);
+    }
+
+    #[test]
+    fn test_chain_tips() {
+        let key = PrivateKey::new_key();
+        let (mut blockchain, _) = chain(&key);
+        let genesis = blockchain.block_at(0).unwrap().clone();
+        let a1 = branch_block(&key, &genesis, vec![]);
+        blockchain.add_block(a1.clone()).unwrap();
+        let b1 = branch_block(&key, &genesis, vec![]);
+        blockchain.add_block(b1.clone()).unwrap();
+        let c1 = branch_block(&key, &genesis, vec![]);
+        blockchain.add_block(c1.clone()).unwrap();
+        let c2 = branch_block(&key, &c1, vec![spend(&key, &output(&key, 5), 5)]);
+        assert!(blockchain.add_block(c2.clone()).is_err());
+
+        let tips = blockchain.chain_tips();
+        let summary: Vec<_> = tips.iter().map(|tip| (tip.hash, tip.height, tip.branch_length, tip.status)).collect();
+        assert_eq!(
+            summary,
+            vec![
+                (a1.hash(), 1, 0, TipStatus::Active),
+                (c2.hash(), 2, 2, TipStatus::Invalid),
+                (b1.hash(), 1, 1, TipStatus::ValidFork),
+            ]
+        );
+        assert_eq!(tips[1].chain_work, work_from_target(crate::MIN_TARGET) * 3);
+        assert_eq!(TipStatus::ValidFork.to_string(), "valid-fork");
+    }
+
+    #[test]
+    fn test_blocks_must_claim_the_chains_target() {
+        let key = PrivateKey::new_key();
+        let (mut blockchain, _) = chain(&key);
+        blockchain.set_params(
+            ChainParams::default().with_difficulty_adjustment(crate::params::DifficultyAdjustment::Ema { window: 2 }),
+        );
+        let genesis = blockchain.block_at(0).unwrap().clone();
+        let a1 = branch_block(&key, &genesis, vec![]);
+        blockchain.add_block(a1.clone()).unwrap();
+        // a1 came a second after genesis, well before the ideal time
+        let target = blockchain.target();
+        assert!(target < crate::MIN_TARGET);
+
+        let time = a1.header.timestamp + chrono::Duration::seconds(1);
+        let coinbase = |value| vec![Transaction::new(vec![], vec![output(&key, value)])];
+        let easy = mined_block_at(a1.hash(), time, crate::MIN_TARGET, coinbase(1));
+        assert!(matches!(blockchain.add_block(easy), Err(BtcError::WrongTarget)));
+        let a2 = mined_block_at(a1.hash(), time, target, coinbase(1));
+        blockchain.add_block(a2.clone()).unwrap();
+        assert_eq!(blockchain.tip_hash(), Some(a2.hash()));
+
+        // a branch is held to the target of its own blocks too
+        let easy = mined_block_at(a1.hash(), time, crate::MIN_TARGET, coinbase(2));
+        assert!(matches!(blockchain.add_block(easy), Err(BtcError::WrongTarget)));
+        let b2 = mined_block_at(a1.hash(), time, target, coinbase(2));
+        blockchain.add_block(b2.clone()).unwrap();
+        assert!(blockchain.side_blocks().any(|side| side.block.hash() == b2.hash()));
+        let b3 = mined_block_at(b2.hash(), time + chrono::Duration::seconds(1), crate::MIN_TARGET, coinbase(3));
+        assert!(matches!(blockchain.add_block(b3), Err(BtcError::WrongTarget)));
+        assert_eq!(blockchain.tip_hash(), Some(a2.hash()));
+    }
+
+    #[test]
+    fn test_invalidate_and_reconsider() {
+        let key = PrivateKey::new_key();
+        let (mut blockchain, _) = chain(&key);
+        let genesis = blockchain.block_at(0).unwrap().clone();
+        let a1 = branch_block(&key, &genesis, vec![]);
+        let a2 = branch_block(&key, &a1, vec![]);
+        let b1 = branch_block(&key, &genesis, vec![]);
+        for block in [a1.clone(), a2.clone(), b1.clone()] {
+            blockchain.add_block(block).unwrap();
+        }
+        blockchain.rebuild_utxos();
+        assert_eq!(blockchain.tip_hash(), Some(a2.hash()));
+        let on_a2 = blockchain.utxo_set_hash();
+
+        // the main chain falls back to the other branch, a2 going with a1
+        let update = blockchain.invalidate_block(&a1.hash()).unwrap();
+        blockchain.rebuild_utxos();
+        assert_eq!(update.fork_height, 1);
+        assert_eq!(update.disconnected.len(), 2);
+        assert_eq!(blockchain.tip_hash(), Some(b1.hash()));
+        assert!(blockchain.is_invalid(&a2.hash()));
+        assert!(!blockchain.utxos().contains_key(&a1.transactions[0].outputs[0].hash()));
+        assert_ne!(blockchain.utxo_set_hash(), on_a2);
+        assert_eq!(blockchain.utxo_set_hash(), utxo_set_hash(blockchain.utxos()).finalize());
+        // nothing builds on it while it is invalid
+        let a3 = branch_block(&key, &a2, vec![]);
+        assert!(matches!(blockchain.add_block(a3.clone()), Err(BtcError::InvalidBlock)));
+
+        let update = blockchain.reconsider_block(&a2.hash()).unwrap();
+        blockchain.rebuild_utxos();
+        assert_eq!(update.disconnected.len(), 1);
+        assert_eq!(update.disconnected[0].hash(), b1.hash());
+        assert_eq!(blockchain.tip_hash(), Some(a2.hash()));
+        assert!(!blockchain.is_invalid(&a1.hash()));
+        assert!(blockchain.utxos().contains_key(&a1.transactions[0].outputs[0].hash()));
+        // the UTXO set hash comes back with the UTXO set
+        assert_eq!(blockchain.utxo_set_hash(), on_a2);
+
+        assert!(matches!(blockchain.invalidate_block(&genesis.hash()), Err(BtcError::IrreversibleBlock)));
+        assert!(matches!(blockchain.invalidate_block(&Hash::zero()), Err(BtcError::UnknownBlock)));
+    }
+}
@@ -0,0 +1,342 @@
+use super::{Transaction, TransactionInput, TransactionOutput, UnsignedInput, UnsignedTransaction};
+use crate::crypto::{PrivateKey, PublicKey, Signature};
+use crate::error::{BtcError, Result};
+use crate::sha256::Hash;
+#[cfg(feature = "std")]
+use crate::util::Saveable;
+use alloc::vec;
+use alloc::vec::Vec;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "std")]
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
+
+/// A transaction passed between signers until every input is signed,
+/// modelled on Bitcoin's PSBT. Each signer adds its signatures, copies
+/// signed by different parties are merged, and once complete the
+/// signatures are finalized and the transaction extracted.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PartiallySignedTransaction {
+    pub unsigned: UnsignedTransaction,
+    /// One entry per input of `unsigned`, in the same order
+    pub inputs: Vec<PsbtInput>,
+}
+
+/// Signing data of a single input
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PsbtInput {
+    /// The output being spent, so signers can check what they sign
+    pub utxo: TransactionOutput,
+    /// Signatures collected so far
+    pub partial_signatures: Vec<(PublicKey, Signature)>,
+    /// Set by `finalize` once the input is fully signed: one signature,
+    /// or `threshold` of them for a multisig input
+    pub final_signatures: Option<Vec<(PublicKey, Signature)>>,
+}
+
+impl PartiallySignedTransaction {
+    /// Start signing `unsigned`, given the outputs its inputs spend
+    pub fn new(unsigned: UnsignedTransaction, utxos: Vec<TransactionOutput>) -> Result<Self> {
+        if utxos.len() != unsigned.inputs.len() {
+            return Err(BtcError::InvalidTransaction);
+        }
+        let matches = unsigned.inputs.iter().zip(&utxos).all(|(input, utxo)| {
+            input.prev_transaction_output_hash == utxo.hash() && input.value == utxo.value
+        });
+        if !matches {
+            return Err(BtcError::InvalidTransactionInput);
+        }
+        let inputs = utxos
+            .into_iter()
+            .map(|utxo| PsbtInput {
+                utxo,
+                partial_signatures: vec![],
+                final_signatures: None,
+            })
+            .collect();
+        Ok(Self { unsigned, inputs })
+    }
+
+    /// Identifies the transaction being signed, equal for every copy
+    pub fn id(&self) -> Hash {
+        Hash::hash(&self.unsigned)
+    }
+
+    /// Add signatures for every input `key` can sign, returning how
+    /// many were added
+    pub fn sign(&mut self, key: &PrivateKey) -> usize {
+        let public_key = key.public_key();
+        let mut signed = 0;
+        for (input, psbt_input) in self.unsigned.inputs.iter().zip(&mut self.inputs) {
+            if !input.is_signer(&public_key)
+                || psbt_input.final_signatures.is_some()
+                || psbt_input.has_signature_from(&public_key)
+            {
+                continue;
+            }
+            let signature = Signature::sign_output(&input.prev_transaction_output_hash, key);
+            psbt_input.partial_signatures.push((public_key.clone(), signature));
+            signed += 1;
+        }
+        signed
+    }
+
+    /// Add a signature made elsewhere, such as by an external signer,
+    /// to every input it signs, returning how many it was added to
+    pub fn add_signature(&mut self, public_key: &PublicKey, signature: &Signature) -> usize {
+        let mut added = 0;
+        for (input, psbt_input) in self.unsigned.inputs.iter().zip(&mut self.inputs) {
+            if input.is_signer(public_key)
+                && psbt_input.final_signatures.is_none()
+                && !psbt_input.has_signature_from(public_key)
+                && signature.verify(&input.prev_transaction_output_hash, public_key)
+            {
+                psbt_input.partial_signatures.push((public_key.clone(), signature.clone()));
+                added += 1;
+            }
+        }
+        added
+    }
+
+    /// Collect the signatures of another copy of the same transaction
+    pub fn merge(&mut self, other: PartiallySignedTransaction) -> Result<()> {
+        if other.id() != self.id() || other.inputs.len() != self.inputs.len() {
+            return Err(BtcError::PsbtMismatch);
+        }
+        for ((input, ours), theirs) in self
+            .unsigned
+            .inputs
+            .iter()
+            .zip(&mut self.inputs)
+            .zip(other.inputs)
+        {
+            if ours.final_signatures.is_none() {
+                ours.final_signatures = theirs.final_signatures;
+            }
+            for (public_key, signature) in theirs.partial_signatures {
+                // only keep signatures that actually sign this input
+                if input.is_signer(&public_key)
+                    && !ours.has_signature_from(&public_key)
+                    && signature.verify(&input.prev_transaction_output_hash, &public_key)
+                {
+                    ours.partial_signatures.push((public_key, signature));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether every input has enough valid signatures
+    pub fn is_complete(&self) -> bool {
+        self.unsigned
+            .inputs
+            .iter()
+            .zip(&self.inputs)
+            .all(|(input, psbt_input)| psbt_input.signatures_for(input).is_some())
+    }
+
+    /// Pick the final signature of every input and drop the partial
+    /// ones. Fails without changes if any input is not fully signed.
+    pub fn finalize(&mut self) -> Result<()> {
+        let finals = self
+            .unsigned
+            .inputs
+            .iter()
+            .zip(&self.inputs)
+            .map(|(input, psbt_input)| psbt_input.signatures_for(input))
+            .collect::<Option<Vec<_>>>()
+            .ok_or(BtcError::IncompleteSignatures)?;
+        for (psbt_input, signatures) in self.inputs.iter_mut().zip(finals) {
+            psbt_input.final_signatures = Some(signatures);
+            psbt_input.partial_signatures.clear();
+        }
+        Ok(())
+    }
+
+    /// Build the signed transaction from a finalized PSBT
+    pub fn extract(&self) -> Result<Transaction> {
+        let inputs = self
+            .unsigned
+            .inputs
+            .iter()
+            .zip(&self.inputs)
+            .map(|(input, psbt_input)| {
+                let mut signatures = psbt_input
+                    .final_signatures
+                    .clone()
+                    .filter(|signatures| !signatures.is_empty())
+                    .ok_or(BtcError::IncompleteSignatures)?;
+                let (public_key, signature) = signatures.remove(0);
+                Ok(TransactionInput {
+                    prev_transaction_output_hash: input.prev_transaction_output_hash,
+                    public_key,
+                    signature,
+                    multisig: input.multisig.clone(),
+                    cosignatures: signatures,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Transaction::new(inputs, self.unsigned.outputs.clone()))
+    }
+}
+
+impl PsbtInput {
+    fn has_signature_from(&self, public_key: &PublicKey) -> bool {
+        self.partial_signatures.iter().any(|(key, _)| key == public_key)
+    }
+
+    // enough valid signatures by keys allowed to spend the input
+    fn signatures_for(&self, input: &UnsignedInput) -> Option<Vec<(PublicKey, Signature)>> {
+        let hash = &input.prev_transaction_output_hash;
+        if let Some(signatures) = &self.final_signatures {
+            // may come from another signer's copy, so check it as well
+            let valid = signatures.len() == input.required_signatures()
+                && signatures
+                    .iter()
+                    .all(|(key, signature)| input.is_signer(key) && signature.verify(hash, key));
+            return valid.then(|| signatures.clone());
+        }
+        let signatures: Vec<_> = self
+            .partial_signatures
+            .iter()
+            .filter(|(key, signature)| input.is_signer(key) && signature.verify(hash, key))
+            .take(input.required_signatures())
+            .cloned()
+            .collect();
+        (signatures.len() == input.required_signatures()).then_some(signatures)
+    }
+}
+
+#[cfg(feature = "std")]
+impl Saveable for PartiallySignedTransaction {
+    fn load<I: Read>(reader: I) -> IoResult<Self> {
+        ciborium::de::from_reader(reader).map_err(|_| {
+            IoError::new(IoErrorKind::InvalidData, "Failed to deserialize partially signed transaction")
+        })
+    }
+
+    fn save<O: Write>(&self, writer: O) -> IoResult<()> {
+        ciborium::ser::into_writer(self, writer).map_err(|_| {
+            IoError::new(IoErrorKind::InvalidData, "Failed to serialize partially signed transaction")
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::UnsignedInput;
+    use uuid::Uuid;
+
+    fn utxo(key: &PrivateKey, value: u64) -> TransactionOutput {
+        TransactionOutput {
+            value,
+            unique_id: Uuid::new_v4(),
+            address: key.public_key().to_address(),
+        }
+    }
+
+    fn psbt(keys: &[&PrivateKey]) -> PartiallySignedTransaction {
+        let utxos: Vec<_> = keys.iter().map(|key| utxo(key, 10)).collect();
+        let inputs = keys
+            .iter()
+            .zip(&utxos)
+            .map(|(key, utxo)| UnsignedInput {
+                prev_transaction_output_hash: utxo.hash(),
+                public_key: key.public_key(),
+                value: utxo.value,
+                multisig: None,
+            })
+            .collect();
+        let unsigned = UnsignedTransaction {
+            inputs,
+            outputs: vec![utxo(keys[0], 15)],
+        };
+        PartiallySignedTransaction::new(unsigned, utxos).unwrap()
+    }
+
+    #[test]
+    fn test_merge_finalize_extract() {
+        let (alice, bob) = (PrivateKey::new_key(), PrivateKey::new_key());
+        let mut first = psbt(&[&alice, &bob]);
+        let mut second = first.clone();
+
+        assert_eq!(first.sign(&alice), 1);
+        assert_eq!(first.sign(&alice), 0);
+        assert!(!first.is_complete());
+        assert!(matches!(first.finalize(), Err(BtcError::IncompleteSignatures)));
+        assert!(first.extract().is_err());
+
+        assert_eq!(second.sign(&bob), 1);
+        first.merge(second).unwrap();
+        assert!(first.is_complete());
+        first.finalize().unwrap();
+
+        let transaction = first.extract().unwrap();
+        for input in &transaction.inputs {
+            assert!(input.signature.verify(&input.prev_transaction_output_hash, &input.public_key));
+        }
+    }
+
+    #[test]
+    fn test_add_signature_checks_it() {
+        let (alice, bob) = (PrivateKey::new_key(), PrivateKey::new_key());
+        let mut psbt = psbt(&[&alice, &bob]);
+        let hash = psbt.unsigned.inputs[1].prev_transaction_output_hash;
+        let wrong = Signature::sign_output(&hash, &alice);
+        assert_eq!(psbt.add_signature(&alice.public_key(), &wrong), 0);
+        assert_eq!(psbt.add_signature(&bob.public_key(), &Signature::sign_output(&hash, &bob)), 1);
+        assert!(!psbt.is_complete());
+    }
+
+    #[test]
+    fn test_merge_rejects_other_transactions() {
+        let key = PrivateKey::new_key();
+        let mut first = psbt(&[&key]);
+        assert!(matches!(first.merge(psbt(&[&key])), Err(BtcError::PsbtMismatch)));
+    }
+
+    #[test]
+    fn test_utxos_must_match_inputs() {
+        let key = PrivateKey::new_key();
+        let signed = psbt(&[&key]);
+        let result = PartiallySignedTransaction::new(signed.unsigned, vec![utxo(&key, 10)]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_multisig_input() {
+        use crate::multisig::MultisigPolicy;
+        let keys: Vec<_> = (0..3).map(|_| PrivateKey::new_key()).collect();
+        let policy =
+            MultisigPolicy::new(2, keys.iter().map(|key| key.public_key()).collect()).unwrap();
+        let utxo = TransactionOutput {
+            value: 10,
+            unique_id: Uuid::new_v4(),
+            address: policy.to_address(),
+        };
+        let unsigned = UnsignedTransaction {
+            inputs: vec![UnsignedInput {
+                prev_transaction_output_hash: utxo.hash(),
+                public_key: policy.public_keys()[0].clone(),
+                value: 10,
+                multisig: Some(policy),
+            }],
+            outputs: vec![],
+        };
+        let mut first = PartiallySignedTransaction::new(unsigned, vec![utxo.clone()]).unwrap();
+        let mut second = first.clone();
+        assert_eq!(first.sign(&keys[0]), 1);
+        assert!(!first.is_complete());
+        assert_eq!(second.sign(&keys[2]), 1);
+        first.merge(second).unwrap();
+        first.finalize().unwrap();
+
+        let transaction = first.extract().unwrap();
+        let input = &transaction.inputs[0];
+        assert_eq!(input.cosignatures.len(), 1);
+        input.verify(&utxo, true).unwrap();
+        let mut partial = input.clone();
+        partial.cosignatures.clear();
+        assert!(partial.verify(&utxo, true).is_err());
+    }
+}
@@ -0,0 +1,182 @@
+use super::{Blockchain, TransactionOutput};
+use crate::crypto::{PrivateKey, PublicKey, Signature};
+use crate::sha256::Hash;
+use crate::util::Saveable;
+use crate::U256;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
+
+/// The point a chain was started from instead of genesis
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ChainBase {
+    /// Number of blocks covered by the base
+    pub height: u64,
+    /// Hash of the block at `height - 1`
+    pub block_hash: Hash,
+    /// Target in effect for the block at `height`
+    pub target: U256,
+    /// Timestamps of the last blocks below `height`, oldest first,
+    /// so difficulty adjustment keeps working without their bodies
+    pub timestamps: Vec<DateTime<Utc>>,
+    /// Set when the blocks below were pruned locally rather than never
+    /// downloaded, in which case they are not backfilled
+    #[serde(default)]
+    pub pruned: bool,
+    /// Work of the chain up to `height`. Left out when zero, so bases
+    /// signed before it existed still verify.
+    #[serde(default, skip_serializing_if = "U256::is_zero")]
+    pub chain_work: U256,
+}
+
+/// A signed copy of the UTXO set at a given block, used to start
+/// a node without replaying the chain from genesis
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Snapshot {
+    pub base: ChainBase,
+    /// Unspent outputs with the height of the block that created them
+    pub utxos: Vec<(Hash, u64, TransactionOutput)>,
+    pub signer: PublicKey,
+    pub signature: Signature,
+}
+
+impl Snapshot {
+    /// Snapshot the current state of a chain, signed with the given
+    /// key. Returns None for an empty chain.
+    pub fn create(blockchain: &Blockchain, private_key: &PrivateKey) -> Option<Self> {
+        let height = blockchain.block_height();
+        let block_hash = blockchain.tip_hash()?;
+        let window = (crate::DIFFICULTY_UPDATE_INTERVAL + crate::MEDIAN_TIME_SPAN).min(height);
+        let timestamps = (height - window..height)
+            .map(|h| blockchain.timestamp_at(h))
+            .collect::<Option<Vec<_>>>()?;
+        let base = ChainBase {
+            height,
+            block_hash,
+            target: blockchain.target(),
+            timestamps,
+            pruned: false,
+            chain_work: blockchain.chain_work(),
+        };
+
+        let mut utxos: Vec<_> = blockchain
+            .utxos()
+            .iter()
+            .map(|(hash, (_, height, output))| (*hash, *height, output.clone()))
+            .collect();
+        // sort so equal UTXO sets always produce the same content hash
+        utxos.sort_by_key(|(hash, _, _)| hash.as_bytes());
+
+        let content_hash = Self::hash_content(&base, &utxos);
+        Some(Snapshot {
+            base,
+            utxos,
+            signer: private_key.public_key(),
+            signature: Signature::sign_output(&content_hash, private_key),
+        })
+    }
+
+    fn hash_content(base: &ChainBase, utxos: &[(Hash, u64, TransactionOutput)]) -> Hash {
+        Hash::hash(&(base, utxos))
+    }
+
+    /// Hash of the signed content
+    pub fn content_hash(&self) -> Hash {
+        Self::hash_content(&self.base, &self.utxos)
+    }
+
+    /// Check that the snapshot was signed by the trusted key
+    pub fn verify(&self, trusted_key: &PublicKey) -> bool {
+        self.signer == *trusted_key && self.signature.verify(&self.content_hash(), trusted_key)
+    }
+}
+
+impl Saveable for Snapshot {
+    fn load<I: Read>(reader: I) -> IoResult<Self> {
+        ciborium::de::from_reader(reader)
+            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to deserialize snapshot"))
+    }
+
+    fn save<O: Write>(&self, writer: O) -> IoResult<()> {
+        ciborium::ser::into_writer(self, writer)
+            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to serialize snapshot"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Block, BlockHeader, Transaction};
+    use crate::util::MerkleRoot;
+    use uuid::Uuid;
+
+    fn genesis(address: String) -> Block {
+        let transactions = vec![Transaction::new(
+            vec![],
+            vec![TransactionOutput {
+                value: crate::INITIAL_REWARD * 10u64.pow(8),
+                unique_id: Uuid::new_v4(),
+                address,
+            }],
+        )];
+        Block::new(
+            BlockHeader::new(
+                Utc::now(),
+                0,
+                Hash::zero(),
+                MerkleRoot::calculate(&transactions),
+                crate::MIN_TARGET,
+            ),
+            transactions,
+        )
+    }
+
+    #[test]
+    fn test_snapshot_signature() {
+        let key = PrivateKey::new_key();
+        let mut blockchain = Blockchain::new();
+        blockchain.add_block(genesis(key.public_key().to_address())).unwrap();
+        blockchain.rebuild_utxos();
+
+        let snapshot = Snapshot::create(&blockchain, &key).unwrap();
+        assert!(snapshot.verify(&key.public_key()));
+        assert!(!snapshot.verify(&PrivateKey::new_key().public_key()));
+
+        let mut tampered = snapshot.clone();
+        tampered.utxos[0].2.value += 1;
+        assert!(!tampered.verify(&key.public_key()));
+    }
+
+    #[test]
+    fn test_snapshot_backfill() {
+        let key = PrivateKey::new_key();
+        let block = genesis(key.public_key().to_address());
+        let mut blockchain = Blockchain::new();
+        blockchain.add_block(block.clone()).unwrap();
+        blockchain.rebuild_utxos();
+        let coinbase = block.transactions[0].hash();
+        assert_eq!(blockchain.height_of(&block.hash()), Some(0));
+        assert_eq!(blockchain.transaction_by_id(&coinbase), Some((0, 0)));
+        assert!(Snapshot::create(&Blockchain::new(), &key).is_none());
+        let snapshot = Snapshot::create(&blockchain, &key).unwrap();
+
+        let mut restored = Blockchain::from_snapshot(snapshot);
+        assert_eq!(restored.block_height(), 1);
+        assert_eq!(restored.tip_hash(), Some(block.hash()));
+        assert_eq!(restored.utxos().len(), 1);
+        // creation heights survive the snapshot
+        let output = block.transactions[0].outputs[0].hash();
+        assert_eq!(restored.utxo_confirmations(&output), Some(1));
+        assert_eq!(restored.confirmations(restored.block_height()), 0);
+        assert_eq!(restored.next_backfill_height(), Some(0));
+        assert!(restored.block_by_hash(&block.hash()).is_none());
+
+        assert!(restored.backfill_block(block.clone()).unwrap());
+        assert!(restored.base().is_none());
+        assert_eq!(restored.next_backfill_height(), None);
+        assert_eq!(restored.block_height(), 1);
+        assert_eq!(restored.blocks().count(), 1);
+        assert_eq!(restored.block_by_hash(&block.hash()).map(Block::hash), Some(block.hash()));
+        assert_eq!(restored.transaction_by_id(&coinbase), Some((0, 0)));
+    }
+}
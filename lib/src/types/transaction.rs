@@ -1,8 +1,17 @@
 use serde::{Deserialize, Serialize};
+use crate::address::Address;
 use crate::sha256::Hash;
-use crate::crypto::{PublicKey, Signature};
+use crate::crypto::{PrivateKey, PublicKey, Signature};
+use crate::encoding::Encode;
+use crate::error::{BtcError, Result};
+use crate::multisig::MultisigPolicy;
+#[cfg(feature = "std")]
 use crate::util::Saveable;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
 use uuid::Uuid;
+#[cfg(feature = "std")]
 use std::io::{Read, Write, Result as IoResult, Error as IoError, ErrorKind as IoErrorKind};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -15,11 +24,18 @@ impl Transaction {
     pub fn new(inputs: Vec<TransactionInput>, outputs: Vec<TransactionOutput>) -> Self {
         Transaction { inputs, outputs }
     }
+    /// The transaction id, a hash of its canonical encoding
     pub fn hash(&self) -> Hash {
-        Hash::hash(self)
+        Hash::hash_bytes(&self.to_bytes())
+    }
+    /// Size in bytes of the canonical encoding, what fee rates are
+    /// measured against
+    pub fn size(&self) -> usize {
+        self.to_bytes().len()
     }
 }
 
+#[cfg(feature = "std")]
 impl Saveable for Transaction {
     fn load<I: Read>(reader: I) -> IoResult<Self> {
         ciborium::de::from_reader(reader).map_err(|_| {
@@ -39,6 +55,46 @@ pub struct TransactionInput {
     pub prev_transaction_output_hash: Hash,
     pub public_key: PublicKey,
     pub signature: Signature,
+    /// Set when spending from a multisig address. `public_key` and
+    /// `signature` are then one of the signers, the others are in
+    /// `cosignatures`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub multisig: Option<MultisigPolicy>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cosignatures: Vec<(PublicKey, Signature)>,
+}
+
+impl TransactionInput {
+    /// Address of the outputs this input is able to spend
+    pub fn address(&self) -> String {
+        match &self.multisig {
+            Some(policy) => policy.to_address(),
+            None => self.public_key.to_address(),
+        }
+    }
+
+    /// Check that the input may spend `prev_output`
+    pub fn verify(&self, prev_output: &TransactionOutput, verify_signatures: bool) -> Result<()> {
+        if !Address::same(&self.address(), &prev_output.address) {
+            return Err(BtcError::InvalidSignature);
+        }
+        if !verify_signatures {
+            return Ok(());
+        }
+        let hash = &self.prev_transaction_output_hash;
+        let valid = match &self.multisig {
+            Some(policy) => {
+                let signatures = core::iter::once((&self.public_key, &self.signature))
+                    .chain(self.cosignatures.iter().map(|(key, signature)| (key, signature)));
+                policy.is_satisfied(hash, signatures)
+            }
+            None => self.cosignatures.is_empty() && self.signature.verify(hash, &self.public_key),
+        };
+        if !valid {
+            return Err(BtcError::InvalidSignature);
+        }
+        Ok(())
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -49,7 +105,124 @@ pub struct TransactionOutput {
 }
 
 impl TransactionOutput {
+    /// Hash of the canonical encoding, identifying the output in the
+    /// UTXO set
     pub fn hash(&self) -> Hash {
-        Hash::hash(self)
+        Hash::hash_bytes(&self.to_bytes())
+    }
+}
+
+/// A transaction whose inputs still have to be signed, passed to a
+/// signer that may run on another machine
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UnsignedTransaction {
+    pub inputs: Vec<UnsignedInput>,
+    pub outputs: Vec<TransactionOutput>,
+}
+
+/// An input waiting for the signature of the owner of `public_key`,
+/// or of enough keys of `multisig` when set
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UnsignedInput {
+    pub prev_transaction_output_hash: Hash,
+    pub public_key: PublicKey,
+    /// Value of the spent output, so the signer can show the fee
+    pub value: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub multisig: Option<MultisigPolicy>,
+}
+
+impl UnsignedInput {
+    /// Whether `public_key` is one of the keys that sign this input
+    pub fn is_signer(&self, public_key: &PublicKey) -> bool {
+        match &self.multisig {
+            Some(policy) => policy.contains(public_key),
+            None => self.public_key == *public_key,
+        }
+    }
+
+    /// Number of signatures the input needs
+    pub fn required_signatures(&self) -> usize {
+        self.multisig.as_ref().map_or(1, |policy| policy.threshold())
+    }
+}
+
+impl UnsignedTransaction {
+    pub fn input_value(&self) -> u64 {
+        self.inputs.iter().map(|input| input.value).sum()
+    }
+
+    pub fn output_value(&self) -> u64 {
+        self.outputs.iter().map(|output| output.value).sum()
+    }
+
+    /// Sign every input with the matching key from `keys`. Multisig
+    /// inputs are signed through a `PartiallySignedTransaction`.
+    pub fn sign(&self, keys: &[PrivateKey]) -> Result<Transaction> {
+        let inputs = self
+            .inputs
+            .iter()
+            .map(|input| {
+                if input.multisig.is_some() {
+                    return Err(BtcError::MissingSigningKey);
+                }
+                let key = keys
+                    .iter()
+                    .find(|key| key.public_key() == input.public_key)
+                    .ok_or(BtcError::MissingSigningKey)?;
+                Ok(TransactionInput {
+                    prev_transaction_output_hash: input.prev_transaction_output_hash,
+                    public_key: input.public_key.clone(),
+                    signature: Signature::sign_output(&input.prev_transaction_output_hash, key),
+                    multisig: None,
+                    cosignatures: vec![],
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Transaction::new(inputs, self.outputs.clone()))
+    }
+}
+
+#[cfg(feature = "std")]
+impl Saveable for UnsignedTransaction {
+    fn load<I: Read>(reader: I) -> IoResult<Self> {
+        ciborium::de::from_reader(reader).map_err(|_| {
+            IoError::new(IoErrorKind::InvalidData, "Failed to deserialize unsigned transaction")
+        })
+    }
+
+    fn save<O: Write>(&self, writer: O) -> IoResult<()> {
+        ciborium::ser::into_writer(self, writer).map_err(|_| {
+            IoError::new(IoErrorKind::InvalidData, "Failed to serialize unsigned transaction")
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_unsigned_transaction() {
+        let key = PrivateKey::new_key();
+        let output_hash = Hash::hash(&"spent output");
+        let unsigned = UnsignedTransaction {
+            inputs: vec![UnsignedInput {
+                prev_transaction_output_hash: output_hash,
+                public_key: key.public_key(),
+                value: 10,
+                multisig: None,
+            }],
+            outputs: vec![],
+        };
+
+        let transaction = unsigned.sign(std::slice::from_ref(&key)).unwrap();
+        let input = &transaction.inputs[0];
+        assert!(input.signature.verify(&output_hash, &key.public_key()));
+
+        assert!(matches!(
+            unsigned.sign(&[PrivateKey::new_key()]),
+            Err(BtcError::MissingSigningKey)
+        ));
     }
 }
@@ -1,9 +1,19 @@
 use super::types::Transaction;
 use crate::sha256::Hash;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
 use serde::{Deserialize, Serialize};
+#[cfg(feature = "std")]
 use std::io::{Read, Write, Result as IoResult};
+#[cfg(feature = "std")]
 use std::path::Path;
+#[cfg(feature = "std")]
 use std::fs::File;
+#[cfg(feature = "tokio")]
+use tokio::io::AsyncWriteExt;
+#[cfg(feature = "tokio")]
+use zeroize::Zeroizing;
 
 #[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
 pub struct MerkleRoot(Hash);
@@ -16,7 +26,7 @@ impl MerkleRoot {
         }
         let mut layer: Vec<Hash> = vec![];
         for transaction in transactions {
-            layer.push(Hash::hash(transaction));
+            layer.push(transaction.hash());
         }
         while layer.len() > 1 {
             let mut new_layer = vec![];
@@ -30,8 +40,74 @@ impl MerkleRoot {
         }
         MerkleRoot(layer[0])
     }
+
+    pub fn hash(&self) -> Hash {
+        self.0
+    }
+
+    /// Prove the transaction at `index` is part of the root calculated
+    /// from `transactions`
+    pub fn proof(transactions: &[Transaction], index: usize) -> Option<MerkleProof> {
+        if index >= transactions.len() {
+            return None;
+        }
+        let mut layer: Vec<Hash> = transactions.iter().map(|tx| tx.hash()).collect();
+        let mut position = index;
+        let mut branch = vec![];
+        while layer.len() > 1 {
+            // the sibling, or the node itself when it has none
+            let sibling = layer.get(position ^ 1).unwrap_or(&layer[position]);
+            branch.push(*sibling);
+            layer = layer
+                .chunks(2)
+                .map(|pair| Hash::hash(&[pair[0], *pair.get(1).unwrap_or(&pair[0])]))
+                .collect();
+            position /= 2;
+        }
+        Some(MerkleProof { index, branch })
+    }
+
+    pub(crate) fn from_hash(hash: Hash) -> Self {
+        MerkleRoot(hash)
+    }
+}
+
+/// The hashes linking a transaction to a merkle root: its sibling at
+/// every level of the tree, from the bottom
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MerkleProof {
+    /// Position of the transaction in the block
+    pub index: usize,
+    pub branch: Vec<Hash>,
 }
 
+impl MerkleProof {
+    /// Root of the tree containing `txid` at this proof's position
+    pub fn root(&self, txid: Hash) -> MerkleRoot {
+        let mut hash = txid;
+        let mut position = self.index;
+        for sibling in &self.branch {
+            hash = match position % 2 {
+                0 => Hash::hash(&[hash, *sibling]),
+                _ => Hash::hash(&[*sibling, hash]),
+            };
+            position /= 2;
+        }
+        MerkleRoot(hash)
+    }
+
+    pub fn verify(&self, txid: Hash, root: &MerkleRoot) -> bool {
+        self.root(txid) == *root
+    }
+}
+
+impl fmt::Display for MerkleRoot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[cfg(feature = "std")]
 pub trait Saveable
 where
     Self: Sized,
@@ -46,4 +122,150 @@ where
         let file = File::open(&path)?;
         Self::load(file)
     }
+    /// Save to a file without blocking the runtime. Unlike
+    /// `save_to_file`, a crash mid-write leaves the old file intact,
+    /// see `write_atomic_async`.
+    #[cfg(feature = "tokio")]
+    fn save_to_file_async<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> impl Future<Output = IoResult<()>> + Send {
+        // serialized up front so `self` isn't borrowed across awaits,
+        // wiped once written as it may hold a private key
+        let mut bytes = Zeroizing::new(Vec::new());
+        let saved = self.save(&mut *bytes);
+        let path = path.as_ref().to_path_buf();
+        async move {
+            saved?;
+            write_atomic_async(&path, &bytes).await
+        }
+    }
+}
+
+/// Write a file by writing and syncing a temporary sibling, then
+/// renaming it over the target, so readers see either the old or
+/// the new content
+#[cfg(feature = "tokio")]
+pub async fn write_atomic_async(path: &Path, contents: &[u8]) -> IoResult<()> {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+    let mut file = tokio::fs::File::create(&tmp_path).await?;
+    file.write_all(contents).await?;
+    file.sync_all().await?;
+    tokio::fs::rename(&tmp_path, path).await
+}
+
+/// How the print tools show a transaction or block
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PrintFormat {
+    /// Rust's Debug formatting
+    Debug,
+    Json,
+    Toml,
+}
+
+#[cfg(feature = "std")]
+impl core::str::FromStr for PrintFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "debug" => Ok(PrintFormat::Debug),
+            "json" => Ok(PrintFormat::Json),
+            "toml" => Ok(PrintFormat::Toml),
+            _ => Err(format!("unknown format {s}, expected json, toml or debug")),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl PrintFormat {
+    /// Render `value`, on one line if `compact`. Hashes are written in
+    /// hex by JSON and TOML.
+    pub fn render<T: Serialize + fmt::Debug>(self, value: &T, compact: bool) -> IoResult<String> {
+        match (self, compact) {
+            (PrintFormat::Debug, false) => Ok(format!("{value:#?}")),
+            (PrintFormat::Debug, true) => Ok(format!("{value:?}")),
+            (PrintFormat::Json, false) => Ok(serde_json::to_string_pretty(value)?),
+            (PrintFormat::Json, true) => Ok(serde_json::to_string(value)?),
+            (PrintFormat::Toml, false) => toml::to_string_pretty(value).map_err(std::io::Error::other),
+            (PrintFormat::Toml, true) => toml::to_string(value).map_err(std::io::Error::other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::PrivateKey;
+    use crate::types::TransactionOutput;
+
+    fn transactions(count: u64) -> Vec<Transaction> {
+        (0..count)
+            .map(|value| {
+                Transaction::new(
+                    vec![],
+                    vec![TransactionOutput {
+                        value,
+                        unique_id: uuid::Uuid::new_v4(),
+                        address: PrivateKey::new_key().public_key().to_address(),
+                    }],
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_merkle_proofs() {
+        for count in [1, 2, 5, 8] {
+            let transactions = transactions(count);
+            let root = MerkleRoot::calculate(&transactions);
+            for (index, transaction) in transactions.iter().enumerate() {
+                let proof = MerkleRoot::proof(&transactions, index).unwrap();
+                assert!(proof.verify(transaction.hash(), &root));
+                // proves nothing about another transaction
+                let other = &transactions[(index + 1) % transactions.len()];
+                assert_eq!(proof.verify(other.hash(), &root), count == 1);
+            }
+            assert!(MerkleRoot::proof(&transactions, count as usize).is_none());
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_save_to_file_async_replaces_file() {
+        let dir = std::env::temp_dir().join(format!("btclib-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("key.cbor");
+        std::fs::write(&path, b"old").unwrap();
+
+        let key = PrivateKey::new_key();
+        key.save_to_file_async(&path).await.unwrap();
+        let loaded = PrivateKey::load_from_file(&path).unwrap();
+        assert_eq!(loaded.public_key(), key.public_key());
+        // the temporary file was renamed away
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_print_formats() {
+        let transaction = transactions(1).remove(0);
+        let json = PrintFormat::Json.render(&transaction, true).unwrap();
+        assert!(!json.contains('\n'));
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        let unique_id = transaction.outputs[0].unique_id.to_string();
+        assert_eq!(value["outputs"][0]["unique_id"], unique_id.as_str());
+        // hashes are hex and read back as the same hash
+        let hash = serde_json::to_value(transaction.hash()).unwrap();
+        assert_eq!(hash, transaction.hash().to_string().as_str());
+        assert_eq!(serde_json::from_value::<Hash>(hash).unwrap(), transaction.hash());
+
+        let toml = PrintFormat::Toml.render(&transaction, false).unwrap();
+        assert!(toml.contains(&unique_id));
+        assert!("yaml".parse::<PrintFormat>().is_err());
+    }
 }
@@ -1,19 +1,20 @@
 use anyhow::{Result, anyhow};
 use btclib::crypto::PublicKey;
+use btclib::mining::{Stats, Work};
 use btclib::network::{Envelope, Message};
+use btclib::params::Network;
+use btclib::sha256::Hash;
 use btclib::types::Block;
 use btclib::util::Saveable;
 use clap::Parser;
-use std::sync::{
-    Arc,
-    atomic::{AtomicBool, Ordering},
-};
-use std::thread;
+use std::sync::Arc;
 use tokio::net::TcpStream;
 use tokio::sync::Mutex;
 use tokio::time::{Duration, interval};
 use uuid::Uuid;
 
+mod stats;
+
 const DEFAULT_TTL: u8 = 8;
 
 #[derive(Parser)]
@@ -23,34 +24,57 @@ struct Cli {
     address: String,
     #[arg(short, long)]
     public_key_file: String,
+    /// Network the node runs on, mainnet or testnet
+    #[arg(long, default_value = "mainnet")]
+    network: Network,
+    /// Number of mining threads, each mining under its own extranonce
+    #[arg(short, long, default_value_t = 1)]
+    threads: usize,
+    /// Seconds between the stats logged
+    #[arg(long, default_value_t = 30)]
+    stats_interval: u64,
+    /// Serve the stats as JSON on http://<ADDR>/stats
+    #[arg(long, value_name = "ADDR")]
+    stats_addr: Option<String>,
 }
 struct Miner {
     node_id: String,
-    public_key: PublicKey,
+    // address of the public key on the node's network
+    reward_address: String,
     stream: Mutex<TcpStream>,
-    current_template: Arc<std::sync::Mutex<Option<Block>>>,
-    mining: Arc<AtomicBool>,
+    work: Arc<Work>,
     mined_block_sender: flume::Sender<Block>,
     mined_block_receiver: flume::Receiver<Block>,
+    stats: Arc<Stats>,
+    // the last block submitted, until a template tells whether the
+    // node built on it
+    submitted: std::sync::Mutex<Option<Hash>>,
 }
-// TODO multithreaded mining
+
 impl Miner {
-    async fn new(address: String, public_key: PublicKey) -> Result<Self> {
+    async fn new(address: String, reward_address: String, stats: Arc<Stats>) -> Result<Self> {
         let stream = TcpStream::connect(&address).await?;
         let (mined_block_sender, mined_block_receiver) = flume::unbounded();
         Ok(Self {
             node_id: Uuid::new_v4().to_string(),
-            public_key,
+            reward_address,
             stream: Mutex::new(stream),
-            current_template: Arc::new(std::sync::Mutex::new(None)),
-            mining: Arc::new(AtomicBool::new(false)),
+            work: Arc::new(Work::default()),
             mined_block_sender,
             mined_block_receiver,
+            stats,
+            submitted: std::sync::Mutex::new(None),
         })
     }
 
-    async fn run(&self) -> Result<()> {
-        self.spawn_mining_thread();
+    async fn run(&self, threads: usize) -> Result<()> {
+        for thread in 0..threads {
+            let sender = self.mined_block_sender.clone();
+            self.work.spawn_thread(thread, self.stats.clone(), move |block| {
+                println!("Block mined by thread {}: {}", thread, block.hash());
+                sender.send(block).expect("Failed to send mined block");
+            });
+        }
         let mut template_interval = interval(Duration::from_secs(5));
         loop {
             let receiver_clone = self.mined_block_receiver.clone();
@@ -65,30 +89,8 @@ impl Miner {
         }
     }
 
-    fn spawn_mining_thread(&self) -> thread::JoinHandle<()> {
-        let template = self.current_template.clone();
-        let mining = self.mining.clone();
-        let sender = self.mined_block_sender.clone();
-        thread::spawn(move || {
-            loop {
-                if mining.load(Ordering::Relaxed) {
-                    if let Some(mut block) = template.lock().unwrap().clone() {
-                        println!("Mining block with target: {}", block.header.target);
-                        if block.header.mine(2_000_000) {
-                            println!("Block mined: {}", block.hash());
-                            sender.send(block).expect("Failed to send mined block");
-
-                            mining.store(false, Ordering::Relaxed);
-                        }
-                    }
-                }
-                thread::yield_now();
-            }
-        })
-    }
-
     async fn fetch_and_validate_template(&self) -> Result<()> {
-        if !self.mining.load(Ordering::Relaxed) {
+        if !self.work.is_mining() {
             self.fetch_template().await?;
         } else {
             self.validate_template().await?;
@@ -98,15 +100,24 @@ impl Miner {
 
     async fn fetch_template(&self) -> Result<()> {
         println!("Fetching new template");
-        let message = Message::FetchTemplate(self.public_key.to_address());
+        let message = Message::FetchTemplate(self.reward_address.clone());
         match self.send_and_receive(message).await? {
             Message::Template(template) => {
                 println!(
                     "Received new template with target: {}",
                     template.header.target
                 );
-                *self.current_template.lock().unwrap() = Some(template);
-                self.mining.store(true, Ordering::Relaxed);
+                // the node builds on our block only if it took it
+                if let Some(submitted) = self.submitted.lock().unwrap().take() {
+                    if template.header.prev_block_hash == submitted {
+                        self.stats.block_accepted();
+                    } else {
+                        println!("Block {} went stale", submitted);
+                        self.stats.block_stale();
+                    }
+                }
+                self.work.set_template(template);
+                self.stats.template_fetched();
                 Ok(())
             }
             _ => Err(anyhow!(
@@ -116,13 +127,13 @@ impl Miner {
     }
 
     async fn validate_template(&self) -> Result<()> {
-        if let Some(template) = self.current_template.lock().unwrap().clone() {
+        if let Some(template) = self.work.template() {
             let message = Message::ValidateTemplate(template);
             match self.send_and_receive(message).await? {
                 Message::TemplateValidity(valid) => {
                     if !valid {
                         println!("Current template is no longer valid");
-                        self.mining.store(false, Ordering::Relaxed);
+                        self.work.stop();
                     } else {
                         println!("Current template is still valid");
                     }
@@ -139,9 +150,10 @@ impl Miner {
 
     async fn submit_block(&self, block: Block) -> Result<()> {
         println!("Submitting mined block");
+        *self.submitted.lock().unwrap() = Some(block.hash());
         let message = Message::SubmitTemplate(block);
         self.send_only(message).await?;
-        self.mining.store(false, Ordering::Relaxed);
+        self.work.stop();
         Ok(())
     }
 
@@ -166,6 +178,18 @@ async fn main() -> Result<()> {
     let cli = Cli::parse();
     let public_key = PublicKey::load_from_file(&cli.public_key_file)
         .map_err(|e| anyhow!("Error reading public key: {}", e))?;
-    let miner = Miner::new(cli.address, public_key).await?;
-    miner.run().await
+    let reward_address = public_key.to_address_for(&cli.network.params());
+    let threads = cli.threads.max(1);
+    let stats = Arc::new(Stats::new(threads));
+    tokio::spawn(stats::log_reports(stats.clone(), Duration::from_secs(cli.stats_interval.max(1))));
+    if let Some(addr) = cli.stats_addr {
+        let stats = stats.clone();
+        tokio::spawn(async move {
+            if let Err(e) = stats::serve(stats, &addr).await {
+                eprintln!("Stats endpoint failed: {}", e);
+            }
+        });
+    }
+    let miner = Miner::new(cli.address, reward_address, stats).await?;
+    miner.run(threads).await
 }
@@ -0,0 +1,36 @@
+//! Hashrate and block statistics, logged periodically and served as
+//! JSON on an optional local HTTP endpoint
+use anyhow::Result;
+use axum::{Json, Router, extract::State, routing::get};
+use btclib::mining::{Report, Stats};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+
+/// Log a report every `interval`
+pub async fn log_reports(stats: Arc<Stats>, interval: Duration) {
+    let mut previous = vec![];
+    let mut ticker = tokio::time::interval(interval);
+    // the first tick is immediate, there is nothing to report yet
+    ticker.tick().await;
+    let mut last = Instant::now();
+    loop {
+        ticker.tick().await;
+        let report = stats.report(&mut previous, last.elapsed());
+        last = Instant::now();
+        println!("{}", report.summary());
+    }
+}
+
+/// Serve the stats as JSON on `GET /stats` until the listener fails
+pub async fn serve(stats: Arc<Stats>, addr: &str) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    println!("Stats on http://{}/stats", addr);
+    let router = Router::new().route("/stats", get(stats_json)).with_state(stats);
+    axum::serve(listener, router).await?;
+    Ok(())
+}
+
+async fn stats_json(State(stats): State<Arc<Stats>>) -> Json<Report> {
+    Json(stats.current())
+}
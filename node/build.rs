@@ -0,0 +1,8 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // use the vendored protoc so no system install is needed
+    let protoc = protoc_bin_vendored::protoc_bin_path()?;
+    // SAFETY: build scripts are single threaded
+    unsafe { std::env::set_var("PROTOC", protoc) };
+    tonic_prost_build::compile_protos("proto/grapheno.proto")?;
+    Ok(())
+}
@@ -0,0 +1,79 @@
+//! Which hosts may connect to the node, by address range
+use ipnet::IpNet;
+use std::net::IpAddr;
+
+/// Allowed and denied address ranges. A denied address is turned away
+/// even if also allowed; with no allowed ranges, everyone else may
+/// connect.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AccessList {
+    pub allow: Vec<IpNet>,
+    pub deny: Vec<IpNet>,
+}
+
+impl AccessList {
+    pub fn admits(&self, ip: IpAddr) -> bool {
+        // IPv4 peers on a dual stack socket show up as mapped addresses
+        let ip = ip.to_canonical();
+        if self.deny.iter().any(|net| net.contains(&ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip))
+    }
+
+    /// Add a range to the allowed or denied ones, returning false if
+    /// it was there already
+    pub fn add(&mut self, net: IpNet, deny: bool) -> bool {
+        let list = if deny { &mut self.deny } else { &mut self.allow };
+        let net = net.trunc();
+        if list.contains(&net) {
+            return false;
+        }
+        list.push(net);
+        true
+    }
+
+    /// Remove a range from the allowed or denied ones, returning false
+    /// if it wasn't there
+    pub fn remove(&mut self, net: IpNet, deny: bool) -> bool {
+        let list = if deny { &mut self.deny } else { &mut self.allow };
+        let net = net.trunc();
+        let before = list.len();
+        list.retain(|entry| *entry != net);
+        list.len() < before
+    }
+}
+
+/// A range as `10.0.0.0/8`, or a single address
+pub fn parse_net(s: &str) -> Result<IpNet, String> {
+    s.parse::<IpNet>()
+        .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("{} is not an address or CIDR range", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_deny_wins_over_allow() {
+        let mut access = AccessList::default();
+        assert!(access.admits(ip("203.0.113.7")));
+        access.add(parse_net("10.0.0.0/8").unwrap(), false);
+        access.add(parse_net("10.1.2.3").unwrap(), true);
+        assert!(access.admits(ip("10.200.0.1")));
+        assert!(!access.admits(ip("10.1.2.3")));
+        assert!(!access.admits(ip("203.0.113.7")));
+        assert!(!access.admits(ip("::ffff:10.1.2.3")));
+
+        // ranges are kept by their network address
+        assert!(!access.add(parse_net("10.9.9.9/8").unwrap(), false));
+        assert!(access.remove(parse_net("10.1.2.3/32").unwrap(), true));
+        assert!(access.admits(ip("10.1.2.3")));
+        assert!(parse_net("10.0.0.0/33").is_err());
+    }
+}
@@ -0,0 +1,175 @@
+//! Addresses of nodes to connect to, from the command line, DNS seeds
+//! and, on seed nodes, crawling the network
+use crate::handler::DEFAULT_TTL;
+use anyhow::{Result, anyhow};
+use btclib::network::{Envelope, Message, PROTOCOL_VERSION, Services, VersionInfo};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::net::{TcpStream, lookup_host};
+use tracing::{debug, warn};
+
+/// Port of seeds given without one
+pub const DEFAULT_PORT: u16 = 9000;
+/// How long a node stays on the list served by seed nodes after it
+/// last answered a probe
+pub const GOOD_FOR: Duration = Duration::from_secs(30 * 60);
+// failed probes in a row before an address is forgotten
+const MAX_FAILURES: u32 = 3;
+const MAX_ADDRESSES: usize = 1000;
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+struct Entry {
+    last_good: Option<Instant>,
+    height: u64,
+    failures: u32,
+}
+
+#[derive(Default)]
+pub struct AddressBook {
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+impl AddressBook {
+    /// Remember an address, returning false if it was known already or
+    /// the book is full
+    pub fn add(&self, addr: String) -> bool {
+        let mut entries = self.entries.lock().expect("address book lock");
+        if entries.len() >= MAX_ADDRESSES || entries.contains_key(&addr) {
+            return false;
+        }
+        entries.insert(addr, Entry::default());
+        true
+    }
+
+    /// Forget an address, returning false if it wasn't known
+    pub fn remove(&self, addr: &str) -> bool {
+        self.entries.lock().expect("address book lock").remove(addr).is_some()
+    }
+
+    pub fn addresses(&self) -> Vec<String> {
+        let mut addresses: Vec<String> = self.entries.lock().expect("address book lock").keys().cloned().collect();
+        addresses.sort();
+        addresses
+    }
+
+    pub fn mark_good(&self, addr: &str, height: u64) {
+        if let Some(entry) = self.entries.lock().expect("address book lock").get_mut(addr) {
+            entry.last_good = Some(Instant::now());
+            entry.height = height;
+            entry.failures = 0;
+        }
+    }
+
+    /// Count a failed probe, forgetting the address after a few in a row
+    pub fn mark_failed(&self, addr: &str) {
+        let mut entries = self.entries.lock().expect("address book lock");
+        if let Some(entry) = entries.get_mut(addr) {
+            entry.failures += 1;
+            if entry.failures >= MAX_FAILURES {
+                debug!("forgetting {addr} after {} failed probes", entry.failures);
+                entries.remove(addr);
+            }
+        }
+    }
+
+    /// Up to `max` addresses that answered a probe within `GOOD_FOR`,
+    /// the highest chains first
+    pub fn good(&self, max: usize) -> Vec<String> {
+        let entries = self.entries.lock().expect("address book lock");
+        let mut good: Vec<(&String, &Entry)> = entries
+            .iter()
+            .filter(|(_, entry)| entry.last_good.is_some_and(|at| at.elapsed() < GOOD_FOR))
+            .collect();
+        good.sort_by(|a, b| b.1.height.cmp(&a.1.height).then_with(|| a.0.cmp(b.0)));
+        good.into_iter().take(max).map(|(addr, _)| addr.clone()).collect()
+    }
+}
+
+/// Resolve DNS seeds to the addresses of the nodes they list, skipping
+/// seeds that don't resolve
+pub async fn resolve_seeds(seeds: &[String]) -> Vec<String> {
+    let mut addresses = vec![];
+    for seed in seeds {
+        let has_port = seed.rsplit_once(':').is_some_and(|(_, port)| port.parse::<u16>().is_ok());
+        let target = if has_port {
+            seed.clone()
+        } else {
+            format!("{seed}:{DEFAULT_PORT}")
+        };
+        match lookup_host(target).await {
+            Ok(found) => {
+                let before = addresses.len();
+                addresses.extend(found.map(|addr| addr.to_string()));
+                debug!("seed {seed} listed {} nodes", addresses.len() - before);
+            }
+            Err(e) => warn!("failed to resolve seed {seed}: {e}"),
+        }
+    }
+    addresses
+}
+
+/// Shake hands with a node over a connection of its own and ask for
+/// the nodes it knows. Returns the version it announced and the
+/// addresses it listed.
+pub async fn probe(addr: &str, self_id: &str) -> Result<(VersionInfo, Vec<String>)> {
+    let exchange = async {
+        let mut stream = TcpStream::connect(addr).await?;
+        let version = VersionInfo {
+            protocol_version: PROTOCOL_VERSION,
+            height: 0,
+            lowest_block: 0,
+            services: Services::NONE,
+            challenge: None,
+            utxo_set_hash: None,
+        };
+        for msg in [Message::Version(version), Message::DiscoverNodes] {
+            Envelope::new(self_id.to_string(), DEFAULT_TTL, msg)
+                .send_async(&mut stream)
+                .await?;
+        }
+        let (mut version, mut nodes) = (None, None);
+        loop {
+            match Envelope::receive_async(&mut stream).await?.msg {
+                Message::Version(announced) => version = Some(announced),
+                Message::NodeList(listed) => nodes = Some(listed),
+                _ => {}
+            }
+            if let (Some(version), Some(nodes)) = (&version, &nodes) {
+                return Ok::<_, anyhow::Error>((version.clone(), nodes.clone()));
+            }
+        }
+    };
+    tokio::time::timeout(PROBE_TIMEOUT, exchange)
+        .await
+        .map_err(|_| anyhow!("{addr} didn't answer in time"))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_good_addresses() {
+        let book = AddressBook::default();
+        assert!(book.add("10.0.0.1:9000".to_string()));
+        assert!(!book.add("10.0.0.1:9000".to_string()));
+        book.add("10.0.0.2:9000".to_string());
+        book.add("10.0.0.3:9000".to_string());
+        assert!(book.good(10).is_empty());
+
+        book.mark_good("10.0.0.1:9000", 5);
+        book.mark_good("10.0.0.2:9000", 7);
+        assert_eq!(book.good(10), ["10.0.0.2:9000", "10.0.0.1:9000"]);
+        assert_eq!(book.good(1), ["10.0.0.2:9000"]);
+
+        for _ in 0..MAX_FAILURES {
+            book.mark_failed("10.0.0.3:9000");
+        }
+        assert_eq!(book.addresses(), ["10.0.0.1:9000", "10.0.0.2:9000"]);
+        assert!(book.remove("10.0.0.1:9000"));
+        assert!(!book.remove("10.0.0.1:9000"));
+        assert_eq!(book.good(10), ["10.0.0.2:9000"]);
+    }
+}
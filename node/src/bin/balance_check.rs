@@ -0,0 +1,196 @@
+//! Prints the balances and unspent outputs a node holds for some
+//! addresses, as the node sees them rather than a wallet.
+use anyhow::{Context, Result, bail};
+use argh::FromArgs;
+use btclib::address::Address;
+use btclib::amount::format_btc;
+use btclib::crypto::PublicKey;
+use btclib::network::Message;
+use btclib::params::Network;
+use btclib::types::TransactionOutput;
+use btclib::util::Saveable;
+use node::client::NodeClient;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+#[derive(FromArgs)]
+/// Show what a node knows about the balance of addresses
+struct Args {
+    #[argh(positional)]
+    /// addresses to check
+    addresses: Vec<String>,
+    #[argh(option)]
+    /// public key PEM file whose address to check, may be repeated
+    pubkey: Vec<PathBuf>,
+    #[argh(option, default = "String::from(\"127.0.0.1:9000\")")]
+    /// address of the node
+    node: String,
+    #[argh(option, default = "Network::Mainnet")]
+    /// network of the addresses of public keys
+    network: Network,
+    #[argh(option)]
+    /// also count what has at least this many confirmations
+    confirmations: Option<u64>,
+    #[argh(switch)]
+    /// print JSON instead of tables
+    json: bool,
+}
+
+#[derive(Serialize)]
+struct Utxo {
+    hash: String,
+    value: u64,
+    /// Spent by a transaction in the mempool
+    spending: bool,
+    /// Has the confirmations asked for, if any were
+    #[serde(skip_serializing_if = "Option::is_none")]
+    deep: Option<bool>,
+}
+
+#[derive(Serialize)]
+struct Balance {
+    address: String,
+    /// Value of all confirmed unspent outputs
+    confirmed: u64,
+    /// Part of `confirmed` spent by unconfirmed transactions
+    spending: u64,
+    /// Part of `confirmed` not spent yet
+    spendable: u64,
+    /// Part of `spendable` with the confirmations asked for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    deep: Option<u64>,
+    utxos: Vec<Utxo>,
+}
+
+impl Balance {
+    fn new(address: String, utxos: &[(TransactionOutput, bool)], deep: Option<HashSet<String>>) -> Self {
+        let mut utxos: Vec<Utxo> = utxos
+            .iter()
+            .map(|(output, spending)| {
+                let hash = output.hash().to_string();
+                Utxo {
+                    deep: deep.as_ref().map(|deep| deep.contains(&hash)),
+                    hash,
+                    value: output.value,
+                    spending: *spending,
+                }
+            })
+            .collect();
+        utxos.sort_by(|a, b| b.value.cmp(&a.value).then_with(|| a.hash.cmp(&b.hash)));
+        let confirmed = utxos.iter().map(|utxo| utxo.value).sum();
+        let spending = utxos.iter().filter(|utxo| utxo.spending).map(|utxo| utxo.value).sum();
+        let deep = deep.map(|_| {
+            utxos
+                .iter()
+                .filter(|utxo| !utxo.spending && utxo.deep == Some(true))
+                .map(|utxo| utxo.value)
+                .sum()
+        });
+        Balance {
+            address,
+            confirmed,
+            spending,
+            spendable: confirmed - spending,
+            deep,
+            utxos,
+        }
+    }
+
+    fn print(&self) {
+        println!("{}", self.address);
+        println!("  Confirmed    {:>20} BTC", format_btc(self.confirmed));
+        println!("  Spending     {:>20} BTC", format_btc(self.spending));
+        println!("  Spendable    {:>20} BTC", format_btc(self.spendable));
+        if let Some(deep) = self.deep {
+            println!("  Deep enough  {:>20} BTC", format_btc(deep));
+        }
+        for utxo in &self.utxos {
+            let mut notes = vec![];
+            if utxo.spending {
+                notes.push("spending");
+            }
+            if utxo.deep == Some(false) {
+                notes.push("too recent");
+            }
+            println!("    {:<64} {:>20} {}", utxo.hash, format_btc(utxo.value), notes.join(", "));
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args: Args = argh::from_env();
+    let mut addresses = args.addresses.clone();
+    for path in &args.pubkey {
+        let key = PublicKey::load_from_file(path).with_context(|| format!("Failed to load {}", path.display()))?;
+        addresses.push(key.to_address_for(&args.network.params()));
+    }
+    if addresses.is_empty() {
+        bail!("Give at least one address or --pubkey");
+    }
+    for address in &addresses {
+        Address::parse(address).with_context(|| format!("Invalid address {address}"))?;
+    }
+
+    let mut node = NodeClient::connect(&args.node).await?;
+    let mut balances = vec![];
+    for address in addresses {
+        let utxos = node.utxos(&address).await?;
+        // the node only filters by confirmations, so outputs missing
+        // from the filtered reply are the recent ones
+        let deep = match args.confirmations {
+            Some(confirmations) => {
+                let deep = node
+                    .request(Message::FetchUTXOs(address.clone(), Some(confirmations)), |msg| match msg {
+                        Message::UTXOs(utxos) => Some(utxos),
+                        _ => None,
+                    })
+                    .await?;
+                Some(deep.iter().map(|(output, _)| output.hash().to_string()).collect())
+            }
+            None => None,
+        };
+        balances.push(Balance::new(address, &utxos, deep));
+    }
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&balances)?);
+    } else {
+        for (index, balance) in balances.iter().enumerate() {
+            if index > 0 {
+                println!();
+            }
+            balance.print();
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn output(value: u64) -> TransactionOutput {
+        TransactionOutput {
+            value,
+            unique_id: Uuid::new_v4(),
+            address: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_balance_sums() {
+        let (a, b, c) = (output(5), output(3), output(2));
+        let deep = HashSet::from([a.hash().to_string(), b.hash().to_string()]);
+        let balance = Balance::new(String::new(), &[(c, false), (a, false), (b, true)], Some(deep));
+        assert_eq!(balance.confirmed, 10);
+        assert_eq!(balance.spending, 3);
+        assert_eq!(balance.spendable, 7);
+        // the spent deep output doesn't count
+        assert_eq!(balance.deep, Some(5));
+        let values: Vec<u64> = balance.utxos.iter().map(|utxo| utxo.value).collect();
+        assert_eq!(values, [5, 3, 2]);
+    }
+}
@@ -0,0 +1,331 @@
+//! Generates a reproducible chain of blocks for demos and benchmarks.
+//!
+//! Everything random in the chain, keys, transfers and output ids,
+//! comes from the seed, and block timestamps are fixed, so the same
+//! seed and scenario always produce the same blocks.
+use anyhow::{Context, Result, bail};
+use argh::FromArgs;
+use btclib::crypto::PrivateKey;
+use btclib::params::Network;
+use btclib::sha256::Hash;
+use btclib::types::{
+    Block, BlockHeader, Blockchain, Transaction, TransactionOutput, UnsignedInput,
+    UnsignedTransaction,
+};
+use btclib::util::{MerkleRoot, Saveable};
+use chrono::{DateTime, Duration, Utc};
+use node::bootstrap::BlockWriter;
+use node::database::BlockchainDB;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use uuid::{Builder, Uuid};
+
+// timestamp of the genesis block, 2024-01-01 00:00:00 UTC
+const START_TIME: i64 = 1_704_067_200;
+// fees the generated transfers pay
+const MIN_FEE: u64 = 100;
+const MAX_FEE: u64 = 10_000;
+// nonces tried between progress checks
+const MINE_STEPS: usize = 1_000_000;
+
+#[derive(FromArgs)]
+/// Generate a reproducible chain of blocks mined by and paying between
+/// generated wallets
+struct Args {
+    #[argh(positional)]
+    /// bootstrap file to write, or database directory with --db
+    output: PathBuf,
+    #[argh(option, default = "0")]
+    /// seed of the keys, transfers and output ids
+    seed: u64,
+    #[argh(option, default = "100")]
+    /// number of blocks, including genesis
+    blocks: u64,
+    #[argh(option, default = "10")]
+    /// number of wallets mining and paying each other
+    wallets: usize,
+    #[argh(option, default = "5")]
+    /// transfers in each block besides the coinbase, as far as the
+    /// wallets' outputs allow
+    transactions: usize,
+    #[argh(option)]
+    /// fork as <height>:<depth>, a branch of `depth` blocks replacing
+    /// the main chain from `height`, written to <output>.fork-<height>.
+    /// May be repeated.
+    reorg: Vec<ReorgPoint>,
+    #[argh(option, default = "Network::Mainnet")]
+    /// network the addresses are for, mainnet or testnet
+    network: Network,
+    #[argh(option)]
+    /// directory to write the wallets' keys to, as wallet-<n>.priv.cbor
+    /// and wallet-<n>.pub.pem
+    keys: Option<PathBuf>,
+    #[argh(switch)]
+    /// write a node database instead of a bootstrap file
+    db: bool,
+}
+
+/// Where a fork branches off the main chain and how long it is
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct ReorgPoint {
+    height: u64,
+    depth: u64,
+}
+
+impl FromStr for ReorgPoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (height, depth) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected <height>:<depth>, got {s:?}"))?;
+        let height = height
+            .parse()
+            .map_err(|e| format!("invalid fork height {height:?}: {e}"))?;
+        let depth = depth
+            .parse()
+            .map_err(|e| format!("invalid fork depth {depth:?}: {e}"))?;
+        Ok(ReorgPoint { height, depth })
+    }
+}
+
+/// Mines blocks for a fixed set of wallets, drawing every choice from
+/// one seeded generator
+struct Generator {
+    rng: StdRng,
+    keys: Vec<PrivateKey>,
+    /// Wallet index of each address
+    owners: HashMap<String, usize>,
+    addresses: Vec<String>,
+    transactions: usize,
+}
+
+impl Generator {
+    fn new(args: &Args) -> Result<Self> {
+        let mut rng = StdRng::seed_from_u64(args.seed);
+        let params = args.network.params();
+        let mut keys = vec![];
+        for _ in 0..args.wallets {
+            let seed: [u8; 32] = rng.random();
+            keys.push(PrivateKey::from_seed(&seed).map_err(anyhow::Error::msg)?);
+        }
+        let addresses: Vec<String> = keys
+            .iter()
+            .map(|key| key.public_key().to_address_for(&params))
+            .collect();
+        let owners = addresses
+            .iter()
+            .enumerate()
+            .map(|(index, address)| (address.clone(), index))
+            .collect();
+        Ok(Generator {
+            rng,
+            keys,
+            owners,
+            addresses,
+            transactions: args.transactions.min(btclib::BLOCK_TRANSACTION_CAP - 1),
+        })
+    }
+
+    fn unique_id(&mut self) -> Uuid {
+        Builder::from_random_bytes(self.rng.random()).into_uuid()
+    }
+
+    fn output(&mut self, value: u64, wallet: usize) -> TransactionOutput {
+        TransactionOutput {
+            value,
+            unique_id: self.unique_id(),
+            address: self.addresses[wallet].clone(),
+        }
+    }
+
+    /// Transfers between the wallets spending outputs of `chain`, with
+    /// the fees they pay
+    fn transfers(&mut self, chain: &Blockchain) -> Result<(Vec<Transaction>, u64)> {
+        // sorted, as the order of the map differs between runs
+        let mut spendable: Vec<TransactionOutput> = chain
+            .utxos()
+            .values()
+            .filter(|(marked, _, output)| !marked && self.owners.contains_key(&output.address))
+            .map(|(_, _, output)| output.clone())
+            .collect();
+        spendable.sort_by_key(|output| output.hash().as_bytes());
+
+        let mut transactions = vec![];
+        let mut fees = 0;
+        while transactions.len() < self.transactions && !spendable.is_empty() {
+            let spent = spendable.swap_remove(self.rng.random_range(0..spendable.len()));
+            let owner = self.owners[&spent.address];
+            let fee = self.rng.random_range(MIN_FEE..=MAX_FEE);
+            if spent.value <= fee {
+                continue;
+            }
+            let amount = self.rng.random_range(1..=spent.value - fee);
+            let recipient = self.rng.random_range(0..self.keys.len());
+            let mut outputs = vec![self.output(amount, recipient)];
+            if spent.value - fee > amount {
+                outputs.push(self.output(spent.value - fee - amount, owner));
+            }
+            let inputs = vec![UnsignedInput {
+                prev_transaction_output_hash: spent.hash(),
+                public_key: self.keys[owner].public_key(),
+                value: spent.value,
+                multisig: None,
+            }];
+            let transaction = UnsignedTransaction { inputs, outputs }
+                .sign(std::slice::from_ref(&self.keys[owner]))
+                .context("Failed to sign transfer")?;
+            transactions.push(transaction);
+            fees += fee;
+        }
+        Ok((transactions, fees))
+    }
+
+    /// Mine the next block of `chain` and add it
+    fn mine_block(&mut self, chain: &mut Blockchain) -> Result<()> {
+        let (transfers, fees) = self.transfers(chain)?;
+        let miner = self.rng.random_range(0..self.keys.len());
+        let coinbase = Transaction::new(
+            vec![],
+            vec![self.output(chain.calculate_block_reward() + fees, miner)],
+        );
+        let mut transactions = vec![coinbase];
+        transactions.extend(transfers);
+
+        // a block every IDEAL_BLOCK_TIME keeps the target where it is
+        let height = chain.block_height() as i64;
+        let timestamp = DateTime::<Utc>::from_timestamp(START_TIME, 0).expect("valid time")
+            + Duration::seconds(height * btclib::IDEAL_BLOCK_TIME as i64);
+        let mut block = Block::new(
+            BlockHeader::new(
+                timestamp,
+                0,
+                chain.tip_hash().unwrap_or(Hash::zero()),
+                MerkleRoot::calculate(&transactions),
+                chain.target(),
+            ),
+            transactions,
+        );
+        while !block.header.mine(MINE_STEPS) {}
+        chain
+            .add_block(block)
+            .with_context(|| format!("Generated block {height} was rejected"))?;
+        chain.rebuild_utxos();
+        Ok(())
+    }
+}
+
+fn write_bootstrap(path: &Path, chain: &Blockchain) -> Result<()> {
+    let file = File::create(path)
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    let mut writer = BlockWriter::new(BufWriter::new(file))?;
+    for block in chain.blocks() {
+        writer.write_block(block)?;
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+fn fork_path(output: &Path, height: u64) -> PathBuf {
+    let mut name = output.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".fork-{height}"));
+    output.with_file_name(name)
+}
+
+fn main() -> Result<()> {
+    let args: Args = argh::from_env();
+    if args.wallets == 0 {
+        bail!("At least one wallet is needed to mine the blocks");
+    }
+    for reorg in &args.reorg {
+        // forks have to branch off a block and lose to the main chain
+        if reorg.height == 0 || reorg.height + reorg.depth >= args.blocks {
+            bail!(
+                "Fork {}:{} must start after genesis and end below height {}",
+                reorg.height,
+                reorg.depth,
+                args.blocks
+            );
+        }
+    }
+    let db = match args.db {
+        true => Some(BlockchainDB::open(&args.output, None)?),
+        false => None,
+    };
+    if let Some(db) = &db
+        && db.get_block(0)?.is_some()
+    {
+        bail!("{} already holds a chain", args.output.display());
+    }
+
+    let mut generator = Generator::new(&args)?;
+    let mut chain = Blockchain::new();
+    chain.set_params(args.network.params());
+    let mut forks = vec![];
+    for height in 0..args.blocks {
+        for reorg in args.reorg.iter().filter(|reorg| reorg.height == height) {
+            let mut fork = chain.clone();
+            for _ in 0..reorg.depth {
+                generator.mine_block(&mut fork)?;
+            }
+            forks.push((height, fork));
+        }
+        generator.mine_block(&mut chain)?;
+    }
+
+    match &db {
+        Some(db) => {
+            db.save_blockchain(&chain)?;
+            db.flush()?;
+        }
+        None => write_bootstrap(&args.output, &chain)?,
+    }
+    for (height, fork) in &forks {
+        write_bootstrap(&fork_path(&args.output, *height), fork)?;
+    }
+    if let Some(dir) = &args.keys {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+        for (index, key) in generator.keys.iter().enumerate() {
+            key.save_to_file(dir.join(format!("wallet-{index}.priv.cbor")))?;
+            key.public_key()
+                .save_to_file(dir.join(format!("wallet-{index}.pub.pem")))?;
+        }
+    }
+
+    println!(
+        "Generated {} blocks with {} transactions, tip {}",
+        chain.block_height(),
+        chain.blocks().map(|block| block.transactions.len()).sum::<usize>(),
+        chain.tip_hash().unwrap_or(Hash::zero())
+    );
+    for (height, fork) in &forks {
+        println!(
+            "Fork at height {}: {} blocks, tip {}",
+            height,
+            fork.block_height() - height,
+            fork.tip_hash().unwrap_or(Hash::zero())
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_reorg_point() {
+        assert_eq!(
+            "20:3".parse::<ReorgPoint>().unwrap(),
+            ReorgPoint { height: 20, depth: 3 }
+        );
+        assert!("20".parse::<ReorgPoint>().is_err());
+        assert!("20:x".parse::<ReorgPoint>().is_err());
+    }
+}
@@ -0,0 +1,189 @@
+//! Prints what a stopped node's database holds, without loading or
+//! replaying the chain.
+use anyhow::{Context, Result, bail};
+use argh::FromArgs;
+use btclib::amount::format_btc;
+use btclib::difficulty::{difficulty_from_target, format_difficulty};
+use btclib::sha256::Hash;
+use btclib::types::{Block, BlockHeader};
+use node::database::{Backend, BlockchainDB, DbSecret};
+use std::path::PathBuf;
+
+#[derive(FromArgs)]
+/// Inspect the database of a stopped node
+struct Args {
+    #[argh(positional)]
+    /// database directory of the node
+    db_path: PathBuf,
+    #[argh(option)]
+    /// show the block at this height
+    block: Option<u64>,
+    #[argh(option)]
+    /// show the unspent output with this hash
+    utxo: Option<Hash>,
+    #[argh(option)]
+    /// key file of an encrypted database, which otherwise takes its
+    /// passphrase from GRAPHENO_DB_PASSPHRASE
+    db_key_file: Option<PathBuf>,
+    #[argh(option, default = "Backend::Sled")]
+    /// storage engine the database was created with, sled or rocksdb
+    db_backend: Backend,
+}
+
+fn print_header(height: u64, hash: Hash, header: &BlockHeader) {
+    println!("Block          #{height} {hash}");
+    println!("Timestamp      {}", header.timestamp);
+    println!("Previous       {}", header.prev_block_hash);
+    println!("Merkle root    {}", header.merkle_root);
+    println!("Target         {:x}", header.target);
+    println!("Difficulty     {}", format_difficulty(difficulty_from_target(header.target)));
+    println!("Nonce          {}", header.nonce);
+}
+
+fn print_block(db: &BlockchainDB, height: u64) -> Result<()> {
+    let Some(block) = db.get_block(height)? else {
+        let Some((header, hash)) = db.get_pruned_header(height)? else {
+            bail!("No block at height {height}");
+        };
+        print_header(height, hash, &header);
+        println!("Body           pruned");
+        return Ok(());
+    };
+    print_header(height, block.hash(), &block.header);
+    println!(
+        "Stored size    {} bytes",
+        db.block_size(height)?.unwrap_or_default()
+    );
+    println!("Transactions   {}", block.transactions.len());
+    for (position, tx) in block.transactions.iter().enumerate() {
+        println!();
+        let kind = if position == 0 { " (coinbase)" } else { "" };
+        println!("  {} {}{}", position, tx.hash(), kind);
+        for input in &tx.inputs {
+            println!("    spends {} from {}", input.prev_transaction_output_hash, input.address());
+        }
+        for output in &tx.outputs {
+            println!(
+                "    pays {} BTC to {} as {}",
+                format_btc(output.value),
+                output.address,
+                output.hash()
+            );
+        }
+    }
+    Ok(())
+}
+
+fn print_utxo(db: &BlockchainDB, hash: &Hash) -> Result<()> {
+    let Some((marked, height, output)) = db.get_utxo(hash)? else {
+        bail!("No unspent output {hash}, it was spent or never existed");
+    };
+    println!("Output         {hash}");
+    println!("Value          {} BTC", format_btc(output.value));
+    println!("Address        {}", output.address);
+    println!("Unique id      {}", output.unique_id);
+    println!("Created at     #{height}");
+    if marked {
+        let spender = db.get_all_mempool_txs()?.into_iter().find(|entry| {
+            entry
+                .transaction
+                .inputs
+                .iter()
+                .any(|input| input.prev_transaction_output_hash == *hash)
+        });
+        match spender {
+            Some(entry) => println!("Spent by       {} (in the mempool)", entry.transaction.hash()),
+            None => println!("Spent by       a mempool transaction that is gone"),
+        }
+    }
+    Ok(())
+}
+
+fn print_summary(db: &BlockchainDB, args: &Args) -> Result<()> {
+    println!(
+        "Database       {} ({} bytes on disk, schema v{}{})",
+        args.db_path.display(),
+        db.size_on_disk()?,
+        db.schema_version()?.unwrap_or_default(),
+        if db.is_encrypted() { ", encrypted" } else { "" }
+    );
+
+    let base = db.get_chain_base()?.or(db.get_snapshot()?.map(|snapshot| snapshot.base));
+    match &base {
+        Some(base) if base.pruned => println!("Chain base     pruned below #{}", base.height),
+        Some(base) => println!("Chain base     snapshot at #{}, older blocks backfilling", base.height),
+        None => {}
+    }
+
+    let mut transactions = 0;
+    let range = db.block_range()?;
+    match range {
+        Some((low, high)) => {
+            let mut tip: Option<Block> = None;
+            for height in low..=high {
+                if let Some(block) = db.get_block(height)? {
+                    transactions += block.transactions.len();
+                    tip = Some(block);
+                }
+            }
+            println!("Blocks         {} stored, #{} to #{}", high - low + 1, low, high);
+            let tip = tip.context("Highest block vanished while reading")?;
+            println!("Tip            #{} {} at {}", high, tip.hash(), tip.header.timestamp);
+            let target = db.get_target()?.unwrap_or(tip.header.target);
+            println!("Target         {:x}", target);
+            println!("Difficulty     {}", format_difficulty(difficulty_from_target(target)));
+        }
+        None => println!("Blocks         none"),
+    }
+
+    let utxos = db.get_all_utxos()?;
+    let value: u64 = utxos.values().map(|(_, _, output)| output.value).sum();
+    let marked = utxos.values().filter(|(marked, _, _)| *marked).count();
+    println!(
+        "UTXOs          {} worth {} BTC, {} spent in the mempool",
+        utxos.len(),
+        format_btc(value),
+        marked
+    );
+    let mempool = db.get_all_mempool_txs()?;
+    match mempool.first() {
+        Some(oldest) => println!(
+            "Mempool        {} transactions paying {} BTC in fees, oldest from {}",
+            mempool.len(),
+            format_btc(mempool.iter().map(|entry| entry.fee).sum()),
+            oldest.added
+        ),
+        None => println!("Mempool        empty"),
+    }
+    // the lookup indexes the node builds when loading
+    println!(
+        "Indexes        {} blocks and {} transactions by hash",
+        range.map_or(0, |(low, high)| high - low + 1),
+        transactions
+    );
+
+    println!();
+    println!("{:<12} {:>10} {:>14}", "Tree", "Entries", "Bytes");
+    for keyspace in db.keyspace_usage()? {
+        println!("{:<12} {:>10} {:>14}", keyspace.name, keyspace.entries, keyspace.bytes);
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let args: Args = argh::from_env();
+    if !args.db_path.exists() {
+        bail!("No database at {}", args.db_path.display());
+    }
+    let secret = DbSecret::from_env(args.db_key_file.as_deref())?;
+    // both backends lock the database, so this fails while the node runs
+    let db = BlockchainDB::open_with(args.db_backend, &args.db_path, secret.as_ref())
+        .context("Failed to open the database, is the node still running?")?;
+    if let Some(height) = args.block {
+        print_block(&db, height)
+    } else if let Some(hash) = &args.utxo {
+        print_utxo(&db, hash)
+    } else {
+        print_summary(&db, &args)
+    }
+}
@@ -0,0 +1,331 @@
+//! Hands out coins on test networks over HTTP.
+//!
+//! The faucet owns a key and pays claims from its outputs, mining a
+//! block on the node's templates whenever it runs dry. Mining also
+//! confirms its pending payouts, so their change can be spent again.
+use anyhow::{Context, Result, anyhow, bail};
+use argh::FromArgs;
+use axum::extract::{ConnectInfo, State};
+use axum::http::{StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::{Json, Router};
+use btclib::address::Address;
+use btclib::crypto::PrivateKey;
+use btclib::network::Message;
+use btclib::params::{ChainParams, Network};
+use btclib::sha256::Hash;
+use btclib::types::{TransactionOutput, UnsignedInput, UnsignedTransaction};
+use btclib::util::Saveable;
+use node::client::NodeClient;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::sync::Mutex;
+use tokio::time;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+// how long the node gets to take a payout or a mined block
+const NODE_TIMEOUT: Duration = Duration::from_secs(10);
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(FromArgs)]
+/// Give coins to whoever asks, on testnet
+struct Args {
+    #[argh(option, default = "String::from(\"127.0.0.1:9000\")")]
+    /// address of the node
+    node: String,
+    #[argh(option, default = "String::from(\"127.0.0.1:8090\")")]
+    /// address to serve the claim endpoint on
+    listen: String,
+    #[argh(option, default = "PathBuf::from(\"faucet.priv.cbor\")")]
+    /// private key of the faucet, created if missing
+    key: PathBuf,
+    #[argh(option, default = "100_000_000")]
+    /// satoshis paid per claim
+    amount: u64,
+    #[argh(option, default = "1000")]
+    /// fee of every payout
+    fee: u64,
+    #[argh(option, default = "3600")]
+    /// seconds before the same IP or address may claim again
+    cooldown: u64,
+    #[argh(switch)]
+    /// never mine, only give away the coins the key already holds
+    no_mine: bool,
+    #[argh(option, default = "Network::Testnet")]
+    /// network the node runs on, faucets refuse mainnet
+    network: Network,
+}
+
+struct Faucet {
+    node: Mutex<NodeClient>,
+    key: PrivateKey,
+    address: String,
+    params: ChainParams,
+    amount: u64,
+    fee: u64,
+    cooldown: Duration,
+    mine: bool,
+    /// Last claim of every IP and address
+    claims: std::sync::Mutex<HashMap<String, Instant>>,
+}
+
+#[derive(Deserialize)]
+struct ClaimRequest {
+    address: String,
+}
+
+#[derive(Serialize)]
+struct ClaimResponse {
+    txid: String,
+    amount: u64,
+}
+
+fn error(status: StatusCode, message: impl Into<String>) -> Response {
+    let body = serde_json::json!({ "error": message.into() });
+    (status, Json(body)).into_response()
+}
+
+impl Faucet {
+    /// Record a claim from `ip` for `address`, or return how long they
+    /// have to wait
+    fn try_claim(&self, ip: &str, address: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut claims = self.claims.lock().unwrap();
+        claims.retain(|_, at| now - *at < self.cooldown);
+        let ids = [format!("ip:{ip}"), format!("address:{address}")];
+        if let Some(wait) = ids
+            .iter()
+            .filter_map(|id| claims.get(id))
+            .map(|at| self.cooldown - (now - *at))
+            .max()
+        {
+            return Err(wait);
+        }
+        for id in ids {
+            claims.insert(id, now);
+        }
+        Ok(())
+    }
+
+    /// Let a failed claim be retried right away
+    fn forget_claim(&self, ip: &str, address: &str) {
+        let mut claims = self.claims.lock().unwrap();
+        claims.remove(&format!("ip:{ip}"));
+        claims.remove(&format!("address:{address}"));
+    }
+
+    /// Poll the faucet's outputs until `done` holds for them
+    async fn wait_for_utxos(
+        &self,
+        node: &mut NodeClient,
+        done: impl Fn(&[(TransactionOutput, bool)]) -> bool,
+    ) -> Result<bool> {
+        let deadline = Instant::now() + NODE_TIMEOUT;
+        while Instant::now() < deadline {
+            if done(&node.utxos(&self.address).await?) {
+                return Ok(true);
+            }
+            time::sleep(POLL_INTERVAL).await;
+        }
+        Ok(false)
+    }
+
+    /// Outputs not spent by a pending payout, worth at least `needed`
+    async fn select_outputs(&self, node: &mut NodeClient, needed: u64) -> Result<Option<Vec<TransactionOutput>>> {
+        let mut selected = vec![];
+        let mut value = 0;
+        for (output, _) in node.utxos(&self.address).await?.into_iter().filter(|(_, marked)| !marked) {
+            if value >= needed {
+                break;
+            }
+            value += output.value;
+            selected.push(output);
+        }
+        Ok((value >= needed).then_some(selected))
+    }
+
+    /// Pay the claimed amount to `address`, returning the transaction
+    /// once the node took it into its mempool
+    async fn pay(&self, address: &str) -> Result<Hash> {
+        let mut node = self.node.lock().await;
+        let needed = self.amount + self.fee;
+        let inputs = match self.select_outputs(&mut node, needed).await? {
+            Some(inputs) => inputs,
+            None if self.mine => {
+                let block = node.mine_block(&self.address).await?;
+                let coinbase = block.transactions[0].outputs[0].hash();
+                info!("mined block {} to refill", block.hash());
+                let mined = self
+                    .wait_for_utxos(&mut node, |utxos| utxos.iter().any(|(output, _)| output.hash() == coinbase))
+                    .await?;
+                if !mined {
+                    bail!("node rejected the faucet's block");
+                }
+                self.select_outputs(&mut node, needed)
+                    .await?
+                    .ok_or_else(|| anyhow!("faucet is dry even after mining"))?
+            }
+            None => bail!("faucet is dry"),
+        };
+
+        let input_value: u64 = inputs.iter().map(|output| output.value).sum();
+        let mut outputs = vec![TransactionOutput {
+            value: self.amount,
+            unique_id: Uuid::new_v4(),
+            address: address.to_string(),
+        }];
+        if input_value > needed {
+            outputs.push(TransactionOutput {
+                value: input_value - needed,
+                unique_id: Uuid::new_v4(),
+                address: self.address.clone(),
+            });
+        }
+        let spent: Vec<Hash> = inputs.iter().map(|output| output.hash()).collect();
+        let inputs = inputs
+            .iter()
+            .map(|output| UnsignedInput {
+                prev_transaction_output_hash: output.hash(),
+                public_key: self.key.public_key(),
+                value: output.value,
+                multisig: None,
+            })
+            .collect();
+        let transaction = UnsignedTransaction { inputs, outputs }.sign(std::slice::from_ref(&self.key))?;
+        let hash = transaction.hash();
+        node.send(Message::SubmitTransaction(transaction)).await?;
+
+        // the node doesn't answer submissions, but marks the outputs
+        // spent by transactions it accepts
+        let accepted = self
+            .wait_for_utxos(&mut node, |utxos| {
+                utxos
+                    .iter()
+                    .all(|(output, marked)| *marked || !spent.contains(&output.hash()))
+            })
+            .await?;
+        if !accepted {
+            bail!("node rejected payout {hash}");
+        }
+        Ok(hash)
+    }
+}
+
+async fn claim(
+    State(faucet): State<Arc<Faucet>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Json(request): Json<ClaimRequest>,
+) -> Response {
+    let address = request.address.trim();
+    if let Err(e) = Address::parse_for(address, &faucet.params) {
+        return error(StatusCode::BAD_REQUEST, format!("invalid {} address: {e}", faucet.params.network));
+    }
+    let ip = peer.ip().to_string();
+    if let Err(wait) = faucet.try_claim(&ip, address) {
+        let mut response = error(
+            StatusCode::TOO_MANY_REQUESTS,
+            format!("already claimed, try again in {}s", wait.as_secs()),
+        );
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, wait.as_secs().into());
+        return response;
+    }
+    match faucet.pay(address).await {
+        Ok(txid) => {
+            info!("paid {} to {} for {}", faucet.amount, address, ip);
+            Json(ClaimResponse {
+                txid: txid.to_string(),
+                amount: faucet.amount,
+            })
+            .into_response()
+        }
+        Err(e) => {
+            warn!("claim of {} for {} failed: {e}", address, ip);
+            faucet.forget_claim(&ip, address);
+            error(StatusCode::SERVICE_UNAVAILABLE, e.to_string())
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt().init();
+    let args: Args = argh::from_env();
+    if args.network == Network::Mainnet {
+        bail!("Faucets give coins away, run the node and faucet with --network testnet");
+    }
+    let params = args.network.params();
+
+    let key = if args.key.exists() {
+        PrivateKey::load_from_file(&args.key)
+            .with_context(|| format!("Failed to load {}", args.key.display()))?
+    } else {
+        let key = PrivateKey::new_key();
+        key.save_to_file(&args.key)
+            .with_context(|| format!("Failed to save {}", args.key.display()))?;
+        info!("created faucet key {}", args.key.display());
+        key
+    };
+    let address = key.public_key().to_address_for(&params);
+    let node = NodeClient::connect(&args.node).await?;
+    let faucet = Arc::new(Faucet {
+        node: Mutex::new(node),
+        key,
+        address,
+        params,
+        amount: args.amount,
+        fee: args.fee,
+        cooldown: Duration::from_secs(args.cooldown),
+        mine: !args.no_mine,
+        claims: std::sync::Mutex::new(HashMap::new()),
+    });
+
+    let app = Router::new()
+        .route("/claim", post(claim))
+        .with_state(faucet.clone());
+    let listener = TcpListener::bind(&args.listen).await?;
+    info!("faucet {} paying {} per claim on http://{}/claim", faucet.address, faucet.amount, args.listen);
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_claims_are_rate_limited() {
+        // a listener standing in for the node, never answering
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let node = NodeClient::connect(&listener.local_addr().unwrap().to_string())
+            .await
+            .unwrap();
+        let faucet = Faucet {
+            node: Mutex::new(node),
+            key: PrivateKey::new_key(),
+            address: String::new(),
+            params: Network::Testnet.params(),
+            amount: 1,
+            fee: 1,
+            cooldown: Duration::from_secs(60),
+            mine: false,
+            claims: std::sync::Mutex::new(HashMap::new()),
+        };
+        assert!(faucet.try_claim("10.0.0.1", "alice").is_ok());
+        // same IP or same address
+        assert!(faucet.try_claim("10.0.0.1", "bob").is_err());
+        assert!(faucet.try_claim("10.0.0.2", "alice").is_err());
+        assert!(faucet.try_claim("10.0.0.2", "bob").is_ok());
+
+        faucet.forget_claim("10.0.0.1", "alice");
+        assert!(faucet.try_claim("10.0.0.1", "alice").is_ok());
+    }
+}
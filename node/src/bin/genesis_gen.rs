@@ -0,0 +1,195 @@
+//! Generates the genesis block of a private network, paying premined
+//! allocations from block 0.
+//!
+//! The allocations file looks like
+//!
+//! ```toml
+//! network = "Testnet"
+//! timestamp = "2026-01-01T00:00:00Z"
+//! message = "Grapheno private net, launched with 3 funded accounts"
+//!
+//! [[allocations]]
+//! address = "mipcBbFg9gMiCh81Kj8tqqdgoZub1ZJRfn"
+//! amount = "1000"
+//! ```
+//!
+//! with amounts in BTC. Outputs have no room for data, so the message
+//! is committed to through the output ids, which are derived from it.
+use anyhow::{Context, Result, bail};
+use argh::FromArgs;
+use btclib::address::Address;
+use btclib::amount::{format_btc, parse_btc};
+use btclib::params::Network;
+use btclib::sha256::Hash;
+use btclib::types::{Block, BlockHeader, Transaction, TransactionOutput};
+use btclib::util::MerkleRoot;
+use chrono::{DateTime, Utc};
+use node::bootstrap::BlockWriter;
+use serde::Deserialize;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+use uuid::{Builder, Uuid};
+
+// nonces tried between progress checks
+const MINE_STEPS: usize = 1_000_000;
+
+#[derive(FromArgs)]
+/// Generate a genesis block paying premined allocations
+struct Args {
+    #[argh(positional)]
+    /// TOML file of the network, timestamp, message and allocations
+    allocations: PathBuf,
+    #[argh(positional)]
+    /// bootstrap file to write the genesis block to
+    output: PathBuf,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GenesisConfig {
+    #[serde(default)]
+    network: Network,
+    /// RFC 3339
+    timestamp: String,
+    message: String,
+    allocations: Vec<Allocation>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Allocation {
+    address: String,
+    /// In BTC, as a string so no precision is lost
+    amount: String,
+}
+
+/// Id of the output at `index`, derived from the genesis message
+fn output_id(message: &str, index: usize) -> Uuid {
+    let hash = Hash::hash(&(message, index as u64)).as_bytes();
+    Builder::from_random_bytes(hash[..16].try_into().expect("16 bytes")).into_uuid()
+}
+
+/// The genesis block `config` describes, the same every time
+fn genesis_block(config: &GenesisConfig) -> Result<Block> {
+    if config.allocations.is_empty() {
+        bail!("The genesis block needs at least one allocation");
+    }
+    let params = config.network.params();
+    let timestamp: DateTime<Utc> = DateTime::parse_from_rfc3339(&config.timestamp)
+        .with_context(|| format!("Invalid timestamp {:?}, expected RFC 3339", config.timestamp))?
+        .into();
+
+    let mut outputs = vec![];
+    for (index, allocation) in config.allocations.iter().enumerate() {
+        Address::parse_for(&allocation.address, &params)
+            .with_context(|| format!("Invalid {} address {:?}", params.network, allocation.address))?;
+        let value = parse_btc(&allocation.amount)
+            .with_context(|| format!("Invalid amount {:?} for {}", allocation.amount, allocation.address))?;
+        if value == 0 {
+            bail!("Allocation to {} is empty", allocation.address);
+        }
+        outputs.push(TransactionOutput {
+            value,
+            unique_id: output_id(&config.message, index),
+            address: allocation.address.clone(),
+        });
+    }
+
+    let transactions = vec![Transaction::new(vec![], outputs)];
+    let mut block = Block::new(
+        BlockHeader::new(
+            timestamp,
+            0,
+            Hash::zero(),
+            MerkleRoot::calculate(&transactions),
+            btclib::MIN_TARGET,
+        ),
+        transactions,
+    );
+    while !block.header.mine(MINE_STEPS) {}
+    Ok(block)
+}
+
+fn main() -> Result<()> {
+    let args: Args = argh::from_env();
+    let contents = std::fs::read_to_string(&args.allocations)
+        .with_context(|| format!("Failed to read {}", args.allocations.display()))?;
+    let config: GenesisConfig = toml::from_str(&contents)
+        .with_context(|| format!("Failed to parse {}", args.allocations.display()))?;
+    let block = genesis_block(&config)?;
+
+    let file = File::create(&args.output)
+        .with_context(|| format!("Failed to create {}", args.output.display()))?;
+    let mut writer = BlockWriter::new(BufWriter::new(file))?;
+    writer.write_block(&block)?;
+    writer.finish()?;
+
+    let hash = block.hash();
+    let total: u64 = block.transactions[0].outputs.iter().map(|output| output.value).sum();
+    let network = config.network;
+    println!(
+        "Genesis block {} paying {} BTC to {} allocations",
+        hash,
+        format_btc(total),
+        config.allocations.len()
+    );
+    println!();
+    println!("Import it into the empty database of every node of the network:");
+    println!(
+        "    node --network {} --db-path <db> import-blocks {}",
+        network,
+        args.output.display()
+    );
+    println!("and start the nodes pinned to it:");
+    println!("    node --network {network} --checkpoint 0:{hash}");
+    println!();
+    println!("To build it into the binaries instead, add it to the checkpoints of the");
+    println!("network's ChainParams in lib/src/params.rs:");
+    println!("    checkpoints: vec![Checkpoint {{");
+    println!("        height: 0,");
+    println!("        hash: \"{hash}\".parse().unwrap(),");
+    println!("    }}],");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        network = "Testnet"
+        timestamp = "2026-01-01T00:00:00Z"
+        message = "test net"
+
+        [[allocations]]
+        address = "mipcBbFg9gMiCh81Kj8tqqdgoZub1ZJRfn"
+        amount = "1000"
+
+        [[allocations]]
+        address = "mipcBbFg9gMiCh81Kj8tqqdgoZub1ZJRfn"
+        amount = "0.5"
+    "#;
+
+    #[test]
+    fn test_genesis_is_reproducible() {
+        let config: GenesisConfig = toml::from_str(CONFIG).unwrap();
+        let block = genesis_block(&config).unwrap();
+        assert_eq!(block.hash(), genesis_block(&config).unwrap().hash());
+        assert!(block.header.hash().matches_target(block.header.target));
+        let values: Vec<u64> = block.transactions[0].outputs.iter().map(|output| output.value).collect();
+        assert_eq!(values, vec![100_000_000_000, 50_000_000]);
+
+        // another message makes another block
+        let mut other = config;
+        other.message = "another net".to_string();
+        assert_ne!(genesis_block(&other).unwrap().hash(), block.hash());
+    }
+
+    #[test]
+    fn test_rejects_addresses_of_another_network() {
+        let mut config: GenesisConfig = toml::from_str(CONFIG).unwrap();
+        config.network = Network::Mainnet;
+        assert!(genesis_block(&config).is_err());
+    }
+}
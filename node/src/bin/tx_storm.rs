@@ -0,0 +1,614 @@
+//! Load tests a node with a storm of valid transactions.
+//!
+//! The storm's wallets are funded from blocks it mines on the node's
+//! templates or from existing keys, then every funded output is split
+//! into lanes. Each lane is a chain of transactions, each spending the
+//! output of the one before, so transactions never wait for blocks.
+//! A separate connection watches the node's relay to tell when a
+//! transaction was accepted and when it was mined. With `--compare` it
+//! benchmarks how slow peers hold up the node, storming once without
+//! and once with them.
+use anyhow::{Context, Result, bail};
+use argh::FromArgs;
+use btclib::crypto::PrivateKey;
+use btclib::network::{Envelope, Message};
+use btclib::params::{ChainParams, Network};
+use btclib::sha256::Hash;
+use btclib::types::{Block, Transaction, TransactionOutput, UnsignedInput, UnsignedTransaction};
+use btclib::util::Saveable;
+use node::client::{NodeClient, NodeSender};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::time::{self, Instant};
+use uuid::Uuid;
+
+// how long the node gets to relay a transaction or block
+const REPLY_TIMEOUT: Duration = Duration::from_secs(30);
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+// receive buffer of the slow peers, small so they back up after a few
+// relayed messages rather than megabytes of them
+const SLOW_PEER_BUFFER: u32 = 4096;
+
+#[derive(FromArgs)]
+/// Submit valid transactions to a node at a target rate and report
+/// how it keeps up
+struct Args {
+    #[argh(positional)]
+    /// address of the node, e.g. 127.0.0.1:9000
+    node: String,
+    #[argh(option, default = "1000")]
+    /// number of transactions to submit
+    count: u64,
+    #[argh(option, default = "100.0")]
+    /// transactions per second to submit, over all connections
+    rate: f64,
+    #[argh(option, default = "8")]
+    /// number of connections to submit over
+    connections: usize,
+    #[argh(option, default = "10")]
+    /// number of wallets the transactions pay between
+    wallets: usize,
+    #[argh(option, default = "0")]
+    /// blocks to mine on the node's templates to fund the wallets
+    mine: u64,
+    #[argh(option)]
+    /// directory of keys (*.priv.cbor) whose confirmed outputs fund
+    /// the storm, e.g. from chain_gen
+    keys: Option<PathBuf>,
+    #[argh(option, default = "20")]
+    /// lanes each funding output is split into
+    fanout: u64,
+    #[argh(option, default = "1000")]
+    /// fee of every transaction
+    fee: u64,
+    #[argh(option, default = "10")]
+    /// seconds to keep watching for acceptances and blocks after the
+    /// last transaction was sent
+    wait: u64,
+    #[argh(option, default = "Network::Mainnet")]
+    /// network the node runs on, mainnet or testnet
+    network: Network,
+    #[argh(option, default = "0")]
+    /// connections that never read what the node relays to them, to
+    /// see how slow peers hold up the others
+    slow_peers: usize,
+    #[argh(switch)]
+    /// storm once without the slow peers and once with them, then
+    /// compare the node's throughput and latency
+    compare: bool,
+}
+
+/// A chain of transactions, each spending the last one's output
+struct Lane {
+    key: PrivateKey,
+    output: TransactionOutput,
+    /// The transaction creating `output`
+    tx: Hash,
+}
+
+/// The keys the storm pays between, shared by its connections
+struct Wallets {
+    keys: Vec<PrivateKey>,
+    params: ChainParams,
+    fee: u64,
+}
+
+/// What the watching connection saw of the submitted transactions
+#[derive(Default)]
+struct Stats {
+    sent: HashMap<Hash, Instant>,
+    accepted: HashSet<Hash>,
+    confirmed: HashSet<Hash>,
+    acceptance_latencies: Vec<Duration>,
+    confirmation_latencies: Vec<Duration>,
+    blocks: u64,
+    last_accepted: Option<Instant>,
+}
+
+impl Stats {
+    fn accept(&mut self, hash: Hash, at: Instant) {
+        if let Some(sent) = self.sent.get(&hash)
+            && self.accepted.insert(hash)
+        {
+            self.acceptance_latencies.push(at - *sent);
+            self.last_accepted = Some(at);
+        }
+    }
+
+    fn confirm(&mut self, block: &Block, at: Instant) {
+        self.blocks += 1;
+        for tx in &block.transactions {
+            let hash = tx.hash();
+            // mined before the relay reached us, or its relay was dropped
+            self.accept(hash, at);
+            if let Some(sent) = self.sent.get(&hash)
+                && self.confirmed.insert(hash)
+            {
+                self.confirmation_latencies.push(at - *sent);
+            }
+        }
+    }
+
+    /// Accepted transactions not mined yet
+    fn pending(&self) -> usize {
+        self.accepted.len() - self.confirmed.len()
+    }
+
+    /// Transactions accepted per second from `started` to the last
+    /// acceptance
+    fn throughput(&self, started: Instant) -> f64 {
+        match self.last_accepted {
+            Some(last) if last > started => self.accepted.len() as f64 / (last - started).as_secs_f64(),
+            _ => 0.0,
+        }
+    }
+}
+
+/// How one storm went, see `run`
+struct Outcome {
+    started: Instant,
+    sending_time: Duration,
+    sent: u64,
+    connections: usize,
+    slow_peers: usize,
+    peak_pending: usize,
+}
+
+/// The value below which `fraction` of the sorted `values` lie
+fn percentile(sorted: &[Duration], fraction: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let index = ((sorted.len() - 1) as f64 * fraction).round() as usize;
+    sorted[index]
+}
+
+fn latency_summary(latencies: &mut [Duration]) -> String {
+    latencies.sort();
+    format!(
+        "p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+        percentile(latencies, 0.5),
+        percentile(latencies, 0.9),
+        percentile(latencies, 0.99),
+        latencies.last().copied().unwrap_or_default()
+    )
+}
+
+/// Watch the node's relay, recording when submitted transactions are
+/// accepted and mined
+async fn watch(mut reader: OwnedReadHalf, stats: Arc<Mutex<Stats>>) {
+    while let Ok(env) = Envelope::receive_async(&mut reader).await {
+        let now = Instant::now();
+        match env.msg {
+            Message::NewTransaction(tx) => stats.lock().unwrap().accept(tx.hash(), now),
+            Message::NewBlock(block) => stats.lock().unwrap().confirm(&block, now),
+            _ => {}
+        }
+    }
+}
+
+/// Connect a peer that never reads what the node relays to it
+async fn connect_slow(node: &str) -> Result<TcpStream> {
+    let address = tokio::net::lookup_host(node)
+        .await?
+        .next()
+        .with_context(|| format!("Failed to resolve {node}"))?;
+    let socket = if address.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.set_recv_buffer_size(SLOW_PEER_BUFFER)?;
+    socket
+        .connect(address)
+        .await
+        .with_context(|| format!("Failed to connect to {node}"))
+}
+
+/// Wait until the watcher saw `done` hold
+async fn wait_for(stats: &Mutex<Stats>, done: impl Fn(&Stats) -> bool) -> bool {
+    let wait = async {
+        while !done(&stats.lock().unwrap()) {
+            time::sleep(POLL_INTERVAL).await;
+        }
+    };
+    time::timeout(REPLY_TIMEOUT, wait).await.is_ok()
+}
+
+/// Spend `lane`'s output, paying it less the fee to `recipient`
+fn next_transaction(lane: &Lane, recipient: &PrivateKey, params: &ChainParams, fee: u64) -> Result<Transaction> {
+    UnsignedTransaction {
+        inputs: vec![UnsignedInput {
+            prev_transaction_output_hash: lane.output.hash(),
+            public_key: lane.key.public_key(),
+            value: lane.output.value,
+            multisig: None,
+        }],
+        outputs: vec![TransactionOutput {
+            value: lane.output.value - fee,
+            unique_id: Uuid::new_v4(),
+            address: recipient.public_key().to_address_for(params),
+        }],
+    }
+    .sign(std::slice::from_ref(&lane.key))
+    .map_err(Into::into)
+}
+
+/// Fund the storm, returning the confirmed outputs it may spend with
+/// their keys
+async fn fund(
+    args: &Args,
+    control: &mut NodeClient,
+    stats: &Mutex<Stats>,
+    keys: &[PrivateKey],
+    params: &ChainParams,
+) -> Result<Vec<(PrivateKey, TransactionOutput)>> {
+    let mut funds = vec![];
+    for index in 0..args.mine {
+        let key = &keys[index as usize % keys.len()];
+        let address = key.public_key().to_address_for(params);
+        let block = control.mine_block(&address).await?;
+        let hash = block.hash();
+        let coinbase = block.transactions[0].hash();
+        stats.lock().unwrap().sent.insert(coinbase, Instant::now());
+        // the node relays blocks it accepts
+        if !wait_for(stats, |stats| stats.confirmed.contains(&coinbase)).await {
+            bail!("node rejected funding block {hash}");
+        }
+        funds.push((key.clone(), block.transactions[0].outputs[0].clone()));
+        println!("Mined funding block {} ({}/{})", hash, index + 1, args.mine);
+    }
+
+    if let Some(dir) = &args.keys {
+        for entry in std::fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))? {
+            let path = entry?.path();
+            if !path.to_string_lossy().ends_with(".priv.cbor") {
+                continue;
+            }
+            let key = PrivateKey::load_from_file(&path)
+                .with_context(|| format!("Failed to load {}", path.display()))?;
+            let address = key.public_key().to_address_for(params);
+            let utxos = control.utxos(&address).await?;
+            funds.extend(
+                utxos
+                    .into_iter()
+                    .filter(|(_, marked)| !marked)
+                    .map(|(output, _)| (key.clone(), output)),
+            );
+        }
+    }
+    Ok(funds)
+}
+
+/// Split every funding output into `fanout` lanes, waiting until the
+/// node accepted the splits
+async fn split(
+    args: &Args,
+    control: &mut NodeClient,
+    stats: &Mutex<Stats>,
+    funds: Vec<(PrivateKey, TransactionOutput)>,
+    keys: &[PrivateKey],
+    params: &ChainParams,
+) -> Result<Vec<Lane>> {
+    let mut lanes = vec![];
+    let mut splits = vec![];
+    for (key, output) in funds {
+        let Some(share) = output.value.saturating_sub(args.fee).checked_div(args.fanout) else {
+            continue;
+        };
+        if share <= args.fee {
+            continue;
+        }
+        let outputs: Vec<TransactionOutput> = (0..args.fanout)
+            .map(|index| TransactionOutput {
+                value: share,
+                unique_id: Uuid::new_v4(),
+                address: keys[index as usize % keys.len()]
+                    .public_key()
+                    .to_address_for(params),
+            })
+            .collect();
+        let input = UnsignedInput {
+            prev_transaction_output_hash: output.hash(),
+            public_key: key.public_key(),
+            value: output.value,
+            multisig: None,
+        };
+        // the remainder of the division goes to the fee
+        let split = UnsignedTransaction {
+            inputs: vec![input],
+            outputs: outputs.clone(),
+        }
+        .sign(std::slice::from_ref(&key))?;
+        let hash = split.hash();
+        stats.lock().unwrap().sent.insert(hash, Instant::now());
+        splits.push(hash);
+        control.send(Message::SubmitTransaction(split)).await?;
+        lanes.extend(outputs.into_iter().enumerate().map(|(index, output)| Lane {
+            key: keys[index % keys.len()].clone(),
+            output,
+            tx: hash,
+        }));
+    }
+    if !wait_for(stats, |stats| splits.iter().all(|hash| stats.accepted.contains(hash))).await {
+        bail!("node did not accept the transactions splitting the funds");
+    }
+    Ok(lanes)
+}
+
+/// Mine blocks on the node's templates until one has no mempool
+/// transaction left to take, returning how many it took
+async fn drain(control: &mut NodeClient, stats: &Mutex<Stats>, address: &str) -> Result<u64> {
+    let mut blocks = 0;
+    loop {
+        let block = control.mine_block(address).await?;
+        let coinbase = block.transactions[0].hash();
+        stats.lock().unwrap().sent.insert(coinbase, Instant::now());
+        if !wait_for(stats, |stats| stats.confirmed.contains(&coinbase)).await {
+            bail!("node rejected block {}", block.hash());
+        }
+        blocks += 1;
+        if block.transactions.len() == 1 {
+            return Ok(blocks);
+        }
+    }
+}
+
+/// Submit `count` transactions over one connection, one every `period`,
+/// taking turns between its lanes. Returns how many it sent and the
+/// lanes left to spend.
+async fn storm(
+    mut submitter: NodeSender,
+    mut lanes: Vec<Lane>,
+    count: u64,
+    period: Duration,
+    wallets: Arc<Wallets>,
+    stats: Arc<Mutex<Stats>>,
+) -> Result<(u64, Vec<Lane>)> {
+    let Wallets { keys, params, fee } = &*wallets;
+    let mut ticker = time::interval(period);
+    let mut sent = 0;
+    while sent < count && !lanes.is_empty() {
+        ticker.tick().await;
+        let lane = sent as usize % lanes.len();
+        if lanes[lane].output.value <= *fee {
+            lanes.swap_remove(lane);
+            continue;
+        }
+        let recipient = &keys[rand::random_range(0..keys.len())];
+        let tx = next_transaction(&lanes[lane], recipient, params, *fee)?;
+        lanes[lane] = Lane {
+            key: recipient.clone(),
+            output: tx.outputs[0].clone(),
+            tx: tx.hash(),
+        };
+        stats.lock().unwrap().sent.insert(tx.hash(), Instant::now());
+        submitter.send(Message::SubmitTransaction(tx)).await?;
+        sent += 1;
+    }
+    Ok((sent, lanes))
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args: Args = argh::from_env();
+    if args.wallets == 0 || args.connections == 0 || args.fanout == 0 || args.rate <= 0.0 {
+        bail!("--wallets, --connections, --fanout and --rate must be positive");
+    }
+    if args.compare && args.slow_peers == 0 {
+        bail!("--compare needs slow peers to compare with, pass --slow-peers");
+    }
+    let params = args.network.params();
+    let keys: Vec<PrivateKey> = (0..args.wallets).map(|_| PrivateKey::new_key()).collect();
+
+    let stats = Arc::new(Mutex::new(Stats::default()));
+    let watcher = TcpStream::connect(&args.node)
+        .await
+        .with_context(|| format!("Failed to connect to {}", args.node))?;
+    let (reader, _writer) = watcher.into_split();
+    tokio::spawn(watch(reader, stats.clone()));
+
+    let mut control = NodeClient::connect(&args.node).await?;
+    let funds = fund(&args, &mut control, &stats, &keys, &params).await?;
+    if funds.is_empty() {
+        bail!("No coins to spend, mine some with --mine or pass funded keys with --keys");
+    }
+    let lanes = split(&args, &mut control, &stats, funds, &keys, &params).await?;
+    println!("Split the funds into {} lanes", lanes.len());
+    *stats.lock().unwrap() = Stats::default();
+
+    let wallets = Arc::new(Wallets {
+        keys,
+        params,
+        fee: args.fee,
+    });
+    if !args.compare {
+        let (_, outcome) = run(&args, lanes, args.slow_peers, &wallets, &stats).await?;
+        summarize(&outcome, &mut stats.lock().unwrap());
+        return Ok(());
+    }
+
+    // the node takes longer the more its mempool holds, so both runs
+    // start from an empty one
+    let address = wallets.keys[0].public_key().to_address_for(&wallets.params);
+    let blocks = drain(&mut control, &stats, &address).await?;
+    println!("Mined {blocks} blocks to empty the mempool");
+    *stats.lock().unwrap() = Stats::default();
+    let (lanes, without) = run(&args, lanes, 0, &wallets, &stats).await?;
+    let mut without_stats = std::mem::take(&mut *stats.lock().unwrap());
+    summarize(&without, &mut without_stats);
+    println!();
+    let blocks = drain(&mut control, &stats, &address).await?;
+    println!("Mined {blocks} blocks to empty the mempool");
+    *stats.lock().unwrap() = Stats::default();
+    let (_, with) = run(&args, lanes, args.slow_peers, &wallets, &stats).await?;
+    let mut with_stats = stats.lock().unwrap();
+    summarize(&with, &mut with_stats);
+
+    println!();
+    for (outcome, stats) in [(&without, &mut without_stats), (&with, &mut *with_stats)] {
+        println!(
+            "{:>4} slow peers: {:>8.1} accepted/s, acceptance latency {}",
+            outcome.slow_peers,
+            stats.throughput(outcome.started),
+            latency_summary(&mut stats.acceptance_latencies)
+        );
+    }
+    let before = without_stats.throughput(without.started);
+    if before > 0.0 {
+        println!(
+            "With slow peers the node accepted {:.1}% of the transactions per second it did without",
+            100.0 * with_stats.throughput(with.started) / before
+        );
+    }
+    Ok(())
+}
+
+/// Storm the node over `lanes` with `slow_peers` more connections that
+/// never read, returning the lanes left to spend. Stops waiting for
+/// blocks once everything sent was mined or after `--wait`; when
+/// comparing, once everything sent was accepted, and then only lanes
+/// whose last transaction was accepted are left to spend.
+async fn run(
+    args: &Args,
+    lanes: Vec<Lane>,
+    slow_peers: usize,
+    wallets: &Arc<Wallets>,
+    stats: &Arc<Mutex<Stats>>,
+) -> Result<(Vec<Lane>, Outcome)> {
+    if lanes.is_empty() {
+        bail!("No lanes left to spend");
+    }
+    // hand the lanes out to the connections in turn
+    let connections = args.connections.min(lanes.len());
+    let mut shares: Vec<Vec<Lane>> = (0..connections).map(|_| vec![]).collect();
+    for (index, lane) in lanes.into_iter().enumerate() {
+        shares[index % connections].push(lane);
+    }
+    let period = Duration::from_secs_f64(connections as f64 / args.rate);
+    // kept open until the end, their reads left to pile up
+    let mut slow = vec![];
+    for _ in 0..slow_peers {
+        slow.push(connect_slow(&args.node).await?);
+    }
+    let started = Instant::now();
+    let mut tasks = vec![];
+    for (index, lanes) in shares.into_iter().enumerate() {
+        let count = args.count / connections as u64 + u64::from((index as u64) < args.count % connections as u64);
+        let submitter = NodeClient::connect(&args.node).await?.into_sender();
+        tasks.push(tokio::spawn(storm(
+            submitter,
+            lanes,
+            count,
+            period,
+            wallets.clone(),
+            stats.clone(),
+        )));
+    }
+
+    let mut peak_pending = 0;
+    let mut progress = time::interval(Duration::from_secs(1));
+    let report = |stats: &Stats| {
+        println!(
+            "{:>6.1}s  sent {:>6}  accepted {:>6}  mined {:>6}  pending {:>6}",
+            started.elapsed().as_secs_f64(),
+            stats.sent.len(),
+            stats.accepted.len(),
+            stats.confirmed.len(),
+            stats.pending()
+        );
+    };
+    while !tasks.iter().all(|task| task.is_finished()) {
+        progress.tick().await;
+        let stats = stats.lock().unwrap();
+        peak_pending = peak_pending.max(stats.pending());
+        report(&stats);
+    }
+    let mut sent = 0;
+    let mut left = vec![];
+    for task in tasks {
+        match task.await? {
+            Ok((count, lanes)) => {
+                sent += count;
+                left.extend(lanes);
+            }
+            Err(e) => eprintln!("A connection stopped early: {e}"),
+        }
+    }
+    let sending_time = started.elapsed();
+
+    // stop waiting early once everything sent was mined
+    let deadline = Instant::now() + Duration::from_secs(args.wait);
+    while Instant::now() < deadline {
+        progress.tick().await;
+        let stats = stats.lock().unwrap();
+        peak_pending = peak_pending.max(stats.pending());
+        report(&stats);
+        if stats.confirmed.len() == stats.sent.len() || (args.compare && stats.accepted.len() == stats.sent.len()) {
+            break;
+        }
+    }
+    if args.compare {
+        // the node rejects whatever spends a transaction it never took
+        let stats = stats.lock().unwrap();
+        left.retain(|lane| !stats.sent.contains_key(&lane.tx) || stats.accepted.contains(&lane.tx));
+    }
+    let outcome = Outcome {
+        started,
+        sending_time,
+        sent,
+        connections,
+        slow_peers: slow.len(),
+        peak_pending,
+    };
+    Ok((left, outcome))
+}
+
+/// Print what the watcher saw of a storm
+fn summarize(outcome: &Outcome, stats: &mut Stats) {
+    let accepted = stats.accepted.len();
+    let sent = outcome.sent;
+    println!();
+    println!(
+        "Sent {} transactions in {:.2?} ({:.1}/s) over {} connections, {} slow peers",
+        sent,
+        outcome.sending_time,
+        sent as f64 / outcome.sending_time.as_secs_f64(),
+        outcome.connections,
+        outcome.slow_peers
+    );
+    println!(
+        "Accepted {} ({:.1}%, {:.1}/s), not seen relayed or mined: {}",
+        accepted,
+        100.0 * accepted as f64 / sent.max(1) as f64,
+        stats.throughput(outcome.started),
+        (sent as usize).saturating_sub(accepted)
+    );
+    println!("Acceptance latency: {}", latency_summary(&mut stats.acceptance_latencies));
+    println!(
+        "Mined {} in {} blocks, still pending {}, at most {} pending at once",
+        stats.confirmed.len(),
+        stats.blocks,
+        stats.pending(),
+        outcome.peak_pending
+    );
+    println!("Confirmation latency: {}", latency_summary(&mut stats.confirmation_latencies));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentile() {
+        let latencies: Vec<Duration> = (1..=100).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&latencies, 0.5), Duration::from_millis(51));
+        assert_eq!(percentile(&latencies, 0.99), Duration::from_millis(99));
+        assert_eq!(percentile(&latencies, 1.0), Duration::from_millis(100));
+        assert_eq!(percentile(&[], 0.5), Duration::ZERO);
+    }
+}
@@ -0,0 +1,278 @@
+use crate::database::BlockchainDB;
+use anyhow::{Context, Result, bail};
+use btclib::crypto::{PrivateKey, PublicKey};
+use btclib::params::ChainParams;
+use btclib::types::{Block, Blockchain, Snapshot};
+use btclib::util::Saveable;
+use ciborium::{de::from_reader, ser::into_writer};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+use tracing::{info, instrument, warn};
+
+/// Bootstrap files start with this magic followed by a big-endian
+/// u32 format version. Each block is then stored as a big-endian u64
+/// length followed by the CBOR-encoded block, in height order.
+const MAGIC: &[u8; 4] = b"GRPB";
+const FORMAT_VERSION: u32 = 1;
+
+/// Streams blocks into a bootstrap file
+pub struct BlockWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> BlockWriter<W> {
+    pub fn new(mut inner: W) -> Result<Self> {
+        inner.write_all(MAGIC)?;
+        inner.write_all(&FORMAT_VERSION.to_be_bytes())?;
+        Ok(Self { inner })
+    }
+
+    pub fn write_block(&mut self, block: &Block) -> Result<()> {
+        let mut bytes = Vec::new();
+        into_writer(block, &mut bytes).context("Failed to serialize block")?;
+        self.inner.write_all(&(bytes.len() as u64).to_be_bytes())?;
+        self.inner.write_all(&bytes)?;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<W> {
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+/// Streams blocks out of a bootstrap file
+pub struct BlockReader<R: Read> {
+    inner: R,
+}
+
+impl<R: Read> BlockReader<R> {
+    pub fn new(mut inner: R) -> Result<Self> {
+        let mut magic = [0u8; 4];
+        inner
+            .read_exact(&mut magic)
+            .context("Failed to read bootstrap header")?;
+        if &magic != MAGIC {
+            bail!("Not a bootstrap file");
+        }
+        let mut version = [0u8; 4];
+        inner
+            .read_exact(&mut version)
+            .context("Failed to read bootstrap header")?;
+        let version = u32::from_be_bytes(version);
+        if version != FORMAT_VERSION {
+            bail!("Unsupported bootstrap format version {}", version);
+        }
+        Ok(Self { inner })
+    }
+
+    /// Read the next block, or None at the end of the file
+    pub fn read_block(&mut self) -> Result<Option<Block>> {
+        let mut len_bytes = [0u8; 8];
+        let mut filled = 0;
+        while filled < len_bytes.len() {
+            match self.inner.read(&mut len_bytes[filled..]) {
+                Ok(0) => break,
+                Ok(read) => filled += read,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        match filled {
+            0 => return Ok(None),
+            8 => {}
+            _ => bail!("Bootstrap file is truncated"),
+        }
+        // the length comes from the file, so read up to it rather than
+        // allocating whatever it claims
+        let len = u64::from_be_bytes(len_bytes);
+        let mut data = Vec::new();
+        self.inner.by_ref().take(len).read_to_end(&mut data)?;
+        if (data.len() as u64) < len {
+            bail!("Bootstrap file is truncated");
+        }
+        let block = from_reader(data.as_slice()).context("Failed to deserialize block")?;
+        Ok(Some(block))
+    }
+}
+
+/// Write every block stored in the database to a bootstrap file,
+/// returning the number of blocks exported. Bootstrap files start at
+/// the genesis block, so pruned databases and ones started from a
+/// snapshot can't be exported.
+#[instrument(skip(db, path), fields(path = %path.as_ref().display()))]
+pub fn export_blocks<P: AsRef<Path>>(db: &BlockchainDB, path: P) -> Result<u64> {
+    let lowest = db.block_range()?.map(|(low, _)| low);
+    if lowest.is_some_and(|low| low > 0) || (lowest.is_none() && db.get_snapshot()?.is_some()) {
+        bail!(
+            "The database doesn't have the genesis block, it was pruned or started from a snapshot. \
+             Export from a node that keeps the whole chain."
+        );
+    }
+    let file = File::create(&path).context("Failed to create bootstrap file")?;
+    let mut writer = BlockWriter::new(BufWriter::new(file))?;
+
+    let mut height = 0u64;
+    while let Some(block) = db.get_block(height)? {
+        writer.write_block(&block)?;
+        height += 1;
+    }
+    writer.finish()?;
+
+    info!("exported {} blocks", height);
+    Ok(height)
+}
+
+/// Append the blocks of a bootstrap file to the chain stored in the
+/// database, returning the number of blocks imported. Blocks the
+/// database already has are skipped, so an interrupted import can
+/// simply be re-run. Blocks are checked under the network's params.
+#[instrument(skip(db, path, params), fields(path = %path.as_ref().display()))]
+pub fn import_blocks<P: AsRef<Path>>(db: &BlockchainDB, path: P, params: &ChainParams) -> Result<u64> {
+    let file = File::open(&path).context("Failed to open bootstrap file")?;
+    let mut reader = BlockReader::new(BufReader::new(file))?;
+
+    let mut blockchain = db.load_blockchain(params)?;
+    let mut height = 0u64;
+    let mut imported = 0u64;
+
+    while let Some(block) = reader.read_block()? {
+        if height < blockchain.base_height() {
+            // covered by the snapshot the chain was started from
+        } else if height < blockchain.block_height() {
+            let known = blockchain.block_at(height).map(|known| known.hash());
+            if known != Some(block.hash()) {
+                bail!("Block {} in bootstrap file conflicts with the local chain", height);
+            }
+        } else {
+            if let Err(e) = blockchain.add_block(block) {
+                warn!("block {} rejected: {}", height, e);
+                bail!("Block {} in bootstrap file is invalid: {}", height, e);
+            }
+            imported += 1;
+        }
+        height += 1;
+    }
+
+    db.save_blockchain(&blockchain)?;
+    info!(
+        "imported {} blocks, chain height is now {}",
+        imported,
+        blockchain.block_height()
+    );
+    Ok(imported)
+}
+
+/// Write a snapshot of the chain stored in the database, signed
+/// with the given key
+#[instrument(skip(db, path, private_key, params), fields(path = %path.as_ref().display()))]
+pub async fn export_snapshot<P: AsRef<Path>>(
+    db: &BlockchainDB,
+    path: P,
+    private_key: &PrivateKey,
+    params: &ChainParams,
+) -> Result<()> {
+    let blockchain = db.load_blockchain(params)?;
+    let snapshot =
+        Snapshot::create(&blockchain, private_key).context("Cannot snapshot an empty chain")?;
+    snapshot
+        .save_to_file_async(&path)
+        .await
+        .context("Failed to write snapshot file")?;
+    info!(
+        "exported snapshot at height {} with {} UTXOs",
+        snapshot.base.height,
+        snapshot.utxos.len()
+    );
+    Ok(())
+}
+
+/// Initialize an empty database from a snapshot signed by the
+/// trusted key. Blocks below the snapshot are backfilled from peers
+/// once the node is running.
+#[instrument(skip(db, path, trusted_key), fields(path = %path.as_ref().display()))]
+pub fn load_snapshot<P: AsRef<Path>>(
+    db: &BlockchainDB,
+    path: P,
+    trusted_key: &PublicKey,
+) -> Result<()> {
+    if db.get_block(0)?.is_some() || db.get_snapshot()?.is_some() {
+        bail!("Snapshots can only be loaded into an empty database");
+    }
+    let snapshot = Snapshot::load_from_file(&path).context("Failed to read snapshot file")?;
+    if !snapshot.verify(trusted_key) {
+        bail!("Snapshot is not signed by the trusted key");
+    }
+
+    db.put_snapshot(&snapshot)?;
+    let height = snapshot.base.height;
+    db.save_blockchain(&Blockchain::from_snapshot(snapshot))?;
+    info!("loaded snapshot at height {}", height);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use btclib::testing::empty_block;
+
+    #[test]
+    fn test_round_trip() {
+        let blocks = vec![empty_block(1), empty_block(2)];
+        let mut writer = BlockWriter::new(Vec::new()).unwrap();
+        for block in &blocks {
+            writer.write_block(block).unwrap();
+        }
+        let bytes = writer.finish().unwrap();
+
+        let mut reader = BlockReader::new(bytes.as_slice()).unwrap();
+        for block in &blocks {
+            let read = reader.read_block().unwrap().unwrap();
+            assert_eq!(read.hash(), block.hash());
+        }
+        assert!(reader.read_block().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_truncated_file_is_an_error() {
+        let mut writer = BlockWriter::new(Vec::new()).unwrap();
+        writer.write_block(&empty_block(1)).unwrap();
+        let bytes = writer.finish().unwrap();
+
+        // cut in the block, then in the length prefix of the next one
+        let mut reader = BlockReader::new(&bytes[..bytes.len() - 1]).unwrap();
+        assert!(reader.read_block().is_err());
+        let mut partial_prefix = bytes.clone();
+        partial_prefix.extend_from_slice(&[0, 0, 0]);
+        let mut reader = BlockReader::new(partial_prefix.as_slice()).unwrap();
+        assert!(reader.read_block().unwrap().is_some());
+        assert!(reader.read_block().is_err());
+
+        // a length past the end of the file isn't allocated up front
+        let mut huge = bytes[..8].to_vec();
+        huge.extend_from_slice(&u64::MAX.to_be_bytes());
+        let mut reader = BlockReader::new(huge.as_slice()).unwrap();
+        assert!(reader.read_block().is_err());
+    }
+
+    #[test]
+    fn test_pruned_database_is_not_exported() {
+        let db = BlockchainDB::temporary().unwrap();
+        for height in 0..3 {
+            db.put_block(height, &empty_block(height)).unwrap();
+        }
+        let path = std::env::temp_dir().join(format!("bootstrap-{}", uuid::Uuid::new_v4()));
+        assert_eq!(export_blocks(&db, &path).unwrap(), 3);
+
+        db.prune_blocks(2).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(export_blocks(&db, &path).is_err());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_rejects_foreign_file() {
+        assert!(BlockReader::new(&b"not a bootstrap file"[..]).is_err());
+    }
+}
@@ -0,0 +1,114 @@
+//! A connection to a node as its tools have, speaking the messages
+//! wallets and miners use
+use crate::handler::DEFAULT_TTL;
+use anyhow::{Context, Result, anyhow};
+use btclib::network::{Envelope, Message};
+use btclib::types::{Block, TransactionOutput};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::time;
+use uuid::Uuid;
+
+// how long the node gets to answer a request
+const REPLY_TIMEOUT: Duration = Duration::from_secs(30);
+// nonces tried between checks of a mined block
+const MINE_STEPS: usize = 1_000_000;
+
+pub struct NodeClient {
+    id: String,
+    reader: OwnedReadHalf,
+    writer: OwnedWriteHalf,
+}
+
+impl NodeClient {
+    pub async fn connect(node: &str) -> Result<Self> {
+        let stream = TcpStream::connect(node)
+            .await
+            .with_context(|| format!("Failed to connect to {node}"))?;
+        let (reader, writer) = stream.into_split();
+        Ok(NodeClient {
+            id: Uuid::new_v4().to_string(),
+            reader,
+            writer,
+        })
+    }
+
+    pub async fn send(&mut self, msg: Message) -> Result<()> {
+        Envelope::new(self.id.clone(), DEFAULT_TTL, msg)
+            .send_async(&mut self.writer)
+            .await?;
+        Ok(())
+    }
+
+    /// Send a request and wait for the reply `pick` accepts, skipping
+    /// the blocks and transactions the node relays meanwhile
+    pub async fn request<T>(&mut self, msg: Message, pick: impl Fn(Message) -> Option<T>) -> Result<T> {
+        self.send(msg).await?;
+        let reply = async {
+            loop {
+                let env = Envelope::receive_async(&mut self.reader).await?;
+                if let Some(reply) = pick(env.msg) {
+                    return Ok(reply);
+                }
+            }
+        };
+        time::timeout(REPLY_TIMEOUT, reply)
+            .await
+            .map_err(|_| anyhow!("node didn't answer"))?
+    }
+
+    /// Confirmed outputs of an address, with whether a pending
+    /// transaction spends them
+    pub async fn utxos(&mut self, address: &str) -> Result<Vec<(TransactionOutput, bool)>> {
+        self.request(Message::FetchUTXOs(address.to_string(), None), |msg| match msg {
+            Message::UTXOs(utxos) => Some(utxos),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Mine a template paying to `address` and submit it. The node
+    /// doesn't answer submissions, so callers watch for the block.
+    pub async fn mine_block(&mut self, address: &str) -> Result<Block> {
+        let mut block = self
+            .request(Message::FetchTemplate(address.to_string()), |msg| match msg {
+                Message::Template(block) => Some(block),
+                _ => None,
+            })
+            .await?;
+        block = tokio::task::spawn_blocking(move || {
+            while !block.header.mine(MINE_STEPS) {}
+            block
+        })
+        .await?;
+        self.send(Message::SubmitTemplate(block.clone())).await?;
+        Ok(block)
+    }
+
+    /// Stop reading replies, discarding whatever the node sends from
+    /// now on. For connections that only submit.
+    pub fn into_sender(self) -> NodeSender {
+        let mut reader = self.reader;
+        tokio::spawn(async move { while Envelope::receive_async(&mut reader).await.is_ok() {} });
+        NodeSender {
+            id: self.id,
+            writer: self.writer,
+        }
+    }
+}
+
+/// A connection only sending to the node, see `NodeClient::into_sender`
+pub struct NodeSender {
+    id: String,
+    writer: OwnedWriteHalf,
+}
+
+impl NodeSender {
+    pub async fn send(&mut self, msg: Message) -> Result<()> {
+        Envelope::new(self.id.clone(), DEFAULT_TTL, msg)
+            .send_async(&mut self.writer)
+            .await?;
+        Ok(())
+    }
+}
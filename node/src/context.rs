@@ -1,52 +1,158 @@
-use crate::database::BlockchainDB;
-use crate::network::NetworkHub;
-use crate::util::populate_connections;
-use anyhow::Result;
-use btclib::types::Blockchain;
-use std::path::Path;
+use crate::addrbook::AddressBook;
+use crate::database::{BlockchainDB, MempoolRestore};
+use crate::journal::{Journal, Record};
+use crate::network::{Identity, NetworkHub};
+use crate::relay::Relay;
+use crate::sync::DownloadScheduler;
+use crate::watch::Watchlist;
+use anyhow::{Context, Result};
+use btclib::events::ChainEvent;
+use btclib::mining::Stats;
+use btclib::network::Services;
+use btclib::params::ChainParams;
+use btclib::types::{Blockchain, MempoolCleanup};
 use std::sync::Arc;
-use tokio::sync::{RwLock};
-use tracing::info;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::{Mutex, RwLock, broadcast};
+use tracing::{info, warn};
 use uuid::Uuid;
 
+/// Events a slow subscriber may fall behind by before missing some
+const EVENT_BUFFER: usize = 1024;
+pub const DEFAULT_MAX_OUTBOUND: usize = 8;
+/// Seconds between mempool cleanups
+pub const DEFAULT_CLEANUP_INTERVAL: u64 = 30;
+
+/// What mempool cleanups and operators removed since the node started
+#[derive(Default)]
+pub struct MempoolStats {
+    pub expired: AtomicU64,
+    pub descendants: AtomicU64,
+    pub unmarked: AtomicU64,
+    /// Transactions dropped by clearing the mempool
+    pub cleared: AtomicU64,
+}
+
+impl MempoolStats {
+    pub fn add_cleanup(&self, cleanup: MempoolCleanup) {
+        self.expired.fetch_add(cleanup.expired as u64, Ordering::Relaxed);
+        self.descendants.fetch_add(cleanup.descendants as u64, Ordering::Relaxed);
+        self.unmarked.fetch_add(cleanup.unmarked as u64, Ordering::Relaxed);
+    }
+}
+
 /// Shared context for the node containing blockchain, database, and peer connections
 #[derive(Clone)]
 pub struct NodeContext {
     pub blockchain: Arc<RwLock<Blockchain>>,
     pub db: Arc<BlockchainDB>,
     pub network: Arc<NetworkHub>,
+    pub downloads: Arc<Mutex<DownloadScheduler>>,
+    pub events: broadcast::Sender<ChainEvent>,
+    /// What the node offers peers. `FULL_BLOCKS` is added while it
+    /// has every block, see `handler::version_info`.
+    pub services: Services,
+    /// Most connections the node opens to other nodes
+    pub max_outbound: usize,
+    /// Nodes to connect to, and on seed nodes to list to others
+    pub addresses: Arc<AddressBook>,
+    /// Answer `DiscoverNodes` with the nodes that recently answered
+    /// our probes, see `util::crawl`
+    pub seed_mode: bool,
+    /// Saved mempool transactions put back or dropped at startup
+    pub mempool_restore: MempoolRestore,
+    /// Where the handled messages are recorded, if anywhere
+    pub journal: Option<Arc<Journal>>,
+    pub mempool_stats: Arc<MempoolStats>,
+    /// Block propagation timings and compact blocks being filled in
+    pub relay: Arc<Relay>,
+    /// Addresses whose history the node indexes, see `crate::watch`
+    pub watchlist: Arc<Watchlist>,
+    /// Stats of the node's own mining threads, when mining with
+    /// `--mine`, see `crate::mining`
+    pub mining: Option<Arc<Stats>>,
 }
 
 impl NodeContext {
-    pub async fn new<P: AsRef<Path>>(db_path: P, nodes: &[String]) -> Result<Self> {
-        info!("opening database at {}", db_path.as_ref().display());
-        let db = Arc::new(BlockchainDB::open(db_path)?);
+    /// A node's context over its database. Connect to other nodes
+    /// with `util::populate_connections`.
+    pub fn new(
+        db: BlockchainDB,
+        params: ChainParams,
+        full_verification: bool,
+        services: Services,
+        identity: Identity,
+    ) -> Result<Self> {
+        let mut ctx = Self::from_db(db, params, full_verification)?.with_identity(identity);
+        ctx.services = services;
+        Ok(ctx)
+    }
+
+    /// A context over an already opened database, not connected to
+    /// any peers yet and offering templates and wallet queries
+    pub fn from_db(db: BlockchainDB, params: ChainParams, full_verification: bool) -> Result<Self> {
+        let db = Arc::new(db);
+        if let Some(version) = db.schema_version()? {
+            info!("database schema version {}", version);
+        }
         
-        // Load blockchain from database or initialize a new one
-        let blockchain = match db.load_blockchain() {
-            Ok(loaded_blockchain) => {
-                info!("blockchain loaded from database");
-                Arc::new(RwLock::new(loaded_blockchain))
-            }
-            Err(_) => {
-                info!("no blockchain found in database, initializing...");
-                Arc::new(RwLock::new(Blockchain::new()))
+        // an empty database loads as an empty chain, anything failing
+        // to load would be overwritten by the next save
+        let (mut blockchain, mempool_restore) = db
+            .load_blockchain_with_report(&params)
+            .context("Failed to load the chain from the database, run `node check-db` to find corrupt records")?;
+        info!("blockchain loaded from database at height {}", blockchain.block_height());
+        blockchain.set_full_verification(full_verification);
+        blockchain.set_max_mempool_age(db.max_mempool_age());
+        for signed in db.get_signed_checkpoints()? {
+            if let Err(e) = blockchain.add_signed_checkpoint(signed) {
+                warn!("dropping stored checkpoint: {e}");
             }
-        };
+        }
+        let blockchain = Arc::new(RwLock::new(blockchain));
+        let watchlist = Arc::new(Watchlist::load(&db)?);
 
         let self_id = Uuid::new_v4().to_string();
         let network = NetworkHub::new(self_id);
 
-        let ctx = Self {
+        Ok(Self {
             blockchain,
             db,
             network,
-        };
+            downloads: Arc::new(Mutex::new(DownloadScheduler::new())),
+            events: broadcast::channel(EVENT_BUFFER).0,
+            services: Services::MINING | Services::WALLET,
+            max_outbound: DEFAULT_MAX_OUTBOUND,
+            addresses: Arc::new(AddressBook::default()),
+            seed_mode: false,
+            mempool_restore,
+            journal: None,
+            mempool_stats: Arc::new(MempoolStats::default()),
+            relay: Arc::new(Relay::default()),
+            watchlist,
+            mining: None,
+        })
+    }
 
-        if !nodes.is_empty() {
-            populate_connections(ctx.clone(), nodes).await?;
+    /// Prove this identity to peers instead of a fresh key. Only
+    /// before connecting to any.
+    pub fn with_identity(mut self, identity: Identity) -> Self {
+        self.network = NetworkHub::with_identity(self.network.self_id.clone(), identity);
+        self
+    }
+
+    /// Append to the journal, if recording
+    pub fn record(&self, record: Record) {
+        if let Some(journal) = &self.journal
+            && let Err(e) = journal.record(record)
+        {
+            warn!("failed to write to the journal: {e}");
         }
+    }
 
-        Ok(ctx)
+    /// Tell event subscribers, if there are any
+    pub fn publish(&self, event: ChainEvent) {
+        // an error only means nobody is listening
+        let _ = self.events.send(event);
     }
 }
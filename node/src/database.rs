@@ -1,489 +1,1491 @@
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, anyhow, bail};
 use btclib::{
+    error::BtcError,
+    params::{ChainParams, SignedCheckpoint},
     sha256::Hash,
-    types::{Block, Transaction, TransactionOutput},
+    types::{Block, BlockHeader, ChainBase, Snapshot, Transaction, TransactionOutput},
     U256,
 };
 use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use std::collections::HashMap;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
-use std::collections::HashMap;
 use ciborium::{ser::into_writer, de::from_reader};
-use hex;
 use btclib::types::Blockchain;
-use tracing::instrument;
-
-/// Database keys for different data types
-mod keys {
-    pub const BLOCK_PREFIX: &str = "block:";
-    pub const UTXO_PREFIX: &str = "utxo:";
-    pub const MEMPOOL_PREFIX: &str = "mempool:";
-    pub const META_TARGET: &str = "meta:target";
-    pub const META_BLOCK_COUNT: &str = "meta:block_count";
-    pub const META_UTXO_KEYS: &str = "meta:utxo_keys";
-    pub const META_MEMPOOL_KEYS: &str = "meta:mempool_keys";
+use tracing::{info, instrument};
+
+mod encryption;
+mod memory;
+mod migrations;
+#[cfg(feature = "rocksdb")]
+mod rocks;
+mod storage;
+
+pub use encryption::{DbSecret, PASSPHRASE_VAR};
+pub use memory::MemoryStorage;
+use encryption::Cipher;
+pub use storage::{Backend, Batch, Records, SledStorage, Storage};
+
+/// Trees the records are kept in, each keyed its own way
+mod trees {
+    /// Block bodies by big-endian height
+    pub const BLOCKS: &str = "blocks";
+    /// Headers and hashes of pruned blocks by big-endian height
+    pub const HEADERS: &str = "headers";
+    /// Unspent outputs by hash
+    pub const UTXOS: &str = "utxos";
+    /// Mempool transactions by hash and big-endian time added
+    pub const MEMPOOL: &str = "mempool";
+    /// Blocks of branches off the main chain by hash
+    pub const SIDE_BLOCKS: &str = "side_blocks";
+    /// Signed checkpoints by big-endian height
+    pub const CHECKPOINTS: &str = "checkpoints";
+    /// Watched addresses
+    pub const WATCHED: &str = "watched";
+    /// History entries by address, height and transaction
+    pub const HISTORY: &str = "history";
+    /// Outputs paying watched addresses by hash
+    pub const WATCHED_OUTPUTS: &str = "watched_outputs";
+    /// Target, block count and the like by name
+    pub const META: &str = "meta";
+    /// The schema version and encryption header, and every record
+    /// up to schema v5
+    pub const DEFAULT: &str = "default";
+
+    /// All but the default tree
+    pub const ALL: [&str; 10] = [
+        BLOCKS,
+        HEADERS,
+        UTXOS,
+        MEMPOOL,
+        SIDE_BLOCKS,
+        CHECKPOINTS,
+        WATCHED,
+        HISTORY,
+        WATCHED_OUTPUTS,
+        META,
+    ];
+}
+
+/// Keys of the meta tree
+mod meta {
+    pub const TARGET: &[u8] = b"target";
+    pub const BLOCK_COUNT: &[u8] = b"block_count";
+    pub const SNAPSHOT: &[u8] = b"snapshot";
+    pub const CHAIN_BASE: &[u8] = b"chain_base";
+    pub const HISTORY_TIP: &[u8] = b"history_tip";
+}
+
+fn height_key(height: u64) -> [u8; 8] {
+    height.to_be_bytes()
+}
+
+// height a record of the blocks, headers or checkpoints is stored at
+fn key_height(key: &[u8]) -> Result<u64> {
+    let bytes: [u8; 8] = key.try_into().map_err(|_| anyhow!("Malformed height key in database"))?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+// Include timestamp in key to handle duplicate transactions with different timestamps
+fn mempool_key(tx_hash: &Hash, timestamp: DateTime<Utc>) -> Vec<u8> {
+    let timestamp_nanos = timestamp.timestamp_nanos_opt().unwrap_or(0);
+    let mut key = tx_hash.as_bytes().to_vec();
+    key.extend(timestamp_nanos.to_be_bytes());
+    key
+}
+
+// entries of an address sort by height, then by transaction
+fn history_key(address: &str, entry: &HistoryEntry) -> String {
+    format!("{}:{:020}:{}", address, entry.height, entry.txid)
+}
+
+// a key the way it is looked up: heights and hashes as the node shows
+// them, text as is
+fn display_key(tree: &str, key: &[u8]) -> String {
+    match (tree, key.len()) {
+        (trees::BLOCKS | trees::HEADERS | trees::CHECKPOINTS, 8) => key_height(key).expect("8 bytes").to_string(),
+        (trees::UTXOS | trees::SIDE_BLOCKS | trees::WATCHED_OUTPUTS, 32) => {
+            Hash::from_bytes(key.try_into().expect("32 bytes")).to_string()
+        }
+        (trees::MEMPOOL, 40) => {
+            let hash = Hash::from_bytes(key[..32].try_into().expect("32 bytes"));
+            let nanos = i64::from_be_bytes(key[32..].try_into().expect("8 bytes"));
+            format!("{hash} added {}", DateTime::from_timestamp_nanos(nanos))
+        }
+        _ => String::from_utf8_lossy(key).into_owned(),
+    }
+}
+
+// bytes of the CRC32 in front of every value
+const CHECKSUM_LEN: usize = 4;
+
+fn seal(value: &[u8]) -> Vec<u8> {
+    let mut sealed = Vec::with_capacity(value.len() + CHECKSUM_LEN);
+    sealed.extend(crc32fast::hash(value).to_be_bytes());
+    sealed.extend_from_slice(value);
+    sealed
+}
+
+fn unseal(sealed: &[u8]) -> Result<&[u8]> {
+    if sealed.len() < CHECKSUM_LEN {
+        bail!("shorter than its checksum");
+    }
+    let (checksum, value) = sealed.split_at(CHECKSUM_LEN);
+    if crc32fast::hash(value).to_be_bytes() != checksum {
+        bail!("checksum mismatch");
+    }
+    Ok(value)
+}
+
+fn encode<T: Serialize + ?Sized>(value: &T, what: &str) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    into_writer(value, &mut bytes).with_context(|| format!("Failed to serialize {what}"))?;
+    Ok(bytes)
+}
+
+fn decode<T: DeserializeOwned>(bytes: &[u8], what: &str) -> Result<T> {
+    from_reader(bytes).with_context(|| format!("Failed to deserialize {what}"))
+}
+
+/// A tree whose values start with a CRC32 of the rest, checked on
+/// every read so a damaged record is reported as such, naming it,
+/// rather than failing to deserialize somewhere. In an encrypted
+/// database the rest is the value encrypted.
+#[derive(Clone)]
+struct CheckedTree {
+    name: &'static str,
+    storage: Arc<dyn Storage>,
+    cipher: Option<Arc<Cipher>>,
+}
+
+impl CheckedTree {
+    fn open(storage: &Arc<dyn Storage>, name: &'static str, cipher: Option<Arc<Cipher>>) -> Self {
+        Self {
+            name,
+            storage: storage.clone(),
+            cipher,
+        }
+    }
+
+    // what encryption authenticates along with a value, so it can't be
+    // moved to another key or tree
+    fn aad(&self, key: &[u8]) -> Vec<u8> {
+        [self.name.as_bytes(), b":", key].concat()
+    }
+
+    fn seal(&self, key: &[u8], value: &[u8]) -> Vec<u8> {
+        match &self.cipher {
+            Some(cipher) => seal(&cipher.encrypt(&self.aad(key), value)),
+            None => seal(value),
+        }
+    }
+
+    // the stored value back, not saying which record it is
+    fn open_value(&self, key: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+        let value = unseal(sealed)?;
+        match &self.cipher {
+            Some(cipher) => cipher.decrypt(&self.aad(key), value),
+            None => Ok(value.to_vec()),
+        }
+    }
+
+    fn unseal(&self, key: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+        self.open_value(key, sealed)
+            .with_context(|| format!("Corrupt record {} in {}", display_key(self.name, key), self.name))
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.storage.get(self.name, key)? {
+            Some(sealed) => Ok(Some(self.unseal(key, &sealed)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_decoded<T: DeserializeOwned>(&self, key: &[u8], what: &str) -> Result<Option<T>> {
+        self.get(key)?.map(|value| decode(&value, what)).transpose()
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.storage.put(self.name, key, &self.seal(key, value))
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<()> {
+        self.storage.delete(self.name, key)
+    }
+
+    /// Keys and checked values of the records under `prefix`, in key
+    /// order
+    fn scan(&self, prefix: &[u8]) -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + '_ {
+        self.storage.scan_prefix(self.name, prefix).map(move |item| {
+            let (key, sealed) = item?;
+            let value = self.unseal(&key, &sealed)?;
+            Ok((key, value))
+        })
+    }
+
+    /// Keys and values as stored, unchecked
+    fn raw(&self) -> Records<'_> {
+        self.storage.scan_prefix(self.name, &[])
+    }
+
+    fn keys(&self) -> impl Iterator<Item = Result<Vec<u8>>> + '_ {
+        self.raw().map(|item| item.map(|(key, _)| key))
+    }
+
+    /// Add writing `value` under `key` to `batch`
+    fn batch_insert(&self, batch: &mut Batch, key: &[u8], value: &[u8]) {
+        batch.put(self.name, key, self.seal(key, value));
+    }
+
+    fn batch_remove(&self, batch: &mut Batch, key: &[u8]) {
+        batch.delete(self.name, key);
+    }
+}
+
+// read a record back the way the node does, short of its checksum
+fn check_record(tree: &str, key: &[u8], value: &[u8]) -> Result<()> {
+    match tree {
+        trees::BLOCKS => key_height(key).and(decode_block(value)).map(drop),
+        trees::SIDE_BLOCKS => decode_block(value).map(drop),
+        trees::HEADERS => key_height(key).and(decode::<(BlockHeader, Hash)>(value, "block header")).map(drop),
+        trees::UTXOS => decode::<(bool, u64, TransactionOutput)>(value, "UTXO").map(drop),
+        trees::MEMPOOL => decode::<(DateTime<Utc>, u64, Transaction)>(value, "mempool transaction").map(drop),
+        trees::CHECKPOINTS => key_height(key).and(decode::<SignedCheckpoint>(value, "checkpoint")).map(drop),
+        trees::HISTORY => decode::<HistoryEntry>(value, "history entry").map(drop),
+        trees::WATCHED_OUTPUTS => decode::<WatchedOutput>(value, "watched output").map(drop),
+        trees::META => match key {
+            meta::TARGET => decode::<U256>(value, "target").map(drop),
+            meta::BLOCK_COUNT => key_height(value).map(drop),
+            meta::SNAPSHOT => decode::<Snapshot>(value, "snapshot").map(drop),
+            meta::CHAIN_BASE => decode::<ChainBase>(value, "chain base").map(drop),
+            meta::HISTORY_TIP => decode::<(u64, Hash)>(value, "history tip").map(drop),
+            _ => Ok(()),
+        },
+        _ => Ok(()),
+    }
+}
+
+/// A confirmed transaction touching a watched address, see
+/// `crate::watch`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct HistoryEntry {
+    pub txid: Hash,
+    pub height: u64,
+    /// Hash of the block holding the transaction
+    pub block: Hash,
+    /// Paid to the address
+    pub received: u64,
+    /// Spent from earlier payments to the address
+    pub sent: u64,
+}
+
+/// An output paying a watched address, kept to recognize the inputs
+/// spending it
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct WatchedOutput {
+    pub address: String,
+    pub value: u64,
+    /// Height of the block that created it
+    pub height: u64,
+}
+
+/// Additions to the history index, written at once with
+/// `put_history`
+#[derive(Default)]
+pub struct HistoryBatch {
+    pub entries: Vec<(String, HistoryEntry)>,
+    pub outputs: Vec<(Hash, WatchedOutput)>,
+}
+
+/// A saved mempool transaction
+pub struct MempoolEntry {
+    /// When it entered the mempool, which is when it expires from
+    pub added: DateTime<Utc>,
+    /// Fee it paid when saved
+    pub fee: u64,
+    pub transaction: Transaction,
+}
+
+/// What became of the saved mempool when the chain was loaded
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MempoolRestore {
+    pub restored: usize,
+    /// Older than the maximum mempool age
+    pub expired: usize,
+    /// No longer valid against the chain, e.g. their inputs got spent
+    pub invalid: usize,
+}
+
+// header byte in front of every stored block
+const BLOCK_RAW: u8 = 0;
+const BLOCK_ZSTD: u8 = 1;
+
+// zstd's default level, a good tradeoff for data written once per block
+const ZSTD_LEVEL: i32 = 3;
+
+fn encode_block(block: &Block, compress: bool) -> Result<Vec<u8>> {
+    let cbor = encode(block, "block")?;
+    let mut value = Vec::with_capacity(cbor.len() + 1);
+    if compress {
+        value.push(BLOCK_ZSTD);
+        zstd::stream::copy_encode(cbor.as_slice(), &mut value, ZSTD_LEVEL)
+            .context("Failed to compress block")?;
+    } else {
+        value.push(BLOCK_RAW);
+        value.extend(cbor);
+    }
+    Ok(value)
+}
+
+fn decode_block(value: &[u8]) -> Result<Block> {
+    match value.split_first() {
+        Some((&BLOCK_RAW, cbor)) => decode(cbor, "block"),
+        Some((&BLOCK_ZSTD, compressed)) => {
+            let cbor = zstd::stream::decode_all(compressed)
+                .context("Failed to decompress block")?;
+            decode(&cbor, "block")
+        }
+        _ => bail!("Unknown block encoding in database"),
+    }
+}
+
+/// Records stored in one tree, see `BlockchainDB::keyspace_usage`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyspaceUsage {
+    pub name: String,
+    pub entries: u64,
+    pub bytes: u64,
+}
+
+/// A record failing its checksum or not reading back, see
+/// `BlockchainDB::check`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CorruptRecord {
+    pub tree: &'static str,
+    pub key: String,
+    pub error: String,
+}
+
+/// What `BlockchainDB::check` found
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CheckReport {
+    /// Records read
+    pub records: u64,
+    pub corrupt: Vec<CorruptRecord>,
+}
+
+// records copied per write while backing up
+const BACKUP_BATCH: usize = 10_000;
+
+/// A copy of the database written by `BlockchainDB::backup`
+pub struct Backup {
+    db: BlockchainDB,
+    /// Records copied
+    records: u64,
+}
+
+impl Backup {
+    /// Read back every record of the copy like `BlockchainDB::check`,
+    /// failing if any is corrupt or missing
+    pub fn verify(self) -> Result<BackupReport> {
+        let report = self.db.check()?;
+        if let Some(record) = report.corrupt.first() {
+            bail!(
+                "{} records of the backup are corrupt, the first {} {}: {}",
+                report.corrupt.len(),
+                record.tree,
+                record.key,
+                record.error
+            );
+        }
+        // the default tree isn't checked, it has no records of the chain
+        let default = self.db.storage.scan_prefix(trees::DEFAULT, &[]).count() as u64;
+        if report.records + default != self.records {
+            bail!("the backup holds {} records of the {} copied", report.records + default, self.records);
+        }
+        Ok(BackupReport {
+            records: self.records,
+            height: self.db.get_block_count()?.unwrap_or(0),
+        })
+    }
+}
+
+/// What `Backup::verify` found in a good backup
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BackupReport {
+    pub records: u64,
+    /// Number of blocks of the chain it holds
+    pub height: u64,
 }
 
 /// Wrapper around Sled (LevelDB-like) for blockchain storage
 pub struct BlockchainDB {
-    db: Arc<sled::Db>,
-    // Mutexes to protect key list updates from race conditions
-    utxo_keys_mutex: Arc<Mutex<()>>,
-    mempool_keys_mutex: Arc<Mutex<()>>,
+    storage: Arc<dyn Storage>,
+    blocks: CheckedTree,
+    headers: CheckedTree,
+    utxos: CheckedTree,
+    mempool: CheckedTree,
+    side_blocks: CheckedTree,
+    checkpoints: CheckedTree,
+    watched: CheckedTree,
+    history: CheckedTree,
+    watched_outputs: CheckedTree,
+    meta: CheckedTree,
+    /// Whether newly written blocks are zstd compressed
+    compress_blocks: bool,
+    /// Seconds loaded chains keep mempool transactions for
+    max_mempool_age: u64,
+    /// Height of the chain last loaded or saved, whose blocks above a
+    /// shorter chain saved next are stale. Unknown until then, so a
+    /// chain saved without loading first never deletes blocks.
+    saved_height: Mutex<Option<u64>>,
 }
 
 impl BlockchainDB {
-    /// Open or create a new database at the given path
-    #[instrument(skip_all, fields(path = %path.as_ref().to_string_lossy()))]
-    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let db = sled::open(path)
-            .context("Failed to open/create database")?;
-        Ok(Self {
-            db: Arc::new(db),
-            utxo_keys_mutex: Arc::new(Mutex::new(())),
-            mempool_keys_mutex: Arc::new(Mutex::new(())),
-        })
+    /// Open or create a new database at the given path, upgrading
+    /// its layout to the current schema version if needed. An
+    /// encrypted database needs the secret it was created with; a new
+    /// one given a secret is encrypted under it.
+    pub fn open<P: AsRef<Path>>(path: P, secret: Option<&DbSecret>) -> Result<Self> {
+        Self::open_with(Backend::Sled, path, secret)
+    }
+
+    /// Open or create a database at the given path kept by `backend`
+    #[instrument(skip_all, fields(path = %path.as_ref().to_string_lossy(), ?backend))]
+    pub fn open_with<P: AsRef<Path>>(backend: Backend, path: P, secret: Option<&DbSecret>) -> Result<Self> {
+        Self::from_storage(backend.open(path.as_ref())?.into(), secret)
+    }
+
+    /// A database living in memory until dropped, for tests,
+    /// simulations and ephemeral nodes
+    pub fn temporary() -> Result<Self> {
+        Self::from_storage(Arc::new(MemoryStorage::new()), None)
+    }
+
+    /// A database kept in `storage`, upgraded to the current schema
+    /// version if needed
+    pub fn from_storage(storage: Arc<dyn Storage>, secret: Option<&DbSecret>) -> Result<Self> {
+        migrations::upgrade(&*storage)?;
+        let cipher = encryption::unlock(&*storage, secret)?.map(Arc::new);
+        Ok(Self::with_cipher(storage, cipher))
+    }
+
+    fn with_cipher(storage: Arc<dyn Storage>, cipher: Option<Arc<Cipher>>) -> Self {
+        let open = |name| CheckedTree::open(&storage, name, cipher.clone());
+        Self {
+            blocks: open(trees::BLOCKS),
+            headers: open(trees::HEADERS),
+            utxos: open(trees::UTXOS),
+            mempool: open(trees::MEMPOOL),
+            side_blocks: open(trees::SIDE_BLOCKS),
+            checkpoints: open(trees::CHECKPOINTS),
+            watched: open(trees::WATCHED),
+            history: open(trees::HISTORY),
+            watched_outputs: open(trees::WATCHED_OUTPUTS),
+            meta: open(trees::META),
+            storage,
+            compress_blocks: false,
+            max_mempool_age: btclib::MAX_MEMPOOL_TRANSACTION_AGE,
+            saved_height: Mutex::new(None),
+        }
+    }
+
+    // in the order `check` and `keyspace_usage` list them
+    fn trees(&self) -> [&CheckedTree; 10] {
+        [
+            &self.blocks,
+            &self.headers,
+            &self.utxos,
+            &self.mempool,
+            &self.side_blocks,
+            &self.checkpoints,
+            &self.watched,
+            &self.history,
+            &self.watched_outputs,
+            &self.meta,
+        ]
+    }
+
+    /// Compress blocks written from now on. Blocks already stored are
+    /// read either way, see `compact_blocks` to recompress them.
+    pub fn with_block_compression(mut self, compress: bool) -> Self {
+        self.compress_blocks = compress;
+        self
+    }
+
+    /// Keep the mempool transactions of loaded chains for this many
+    /// seconds. Saved ones older than that aren't restored.
+    pub fn with_max_mempool_age(mut self, seconds: u64) -> Self {
+        self.max_mempool_age = seconds;
+        self
+    }
+
+    pub fn max_mempool_age(&self) -> u64 {
+        self.max_mempool_age
+    }
+
+    /// Whether the values are stored encrypted
+    pub fn is_encrypted(&self) -> bool {
+        self.meta.cipher.is_some()
+    }
+
+    /// Retrieve the schema version the database is stored in
+    #[instrument(skip(self))]
+    pub fn schema_version(&self) -> Result<Option<u32>> {
+        migrations::stored_version(&*self.storage)
+    }
+
+    /// Sync all writes so far to disk, blocking until done
+    pub fn flush(&self) -> Result<()> {
+        self.storage.flush()
+    }
+
+    /// Wait until all writes so far are synced to disk
+    pub async fn flush_async(&self) -> Result<()> {
+        let storage = self.storage.clone();
+        tokio::task::spawn_blocking(move || storage.flush())
+            .await
+            .context("Failed to flush database")?
+    }
+
+    /// Read every record of every tree, verifying its checksum and
+    /// that it deserializes, and list those that don't instead of
+    /// failing on the first
+    #[instrument(skip(self))]
+    pub fn check(&self) -> Result<CheckReport> {
+        let mut report = CheckReport::default();
+        for tree in self.trees() {
+            for item in tree.raw() {
+                let (key, sealed) = item?;
+                report.records += 1;
+                if let Err(e) = tree.open_value(&key, &sealed).and_then(|value| check_record(tree.name, &key, &value)) {
+                    report.corrupt.push(CorruptRecord {
+                        tree: tree.name,
+                        key: display_key(tree.name, &key),
+                        error: format!("{e:#}"),
+                    });
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// Copy every record as stored to a new sled database at `dest`,
+    /// encrypted ones staying encrypted under the same secret. The
+    /// copy is consistent if nothing writes meanwhile; see
+    /// `Backup::verify` to read it back.
+    #[instrument(skip(self))]
+    pub fn backup(&self, dest: &Path) -> Result<Backup> {
+        if dest.read_dir().is_ok_and(|mut entries| entries.next().is_some()) {
+            bail!("{} already exists and isn't empty", dest.display());
+        }
+        let storage: Arc<dyn Storage> = Arc::new(SledStorage::open(dest)?);
+        let mut records = 0;
+        for tree in [trees::DEFAULT].into_iter().chain(trees::ALL) {
+            let mut batch = Batch::default();
+            for item in self.storage.scan_prefix(tree, &[]) {
+                let (key, value) = item?;
+                batch.put(tree, key, value);
+                records += 1;
+                if batch.writes.len() == BACKUP_BATCH {
+                    storage.apply(std::mem::take(&mut batch))?;
+                }
+            }
+            storage.apply(batch)?;
+        }
+        storage.flush()?;
+        let db = Self::with_cipher(storage, self.meta.cipher.clone());
+        Ok(Backup { db, records })
+    }
+
+    /// Number of blocks of the chain last saved
+    #[instrument(skip(self))]
+    pub fn get_block_count(&self) -> Result<Option<u64>> {
+        self.meta.get(meta::BLOCK_COUNT)?.map(|value| key_height(&value)).transpose()
     }
 
     /// Store a block at the given index
     #[instrument(skip(self, block))]
     pub fn put_block(&self, index: u64, block: &Block) -> Result<()> {
-        let key = format!("{}{}", keys::BLOCK_PREFIX, index);
-        
-        let mut value = Vec::new();
-        into_writer(block, &mut value)
-            .context("Failed to serialize block")?;
-        
-        self.db
-            .insert(key.as_bytes(), value)
-            .context("Failed to write block to database")?;
-        Ok(())
+        let value = encode_block(block, self.compress_blocks)?;
+        self.blocks.insert(&height_key(index), &value)
     }
 
     /// Retrieve a block at the given index
     #[instrument(skip(self))]
     pub fn get_block(&self, index: u64) -> Result<Option<Block>> {
-        let key = format!("{}{}", keys::BLOCK_PREFIX, index);
-        
-        match self.db.get(key.as_bytes()).context("Failed to read block from database")? {
-            Some(value) => {
-                let block: Block = from_reader(value.as_ref())
-                    .context("Failed to deserialize block")?;
-                Ok(Some(block))
-            }
+        match self.blocks.get(&height_key(index))? {
+            Some(value) => Ok(Some(decode_block(&value)?)),
             None => Ok(None),
         }
     }
 
-    /// Get all blocks in order
+    /// Size in bytes of the stored block at the given index
     #[instrument(skip(self))]
-    pub fn get_all_blocks(&self) -> Result<Vec<Block>> {
-        let mut blocks = Vec::new();
-        let mut index = 0u64;
-        loop {
-            match self.get_block(index)? {
-                Some(block) => {
-                    blocks.push(block);
-                    index += 1;
-                }
-                None => break,
-            }
+    pub fn block_size(&self, index: u64) -> Result<Option<u64>> {
+        let value = self.blocks.get(&height_key(index))?;
+        Ok(value.map(|value| value.len() as u64))
+    }
+
+    /// Total size in bytes of all stored block bodies
+    #[instrument(skip(self))]
+    pub fn block_storage_size(&self) -> Result<u64> {
+        let mut size = 0;
+        for item in self.blocks.scan(&[]) {
+            let (_, value) = item?;
+            size += value.len() as u64;
         }
-        Ok(blocks)
+        Ok(size)
     }
 
-    /// Store a UTXO
-    #[instrument(skip(self, hash, output))]
-    pub fn put_utxo(&self, hash: &Hash, marked: bool, output: &TransactionOutput) -> Result<()> {
-        let hash_bytes = hash.as_bytes();
-        let hash_hex = hex::encode(hash_bytes);
-        let key = format!("{}{}", keys::UTXO_PREFIX, hash_hex);
-        
-        let mut value = Vec::new();
-        into_writer(&(marked, output), &mut value)
-            .context("Failed to serialize UTXO")?;
-        
-        self.db
-            .insert(key.as_bytes(), value)
-            .context("Failed to write UTXO to database")?;
-        
-        // Update UTXO keys list atomically to prevent race conditions
-        let _guard = self.utxo_keys_mutex.lock().unwrap();
-        let mut utxo_keys = self.get_utxo_keys()?.unwrap_or_default();
-        if !utxo_keys.contains(hash) {
-            utxo_keys.push(*hash);
-            self.put_utxo_keys(&utxo_keys)?;
-        }
-        
-        Ok(())
+    /// Replace the bodies of all blocks below `height` with their
+    /// headers and hashes, returning the number of blocks pruned
+    #[instrument(skip(self))]
+    pub fn prune_blocks(&self, height: u64) -> Result<u64> {
+        let mut batch = Batch::default();
+        let mut pruned = 0;
+        for item in self.blocks.scan(&[]) {
+            let (key, value) = item?;
+            if key_height(&key)? >= height {
+                break;
+            }
+            let block = decode_block(&value)?;
+            let header = encode(&(&block.header, block.hash()), "block header")?;
+            self.headers.batch_insert(&mut batch, &key, &header);
+            self.blocks.batch_remove(&mut batch, &key);
+            pruned += 1;
+        }
+        self.storage.apply(batch)?;
+        Ok(pruned)
     }
 
-    /// Retrieve a UTXO
-    #[instrument(skip(self, hash))]
-    pub fn get_utxo(&self, hash: &Hash) -> Result<Option<(bool, TransactionOutput)>> {
-        let hash_bytes = hash.as_bytes();
-        let hash_hex = hex::encode(hash_bytes);
-        let key = format!("{}{}", keys::UTXO_PREFIX, hash_hex);
-        
-        match self.db.get(key.as_bytes()).context("Failed to read UTXO from database")? {
-            Some(value) => {
-                let utxo: (bool, TransactionOutput) = from_reader(value.as_ref())
-                    .context("Failed to deserialize UTXO")?;
-                Ok(Some(utxo))
+    /// Rewrite every stored block zstd compressed, returning the total
+    /// size of the blocks before and after
+    #[instrument(skip(self))]
+    pub fn compact_blocks(&self) -> Result<(u64, u64)> {
+        let (mut before, mut after) = (0, 0);
+        for item in self.blocks.scan(&[]) {
+            let (key, value) = item?;
+            before += value.len() as u64;
+            if value.first() == Some(&BLOCK_ZSTD) {
+                after += value.len() as u64;
+                continue;
             }
-            None => Ok(None),
+            let compressed = encode_block(&decode_block(&value)?, true)?;
+            after += compressed.len() as u64;
+            // one block at a time, an interrupted run leaves a readable mix
+            self.blocks.insert(&key, &compressed)?;
         }
+        self.storage.flush()?;
+        Ok((before, after))
     }
 
-    /// Delete a UTXO
-    #[instrument(skip(self, hash))]
-    pub fn delete_utxo(&self, hash: &Hash) -> Result<()> {
-        let hash_bytes = hash.as_bytes();
-        let hash_hex = hex::encode(hash_bytes);
-        let key = format!("{}{}", keys::UTXO_PREFIX, hash_hex);
-        
-        self.db
-            .remove(key.as_bytes())
-            .context("Failed to delete UTXO from database")?;
-        
-        // Update UTXO keys list atomically to prevent race conditions
-        let _guard = self.utxo_keys_mutex.lock().unwrap();
-        if let Some(mut utxo_keys) = self.get_utxo_keys()? {
-            utxo_keys.retain(|h| h != hash);
-            self.put_utxo_keys(&utxo_keys)?;
-        }
-        
-        Ok(())
+    /// Get all blocks in order, starting at the given index
+    #[instrument(skip(self))]
+    pub fn get_blocks_from(&self, start: u64) -> Result<Vec<Block>> {
+        let mut blocks = Vec::new();
+        let mut index = start;
+        while let Some(block) = self.get_block(index)? {
+            blocks.push(block);
+            index += 1;
+        }
+        Ok(blocks)
     }
 
-    /// Get all UTXOs
+    /// Get all UTXOs with their mempool marks and creation heights
     #[instrument(skip(self))]
-    pub fn get_all_utxos(&self) -> Result<HashMap<Hash, (bool, TransactionOutput)>> {
+    pub fn get_all_utxos(&self) -> Result<HashMap<Hash, (bool, u64, TransactionOutput)>> {
         let mut utxos = HashMap::new();
-        
-        let utxo_keys = self.get_utxo_keys()?.unwrap_or_default();
-        for hash in utxo_keys {
-            if let Some(utxo) = self.get_utxo(&hash)? {
-                utxos.insert(hash, utxo);
-            }
+
+        for item in self.utxos.scan(&[]) {
+            let (_, value) = item?;
+            let (marked, height, output): (bool, u64, TransactionOutput) = decode(&value, "UTXO")?;
+            utxos.insert(output.hash(), (marked, height, output));
         }
-        
+
         Ok(utxos)
     }
 
-    /// Store a mempool transaction
-    /// Uses hash + timestamp as key to preserve duplicate transactions with different timestamps
-    #[instrument(skip(self, tx_hash, tx))]
-    pub fn put_mempool_tx(&self, tx_hash: &Hash, timestamp: DateTime<Utc>, tx: &Transaction) -> Result<()> {
-        let hash_bytes = tx_hash.as_bytes();
-        let hash_hex = hex::encode(hash_bytes);
-        // Include timestamp in key to handle duplicate transactions with different timestamps
-        let timestamp_nanos = timestamp.timestamp_nanos_opt().unwrap_or(0);
-        let key = format!("{}{}:{}", keys::MEMPOOL_PREFIX, hash_hex, timestamp_nanos);
-        
-        let mut value = Vec::new();
-        into_writer(&(timestamp, tx), &mut value)
-            .context("Failed to serialize mempool transaction")?;
-        
-        self.db
-            .insert(key.as_bytes(), value)
-            .context("Failed to write mempool transaction to database")?;
-        
-        // Update mempool keys list atomically to prevent race conditions
-        let _guard = self.mempool_keys_mutex.lock().unwrap();
-        let mut mempool_keys = self.get_mempool_keys()?.unwrap_or_default();
-        // Store (hash, timestamp) pair to preserve duplicates
-        let key_pair = (*tx_hash, timestamp);
-        if !mempool_keys.contains(&key_pair) {
-            mempool_keys.push(key_pair);
-            self.put_mempool_keys(&mempool_keys)?;
-        }
-        
-        Ok(())
+    /// Get all mempool transactions, oldest first
+    #[instrument(skip(self))]
+    pub fn get_all_mempool_txs(&self) -> Result<Vec<MempoolEntry>> {
+        let mut mempool = Vec::new();
+
+        for item in self.mempool.scan(&[]) {
+            let (_, value) = item?;
+            let (added, fee, transaction): (DateTime<Utc>, u64, Transaction) =
+                decode(&value, "mempool transaction")?;
+            mempool.push(MempoolEntry { added, fee, transaction });
+        }
+        // keys are ordered by hash, restore insertion order
+        mempool.sort_by_key(|entry| entry.added);
+
+        Ok(mempool)
     }
 
-    /// Retrieve a mempool transaction by hash and timestamp
-    #[instrument(skip(self, tx_hash))]
-    pub fn get_mempool_tx(&self, tx_hash: &Hash, timestamp: DateTime<Utc>) -> Result<Option<(DateTime<Utc>, Transaction)>> {
-        let hash_bytes = tx_hash.as_bytes();
-        let hash_hex = hex::encode(hash_bytes);
-        let timestamp_nanos = timestamp.timestamp_nanos_opt().unwrap_or(0);
-        let key = format!("{}{}:{}", keys::MEMPOOL_PREFIX, hash_hex, timestamp_nanos);
-        
-        match self.db.get(key.as_bytes()).context("Failed to read mempool transaction from database")? {
-            Some(value) => {
-                let mempool_tx: (DateTime<Utc>, Transaction) = from_reader(value.as_ref())
-                    .context("Failed to deserialize mempool transaction")?;
-                Ok(Some(mempool_tx))
-            }
-            None => Ok(None),
+    /// Get a single UTXO with its mempool mark and creation height
+    #[instrument(skip(self))]
+    pub fn get_utxo(&self, hash: &Hash) -> Result<Option<(bool, u64, TransactionOutput)>> {
+        self.utxos.get_decoded(&hash.as_bytes(), "UTXO")
+    }
+
+    /// Store a UTXO with its mempool mark and creation height
+    #[instrument(skip(self, hash, output))]
+    pub fn put_utxo(&self, hash: &Hash, marked: bool, height: u64, output: &TransactionOutput) -> Result<()> {
+        self.utxos.insert(&hash.as_bytes(), &encode(&(marked, height, output), "UTXO")?)
+    }
+
+    /// Delete a UTXO
+    #[instrument(skip(self))]
+    pub fn delete_utxo(&self, hash: &Hash) -> Result<()> {
+        self.utxos.remove(&hash.as_bytes())
+    }
+
+    /// Delete every saved mempool transaction
+    #[instrument(skip(self))]
+    pub fn clear_mempool(&self) -> Result<()> {
+        let mut batch = Batch::default();
+        for key in self.mempool.keys() {
+            self.mempool.batch_remove(&mut batch, &key?);
         }
+        self.storage.apply(batch).context("Failed to clear the mempool in database")
     }
 
-    /// Delete a mempool transaction by hash and timestamp
-    #[instrument(skip(self, tx_hash))]
-    pub fn delete_mempool_tx(&self, tx_hash: &Hash, timestamp: DateTime<Utc>) -> Result<()> {
-        let hash_bytes = tx_hash.as_bytes();
-        let hash_hex = hex::encode(hash_bytes);
-        let timestamp_nanos = timestamp.timestamp_nanos_opt().unwrap_or(0);
-        let key = format!("{}{}:{}", keys::MEMPOOL_PREFIX, hash_hex, timestamp_nanos);
-        
-        self.db
-            .remove(key.as_bytes())
-            .context("Failed to delete mempool transaction from database")?;
-        
-        // Update mempool keys list atomically to prevent race conditions
-        let _guard = self.mempool_keys_mutex.lock().unwrap();
-        if let Some(mut mempool_keys) = self.get_mempool_keys()? {
-            let key_pair = (*tx_hash, timestamp);
-            mempool_keys.retain(|k| k != &key_pair);
-            self.put_mempool_keys(&mempool_keys)?;
-        }
-        
-        Ok(())
+    /// Header and hash of a block whose body was pruned
+    #[instrument(skip(self))]
+    pub fn get_pruned_header(&self, index: u64) -> Result<Option<(BlockHeader, Hash)>> {
+        self.headers.get_decoded(&height_key(index), "block header")
     }
 
-    /// Get all mempool transactions
+    /// Lowest and highest height of the stored block bodies
     #[instrument(skip(self))]
-    pub fn get_all_mempool_txs(&self) -> Result<Vec<(DateTime<Utc>, Transaction)>> {
-        let mut mempool = Vec::new();
-        
-        let mempool_keys = self.get_mempool_keys()?.unwrap_or_default();
-        for (tx_hash, timestamp) in mempool_keys {
-            if let Some(tx) = self.get_mempool_tx(&tx_hash, timestamp)? {
-                mempool.push(tx);
+    pub fn block_range(&self) -> Result<Option<(u64, u64)>> {
+        // heights are big-endian, so the keys sort by them
+        let first = self.blocks.raw().next().transpose()?;
+        let last = self.storage.last(trees::BLOCKS)?;
+        match (first, last) {
+            (Some((low, _)), Some((high, _))) => Ok(Some((key_height(&low)?, key_height(&high)?))),
+            _ => Ok(None),
+        }
+    }
+
+    /// Number of records and bytes of keys and values stored in each
+    /// tree that isn't empty
+    #[instrument(skip(self))]
+    pub fn keyspace_usage(&self) -> Result<Vec<KeyspaceUsage>> {
+        let mut usage: Vec<KeyspaceUsage> = vec![];
+        for tree in self.trees() {
+            let mut keyspace = KeyspaceUsage {
+                name: tree.name.to_string(),
+                entries: 0,
+                bytes: 0,
+            };
+            for item in tree.raw() {
+                let (key, value) = item?;
+                keyspace.entries += 1;
+                keyspace.bytes += (key.len() + value.len()) as u64;
+            }
+            if keyspace.entries > 0 {
+                usage.push(keyspace);
             }
         }
-        
-        Ok(mempool)
+        Ok(usage)
+    }
+
+    /// Size of the database files
+    pub fn size_on_disk(&self) -> Result<u64> {
+        self.storage.size_on_disk()
+    }
+
+    /// Store the snapshot the chain was started from
+    #[instrument(skip(self, snapshot))]
+    pub fn put_snapshot(&self, snapshot: &Snapshot) -> Result<()> {
+        self.meta.insert(meta::SNAPSHOT, &encode(snapshot, "snapshot")?)
+    }
+
+    /// Retrieve the snapshot the chain was started from, if it is
+    /// still backfilling
+    #[instrument(skip(self))]
+    pub fn get_snapshot(&self) -> Result<Option<Snapshot>> {
+        self.meta.get_decoded(meta::SNAPSHOT, "snapshot")
+    }
+
+    /// Store the base of a pruned chain
+    #[instrument(skip(self, base))]
+    pub fn put_chain_base(&self, base: &ChainBase) -> Result<()> {
+        self.meta.insert(meta::CHAIN_BASE, &encode(base, "chain base")?)
+    }
+
+    /// Retrieve the base of the chain if it has been pruned
+    #[instrument(skip(self))]
+    pub fn get_chain_base(&self) -> Result<Option<ChainBase>> {
+        self.meta.get_decoded(meta::CHAIN_BASE, "chain base")
+    }
+
+    /// Keep a checkpoint signed by the checkpoint key to take again on
+    /// the next start
+    #[instrument(skip(self, signed))]
+    pub fn put_signed_checkpoint(&self, signed: &SignedCheckpoint) -> Result<()> {
+        let key = height_key(signed.checkpoint.height);
+        self.checkpoints.insert(&key, &encode(signed, "checkpoint")?)
+    }
+
+    /// Every stored signed checkpoint
+    #[instrument(skip(self))]
+    pub fn get_signed_checkpoints(&self) -> Result<Vec<SignedCheckpoint>> {
+        self.checkpoints
+            .scan(&[])
+            .map(|item| decode(&item?.1, "checkpoint"))
+            .collect()
     }
 
     /// Store the target value
     #[instrument(skip(self))]
     pub fn put_target(&self, target: U256) -> Result<()> {
-        let mut value = Vec::new();
-        into_writer(&target, &mut value)
-            .context("Failed to serialize target")?;
-        
-        self.db
-            .insert(keys::META_TARGET.as_bytes(), value)
-            .context("Failed to write target to database")?;
-        Ok(())
+        self.meta.insert(meta::TARGET, &encode(&target, "target")?)
     }
 
     /// Retrieve the target value
     #[instrument(skip(self))]
     pub fn get_target(&self) -> Result<Option<U256>> {
-        match self.db.get(keys::META_TARGET.as_bytes()).context("Failed to read target from database")? {
-            Some(value) => {
-                let target: U256 = from_reader(value.as_ref())
-                    .context("Failed to deserialize target")?;
-                Ok(Some(target))
-            }
-            None => Ok(None),
-        }
+        self.meta.get_decoded(meta::TARGET, "target")
     }
 
     /// Store the block count
     #[instrument(skip(self))]
     pub fn put_block_count(&self, count: u64) -> Result<()> {
-        let value = count.to_be_bytes().to_vec();
-        
-        self.db
-            .insert(keys::META_BLOCK_COUNT.as_bytes(), value)
-            .context("Failed to write block count to database")?;
-        Ok(())
+        self.meta.insert(meta::BLOCK_COUNT, &count.to_be_bytes())
     }
 
-    /// Retrieve the block count
-    #[instrument(skip(self))]
-    pub fn get_block_count(&self) -> Result<Option<u64>> {
-        match self.db.get(keys::META_BLOCK_COUNT.as_bytes()).context("Failed to read block count from database")? {
-            Some(value) => {
-                let mut bytes = [0u8; 8];
-                if value.len() >= 8 {
-                    bytes.copy_from_slice(&value[..8]);
-                    Ok(Some(u64::from_be_bytes(bytes)))
-                } else {
-                    Ok(None)
-                }
-            }
-            None => Ok(None),
-        }
+    /// Load the entire blockchain from the database, replaying its
+    /// blocks under the network's params
+    #[instrument(skip(self, params))]
+    pub fn load_blockchain(&self, params: &ChainParams) -> Result<Blockchain> {
+        self.load_blockchain_with_report(params).map(|(blockchain, _)| blockchain)
     }
 
-    /// Store UTXO keys list
-    #[instrument(skip(self, keys))]
-    fn put_utxo_keys(&self, keys: &[Hash]) -> Result<()> {
-        let mut value = Vec::new();
-        into_writer(keys, &mut value)
-            .context("Failed to serialize UTXO keys")?;
-        
-        self.db
-            .insert(keys::META_UTXO_KEYS.as_bytes(), value)
-            .context("Failed to write UTXO keys to database")?;
-        Ok(())
-    }
+    /// Load the chain like `load_blockchain`, also telling how many of
+    /// the saved mempool transactions made it back
+    pub fn load_blockchain_with_report(&self, params: &ChainParams) -> Result<(Blockchain, MempoolRestore)> {
+        let mut blockchain = if let Some(base) = self.get_chain_base()? {
+            // Pruned chains can't be replayed, trust the stored state instead
+            let blocks = self.get_blocks_from(base.height)?;
+            let utxos = self.get_all_utxos()?;
+            let target = self.get_target()?.unwrap_or(base.target);
+            let mut blockchain = Blockchain::restore_pruned(base, blocks, utxos, target);
+            blockchain.set_params(params.clone());
+            blockchain
+        } else {
+            // Start from the snapshot if the chain hasn't been backfilled yet
+            let mut blockchain = match self.get_snapshot()? {
+                Some(snapshot) => Blockchain::from_snapshot(snapshot),
+                None => Blockchain::new(),
+            };
+            // blocks must carry the targets the network's difficulty
+            // adjustment asks for
+            blockchain.set_params(params.clone());
 
-    /// Get UTXO keys list
-    #[instrument(skip(self))]
-    fn get_utxo_keys(&self) -> Result<Option<Vec<Hash>>> {
-        match self.db.get(keys::META_UTXO_KEYS.as_bytes()).context("Failed to read UTXO keys from database")? {
-            Some(value) => {
-                let keys: Vec<Hash> = ciborium::de::from_reader(value.as_ref())
-                    .context("Failed to deserialize UTXO keys")?;
-                Ok(Some(keys))
+            // Add all blocks one by one, each updating the UTXOs and
+            // adjusting the target as it connects
+            for block in self.get_blocks_from(blockchain.base_height())? {
+                blockchain.add_block(block)
+                    .context("Failed to add block when loading from database")?;
             }
-            None => Ok(None),
-        }
+            blockchain
+        };
+        self.restore_side_blocks(&mut blockchain)?;
+        blockchain.set_max_mempool_age(self.max_mempool_age);
+        let restore = self.restore_mempool(&mut blockchain)?;
+        *self.saved_height.lock().unwrap() = Some(blockchain.block_height());
+        Ok((blockchain, restore))
     }
 
-    /// Store mempool keys list (hash, timestamp) pairs to preserve duplicates
-    #[instrument(skip(self, keys))]
-    fn put_mempool_keys(&self, keys: &[(Hash, DateTime<Utc>)]) -> Result<()> {
-        let mut value = Vec::new();
-        into_writer(keys, &mut value)
-            .context("Failed to serialize mempool keys")?;
-        
-        self.db
-            .insert(keys::META_MEMPOOL_KEYS.as_bytes(), value)
-            .context("Failed to write mempool keys to database")?;
-        Ok(())
+    /// Blocks of branches off the main chain, in no particular order
+    #[instrument(skip(self))]
+    pub fn get_side_blocks(&self) -> Result<Vec<Block>> {
+        self.side_blocks
+            .scan(&[])
+            .map(|item| decode_block(&item?.1))
+            .collect()
     }
 
-    /// Get mempool keys list (hash, timestamp) pairs
-    #[instrument(skip(self))]
-    fn get_mempool_keys(&self) -> Result<Option<Vec<(Hash, DateTime<Utc>)>>> {
-        match self.db.get(keys::META_MEMPOOL_KEYS.as_bytes()).context("Failed to read mempool keys from database")? {
-            Some(value) => {
-                let keys: Vec<(Hash, DateTime<Utc>)> = from_reader(value.as_ref())
-                    .context("Failed to deserialize mempool keys")?;
-                Ok(Some(keys))
+    // put the saved branches back, a block whose parent comes later
+    // waits for it like any orphan
+    fn restore_side_blocks(&self, blockchain: &mut Blockchain) -> Result<()> {
+        let mut restored = 0;
+        for block in self.get_side_blocks()? {
+            let hash = block.hash();
+            match blockchain.add_block(block) {
+                Ok(()) => restored += 1,
+                Err(BtcError::UnknownParent) => {}
+                Err(e) => info!("dropping side block {hash}: {e}"),
             }
-            None => Ok(None),
         }
-    }
-
-    /// Clear all mempool transactions (for cleanup)
-    #[instrument(skip(self))]
-    pub fn clear_mempool(&self) -> Result<()> {
-        let mempool_keys = self.get_mempool_keys()?.unwrap_or_default();
-        for (tx_hash, timestamp) in mempool_keys {
-            self.delete_mempool_tx(&tx_hash, timestamp)?;
+        if restored > 0 {
+            blockchain.rebuild_utxos();
+            info!("restored {} blocks of other branches", blockchain.side_blocks().count());
         }
         Ok(())
     }
 
-    /// Load the entire blockchain from the database
-    #[instrument(skip(self))]
-    pub fn load_blockchain(&self) -> Result<Blockchain> {
-        
-        let blocks = self.get_all_blocks()?;
-        let mempool = self.get_all_mempool_txs()?;
-        
-        // Create a new blockchain
-        let mut blockchain = Blockchain::new();
-        
-        // Add all blocks one by one - this will rebuild UTXOs and adjust target
-        for block in blocks {
-            blockchain.add_block(block)
-                .context("Failed to add block when loading from database")?;
+    /// Put the saved mempool back, oldest first so parents come before
+    /// their children. Each transaction is checked against the rebuilt
+    /// UTXO set and keeps its original age, expired ones are dropped.
+    fn restore_mempool(&self, blockchain: &mut Blockchain) -> Result<MempoolRestore> {
+        let now = Utc::now();
+        let mut restore = MempoolRestore::default();
+        for entry in self.get_all_mempool_txs()? {
+            let hash = entry.transaction.hash();
+            if blockchain.mempool_expired(entry.added, now) {
+                info!("dropping mempool transaction {hash} (fee {}): expired, added {}", entry.fee, entry.added);
+                restore.expired += 1;
+                continue;
+            }
+            match blockchain.add_to_mempool_at(entry.transaction, entry.added) {
+                Ok(()) => restore.restored += 1,
+                Err(e) => {
+                    info!("dropping mempool transaction {hash} (fee {}): {e}", entry.fee);
+                    restore.invalid += 1;
+                }
+            }
         }
-        
-        // Restore mempool transactions
-        // Note: We need to add them in order to maintain the same order as when saved
-        for (_, tx) in mempool {
-            // Use add_to_mempool which will validate and add the transaction
-            // If it fails (e.g., UTXO no longer exists), we'll skip it
-            blockchain.add_to_mempool(tx).ok();
+        if restore != MempoolRestore::default() {
+            info!(
+                "restored {} mempool transactions, dropped {} expired and {} invalid",
+                restore.restored, restore.expired, restore.invalid
+            );
         }
-        
-        Ok(blockchain)
+        Ok(restore)
     }
 
+
     /// Save the entire blockchain to the database
     #[instrument(skip(self, blockchain))]
     pub fn save_blockchain(&self, blockchain: &Blockchain) -> Result<()> {
         // Save all blocks
         for (index, block) in blockchain.blocks().enumerate() {
-            self.put_block(index as u64, block)?;
+            self.put_block(blockchain.base_height() + index as u64, block)?;
+        }
+        // a reorg onto a branch with more work but fewer blocks leaves
+        // the old chain's top behind
+        let mut saved_height = self.saved_height.lock().unwrap();
+        if let Some(old_height) = *saved_height {
+            let mut stale = Batch::default();
+            for height in blockchain.block_height()..old_height {
+                self.blocks.batch_remove(&mut stale, &height_key(height));
+            }
+            self.storage.apply(stale)?;
+        }
+        *saved_height = Some(blockchain.block_height());
+        drop(saved_height);
+
+        // The snapshot is only needed until the chain reaches genesis
+        // or gets pruned
+        match blockchain.base() {
+            Some(base) if base.pruned => {
+                self.put_chain_base(base)?;
+                self.meta.remove(meta::SNAPSHOT)?;
+            }
+            Some(_) => {}
+            None => self.meta.remove(meta::SNAPSHOT)?,
         }
-        
+
         // Save block count
         self.put_block_count(blockchain.block_height())?;
-        
+
         // Save target
         self.put_target(blockchain.target())?;
-        
-        // Save all UTXOs atomically to prevent race conditions
-        {
-            let _guard = self.utxo_keys_mutex.lock().unwrap();
-            // First, clear existing UTXO keys to rebuild from scratch
-            if let Some(old_keys) = self.get_utxo_keys()? {
-                for hash in old_keys {
-                    // Delete UTXO data (but skip key list update since we're rebuilding it)
-                    let hash_bytes = hash.as_bytes();
-                    let hash_hex = hex::encode(hash_bytes);
-                    let key = format!("{}{}", keys::UTXO_PREFIX, hash_hex);
-                    self.db.remove(key.as_bytes())
-                        .context("Failed to delete UTXO from database")?;
-                }
+
+        // Replace all UTXOs, mempool transactions and side blocks in a
+        // single batch so readers never observe a half-written set
+        let mut batch = Batch::default();
+        for tree in [&self.utxos, &self.mempool, &self.side_blocks] {
+            for key in tree.keys() {
+                tree.batch_remove(&mut batch, &key?);
+            }
+        }
+        // blocks found invalid are forgotten, they are rejected again
+        // if they come back
+        for side in blockchain.side_blocks().filter(|side| !blockchain.is_invalid(&side.block.hash())) {
+            let value = encode_block(&side.block, self.compress_blocks)?;
+            self.side_blocks.batch_insert(&mut batch, &side.block.hash().as_bytes(), &value);
+        }
+
+        for (hash, (marked, height, output)) in blockchain.utxos() {
+            let value = encode(&(marked, height, output), "UTXO")?;
+            self.utxos.batch_insert(&mut batch, &hash.as_bytes(), &value);
+        }
+
+        for (timestamp, tx) in blockchain.mempool() {
+            let fee = blockchain.transaction_fee(tx).unwrap_or(0);
+            let value = encode(&(timestamp, fee, tx), "mempool transaction")?;
+            self.mempool.batch_insert(&mut batch, &mempool_key(&tx.hash(), *timestamp), &value);
+        }
+
+        self.storage
+            .apply(batch)
+            .context("Failed to write UTXOs and mempool to database")
+    }
+
+    /// Addresses whose history is indexed, in key order
+    #[instrument(skip(self))]
+    pub fn get_watched_addresses(&self) -> Result<Vec<String>> {
+        self.watched
+            .keys()
+            .map(|key| String::from_utf8(key?).context("Malformed watched address in database"))
+            .collect()
+    }
+
+    /// Start indexing the history of `address`
+    #[instrument(skip(self))]
+    pub fn put_watched_address(&self, address: &str) -> Result<()> {
+        self.watched.insert(address.as_bytes(), &[])
+    }
+
+    /// Stop indexing the history of `address`, dropping what was
+    /// indexed
+    #[instrument(skip(self))]
+    pub fn remove_watched_address(&self, address: &str) -> Result<()> {
+        let mut batch = Batch::default();
+        for item in self.history.scan(format!("{address}:").as_bytes()) {
+            self.history.batch_remove(&mut batch, &item?.0);
+        }
+        for item in self.watched_outputs.scan(&[]) {
+            let (key, value) = item?;
+            let output: WatchedOutput = decode(&value, "watched output")?;
+            if output.address == address {
+                self.watched_outputs.batch_remove(&mut batch, &key);
             }
-            
-            // Store new UTXO keys list
-            let utxo_hashes: Vec<Hash> = blockchain.utxos().keys().copied().collect();
-            self.put_utxo_keys(&utxo_hashes)?;
-            
-            // Save each UTXO (skip key list update since we just set it)
-            for (hash, (marked, output)) in blockchain.utxos() {
-                let hash_bytes = hash.as_bytes();
-                let hash_hex = hex::encode(hash_bytes);
-                let key = format!("{}{}", keys::UTXO_PREFIX, hash_hex);
-                let mut value = Vec::new();
-                into_writer(&(marked, output), &mut value)
-                    .context("Failed to serialize UTXO")?;
-                self.db.insert(key.as_bytes(), value)
-                    .context("Failed to write UTXO to database")?;
+        }
+        self.watched.batch_remove(&mut batch, address.as_bytes());
+        self.storage.apply(batch)
+    }
+
+    /// Indexed transactions touching `address`, oldest first
+    #[instrument(skip(self))]
+    pub fn get_history(&self, address: &str) -> Result<Vec<HistoryEntry>> {
+        self.history
+            .scan(format!("{address}:").as_bytes())
+            .map(|item| decode(&item?.1, "history entry"))
+            .collect()
+    }
+
+    /// A watched address' output with the given hash
+    #[instrument(skip(self))]
+    pub fn get_watched_output(&self, hash: &Hash) -> Result<Option<WatchedOutput>> {
+        self.watched_outputs.get_decoded(&hash.as_bytes(), "watched output")
+    }
+
+    /// Write history entries and watched outputs, and the height and
+    /// hash of the last block indexed, None before the first block
+    #[instrument(skip(self, batch))]
+    pub fn put_history(&self, batch: HistoryBatch, tip: Option<(u64, Hash)>) -> Result<()> {
+        let mut writes = Batch::default();
+        for (address, entry) in &batch.entries {
+            let key = history_key(address, entry);
+            self.history.batch_insert(&mut writes, key.as_bytes(), &encode(entry, "history entry")?);
+        }
+        for (hash, output) in &batch.outputs {
+            self.watched_outputs.batch_insert(&mut writes, &hash.as_bytes(), &encode(output, "watched output")?);
+        }
+        match tip {
+            Some(tip) => self.meta.batch_insert(&mut writes, meta::HISTORY_TIP, &encode(&tip, "history tip")?),
+            None => self.meta.batch_remove(&mut writes, meta::HISTORY_TIP),
+        }
+        self.storage.apply(writes)
+    }
+
+    /// Height and hash of the last block indexed
+    #[instrument(skip(self))]
+    pub fn get_history_tip(&self) -> Result<Option<(u64, Hash)>> {
+        self.meta.get_decoded(meta::HISTORY_TIP, "history tip")
+    }
+
+    /// Drop the history entries and watched outputs of the blocks
+    /// from `height` up, disconnected by a reorg
+    #[instrument(skip(self))]
+    pub fn remove_history_from(&self, height: u64) -> Result<()> {
+        let mut batch = Batch::default();
+        for item in self.history.scan(&[]) {
+            let (key, value) = item?;
+            let entry: HistoryEntry = decode(&value, "history entry")?;
+            if entry.height >= height {
+                self.history.batch_remove(&mut batch, &key);
             }
         }
-        
-        // Save all mempool transactions atomically to prevent race conditions
-        {
-            let _guard = self.mempool_keys_mutex.lock().unwrap();
-            // First, clear existing mempool
-            if let Some(old_keys) = self.get_mempool_keys()? {
-                for (tx_hash, timestamp) in old_keys {
-                    // Delete mempool data (but skip key list update since we're rebuilding it)
-                    let hash_bytes = tx_hash.as_bytes();
-                    let hash_hex = hex::encode(hash_bytes);
-                    let timestamp_nanos = timestamp.timestamp_nanos_opt().unwrap_or(0);
-                    let key = format!("{}{}:{}", keys::MEMPOOL_PREFIX, hash_hex, timestamp_nanos);
-                    self.db.remove(key.as_bytes())
-                        .context("Failed to delete mempool transaction from database")?;
-                }
+        for item in self.watched_outputs.scan(&[]) {
+            let (key, value) = item?;
+            let output: WatchedOutput = decode(&value, "watched output")?;
+            if output.height >= height {
+                self.watched_outputs.batch_remove(&mut batch, &key);
             }
-            
-            // Store new mempool keys list with (hash, timestamp) pairs to preserve duplicates
-            let mempool_keys: Vec<(Hash, DateTime<Utc>)> = blockchain.mempool().iter()
-                .map(|(timestamp, tx)| (tx.hash(), *timestamp))
-                .collect();
-            self.put_mempool_keys(&mempool_keys)?;
-            
-            // Save each mempool transaction with unique key (hash + timestamp)
-            for (timestamp, tx) in blockchain.mempool() {
-                let tx_hash = tx.hash();
-                let hash_bytes = tx_hash.as_bytes();
-                let hash_hex = hex::encode(hash_bytes);
-                let timestamp_nanos = timestamp.timestamp_nanos_opt().unwrap_or(0);
-                let key = format!("{}{}:{}", keys::MEMPOOL_PREFIX, hash_hex, timestamp_nanos);
-                let mut value = Vec::new();
-                into_writer(&(timestamp, tx), &mut value)
-                    .context("Failed to serialize mempool transaction")?;
-                self.db.insert(key.as_bytes(), value)
-                    .context("Failed to write mempool transaction to database")?;
+        }
+        self.storage.apply(batch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use btclib::testing::empty_block;
+    use btclib::util::MerkleRoot;
+
+    fn temporary_db() -> BlockchainDB {
+        BlockchainDB::temporary().unwrap()
+    }
+
+    #[test]
+    fn test_prune_keeps_headers() {
+        let db = temporary_db();
+        let blocks: Vec<_> = (0..3).map(empty_block).collect();
+        for (index, block) in blocks.iter().enumerate() {
+            db.put_block(index as u64, block).unwrap();
+        }
+        let size = db.block_storage_size().unwrap();
+
+        assert_eq!(db.prune_blocks(2).unwrap(), 2);
+        assert!(db.get_block(0).unwrap().is_none());
+        assert!(db.get_block(1).unwrap().is_none());
+        assert_eq!(db.get_blocks_from(2).unwrap().len(), 1);
+        assert_eq!(
+            db.block_storage_size().unwrap(),
+            size - db.block_size(2).unwrap().unwrap() * 2
+        );
+
+        let (header, hash) = db.get_pruned_header(1).unwrap().unwrap();
+        assert_eq!(header.nonce, 1);
+        assert_eq!(hash, blocks[1].hash());
+        assert_eq!(db.block_range().unwrap(), Some((2, 2)));
+    }
+
+    #[test]
+    fn test_single_records() {
+        let db = temporary_db();
+        let key = btclib::crypto::PrivateKey::new_key();
+        let chain = btclib::testing::build_chain(&key, &[vec![], vec![]]);
+        db.save_blockchain(&chain).unwrap();
+        assert_eq!(db.get_block_count().unwrap(), Some(chain.block_height()));
+
+        let output = TransactionOutput {
+            value: 7,
+            unique_id: uuid::Uuid::new_v4(),
+            address: key.public_key().to_address(),
+        };
+        let hash = output.hash();
+        db.put_utxo(&hash, true, 3, &output).unwrap();
+        let (marked, height, stored) = db.get_utxo(&hash).unwrap().unwrap();
+        assert_eq!((marked, height, stored.hash()), (true, 3, hash));
+        db.delete_utxo(&hash).unwrap();
+        assert!(db.get_utxo(&hash).unwrap().is_none());
+
+        let transaction = Transaction::new(vec![], vec![output]);
+        let value = encode(&(Utc::now(), 0u64, &transaction), "mempool transaction").unwrap();
+        db.mempool.insert(&mempool_key(&transaction.hash(), Utc::now()), &value).unwrap();
+        assert_eq!(db.get_all_mempool_txs().unwrap().len(), 1);
+        db.clear_mempool().unwrap();
+        assert!(db.get_all_mempool_txs().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_save_drops_replaced_blocks() {
+        let db = temporary_db();
+        for index in 0..3 {
+            db.put_block(index, &empty_block(index)).unwrap();
+        }
+        // blocks the process never saw itself are left alone
+        let mut blockchain = Blockchain::new();
+        blockchain.add_block(empty_block(7)).unwrap();
+        db.save_blockchain(&blockchain).unwrap();
+        assert_eq!(db.block_range().unwrap(), Some((0, 2)));
+
+        let longer = btclib::testing::build_chain(&btclib::crypto::PrivateKey::new_key(), &[vec![], vec![]]);
+        db.save_blockchain(&longer).unwrap();
+        db.save_blockchain(&blockchain).unwrap();
+        assert_eq!(db.get_block(0).unwrap().unwrap().hash(), blockchain.tip_hash().unwrap());
+        assert_eq!(db.block_range().unwrap(), Some((0, 0)));
+    }
+
+    #[test]
+    fn test_corrupt_block_keeps_the_others() {
+        let db = Arc::new(temporary_db());
+        let chain = btclib::testing::build_chain(&btclib::crypto::PrivateKey::new_key(), &[vec![], vec![]]);
+        db.save_blockchain(&chain).unwrap();
+        let mut sealed = db.storage.get(trees::BLOCKS, &height_key(1)).unwrap().unwrap();
+        *sealed.last_mut().unwrap() ^= 1;
+        db.storage.put(trees::BLOCKS, &height_key(1), &sealed).unwrap();
+
+        // a node over it refuses to start rather than save an empty chain
+        let reopened = BlockchainDB::with_cipher(db.storage.clone(), None);
+        let Err(error) = crate::context::NodeContext::from_db(reopened, ChainParams::default(), false)
+        else {
+            panic!("started over a corrupt block");
+        };
+        assert!(error.to_string().contains("node check-db"));
+        assert!(format!("{error:#}").contains("Corrupt record 1 in blocks"));
+        let reopened = BlockchainDB::with_cipher(db.storage.clone(), None);
+        reopened.save_blockchain(&Blockchain::new()).unwrap();
+        for height in [0, 2] {
+            assert_eq!(reopened.get_block(height).unwrap().unwrap().hash(), chain.block_at(height).unwrap().hash());
+        }
+    }
+
+    #[test]
+    fn test_side_blocks_survive_restart() {
+        let address = btclib::crypto::PrivateKey::new_key().public_key().to_address();
+        let mine = |prev: &Block, nonce: u64| {
+            let coinbase = TransactionOutput {
+                value: 1,
+                unique_id: uuid::Uuid::new_v4(),
+                address: address.clone(),
+            };
+            let transactions = vec![Transaction::new(vec![], vec![coinbase])];
+            let mut block = Block::new(
+                BlockHeader::new(
+                    prev.header.timestamp + chrono::Duration::seconds(1),
+                    nonce,
+                    prev.hash(),
+                    MerkleRoot::calculate(&transactions),
+                    btclib::MIN_TARGET,
+                ),
+                transactions,
+            );
+            while !block.header.mine(100_000) {}
+            block
+        };
+        let genesis = empty_block(0);
+        let mut blockchain = Blockchain::new();
+        blockchain.add_block(genesis.clone()).unwrap();
+        let main = mine(&genesis, 0);
+        blockchain.add_block(main.clone()).unwrap();
+        let fork = mine(&genesis, 1_000_000);
+        let fork_child = mine(&fork, 0);
+        blockchain.add_block(fork.clone()).unwrap();
+        let db = temporary_db();
+        db.save_blockchain(&blockchain).unwrap();
+        assert_eq!(db.get_side_blocks().unwrap().len(), 1);
+
+        let mut loaded = db.load_blockchain(&ChainParams::default()).unwrap();
+        assert_eq!(loaded.tip_hash(), Some(main.hash()));
+        assert_eq!(loaded.chain_tips().len(), 2);
+        // the branch carries on from where it was left
+        loaded.add_block(fork_child.clone()).unwrap();
+        assert_eq!(loaded.tip_hash(), Some(fork_child.hash()));
+    }
+
+    #[test]
+    fn test_mempool_is_revalidated_on_load() {
+        use btclib::crypto::{PrivateKey, Signature};
+        use btclib::types::TransactionInput;
+
+        let key = PrivateKey::new_key();
+        let output = |value| TransactionOutput {
+            value,
+            unique_id: uuid::Uuid::new_v4(),
+            address: key.public_key().to_address(),
+        };
+        let spend = |prev: &TransactionOutput, value| {
+            let hash = prev.hash();
+            Transaction::new(
+                vec![TransactionInput {
+                    prev_transaction_output_hash: hash,
+                    public_key: key.public_key(),
+                    signature: Signature::sign_output(&hash, &key),
+                    multisig: None,
+                    cosignatures: vec![],
+                }],
+                vec![output(value)],
+            )
+        };
+        let coinbase = output(1000);
+        let transactions = vec![Transaction::new(vec![], vec![coinbase.clone()])];
+        let genesis = Block::new(
+            BlockHeader::new(
+                Utc::now(),
+                0,
+                Hash::zero(),
+                MerkleRoot::calculate(&transactions),
+                btclib::MIN_TARGET,
+            ),
+            transactions,
+        );
+        let mut blockchain = Blockchain::new();
+        blockchain.add_block(genesis).unwrap();
+        blockchain.rebuild_utxos();
+        let db = temporary_db();
+        db.save_blockchain(&blockchain).unwrap();
+
+        let added = Utc::now() - chrono::Duration::seconds(60);
+        let stale = Utc::now() - chrono::Duration::seconds(btclib::MAX_MEMPOOL_TRANSACTION_AGE as i64 + 60);
+        let valid = spend(&coinbase, 990);
+        let entries = [
+            (stale, spend(&coinbase, 980)),
+            (added, valid.clone()),
+            (added, spend(&output(500), 400)),
+        ];
+        for (added, transaction) in &entries {
+            let mut value = Vec::new();
+            into_writer(&(added, 10u64, transaction), &mut value).unwrap();
+            db.mempool.insert(&mempool_key(&transaction.hash(), *added), &value).unwrap();
+        }
+
+        let (loaded, restore) = db.load_blockchain_with_report(&ChainParams::default()).unwrap();
+        assert_eq!(
+            restore,
+            MempoolRestore {
+                restored: 1,
+                expired: 1,
+                invalid: 1,
             }
+        );
+        assert_eq!(loaded.mempool().len(), 1);
+        // it keeps its age
+        assert_eq!(loaded.mempool()[0].0, added);
+        assert_eq!(loaded.mempool()[0].1.hash(), valid.hash());
+        db.save_blockchain(&loaded).unwrap();
+        assert_eq!(db.get_all_mempool_txs().unwrap()[0].fee, 10);
+    }
+
+    #[test]
+    fn test_keyspace_usage() {
+        let db = temporary_db();
+        for index in 0..12 {
+            db.put_block(index, &empty_block(index)).unwrap();
         }
-        
-        Ok(())
+        db.put_target(btclib::MIN_TARGET).unwrap();
+        let usage = db.keyspace_usage().unwrap();
+        let names: Vec<&str> = usage.iter().map(|keyspace| keyspace.name.as_str()).collect();
+        assert_eq!(names, vec!["blocks", "meta"]);
+        assert_eq!(usage[0].entries, 12);
+        assert!(usage[0].bytes > db.block_storage_size().unwrap());
+        assert_eq!(db.block_range().unwrap(), Some((0, 11)));
+    }
+
+    #[test]
+    fn test_compressed_blocks() {
+        let blocks = [empty_block(0), empty_block(1)];
+        let db = temporary_db();
+        db.put_block(0, &blocks[0]).unwrap();
+        let db = db.with_block_compression(true);
+        db.put_block(1, &blocks[1]).unwrap();
+        let stored = db.blocks.get(&height_key(1)).unwrap().unwrap();
+        assert_eq!(stored[0], BLOCK_ZSTD);
+
+        // both encodings read back
+        let stored_blocks = db.get_blocks_from(0).unwrap();
+        assert_eq!(stored_blocks.len(), 2);
+        assert_eq!(stored_blocks[1].hash(), blocks[1].hash());
+
+        let (before, after) = db.compact_blocks().unwrap();
+        assert_eq!(after, db.block_storage_size().unwrap());
+        assert_ne!(before, after);
+        let stored = db.blocks.get(&height_key(0)).unwrap().unwrap();
+        assert_eq!(stored[0], BLOCK_ZSTD);
+        assert_eq!(db.get_block(0).unwrap().unwrap().hash(), blocks[0].hash());
+    }
+
+    #[test]
+    fn test_corrupt_records_are_reported() {
+        let db = temporary_db();
+        for index in 0..3 {
+            db.put_block(index, &empty_block(index)).unwrap();
+        }
+        db.put_target(btclib::MIN_TARGET).unwrap();
+        assert_eq!(db.check().unwrap(), CheckReport { records: 4, corrupt: vec![] });
+
+        // a flipped bit fails the checksum on every read
+        let mut sealed = db.storage.get(trees::BLOCKS, &height_key(1)).unwrap().unwrap();
+        *sealed.last_mut().unwrap() ^= 1;
+        db.storage.put(trees::BLOCKS, &height_key(1), &sealed).unwrap();
+        let error = db.get_blocks_from(0).unwrap_err();
+        assert_eq!(error.to_string(), "Corrupt record 1 in blocks");
+        // a record with a good checksum may still not read back
+        db.utxos.insert(&Hash::zero().as_bytes(), b"junk").unwrap();
+
+        let report = db.check().unwrap();
+        assert_eq!(report.records, 5);
+        assert_eq!(
+            report.corrupt,
+            vec![
+                CorruptRecord {
+                    tree: "blocks",
+                    key: "1".to_string(),
+                    error: "checksum mismatch".to_string(),
+                },
+                CorruptRecord {
+                    tree: "utxos",
+                    key: Hash::zero().to_string(),
+                    error: report.corrupt[1].error.clone(),
+                },
+            ]
+        );
+        assert!(report.corrupt[1].error.starts_with("Failed to deserialize UTXO"));
+    }
+
+    #[test]
+    fn test_encrypted_records() {
+        let secret = DbSecret::passphrase("hunter2");
+        let storage: Arc<dyn Storage> = Arc::new(SledStorage::temporary().unwrap());
+        let db = BlockchainDB::from_storage(storage.clone(), Some(&secret)).unwrap();
+        assert!(db.is_encrypted());
+        let block = empty_block(7);
+        db.put_block(0, &block).unwrap();
+        db.put_block(1, &empty_block(8)).unwrap();
+        db.put_target(btclib::MIN_TARGET).unwrap();
+        assert_eq!(db.get_block(0).unwrap().unwrap().hash(), block.hash());
+
+        // what is stored doesn't decode without the key
+        let stored = storage.get(trees::BLOCKS, &height_key(0)).unwrap().unwrap();
+        assert!(decode_block(unseal(&stored).unwrap()).is_err());
+        assert!(BlockchainDB::from_storage(storage.clone(), None).is_err());
+        assert!(BlockchainDB::from_storage(storage.clone(), Some(&DbSecret::passphrase("hunter3"))).is_err());
+
+        // a record moved to another key fails to decrypt like a corrupt one
+        storage.put(trees::BLOCKS, &height_key(1), &stored).unwrap();
+        let reopened = BlockchainDB::from_storage(storage, Some(&secret)).unwrap();
+        assert_eq!(reopened.get_target().unwrap(), Some(btclib::MIN_TARGET));
+        let report = reopened.check().unwrap();
+        assert_eq!(report.records, 3);
+        assert_eq!(report.corrupt.len(), 1);
+        assert_eq!(report.corrupt[0].key, "1");
+    }
+
+    #[test]
+    fn test_backup() {
+        let secret = DbSecret::passphrase("hunter2");
+        let db = BlockchainDB::from_storage(Arc::new(MemoryStorage::new()), Some(&secret)).unwrap();
+        let block = empty_block(7);
+        db.put_block(0, &block).unwrap();
+        db.put_block(1, &empty_block(8)).unwrap();
+        db.put_block_count(2).unwrap();
+
+        let dest = std::env::temp_dir().join(format!("grapheno-backup-{}", uuid::Uuid::new_v4()));
+        let report = db.backup(&dest).unwrap().verify().unwrap();
+        // the schema version and encryption header come along
+        assert_eq!(report, BackupReport { records: 5, height: 2 });
+        assert!(db.backup(&dest).is_err());
+
+        let copy = BlockchainDB::open(&dest, Some(&secret)).unwrap();
+        assert!(copy.is_encrypted());
+        assert_eq!(copy.get_block(0).unwrap().unwrap().hash(), block.hash());
+        drop(copy);
+        assert!(BlockchainDB::open(&dest, None).is_err());
+        std::fs::remove_dir_all(&dest).unwrap();
+    }
+
+    #[test]
+    fn test_signed_checkpoints() {
+        use btclib::crypto::PrivateKey;
+        use btclib::params::Checkpoint;
+
+        let db = temporary_db();
+        let key = PrivateKey::new_key();
+        for height in [20, 10] {
+            let checkpoint = Checkpoint { height, hash: empty_block(height).hash() };
+            db.put_signed_checkpoint(&SignedCheckpoint::sign(checkpoint, &key)).unwrap();
+        }
+        let stored = db.get_signed_checkpoints().unwrap();
+        assert_eq!(stored.len(), 2);
+        assert!(stored.iter().all(|signed| signed.verify(&key.public_key())));
     }
 }
@@ -0,0 +1,194 @@
+//! Optional encryption of the values the database stores, for nodes
+//! on shared machines. Values are encrypted with XChaCha20-Poly1305
+//! under a key derived with PBKDF2 from a passphrase or a key file,
+//! authenticating the tree and key they are stored under so records
+//! can't be swapped. Keys stay readable: heights, hashes and watched
+//! addresses aren't hidden, only what is stored under them.
+//!
+//! The salt and a known value encrypted under the key are kept in the
+//! default tree, so a wrong secret is told apart from corrupt records
+//! when the database is opened.
+use super::storage::Storage;
+use super::trees;
+use anyhow::{Context, Result, anyhow, bail};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use ciborium::{de::from_reader, ser::into_writer};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::path::Path;
+use zeroize::Zeroizing;
+
+/// Environment variable the database passphrase is read from
+pub const PASSPHRASE_VAR: &str = "GRAPHENO_DB_PASSPHRASE";
+
+// where the salt and key check live in the default tree
+const HEADER_KEY: &[u8] = b"encryption";
+// what the key check decrypts to under the right key
+const CHECK_VALUE: &[u8] = b"grapheno database key";
+// PBKDF2-HMAC-SHA256 rounds for new databases; stored in the header,
+// so raising it later doesn't lock out existing ones
+const ROUNDS: u32 = if cfg!(test) { 1_000 } else { 600_000 };
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+
+/// What the database key is derived from
+pub struct DbSecret(Zeroizing<Vec<u8>>);
+
+impl DbSecret {
+    pub fn passphrase(passphrase: &str) -> Self {
+        Self(Zeroizing::new(passphrase.as_bytes().to_vec()))
+    }
+
+    /// The whole contents of the file at `path`, trailing newline
+    /// included
+    pub fn key_file(path: &Path) -> Result<Self> {
+        let bytes = std::fs::read(path).with_context(|| format!("Failed to read key file {}", path.display()))?;
+        if bytes.is_empty() {
+            bail!("key file {} is empty", path.display());
+        }
+        Ok(Self(Zeroizing::new(bytes)))
+    }
+
+    /// The secret from `key_file`, or else from the passphrase
+    /// variable, None if neither is given
+    pub fn from_env(key_file: Option<&Path>) -> Result<Option<Self>> {
+        let passphrase = std::env::var(PASSPHRASE_VAR).ok().filter(|passphrase| !passphrase.is_empty());
+        match (key_file, passphrase) {
+            (Some(_), Some(_)) => bail!("give the database a key file or {PASSPHRASE_VAR}, not both"),
+            (Some(path), None) => Self::key_file(path).map(Some),
+            (None, Some(passphrase)) => Ok(Some(Self::passphrase(&passphrase))),
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    salt: Vec<u8>,
+    rounds: u32,
+    /// `CHECK_VALUE` encrypted under the key
+    check: Vec<u8>,
+}
+
+/// Encrypts and decrypts the values of an encrypted database
+pub struct Cipher(XChaCha20Poly1305);
+
+impl Cipher {
+    fn derive(secret: &DbSecret, salt: &[u8], rounds: u32) -> Self {
+        let mut key = Zeroizing::new([0u8; 32]);
+        pbkdf2::pbkdf2_hmac::<Sha256>(&secret.0, salt, rounds, key.as_mut());
+        Self(XChaCha20Poly1305::new(key.as_ref().into()))
+    }
+
+    /// A random nonce followed by `value` encrypted, with `aad`
+    /// authenticated along
+    pub fn encrypt(&self, aad: &[u8], value: &[u8]) -> Vec<u8> {
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let ciphertext = self
+            .0
+            .encrypt(&XNonce::from(nonce), Payload { msg: value, aad })
+            .expect("encrypting into a Vec doesn't fail");
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        sealed
+    }
+
+    pub fn decrypt(&self, aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+        let Some((nonce, ciphertext)) = sealed.split_first_chunk::<NONCE_LEN>() else {
+            bail!("shorter than its nonce");
+        };
+        self.0
+            .decrypt(&XNonce::from(*nonce), Payload { msg: ciphertext, aad })
+            .map_err(|_| anyhow!("failed to decrypt"))
+    }
+}
+
+// whether no tree but the default one, holding the schema version,
+// has records
+fn is_empty(storage: &dyn Storage) -> Result<bool> {
+    for tree in trees::ALL {
+        if !storage.is_empty(tree)? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Whether the database is encrypted, without unlocking it
+pub fn is_encrypted(storage: &dyn Storage) -> Result<bool> {
+    let header = storage
+        .get(trees::DEFAULT, HEADER_KEY)
+        .context("Failed to read the encryption header")?;
+    Ok(header.is_some())
+}
+
+/// The cipher of the database, None if it isn't encrypted. A new
+/// database given a secret is encrypted under it.
+pub fn unlock(storage: &dyn Storage, secret: Option<&DbSecret>) -> Result<Option<Cipher>> {
+    let header = storage
+        .get(trees::DEFAULT, HEADER_KEY)
+        .context("Failed to read the encryption header")?;
+    match (header, secret) {
+        (None, None) => Ok(None),
+        (Some(_), None) => bail!("the database is encrypted, give --db-key-file or set {PASSPHRASE_VAR}"),
+        (Some(header), Some(secret)) => {
+            let header: Header = from_reader(header.as_slice()).context("Malformed encryption header")?;
+            let cipher = Cipher::derive(secret, &header.salt, header.rounds);
+            match cipher.decrypt(HEADER_KEY, &header.check) {
+                Ok(check) if check == CHECK_VALUE => Ok(Some(cipher)),
+                _ => bail!("wrong passphrase or key file for the encrypted database"),
+            }
+        }
+        (None, Some(_)) if !is_empty(storage)? => bail!(
+            "the database isn't encrypted; export its blocks with export-blocks and import them into a new, encrypted one"
+        ),
+        (None, Some(secret)) => {
+            let salt: [u8; SALT_LEN] = rand::random();
+            let cipher = Cipher::derive(secret, &salt, ROUNDS);
+            let header = Header {
+                salt: salt.to_vec(),
+                rounds: ROUNDS,
+                check: cipher.encrypt(HEADER_KEY, CHECK_VALUE),
+            };
+            let mut bytes = Vec::new();
+            into_writer(&header, &mut bytes).context("Failed to serialize the encryption header")?;
+            storage
+                .put(trees::DEFAULT, HEADER_KEY, &bytes)
+                .context("Failed to write the encryption header")?;
+            Ok(Some(cipher))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::SledStorage;
+
+    fn temporary() -> SledStorage {
+        SledStorage::temporary().unwrap()
+    }
+
+    #[test]
+    fn test_unlock_checks_the_secret() {
+        let db = temporary();
+        assert!(unlock(&db, None).unwrap().is_none());
+        let right = DbSecret::passphrase("right");
+        let cipher = unlock(&db, Some(&right)).unwrap().unwrap();
+        let sealed = cipher.encrypt(b"aad", b"value");
+        assert_eq!(cipher.decrypt(b"aad", &sealed).unwrap(), b"value");
+        assert!(cipher.decrypt(b"other", &sealed).is_err());
+
+        // the key is derived the same way again
+        let reopened = unlock(&db, Some(&right)).unwrap().unwrap();
+        assert_eq!(reopened.decrypt(b"aad", &sealed).unwrap(), b"value");
+        assert!(unlock(&db, Some(&DbSecret::passphrase("wrong"))).is_err());
+        assert!(unlock(&db, None).is_err());
+
+        // a database with plain records isn't encrypted over them
+        let plain = temporary();
+        plain.put(trees::BLOCKS, b"key", b"value").unwrap();
+        assert!(unlock(&plain, Some(&right)).is_err());
+    }
+}
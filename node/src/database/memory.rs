@@ -0,0 +1,113 @@
+//! Storage kept in memory only, for tests and ephemeral nodes that
+//! shouldn't leave a database directory behind
+use super::storage::{Batch, Records, Storage};
+use anyhow::Result;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::RwLock;
+
+type Tree = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// Trees held in ordered maps, gone when dropped
+#[derive(Default)]
+pub struct MemoryStorage {
+    trees: RwLock<HashMap<String, Tree>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn read<T>(&self, tree: &str, f: impl FnOnce(&Tree) -> T) -> T {
+        let trees = self.trees.read().unwrap();
+        f(trees.get(tree).unwrap_or(&BTreeMap::new()))
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn get(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.read(tree, |tree| tree.get(key).cloned()))
+    }
+
+    fn put(&self, tree: &str, key: &[u8], value: &[u8]) -> Result<()> {
+        let mut trees = self.trees.write().unwrap();
+        trees.entry(tree.to_string()).or_default().insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn delete(&self, tree: &str, key: &[u8]) -> Result<()> {
+        if let Some(tree) = self.trees.write().unwrap().get_mut(tree) {
+            tree.remove(key);
+        }
+        Ok(())
+    }
+
+    // a copy of the records, so writes don't wait on a slow reader
+    fn iter_from<'a>(&'a self, tree: &str, start: &[u8]) -> Records<'a> {
+        let records: Vec<_> = self.read(tree, |tree| {
+            tree.range(start.to_vec()..)
+                .map(|(key, value)| Ok((key.clone(), value.clone())))
+                .collect()
+        });
+        Box::new(records.into_iter())
+    }
+
+    fn last(&self, tree: &str) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        Ok(self.read(tree, |tree| {
+            tree.last_key_value().map(|(key, value)| (key.clone(), value.clone()))
+        }))
+    }
+
+    fn apply(&self, batch: Batch) -> Result<()> {
+        let mut trees = self.trees.write().unwrap();
+        for (tree, key, value) in batch.writes {
+            let tree = trees.entry(tree.to_string()).or_default();
+            match value {
+                Some(value) => tree.insert(key, value),
+                None => tree.remove(&key),
+            };
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// The bytes of the keys and values held
+    fn size_on_disk(&self) -> Result<u64> {
+        let trees = self.trees.read().unwrap();
+        let size = trees
+            .values()
+            .flat_map(|tree| tree.iter())
+            .map(|(key, value)| (key.len() + value.len()) as u64)
+            .sum();
+        Ok(size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::trees;
+
+    #[test]
+    fn test_memory_storage() {
+        let storage = MemoryStorage::new();
+        assert!(storage.is_empty(trees::UTXOS).unwrap());
+        let mut batch = Batch::default();
+        batch.put(trees::UTXOS, &b"b"[..], b"2".to_vec());
+        batch.put(trees::UTXOS, &b"a"[..], b"1".to_vec());
+        batch.delete(trees::META, &b"missing"[..]);
+        storage.apply(batch).unwrap();
+
+        let records: Vec<_> = storage.iter_from(trees::UTXOS, b"").map(Result::unwrap).collect();
+        assert_eq!(records, vec![(b"a".to_vec(), b"1".to_vec()), (b"b".to_vec(), b"2".to_vec())]);
+        assert_eq!(storage.last(trees::UTXOS).unwrap(), Some((b"b".to_vec(), b"2".to_vec())));
+        assert_eq!(storage.get(trees::DEFAULT, b"a").unwrap(), None);
+        assert_eq!(storage.size_on_disk().unwrap(), 4);
+
+        storage.delete(trees::UTXOS, b"a").unwrap();
+        assert_eq!(storage.scan_prefix(trees::UTXOS, b"a").count(), 0);
+    }
+}
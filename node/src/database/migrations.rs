@@ -0,0 +1,567 @@
+use super::storage::{Batch, Storage};
+use super::{encryption, seal, trees, unseal};
+use anyhow::{Context, Result, anyhow, bail};
+use btclib::sha256::Hash;
+use btclib::types::{Transaction, TransactionOutput};
+use btclib::util::MerkleRoot;
+use chrono::{DateTime, Utc};
+use ciborium::{de::from_reader, ser::into_writer};
+use std::collections::HashMap;
+use tracing::info;
+
+/// Keys of the default tree, which kept every record up to schema v5,
+/// each kind under its prefix. Only the schema version is still kept
+/// there.
+mod keys {
+    pub const BLOCK_PREFIX: &str = "block:";
+    pub const HEADER_PREFIX: &str = "header:";
+    pub const UTXO_PREFIX: &str = "utxo:";
+    pub const MEMPOOL_PREFIX: &str = "mempool:";
+    pub const CHECKPOINT_PREFIX: &str = "checkpoint:";
+    pub const SIDE_BLOCK_PREFIX: &str = "side_block:";
+    pub const WATCH_PREFIX: &str = "watch:";
+    pub const HISTORY_PREFIX: &str = "history:";
+    pub const WATCHED_OUTPUT_PREFIX: &str = "watched_output:";
+    pub const META_TARGET: &str = "meta:target";
+    pub const META_BLOCK_COUNT: &str = "meta:block_count";
+    pub const META_SCHEMA_VERSION: &str = "meta:schema_version";
+    pub const META_SNAPSHOT: &str = "meta:snapshot";
+    pub const META_CHAIN_BASE: &str = "meta:chain_base";
+    pub const META_HISTORY_TIP: &str = "meta:history_tip";
+    // key lists used by schema v1, dropped in favour of prefix scans
+    pub const META_UTXO_KEYS: &str = "meta:utxo_keys";
+    pub const META_MEMPOOL_KEYS: &str = "meta:mempool_keys";
+}
+
+fn utxo_key(hash: &Hash) -> String {
+    format!("{}{}", keys::UTXO_PREFIX, hex::encode(hash.as_bytes()))
+}
+
+// height of the block stored under `key`
+fn block_index(key: &[u8]) -> Result<u64> {
+    std::str::from_utf8(&key[keys::BLOCK_PREFIX.len()..])
+        .ok()
+        .and_then(|index| index.parse().ok())
+        .context("Malformed block key in database")
+}
+
+/// Current on-disk layout version
+///
+/// v1: UTXO and mempool entries are enumerated through META key lists
+/// v2: UTXO and mempool entries are enumerated by prefix scans
+/// v3: stored blocks start with a header byte marking compression
+/// v4: UTXO entries carry the height of the block that created them
+/// v5: mempool entries carry the fee they paid
+/// v6: each kind of record is kept in a tree of its own, its value
+///     behind a CRC32 checksum
+/// v7: transaction ids and output hashes are taken over the canonical
+///     encoding rather than the CBOR of the structs
+pub const SCHEMA_VERSION: u32 = 7;
+
+// databases created before versioning was introduced carry no version key
+const UNVERSIONED: u32 = 1;
+
+/// An in-place upgrade from schema version `from` to `from + 1`
+struct Migration {
+    from: u32,
+    description: &'static str,
+    apply: fn(&dyn Storage) -> Result<()>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        from: 1,
+        description: "drop META key lists in favour of prefix scans",
+        apply: drop_key_lists,
+    },
+    Migration {
+        from: 2,
+        description: "mark stored blocks as uncompressed",
+        apply: add_block_headers,
+    },
+    Migration {
+        from: 3,
+        description: "record the creation height of UTXOs",
+        apply: add_utxo_heights,
+    },
+    Migration {
+        from: 4,
+        description: "record the fee of mempool transactions",
+        apply: add_mempool_fees,
+    },
+    Migration {
+        from: 5,
+        description: "move records into trees of their own with checksums",
+        apply: split_trees,
+    },
+    Migration {
+        from: 6,
+        description: "check transactions are hashed over the canonical encoding",
+        apply: check_canonical_hashes,
+    },
+];
+
+/// Read the stored schema version, if any
+pub fn stored_version(db: &dyn Storage) -> Result<Option<u32>> {
+    match db
+        .get(trees::DEFAULT, keys::META_SCHEMA_VERSION.as_bytes())
+        .context("Failed to read schema version from database")?
+    {
+        Some(value) => {
+            let bytes: [u8; 4] = value
+                .as_slice()
+                .try_into()
+                .map_err(|_| anyhow!("Malformed schema version in database"))?;
+            Ok(Some(u32::from_be_bytes(bytes)))
+        }
+        None => Ok(None),
+    }
+}
+
+fn put_version(db: &dyn Storage, version: u32) -> Result<()> {
+    db.put(trees::DEFAULT, keys::META_SCHEMA_VERSION.as_bytes(), &version.to_be_bytes())
+        .context("Failed to write schema version to database")?;
+    Ok(())
+}
+
+/// Bring the database up to SCHEMA_VERSION, refusing to touch
+/// databases written by a newer node
+pub fn upgrade(db: &dyn Storage) -> Result<()> {
+    let mut version = match stored_version(db)? {
+        Some(version) => version,
+        None if db.is_empty(trees::DEFAULT)? => {
+            // fresh database, nothing to migrate
+            put_version(db, SCHEMA_VERSION)?;
+            return Ok(());
+        }
+        None => UNVERSIONED,
+    };
+
+    if version > SCHEMA_VERSION {
+        bail!(
+            "database schema version {} is newer than the supported version {}, please upgrade the node",
+            version,
+            SCHEMA_VERSION
+        );
+    }
+
+    while version < SCHEMA_VERSION {
+        let migration = MIGRATIONS
+            .iter()
+            .find(|migration| migration.from == version)
+            .ok_or_else(|| anyhow!("No migration available from schema version {}", version))?;
+        info!(
+            "migrating database from schema v{} to v{}: {}",
+            version,
+            version + 1,
+            migration.description
+        );
+        (migration.apply)(db)?;
+        version += 1;
+        put_version(db, version)?;
+        db.flush().context("Failed to flush migrated database")?;
+    }
+
+    Ok(())
+}
+
+// v1 -> v2: the entries themselves already live under their prefixes,
+// only the redundant key lists need to go
+fn drop_key_lists(db: &dyn Storage) -> Result<()> {
+    db.delete(trees::DEFAULT, keys::META_UTXO_KEYS.as_bytes())
+        .context("Failed to remove UTXO key list")?;
+    db.delete(trees::DEFAULT, keys::META_MEMPOOL_KEYS.as_bytes())
+        .context("Failed to remove mempool key list")?;
+    Ok(())
+}
+
+// v2 -> v3: blocks were plain CBOR, which is what the raw header marks
+fn add_block_headers(db: &dyn Storage) -> Result<()> {
+    let mut batch = Batch::default();
+    for item in db.scan_prefix(trees::DEFAULT, keys::BLOCK_PREFIX.as_bytes()) {
+        let (key, value) = item.context("Failed to read block from database")?;
+        let mut marked = Vec::with_capacity(value.len() + 1);
+        marked.push(super::BLOCK_RAW);
+        marked.extend_from_slice(&value);
+        batch.put(trees::DEFAULT, key, marked);
+    }
+    db.apply(batch)
+        .context("Failed to mark blocks in database")?;
+    Ok(())
+}
+
+// v3 -> v4: heights are recovered from the stored blocks
+fn add_utxo_heights(db: &dyn Storage) -> Result<()> {
+    let mut heights = HashMap::new();
+    for item in db.scan_prefix(trees::DEFAULT, keys::BLOCK_PREFIX.as_bytes()) {
+        let (key, value) = item.context("Failed to read block from database")?;
+        let height = block_index(&key)?;
+        for transaction in super::decode_block(&value)?.transactions {
+            for output in &transaction.outputs {
+                heights.insert(output.hash(), height);
+            }
+        }
+    }
+
+    let mut batch = Batch::default();
+    for item in db.scan_prefix(trees::DEFAULT, keys::UTXO_PREFIX.as_bytes()) {
+        let (key, value) = item.context("Failed to read UTXO from database")?;
+        let (marked, output): (bool, TransactionOutput) =
+            from_reader(value.as_slice()).context("Failed to deserialize UTXO")?;
+        // only outputs of pruned blocks are missing, and those are far
+        // enough below the tip to count as created at genesis
+        let height = heights.get(&output.hash()).copied().unwrap_or(0);
+        let mut value = Vec::new();
+        into_writer(&(marked, height, &output), &mut value)
+            .context("Failed to serialize UTXO")?;
+        batch.put(trees::DEFAULT, key, value);
+    }
+    db.apply(batch)
+        .context("Failed to write UTXOs to database")?;
+    Ok(())
+}
+
+// v4 -> v5: fees of transactions spending confirmed outputs are read
+// from the UTXO set, the others are left at 0. The fee is only
+// reported, transactions are validated again when loaded.
+fn add_mempool_fees(db: &dyn Storage) -> Result<()> {
+    let mut batch = Batch::default();
+    for item in db.scan_prefix(trees::DEFAULT, keys::MEMPOOL_PREFIX.as_bytes()) {
+        let (key, value) = item.context("Failed to read mempool transaction from database")?;
+        let (added, transaction): (DateTime<Utc>, Transaction) =
+            from_reader(value.as_slice()).context("Failed to deserialize mempool transaction")?;
+        let inputs: Option<u64> = transaction
+            .inputs
+            .iter()
+            .map(|input| {
+                let stored = db.get(trees::DEFAULT, utxo_key(&input.prev_transaction_output_hash).as_bytes()).ok()??;
+                let (_, _, output): (bool, u64, TransactionOutput) = from_reader(stored.as_slice()).ok()?;
+                Some(output.value)
+            })
+            .sum();
+        let fee = inputs.map_or(0, |inputs| inputs.saturating_sub(transaction.outputs.iter().map(|output| output.value).sum()));
+        let mut value = Vec::new();
+        into_writer(&(added, fee, &transaction), &mut value)
+            .context("Failed to serialize mempool transaction")?;
+        batch.put(trees::DEFAULT, key, value);
+    }
+    db.apply(batch)
+        .context("Failed to write mempool to database")?;
+    Ok(())
+}
+
+// how the rest of a v5 key after its prefix becomes the key in its
+// tree
+fn height_suffix(suffix: &str) -> Result<Vec<u8>> {
+    let height: u64 = suffix.parse().context("Malformed height key in database")?;
+    Ok(super::height_key(height).to_vec())
+}
+
+// UTXO keys were the hex of the hash's bytes
+fn hex_suffix(suffix: &str) -> Result<Vec<u8>> {
+    hex::decode(suffix).context("Malformed hash key in database")
+}
+
+// side blocks and watched outputs were keyed by the hash as displayed
+fn hash_suffix(suffix: &str) -> Result<Vec<u8>> {
+    let hash: Hash = suffix.parse().map_err(|_| anyhow!("Malformed hash key in database"))?;
+    Ok(hash.as_bytes().to_vec())
+}
+
+fn mempool_suffix(suffix: &str) -> Result<Vec<u8>> {
+    let (hash, nanos) = suffix.split_once(':').context("Malformed mempool key in database")?;
+    let mut key = hex_suffix(hash)?;
+    let nanos: i64 = nanos.parse().context("Malformed mempool key in database")?;
+    key.extend(nanos.to_be_bytes());
+    Ok(key)
+}
+
+fn text_suffix(suffix: &str) -> Result<Vec<u8>> {
+    Ok(suffix.as_bytes().to_vec())
+}
+
+// v5 -> v6: every record moves from under its prefix to its own tree,
+// sealed with a checksum. The trees are written before the old keys
+// go, so an interrupted run is simply repeated.
+fn split_trees(db: &dyn Storage) -> Result<()> {
+    type Move = (&'static str, &'static str, fn(&str) -> Result<Vec<u8>>);
+    let moves: [Move; 14] = [
+        (keys::BLOCK_PREFIX, trees::BLOCKS, height_suffix),
+        (keys::HEADER_PREFIX, trees::HEADERS, height_suffix),
+        (keys::UTXO_PREFIX, trees::UTXOS, hex_suffix),
+        (keys::MEMPOOL_PREFIX, trees::MEMPOOL, mempool_suffix),
+        (keys::CHECKPOINT_PREFIX, trees::CHECKPOINTS, height_suffix),
+        (keys::SIDE_BLOCK_PREFIX, trees::SIDE_BLOCKS, hash_suffix),
+        (keys::WATCH_PREFIX, trees::WATCHED, text_suffix),
+        (keys::HISTORY_PREFIX, trees::HISTORY, text_suffix),
+        (keys::WATCHED_OUTPUT_PREFIX, trees::WATCHED_OUTPUTS, hash_suffix),
+        (keys::META_TARGET, trees::META, |_| Ok(super::meta::TARGET.to_vec())),
+        (keys::META_BLOCK_COUNT, trees::META, |_| Ok(super::meta::BLOCK_COUNT.to_vec())),
+        (keys::META_SNAPSHOT, trees::META, |_| Ok(super::meta::SNAPSHOT.to_vec())),
+        (keys::META_CHAIN_BASE, trees::META, |_| Ok(super::meta::CHAIN_BASE.to_vec())),
+        (keys::META_HISTORY_TIP, trees::META, |_| Ok(super::meta::HISTORY_TIP.to_vec())),
+    ];
+    let mut moved = Batch::default();
+    for (prefix, tree, new_key) in moves {
+        let mut batch = Batch::default();
+        for item in db.scan_prefix(trees::DEFAULT, prefix.as_bytes()) {
+            let (key, value) = item.with_context(|| format!("Failed to read {prefix} records from database"))?;
+            let suffix = std::str::from_utf8(&key[prefix.len()..]).context("Malformed key in database")?;
+            batch.put(tree, new_key(suffix)?, seal(&value));
+            moved.delete(trees::DEFAULT, key);
+        }
+        db.apply(batch)
+            .with_context(|| format!("Failed to write the {tree} tree"))?;
+    }
+    db.apply(moved)
+        .context("Failed to delete moved records from database")?;
+    Ok(())
+}
+
+// v6 -> v7: hashes over the old encoding can't be rewritten, every
+// id, merkle root and proof of work built on them would change. A
+// chain stored with them is refused before the node trips over it.
+// Encrypted databases came after the canonical encoding, and their
+// values can't be read before they are unlocked.
+fn check_canonical_hashes(db: &dyn Storage) -> Result<()> {
+    if encryption::is_encrypted(db)? {
+        return Ok(());
+    }
+    let legacy = || {
+        anyhow!(
+            "the database was written before transactions were hashed over the canonical encoding \
+             and its chain can't be used any more: re-sync the node into a new database or import a \
+             bootstrap file exported by an up to date node"
+        )
+    };
+    if let Some(item) = db.scan_prefix(trees::BLOCKS, &[]).next() {
+        let (_, sealed) = item.context("Failed to read block from database")?;
+        let block = super::decode_block(unseal(&sealed)?)?;
+        if MerkleRoot::calculate(&block.transactions) != block.header.merkle_root {
+            return Err(legacy());
+        }
+    }
+    // pruned chains may have no block left, their UTXOs are keyed by
+    // the output hash
+    if let Some(item) = db.scan_prefix(trees::UTXOS, &[]).next() {
+        let (key, sealed) = item.context("Failed to read UTXO from database")?;
+        let (_, _, output): (bool, u64, TransactionOutput) =
+            from_reader(unseal(&sealed)?).context("Failed to deserialize UTXO")?;
+        if key != output.hash().as_bytes() {
+            return Err(legacy());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::SledStorage;
+    use btclib::types::{Block, BlockHeader};
+    use btclib::util::MerkleRoot;
+
+    fn temporary_db() -> SledStorage {
+        SledStorage::temporary().unwrap()
+    }
+
+    // a record of the default tree, the way schema v5 and older kept them
+    fn insert(db: &SledStorage, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<()> {
+        db.put(trees::DEFAULT, key.as_ref(), value.as_ref())
+    }
+
+    // a record as migrated into its tree, without its checksum
+    fn stored(db: &SledStorage, tree: &str, key: &[u8]) -> Option<Vec<u8>> {
+        let sealed = db.get(tree, key).unwrap()?;
+        Some(super::super::unseal(&sealed).unwrap().to_vec())
+    }
+
+    fn block(outputs: Vec<TransactionOutput>) -> Block {
+        let transactions = vec![Transaction::new(vec![], outputs)];
+        Block::new(
+            BlockHeader::new(
+                chrono::Utc::now(),
+                0,
+                Hash::zero(),
+                MerkleRoot::calculate(&transactions),
+                btclib::MIN_TARGET,
+            ),
+            transactions,
+        )
+    }
+
+    #[test]
+    fn test_fresh_database_gets_current_version() {
+        let db = temporary_db();
+        upgrade(&db).unwrap();
+        assert_eq!(stored_version(&db).unwrap(), Some(SCHEMA_VERSION));
+    }
+
+    #[test]
+    fn test_unversioned_database_is_migrated() {
+        let db = temporary_db();
+        insert(&db, keys::META_UTXO_KEYS.as_bytes(), &[0u8][..]).unwrap();
+        insert(&db, keys::META_MEMPOOL_KEYS.as_bytes(), &[0u8][..]).unwrap();
+        let mut cbor = Vec::new();
+        into_writer(&block(vec![]), &mut cbor).unwrap();
+        insert(&db, format!("{}0", keys::BLOCK_PREFIX).as_bytes(), cbor.as_slice())
+            .unwrap();
+
+        upgrade(&db).unwrap();
+
+        assert_eq!(stored_version(&db).unwrap(), Some(SCHEMA_VERSION));
+        assert!(db.get(trees::DEFAULT, keys::META_UTXO_KEYS.as_bytes()).unwrap().is_none());
+        assert!(db.get(trees::DEFAULT, keys::META_MEMPOOL_KEYS.as_bytes()).unwrap().is_none());
+        // blocks are kept, marked as uncompressed
+        let stored = stored(&db, trees::BLOCKS, &super::super::height_key(0)).unwrap();
+        assert_eq!(stored[0], super::super::BLOCK_RAW);
+        assert_eq!(&stored[1..], cbor.as_slice());
+    }
+
+    #[test]
+    fn test_utxos_get_creation_heights() {
+        let output = |value| TransactionOutput {
+            value,
+            unique_id: uuid::Uuid::new_v4(),
+            address: String::new(),
+        };
+        let (confirmed, pruned) = (output(1), output(2));
+        let block = block(vec![confirmed.clone()]);
+
+        let db = temporary_db();
+        put_version(&db, 3).unwrap();
+        let block_key = format!("{}5", keys::BLOCK_PREFIX);
+        insert(&db, block_key, super::super::encode_block(&block, false).unwrap())
+            .unwrap();
+        for output in [&confirmed, &pruned] {
+            let mut value = Vec::new();
+            into_writer(&(true, output), &mut value).unwrap();
+            insert(&db, utxo_key(&output.hash()), value).unwrap();
+        }
+
+        upgrade(&db).unwrap();
+
+        for (output, height) in [(&confirmed, 5), (&pruned, 0)] {
+            let value = stored(&db, trees::UTXOS, &output.hash().as_bytes()).unwrap();
+            let (marked, stored_height, stored): (bool, u64, TransactionOutput) =
+                from_reader(value.as_slice()).unwrap();
+            assert!(marked);
+            assert_eq!(stored_height, height);
+            assert_eq!(stored.hash(), output.hash());
+        }
+    }
+
+    #[test]
+    fn test_mempool_entries_get_fees() {
+        let output = |value| TransactionOutput {
+            value,
+            unique_id: uuid::Uuid::new_v4(),
+            address: String::new(),
+        };
+        let confirmed = output(100);
+        let spending = |prev: &TransactionOutput| {
+            let key = btclib::crypto::PrivateKey::new_key();
+            Transaction::new(
+                vec![btclib::types::TransactionInput {
+                    prev_transaction_output_hash: prev.hash(),
+                    public_key: key.public_key(),
+                    signature: btclib::crypto::Signature::sign_output(&prev.hash(), &key),
+                    multisig: None,
+                    cosignatures: vec![],
+                }],
+                vec![output(90)],
+            )
+        };
+        // the second one spends an output of the first
+        let parent = spending(&confirmed);
+        let child = spending(&parent.outputs[0]);
+
+        let db = temporary_db();
+        put_version(&db, 4).unwrap();
+        let mut value = Vec::new();
+        into_writer(&(false, 0u64, &confirmed), &mut value).unwrap();
+        insert(&db, utxo_key(&confirmed.hash()), value).unwrap();
+        let added = Utc::now();
+        let nanos = added.timestamp_nanos_opt().unwrap();
+        for transaction in [&parent, &child] {
+            let mut value = Vec::new();
+            into_writer(&(added, transaction), &mut value).unwrap();
+            let key = format!("{}{}:{}", keys::MEMPOOL_PREFIX, hex::encode(transaction.hash().as_bytes()), nanos);
+            insert(&db, key, value).unwrap();
+        }
+
+        upgrade(&db).unwrap();
+
+        for (transaction, fee) in [(&parent, 10), (&child, 0)] {
+            let key = super::super::mempool_key(&transaction.hash(), added);
+            let value = stored(&db, trees::MEMPOOL, &key).unwrap();
+            let (stored_added, stored_fee, _): (DateTime<Utc>, u64, Transaction) =
+                from_reader(value.as_slice()).unwrap();
+            assert_eq!(stored_added, added);
+            assert_eq!(stored_fee, fee);
+        }
+    }
+
+    #[test]
+    fn test_records_move_into_trees() {
+        let db = temporary_db();
+        put_version(&db, 5).unwrap();
+        let side_block = block(vec![]);
+        let side_key = format!("{}{}", keys::SIDE_BLOCK_PREFIX, side_block.hash());
+        insert(&db, side_key.as_bytes(), super::super::encode_block(&side_block, false).unwrap())
+            .unwrap();
+        insert(&db, keys::META_BLOCK_COUNT, 7u64.to_be_bytes()).unwrap();
+        insert(&db, format!("{}mx1", keys::WATCH_PREFIX), &[][..]).unwrap();
+
+        upgrade(&db).unwrap();
+
+        // only the schema version stays behind
+        assert_eq!(db.scan_prefix(trees::DEFAULT, &[]).count(), 1);
+        assert_eq!(stored_version(&db).unwrap(), Some(SCHEMA_VERSION));
+        let stored_block = stored(&db, trees::SIDE_BLOCKS, &side_block.hash().as_bytes()).unwrap();
+        assert_eq!(super::super::decode_block(&stored_block).unwrap().hash(), side_block.hash());
+        assert_eq!(stored(&db, trees::META, super::super::meta::BLOCK_COUNT), Some(7u64.to_be_bytes().to_vec()));
+        assert_eq!(stored(&db, trees::WATCHED, b"mx1"), Some(vec![]));
+    }
+
+    #[test]
+    fn test_legacy_hashes_are_refused() {
+        let output = TransactionOutput {
+            value: 1,
+            unique_id: uuid::Uuid::new_v4(),
+            address: String::new(),
+        };
+        let mut utxo = Vec::new();
+        into_writer(&(false, 0u64, &output), &mut utxo).unwrap();
+        let current = block(vec![output.clone()]);
+        // a block whose merkle root was taken over other hashes
+        let mut legacy = current.clone();
+        legacy.header.merkle_root = MerkleRoot::calculate(&[]);
+
+        let v6 = |block: &Block, utxo_hash: Hash| {
+            let db = temporary_db();
+            put_version(&db, 6).unwrap();
+            let value = super::super::encode_block(block, false).unwrap();
+            db.put(trees::BLOCKS, &super::super::height_key(0), &seal(&value)).unwrap();
+            db.put(trees::UTXOS, &utxo_hash.as_bytes(), &seal(&utxo)).unwrap();
+            db
+        };
+
+        let db = v6(&current, output.hash());
+        upgrade(&db).unwrap();
+        assert_eq!(stored_version(&db).unwrap(), Some(SCHEMA_VERSION));
+
+        for db in [v6(&legacy, output.hash()), v6(&current, Hash::zero())] {
+            let error = upgrade(&db).unwrap_err().to_string();
+            assert!(error.contains("re-sync"), "{error}");
+            assert_eq!(stored_version(&db).unwrap(), Some(6));
+        }
+    }
+
+    #[test]
+    fn test_newer_database_is_refused() {
+        let db = temporary_db();
+        put_version(&db, SCHEMA_VERSION + 1).unwrap();
+        assert!(upgrade(&db).is_err());
+    }
+}
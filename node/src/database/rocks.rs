@@ -0,0 +1,103 @@
+//! RocksDB storage, a column family per tree, for chains large enough
+//! that sled's memory use and write amplification get in the way
+use super::storage::{Batch, Records, Storage};
+use super::trees;
+use anyhow::{Context, Result};
+use rocksdb::{ColumnFamily, DB, Direction, IteratorMode, Options, WriteBatch};
+use std::path::{Path, PathBuf};
+
+pub struct RocksStorage {
+    db: DB,
+    path: PathBuf,
+}
+
+impl RocksStorage {
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut options = Options::default();
+        options.create_if_missing(true);
+        options.create_missing_column_families(true);
+        let db = DB::open_cf(&options, path, trees::ALL).context("Failed to open/create RocksDB database")?;
+        Ok(Self {
+            db,
+            path: path.to_path_buf(),
+        })
+    }
+
+    // the default column family is the default tree
+    fn cf(&self, tree: &str) -> Result<&ColumnFamily> {
+        self.db
+            .cf_handle(tree)
+            .with_context(|| format!("No {tree} column family in the database"))
+    }
+}
+
+impl Storage for RocksStorage {
+    fn get(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.db
+            .get_cf(self.cf(tree)?, key)
+            .with_context(|| format!("Failed to read from {tree}"))
+    }
+
+    fn put(&self, tree: &str, key: &[u8], value: &[u8]) -> Result<()> {
+        self.db
+            .put_cf(self.cf(tree)?, key, value)
+            .with_context(|| format!("Failed to write to {tree}"))
+    }
+
+    fn delete(&self, tree: &str, key: &[u8]) -> Result<()> {
+        self.db
+            .delete_cf(self.cf(tree)?, key)
+            .with_context(|| format!("Failed to delete from {tree}"))
+    }
+
+    fn iter_from<'a>(&'a self, tree: &str, start: &[u8]) -> Records<'a> {
+        let cf = match self.cf(tree) {
+            Ok(cf) => cf,
+            Err(e) => return Box::new(std::iter::once(Err(e))),
+        };
+        let name = tree.to_string();
+        Box::new(
+            self.db
+                .iterator_cf(cf, IteratorMode::From(start, Direction::Forward))
+                .map(move |item| {
+                    let (key, value) = item.with_context(|| format!("Failed to read from {name}"))?;
+                    Ok((key.into_vec(), value.into_vec()))
+                }),
+        )
+    }
+
+    fn last(&self, tree: &str) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let last = self.db.iterator_cf(self.cf(tree)?, IteratorMode::End).next().transpose();
+        let last = last.with_context(|| format!("Failed to read from {tree}"))?;
+        Ok(last.map(|(key, value)| (key.into_vec(), value.into_vec())))
+    }
+
+    fn apply(&self, batch: Batch) -> Result<()> {
+        let mut write = WriteBatch::default();
+        for (tree, key, value) in batch.writes {
+            let cf = self.cf(tree)?;
+            match value {
+                Some(value) => write.put_cf(cf, key, value),
+                None => write.delete_cf(cf, key),
+            }
+        }
+        self.db.write(write).context("Failed to write to database")
+    }
+
+    fn flush(&self) -> Result<()> {
+        for tree in trees::ALL.iter().chain([&trees::DEFAULT]) {
+            self.db
+                .flush_cf(self.cf(tree)?)
+                .context("Failed to flush database")?;
+        }
+        Ok(())
+    }
+
+    fn size_on_disk(&self) -> Result<u64> {
+        let mut size = 0;
+        for entry in std::fs::read_dir(&self.path).context("Failed to read database size")? {
+            size += entry.and_then(|entry| entry.metadata()).context("Failed to read database size")?.len();
+        }
+        Ok(size)
+    }
+}
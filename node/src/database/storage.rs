@@ -0,0 +1,250 @@
+//! Where the records end up. `BlockchainDB` keeps them in named trees
+//! of ordered keys through the `Storage` trait: sled's by default,
+//! RocksDB's column families with the `rocksdb` feature.
+use super::trees;
+use anyhow::{Context, Result, anyhow, bail};
+use sled::Transactional;
+use sled::transaction::ConflictableTransactionError;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Keys and values of records, in key order
+pub type Records<'a> = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a>;
+
+/// Tree, key and the value to store, None to delete the key
+pub(super) type Write = (&'static str, Vec<u8>, Option<Vec<u8>>);
+
+/// Writes applied together, all or none of them
+#[derive(Default)]
+pub struct Batch {
+    pub(super) writes: Vec<Write>,
+}
+
+impl Batch {
+    pub fn put(&mut self, tree: &'static str, key: impl Into<Vec<u8>>, value: Vec<u8>) {
+        self.writes.push((tree, key.into(), Some(value)));
+    }
+
+    pub fn delete(&mut self, tree: &'static str, key: impl Into<Vec<u8>>) {
+        self.writes.push((tree, key.into(), None));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+}
+
+/// A key-value store of named trees. Keys sort bytewise within a
+/// tree; `trees::DEFAULT` always exists, the others are created when
+/// first written.
+pub trait Storage: Send + Sync {
+    fn get(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    fn put(&self, tree: &str, key: &[u8], value: &[u8]) -> Result<()>;
+
+    fn delete(&self, tree: &str, key: &[u8]) -> Result<()>;
+
+    /// The records with keys from `start` on
+    fn iter_from<'a>(&'a self, tree: &str, start: &[u8]) -> Records<'a>;
+
+    /// The record with the highest key
+    fn last(&self, tree: &str) -> Result<Option<(Vec<u8>, Vec<u8>)>>;
+
+    /// Apply all writes of `batch` atomically
+    fn apply(&self, batch: Batch) -> Result<()>;
+
+    /// Sync all writes so far to disk, blocking until done
+    fn flush(&self) -> Result<()>;
+
+    fn size_on_disk(&self) -> Result<u64>;
+
+    /// The records with keys starting with `prefix`
+    fn scan_prefix<'a>(&'a self, tree: &str, prefix: &[u8]) -> Records<'a> {
+        let owned = prefix.to_vec();
+        Box::new(
+            self.iter_from(tree, prefix)
+                .take_while(move |item| item.as_ref().map_or(true, |(key, _)| key.starts_with(&owned))),
+        )
+    }
+
+    /// Whether the tree holds no records
+    fn is_empty(&self, tree: &str) -> Result<bool> {
+        match self.iter_from(tree, &[]).next() {
+            Some(item) => item.map(|_| false),
+            None => Ok(true),
+        }
+    }
+}
+
+/// The storage engines a database can be opened with
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Backend {
+    #[default]
+    Sled,
+    /// Needs the node built with the `rocksdb` feature
+    RocksDb,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sled" => Ok(Self::Sled),
+            "rocksdb" => Ok(Self::RocksDb),
+            _ => Err(format!("unknown storage backend {s}, expected sled or rocksdb")),
+        }
+    }
+}
+
+impl Backend {
+    /// Open or create a database of this backend at `path`
+    pub fn open(self, path: &Path) -> Result<Box<dyn Storage>> {
+        // each leaves files the other would take for its own
+        let rocksdb_files = path.join("CURRENT").exists();
+        let sled_files = path.join("conf").exists();
+        match self {
+            Self::Sled if rocksdb_files => bail!("{} holds a RocksDB database, open it with --db-backend rocksdb", path.display()),
+            Self::Sled => Ok(Box::new(SledStorage::open(path)?)),
+            Self::RocksDb if sled_files => bail!("{} holds a sled database, open it with --db-backend sled", path.display()),
+            #[cfg(feature = "rocksdb")]
+            Self::RocksDb => Ok(Box::new(super::rocks::RocksStorage::open(path)?)),
+            #[cfg(not(feature = "rocksdb"))]
+            Self::RocksDb => bail!("the node was built without the rocksdb feature"),
+        }
+    }
+}
+
+/// Trees of a sled database, the default one as `trees::DEFAULT`
+pub struct SledStorage {
+    db: sled::Db,
+}
+
+impl SledStorage {
+    pub fn open(path: &Path) -> Result<Self> {
+        let db = sled::open(path).context("Failed to open/create database")?;
+        Ok(Self { db })
+    }
+
+    /// A database living in memory until dropped
+    pub fn temporary() -> Result<Self> {
+        let db = sled::Config::new()
+            .temporary(true)
+            .open()
+            .context("Failed to create temporary database")?;
+        Ok(Self { db })
+    }
+
+    fn tree(&self, name: &str) -> Result<sled::Tree> {
+        if name == trees::DEFAULT {
+            return Ok((*self.db).clone());
+        }
+        self.db.open_tree(name).with_context(|| format!("Failed to open the {name} tree"))
+    }
+}
+
+impl Storage for SledStorage {
+    fn get(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let value = self.tree(tree)?.get(key).with_context(|| format!("Failed to read from {tree}"))?;
+        Ok(value.map(|value| value.to_vec()))
+    }
+
+    fn put(&self, tree: &str, key: &[u8], value: &[u8]) -> Result<()> {
+        self.tree(tree)?.insert(key, value).with_context(|| format!("Failed to write to {tree}"))?;
+        Ok(())
+    }
+
+    fn delete(&self, tree: &str, key: &[u8]) -> Result<()> {
+        self.tree(tree)?.remove(key).with_context(|| format!("Failed to delete from {tree}"))?;
+        Ok(())
+    }
+
+    fn iter_from<'a>(&'a self, tree: &str, start: &[u8]) -> Records<'a> {
+        let name = tree.to_string();
+        match self.tree(tree) {
+            Ok(tree) => Box::new(tree.range(start..).map(move |item| {
+                let (key, value) = item.with_context(|| format!("Failed to read from {name}"))?;
+                Ok((key.to_vec(), value.to_vec()))
+            })),
+            Err(e) => Box::new(std::iter::once(Err(e))),
+        }
+    }
+
+    fn last(&self, tree: &str) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let last = self.tree(tree)?.last().with_context(|| format!("Failed to read from {tree}"))?;
+        Ok(last.map(|(key, value)| (key.to_vec(), value.to_vec())))
+    }
+
+    fn apply(&self, batch: Batch) -> Result<()> {
+        // sled can't run a transaction over no trees at all
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let mut names: Vec<&str> = vec![];
+        let mut batches: Vec<sled::Batch> = vec![];
+        for (tree, key, value) in batch.writes {
+            let index = match names.iter().position(|name| *name == tree) {
+                Some(index) => index,
+                None => {
+                    names.push(tree);
+                    batches.push(sled::Batch::default());
+                    names.len() - 1
+                }
+            };
+            match value {
+                Some(value) => batches[index].insert(key, value),
+                None => batches[index].remove(key),
+            }
+        }
+        let trees = names.iter().map(|name| self.tree(name)).collect::<Result<Vec<_>>>()?;
+        trees
+            .as_slice()
+            .transaction(|trees| {
+                for (tree, batch) in trees.iter().zip(&batches) {
+                    tree.apply_batch(batch)?;
+                }
+                Ok::<_, ConflictableTransactionError>(())
+            })
+            .map_err(|e| anyhow!("Failed to write to database: {e}"))
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.db.flush().context("Failed to flush database")?;
+        Ok(())
+    }
+
+    fn size_on_disk(&self) -> Result<u64> {
+        self.db.size_on_disk().context("Failed to read database size")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sled_storage() {
+        let storage = SledStorage::temporary().unwrap();
+        assert!(storage.is_empty(trees::BLOCKS).unwrap());
+        let mut batch = Batch::default();
+        for key in [&b"a1"[..], b"a2", b"b1"] {
+            batch.put(trees::BLOCKS, key, key.to_vec());
+        }
+        batch.put(trees::DEFAULT, &b"a3"[..], vec![]);
+        storage.apply(batch).unwrap();
+
+        let keys = |prefix: &[u8]| -> Vec<Vec<u8>> {
+            storage.scan_prefix(trees::BLOCKS, prefix).map(|item| item.unwrap().0).collect()
+        };
+        assert_eq!(keys(b"a"), vec![b"a1".to_vec(), b"a2".to_vec()]);
+        assert_eq!(storage.last(trees::BLOCKS).unwrap(), Some((b"b1".to_vec(), b"b1".to_vec())));
+        // trees don't share keys
+        assert_eq!(storage.get(trees::DEFAULT, b"a1").unwrap(), None);
+        assert_eq!(storage.get(trees::DEFAULT, b"a3").unwrap(), Some(vec![]));
+
+        storage.delete(trees::BLOCKS, b"a1").unwrap();
+        assert_eq!(keys(b""), vec![b"a2".to_vec(), b"b1".to_vec()]);
+        storage.apply(Batch::default()).unwrap();
+        assert!(Backend::from_str("leveldb").is_err());
+    }
+}
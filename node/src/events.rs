@@ -0,0 +1,116 @@
+//! Event stream for subscribers connected over WebSocket at `/ws`
+use crate::context::NodeContext;
+use axum::Router;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use btclib::events::Topic;
+use serde::Deserialize;
+use std::collections::HashSet;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, warn};
+
+pub fn router(ctx: NodeContext) -> Router {
+    Router::new().route("/ws", get(subscribe)).with_state(ctx)
+}
+
+#[derive(Deserialize)]
+struct SubscribeQuery {
+    /// Comma separated topics, all of them when left out
+    topics: Option<String>,
+}
+
+/// Changes to the topics of a connection, sent by the client as JSON
+/// text messages, e.g. `{"subscribe": ["reorgs"]}`
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct TopicRequest {
+    subscribe: Vec<Topic>,
+    unsubscribe: Vec<Topic>,
+}
+
+fn parse_topics(topics: Option<&str>) -> Result<HashSet<Topic>, String> {
+    match topics {
+        None => Ok(Topic::ALL.into_iter().collect()),
+        Some(topics) => topics
+            .split(',')
+            .filter(|topic| !topic.trim().is_empty())
+            .map(str::parse)
+            .collect(),
+    }
+}
+
+async fn subscribe(
+    State(ctx): State<NodeContext>,
+    Query(query): Query<SubscribeQuery>,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    let topics = match parse_topics(query.topics.as_deref()) {
+        Ok(topics) => topics,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+    upgrade.on_upgrade(move |socket| stream_events(ctx, socket, topics))
+}
+
+async fn stream_events(ctx: NodeContext, mut socket: WebSocket, mut topics: HashSet<Topic>) {
+    let mut events = ctx.events.subscribe();
+    loop {
+        tokio::select! {
+            event = events.recv() => {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!("event subscriber fell behind, {} events dropped", missed);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                if !topics.contains(&event.topic()) {
+                    continue;
+                }
+                let json = serde_json::to_string(&event).expect("events serialize");
+                if socket.send(Message::Text(json.into())).await.is_err() {
+                    break;
+                }
+            }
+            message = socket.recv() => {
+                let text = match message {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    // axum answers pings by itself
+                    Some(Ok(_)) => continue,
+                };
+                match serde_json::from_str::<TopicRequest>(&text) {
+                    Ok(request) => {
+                        topics.extend(request.subscribe);
+                        topics.retain(|topic| !request.unsubscribe.contains(topic));
+                    }
+                    Err(e) => {
+                        let error = serde_json::json!({ "error": e.to_string() }).to_string();
+                        if socket.send(Message::Text(error.into())).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
+    debug!("event subscriber disconnected");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_topic_filters() {
+        assert_eq!(parse_topics(None).unwrap().len(), Topic::ALL.len());
+        let topics = parse_topics(Some("blocks, reorgs")).unwrap();
+        assert!(topics.contains(&Topic::Blocks) && topics.contains(&Topic::Reorgs));
+        assert!(!topics.contains(&Topic::Transactions));
+        assert!(parse_topics(Some("")).unwrap().is_empty());
+        assert!(parse_topics(Some("blocks,utxos")).is_err());
+    }
+}
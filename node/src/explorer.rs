@@ -0,0 +1,468 @@
+//! Read-only HTML block explorer served under `/explorer`
+use crate::context::NodeContext;
+use crate::database::HistoryEntry as IndexedEntry;
+use crate::watch;
+use axum::Router;
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Redirect, Response};
+use axum::routing::get;
+use btclib::address::Address;
+use btclib::amount::format_btc;
+use btclib::difficulty::{difficulty_from_target, format_difficulty};
+use btclib::mining::{Report, format_hashrate};
+use btclib::sha256::Hash;
+use btclib::types::{Block, Blockchain, Transaction};
+use maud::{DOCTYPE, Markup, html};
+use serde::Deserialize;
+use std::collections::HashMap;
+use tracing::warn;
+
+/// Blocks listed on the front page
+const RECENT_BLOCKS: u64 = 20;
+/// Most transactions listed for an address
+const HISTORY_LIMIT: usize = 100;
+
+const STYLE: &str = "body { font-family: sans-serif; margin: 2em auto; max-width: 70em; } \
+    table { border-collapse: collapse; width: 100%; } \
+    td, th { border-bottom: 1px solid #ddd; padding: 0.3em 0.6em; text-align: left; } \
+    code { font-size: 0.9em; }";
+
+pub fn router(ctx: NodeContext) -> Router {
+    Router::new()
+        .route("/explorer", get(index))
+        .route("/explorer/search", get(search))
+        .route("/explorer/block/{id}", get(block))
+        .route("/explorer/tx/{hash}", get(transaction))
+        .route("/explorer/address/{address}", get(address))
+        .with_state(ctx)
+}
+
+fn page(title: &str, content: Markup) -> Markup {
+    html! {
+        (DOCTYPE)
+        html {
+            head {
+                meta charset="utf-8";
+                title { (title) " - grapheno explorer" }
+                style { (STYLE) }
+            }
+            body {
+                header {
+                    a href="/explorer" { "grapheno explorer" }
+                    form action="/explorer/search" style="float: right" {
+                        input name="q" size="50" placeholder="height, block or transaction hash, address";
+                        " "
+                        button { "Search" }
+                    }
+                }
+                h1 { (title) }
+                (content)
+            }
+        }
+    }
+}
+
+fn not_found(what: String) -> Response {
+    (StatusCode::NOT_FOUND, page("Not found", html! { p { (what) } })).into_response()
+}
+
+fn amount(sats: u64) -> String {
+    format!("{} BTC", format_btc(sats))
+}
+
+fn block_link(height: u64, hash: Hash) -> Markup {
+    html! { a href={ "/explorer/block/" (height) } { code { (hash) } } }
+}
+
+fn tx_link(hash: Hash) -> Markup {
+    html! { a href={ "/explorer/tx/" (hash) } { code { (hash) } } }
+}
+
+fn address_link(address: &str) -> Markup {
+    html! { a href={ "/explorer/address/" (address) } { code { (address) } } }
+}
+
+async fn index(State(ctx): State<NodeContext>) -> Markup {
+    let mining = ctx.mining.as_ref().map(|stats| stats.current());
+    let blockchain = ctx.blockchain.read().await;
+    index_page(&blockchain, mining.as_ref())
+}
+
+/// Stats of the node's own mining threads
+fn mining_panel(report: &Report) -> Markup {
+    let threads: Vec<String> = report.thread_hashrates.iter().map(|rate| format_hashrate(*rate)).collect();
+    html! {
+        h2 { "Mining" }
+        table {
+            tr { th { "Hashrate" } td { (format_hashrate(report.hashrate)) } }
+            tr { th { "Threads" } td { (threads.join(", ")) } }
+            tr { th { "Blocks accepted" } td { (report.accepted) } }
+            tr { th { "Stale blocks" } td { (report.stale) } }
+            tr {
+                th { "Template age" }
+                td {
+                    @match report.template_age {
+                        Some(age) => { (age) " s" }
+                        None => "no template yet",
+                    }
+                }
+            }
+            tr { th { "Hashes tried" } td { (report.total_hashes) } }
+        }
+    }
+}
+
+fn index_page(blockchain: &Blockchain, mining: Option<&Report>) -> Markup {
+    let height = blockchain.block_height();
+    let lowest = height.saturating_sub(RECENT_BLOCKS).max(blockchain.base_height());
+    page(
+        "Recent blocks",
+        html! {
+            p {
+                (height) " blocks, " (blockchain.utxos().len()) " unspent outputs, "
+                (blockchain.mempool().len()) " transactions in the mempool"
+            }
+            @if let Some(report) = mining {
+                (mining_panel(report))
+                h2 { "Recent blocks" }
+            }
+            table {
+                tr { th { "Height" } th { "Hash" } th { "Time" } th { "Transactions" } }
+                @for height in (lowest..height).rev() {
+                    @if let Some(block) = blockchain.block_at(height) {
+                        tr {
+                            td { (height) }
+                            td { (block_link(height, block.hash())) }
+                            td { (block.header.timestamp.format("%Y-%m-%d %H:%M:%S")) }
+                            td { (block.transactions.len()) }
+                        }
+                    }
+                }
+            }
+        },
+    )
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    q: String,
+}
+
+/// Page a search query refers to: a height, a block or transaction
+/// hash, or else an address
+fn search_target(blockchain: &Blockchain, query: &str) -> String {
+    let query = query.trim();
+    if query.parse::<u64>().is_ok() {
+        return format!("/explorer/block/{}", query);
+    }
+    if let Ok(hash) = query.parse::<Hash>() {
+        if blockchain.height_of(&hash).is_some() {
+            return format!("/explorer/block/{}", hash);
+        }
+        return format!("/explorer/tx/{}", hash);
+    }
+    format!("/explorer/address/{}", query)
+}
+
+async fn search(State(ctx): State<NodeContext>, Query(query): Query<SearchQuery>) -> Redirect {
+    let blockchain = ctx.blockchain.read().await;
+    Redirect::to(&search_target(&blockchain, &query.q))
+}
+
+async fn block(State(ctx): State<NodeContext>, Path(id): Path<String>) -> Response {
+    let blockchain = ctx.blockchain.read().await;
+    let height = match id.parse::<u64>() {
+        Ok(height) => Some(height),
+        Err(_) => id.parse::<Hash>().ok().and_then(|hash| blockchain.height_of(&hash)),
+    };
+    match height.and_then(|height| Some((height, blockchain.block_at(height)?))) {
+        Some((height, block)) => block_page(&blockchain, height, block).into_response(),
+        None => not_found(format!("No block {} on this node, it may have been pruned", id)),
+    }
+}
+
+fn block_page(blockchain: &Blockchain, height: u64, block: &Block) -> Markup {
+    let header = &block.header;
+    page(
+        &format!("Block {}", height),
+        html! {
+            table {
+                tr { th { "Hash" } td { code { (block.hash()) } } }
+                tr { th { "Confirmations" } td { (blockchain.confirmations(height)) } }
+                tr { th { "Time" } td { (header.timestamp) } }
+                tr {
+                    th { "Previous block" }
+                    td {
+                        @if height > 0 {
+                            (block_link(height - 1, header.prev_block_hash))
+                        } @else {
+                            "none, this is the genesis block"
+                        }
+                    }
+                }
+                tr { th { "Merkle root" } td { code { (header.merkle_root) } } }
+                tr { th { "Target" } td { code { (header.target) } } }
+                tr { th { "Difficulty" } td { (format_difficulty(difficulty_from_target(header.target))) } }
+                @if let Some(work) = blockchain.chain_work_at(height) {
+                    tr { th { "Chain work" } td { code { (format!("{:x}", work)) } } }
+                }
+                tr { th { "Nonce" } td { (header.nonce) } }
+            }
+            h2 { (block.transactions.len()) " transactions" }
+            table {
+                tr { th { "Transaction" } th { "Inputs" } th { "Outputs" } th { "Value" } }
+                @for transaction in &block.transactions {
+                    tr {
+                        td { (tx_link(transaction.hash())) }
+                        td { (transaction.inputs.len()) }
+                        td { (transaction.outputs.len()) }
+                        td { (amount(transaction.outputs.iter().map(|output| output.value).sum())) }
+                    }
+                }
+            }
+        },
+    )
+}
+
+async fn transaction(State(ctx): State<NodeContext>, Path(hash): Path<String>) -> Response {
+    let blockchain = ctx.blockchain.read().await;
+    let Ok(hash) = hash.parse::<Hash>() else {
+        return not_found(format!("{} is not a transaction hash", hash));
+    };
+    let confirmed = blockchain.transaction_by_id(&hash).and_then(|(height, index)| {
+        Some((height, &blockchain.block_at(height)?.transactions[index]))
+    });
+    if let Some((height, transaction)) = confirmed {
+        return transaction_page(&blockchain, transaction, Some(height)).into_response();
+    }
+    let pending = blockchain
+        .mempool()
+        .iter()
+        .map(|(_, transaction)| transaction)
+        .find(|transaction| transaction.hash() == hash);
+    match pending {
+        Some(transaction) => transaction_page(&blockchain, transaction, None).into_response(),
+        None => not_found(format!("Transaction {} is not known to this node", hash)),
+    }
+}
+
+fn transaction_page(blockchain: &Blockchain, transaction: &Transaction, height: Option<u64>) -> Markup {
+    let params = blockchain.params();
+    page(
+        "Transaction",
+        html! {
+            table {
+                tr { th { "Hash" } td { code { (transaction.hash()) } } }
+                tr {
+                    th { "Status" }
+                    td {
+                        @match height {
+                            Some(height) => {
+                                "confirmed in block " a href={ "/explorer/block/" (height) } { (height) }
+                                ", " (blockchain.confirmations(height)) " confirmations"
+                            }
+                            None => "in the mempool",
+                        }
+                    }
+                }
+                @if let (None, Some(fee)) = (height, blockchain.transaction_fee(transaction)) {
+                    tr { th { "Fee" } td { (amount(fee)) } }
+                }
+                tr { th { "Size" } td { (transaction.size()) " bytes" } }
+            }
+            h2 { "Inputs" }
+            @if transaction.inputs.is_empty() {
+                p { "None, this is a coinbase transaction" }
+            } @else {
+                table {
+                    tr { th { "Spent output" } th { "Signed by" } }
+                    @for input in &transaction.inputs {
+                        tr {
+                            td { code { (input.prev_transaction_output_hash) } }
+                            td {
+                                @match &input.multisig {
+                                    Some(policy) => (address_link(&policy.to_address_for(params))),
+                                    None => (address_link(&input.public_key.to_address_for(params))),
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            h2 { "Outputs" }
+            table {
+                tr { th { "Address" } th { "Value" } th { "State" } }
+                @for output in &transaction.outputs {
+                    tr {
+                        td { (address_link(&output.address)) }
+                        td { (amount(output.value)) }
+                        td {
+                            @match blockchain.utxos().get(&output.hash()) {
+                                Some((true, _, _)) => "spent in the mempool",
+                                Some((false, _, _)) => "unspent",
+                                None if height.is_some() => "spent",
+                                None => "unconfirmed",
+                            }
+                        }
+                    }
+                }
+            }
+        },
+    )
+}
+
+async fn address(State(ctx): State<NodeContext>, Path(address): Path<String>) -> Response {
+    if Address::parse_for(&address, ctx.blockchain.read().await.params()).is_err() {
+        return not_found(format!("{} is not an address of this network", address));
+    }
+    // the index has the full history of watched addresses
+    let indexed = match watch::history(&ctx, &address).await {
+        Ok(indexed) => indexed,
+        Err(e) => {
+            warn!("failed to read the history of {address}: {e}");
+            None
+        }
+    };
+    let blockchain = ctx.blockchain.read().await;
+    address_page(&blockchain, &address, indexed).into_response()
+}
+
+/// A transaction moving funds of an address
+struct HistoryEntry {
+    hash: Hash,
+    height: Option<u64>,
+    received: u64,
+    sent: u64,
+}
+
+/// Transactions paying to or spending from `address`, newest first.
+/// The confirmed ones come from the index for watched addresses,
+/// otherwise only blocks stored on this node are searched.
+fn address_history(blockchain: &Blockchain, address: &str, indexed: Option<Vec<IndexedEntry>>) -> Vec<HistoryEntry> {
+    // outputs paid to the address, to recognize the inputs spending them
+    let mut owned: HashMap<Hash, u64> = HashMap::new();
+    let mut history = vec![];
+    let mut scanned = vec![];
+    match indexed {
+        Some(entries) => {
+            history.extend(entries.into_iter().map(|entry| HistoryEntry {
+                hash: entry.txid,
+                height: Some(entry.height),
+                received: entry.received,
+                sent: entry.sent,
+            }));
+            // mempool transactions can only spend what is unspent
+            for (hash, (_, _, output)) in blockchain.utxos() {
+                if Address::same(&output.address, address) {
+                    owned.insert(*hash, output.value);
+                }
+            }
+        }
+        None => scanned.extend((blockchain.base_height()..blockchain.block_height()).filter_map(|height| {
+            let block = blockchain.block_at(height)?;
+            Some(block.transactions.iter().map(move |transaction| (Some(height), transaction)))
+        })),
+    }
+    let pending = blockchain.mempool().iter().map(|(_, transaction)| (None, transaction));
+    for (height, transaction) in scanned.into_iter().flatten().chain(pending) {
+        let sent = transaction
+            .inputs
+            .iter()
+            .filter_map(|input| owned.get(&input.prev_transaction_output_hash))
+            .sum();
+        let mut received = 0;
+        for output in &transaction.outputs {
+            if Address::same(&output.address, address) {
+                owned.insert(output.hash(), output.value);
+                received += output.value;
+            }
+        }
+        if sent > 0 || received > 0 {
+            history.push(HistoryEntry { hash: transaction.hash(), height, received, sent });
+        }
+    }
+    history.reverse();
+    history
+}
+
+fn address_page(blockchain: &Blockchain, address: &str, indexed: Option<Vec<IndexedEntry>>) -> Markup {
+    let unspent = blockchain
+        .utxos()
+        .values()
+        .filter(|(_, _, output)| Address::same(&output.address, address));
+    let (count, balance) = unspent.fold((0, 0), |(count, balance), (_, _, output)| {
+        (count + 1, balance + output.value)
+    });
+    let watched = indexed.is_some();
+    let history = address_history(blockchain, address, indexed);
+    page(
+        "Address",
+        html! {
+            table {
+                tr { th { "Address" } td { code { (address) } } }
+                tr { th { "Balance" } td { (amount(balance)) } }
+                tr { th { "Unspent outputs" } td { (count) } }
+            }
+            h2 { "History" }
+            @if !watched && blockchain.base_height() > 0 {
+                p { "Blocks below height " (blockchain.base_height()) " are not stored on this node" }
+            }
+            table {
+                tr { th { "Transaction" } th { "Block" } th { "Received" } th { "Sent" } }
+                @for entry in history.iter().take(HISTORY_LIMIT) {
+                    tr {
+                        td { (tx_link(entry.hash)) }
+                        td {
+                            @match entry.height {
+                                Some(height) => a href={ "/explorer/block/" (height) } { (height) },
+                                None => "pending",
+                            }
+                        }
+                        td { @if entry.received > 0 { (amount(entry.received)) } }
+                        td { @if entry.sent > 0 { (amount(entry.sent)) } }
+                    }
+                }
+            }
+            @if history.len() > HISTORY_LIMIT {
+                p { "Only the latest " (HISTORY_LIMIT) " of " (history.len()) " transactions are shown" }
+            }
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_search_targets() {
+        let blockchain = Blockchain::new();
+        assert_eq!(search_target(&blockchain, " 12 "), "/explorer/block/12");
+        let hash = Hash::hash(&"transaction").to_string();
+        // unknown hashes can only be transactions
+        assert_eq!(search_target(&blockchain, &hash), format!("/explorer/tx/{}", hash));
+        assert_eq!(
+            search_target(&blockchain, "18VvDB8FnwU4symRpFSjbFoDJFyzQyHWVV"),
+            "/explorer/address/18VvDB8FnwU4symRpFSjbFoDJFyzQyHWVV"
+        );
+    }
+
+    #[test]
+    fn test_mining_panel_only_when_mining() {
+        let blockchain = Blockchain::new();
+        assert!(!index_page(&blockchain, None).into_string().contains("Hashrate"));
+        let report = Report {
+            hashrate: 2_500.0,
+            thread_hashrates: vec![900.0, 1_600.0],
+            accepted: 3,
+            stale: 1,
+            template_age: Some(4),
+            ..Report::default()
+        };
+        let page = index_page(&blockchain, Some(&report)).into_string();
+        assert!(page.contains("2.50 kH/s"));
+        assert!(page.contains("900.00 H/s, 1.60 kH/s"));
+        assert!(page.contains("4 s"));
+    }
+}
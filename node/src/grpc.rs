@@ -0,0 +1,1053 @@
+//! gRPC service generated from `proto/grapheno.proto`
+use crate::access::{AccessList, parse_net};
+use crate::context::NodeContext;
+use crate::handler::{self, DEFAULT_TTL};
+use crate::journal::Record;
+use crate::network::PeerHandle;
+use crate::traffic::Traffic;
+use crate::{util, watch};
+use anyhow::{Context, Result, anyhow};
+use btclib::address::Address;
+use btclib::difficulty::difficulty_from_target;
+use btclib::encoding::{Decode, Encode};
+use btclib::error::BtcError;
+use btclib::events::{ChainEvent, Topic};
+use btclib::network::{Envelope, Message};
+use btclib::params::ChainParams;
+use btclib::sha256::Hash;
+use btclib::types::{self, Blockchain, TipStatus};
+use btclib::U256;
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use tokio::net::lookup_host;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{Stream, StreamExt};
+use tonic::{Request, Response, Status};
+use tracing::{info, warn};
+
+pub mod proto {
+    tonic::include_proto!("grapheno");
+}
+
+use proto::node_client::NodeClient;
+use proto::node_server::{Node, NodeServer};
+
+/// Ask the node serving gRPC on `addr` to back its database up to
+/// `dest`, a path on its machine
+pub async fn request_backup(addr: SocketAddr, dest: &Path) -> Result<proto::BackupResponse> {
+    let dest = std::path::absolute(dest)?;
+    let mut client = NodeClient::connect(format!("http://{addr}"))
+        .await
+        .with_context(|| format!("Failed to connect to gRPC on {addr}"))?;
+    let request = proto::BackupRequest {
+        path: dest.to_string_lossy().into_owned(),
+    };
+    let response = client.backup(request).await.map_err(|status| anyhow!("{}", status.message()))?;
+    Ok(response.into_inner())
+}
+
+/// Serve until the server fails
+pub async fn serve(ctx: NodeContext, addr: SocketAddr) -> Result<()> {
+    info!("gRPC on {}", addr);
+    tonic::transport::Server::builder()
+        .add_service(NodeServer::new(NodeService { ctx }))
+        .serve(addr)
+        .await?;
+    Ok(())
+}
+
+struct NodeService {
+    ctx: NodeContext,
+}
+
+impl NodeService {
+    // apply an operator's change to which blocks are valid, telling
+    // event subscribers how the main chain moved
+    async fn change_chain(
+        &self,
+        record: Record,
+        change: impl FnOnce(&mut Blockchain) -> btclib::error::Result<types::ChainUpdate>,
+    ) -> Result<Response<proto::ChainChange>, Status> {
+        let mut blockchain = self.ctx.blockchain.write().await;
+        self.ctx.record(record);
+        let old_tip = blockchain.tip_hash();
+        let update = change(&mut blockchain).map_err(|e| match e {
+            BtcError::UnknownBlock => Status::not_found(e.to_string()),
+            _ => Status::failed_precondition(e.to_string()),
+        })?;
+        let events = handler::chain_events(&blockchain, old_tip, &update);
+        let change = proto::ChainChange {
+            fork_height: update.fork_height,
+            disconnected: update.disconnected.iter().map(|block| block.hash().to_string()).collect(),
+            tip_hash: blockchain.tip_hash().map(|hash| hash.to_string()).unwrap_or_default(),
+            height: blockchain.block_height(),
+        };
+        drop(blockchain);
+        for event in events {
+            self.ctx.publish(event);
+        }
+        Ok(Response::new(change))
+    }
+}
+
+fn parse_hash(hash: &str) -> Result<Hash, Status> {
+    hash.parse()
+        .map_err(|_| Status::invalid_argument(format!("{} is not a hash", hash)))
+}
+
+fn output(output: &types::TransactionOutput) -> proto::TransactionOutput {
+    proto::TransactionOutput {
+        hash: output.hash().to_string(),
+        value: output.value,
+        unique_id: output.unique_id.to_string(),
+        address: output.address.clone(),
+    }
+}
+
+fn transaction(blockchain: &Blockchain, transaction: &types::Transaction) -> proto::Transaction {
+    let params = blockchain.params();
+    proto::Transaction {
+        hash: transaction.hash().to_string(),
+        inputs: transaction
+            .inputs
+            .iter()
+            .map(|input| proto::TransactionInput {
+                prev_output_hash: input.prev_transaction_output_hash.to_string(),
+                signer: match &input.multisig {
+                    Some(policy) => policy.to_address_for(params),
+                    None => input.public_key.to_address_for(params),
+                },
+            })
+            .collect(),
+        outputs: transaction.outputs.iter().map(output).collect(),
+        size: transaction.size() as u64,
+    }
+}
+
+/// Lower bounds of the fee rate histogram buckets, in satoshis per byte
+pub const FEE_RATE_BUCKETS: [f64; 12] = [0.0, 0.01, 0.02, 0.05, 0.1, 0.2, 0.5, 1.0, 2.0, 5.0, 10.0, 100.0];
+
+fn fee_rate(fee: u64, size: usize) -> f64 {
+    fee as f64 / size.max(1) as f64
+}
+
+fn mempool_entry(
+    blockchain: &Blockchain,
+    added: DateTime<Utc>,
+    tx: &types::Transaction,
+    now: DateTime<Utc>,
+) -> proto::MempoolEntry {
+    let hash = tx.hash();
+    let size = tx.size();
+    let fee = blockchain.transaction_fee(tx).unwrap_or(0);
+    let (ancestor_fee, ancestor_size) = blockchain.ancestor_package(tx);
+    proto::MempoolEntry {
+        txid: hash.to_string(),
+        size: size as u64,
+        fee,
+        fee_rate: fee_rate(fee, size),
+        time: added.timestamp(),
+        age_seconds: (now - added).num_seconds().max(0) as u64,
+        ancestors: blockchain
+            .mempool_ancestors(tx)
+            .iter()
+            .map(|ancestor| ancestor.hash().to_string())
+            .collect(),
+        descendants: blockchain
+            .mempool_descendants(&hash)
+            .iter()
+            .map(Hash::to_string)
+            .collect(),
+        ancestor_fee,
+        ancestor_size: ancestor_size as u64,
+    }
+}
+
+/// Every mempool entry, best fee rate first
+fn mempool_entries(blockchain: &Blockchain, now: DateTime<Utc>) -> Vec<proto::MempoolEntry> {
+    let mut entries: Vec<proto::MempoolEntry> = blockchain
+        .mempool()
+        .iter()
+        .map(|(added, tx)| mempool_entry(blockchain, *added, tx, now))
+        .collect();
+    entries.sort_by(|a, b| b.fee_rate.total_cmp(&a.fee_rate).then_with(|| a.txid.cmp(&b.txid)));
+    entries
+}
+
+fn mempool_info(entries: &[proto::MempoolEntry]) -> proto::MempoolInfo {
+    let mut histogram: Vec<proto::FeeRateBucket> = FEE_RATE_BUCKETS
+        .iter()
+        .map(|min_fee_rate| proto::FeeRateBucket {
+            min_fee_rate: *min_fee_rate,
+            ..Default::default()
+        })
+        .collect();
+    for entry in entries {
+        let index = FEE_RATE_BUCKETS.partition_point(|min| *min <= entry.fee_rate) - 1;
+        let bucket = &mut histogram[index];
+        bucket.count += 1;
+        bucket.bytes += entry.size;
+        bucket.fees += entry.fee;
+    }
+    let rates = entries.iter().map(|entry| entry.fee_rate);
+    proto::MempoolInfo {
+        size: entries.len() as u64,
+        bytes: entries.iter().map(|entry| entry.size).sum(),
+        total_fee: entries.iter().map(|entry| entry.fee).sum(),
+        min_fee_rate: rates.clone().reduce(f64::min).unwrap_or(0.0),
+        max_fee_rate: rates.reduce(f64::max).unwrap_or(0.0),
+        histogram,
+    }
+}
+
+fn block(blockchain: &Blockchain, height: u64, chain_work: U256, block: &types::Block) -> proto::Block {
+    let header = &block.header;
+    proto::Block {
+        hash: block.hash().to_string(),
+        height,
+        chainwork: format!("{:x}", chain_work),
+        side_branch: blockchain.height_of(&block.hash()).is_none(),
+        header: Some(proto::BlockHeader {
+            timestamp: header.timestamp.timestamp(),
+            nonce: header.nonce,
+            prev_block_hash: header.prev_block_hash.to_string(),
+            merkle_root: header.merkle_root.to_string(),
+            target: format!("{:x}", header.target),
+            difficulty: difficulty_from_target(header.target),
+        }),
+        transactions: block
+            .transactions
+            .iter()
+            .map(|tx| transaction(blockchain, tx))
+            .collect(),
+    }
+}
+
+fn chain_tip(tip: types::ChainTip) -> proto::ChainTip {
+    let status = match tip.status {
+        TipStatus::Active => proto::TipStatus::Active,
+        TipStatus::ValidFork => proto::TipStatus::ValidFork,
+        TipStatus::Invalid => proto::TipStatus::Invalid,
+    };
+    proto::ChainTip {
+        hash: tip.hash.to_string(),
+        height: tip.height,
+        branch_length: tip.branch_length,
+        status: status.into(),
+        chainwork: format!("{:x}", tip.chain_work),
+    }
+}
+
+fn peer(id: &str, handle: &PeerHandle) -> proto::Peer {
+    proto::Peer {
+        id: id.to_string(),
+        address: handle.addr.map(|addr| addr.to_string()).unwrap_or_default(),
+        inbound: !handle.dialed,
+        connected_seconds: handle.connected_at.elapsed().as_secs(),
+        height: handle.version.as_ref().map(|version| version.height),
+        services: handle
+            .version
+            .as_ref()
+            .map(|version| version.services.to_string())
+            .unwrap_or_default(),
+        identity: handle.identity.as_ref().map(|key| key.to_hex()).unwrap_or_default(),
+        trusted: handle.trusted,
+        misbehavior: handle.misbehavior,
+        received: Some(traffic(handle.traffic.total_received())),
+        sent: Some(traffic(handle.traffic.total_sent())),
+        received_by_type: by_type(&handle.traffic.received),
+        sent_by_type: by_type(&handle.traffic.sent),
+        latency_ms: handle.latency.map(|latency| latency.as_millis() as u64),
+        useful: handle.useful,
+        silent_seconds: handle.last_message.elapsed().as_secs(),
+    }
+}
+
+fn peer_info(ctx: &NodeContext) -> proto::PeerInfo {
+    let mut peers: Vec<proto::Peer> = ctx
+        .network
+        .peers
+        .iter()
+        .map(|entry| peer(entry.key(), entry.value()))
+        .collect();
+    peers.sort_by(|a, b| a.id.cmp(&b.id));
+    proto::PeerInfo { peers }
+}
+
+fn traffic(traffic: Traffic) -> proto::Traffic {
+    proto::Traffic {
+        messages: traffic.messages,
+        bytes: traffic.bytes,
+    }
+}
+
+fn by_type(by_kind: &BTreeMap<&'static str, Traffic>) -> HashMap<String, proto::Traffic> {
+    by_kind
+        .iter()
+        .map(|(kind, stats)| (kind.to_string(), traffic(*stats)))
+        .collect()
+}
+
+fn access_list(access: &AccessList) -> proto::AccessList {
+    proto::AccessList {
+        allow: access.allow.iter().map(|net| net.to_string()).collect(),
+        deny: access.deny.iter().map(|net| net.to_string()).collect(),
+    }
+}
+
+async fn watched_addresses(ctx: &NodeContext) -> proto::WatchedAddresses {
+    proto::WatchedAddresses {
+        addresses: ctx.watchlist.addresses().await,
+    }
+}
+
+fn topic(topic: proto::Topic) -> Option<Topic> {
+    match topic {
+        proto::Topic::Unspecified => None,
+        proto::Topic::Blocks => Some(Topic::Blocks),
+        proto::Topic::Transactions => Some(Topic::Transactions),
+        proto::Topic::Reorgs => Some(Topic::Reorgs),
+    }
+}
+
+fn event(event: ChainEvent) -> proto::Event {
+    use proto::event::Event;
+    let event = match event {
+        ChainEvent::Block { height, hash, timestamp, transactions } => {
+            Event::Block(proto::BlockEvent {
+                height,
+                hash,
+                timestamp: timestamp.timestamp(),
+                transactions: transactions as u64,
+            })
+        }
+        ChainEvent::Transaction { hash, fee, size } => {
+            Event::Transaction(proto::TransactionEvent { hash, fee, size: size as u64 })
+        }
+        ChainEvent::Reorg { fork_height, old_tip, new_tip } => {
+            Event::Reorg(proto::ReorgEvent { fork_height, old_tip, new_tip })
+        }
+    };
+    proto::Event { event: Some(event) }
+}
+
+type EventStream = Pin<Box<dyn Stream<Item = Result<proto::Event, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl Node for NodeService {
+    async fn get_chain_info(
+        &self,
+        _request: Request<proto::ChainInfoRequest>,
+    ) -> Result<Response<proto::ChainInfo>, Status> {
+        let blockchain = self.ctx.blockchain.read().await;
+        Ok(Response::new(proto::ChainInfo {
+            network: blockchain.params().network.to_string(),
+            height: blockchain.block_height(),
+            tip_hash: blockchain.tip_hash().map(|hash| hash.to_string()).unwrap_or_default(),
+            lowest_block: blockchain.base_height(),
+            mempool_size: blockchain.mempool().len() as u64,
+            utxo_count: blockchain.utxos().len() as u64,
+            block_reward: blockchain.calculate_block_reward(),
+            total_supply: blockchain
+                .block_height()
+                .checked_sub(1)
+                .map_or(0, ChainParams::total_supply_at),
+            max_supply: ChainParams::max_supply(),
+            target: format!("{:x}", blockchain.target()),
+            difficulty: difficulty_from_target(blockchain.target()),
+            chainwork: format!("{:x}", blockchain.chain_work()),
+            utxo_set_hash: blockchain.utxo_set_hash().to_string(),
+        }))
+    }
+
+    async fn get_block(
+        &self,
+        request: Request<proto::GetBlockRequest>,
+    ) -> Result<Response<proto::Block>, Status> {
+        use proto::get_block_request::Block;
+        let blockchain = self.ctx.blockchain.read().await;
+        let height = match request.into_inner().block {
+            Some(Block::Height(height)) => height,
+            Some(Block::Hash(hash)) => {
+                let parsed = parse_hash(&hash)?;
+                match blockchain.height_of(&parsed) {
+                    Some(height) => height,
+                    None => {
+                        // blocks of branches the node doesn't follow
+                        let side = blockchain
+                            .side_blocks()
+                            .find(|side| side.block.hash() == parsed)
+                            .ok_or_else(|| Status::not_found(format!("no block {}", hash)))?;
+                        return Ok(Response::new(block(&blockchain, side.height, side.chain_work, &side.block)));
+                    }
+                }
+            }
+            None => return Err(Status::invalid_argument("a height or hash is required")),
+        };
+        let found = blockchain
+            .block_at(height)
+            .ok_or_else(|| Status::not_found(format!("no block at height {}", height)))?;
+        let chain_work = blockchain.chain_work_at(height).unwrap_or_default();
+        Ok(Response::new(block(&blockchain, height, chain_work, found)))
+    }
+
+    async fn get_chain_tips(
+        &self,
+        _request: Request<proto::ChainTipsRequest>,
+    ) -> Result<Response<proto::ChainTips>, Status> {
+        let blockchain = self.ctx.blockchain.read().await;
+        let tips = blockchain.chain_tips().into_iter().map(chain_tip).collect();
+        Ok(Response::new(proto::ChainTips { tips }))
+    }
+
+    async fn invalidate_block(
+        &self,
+        request: Request<proto::BlockHashRequest>,
+    ) -> Result<Response<proto::ChainChange>, Status> {
+        let hash = parse_hash(&request.into_inner().hash)?;
+        self.change_chain(Record::InvalidateBlock(hash), |blockchain| blockchain.invalidate_block(&hash))
+            .await
+    }
+
+    async fn reconsider_block(
+        &self,
+        request: Request<proto::BlockHashRequest>,
+    ) -> Result<Response<proto::ChainChange>, Status> {
+        let hash = parse_hash(&request.into_inner().hash)?;
+        self.change_chain(Record::ReconsiderBlock(hash), |blockchain| blockchain.reconsider_block(&hash))
+            .await
+    }
+
+    async fn get_transaction(
+        &self,
+        request: Request<proto::GetTransactionRequest>,
+    ) -> Result<Response<proto::TransactionInfo>, Status> {
+        let hash = parse_hash(&request.into_inner().hash)?;
+        let blockchain = self.ctx.blockchain.read().await;
+        let confirmed = blockchain.transaction_by_id(&hash).and_then(|(height, index)| {
+            Some((height, &blockchain.block_at(height)?.transactions[index]))
+        });
+        if let Some((height, tx)) = confirmed {
+            return Ok(Response::new(proto::TransactionInfo {
+                transaction: Some(transaction(&blockchain, tx)),
+                height: Some(height),
+                fee: None,
+            }));
+        }
+        let pending = blockchain
+            .mempool()
+            .iter()
+            .map(|(_, tx)| tx)
+            .find(|tx| tx.hash() == hash)
+            .ok_or_else(|| Status::not_found(format!("no transaction {}", hash)))?;
+        Ok(Response::new(proto::TransactionInfo {
+            transaction: Some(transaction(&blockchain, pending)),
+            height: None,
+            fee: blockchain.transaction_fee(pending),
+        }))
+    }
+
+    async fn get_tx_out_proof(
+        &self,
+        request: Request<proto::GetTxOutProofRequest>,
+    ) -> Result<Response<proto::TxOutProof>, Status> {
+        let request = request.into_inner();
+        let txid = parse_hash(&request.txid)?;
+        let blockchain = self.ctx.blockchain.read().await;
+        let height = match request.block_hash.is_empty() {
+            true => blockchain
+                .transaction_by_id(&txid)
+                .map(|(height, _)| height)
+                .ok_or_else(|| Status::not_found(format!("no confirmed transaction {}", txid)))?,
+            false => {
+                let hash = parse_hash(&request.block_hash)?;
+                blockchain
+                    .height_of(&hash)
+                    .ok_or_else(|| Status::not_found(format!("no block {} on the main chain", hash)))?
+            }
+        };
+        let block = blockchain
+            .block_at(height)
+            .ok_or_else(|| Status::not_found(format!("no block body at height {}", height)))?;
+        let proof = block
+            .transaction_proof(height, txid)
+            .ok_or_else(|| Status::not_found(format!("no transaction {} in the block at height {}", txid, height)))?;
+        Ok(Response::new(proto::TxOutProof {
+            proof: proof.to_bytes(),
+            txid: txid.to_string(),
+            block_hash: block.hash().to_string(),
+            height,
+            merkle_root: proof.header.merkle_root.to_string(),
+            index: proof.proof.index as u64,
+            branch: proof.proof.branch.iter().map(|hash| hash.to_string()).collect(),
+        }))
+    }
+
+    async fn verify_tx_out_proof(
+        &self,
+        request: Request<proto::VerifyTxOutProofRequest>,
+    ) -> Result<Response<proto::TxOutProofVerification>, Status> {
+        let proof = types::TransactionProof::from_bytes(&request.into_inner().proof)
+            .map_err(|_| Status::invalid_argument("malformed proof"))?;
+        if !proof.verify() {
+            return Err(Status::invalid_argument(format!(
+                "the merkle branch doesn't lead from {} to the header's merkle root",
+                proof.txid
+            )));
+        }
+        // proofs carry the header, blocks are looked up by the hash
+        // of the whole block
+        let header_hash = proof.header_hash();
+        let blockchain = self.ctx.blockchain.read().await;
+        let block_hash = match blockchain.block_at(proof.height) {
+            Some(block) => (block.header.hash() == header_hash).then(|| block.hash()),
+            None if proof.height < blockchain.base_height() => self
+                .ctx
+                .db
+                .get_pruned_header(proof.height)
+                .map_err(|e| Status::internal(e.to_string()))?
+                .and_then(|(header, hash)| (header.hash() == header_hash).then_some(hash)),
+            None => None,
+        };
+        let Some(block_hash) = block_hash else {
+            return Err(Status::not_found(format!(
+                "the proven block is not at height {} of the main chain",
+                proof.height
+            )));
+        };
+        Ok(Response::new(proto::TxOutProofVerification {
+            txid: proof.txid.to_string(),
+            block_hash: block_hash.to_string(),
+            height: proof.height,
+            confirmations: blockchain.confirmations(proof.height),
+        }))
+    }
+
+    async fn get_utxos(
+        &self,
+        request: Request<proto::GetUtxosRequest>,
+    ) -> Result<Response<proto::UtxoList>, Status> {
+        let request = request.into_inner();
+        let blockchain = self.ctx.blockchain.read().await;
+        let utxos = blockchain
+            .utxos()
+            .values()
+            .filter(|(_, height, txout)| {
+                Address::same(&txout.address, &request.address)
+                    && blockchain.confirmations(*height) >= request.min_confirmations
+            })
+            .map(|(marked, height, txout)| proto::Utxo {
+                output: Some(output(txout)),
+                height: *height,
+                marked: *marked,
+            })
+            .collect();
+        Ok(Response::new(proto::UtxoList { utxos }))
+    }
+
+    async fn verify_utxo_set(
+        &self,
+        request: Request<proto::VerifyUtxoSetRequest>,
+    ) -> Result<Response<proto::UtxoSetVerification>, Status> {
+        let expected = request.into_inner().expected;
+        let expected = match expected.is_empty() {
+            true => None,
+            false => Some(parse_hash(&expected)?),
+        };
+        let blockchain = self.ctx.blockchain.read().await;
+        let kept = blockchain.utxo_set_hash();
+        let computed = blockchain.compute_utxo_set_hash();
+        if kept != computed {
+            warn!("UTXO set hash {} doesn't match {} computed from the UTXO set", kept, computed);
+        }
+        Ok(Response::new(proto::UtxoSetVerification {
+            height: blockchain.block_height(),
+            tip_hash: blockchain.tip_hash().map(|hash| hash.to_string()).unwrap_or_default(),
+            utxo_count: blockchain.utxos().len() as u64,
+            utxo_set_hash: kept.to_string(),
+            computed_hash: computed.to_string(),
+            consistent: kept == computed,
+            matches_expected: expected.map(|expected| expected == kept),
+        }))
+    }
+
+    async fn watch_address(
+        &self,
+        request: Request<proto::WatchRequest>,
+    ) -> Result<Response<proto::WatchedAddresses>, Status> {
+        let address = request.into_inner().address;
+        {
+            let blockchain = self.ctx.blockchain.read().await;
+            Address::parse_for(&address, blockchain.params())
+                .map_err(|e| Status::invalid_argument(format!("can't watch {}: {}", address, e)))?;
+        }
+        watch::watch(&self.ctx, &address)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        Ok(Response::new(watched_addresses(&self.ctx).await))
+    }
+
+    async fn unwatch_address(
+        &self,
+        request: Request<proto::WatchRequest>,
+    ) -> Result<Response<proto::WatchedAddresses>, Status> {
+        let address = request.into_inner().address;
+        let unwatched = watch::unwatch(&self.ctx, &address)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        if !unwatched {
+            return Err(Status::not_found(format!("{} is not watched", address)));
+        }
+        Ok(Response::new(watched_addresses(&self.ctx).await))
+    }
+
+    async fn get_watched_addresses(
+        &self,
+        _request: Request<proto::WatchedAddressesRequest>,
+    ) -> Result<Response<proto::WatchedAddresses>, Status> {
+        Ok(Response::new(watched_addresses(&self.ctx).await))
+    }
+
+    async fn get_history(
+        &self,
+        request: Request<proto::GetHistoryRequest>,
+    ) -> Result<Response<proto::History>, Status> {
+        let address = request.into_inner().address;
+        let history = watch::history(&self.ctx, &address)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .ok_or_else(|| Status::failed_precondition(format!("{} is not watched, see WatchAddress", address)))?;
+        let entries = history
+            .into_iter()
+            .map(|entry| proto::HistoryEntry {
+                txid: entry.txid.to_string(),
+                height: entry.height,
+                block_hash: entry.block.to_string(),
+                received: entry.received,
+                sent: entry.sent,
+            })
+            .collect();
+        Ok(Response::new(proto::History { entries }))
+    }
+
+    async fn get_reservations(
+        &self,
+        _request: Request<proto::ReservationsRequest>,
+    ) -> Result<Response<proto::ReservationList>, Status> {
+        let blockchain = self.ctx.blockchain.read().await;
+        let mut reservations: Vec<proto::Reservation> = blockchain
+            .reservations()
+            .iter()
+            .filter_map(|(hash, reservation)| {
+                let (_, _, txout) = blockchain.utxos().get(hash)?;
+                Some(proto::Reservation {
+                    output: Some(output(txout)),
+                    txid: reservation.txid.to_string(),
+                    expires: reservation.expires.timestamp(),
+                })
+            })
+            .collect();
+        reservations.sort_by(|a, b| a.expires.cmp(&b.expires).then_with(|| a.txid.cmp(&b.txid)));
+        Ok(Response::new(proto::ReservationList { reservations }))
+    }
+
+    async fn get_mempool_info(
+        &self,
+        _request: Request<proto::MempoolInfoRequest>,
+    ) -> Result<Response<proto::MempoolInfo>, Status> {
+        let blockchain = self.ctx.blockchain.read().await;
+        let entries = mempool_entries(&blockchain, Utc::now());
+        Ok(Response::new(mempool_info(&entries)))
+    }
+
+    async fn get_raw_mempool(
+        &self,
+        request: Request<proto::RawMempoolRequest>,
+    ) -> Result<Response<proto::RawMempool>, Status> {
+        let blockchain = self.ctx.blockchain.read().await;
+        let entries = mempool_entries(&blockchain, Utc::now());
+        let mempool = match request.into_inner().verbose {
+            true => proto::RawMempool {
+                txids: vec![],
+                entries,
+            },
+            false => proto::RawMempool {
+                txids: entries.into_iter().map(|entry| entry.txid).collect(),
+                entries: vec![],
+            },
+        };
+        Ok(Response::new(mempool))
+    }
+
+    async fn clear_mempool(
+        &self,
+        _request: Request<proto::ClearMempoolRequest>,
+    ) -> Result<Response<proto::ClearMempoolResponse>, Status> {
+        let mut blockchain = self.ctx.blockchain.write().await;
+        self.ctx.record(Record::ClearMempool);
+        let (transactions, unmarked) = blockchain.clear_mempool();
+        drop(blockchain);
+        info!("cleared {} transactions from the mempool over gRPC", transactions);
+        self.ctx
+            .mempool_stats
+            .cleared
+            .fetch_add(transactions as u64, std::sync::atomic::Ordering::Relaxed);
+        Ok(Response::new(proto::ClearMempoolResponse {
+            transactions: transactions as u64,
+            unmarked: unmarked as u64,
+        }))
+    }
+
+    async fn submit_transaction(
+        &self,
+        request: Request<proto::SubmitTransactionRequest>,
+    ) -> Result<Response<proto::SubmitTransactionResponse>, Status> {
+        let tx = types::Transaction::from_bytes(&request.into_inner().transaction)
+            .map_err(|e| Status::invalid_argument(format!("invalid transaction: {}", e)))?;
+        let hash = tx.hash();
+        self.ctx.record(Record::Message {
+            peer: "grpc".to_string(),
+            envelope: Envelope::new(
+                self.ctx.network.self_id.clone(),
+                DEFAULT_TTL,
+                Message::SubmitTransaction(tx.clone()),
+            ),
+        });
+        handler::accept_transaction(&self.ctx, &tx)
+            .await
+            .map_err(|e| Status::failed_precondition(format!("transaction rejected: {}", e)))?;
+        info!("added transaction {} submitted over gRPC", hash);
+        let gossip = Envelope::new(
+            self.ctx.network.self_id.clone(),
+            DEFAULT_TTL,
+            Message::NewTransaction(tx),
+        );
+        handler::broadcast_except(&self.ctx, None, gossip);
+        Ok(Response::new(proto::SubmitTransactionResponse { hash: hash.to_string() }))
+    }
+
+    type SubscribeStream = EventStream;
+
+    async fn subscribe(
+        &self,
+        request: Request<proto::SubscribeRequest>,
+    ) -> Result<Response<EventStream>, Status> {
+        let mut topics: HashSet<Topic> = request
+            .into_inner()
+            .topics()
+            .filter_map(topic)
+            .collect();
+        if topics.is_empty() {
+            topics.extend(Topic::ALL);
+        }
+        // subscribers falling behind skip the events they missed
+        let events = BroadcastStream::new(self.ctx.events.subscribe())
+            .filter_map(Result::ok)
+            .filter(move |chain_event| topics.contains(&chain_event.topic()))
+            .map(|chain_event| Ok(event(chain_event)));
+        Ok(Response::new(Box::pin(events)))
+    }
+
+    async fn get_peer_info(
+        &self,
+        _request: Request<proto::PeerInfoRequest>,
+    ) -> Result<Response<proto::PeerInfo>, Status> {
+        Ok(Response::new(peer_info(&self.ctx)))
+    }
+
+    async fn add_node(&self, request: Request<proto::AddNodeRequest>) -> Result<Response<proto::PeerInfo>, Status> {
+        use proto::AddNodeCommand;
+        let request = request.into_inner();
+        let command = request.command();
+        let address = request.address;
+        let resolved: Vec<SocketAddr> = match lookup_host(&address).await {
+            Ok(resolved) => resolved.collect(),
+            Err(e) => return Err(Status::invalid_argument(format!("{address} doesn't resolve: {e}"))),
+        };
+        let connected = self.ctx.network.dialed_at(&resolved);
+        match command {
+            AddNodeCommand::Unspecified => {
+                return Err(Status::invalid_argument("a command is required"));
+            }
+            AddNodeCommand::Remove => {
+                let forgotten = self.ctx.addresses.remove(&address);
+                if !forgotten && connected.is_empty() {
+                    return Err(Status::not_found(format!("{address} was not added")));
+                }
+                for peer_id in connected {
+                    self.ctx.network.disconnect(&peer_id);
+                }
+                info!("removed node {} over gRPC", address);
+            }
+            command => {
+                if let Some(addr) = resolved.iter().find(|addr| !self.ctx.network.admits(addr.ip())) {
+                    return Err(Status::permission_denied(format!("{} is denied by the access list", addr.ip())));
+                }
+                let add = command == AddNodeCommand::Add;
+                if add && !self.ctx.addresses.add(address.clone()) && !connected.is_empty() {
+                    return Err(Status::already_exists(format!("{address} was added already")));
+                }
+                if connected.is_empty() {
+                    util::connect_peer(&self.ctx, &address)
+                        .await
+                        .map_err(|e| Status::unavailable(format!("{e:#}")))?;
+                }
+                info!("{} node {} over gRPC", if add { "added" } else { "connected to" }, address);
+            }
+        }
+        Ok(Response::new(peer_info(&self.ctx)))
+    }
+
+    async fn disconnect_node(
+        &self,
+        request: Request<proto::DisconnectNodeRequest>,
+    ) -> Result<Response<proto::PeerInfo>, Status> {
+        let id = request.into_inner().id;
+        if !self.ctx.network.disconnect(&id) {
+            return Err(Status::not_found(format!("no peer {}", id)));
+        }
+        info!("disconnected peer {} over gRPC", id);
+        Ok(Response::new(peer_info(&self.ctx)))
+    }
+
+    async fn get_access_list(
+        &self,
+        _request: Request<proto::AccessListRequest>,
+    ) -> Result<Response<proto::AccessList>, Status> {
+        let access = self.ctx.network.access.read().expect("access list lock");
+        Ok(Response::new(access_list(&access)))
+    }
+
+    async fn add_access_rule(
+        &self,
+        request: Request<proto::AccessRule>,
+    ) -> Result<Response<proto::AccessList>, Status> {
+        let rule = request.into_inner();
+        let net = parse_net(&rule.range).map_err(Status::invalid_argument)?;
+        if self.ctx.network.restrict(net, rule.deny) {
+            info!("{} {} over gRPC", if rule.deny { "denied" } else { "allowed" }, net);
+        }
+        let access = self.ctx.network.access.read().expect("access list lock");
+        Ok(Response::new(access_list(&access)))
+    }
+
+    async fn remove_access_rule(
+        &self,
+        request: Request<proto::AccessRule>,
+    ) -> Result<Response<proto::AccessList>, Status> {
+        let rule = request.into_inner();
+        let net = parse_net(&rule.range).map_err(Status::invalid_argument)?;
+        if !self.ctx.network.unrestrict(net, rule.deny) {
+            return Err(Status::not_found(format!("{} is not in the list", net)));
+        }
+        info!("removed access rule for {} over gRPC", net);
+        let access = self.ctx.network.access.read().expect("access list lock");
+        Ok(Response::new(access_list(&access)))
+    }
+
+    async fn backup(&self, request: Request<proto::BackupRequest>) -> Result<Response<proto::BackupResponse>, Status> {
+        let dest = PathBuf::from(request.into_inner().path);
+        if !dest.is_absolute() {
+            return Err(Status::invalid_argument("the backup path must be absolute"));
+        }
+        let report = util::backup(&self.ctx, dest)
+            .await
+            .map_err(|e| Status::internal(format!("{e:#}")))?;
+        Ok(Response::new(proto::BackupResponse {
+            records: report.records,
+            height: report.height,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_events_convert() {
+        let converted = event(ChainEvent::Transaction {
+            hash: "ab".to_string(),
+            fee: Some(10),
+            size: 200,
+        });
+        assert_eq!(
+            converted.event,
+            Some(proto::event::Event::Transaction(proto::TransactionEvent {
+                hash: "ab".to_string(),
+                fee: Some(10),
+                size: 200,
+            }))
+        );
+        assert_eq!(topic(proto::Topic::Unspecified), None);
+        assert_eq!(topic(proto::Topic::Reorgs), Some(Topic::Reorgs));
+    }
+
+    #[test]
+    fn test_mempool_entries() {
+        use btclib::crypto::{PrivateKey, Signature};
+        use btclib::types::{Block, BlockHeader, Transaction, TransactionInput, TransactionOutput};
+        use btclib::util::MerkleRoot;
+        use uuid::Uuid;
+
+        let key = PrivateKey::new_key();
+        let output = |value| TransactionOutput {
+            value,
+            unique_id: Uuid::new_v4(),
+            address: key.public_key().to_address(),
+        };
+        let spend = |prev: &TransactionOutput, value| {
+            let hash = prev.hash();
+            Transaction::new(
+                vec![TransactionInput {
+                    prev_transaction_output_hash: hash,
+                    public_key: key.public_key(),
+                    signature: Signature::sign_output(&hash, &key),
+                    multisig: None,
+                    cosignatures: vec![],
+                }],
+                vec![output(value)],
+            )
+        };
+        let coinbase = output(100_000);
+        let transactions = vec![Transaction::new(vec![], vec![coinbase.clone()])];
+        let mut blockchain = Blockchain::new();
+        blockchain
+            .add_block(Block::new(
+                BlockHeader::new(
+                    Utc::now(),
+                    0,
+                    Hash::zero(),
+                    MerkleRoot::calculate(&transactions),
+                    btclib::MIN_TARGET,
+                ),
+                transactions,
+            ))
+            .unwrap();
+        blockchain.rebuild_utxos();
+        let parent = spend(&coinbase, 99_990);
+        let child = spend(&parent.outputs[0], 99_000);
+        let now = Utc::now();
+        blockchain
+            .add_to_mempool_at(parent.clone(), now - chrono::Duration::seconds(60))
+            .unwrap();
+        blockchain.add_to_mempool_at(child.clone(), now).unwrap();
+
+        let entries = mempool_entries(&blockchain, now);
+        let txids: Vec<&str> = entries.iter().map(|entry| entry.txid.as_str()).collect();
+        assert_eq!(txids, [child.hash().to_string(), parent.hash().to_string()]);
+        let (child_entry, parent_entry) = (&entries[0], &entries[1]);
+        assert_eq!(child_entry.fee, 990);
+        assert_eq!(child_entry.ancestors, [parent.hash().to_string()]);
+        assert_eq!(child_entry.ancestor_fee, 1000);
+        assert_eq!(child_entry.ancestor_size, (parent.size() + child.size()) as u64);
+        assert_eq!(parent_entry.descendants, [child.hash().to_string()]);
+        assert_eq!(parent_entry.age_seconds, 60);
+
+        let info = mempool_info(&entries);
+        assert_eq!((info.size, info.total_fee), (2, 1000));
+        assert_eq!(info.histogram.len(), FEE_RATE_BUCKETS.len());
+        // the parent pays 10 over some 180 bytes, the child 990
+        let counted: Vec<(f64, u64)> = info
+            .histogram
+            .iter()
+            .filter(|bucket| bucket.count > 0)
+            .map(|bucket| (bucket.min_fee_rate, bucket.count))
+            .collect();
+        assert_eq!(counted, [(0.05, 1), (5.0, 1)]);
+        assert_eq!(info.min_fee_rate, parent_entry.fee_rate);
+        assert_eq!(info.max_fee_rate, child_entry.fee_rate);
+        assert_eq!(mempool_info(&[]).max_fee_rate, 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_tx_out_proof_round_trip() {
+        use crate::database::BlockchainDB;
+        use btclib::crypto::PrivateKey;
+        use tonic::Code;
+
+        let chain = btclib::testing::build_chain(&PrivateKey::new_key(), &[vec![(0, 2, 10)], vec![]]);
+        let ctx = NodeContext::from_db(BlockchainDB::temporary().unwrap(), ChainParams::default(), false).unwrap();
+        for (height, block) in chain.blocks().enumerate() {
+            ctx.db.put_block(height as u64, block).unwrap();
+        }
+        let block = chain.block_at(1).unwrap().clone();
+        let txid = block.transactions[1].hash().to_string();
+        *ctx.blockchain.write().await = chain;
+        let service = NodeService { ctx };
+        let get_proof = |block_hash: String| {
+            service.get_tx_out_proof(Request::new(proto::GetTxOutProofRequest {
+                txid: txid.clone(),
+                block_hash,
+            }))
+        };
+        let verify = |proof: Vec<u8>| service.verify_tx_out_proof(Request::new(proto::VerifyTxOutProofRequest { proof }));
+
+        let proof = get_proof(String::new()).await.unwrap().into_inner();
+        assert_eq!(proof.block_hash, block.hash().to_string());
+        // the returned hash is the one blocks are looked up by
+        assert_eq!(get_proof(proof.block_hash.clone()).await.unwrap().into_inner().proof, proof.proof);
+        let verified = verify(proof.proof.clone()).await.unwrap().into_inner();
+        assert_eq!((verified.block_hash.as_str(), verified.height), (proof.block_hash.as_str(), 1));
+        assert_eq!(verified.confirmations, 2);
+
+        // a header that isn't the one at the height isn't on the chain
+        let mut other = types::TransactionProof::from_bytes(&proof.proof).unwrap();
+        other.header.nonce += 1;
+        assert_eq!(verify(other.to_bytes()).await.unwrap_err().code(), Code::NotFound);
+
+        // pruned blocks are checked against their stored headers
+        service.ctx.blockchain.write().await.prune(2).unwrap();
+        service.ctx.db.prune_blocks(2).unwrap();
+        let verified = verify(proof.proof.clone()).await.unwrap().into_inner();
+        assert_eq!((verified.block_hash.as_str(), verified.height), (proof.block_hash.as_str(), 1));
+        assert_eq!(verify(other.to_bytes()).await.unwrap_err().code(), Code::NotFound);
+        assert_eq!(get_proof(String::new()).await.unwrap_err().code(), Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_manage_peers() {
+        use crate::database::BlockchainDB;
+        use proto::AddNodeCommand;
+        use tonic::Code;
+
+        let context = || NodeContext::from_db(BlockchainDB::temporary().unwrap(), ChainParams::testnet(), false).unwrap();
+        let (service, other) = (NodeService { ctx: context() }, context());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            while let Ok((socket, peer_addr)) = listener.accept().await {
+                handler::accept_peer(other.clone(), socket, peer_addr, false, None).await.unwrap();
+            }
+        });
+        let add_node = |command: AddNodeCommand| {
+            let mut request = proto::AddNodeRequest {
+                address: address.clone(),
+                ..Default::default()
+            };
+            request.set_command(command);
+            service.add_node(Request::new(request))
+        };
+
+        let peers = add_node(AddNodeCommand::Onetry).await.unwrap().into_inner().peers;
+        assert_eq!(peers.len(), 1);
+        assert!(!peers[0].inbound);
+        assert!(service.ctx.addresses.addresses().is_empty());
+        let disconnect = |id: String| service.disconnect_node(Request::new(proto::DisconnectNodeRequest { id }));
+        assert!(disconnect(peers[0].id.clone()).await.unwrap().into_inner().peers.is_empty());
+        assert_eq!(disconnect(peers[0].id.clone()).await.unwrap_err().code(), Code::NotFound);
+
+        assert_eq!(add_node(AddNodeCommand::Add).await.unwrap().into_inner().peers.len(), 1);
+        assert_eq!(service.ctx.addresses.addresses(), [address.as_str()]);
+        assert_eq!(add_node(AddNodeCommand::Add).await.unwrap_err().code(), Code::AlreadyExists);
+        assert!(add_node(AddNodeCommand::Remove).await.unwrap().into_inner().peers.is_empty());
+        assert_eq!(add_node(AddNodeCommand::Remove).await.unwrap_err().code(), Code::NotFound);
+        assert_eq!(add_node(AddNodeCommand::Unspecified).await.unwrap_err().code(), Code::InvalidArgument);
+
+        service.ctx.network.restrict("127.0.0.0/8".parse().unwrap(), true);
+        assert_eq!(add_node(AddNodeCommand::Onetry).await.unwrap_err().code(), Code::PermissionDenied);
+    }
+}
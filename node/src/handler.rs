@@ -1,66 +1,184 @@
 use crate::context::NodeContext;
-use crate::network::{PeerHandle, PeerId};
+use crate::journal::Record;
+use crate::network::{MISBEHAVIOR_THRESHOLD, PeerHandle, PeerId, PeerOutbox};
+use crate::relay::PendingBlock;
 use anyhow::Result;
-use btclib::network::{Envelope, Message};
+use btclib::address::Address;
+use btclib::error::BtcError;
+use btclib::events::ChainEvent;
+use btclib::network::{
+    COMPACT_PROTOCOL_VERSION, CompactBlock, Envelope, Message, PROTOCOL_VERSION, Services,
+    VersionInfo, WireFormat,
+};
+use btclib::params::SignedCheckpoint;
 use btclib::sha256::Hash;
-use btclib::types::{Block, BlockHeader, Blockchain, Transaction, TransactionOutput};
+use btclib::types::{Block, BlockHeader, Blockchain, ChainUpdate, Transaction, TransactionOutput};
 use btclib::util::MerkleRoot;
 use chrono::Utc;
 use tokio::net::TcpStream;
-use tokio::sync::mpsc;
+use tokio::sync::{OwnedSemaphorePermit, mpsc};
 use tracing::{debug, error, info, warn};
 use uuid::Uuid;
+use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
-const DEFAULT_TTL: u8 = 8;
+pub const DEFAULT_TTL: u8 = 8;
 const OUTBOUND_BUFFER: usize = 256;
+// upper bound on the addresses a seed node lists at once
+const MAX_NODE_LIST: usize = 64;
+// upper bound on the blocks sent in reply to a single FetchBlocks
+const MAX_FETCH_BLOCKS: u64 = 64;
+// and on the headers sent in reply to a single FetchHeaders
+const MAX_FETCH_HEADERS: u64 = 2000;
+// and on the addresses looked up for a single FetchAddressUsage
+const MAX_ADDRESS_USAGE: usize = 1000;
+// main chain blocks searched for the one a peer asks transactions of,
+// besides the blocks of other branches
+const RECENT_RELAYED_BLOCKS: u64 = 16;
 
 fn get_last_block_hash(blockchain: &Blockchain) -> Hash {
-    blockchain
-        .blocks()
-        .last()
-        .map(|last_block| last_block.hash())
-        .unwrap_or(Hash::zero())
+    blockchain.tip_hash().unwrap_or(Hash::zero())
 }
 
+fn version_info(
+    blockchain: &Blockchain,
+    mut services: Services,
+    challenge: Option<[u8; 32]>,
+) -> VersionInfo {
+    // a node started from a snapshot has them once it backfilled
+    if blockchain.base_height() == 0 && !services.contains(Services::PRUNED) {
+        services |= Services::FULL_BLOCKS;
+    }
+    VersionInfo {
+        protocol_version: PROTOCOL_VERSION,
+        height: blockchain.block_height(),
+        // blocks below the base are pruned or not backfilled yet
+        lowest_block: blockchain.base_height(),
+        services,
+        challenge,
+        utxo_set_hash: Some(blockchain.utxo_set_hash()),
+    }
+}
+
+async fn send_version(ctx: &NodeContext, peer_id: &str) {
+    let challenge = ctx.network.challenge(peer_id);
+    let mut version = version_info(&*ctx.blockchain.read().await, ctx.services, challenge);
+    if !ctx.network.takes_utxo_hash(peer_id) {
+        version.utxo_set_hash = None;
+    }
+    let env = Envelope::new(
+        ctx.network.self_id.clone(),
+        DEFAULT_TTL,
+        Message::Version(version),
+    );
+    ctx.network.mark_version_sent(peer_id);
+    ctx.network.send_to(peer_id, env);
+}
+
+/// Answer a peer's request
+fn reply(ctx: &NodeContext, peer_id: &str, msg: Message) {
+    let env = Envelope::new(ctx.network.self_id.clone(), DEFAULT_TTL, msg);
+    ctx.network.send_to(peer_id, env);
+}
+
+/// Register a peer, whatever carries its messages. Connections we
+/// opened ourselves go to other nodes, so they start with the version
+/// handshake; inbound ones may be wallets or miners, which never take
+/// part in it. Messages from the peer go through `ctx.network.deliver`
+/// tagged with `peer_id`, messages to it come out of the returned
+/// outbox.
+pub async fn attach_peer(ctx: &NodeContext, peer_id: PeerId, outbound: bool) -> PeerOutbox {
+    attach_peer_at(ctx, peer_id, None, outbound).await
+}
+
+async fn attach_peer_at(
+    ctx: &NodeContext,
+    peer_id: PeerId,
+    addr: Option<SocketAddr>,
+    outbound: bool,
+) -> PeerOutbox {
+    let (out_tx, out_rx) = mpsc::channel::<Envelope>(OUTBOUND_BUFFER);
+    // CBOR until the handshake shows the peer understands more, wallets
+    // and miners never leave it
+    let compact = Arc::new(AtomicBool::new(false));
+    let mut handle = PeerHandle::new(out_tx, compact.clone());
+    handle.addr = addr;
+    handle.dialed = outbound;
+    ctx.network.peers.insert(peer_id.clone(), handle);
+    if outbound {
+        send_version(ctx, &peer_id).await;
+    }
+    PeerOutbox {
+        messages: out_rx,
+        compact,
+    }
+}
+
+/// Start serving a TCP connection, see `attach_peer`. `slot`, if any,
+/// is held until the connection closes.
 pub async fn accept_peer(
     ctx: NodeContext,
     socket: TcpStream,
     peer_addr: SocketAddr,
+    outbound: bool,
+    slot: Option<OwnedSemaphorePermit>,
 ) -> Result<()> {
     let peer_id = peer_addr.to_string();
     let (mut rd, mut wr) = socket.into_split();
-
-    let (out_tx, mut out_rx) = mpsc::channel::<Envelope>(OUTBOUND_BUFFER);
-    ctx.network
-        .peers
-        .insert(peer_id.clone(), PeerHandle { outbound: out_tx });
-
-    let writer = tokio::spawn(async move {
-        while let Some(env) = out_rx.recv().await {
-            if env.send_async(&mut wr).await.is_err() {
-                break;
-            }
-        }
-    });
+    let PeerOutbox {
+        messages: mut out_rx,
+        compact,
+    } = attach_peer_at(&ctx, peer_id.clone(), Some(peer_addr), outbound).await;
 
     let network = ctx.network.clone();
+    let reader_peer = peer_id.clone();
     let reader = tokio::spawn(async move {
         loop {
-            match Envelope::receive_async(&mut rd).await {
-                Ok(env) => {
-                    // // if inbound is full, this will await: backpressure by design
-                    if network.inbound_tx.send((peer_id.clone(), env)).await.is_err() {
+            match Envelope::receive_async_sized(&mut rd).await {
+                Ok((env, bytes)) => {
+                    network.record_traffic(&reader_peer, env.msg.kind(), bytes, false);
+                    // waits while the peer's inbound slots are taken,
+                    // which stops reading from it: backpressure by design
+                    if !network.deliver(&reader_peer, env).await {
                         break;
                     }
-                },
-                Err(_) => break,
+                }
+                Err(ciborium::de::Error::Io(e)) => {
+                    debug!("connection to {reader_peer} closed: {e}");
+                    break;
+                }
+                Err(e) => {
+                    network.misbehaving(&reader_peer, MISBEHAVIOR_THRESHOLD, &format!("undecodable message: {e}"));
+                    break;
+                }
             }
         }
+        network.disconnect(&reader_peer);
     });
 
-    // detach; cleanup could be improved later
-    let _ = (writer, reader);
+    // ends when the peer is disconnected, which drops its queue, or
+    // when writing fails; either way the reader goes with it
+    let network = ctx.network.clone();
+    tokio::spawn(async move {
+        while let Some(env) = out_rx.recv().await {
+            let format = if compact.load(Ordering::Relaxed) {
+                WireFormat::Compact
+            } else {
+                WireFormat::Cbor
+            };
+            match env.send_async_as(&mut wr, format).await {
+                Ok(bytes) => network.record_traffic(&peer_id, env.msg.kind(), bytes, true),
+                Err(e) => {
+                    network.send_failed(&peer_id, &e.to_string());
+                    break;
+                }
+            }
+        }
+        reader.abort();
+        drop(slot);
+    });
     Ok(())
 }
 
@@ -79,124 +197,332 @@ pub async fn dispatcher_loop(ctx: NodeContext) -> Result<()> {
             continue;
         }
 
+        // relays ignored below never reach validation
+        let ignored = matches!(env.msg, Message::NewBlock(_) | Message::CompactBlock(_) | Message::NewTransaction(_))
+            && !ctx.network.admitted(&from_peer);
+        if ctx.journal.is_some() && !ignored {
+            ctx.record(Record::Message {
+                peer: from_peer.clone(),
+                envelope: env.clone(),
+            });
+        }
+
         let mut should_gossip = false;
 
         match &env.msg {
             Message::UTXOs(_)
             | Message::Template(_)
-            | Message::Difference(_)
             | Message::TemplateValidity(_)
             | Message::NodeList(_)
-            | Message::AllBlocks(_) => {
+            | Message::AllBlocks(_)
+            | Message::TransactionInfo(..)
+            | Message::Headers(..)
+            | Message::AddressUsage(_)
+            | Message::TransactionProof(..) => {
                 info!("unexpected inbound response for node role, ignoring");
             }
-            Message::FetchBlock(height) => {
-                let blockchain = ctx.blockchain.read().await;
-                if let Some(block) = blockchain.blocks().nth(*height as usize).cloned() {
-                    let reply = Envelope::new(
-                        ctx.network.self_id.clone(),
-                        DEFAULT_TTL,
-                        Message::NewBlock(block),
+            Message::NewBlock(_) | Message::CompactBlock(_) | Message::NewTransaction(_)
+                if !ctx.network.admitted(&from_peer) =>
+            {
+                debug!("ignoring relay from {from_peer}, not a trusted peer");
+            }
+            Message::Version(version) => {
+                debug!(
+                    "peer {} is at height {}, serving blocks from {}, offering {}",
+                    from_peer, version.height, version.lowest_block, version.services
+                );
+                if version.protocol_version > PROTOCOL_VERSION {
+                    warn!(
+                        "peer {} speaks protocol version {}, newer than ours ({})",
+                        from_peer, version.protocol_version, PROTOCOL_VERSION
+                    );
+                } else if version.protocol_version < COMPACT_PROTOCOL_VERSION {
+                    debug!(
+                        "peer {} speaks protocol version {}, sending it CBOR messages",
+                        from_peer, version.protocol_version
                     );
-                    ctx.network.send_to(&from_peer, reply).await;
                 }
+                if let Some(theirs) = version.utxo_set_hash {
+                    let blockchain = ctx.blockchain.read().await;
+                    let ours = blockchain.utxo_set_hash();
+                    if version.height == blockchain.block_height() && theirs != ours {
+                        warn!(
+                            "peer {} has UTXO set hash {} at height {}, ours is {}: it follows another branch or its state diverged",
+                            from_peer, theirs, version.height, ours
+                        );
+                    }
+                }
+                let first = ctx.network.record_version(&from_peer, version.clone());
+                if first {
+                    send_version(&ctx, &from_peer).await;
+                }
+                // wallets probing us send no challenge
+                if let Some(challenge) = &version.challenge {
+                    let (key, signature) = ctx.network.prove_identity(challenge);
+                    reply(&ctx, &from_peer, Message::Identity(key, signature));
+                    // nodes joining late still get the checkpoints
+                    if first {
+                        let checkpoints = ctx.blockchain.read().await.signed_checkpoints().to_vec();
+                        for signed in checkpoints {
+                            reply(&ctx, &from_peer, Message::Checkpoint(signed));
+                        }
+                    }
+                }
+            }
+            Message::Identity(key, signature) => {
+                match ctx.network.authenticate(&from_peer, key.clone(), signature) {
+                    Some(true) => info!("peer {from_peer} is trusted"),
+                    Some(false) if ctx.network.identity.trusted_only => {
+                        info!("peer {from_peer} isn't trusted, disconnecting");
+                        ctx.network.disconnect(&from_peer);
+                    }
+                    Some(false) => debug!("peer {from_peer} proved its identity"),
+                    None => {
+                        ctx.network
+                            .misbehaving(&from_peer, MISBEHAVIOR_THRESHOLD, "bad identity signature");
+                    }
+                }
+            }
+            Message::FetchBlock(height) => {
+                let block = ctx.blockchain.read().await.block_at(*height as u64).cloned();
+                if let Some(block) = block {
+                    reply(&ctx, &from_peer, Message::NewBlock(block));
+                }
+            }
+            Message::FetchBlockByHash(hash) => {
+                let block = {
+                    let blockchain = ctx.blockchain.read().await;
+                    // blocks of other branches too, a peer may be following one
+                    blockchain.block_by_hash(hash).cloned().or_else(|| {
+                        blockchain.side_blocks().find(|side| side.block.hash() == *hash).map(|side| side.block.clone())
+                    })
+                };
+                if let Some(block) = block {
+                    reply(&ctx, &from_peer, Message::NewBlock(block));
+                }
+            }
+            Message::FetchTransaction(_)
+            | Message::FetchTransactionProof(_)
+            | Message::FetchUTXOs(..)
+            | Message::FetchAddressUsage(_)
+                if !ctx.services.contains(Services::WALLET) =>
+            {
+                debug!("not answering wallet query from {from_peer}, not offered");
+            }
+            Message::FetchTemplate(_) if !ctx.services.contains(Services::MINING) => {
+                debug!("not building a template for {from_peer}, not offered");
+            }
+            Message::FetchTransaction(hash) => {
+                let found = {
+                    let blockchain = ctx.blockchain.read().await;
+                    blockchain.transaction_by_id(hash).and_then(|(height, position)| {
+                        let transaction = blockchain.block_at(height)?.transactions.get(position)?;
+                        Some((height, transaction.clone()))
+                    })
+                };
+                reply(&ctx, &from_peer, Message::TransactionInfo(*hash, found));
+            }
+            Message::FetchTransactionProof(hash) => {
+                let proof = {
+                    let blockchain = ctx.blockchain.read().await;
+                    blockchain.transaction_by_id(hash).and_then(|(height, _)| {
+                        blockchain.block_at(height)?.transaction_proof(height, *hash)
+                    })
+                };
+                reply(&ctx, &from_peer, Message::TransactionProof(*hash, proof));
+            }
+            Message::FetchAddressUsage(addresses) => {
+                let addresses = &addresses[..addresses.len().min(MAX_ADDRESS_USAGE)];
+                let used = ctx.blockchain.read().await.addresses_used(addresses);
+                reply(&ctx, &from_peer, Message::AddressUsage(used));
+            }
+            Message::FetchBlocks(start, count) => {
+                let blocks: Vec<Block> = {
+                    let blockchain = ctx.blockchain.read().await;
+                    (*start..start.saturating_add((*count).min(MAX_FETCH_BLOCKS)))
+                        .map_while(|height| blockchain.block_at(height).cloned())
+                        .collect()
+                };
+                reply(&ctx, &from_peer, Message::Blocks(*start, blocks));
+            }
+            Message::FetchHeaders(start, count) => {
+                let headers: Vec<BlockHeader> = {
+                    let blockchain = ctx.blockchain.read().await;
+                    (*start..start.saturating_add((*count).min(MAX_FETCH_HEADERS)))
+                        .map_while(|height| Some(blockchain.block_at(height)?.header.clone()))
+                        .collect()
+                };
+                reply(&ctx, &from_peer, Message::Headers(*start, headers));
+            }
+            Message::Blocks(start, blocks) => {
+                crate::sync::receive_blocks(&ctx, &from_peer, *start, blocks.clone()).await;
+            }
+            Message::Difference(count) => {
+                ctx.network.pong(&from_peer, *count);
             }
             Message::FetchAllBlocks => {
-                let blockchain = ctx.blockchain.read().await;
-                let blocks: Vec<Block> = blockchain.blocks().cloned().collect();
-                let reply = Envelope::new(
-                    ctx.network.self_id.clone(),
-                    DEFAULT_TTL,
-                    Message::AllBlocks(blocks),
-                );
-                ctx.network.send_to(&from_peer, reply).await;
+                let blocks: Vec<Block> = ctx.blockchain.read().await.blocks().cloned().collect();
+                reply(&ctx, &from_peer, Message::AllBlocks(blocks));
             }
             Message::DiscoverNodes => {
-                let nodes = ctx.network.peer_ids();
-                let reply = Envelope::new(
-                    ctx.network.self_id.clone(),
-                    DEFAULT_TTL,
-                    Message::NodeList(nodes),
-                );
-                ctx.network.send_to(&from_peer, reply).await;
+                let nodes = if ctx.seed_mode {
+                    ctx.addresses.good(MAX_NODE_LIST)
+                } else {
+                    ctx.network.dialed_addresses()
+                };
+                reply(&ctx, &from_peer, Message::NodeList(nodes));
             }
             Message::AskDifference(height) => {
-                let blockchain = ctx.blockchain.read().await;
-                let count = blockchain.block_height() as i32 - *height as i32;
-                let reply = Envelope::new(
-                    ctx.network.self_id.clone(),
-                    DEFAULT_TTL,
-                    Message::Difference(count),
-                );
-                ctx.network.send_to(&from_peer, reply).await;
+                let count = ctx.blockchain.read().await.block_height() as i32 - *height as i32;
+                reply(&ctx, &from_peer, Message::Difference(count));
             }
-            Message::FetchUTXOs(key) => {
+            Message::FetchUTXOs(key, min_confirmations) => {
                 debug!("received request to fetch UTXOs");
-                let blockchain = ctx.blockchain.read().await;
-                let utxos = blockchain
-                    .utxos()
-                    .iter()
-                    .filter(|(_, (_, txout))| txout.pubkey == *key)
-                    .map(|(_, (marked, txout))| (txout.clone(), *marked))
-                    .collect::<Vec<_>>();
-                let reply = Envelope::new(
-                    ctx.network.self_id.clone(),
-                    DEFAULT_TTL,
-                    Message::UTXOs(utxos),
-                );
-                ctx.network.send_to(&from_peer, reply).await;
+                let min_confirmations = min_confirmations.unwrap_or(0);
+                let utxos = {
+                    let blockchain = ctx.blockchain.read().await;
+                    // outputs may be paid to either address format
+                    blockchain
+                        .utxos()
+                        .iter()
+                        .filter(|(_, (_, height, txout))| {
+                            Address::same(&txout.address, key)
+                                && blockchain.confirmations(*height) >= min_confirmations
+                        })
+                        .map(|(_, (marked, _, txout))| (txout.clone(), *marked))
+                        .collect::<Vec<_>>()
+                };
+                reply(&ctx, &from_peer, Message::UTXOs(utxos));
             }
             Message::NewBlock(block) => {
-                let hash = block.hash();
-                let mut blockchain = ctx.blockchain.write().await;
-                info!("received new block: {}", hash);
-                if blockchain.add_block(block.clone()).is_err() {
-                    warn!("block rejected: {} (nodes may be out of sync)", hash);
+                should_gossip = receive_block(&ctx, &from_peer, block).await;
+            }
+            Message::CompactBlock(compact) => {
+                let header_hash = compact.header.hash();
+                ctx.relay.seen(header_hash);
+                let transactions = {
+                    let blockchain = ctx.blockchain.read().await;
+                    let mempool: HashMap<Hash, &Transaction> =
+                        blockchain.mempool().iter().map(|(_, tx)| (tx.hash(), tx)).collect();
+                    compact.transactions(|txid| mempool.get(txid).map(|tx| (*tx).clone()))
+                };
+                let missing: Vec<u64> = (0..transactions.len() as u64)
+                    .filter(|position| transactions[*position as usize].is_none())
+                    .collect();
+                if missing.is_empty() {
+                    ctx.relay.compact_complete.fetch_add(1, Ordering::Relaxed);
+                    let block = Block::new(compact.header.clone(), transactions.into_iter().flatten().collect());
+                    receive_compact_block(&ctx, &from_peer, &env, block).await;
                 } else {
-                    should_gossip = true;
+                    debug!(
+                        "compact block {} lacks {} transactions, asking {}",
+                        header_hash,
+                        missing.len(),
+                        from_peer
+                    );
+                    let pending = PendingBlock {
+                        header: compact.header.clone(),
+                        transactions,
+                        peer: from_peer.clone(),
+                        envelope: env.clone(),
+                    };
+                    ctx.relay.wait_for_transactions(header_hash, pending);
+                    reply(&ctx, &from_peer, Message::FetchBlockTransactions(header_hash, missing));
+                }
+            }
+            Message::FetchBlockTransactions(header_hash, positions) => {
+                let transactions = {
+                    let blockchain = ctx.blockchain.read().await;
+                    let height = blockchain.block_height();
+                    (height.saturating_sub(RECENT_RELAYED_BLOCKS)..height)
+                        .rev()
+                        .filter_map(|height| blockchain.block_at(height))
+                        .chain(blockchain.side_blocks().map(|side| &side.block))
+                        .find(|block| block.header.hash() == *header_hash)
+                        .map(|block| {
+                            positions
+                                .iter()
+                                .map(|position| block.transactions.get(*position as usize).cloned())
+                                .collect::<Option<Vec<_>>>()
+                        })
+                };
+                match transactions {
+                    Some(Some(transactions)) => {
+                        reply(&ctx, &from_peer, Message::BlockTransactions(*header_hash, transactions));
+                    }
+                    Some(None) => {
+                        ctx.network.misbehaving(&from_peer, 10, "asked for transactions past the end of a block");
+                    }
+                    None => debug!("no recent block {header_hash} to send transactions of"),
+                }
+            }
+            Message::BlockTransactions(header_hash, transactions) => {
+                let Some(mut pending) = ctx.relay.take_pending(header_hash, &from_peer) else {
+                    debug!("transactions of block {header_hash} from {from_peer} weren't asked for");
+                    continue;
+                };
+                let mut given = transactions.iter().cloned();
+                for slot in pending.transactions.iter_mut().filter(|slot| slot.is_none()) {
+                    *slot = given.next();
+                }
+                let filled = pending.transactions.into_iter().collect::<Option<Vec<_>>>();
+                match filled {
+                    Some(transactions) if given.next().is_none() => {
+                        ctx.relay.compact_fetched.fetch_add(1, Ordering::Relaxed);
+                        let block = Block::new(pending.header, transactions);
+                        receive_compact_block(&ctx, &from_peer, &pending.envelope, block).await;
+                    }
+                    _ => {
+                        ctx.relay.compact_failed.fetch_add(1, Ordering::Relaxed);
+                        let reason = format!("sent {} transactions of block {header_hash}, not those asked for", transactions.len());
+                        ctx.network.misbehaving(&from_peer, MISBEHAVIOR_THRESHOLD / 2, &reason);
+                    }
                 }
             }
             Message::NewTransaction(tx) => {
                 let hash = tx.hash();
-                let mut blockchain = ctx.blockchain.write().await;
                 info!("received new transaction: {}", hash);
-                if blockchain.add_to_mempool(tx.clone()).is_err() {
+                if accept_transaction(&ctx, tx).await.is_err() {
                     warn!("transaction rejected: {} (nodes may be out of sync)", hash);
                 } else {
+                    ctx.network.useful(&from_peer);
                     should_gossip = true;
                 }
             }
+            Message::Checkpoint(signed) => match accept_checkpoint(&ctx, signed.clone()).await {
+                Ok(true) => {
+                    info!("took checkpoint {}", signed.checkpoint);
+                    should_gossip = true;
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    // nodes only relay checkpoints they took
+                    let reason = format!("bad checkpoint: {e}");
+                    ctx.network.misbehaving(&from_peer, MISBEHAVIOR_THRESHOLD / 2, &reason);
+                }
+            },
             Message::ValidateTemplate(block_template) => {
-                let blockchain = ctx.blockchain.read().await;
-                let status =
-                    block_template.header.prev_block_hash == get_last_block_hash(&blockchain);
-                let reply = Envelope::new(
-                    ctx.network.self_id.clone(),
-                    DEFAULT_TTL,
-                    Message::TemplateValidity(status),
-                );
-                ctx.network.send_to(&from_peer, reply).await;
+                let status = block_template.header.prev_block_hash
+                    == get_last_block_hash(&*ctx.blockchain.read().await);
+                reply(&ctx, &from_peer, Message::TemplateValidity(status));
             }
             Message::SubmitTemplate(block) => {
                 info!("received allegedly mined template");
-                let mut blockchain = ctx.blockchain.write().await;
-                if let Err(e) = blockchain.add_block(block.clone()) {
+                if let Err(e) = accept_block(&ctx, block).await {
                     warn!("block rejected: {e}, closing connection");
                     continue;
                 }
-                blockchain.rebuild_utxos();
                 info!("block looks good, broadcasting");
                 let gossip = Envelope::new(
                     ctx.network.self_id.clone(),
                     DEFAULT_TTL,
                     Message::NewBlock(block.clone()),
                 );
-                broadcast_except(&ctx, Some(&from_peer), gossip).await;
+                broadcast_except(&ctx, Some(&from_peer), gossip);
             }
             Message::SubmitTransaction(tx) => {
                 debug!("submit tx");
-                let mut blockchain = ctx.blockchain.write().await;
-                if let Err(e) = blockchain.add_to_mempool(tx.clone()) {
+                if let Err(e) = accept_transaction(&ctx, tx).await {
                     warn!("transaction rejected: {e}, closing connection");
                     continue;
                 }
@@ -206,82 +532,429 @@ pub async fn dispatcher_loop(ctx: NodeContext) -> Result<()> {
                     DEFAULT_TTL,
                     Message::NewTransaction(tx.clone()),
                 );
-                broadcast_except(&ctx, Some(&from_peer), gossip).await;
+                broadcast_except(&ctx, Some(&from_peer), gossip);
                 info!("transaction sent to all nodes");
             }
             Message::FetchTemplate(pubkey) => {
-                let blockchain = ctx.blockchain.read().await;
-
-                // Build transactions list: coinbase first, then mempool transactions
-                let mut transactions: Vec<Transaction> = blockchain
-                    .mempool()
-                    .iter()
-                    .take(btclib::BLOCK_TRANSACTION_CAP)
-                    .map(|(_, tx)| tx)
-                    .cloned()
-                    .collect();
+                let template = build_template(&*ctx.blockchain.read().await, pubkey);
+                match template {
+                    Ok(block) => reply(&ctx, &from_peer, Message::Template(block)),
+                    // the miner's reward would be unspendable on this chain
+                    Err(e @ (BtcError::InvalidAddress | BtcError::WrongNetwork)) => {
+                        warn!("not building a template paying to {pubkey}: {e}");
+                    }
+                    Err(e) => error!("error building a template: {e}"),
+                }
+            }
+        }
 
-                // Insert coinbase transaction at the beginning
-                let coinbase = Transaction {
-                    inputs: vec![],
-                    outputs: vec![TransactionOutput {
-                        address: *pubkey,
-                        value: 0,
-                        unique_id: Uuid::new_v4(),
-                    }],
-                };
-                transactions.insert(0, coinbase);
-
-                // Create block with placeholder merkle root (will be calculated after coinbase value is set)
-                let prev_block_hash = get_last_block_hash(&blockchain);
-                let mut block = Block::new(
-                    BlockHeader {
-                        timestamp: Utc::now(),
-                        nonce: 0,
-                        prev_block_hash,
-                        merkle_root: MerkleRoot::calculate(&[]),
-                        target: blockchain.target(),
-                    },
-                    transactions,
-                );
+        if should_gossip && env.ttl > 0 {
+            env.ttl -= 1;
+            broadcast_except(&ctx, Some(&from_peer), env);
+        }
+    }
+}
 
-                // Calculate miner fees and update coinbase value
-                let miner_fees = match block.calculate_miner_fees(blockchain.utxos()) {
-                    Ok(fees) => fees,
-                    Err(e) => {
-                        error!("error calculating miner fees: {e}, closing connection");
-                        continue;
-                    }
-                };
+/// Build a block template paying to `address`: coinbase first, then
+/// mempool transactions by ancestor fee rate
+pub(crate) fn build_template(blockchain: &Blockchain, address: &str) -> btclib::error::Result<Block> {
+    Address::parse_for(address, blockchain.params())?;
+    let mut transactions = blockchain.template_transactions(btclib::BLOCK_TRANSACTION_CAP);
 
-                let reward = blockchain.calculate_block_reward();
-                block.transactions[0].outputs[0].value = reward + miner_fees;
+    let coinbase = Transaction {
+        inputs: vec![],
+        outputs: vec![TransactionOutput {
+            address: address.to_string(),
+            value: 0,
+            // a random prefix and the extranonce, 0 until
+            // the miner changes it
+            unique_id: Uuid::from_u64_pair(Uuid::new_v4().as_u64_pair().0, 0),
+        }],
+    };
+    transactions.insert(0, coinbase);
 
-                // Calculate merkle root once after coinbase value is finalized
-                block.header.merkle_root = MerkleRoot::calculate(&block.transactions);
+    // Create block with placeholder merkle root (will be calculated after coinbase value is set)
+    let mut block = Block::new(
+        BlockHeader {
+            timestamp: Utc::now(),
+            nonce: 0,
+            prev_block_hash: get_last_block_hash(blockchain),
+            merkle_root: MerkleRoot::calculate(&[]),
+            target: blockchain.target(),
+        },
+        transactions,
+    );
 
-                let reply = Envelope::new(
-                    ctx.network.self_id.clone(),
-                    DEFAULT_TTL,
-                    Message::Template(block),
-                );
-                ctx.network.send_to(&from_peer, reply).await;
+    // Calculate miner fees and update coinbase value
+    let miner_fees = block.calculate_miner_fees(blockchain.utxos())?;
+    let reward = blockchain.calculate_block_reward();
+    block.transactions[0].outputs[0].value = reward + miner_fees;
+
+    // Calculate merkle root once after coinbase value is finalized
+    block.header.merkle_root = MerkleRoot::calculate(&block.transactions);
+    Ok(block)
+}
+
+/// Take a block a peer relayed, returning whether it is new and
+/// valid, to be relayed on
+async fn receive_block(ctx: &NodeContext, from_peer: &PeerId, block: &Block) -> bool {
+    let hash = block.hash();
+    info!("received new block: {}", hash);
+    match accept_block(ctx, block).await {
+        Ok(()) => {
+            ctx.network.useful(from_peer);
+            // the peer has at least this block, so is at least as high
+            if let Some(height) = ctx.blockchain.read().await.height_of(&hash) {
+                ctx.network.update_height(from_peer, height + 1);
             }
+            return true;
         }
+        Err(BtcError::KnownBlock) => debug!("already have block {}", hash),
+        Err(e) => {
+            let mut blockchain = ctx.blockchain.write().await;
+            // may be an older block we asked for while backfilling
+            let backfilled = blockchain.base().is_some() && match blockchain.backfill_block(block.clone()) {
+                Ok(_) => true,
+                Err(e) => {
+                    debug!("not a backfill block: {} ({e})", hash);
+                    false
+                }
+            };
+            drop(blockchain);
+            if !backfilled && matches!(e, BtcError::UnknownParent) {
+                // a branch we haven't seen, ask for it back to
+                // where it meets ours
+                info!("block {} builds on an unknown block, fetching it", hash);
+                reply(ctx, from_peer, Message::FetchBlockByHash(block.header.prev_block_hash));
+            } else if !backfilled {
+                warn!("block rejected: {} ({e})", hash);
+            }
+        }
+    }
+    false
+}
 
-        if should_gossip && env.ttl > 0 {
-            env.ttl -= 1;
-            broadcast_except(&ctx, Some(&from_peer), env).await;
+/// Take a block filled in from a compact block and relay it on under
+/// the id of the envelope the compact block came in, as a full block
+/// to peers not taking compact ones
+async fn receive_compact_block(ctx: &NodeContext, from_peer: &PeerId, envelope: &Envelope, block: Block) {
+    // replays need the block, not what it was filled in from
+    if ctx.journal.is_some() {
+        ctx.record(Record::Message {
+            peer: from_peer.clone(),
+            envelope: Envelope {
+                msg: Message::NewBlock(block.clone()),
+                origin: envelope.origin.clone(),
+                ..*envelope
+            },
+        });
+    }
+    if receive_block(ctx, from_peer, &block).await && envelope.ttl > 0 {
+        let gossip = Envelope {
+            id: envelope.id,
+            origin: envelope.origin.clone(),
+            ttl: envelope.ttl - 1,
+            msg: Message::NewBlock(block),
+        };
+        broadcast_except(ctx, Some(from_peer), gossip);
+    }
+}
+
+/// Add a block to the chain and tell event subscribers. The write
+/// lock is held only while adding it, relaying is up to the caller
+/// once it's released.
+pub(crate) async fn accept_block(ctx: &NodeContext, block: &Block) -> btclib::error::Result<()> {
+    let header_hash = block.header.hash();
+    ctx.relay.seen(header_hash);
+    let events = {
+        let mut blockchain = ctx.blockchain.write().await;
+        let old_tip = blockchain.tip_hash();
+        let update = blockchain.submit_block(block.clone())?;
+        chain_events(&blockchain, old_tip, &update)
+    };
+    ctx.relay.validated(header_hash);
+    for event in events {
+        ctx.publish(event);
+    }
+    Ok(())
+}
+
+/// Events for a change of the main chain: a reorg if blocks were
+/// replaced, then every block connected above the fork
+pub(crate) fn chain_events(blockchain: &Blockchain, old_tip: Option<Hash>, update: &ChainUpdate) -> Vec<ChainEvent> {
+    let mut events = vec![];
+    if !update.disconnected.is_empty() {
+        events.push(ChainEvent::Reorg {
+            fork_height: update.fork_height,
+            old_tip: old_tip.map(|hash| hash.to_string()).unwrap_or_default(),
+            new_tip: blockchain.tip_hash().map(|hash| hash.to_string()).unwrap_or_default(),
+        });
+    }
+    for height in update.fork_height..blockchain.block_height() {
+        if let Some(block) = blockchain.block_at(height) {
+            events.push(ChainEvent::block(height, block));
         }
     }
+    events
+}
+
+/// Add a transaction to the mempool, see `accept_block`
+pub(crate) async fn accept_transaction(ctx: &NodeContext, tx: &Transaction) -> btclib::error::Result<()> {
+    let fee = {
+        let mut blockchain = ctx.blockchain.write().await;
+        blockchain.add_to_mempool(tx.clone())?;
+        blockchain.transaction_fee(tx)
+    };
+    ctx.publish(ChainEvent::transaction(tx, fee));
+    Ok(())
+}
+
+/// Take a checkpoint signed by the network's checkpoint key and keep
+/// it in the database, returning whether it is new
+pub(crate) async fn accept_checkpoint(ctx: &NodeContext, signed: SignedCheckpoint) -> btclib::error::Result<bool> {
+    let added = ctx.blockchain.write().await.add_signed_checkpoint(signed.clone())?;
+    if added && let Err(e) = ctx.db.put_signed_checkpoint(&signed) {
+        error!("failed to store checkpoint {}: {e}", signed.checkpoint);
+    }
+    Ok(added)
 }
 
-async fn broadcast_except(ctx: &NodeContext, except: Option<&PeerId>, env: Envelope) {
-    for item in ctx.network.peers.iter() {
-        let peer_id = item.key();
-        if except.is_some_and(|e| e == peer_id) {
+pub(crate) fn broadcast_except(ctx: &NodeContext, except: Option<&PeerId>, env: Envelope) {
+    // new blocks go as compact blocks to the peers taking them
+    let block = match &env.msg {
+        Message::NewBlock(block) => Some(block),
+        _ => None,
+    };
+    let compact = block.and_then(CompactBlock::new).map(|compact| Envelope {
+        msg: Message::CompactBlock(compact),
+        origin: env.origin.clone(),
+        ..env
+    });
+    // ids first, sending needs the map's locks
+    for peer_id in ctx.network.peer_ids() {
+        if except.is_some_and(|e| *e == peer_id) {
             continue;
         }
-        let _ = item.value().outbound.try_send(env.clone());
+        match &compact {
+            Some(compact) if ctx.network.takes_compact_blocks(&peer_id) => {
+                ctx.network.send_to(&peer_id, compact.clone())
+            }
+            _ => ctx.network.send_to(&peer_id, env.clone()),
+        };
+    }
+    if let Some(block) = block {
+        ctx.relay.relayed(block.header.hash());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::BlockchainDB;
+    use crate::network::Identity;
+    use btclib::crypto::{PrivateKey, PublicKey, Signature};
+    use btclib::network::identity_digest;
+    use btclib::params::ChainParams;
+    use std::time::Duration;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    async fn serving_node() -> (NodeContext, TcpListener) {
+        serving_node_as(Identity::ephemeral()).await
+    }
+
+    async fn serving_node_as(identity: Identity) -> (NodeContext, TcpListener) {
+        let ctx = NodeContext::from_db(BlockchainDB::temporary().unwrap(), ChainParams::testnet(), false)
+            .unwrap()
+            .with_identity(identity);
+        tokio::spawn(dispatcher_loop(ctx.clone()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        (ctx, listener)
+    }
+
+    /// Connect to the node and return the stream and the peer's id
+    async fn connect(ctx: &NodeContext, listener: &TcpListener) -> (TcpStream, PeerId) {
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (socket, peer_addr) = listener.accept().await.unwrap();
+        accept_peer(ctx.clone(), socket, peer_addr, false, None).await.unwrap();
+        (client, peer_addr.to_string())
+    }
+
+    /// Shake hands with the node as a peer holding `key`
+    async fn handshake(client: &mut TcpStream, key: &PrivateKey) -> PublicKey {
+        let challenge = [7; 32];
+        let version = VersionInfo {
+            challenge: Some(challenge),
+            ..version_info(&Blockchain::new(), Services::default(), None)
+        };
+        request(Message::Version(version)).send_async(client).await.unwrap();
+        let Message::Version(VersionInfo { challenge: Some(theirs), .. }) =
+            Envelope::receive_async(client).await.unwrap().msg
+        else {
+            panic!("expected a version with a challenge");
+        };
+        let Message::Identity(node_key, signature) = Envelope::receive_async(client).await.unwrap().msg else {
+            panic!("expected the node's identity");
+        };
+        assert!(signature.verify(&identity_digest(&challenge), &node_key));
+        let signature = Signature::sign_output(&identity_digest(&theirs), key);
+        request(Message::Identity(key.public_key(), signature)).send_async(client).await.unwrap();
+        node_key
+    }
+
+    async fn wait_until(condition: impl Fn() -> bool) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !condition() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("condition not met in time");
+    }
+
+    fn request(msg: Message) -> Envelope {
+        Envelope::new(Uuid::new_v4().to_string(), DEFAULT_TTL, msg)
+    }
+
+    #[tokio::test]
+    async fn test_peer_closing_mid_response() {
+        let (ctx, listener) = serving_node().await;
+        let (mut client, peer_id) = connect(&ctx, &listener).await;
+        request(Message::FetchAllBlocks).send_async(&mut client).await.unwrap();
+        request(Message::DiscoverNodes).send_async(&mut client).await.unwrap();
+        drop(client);
+        wait_until(|| !ctx.network.peers.contains_key(&peer_id)).await;
+        assert_eq!(ctx.network.disconnects.load(Ordering::Relaxed), 1);
+
+        // the node still answers everyone else
+        let (mut client, _) = connect(&ctx, &listener).await;
+        request(Message::DiscoverNodes).send_async(&mut client).await.unwrap();
+        let reply = tokio::time::timeout(Duration::from_secs(5), Envelope::receive_async(&mut client))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(reply.msg, Message::NodeList(_)));
+    }
+
+    #[tokio::test]
+    async fn test_undecodable_message_disconnects() {
+        let (ctx, listener) = serving_node().await;
+        let (mut client, peer_id) = connect(&ctx, &listener).await;
+        client.write_all(&4u64.to_be_bytes()).await.unwrap();
+        client.write_all(&[0xff; 4]).await.unwrap();
+        wait_until(|| !ctx.network.peers.contains_key(&peer_id)).await;
+        assert_eq!(ctx.network.misbehaving.load(Ordering::Relaxed), 1);
+        // replies still on their way to it go nowhere
+        ctx.network.send_to(&peer_id, request(Message::DiscoverNodes));
+        assert_eq!(ctx.network.disconnects.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_only_trusted_peers_stay() {
+        let (trusted, stranger) = (PrivateKey::new_key(), PrivateKey::new_key());
+        let identity = Identity {
+            key: PrivateKey::new_key(),
+            trusted: vec![trusted.public_key()],
+            trusted_only: true,
+        };
+        let node_key = identity.key.public_key();
+        let (ctx, listener) = serving_node_as(identity).await;
+
+        let (mut client, peer_id) = connect(&ctx, &listener).await;
+        assert_eq!(handshake(&mut client, &trusted).await, node_key);
+        wait_until(|| ctx.network.peers.get(&peer_id).is_some_and(|peer| peer.trusted)).await;
+        assert_eq!(ctx.network.handshaked_peers().len(), 1);
+
+        let (mut client, peer_id) = connect(&ctx, &listener).await;
+        handshake(&mut client, &stranger).await;
+        wait_until(|| !ctx.network.peers.contains_key(&peer_id)).await;
+        assert_eq!(ctx.network.misbehaving.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_compact_block_relay() {
+        let chain = btclib::testing::build_chain(&PrivateKey::new_key(), &[vec![(0, 1, 10)]]);
+        let (genesis, block) = (chain.block_at(0).unwrap().clone(), chain.block_at(1).unwrap().clone());
+        let ctx = NodeContext::from_db(BlockchainDB::temporary().unwrap(), ChainParams::default(), false).unwrap();
+        tokio::spawn(dispatcher_loop(ctx.clone()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (mut relayer, relayer_id) = connect(&ctx, &listener).await;
+        handshake(&mut relayer, &PrivateKey::new_key()).await;
+        let (mut node, node_id) = connect(&ctx, &listener).await;
+        handshake(&mut node, &PrivateKey::new_key()).await;
+        // a miner, which never shakes hands
+        let (mut miner, _) = connect(&ctx, &listener).await;
+        wait_until(|| ctx.network.takes_compact_blocks(&relayer_id) && ctx.network.takes_compact_blocks(&node_id)).await;
+
+        // nothing to ask for in a block of just its coinbase
+        let compact = CompactBlock::new(&genesis).unwrap();
+        request(Message::CompactBlock(compact)).send_async(&mut relayer).await.unwrap();
+        wait_until(|| ctx.relay.compact_complete.load(Ordering::Relaxed) == 1).await;
+        let Message::CompactBlock(relayed) = Envelope::receive_async(&mut node).await.unwrap().msg else {
+            panic!("expected a compact block");
+        };
+        assert_eq!(relayed.header.hash(), genesis.header.hash());
+        let Message::NewBlock(relayed) = Envelope::receive_async(&mut miner).await.unwrap().msg else {
+            panic!("expected a full block");
+        };
+        assert_eq!(relayed.hash(), genesis.hash());
+
+        // the spend never reached our mempool
+        let compact = CompactBlock::new(&block).unwrap();
+        request(Message::CompactBlock(compact)).send_async(&mut relayer).await.unwrap();
+        let Message::FetchBlockTransactions(hash, positions) = Envelope::receive_async(&mut relayer).await.unwrap().msg
+        else {
+            panic!("expected a request for the missing transaction");
+        };
+        assert_eq!((hash, positions.clone()), (block.header.hash(), vec![1]));
+        request(Message::BlockTransactions(hash, vec![block.transactions[1].clone()]))
+            .send_async(&mut relayer)
+            .await
+            .unwrap();
+        wait_until(|| ctx.relay.compact_fetched.load(Ordering::Relaxed) == 1).await;
+        assert_eq!(ctx.blockchain.read().await.tip_hash(), Some(block.hash()));
+        assert_eq!(ctx.relay.transactions_requested.load(Ordering::Relaxed), 1);
+        let timing = ctx.relay.timing(&block.header.hash()).unwrap();
+        assert!(timing.validated.is_some() && timing.relayed.is_some());
+
+        // and we answer the same request from others
+        request(Message::FetchBlockTransactions(hash, positions)).send_async(&mut node).await.unwrap();
+        let reply = loop {
+            match Envelope::receive_async(&mut node).await.unwrap().msg {
+                Message::CompactBlock(_) => continue,
+                reply => break reply,
+            }
+        };
+        let Message::BlockTransactions(_, transactions) = reply else {
+            panic!("expected the block's transactions");
+        };
+        assert_eq!(transactions[0].hash(), block.transactions[1].hash());
+    }
+
+    #[tokio::test]
+    async fn test_transaction_proof() {
+        let chain = btclib::testing::build_chain(&PrivateKey::new_key(), &[vec![(0, 1, 10)]]);
+        let ctx = NodeContext::from_db(BlockchainDB::temporary().unwrap(), ChainParams::default(), false).unwrap();
+        for height in 0..2 {
+            accept_block(&ctx, chain.block_at(height).unwrap()).await.unwrap();
+        }
+        tokio::spawn(dispatcher_loop(ctx.clone()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (mut wallet, _) = connect(&ctx, &listener).await;
+
+        let block = chain.block_at(1).unwrap();
+        let txid = block.transactions[1].hash();
+        for (asked, found) in [(txid, true), (Hash::zero(), false)] {
+            request(Message::FetchTransactionProof(asked)).send_async(&mut wallet).await.unwrap();
+            let Message::TransactionProof(hash, proof) = Envelope::receive_async(&mut wallet).await.unwrap().msg else {
+                panic!("expected a transaction proof");
+            };
+            assert_eq!(hash, asked);
+            assert_eq!(proof.is_some(), found);
+            if let Some(proof) = proof {
+                assert!(proof.verify());
+                assert_eq!((proof.height, proof.header_hash()), (1, block.header.hash()));
+            }
+        }
     }
-}
\ No newline at end of file
+}
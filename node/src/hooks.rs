@@ -0,0 +1,352 @@
+//! Commands and webhooks run on chain events, configured in a TOML
+//! file given with `--hooks`:
+//!
+//! ```toml
+//! on_new_block = "logger new block"
+//! on_wallet_tx = "http://127.0.0.1:8000/payments"
+//! watch = ["mx..."]
+//! ```
+//!
+//! A hook starting with `http://` is a webhook, the event is POSTed to
+//! it as JSON. Anything else is run with `sh -c`, with the JSON on its
+//! standard input and the event's type in `GRAPHENO_EVENT`.
+use crate::context::NodeContext;
+use anyhow::{Context, Result, anyhow, bail};
+use btclib::address::Address;
+use btclib::events::ChainEvent;
+use btclib::params::ChainParams;
+use btclib::types::Transaction;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::process::Stdio;
+use std::str::FromStr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::process::Command;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::{Duration, timeout};
+use tracing::{info, warn};
+
+/// How long a webhook may take to answer
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What to run, see the module documentation
+#[derive(Debug, Clone, PartialEq)]
+pub enum Hook {
+    Command(String),
+    /// The host and port to connect to, and the path to post to
+    Webhook { authority: String, path: String },
+}
+
+impl FromStr for Hook {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with("https://") {
+            return Err(format!("{s}: webhooks are only posted over plain http://"));
+        }
+        let Some(url) = s.strip_prefix("http://") else {
+            return match s.trim().is_empty() {
+                true => Err("empty hook command".to_string()),
+                false => Ok(Hook::Command(s.to_string())),
+            };
+        };
+        let (authority, path) = match url.find('/') {
+            Some(slash) => (&url[..slash], &url[slash..]),
+            None => (url, "/"),
+        };
+        if authority.is_empty() {
+            return Err(format!("{s}: no host"));
+        }
+        let authority = match authority.contains(':') {
+            true => authority.to_string(),
+            false => format!("{authority}:80"),
+        };
+        Ok(Hook::Webhook {
+            authority,
+            path: path.to_string(),
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Hook {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct HooksConfig {
+    /// Run for every block connected to the chain
+    pub on_new_block: Option<Hook>,
+    /// Run for every transaction paying a watched address, once when
+    /// it enters the mempool and once when it is mined
+    pub on_wallet_tx: Option<Hook>,
+    /// Addresses `on_wallet_tx` watches
+    #[serde(default)]
+    pub watch: Vec<String>,
+}
+
+impl HooksConfig {
+    pub fn parse(text: &str, params: &ChainParams) -> Result<Self> {
+        let config: HooksConfig = toml::from_str(text)?;
+        for address in &config.watch {
+            Address::parse_for(address, params).map_err(|e| anyhow!("can't watch {address}: {e}"))?;
+        }
+        if config.on_wallet_tx.is_some() && config.watch.is_empty() {
+            bail!("on_wallet_tx needs addresses to watch");
+        }
+        Ok(config)
+    }
+
+    pub fn load(path: &Path, params: &ChainParams) -> Result<Self> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        Self::parse(&text, params).with_context(|| format!("Failed to parse {}", path.display()))
+    }
+}
+
+/// What `on_wallet_tx` is given: the outputs of a transaction paying
+/// one watched address, added up
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WalletPayment {
+    pub txid: String,
+    pub address: String,
+    pub value: u64,
+    /// Height of the block holding the transaction, None while it is
+    /// in the mempool
+    pub height: Option<u64>,
+}
+
+/// The payments of `transaction` to the watched addresses
+pub fn payments(transaction: &Transaction, watch: &[String], height: Option<u64>) -> Vec<WalletPayment> {
+    let txid = transaction.hash().to_string();
+    watch
+        .iter()
+        .filter_map(|address| {
+            let paying = transaction.outputs.iter().filter(|output| Address::same(&output.address, address));
+            let value = paying.map(|output| output.value).reduce(|a, b| a + b)?;
+            Some(WalletPayment {
+                txid: txid.clone(),
+                address: address.clone(),
+                value,
+                height,
+            })
+        })
+        .collect()
+}
+
+impl Hook {
+    pub async fn run(&self, event: &str, payload: &str) -> Result<()> {
+        match self {
+            Hook::Command(command) => {
+                let mut child = Command::new("sh")
+                    .arg("-c")
+                    .arg(command)
+                    .env("GRAPHENO_EVENT", event)
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::null())
+                    .spawn()?;
+                if let Some(mut stdin) = child.stdin.take() {
+                    // commands may not read it
+                    let _ = stdin.write_all(payload.as_bytes()).await;
+                }
+                let status = child.wait().await?;
+                if !status.success() {
+                    bail!("`{command}` exited with {status}");
+                }
+                Ok(())
+            }
+            Hook::Webhook { authority, path } => timeout(WEBHOOK_TIMEOUT, post(authority, path, payload))
+                .await
+                .map_err(|_| anyhow!("no answer from {authority} in {:?}", WEBHOOK_TIMEOUT))?,
+        }
+    }
+}
+
+async fn post(authority: &str, path: &str, body: &str) -> Result<()> {
+    let mut stream = TcpStream::connect(authority).await?;
+    let request = format!(
+        "POST {path} HTTP/1.1\r\nHost: {authority}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = vec![];
+    stream.read_to_end(&mut response).await?;
+    let status_line = response.split(|byte| *byte == b'\n').next().unwrap_or_default();
+    let status_line = String::from_utf8_lossy(status_line);
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => bail!("http://{authority}{path} answered {:?}", status_line.trim()),
+    }
+}
+
+/// Run a hook without holding up the events after it
+fn fire(hook: &Hook, event: &'static str, payload: String) {
+    let hook = hook.clone();
+    tokio::spawn(async move {
+        if let Err(e) = hook.run(event, &payload).await {
+            warn!("{event} hook failed: {e}");
+        }
+    });
+}
+
+fn fire_payments(config: &HooksConfig, transaction: &Transaction, height: Option<u64>) {
+    let Some(hook) = &config.on_wallet_tx else {
+        return;
+    };
+    for payment in payments(transaction, &config.watch, height) {
+        let payload = serde_json::to_string(&payment).expect("payments serialize");
+        fire(hook, "wallet_tx", payload);
+    }
+}
+
+async fn handle(ctx: &NodeContext, config: &HooksConfig, event: ChainEvent) {
+    match &event {
+        ChainEvent::Block { height, hash, .. } => {
+            if let Some(hook) = &config.on_new_block {
+                fire(hook, "block", serde_json::to_string(&event).expect("events serialize"));
+            }
+            if config.on_wallet_tx.is_some() {
+                let blockchain = ctx.blockchain.read().await;
+                // gone if a reorg replaced it meanwhile
+                let Some(block) = blockchain.block_at(*height).filter(|block| block.hash().to_string() == *hash) else {
+                    return;
+                };
+                for transaction in &block.transactions {
+                    fire_payments(config, transaction, Some(*height));
+                }
+            }
+        }
+        ChainEvent::Transaction { hash, .. } if config.on_wallet_tx.is_some() => {
+            let blockchain = ctx.blockchain.read().await;
+            let transaction = blockchain
+                .mempool()
+                .iter()
+                .map(|(_, transaction)| transaction)
+                .find(|transaction| transaction.hash().to_string() == *hash);
+            if let Some(transaction) = transaction {
+                fire_payments(config, transaction, None);
+            }
+        }
+        ChainEvent::Transaction { .. } | ChainEvent::Reorg { .. } => {}
+    }
+}
+
+/// Run the hooks on the node's events until it stops
+pub async fn run(ctx: NodeContext, config: HooksConfig) {
+    let mut events = ctx.events.subscribe();
+    info!(
+        "hooks on new blocks: {}, on wallet transactions: {} watching {} addresses",
+        config.on_new_block.is_some(),
+        config.on_wallet_tx.is_some(),
+        config.watch.len()
+    );
+    loop {
+        match events.recv().await {
+            Ok(event) => handle(&ctx, &config, event).await,
+            Err(RecvError::Lagged(missed)) => warn!("hooks fell behind, {} events dropped", missed),
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use btclib::crypto::PrivateKey;
+    use btclib::types::TransactionOutput;
+    use tokio::net::TcpListener;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_parse_hooks() {
+        assert_eq!("echo hi".parse(), Ok(Hook::Command("echo hi".to_string())));
+        assert_eq!(
+            "http://127.0.0.1:8000/blocks".parse(),
+            Ok(Hook::Webhook {
+                authority: "127.0.0.1:8000".to_string(),
+                path: "/blocks".to_string(),
+            })
+        );
+        assert_eq!(
+            "http://example.com".parse(),
+            Ok(Hook::Webhook {
+                authority: "example.com:80".to_string(),
+                path: "/".to_string(),
+            })
+        );
+        assert!("https://example.com".parse::<Hook>().is_err());
+        assert!(" ".parse::<Hook>().is_err());
+
+        let params = ChainParams::testnet();
+        let address = PrivateKey::new_key().public_key().to_address_for(&params);
+        let config = HooksConfig::parse(&format!("on_wallet_tx = \"true\"\nwatch = [\"{address}\"]"), &params).unwrap();
+        assert_eq!(config.on_new_block, None);
+        assert_eq!(config.watch, vec![address.clone()]);
+        // mainnet addresses never show up on testnet
+        let mainnet = PrivateKey::new_key().public_key().to_address();
+        assert!(HooksConfig::parse(&format!("on_wallet_tx = \"true\"\nwatch = [\"{mainnet}\"]"), &params).is_err());
+        assert!(HooksConfig::parse("on_wallet_tx = \"true\"", &params).is_err());
+        assert!(HooksConfig::parse("on_new_blocks = \"true\"", &params).is_err());
+    }
+
+    #[test]
+    fn test_payments_add_up_per_address() {
+        let (alice, bob) = (PrivateKey::new_key().public_key(), PrivateKey::new_key().public_key());
+        let output = |address: String, value| TransactionOutput {
+            value,
+            unique_id: Uuid::new_v4(),
+            address,
+        };
+        let transaction = Transaction::new(
+            vec![],
+            vec![output(alice.to_address(), 5), output(bob.to_address(), 7), output(alice.to_address(), 10)],
+        );
+        let watch = vec![alice.to_address(), PrivateKey::new_key().public_key().to_address()];
+        assert_eq!(
+            payments(&transaction, &watch, Some(3)),
+            vec![WalletPayment {
+                txid: transaction.hash().to_string(),
+                address: alice.to_address(),
+                value: 15,
+                height: Some(3),
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_webhook_posts_json() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let hook: Hook = format!("http://{}/hook", listener.local_addr().unwrap()).parse().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = vec![0; 4096];
+            let read = socket.read(&mut request).await.unwrap();
+            socket.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await.unwrap();
+            String::from_utf8(request[..read].to_vec()).unwrap()
+        });
+        hook.run("block", "{\"height\":1}").await.unwrap();
+        let request = server.await.unwrap();
+        assert!(request.starts_with("POST /hook HTTP/1.1\r\n"));
+        assert!(request.ends_with("\r\n\r\n{\"height\":1}"));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let hook: Hook = format!("http://{}/", listener.local_addr().unwrap()).parse().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            socket.write_all(b"HTTP/1.1 500 Internal Server Error\r\n\r\n").await.unwrap();
+        });
+        assert!(hook.run("block", "{}").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_command_gets_event() {
+        let path = std::env::temp_dir().join(format!("grapheno-hook-{}", Uuid::new_v4()));
+        let hook = Hook::Command(format!("(echo $GRAPHENO_EVENT; cat) > {}", path.display()));
+        hook.run("wallet_tx", "{\"value\":5}").await.unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "wallet_tx\n{\"value\":5}");
+        std::fs::remove_file(path).unwrap();
+        assert!(Hook::Command("exit 3".to_string()).run("block", "{}").await.is_err());
+    }
+}
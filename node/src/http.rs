@@ -0,0 +1,25 @@
+//! HTTP server for browsers and tools: the explorer, the event stream
+//! and metrics
+use crate::context::NodeContext;
+use crate::{events, explorer, metrics};
+use anyhow::Result;
+use axum::Router;
+use tokio::net::TcpListener;
+use tracing::info;
+
+pub fn router(ctx: NodeContext) -> Router {
+    explorer::router(ctx.clone())
+        .merge(events::router(ctx.clone()))
+        .merge(metrics::router(ctx))
+}
+
+/// Serve until the listener fails
+pub async fn serve(ctx: NodeContext, addr: &str) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!(
+        "Explorer on http://{}/explorer, events on ws://{}/ws, metrics on http://{}/metrics",
+        addr, addr, addr
+    );
+    axum::serve(listener, router(ctx)).await?;
+    Ok(())
+}
@@ -0,0 +1,403 @@
+//! An append-only record of the messages the node handled, for finding
+//! where two nodes that should agree started to disagree. `--journal`
+//! writes it and the `replay` command feeds it back through validation,
+//! printing every decision, so the replays of two nodes can be diffed.
+//!
+//! Each entry is CBOR behind an 8 byte big endian length, like messages
+//! on the wire.
+use crate::database::BlockchainDB;
+use crate::network::PeerId;
+use anyhow::{Context, Result, anyhow, bail};
+use btclib::network::{Envelope, Message};
+use btclib::crypto::PublicKey;
+use btclib::params::{ChainParams, Network, SignedCheckpoint};
+use btclib::sha256::Hash;
+use btclib::types::{Block, Blockchain, Transaction};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use tracing::warn;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Record {
+    /// The node started recording with its chain in this state
+    Start {
+        network: Network,
+        height: u64,
+        tip: Option<Hash>,
+        mempool: Vec<(DateTime<Utc>, Transaction)>,
+        #[serde(default = "default_max_mempool_age")]
+        max_mempool_age: u64,
+        #[serde(default)]
+        checkpoint_key: Option<PublicKey>,
+        #[serde(default)]
+        checkpoints: Vec<SignedCheckpoint>,
+    },
+    /// A message the node handled
+    Message { peer: PeerId, envelope: Envelope },
+    /// The periodic mempool cleanup ran
+    Cleanup,
+    /// An operator cleared the mempool
+    ClearMempool,
+    /// An operator marked a block invalid
+    InvalidateBlock(Hash),
+    /// An operator took back the invalid mark of a block
+    ReconsiderBlock(Hash),
+}
+
+fn default_max_mempool_age() -> u64 {
+    btclib::MAX_MEMPOOL_TRANSACTION_AGE
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Entry {
+    pub time: DateTime<Utc>,
+    pub record: Record,
+}
+
+pub struct Journal {
+    file: Mutex<BufWriter<File>>,
+}
+
+impl Journal {
+    /// Open the journal at `path` to append to, starting with the state
+    /// of the chain
+    pub fn start(path: &Path, blockchain: &Blockchain) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open journal {}", path.display()))?;
+        let journal = Self {
+            file: Mutex::new(BufWriter::new(file)),
+        };
+        journal.record(Record::Start {
+            network: blockchain.params().network,
+            height: blockchain.block_height(),
+            tip: blockchain.tip_hash(),
+            mempool: blockchain.mempool().to_vec(),
+            max_mempool_age: blockchain.max_mempool_age(),
+            checkpoint_key: blockchain.params().checkpoint_key.clone(),
+            checkpoints: blockchain.signed_checkpoints().to_vec(),
+        })?;
+        Ok(journal)
+    }
+
+    /// Append a record at the current time, flushed before returning
+    pub fn record(&self, record: Record) -> io::Result<()> {
+        let entry = Entry {
+            time: Utc::now(),
+            record,
+        };
+        let mut bytes = vec![];
+        ciborium::into_writer(&entry, &mut bytes).map_err(io::Error::other)?;
+        let mut file = self.file.lock().expect("journal lock");
+        file.write_all(&(bytes.len() as u64).to_be_bytes())?;
+        file.write_all(&bytes)?;
+        file.flush()
+    }
+}
+
+/// Every entry of a journal. An entry cut short, as by a crash while it
+/// was written, ends it.
+pub fn read(path: &Path) -> Result<Vec<Entry>> {
+    let file = File::open(path).with_context(|| format!("Failed to open journal {}", path.display()))?;
+    let mut reader = BufReader::new(file);
+    let mut entries = vec![];
+    loop {
+        let mut len = [0u8; 8];
+        match reader.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+        let len = u64::from_be_bytes(len);
+        let mut bytes = vec![];
+        reader.by_ref().take(len).read_to_end(&mut bytes)?;
+        if (bytes.len() as u64) < len {
+            warn!("journal {} ends in a partly written entry", path.display());
+            break;
+        }
+        let entry = ciborium::from_reader(bytes.as_slice())
+            .with_context(|| format!("Malformed entry {} in journal {}", entries.len(), path.display()))?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// Feed a journal back through validation and write a line per block
+/// and transaction accepted or rejected to `out`. Each time the node
+/// started recording, the chain is rebuilt from the blocks of `db`
+/// below the height it was at, with the mempool it had. Returns the
+/// chain as it ends up.
+pub fn replay(db: &BlockchainDB, path: &Path, out: &mut impl Write) -> Result<Blockchain> {
+    let mut blockchain: Option<Blockchain> = None;
+    // downloaded ranges waiting for the blocks below them
+    let mut ranges: BTreeMap<u64, Vec<Block>> = BTreeMap::new();
+    for (index, entry) in read(path)?.into_iter().enumerate() {
+        let time = entry.time.to_rfc3339_opts(SecondsFormat::Millis, true);
+        match entry.record {
+            Record::Start {
+                network,
+                height,
+                tip,
+                mempool,
+                max_mempool_age,
+                checkpoint_key,
+                checkpoints,
+            } => {
+                if let Some(chain) = &blockchain {
+                    let verdict = if chain.block_height() == height && chain.tip_hash() == tip {
+                        "matches the replay".to_string()
+                    } else {
+                        format!("differs from the replay at height {}", chain.block_height())
+                    };
+                    writeln!(out, "{index} {time} restart at height {height}, {verdict}")?;
+                } else {
+                    writeln!(out, "{index} {time} start at height {height}")?;
+                }
+                let mut params = network.params();
+                if let Some(key) = checkpoint_key {
+                    params = params.with_checkpoint_key(key);
+                }
+                let mut chain = starting_chain(db, params, height, tip)?;
+                for signed in checkpoints {
+                    chain.add_signed_checkpoint(signed)?;
+                }
+                chain.set_max_mempool_age(max_mempool_age);
+                restore_mempool(&mut chain, mempool);
+                blockchain = Some(chain);
+                ranges.clear();
+            }
+            Record::Cleanup => {
+                let chain = blockchain.as_mut().ok_or_else(|| not_started(path))?;
+                let cleanup = chain.cleanup_mempool_at(entry.time);
+                let dropped = cleanup.expired + cleanup.descendants;
+                if dropped > 0 {
+                    writeln!(out, "{index} {time} cleanup dropped {dropped} transactions")?;
+                }
+            }
+            Record::ClearMempool => {
+                let chain = blockchain.as_mut().ok_or_else(|| not_started(path))?;
+                let (cleared, _) = chain.clear_mempool();
+                writeln!(out, "{index} {time} mempool cleared of {cleared} transactions")?;
+            }
+            Record::InvalidateBlock(hash) => {
+                let chain = blockchain.as_mut().ok_or_else(|| not_started(path))?;
+                let result = chain.invalidate_block(&hash).map(|_| ());
+                writeln!(out, "{index} {time} invalidate {hash} {}", verdict(result))?;
+            }
+            Record::ReconsiderBlock(hash) => {
+                let chain = blockchain.as_mut().ok_or_else(|| not_started(path))?;
+                let result = chain.reconsider_block(&hash).map(|_| ());
+                writeln!(out, "{index} {time} reconsider {hash} {}", verdict(result))?;
+            }
+            Record::Message { peer, envelope } => {
+                let chain = blockchain.as_mut().ok_or_else(|| not_started(path))?;
+                for (kind, hash, verdict) in apply(chain, &mut ranges, envelope.msg, entry.time) {
+                    writeln!(out, "{index} {time} {peer} {kind} {hash} {verdict}")?;
+                }
+            }
+        }
+    }
+    let chain = blockchain.ok_or_else(|| not_started(path))?;
+    let tip = chain.tip_hash().map(|hash| hash.to_string()).unwrap_or_default();
+    writeln!(out, "tip at height {} {}", chain.block_height(), tip)?;
+    Ok(chain)
+}
+
+fn not_started(path: &Path) -> anyhow::Error {
+    anyhow!("{} doesn't start with the state of the chain", path.display())
+}
+
+// the chain as the node had it when it started recording
+fn starting_chain(
+    db: &BlockchainDB,
+    params: ChainParams,
+    height: u64,
+    tip: Option<Hash>,
+) -> Result<Blockchain> {
+    let mut blockchain = Blockchain::new();
+    blockchain.set_params(params);
+    for index in 0..height {
+        let block = db
+            .get_block(index)?
+            .ok_or_else(|| anyhow!("The database has no block {index}, recording started at height {height}"))?;
+        blockchain
+            .add_block(block)
+            .with_context(|| format!("Block {index} of the database is invalid"))?;
+    }
+    if blockchain.tip_hash() != tip {
+        bail!("The database's block {} is not the one recording started on", height.saturating_sub(1));
+    }
+    Ok(blockchain)
+}
+
+// the mempool the node had when it started recording
+fn restore_mempool(blockchain: &mut Blockchain, mempool: Vec<(DateTime<Utc>, Transaction)>) {
+    for (added, transaction) in mempool {
+        let hash = transaction.hash();
+        if let Err(e) = blockchain.add_to_mempool_at(transaction, added) {
+            warn!("recorded mempool transaction {hash} is invalid: {e}");
+        }
+    }
+}
+
+fn verdict(result: btclib::error::Result<()>) -> String {
+    match result {
+        Ok(()) => "accepted".to_string(),
+        Err(e) => format!("rejected: {e}"),
+    }
+}
+
+// take the blocks and transactions a message carries, the way the
+// handler does, returning what was decided for each
+fn apply(
+    blockchain: &mut Blockchain,
+    ranges: &mut BTreeMap<u64, Vec<Block>>,
+    msg: Message,
+    time: DateTime<Utc>,
+) -> Vec<(&'static str, Hash, String)> {
+    let kind = msg.kind();
+    let mut decisions = vec![];
+    let mut add_block = |blockchain: &mut Blockchain, block: Block| {
+        let hash = block.hash();
+        let result = blockchain.add_block(block);
+        decisions.push((kind, hash, verdict(result)));
+        decisions.last().is_some_and(|(_, _, verdict)| verdict == "accepted")
+    };
+    match msg {
+        Message::NewBlock(block) | Message::SubmitTemplate(block) => {
+            add_block(blockchain, block);
+        }
+        Message::Blocks(start, blocks) => {
+            ranges.insert(start, blocks);
+            while let Some(entry) = ranges.first_entry() {
+                if *entry.key() > blockchain.block_height() {
+                    break;
+                }
+                let (start, blocks) = entry.remove_entry();
+                for (offset, block) in blocks.into_iter().enumerate() {
+                    if start + (offset as u64) < blockchain.block_height() {
+                        continue;
+                    }
+                    if !add_block(blockchain, block) {
+                        break;
+                    }
+                }
+            }
+        }
+        Message::NewTransaction(transaction) | Message::SubmitTransaction(transaction) => {
+            let hash = transaction.hash();
+            let result = blockchain.add_to_mempool_at(transaction, time);
+            decisions.push((kind, hash, verdict(result)));
+        }
+        Message::Checkpoint(signed) => {
+            let hash = signed.checkpoint.hash;
+            let result = blockchain.add_signed_checkpoint(signed).map(|_| ());
+            decisions.push((kind, hash, verdict(result)));
+        }
+        _ => {}
+    }
+    decisions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use btclib::crypto::{PrivateKey, Signature};
+    use btclib::types::{BlockHeader, TransactionInput, TransactionOutput};
+    use btclib::util::MerkleRoot;
+    use uuid::Uuid;
+
+    fn output(key: &PrivateKey, value: u64) -> TransactionOutput {
+        TransactionOutput {
+            value,
+            unique_id: Uuid::new_v4(),
+            address: key.public_key().to_address(),
+        }
+    }
+
+    fn spend(key: &PrivateKey, prev: &TransactionOutput, value: u64) -> Transaction {
+        let hash = prev.hash();
+        Transaction::new(
+            vec![TransactionInput {
+                prev_transaction_output_hash: hash,
+                public_key: key.public_key(),
+                signature: Signature::sign_output(&hash, key),
+                multisig: None,
+                cosignatures: vec![],
+            }],
+            vec![output(key, value)],
+        )
+    }
+
+    fn message(msg: Message) -> Record {
+        Record::Message {
+            peer: "127.0.0.1:9001".to_string(),
+            envelope: Envelope::new(Uuid::new_v4().to_string(), 8, msg),
+        }
+    }
+
+    #[test]
+    fn test_replay() {
+        let key = PrivateKey::new_key();
+        let coinbase = output(&key, 1000);
+        let transactions = vec![Transaction::new(vec![], vec![coinbase.clone()])];
+        let genesis = Block::new(
+            BlockHeader::new(Utc::now(), 0, Hash::zero(), MerkleRoot::calculate(&transactions), btclib::MIN_TARGET),
+            transactions,
+        );
+        let db = BlockchainDB::temporary().unwrap();
+        db.put_block(0, &genesis).unwrap();
+        let mut blockchain = Blockchain::new();
+        blockchain.add_block(genesis.clone()).unwrap();
+        blockchain.rebuild_utxos();
+
+        let path = std::env::temp_dir().join(format!("journal-{}", Uuid::new_v4()));
+        let journal = Journal::start(&path, &blockchain).unwrap();
+        let payment = spend(&key, &coinbase, 900);
+        journal.record(message(Message::NewTransaction(payment.clone()))).unwrap();
+        // a double spend paying less in fees
+        journal.record(message(Message::NewTransaction(spend(&key, &coinbase, 950)))).unwrap();
+        let transactions = vec![
+            Transaction::new(vec![], vec![output(&key, blockchain.calculate_block_reward() + 100)]),
+            payment,
+        ];
+        let mut block = Block::new(
+            BlockHeader::new(
+                Utc::now(),
+                0,
+                genesis.hash(),
+                MerkleRoot::calculate(&transactions),
+                btclib::MIN_TARGET,
+            ),
+            transactions,
+        );
+        while !block.header.mine(100_000) {}
+        journal.record(message(Message::SubmitTemplate(block.clone()))).unwrap();
+        journal.record(message(Message::NewBlock(block.clone()))).unwrap();
+        journal.record(message(Message::FetchAllBlocks)).unwrap();
+        drop(journal);
+
+        let mut out = vec![];
+        let replayed = replay(&db, &path, &mut out).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(replayed.block_height(), 2);
+        assert_eq!(replayed.tip_hash(), Some(block.hash()));
+        let out = String::from_utf8(out).unwrap();
+        // index, time, peer, kind, hash, verdict
+        let verdicts: Vec<&str> = out
+            .lines()
+            .filter(|line| line.contains("127.0.0.1:9001"))
+            .filter_map(|line| line.split(' ').nth(5))
+            .collect();
+        assert_eq!(verdicts, ["accepted", "rejected:", "accepted", "rejected:"]);
+        assert!(out.starts_with("0 "));
+        assert!(out.lines().last().unwrap().ends_with(&block.hash().to_string()));
+    }
+}
@@ -0,0 +1,25 @@
+//! The node as a library, for embedding nodes in other programs such
+//! as the `simnet` test harness. `main.rs` wires these into the node
+//! binary.
+pub mod access;
+pub mod addrbook;
+pub mod bootstrap;
+pub mod client;
+pub mod context;
+pub mod database;
+pub mod events;
+pub mod explorer;
+pub mod grpc;
+pub mod handler;
+pub mod hooks;
+pub mod http;
+pub mod journal;
+pub mod metrics;
+pub mod mining;
+pub mod network;
+pub mod relay;
+pub mod stratum;
+pub mod sync;
+pub mod traffic;
+pub mod util;
+pub mod watch;
@@ -1,14 +1,22 @@
-use anyhow::Result;
+use anyhow::{Result, anyhow};
 use argh::FromArgs;
+use btclib::crypto::{PrivateKey, PublicKey};
+use btclib::mining::Stats;
+use btclib::network::Services;
+use btclib::params::{ChainParams, Checkpoint, DifficultyAdjustment, Network};
+use btclib::util::Saveable;
+use ipnet::IpNet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use tokio::net::TcpListener;
+use tokio::sync::Semaphore;
 use tracing::info;
 use tracing_subscriber::{EnvFilter, fmt, prelude::*};
 
-mod context;
-mod database;
-mod handler;
-mod network;
-mod util;
+use node::access::parse_net;
+use node::database::{Backend, DbSecret};
+use node::network::Identity;
+use node::{addrbook, bootstrap, context, database, grpc, handler, hooks, http, journal, mining, stratum, sync, util, watch};
 
 fn init_tracing() -> Result<()> {
     // Create a formatting layer for tracing output with a compact format
@@ -38,11 +46,292 @@ struct Args {
     #[argh(option, default = "String::from(\"./blockchain_db\")")]
     /// blockchain database directory
     db_path: String,
+    #[argh(option)]
+    /// prune old blocks to keep block storage under this many megabytes
+    prune: Option<u64>,
+    #[argh(option, default = "288")]
+    /// number of recent blocks never pruned
+    prune_depth: u64,
+    #[argh(option, default = "Network::Mainnet")]
+    /// network to run on, mainnet or testnet
+    network: Network,
+    #[argh(option)]
+    /// extra checkpoint as <height>:<hash>, may be repeated
+    checkpoint: Vec<Checkpoint>,
+    #[argh(switch)]
+    /// verify all signatures, even below the last checkpoint
+    full_verify: bool,
+    #[argh(switch)]
+    /// zstd compress blocks written to the database
+    compress_blocks: bool,
+    #[argh(option)]
+    /// file whose contents the database encryption key is derived
+    /// from; without it the passphrase in GRAPHENO_DB_PASSPHRASE is
+    /// used, if set. A new database is encrypted, an existing one must
+    /// have been created with the same secret
+    db_key_file: Option<PathBuf>,
+    #[argh(option, default = "Backend::Sled")]
+    /// storage engine of the database, sled or rocksdb (needs the node
+    /// built with the rocksdb feature); an existing database must be
+    /// opened with the one that created it
+    db_backend: Backend,
+    #[argh(switch)]
+    /// keep the database in memory and write nothing to --db-path;
+    /// the chain is gone when the node stops
+    ephemeral: bool,
+    #[argh(option)]
+    /// serve the block explorer and event stream over HTTP on this
+    /// address, e.g. 127.0.0.1:8080
+    http: Option<String>,
+    #[argh(option)]
+    /// serve the gRPC API on this address, e.g. 127.0.0.1:50051
+    grpc: Option<std::net::SocketAddr>,
+    #[argh(switch)]
+    /// don't build block templates for miners
+    no_mining: bool,
+    #[argh(option)]
+    /// serve miners the line-delimited JSON mining protocol on this
+    /// address, e.g. 127.0.0.1:3333
+    stratum: Option<std::net::SocketAddr>,
+    #[argh(option)]
+    /// mine in the node, paying to this public key file; the stats are
+    /// logged and shown on the explorer's front page
+    mine: Option<PathBuf>,
+    #[argh(option, default = "1")]
+    /// number of threads mining with --mine
+    mining_threads: usize,
+    #[argh(switch)]
+    /// don't answer wallets' UTXO and transaction queries
+    no_wallet: bool,
+    #[argh(option, default = "125")]
+    /// most inbound connections served at once, a new one evicts a
+    /// peer or waits for one to close
+    max_connections: usize,
+    #[argh(option, default = "context::DEFAULT_MAX_OUTBOUND")]
+    /// most connections opened to other nodes
+    max_outbound: usize,
+    #[argh(option)]
+    /// hostname listing nodes to connect to when none are given, as
+    /// host or host:port, may be repeated
+    dns_seed: Vec<String>,
+    #[argh(switch)]
+    /// crawl the network and list the nodes that are up to those
+    /// asking, for running a DNS seed or bootstrap node
+    seed_mode: bool,
+    #[argh(option, from_str_fn(parse_net))]
+    /// only accept connections from this address or CIDR range, may be
+    /// repeated
+    allow: Vec<IpNet>,
+    #[argh(option, from_str_fn(parse_net))]
+    /// refuse connections from this address or CIDR range, may be
+    /// repeated
+    deny: Vec<IpNet>,
+    #[argh(option)]
+    /// private key proving this node to peers, created if missing,
+    /// defaults to identity.priv.cbor in the database directory
+    identity: Option<PathBuf>,
+    #[argh(option)]
+    /// public key PEM file of a trusted node, may be repeated
+    trusted_peer: Vec<PathBuf>,
+    #[argh(switch)]
+    /// only peer with nodes holding a trusted key
+    trusted_only: bool,
+    #[argh(option, default = "btclib::MAX_MEMPOOL_TRANSACTION_AGE")]
+    /// seconds a transaction may wait in the mempool before it is
+    /// dropped
+    mempool_max_age: u64,
+    #[argh(option, default = "context::DEFAULT_CLEANUP_INTERVAL")]
+    /// seconds between drops of old mempool transactions
+    mempool_cleanup_interval: u64,
+    #[argh(option, default = "DifficultyAdjustment::Interval")]
+    /// how the target is retargeted: interval, lwma[:N] or ema[:N]. All
+    /// nodes of a network must agree on it
+    difficulty_adjustment: DifficultyAdjustment,
+    #[argh(option)]
+    /// public key PEM file of the network's checkpoint authority, whose
+    /// signed checkpoints the node takes from peers
+    checkpoint_key: Option<PathBuf>,
+    #[argh(option)]
+    /// private key file of the checkpoint authority, to sign and announce
+    /// checkpoints from this node
+    checkpoint_signing_key: Option<PathBuf>,
+    #[argh(option, default = "10")]
+    /// blocks between two checkpoints signed with --checkpoint-signing-key
+    checkpoint_every: u64,
+    #[argh(option)]
+    /// append every message the node handles to this file, to be
+    /// replayed with the replay command
+    journal: Option<PathBuf>,
+    #[argh(option)]
+    /// TOML file of commands and webhooks to run on new blocks and on
+    /// payments to watched addresses
+    hooks: Option<PathBuf>,
+    #[argh(option)]
+    /// index the full history of this address, served by the gRPC
+    /// GetHistory call and the explorer, may be repeated
+    watch: Vec<String>,
+    #[argh(subcommand)]
+    command: Option<Command>,
     #[argh(positional)]
     /// addresses of initial nodes
     nodes: Vec<String>,
 }
 
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum Command {
+    ExportBlocks(ExportBlocks),
+    ImportBlocks(ImportBlocks),
+    ExportSnapshot(ExportSnapshot),
+    LoadSnapshot(LoadSnapshot),
+    CompactDb(CompactDb),
+    CheckDb(CheckDb),
+    Backup(Backup),
+    Replay(Replay),
+}
+
+#[derive(FromArgs)]
+/// Write all blocks to a bootstrap file and exit
+#[argh(subcommand, name = "export-blocks")]
+struct ExportBlocks {
+    #[argh(positional)]
+    /// bootstrap file to write
+    file: String,
+}
+
+#[derive(FromArgs)]
+/// Append the blocks of a bootstrap file to the database and exit
+#[argh(subcommand, name = "import-blocks")]
+struct ImportBlocks {
+    #[argh(positional)]
+    /// bootstrap file to read
+    file: String,
+}
+
+#[derive(FromArgs)]
+/// Write a signed snapshot of the UTXO set and exit
+#[argh(subcommand, name = "export-snapshot")]
+struct ExportSnapshot {
+    #[argh(positional)]
+    /// snapshot file to write
+    file: String,
+    #[argh(option)]
+    /// private key file to sign the snapshot with
+    key: String,
+}
+
+#[derive(FromArgs)]
+/// Initialize an empty database from a snapshot and exit
+#[argh(subcommand, name = "load-snapshot")]
+struct LoadSnapshot {
+    #[argh(positional)]
+    /// snapshot file to read
+    file: String,
+    #[argh(option)]
+    /// public key file the snapshot must be signed with
+    trusted_key: String,
+}
+
+#[derive(FromArgs)]
+/// Recompress all stored blocks with zstd and exit
+#[argh(subcommand, name = "compact-db")]
+struct CompactDb {}
+
+#[derive(FromArgs)]
+/// Verify the checksum of every record in the database, report the
+/// corrupt ones and exit
+#[argh(subcommand, name = "check-db")]
+struct CheckDb {}
+
+#[derive(FromArgs)]
+/// Copy the database to a new directory, verify the copy and exit
+#[argh(subcommand, name = "backup")]
+struct Backup {
+    #[argh(positional)]
+    /// directory to create the copy in, must not exist or be empty
+    dest: PathBuf,
+    #[argh(option)]
+    /// back up a running node through its gRPC API on this address
+    /// instead of opening the database
+    grpc: Option<std::net::SocketAddr>,
+}
+
+#[derive(FromArgs)]
+/// Feed a journal back through validation from the blocks in the
+/// database, print every decision and exit
+#[argh(subcommand, name = "replay")]
+struct Replay {
+    #[argh(positional)]
+    /// journal written with --journal
+    journal: PathBuf,
+}
+
+/// The node's identity key, generated on first start
+fn load_identity(path: &Path) -> Result<PrivateKey> {
+    if path.exists() {
+        return PrivateKey::load_from_file(path)
+            .map_err(|e| anyhow!("Error reading identity key {}: {}", path.display(), e));
+    }
+    let key = PrivateKey::new_key();
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    key.save_to_file(path)
+        .map_err(|e| anyhow!("Error saving identity key {}: {}", path.display(), e))?;
+    info!("created identity key {}", path.display());
+    Ok(key)
+}
+
+async fn run_command(
+    backend: Backend,
+    db_path: &str,
+    secret: Option<&DbSecret>,
+    command: Command,
+    params: &ChainParams,
+) -> Result<()> {
+    let db = database::BlockchainDB::open_with(backend, db_path, secret)?;
+    match command {
+        Command::ExportBlocks(cmd) => {
+            bootstrap::export_blocks(&db, &cmd.file)?;
+        }
+        Command::ImportBlocks(cmd) => {
+            bootstrap::import_blocks(&db, &cmd.file, params)?;
+        }
+        Command::ExportSnapshot(cmd) => {
+            let key = PrivateKey::load_from_file(&cmd.key)
+                .map_err(|e| anyhow!("Error reading private key: {}", e))?;
+            bootstrap::export_snapshot(&db, &cmd.file, &key, params).await?;
+        }
+        Command::LoadSnapshot(cmd) => {
+            let key = PublicKey::load_from_file(&cmd.trusted_key)
+                .map_err(|e| anyhow!("Error reading public key: {}", e))?;
+            bootstrap::load_snapshot(&db, &cmd.file, &key)?;
+        }
+        Command::CompactDb(_) => {
+            let (before, after) = db.compact_blocks()?;
+            println!("Compacted block storage from {} to {} bytes", before, after);
+        }
+        Command::CheckDb(_) => {
+            let report = db.check()?;
+            for record in &report.corrupt {
+                println!("{} {}: {}", record.tree, record.key, record.error);
+            }
+            println!("Checked {} records, {} corrupt", report.records, report.corrupt.len());
+            if !report.corrupt.is_empty() {
+                return Err(anyhow!("the database has corrupt records"));
+            }
+        }
+        Command::Backup(cmd) => {
+            let report = db.backup(&cmd.dest)?.verify()?;
+            println!("Backed up {} records, {} blocks to {}", report.records, report.height, cmd.dest.display());
+        }
+        Command::Replay(cmd) => {
+            journal::replay(&db, &cmd.journal, &mut std::io::stdout().lock())?;
+        }
+    }
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     init_tracing()?;
@@ -54,8 +343,162 @@ async fn main() -> Result<()> {
     let db_path = args.db_path;
     let nodes = args.nodes;
 
+    let secret = DbSecret::from_env(args.db_key_file.as_deref())?;
+    if args.ephemeral && args.command.is_some() {
+        return Err(anyhow!("subcommands work on a database on disk, they can't be used with --ephemeral"));
+    }
+    if args.ephemeral && secret.is_some() {
+        return Err(anyhow!("an --ephemeral database is never written to disk, it can't be encrypted"));
+    }
+    // a running node holds the lock on its database, it backs up itself
+    if let Some(Command::Backup(Backup { dest, grpc: Some(addr) })) = &args.command {
+        let response = grpc::request_backup(*addr, dest).await?;
+        println!("Backed up {} records, {} blocks to {}", response.records, response.height, dest.display());
+        return Ok(());
+    }
+    let mut params = args
+        .network
+        .params()
+        .with_checkpoints(args.checkpoint)
+        .with_dns_seeds(args.dns_seed)
+        .with_difficulty_adjustment(args.difficulty_adjustment);
+    if let Some(command) = args.command {
+        return run_command(args.db_backend, &db_path, secret.as_ref(), command, &params).await;
+    }
+
+    let signing_key = args
+        .checkpoint_signing_key
+        .as_ref()
+        .map(|path| {
+            PrivateKey::load_from_file(path)
+                .map_err(|e| anyhow!("Error reading checkpoint signing key {}: {}", path.display(), e))
+        })
+        .transpose()?;
+    let checkpoint_key = match &args.checkpoint_key {
+        Some(path) => Some(
+            PublicKey::load_from_file(path)
+                .map_err(|e| anyhow!("Error reading checkpoint key {}: {}", path.display(), e))?,
+        ),
+        None => signing_key.as_ref().map(|key| key.public_key()),
+    };
+    if let (Some(signing), Some(key)) = (&signing_key, &checkpoint_key)
+        && signing.public_key() != *key
+    {
+        return Err(anyhow!("--checkpoint-signing-key doesn't match --checkpoint-key"));
+    }
+    if args.stratum.is_some() && args.no_mining {
+        return Err(anyhow!("--stratum builds block templates, it can't be used with --no-mining"));
+    }
+    if args.mine.is_some() && args.no_mining {
+        return Err(anyhow!("--mine builds block templates, it can't be used with --no-mining"));
+    }
+    if args.checkpoint_every == 0 {
+        return Err(anyhow!("--checkpoint-every must be at least 1 block"));
+    }
+
     // Initialize database and blockchain
-    let ctx = context::NodeContext::new(&db_path, &nodes).await?;
+    if let Some(key) = checkpoint_key {
+        params = params.with_checkpoint_key(key);
+    }
+    let mining_address = args
+        .mine
+        .as_ref()
+        .map(|path| {
+            PublicKey::load_from_file(path)
+                .map(|key| key.to_address_for(&params))
+                .map_err(|e| anyhow!("Error reading mining key {}: {}", path.display(), e))
+        })
+        .transpose()?;
+    let hooks = args
+        .hooks
+        .as_ref()
+        .map(|path| hooks::HooksConfig::load(path, &params))
+        .transpose()?;
+    let seeds = params.dns_seeds.clone();
+    info!("Running on {}", params.network);
+    let mut services = Services::NONE;
+    if args.prune.is_some() {
+        services |= Services::PRUNED;
+    }
+    if !args.no_mining {
+        services |= Services::MINING;
+    }
+    if !args.no_wallet {
+        services |= Services::WALLET;
+    }
+    // an ephemeral node without --identity gets a new one each start
+    let key = match args.identity {
+        Some(path) => load_identity(&path)?,
+        None if args.ephemeral => PrivateKey::new_key(),
+        None => load_identity(&Path::new(&db_path).join("identity.priv.cbor"))?,
+    };
+    info!("node identity {}", key.public_key().to_hex());
+    let trusted = args
+        .trusted_peer
+        .iter()
+        .map(|path| {
+            PublicKey::load_from_file(path)
+                .map_err(|e| anyhow!("Error reading trusted peer key {}: {}", path.display(), e))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if args.trusted_only && trusted.is_empty() {
+        return Err(anyhow!("--trusted-only needs at least one --trusted-peer"));
+    }
+    if args.mempool_cleanup_interval == 0 {
+        return Err(anyhow!("--mempool-cleanup-interval must be at least 1 second"));
+    }
+    let identity = Identity {
+        key,
+        trusted,
+        trusted_only: args.trusted_only,
+    };
+    let db = if args.ephemeral {
+        info!("keeping the database in memory");
+        database::BlockchainDB::temporary()?
+    } else {
+        info!("opening database at {db_path}");
+        database::BlockchainDB::open_with(args.db_backend, &db_path, secret.as_ref())?
+    };
+    let db = db
+        .with_block_compression(args.compress_blocks)
+        .with_max_mempool_age(args.mempool_max_age);
+    drop(secret);
+    let mut ctx = context::NodeContext::new(db, params, args.full_verify, services, identity)?;
+    if let Some(path) = &args.journal {
+        let started = journal::Journal::start(path, &*ctx.blockchain.read().await)?;
+        info!("recording handled messages to {}", path.display());
+        ctx.journal = Some(Arc::new(started));
+    }
+    if mining_address.is_some() {
+        ctx.mining = Some(Arc::new(Stats::new(args.mining_threads.max(1))));
+    }
+    // addresses the hooks watch get their history indexed too
+    let hook_watches = hooks.iter().flat_map(|hooks| hooks.watch.iter());
+    for address in args.watch.iter().chain(hook_watches) {
+        watch::watch(&ctx, address).await?;
+    }
+    ctx.max_outbound = args.max_outbound;
+    ctx.seed_mode = args.seed_mode;
+    for node in &nodes {
+        ctx.addresses.add(node.clone());
+    }
+    if nodes.is_empty() && !seeds.is_empty() {
+        for node in addrbook::resolve_seeds(&seeds).await {
+            ctx.addresses.add(node);
+        }
+        info!("DNS seeds listed {} nodes", ctx.addresses.addresses().len());
+    }
+    util::populate_connections(ctx.clone(), &ctx.addresses.addresses()).await?;
+    if args.seed_mode {
+        tokio::spawn(util::crawl(ctx.clone()));
+    }
+
+    for net in args.allow {
+        ctx.network.restrict(net, false);
+    }
+    for net in args.deny {
+        ctx.network.restrict(net, true);
+    }
 
     let addr = format!("0.0.0.0:{}", port);
     let listener = TcpListener::bind(&addr).await?;
@@ -64,11 +507,72 @@ async fn main() -> Result<()> {
     // Clone context for background tasks
     let ctx_cleanup = ctx.clone();
     let ctx_save = ctx.clone();
+    let ctx_backfill = ctx.clone();
 
     // start a task to periodically cleanup the mempool. Normally, you would want to keep and join the handle
-    tokio::spawn(util::cleanup(ctx_cleanup));
+    tokio::spawn(util::cleanup(ctx_cleanup, args.mempool_cleanup_interval));
+    // the checkpoint authority signs checkpoints as the chain grows
+    if let Some(key) = signing_key {
+        tokio::spawn(util::sign_checkpoints(ctx.clone(), key, args.checkpoint_every));
+    }
     // and a task to periodically save the blockchain
     tokio::spawn(util::save(ctx_save));
+    // and one to fetch the blocks below a snapshot, if started from one
+    tokio::spawn(util::backfill(ctx_backfill));
+
+    // and one to catch up with peers that are ahead of us
+    tokio::spawn(sync::sync(ctx.clone()));
+    // and, if enabled, one to prune old blocks
+    if let Some(megabytes) = args.prune {
+        info!(
+            "pruning block storage to {} MB, keeping the last {} blocks",
+            megabytes, args.prune_depth
+        );
+        tokio::spawn(util::prune(ctx.clone(), megabytes * 1024 * 1024, args.prune_depth));
+    }
+
+    // and one indexing the history of watched addresses
+    tokio::spawn(watch::run(ctx.clone()));
+
+    // and, if configured, one running hooks on chain events
+    if let Some(hooks) = hooks {
+        tokio::spawn(hooks::run(ctx.clone(), hooks));
+    }
+
+    // and, if enabled, the HTTP server
+    if let Some(http_addr) = args.http {
+        let ctx_http = ctx.clone();
+        tokio::spawn(async move {
+            if let Err(err) = http::serve(ctx_http, &http_addr).await {
+                tracing::error!("HTTP server exited: {err}");
+            }
+        });
+    }
+
+    // and, if enabled, the gRPC server
+    if let Some(grpc_addr) = args.grpc {
+        let ctx_grpc = ctx.clone();
+        tokio::spawn(async move {
+            if let Err(err) = grpc::serve(ctx_grpc, grpc_addr).await {
+                tracing::error!("gRPC server exited: {err}");
+            }
+        });
+    }
+
+    // and, if enabled, the mining protocol adapter
+    if let Some(stratum_addr) = args.stratum {
+        let ctx_stratum = ctx.clone();
+        tokio::spawn(async move {
+            if let Err(err) = stratum::serve(ctx_stratum, stratum_addr).await {
+                tracing::error!("mining protocol server exited: {err}");
+            }
+        });
+    }
+
+    // and, if enabled, the node's own miner
+    if let Some(address) = mining_address {
+        tokio::spawn(mining::run(ctx.clone(), address));
+    }
 
     // Spawn dispatcher once
     let dispatcher_ctx = ctx.clone();
@@ -78,13 +582,28 @@ async fn main() -> Result<()> {
         }
     });
 
+    // every connection holds a slot, past the limit new ones wait for
+    // one, making room by evicting a peer if any is worth less than a
+    // newcomer, see `NetworkHub::eviction_candidate`
+    let slots = Arc::new(Semaphore::new(args.max_connections));
     loop {
         let (socket, peer_addr) = listener.accept().await?;
-        let ctx_accept = ctx.clone();
-        tokio::spawn(async move {
-            if let Err(err) = handler::accept_peer(ctx_accept, socket, peer_addr).await {
-                tracing::warn!("failed to accept peer: {err}");
+        if !ctx.network.admits(peer_addr.ip()) {
+            tracing::debug!("refusing connection from {peer_addr}");
+            continue;
+        }
+        let slot = match slots.clone().try_acquire_owned() {
+            Ok(slot) => slot,
+            Err(_) => {
+                if let Some(peer_id) = ctx.network.eviction_candidate() {
+                    info!("at the connection limit, evicting {peer_id} for {peer_addr}");
+                    ctx.network.disconnect(&peer_id);
+                }
+                slots.clone().acquire_owned().await?
             }
-        });
+        };
+        if let Err(err) = handler::accept_peer(ctx.clone(), socket, peer_addr, false, Some(slot)).await {
+            tracing::warn!("failed to accept peer: {err}");
+        }
     }
 }
@@ -0,0 +1,166 @@
+//! Prometheus metrics at `/metrics`
+use crate::context::NodeContext;
+use crate::relay::Histogram;
+use crate::traffic::{Traffic, TrafficStats};
+use axum::Router;
+use axum::extract::State;
+use axum::http::header;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use std::fmt::Write;
+use std::sync::atomic::Ordering;
+
+pub fn router(ctx: NodeContext) -> Router {
+    Router::new().route("/metrics", get(metrics)).with_state(ctx)
+}
+
+async fn metrics(State(ctx): State<NodeContext>) -> impl IntoResponse {
+    let (height, mempool) = {
+        let blockchain = ctx.blockchain.read().await;
+        (blockchain.block_height(), blockchain.mempool().len())
+    };
+    let network = &ctx.network;
+    let mut out = String::new();
+    gauge(&mut out, "grapheno_height", "Blocks in the chain", height);
+    gauge(&mut out, "grapheno_mempool_transactions", "Transactions in the mempool", mempool as u64);
+    gauge(&mut out, "grapheno_peers", "Connected peers", network.peers.len() as u64);
+    let restore = ctx.mempool_restore;
+    gauge(
+        &mut out,
+        "grapheno_mempool_restored",
+        "Saved mempool transactions put back at startup",
+        restore.restored as u64,
+    );
+    let _ = writeln!(out, "# HELP grapheno_mempool_dropped Saved mempool transactions dropped at startup");
+    let _ = writeln!(out, "# TYPE grapheno_mempool_dropped gauge");
+    let _ = writeln!(out, "grapheno_mempool_dropped{{reason=\"expired\"}} {}", restore.expired);
+    let _ = writeln!(out, "grapheno_mempool_dropped{{reason=\"invalid\"}} {}", restore.invalid);
+    let stats = &ctx.mempool_stats;
+    let _ = writeln!(out, "# HELP grapheno_mempool_removed_total Transactions dropped from the mempool since startup");
+    let _ = writeln!(out, "# TYPE grapheno_mempool_removed_total counter");
+    for (reason, removed) in [
+        ("expired", &stats.expired),
+        ("descendant", &stats.descendants),
+        ("cleared", &stats.cleared),
+    ] {
+        let _ = writeln!(out, "grapheno_mempool_removed_total{{reason=\"{reason}\"}} {}", removed.load(Ordering::Relaxed));
+    }
+    counter(
+        &mut out,
+        "grapheno_mempool_unmarked_total",
+        "Unspent outputs released by mempool cleanups",
+        stats.unmarked.load(Ordering::Relaxed),
+    );
+    counter(
+        &mut out,
+        "grapheno_peer_disconnects_total",
+        "Peers dropped",
+        network.disconnects.load(Ordering::Relaxed),
+    );
+    counter(
+        &mut out,
+        "grapheno_send_failures_total",
+        "Messages that couldn't be sent to a peer",
+        network.send_failures.load(Ordering::Relaxed),
+    );
+    counter(
+        &mut out,
+        "grapheno_misbehaving_peers_total",
+        "Peers dropped for misbehaving",
+        network.misbehaving.load(Ordering::Relaxed),
+    );
+
+    let relay = &ctx.relay;
+    histogram(
+        &mut out,
+        "grapheno_block_validation_seconds",
+        "Time from first seeing a block to having validated it",
+        &relay.validation,
+    );
+    histogram(
+        &mut out,
+        "grapheno_block_relay_seconds",
+        "Time from first seeing a block to relaying it to peers",
+        &relay.relay,
+    );
+    if let Some((hash, timing)) = relay.last() {
+        let _ = writeln!(out, "# HELP grapheno_last_block_timestamp_seconds When the block seen last got through each stage");
+        let _ = writeln!(out, "# TYPE grapheno_last_block_timestamp_seconds gauge");
+        for (stage, time) in [
+            ("first_seen", Some(timing.first_seen)),
+            ("validated", timing.validated),
+            ("relayed", timing.relayed),
+        ] {
+            if let Some(time) = time {
+                let seconds = time.timestamp_micros() as f64 / 1e6;
+                let _ = writeln!(
+                    out,
+                    "grapheno_last_block_timestamp_seconds{{block=\"{hash}\",stage=\"{stage}\"}} {seconds}"
+                );
+            }
+        }
+    }
+    let _ = writeln!(out, "# HELP grapheno_compact_blocks_total Compact blocks received, by how they were filled in");
+    let _ = writeln!(out, "# TYPE grapheno_compact_blocks_total counter");
+    for (outcome, count) in [
+        ("mempool", &relay.compact_complete),
+        ("fetched", &relay.compact_fetched),
+        ("failed", &relay.compact_failed),
+    ] {
+        let _ = writeln!(out, "grapheno_compact_blocks_total{{outcome=\"{outcome}\"}} {}", count.load(Ordering::Relaxed));
+    }
+    counter(
+        &mut out,
+        "grapheno_compact_transactions_requested_total",
+        "Transactions asked of peers to fill in compact blocks",
+        relay.transactions_requested.load(Ordering::Relaxed),
+    );
+
+    let totals = network.traffic.lock().expect("traffic lock").clone();
+    let _ = writeln!(out, "# HELP grapheno_messages_total Messages exchanged with peers");
+    let _ = writeln!(out, "# TYPE grapheno_messages_total counter");
+    traffic(&mut out, "grapheno_messages_total", &totals, |traffic| traffic.messages);
+    let _ = writeln!(out, "# HELP grapheno_message_bytes_total Bytes exchanged with peers");
+    let _ = writeln!(out, "# TYPE grapheno_message_bytes_total counter");
+    traffic(&mut out, "grapheno_message_bytes_total", &totals, |traffic| traffic.bytes);
+
+    let _ = writeln!(out, "# HELP grapheno_peer_bytes_total Bytes exchanged with each connected peer");
+    let _ = writeln!(out, "# TYPE grapheno_peer_bytes_total counter");
+    for peer in network.peers.iter() {
+        let labels = format!("peer=\"{}\",", peer.key());
+        let (received, sent) = (peer.traffic.total_received(), peer.traffic.total_sent());
+        let _ = writeln!(out, "grapheno_peer_bytes_total{{{labels}direction=\"in\"}} {}", received.bytes);
+        let _ = writeln!(out, "grapheno_peer_bytes_total{{{labels}direction=\"out\"}} {}", sent.bytes);
+    }
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], out)
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}");
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} counter\n{name} {value}");
+}
+
+fn histogram(out: &mut String, name: &str, help: &str, histogram: &Histogram) {
+    let (buckets, count, sum) = histogram.snapshot();
+    let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} histogram");
+    for (bound, cumulative) in buckets {
+        let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {cumulative}");
+    }
+    let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}\n{name}_sum {sum}\n{name}_count {count}");
+}
+
+/// One sample per message type and direction
+fn traffic(out: &mut String, name: &str, stats: &TrafficStats, value: impl Fn(&Traffic) -> u64) {
+    for (direction, by_kind) in [("in", &stats.received), ("out", &stats.sent)] {
+        for (kind, traffic) in by_kind {
+            let _ = writeln!(
+                out,
+                "{name}{{direction=\"{direction}\",type=\"{kind}\"}} {}",
+                value(traffic)
+            );
+        }
+    }
+}
@@ -0,0 +1,143 @@
+//! Mining in the node itself, with `--mine`. Templates are built
+//! straight from the chain, rebuilt when the tip moves and refreshed
+//! with new mempool transactions now and then, and found blocks are
+//! accepted like any submitted one. The stats are logged and shown on
+//! the explorer's front page.
+use crate::context::NodeContext;
+use crate::handler::{DEFAULT_TTL, accept_block, broadcast_except, build_template};
+use btclib::events::ChainEvent;
+use btclib::mining::{Stats, Work};
+use btclib::network::{Envelope, Message};
+use btclib::types::Block;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use tokio::time::{Duration, interval};
+use tracing::{info, warn};
+
+/// Seconds between templates refreshed with the transactions that
+/// entered the mempool meanwhile
+const REFRESH_INTERVAL: u64 = 30;
+/// Seconds between the stats logged
+const STATS_INTERVAL: u64 = 30;
+
+/// Mine blocks paying to `address` on the threads counted by
+/// `ctx.mining`, until the node stops
+pub async fn run(ctx: NodeContext, address: String) {
+    let Some(stats) = ctx.mining.clone() else {
+        return;
+    };
+    let work = Arc::new(Work::default());
+    let _closing = Closing(work.clone());
+    let (found_sender, mut found) = mpsc::unbounded_channel();
+    for thread in 0..stats.threads() {
+        let sender = found_sender.clone();
+        work.spawn_thread(thread, stats.clone(), move |block| {
+            // the receiver only goes away with the node
+            let _ = sender.send(block);
+        });
+    }
+    info!("mining on {} threads, paying to {}", stats.threads(), address);
+    tokio::spawn(log_reports(stats.clone()));
+
+    let mut events = ctx.events.subscribe();
+    let mut refresh = interval(Duration::from_secs(REFRESH_INTERVAL));
+    loop {
+        tokio::select! {
+            _ = refresh.tick() => {}
+            event = events.recv() => match event {
+                Ok(ChainEvent::Transaction { .. }) => continue,
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => return,
+            },
+            Some(block) = found.recv() => submit(&ctx, &stats, block).await,
+        }
+        let template = build_template(&*ctx.blockchain.read().await, &address);
+        match template {
+            Ok(block) => {
+                work.set_template(block);
+                stats.template_fetched();
+            }
+            Err(e) => {
+                warn!("can't build a template to mine: {e}");
+                work.stop();
+            }
+        }
+    }
+}
+
+// stops the mining threads along with the task running them
+struct Closing(Arc<Work>);
+
+impl Drop for Closing {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+// add a block the threads found to the chain, telling peers if it
+// made it onto the main chain
+async fn submit(ctx: &NodeContext, stats: &Stats, block: Block) {
+    let hash = block.hash();
+    match accept_block(ctx, &block).await {
+        Ok(()) if ctx.blockchain.read().await.height_of(&hash).is_some() => {
+            info!("mined block {hash}, broadcasting");
+            stats.block_accepted();
+            let gossip = Envelope::new(ctx.network.self_id.clone(), DEFAULT_TTL, Message::NewBlock(block));
+            broadcast_except(ctx, None, gossip);
+        }
+        Ok(()) => {
+            info!("mined block {hash} went stale");
+            stats.block_stale();
+        }
+        Err(e) => {
+            warn!("mined block {hash} rejected: {e}");
+            stats.block_stale();
+        }
+    }
+}
+
+async fn log_reports(stats: Arc<Stats>) {
+    let mut previous = vec![];
+    let mut ticker = interval(Duration::from_secs(STATS_INTERVAL));
+    // the first tick is immediate, there is nothing to report yet
+    ticker.tick().await;
+    let mut last = Instant::now();
+    loop {
+        ticker.tick().await;
+        let report = stats.report(&mut previous, last.elapsed());
+        last = Instant::now();
+        info!("{}", report.summary());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::BlockchainDB;
+    use btclib::crypto::PrivateKey;
+    use btclib::params::ChainParams;
+
+    #[tokio::test]
+    async fn test_mines_onto_the_chain() {
+        let params = ChainParams::testnet();
+        let address = PrivateKey::new_key().public_key().to_address_for(&params);
+        let mut ctx = NodeContext::from_db(BlockchainDB::temporary().unwrap(), params, false).unwrap();
+        let stats = Arc::new(Stats::new(2));
+        ctx.mining = Some(stats.clone());
+        tokio::spawn(run(ctx.clone(), address.clone()));
+
+        tokio::time::timeout(Duration::from_secs(60), async {
+            while ctx.blockchain.read().await.block_height() < 2 {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let blockchain = ctx.blockchain.read().await;
+        let coinbase = &blockchain.block_at(1).unwrap().transactions[0];
+        assert_eq!(coinbase.outputs[0].address, address);
+        assert!(stats.current().accepted >= 2);
+    }
+}
@@ -1,24 +1,162 @@
-use btclib::network::Envelope;
+use crate::access::AccessList;
+use crate::traffic::TrafficStats;
+use btclib::crypto::{PrivateKey, PublicKey, Signature};
+use btclib::network::{
+    COMPACT_BLOCKS_PROTOCOL_VERSION, Envelope, Services, UTXO_HASH_PROTOCOL_VERSION, VersionInfo, WireFormat,
+    identity_digest,
+};
 use dashmap::DashMap;
 use lru::LruCache;
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
 use std::num::NonZeroUsize;
-use std::sync::Arc;
-use tokio::sync::{mpsc, Mutex};
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, Mutex, OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
 use tracing::{debug, warn};
 use uuid::Uuid;
 
 pub type PeerId = String;
 
+/// Misbehavior score at which a peer is disconnected
+pub const MISBEHAVIOR_THRESHOLD: u32 = 100;
+/// Inbound share of trusted peers, see `PEER_INBOUND_BUFFER`
+pub const TRUSTED_INBOUND_BUFFER: usize = 64;
+/// How long a peer's outbound queue may stay full before the peer is
+/// dropped. Messages to it are discarded meanwhile.
+pub const OUTBOUND_STALL_TIMEOUT: Duration = Duration::from_secs(30);
+/// A peer is only evicted for downloading far more than it sends once
+/// it got this many bytes from us...
+pub const EVICTION_MIN_SENT: u64 = 1024 * 1024;
+/// ...this many times what it sent, over at least `EVICTION_GRACE`
+pub const EVICTION_ASYMMETRY: f64 = 10.0;
+pub const EVICTION_GRACE: Duration = Duration::from_secs(60);
+/// Inbound peers kept by each of the eviction measures, see
+/// `NetworkHub::eviction_candidate`
+pub const EVICTION_PROTECTED: usize = 4;
+/// Messages from one peer waiting for the dispatcher at once. A peer
+/// sending faster than that waits on its own connection, leaving room
+/// in the inbound queue for everyone else.
+pub const PEER_INBOUND_BUFFER: usize = 16;
+
+/// A message waiting for the dispatcher, holding one of its peer's
+/// inbound slots
+pub type Inbound = (PeerId, Envelope, OwnedSemaphorePermit);
+
 pub struct PeerHandle {
     pub outbound: mpsc::Sender<Envelope>,
+    /// Set once the peer has completed the version handshake
+    pub version: Option<VersionInfo>,
+    pub sent_version: bool,
+    /// Whether the peer takes compact messages, shared with the task
+    /// writing to its connection
+    pub compact: Arc<AtomicBool>,
+    /// Grows with every protocol violation, see `NetworkHub::misbehaving`
+    pub misbehavior: u32,
+    /// The peer's free slots in the inbound queue
+    pub inbound: Arc<Semaphore>,
+    /// Since when the outbound queue has been full, if it is
+    pub stalled_since: Option<Instant>,
+    /// Sent in our version, for the peer to sign
+    pub challenge: [u8; 32],
+    /// The identity key the peer proved it holds
+    pub identity: Option<PublicKey>,
+    /// Whether that key is one of `Identity::trusted`
+    pub trusted: bool,
+    /// Where the connection comes from, for peers over TCP
+    pub addr: Option<SocketAddr>,
+    /// Whether we opened the connection
+    pub dialed: bool,
+    pub connected_at: Instant,
+    pub traffic: TrafficStats,
+    /// When the peer last sent us anything
+    pub last_message: Instant,
+    /// Sent an `AskDifference` relative to this height that we are
+    /// waiting on the answer to
+    pub ping_sent: Option<(Instant, u64)>,
+    /// Round trip of the last answered `AskDifference`
+    pub latency: Option<Duration>,
+    /// Blocks and transactions the peer brought us that we took
+    pub useful: u64,
+}
+
+impl PeerHandle {
+    pub fn new(outbound: mpsc::Sender<Envelope>, compact: Arc<AtomicBool>) -> Self {
+        Self {
+            outbound,
+            version: None,
+            sent_version: false,
+            compact,
+            misbehavior: 0,
+            inbound: Arc::new(Semaphore::new(PEER_INBOUND_BUFFER)),
+            stalled_since: None,
+            challenge: rand::random(),
+            identity: None,
+            trusted: false,
+            addr: None,
+            dialed: false,
+            connected_at: Instant::now(),
+            traffic: TrafficStats::default(),
+            last_message: Instant::now(),
+            ping_sent: None,
+            latency: None,
+            useful: 0,
+        }
+    }
+}
+
+/// The receiving end of a peer's outbound queue, drained by whatever
+/// carries messages to the peer
+pub struct PeerOutbox {
+    pub messages: mpsc::Receiver<Envelope>,
+    /// Whether the peer takes compact messages, once it completed the
+    /// handshake
+    pub compact: Arc<AtomicBool>,
+}
+
+/// The key a node proves itself with in the handshake, and the keys
+/// of the nodes it trusts
+pub struct Identity {
+    pub key: PrivateKey,
+    /// Trusted peers get a larger share of the inbound queue and
+    /// aren't disconnected for misbehaving
+    pub trusted: Vec<PublicKey>,
+    /// Disconnect nodes that don't prove a trusted key. Wallets and
+    /// miners never take part in the handshake and aren't affected.
+    pub trusted_only: bool,
+}
+
+impl Identity {
+    /// A fresh key trusting nobody, for nodes that don't keep one
+    pub fn ephemeral() -> Self {
+        Identity {
+            key: PrivateKey::new_key(),
+            trusted: vec![],
+            trusted_only: false,
+        }
+    }
 }
 
 pub struct NetworkHub {
     pub self_id: PeerId,
+    pub identity: Identity,
     pub peers: DashMap<PeerId, PeerHandle>,
-    pub inbound_tx: mpsc::Sender<(PeerId, Envelope)>,
-    pub inbound_rx: tokio::sync::Mutex<mpsc::Receiver<(PeerId, Envelope)>>,
+    pub inbound_tx: mpsc::Sender<Inbound>,
+    pub inbound_rx: tokio::sync::Mutex<mpsc::Receiver<Inbound>>,
     pub seen: tokio::sync::Mutex<LruCache<Uuid, ()>>,
+    /// Checked for every inbound connection
+    pub access: RwLock<AccessList>,
+    /// Traffic with all peers since the node started
+    pub traffic: std::sync::Mutex<TrafficStats>,
+    /// Peers dropped since the node started, for whatever reason
+    pub disconnects: AtomicU64,
+    /// Messages that couldn't be delivered to a peer
+    pub send_failures: AtomicU64,
+    /// Peers dropped for misbehaving
+    pub misbehaving: AtomicU64,
 }
 
 const INBOUND_BUFFER: usize = 128;
@@ -26,35 +164,378 @@ const SEEN_CAPACITY: usize = 4096;
 
 impl NetworkHub {
     pub fn new(self_id: PeerId) -> Arc<Self> {
+        Self::with_identity(self_id, Identity::ephemeral())
+    }
+
+    pub fn with_identity(self_id: PeerId, identity: Identity) -> Arc<Self> {
         let (inbound_tx, inbound_rx) = mpsc::channel(INBOUND_BUFFER);
         let seen_capacity = NonZeroUsize::new(SEEN_CAPACITY).expect("non-zero LRU size");
         Arc::new(Self {
             self_id,
+            identity,
             peers: DashMap::new(),
             inbound_tx,
             inbound_rx: Mutex::new(inbound_rx),
             seen: Mutex::new(LruCache::new(seen_capacity)),
+            access: RwLock::new(AccessList::default()),
+            traffic: std::sync::Mutex::new(TrafficStats::default()),
+            disconnects: AtomicU64::new(0),
+            send_failures: AtomicU64::new(0),
+            misbehaving: AtomicU64::new(0),
         })
     }
 
+    /// Whether a host may connect to us
+    pub fn admits(&self, ip: IpAddr) -> bool {
+        self.access.read().expect("access list lock").admits(ip)
+    }
+
+    /// Allow or deny a range from now on. Denying it drops the peers
+    /// connected from it, whichever side opened the connection.
+    pub fn restrict(&self, net: IpNet, deny: bool) -> bool {
+        let added = self.access.write().expect("access list lock").add(net, deny);
+        if deny {
+            let denied: Vec<PeerId> = self
+                .peers
+                .iter()
+                .filter(|p| p.addr.is_some_and(|addr| net.contains(&addr.ip().to_canonical())))
+                .map(|p| p.key().clone())
+                .collect();
+            for peer_id in denied {
+                debug!("peer {peer_id} is in denied range {net}");
+                self.disconnect(&peer_id);
+            }
+        }
+        added
+    }
+
+    /// Undo `restrict`, leaving connected peers alone
+    pub fn unrestrict(&self, net: IpNet, deny: bool) -> bool {
+        self.access.write().expect("access list lock").remove(net, deny)
+    }
+
+    /// Queue a message from a peer for the dispatcher, waiting while
+    /// the peer has `PEER_INBOUND_BUFFER` messages queued already.
+    /// Returns false if the peer is gone or nothing dispatches anymore.
+    pub async fn deliver(&self, peer_id: &str, env: Envelope) -> bool {
+        let Some(slots) = self.peers.get(peer_id).map(|entry| entry.inbound.clone()) else {
+            return false;
+        };
+        let Ok(slot) = slots.acquire_owned().await else {
+            return false;
+        };
+        self.inbound_tx.send((peer_id.to_string(), env, slot)).await.is_ok()
+    }
+
     pub async fn next_inbound(&self) -> Option<(PeerId, Envelope)> {
-        self.inbound_rx.lock().await.recv().await
+        // the peer's slot frees up as its message leaves the queue
+        let (peer_id, env, _slot) = self.inbound_rx.lock().await.recv().await?;
+        Some((peer_id, env))
     }
 
-    pub async fn send_to(&self, peer_id: &str, env: Envelope) {
-        if let Some(entry) = self.peers.get(peer_id) {
-            if let Err(err) = entry.value().outbound.send(env).await {
-                warn!("failed to send to {peer_id}: {err}");
+    /// Queue a message for a peer without waiting, so a peer that
+    /// doesn't keep up never holds up the node. While its queue is full
+    /// messages to it are dropped, and once it has been full for
+    /// `OUTBOUND_STALL_TIMEOUT` the peer is. Returns whether the
+    /// message was queued.
+    pub fn send_to(&self, peer_id: &str, env: Envelope) -> bool {
+        let Some(mut entry) = self.peers.get_mut(peer_id) else {
+            debug!("peer {peer_id} not found for send");
+            return false;
+        };
+        let stalled = match entry.outbound.try_send(env) {
+            Ok(()) => {
+                entry.stalled_since = None;
+                return true;
+            }
+            Err(TrySendError::Full(_)) => {
+                let since = *entry.stalled_since.get_or_insert_with(Instant::now);
+                since.elapsed() >= OUTBOUND_STALL_TIMEOUT
             }
+            // the queue only closes once its connection is gone
+            Err(TrySendError::Closed(_)) => true,
+        };
+        // the entry locks its part of the map, disconnecting needs it
+        drop(entry);
+        if stalled {
+            self.send_failed(peer_id, "outbound queue stalled or closed");
         } else {
-            debug!("peer {peer_id} not found for send");
+            debug!("outbound queue of {peer_id} full, dropping message");
+            self.send_failures.fetch_add(1, Ordering::Relaxed);
         }
+        false
+    }
+
+    /// Count a message that couldn't reach a peer and drop the peer,
+    /// its connection is of no use anymore
+    pub fn send_failed(&self, peer_id: &str, reason: &str) {
+        warn!("failed to send to {peer_id}: {reason}, disconnecting");
+        self.send_failures.fetch_add(1, Ordering::Relaxed);
+        self.disconnect(peer_id);
+    }
+
+    /// Forget a peer. Its queue closes, which ends the tasks serving
+    /// its connection. Returns false if it was already gone.
+    pub fn disconnect(&self, peer_id: &str) -> bool {
+        if self.peers.remove(peer_id).is_none() {
+            return false;
+        }
+        debug!("peer {peer_id} disconnected");
+        self.disconnects.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// Count a message to or from a peer, `bytes` as on the wire
+    pub fn record_traffic(&self, peer_id: &str, kind: &'static str, bytes: usize, sent: bool) {
+        if let Some(mut entry) = self.peers.get_mut(peer_id) {
+            entry.traffic.record(kind, bytes, sent);
+            if !sent {
+                entry.last_message = Instant::now();
+            }
+        }
+        self.traffic.lock().expect("traffic lock").record(kind, bytes, sent);
+    }
+
+    /// Note that we asked a peer how far it is from `height`, see
+    /// `pong`
+    pub fn ping(&self, peer_id: &str, height: u64) {
+        if let Some(mut entry) = self.peers.get_mut(peer_id) {
+            entry.ping_sent.get_or_insert_with(|| (Instant::now(), height));
+        }
+    }
+
+    /// A peer answered how far it is from the height we asked about,
+    /// which times the round trip to it and tells us if it got ahead
+    /// of what it announced, e.g. while we couldn't hear its blocks
+    pub fn pong(&self, peer_id: &str, difference: i32) {
+        let Some(mut entry) = self.peers.get_mut(peer_id) else {
+            return;
+        };
+        let Some((sent, asked)) = entry.ping_sent.take() else {
+            return;
+        };
+        entry.latency = Some(sent.elapsed());
+        if let Some(version) = entry.version.as_mut()
+            && let Some(height) = asked.checked_add_signed(difference.into())
+        {
+            version.height = version.height.max(height);
+        }
+    }
+
+    /// Credit a peer with a block or transaction we took from it
+    pub fn useful(&self, peer_id: &str) {
+        if let Some(mut entry) = self.peers.get_mut(peer_id) {
+            entry.useful += 1;
+        }
+    }
+
+    /// The inbound peer to drop to make room for a new connection.
+    /// One that has been downloading far more than it sends goes
+    /// first. Otherwise the `EVICTION_PROTECTED` most useful, fastest
+    /// and most recently heard from peers are kept, as an attacker
+    /// can hardly beat honest peers at all three, and the one silent
+    /// the longest of the rest goes. Trusted peers, connections we
+    /// opened and those younger than `EVICTION_GRACE` are kept.
+    pub fn eviction_candidate(&self) -> Option<PeerId> {
+        struct Candidate {
+            peer_id: PeerId,
+            asymmetry: Option<f64>,
+            useful: u64,
+            latency: Option<Duration>,
+            last_message: Instant,
+        }
+        let mut candidates: Vec<Candidate> = self
+            .peers
+            .iter()
+            .filter(|p| !p.dialed && !p.trusted && p.connected_at.elapsed() >= EVICTION_GRACE)
+            .map(|p| Candidate {
+                peer_id: p.key().clone(),
+                asymmetry: (p.traffic.total_sent().bytes >= EVICTION_MIN_SENT)
+                    .then(|| p.traffic.asymmetry())
+                    .filter(|asymmetry| *asymmetry >= EVICTION_ASYMMETRY),
+                useful: p.useful,
+                latency: p.latency,
+                last_message: p.last_message,
+            })
+            .collect();
+
+        let lopsided = candidates
+            .iter()
+            .filter_map(|c| Some((c, c.asymmetry?)))
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+        if let Some((candidate, _)) = lopsided {
+            return Some(candidate.peer_id.clone());
+        }
+
+        // each sort puts the peers to keep last
+        candidates.sort_by_key(|c| c.useful);
+        candidates.truncate(candidates.len().saturating_sub(EVICTION_PROTECTED));
+        // unmeasured peers count as the slowest
+        candidates.sort_by_key(|c| std::cmp::Reverse(c.latency.unwrap_or(Duration::MAX)));
+        candidates.truncate(candidates.len().saturating_sub(EVICTION_PROTECTED));
+        candidates.sort_by_key(|c| c.last_message);
+        candidates.truncate(candidates.len().saturating_sub(EVICTION_PROTECTED));
+        candidates.first().map(|c| c.peer_id.clone())
+    }
+
+    /// Add to a peer's misbehavior score, disconnecting it once the
+    /// score reaches `MISBEHAVIOR_THRESHOLD`. Returns true if it was
+    /// disconnected.
+    pub fn misbehaving(&self, peer_id: &str, score: u32, reason: &str) -> bool {
+        let (total, trusted) = match self.peers.get_mut(peer_id) {
+            Some(mut entry) => {
+                entry.misbehavior = entry.misbehavior.saturating_add(score);
+                (entry.misbehavior, entry.trusted)
+            }
+            None => return false,
+        };
+        warn!("peer {peer_id} misbehaving ({reason}), score {total}");
+        if total < MISBEHAVIOR_THRESHOLD || trusted {
+            return false;
+        }
+        self.misbehaving.fetch_add(1, Ordering::Relaxed);
+        self.disconnect(peer_id)
+    }
+
+    /// Addresses of the nodes we connected to, which unlike those of
+    /// inbound peers take connections
+    pub fn dialed_addresses(&self) -> Vec<String> {
+        self.peers
+            .iter()
+            .filter(|p| p.dialed)
+            .filter_map(|p| p.addr.map(|addr| addr.to_string()))
+            .collect()
+    }
+
+    /// Peers we connected to at any of the addresses
+    pub fn dialed_at(&self, addrs: &[SocketAddr]) -> Vec<PeerId> {
+        self.peers
+            .iter()
+            .filter(|p| p.dialed && p.addr.is_some_and(|addr| addrs.contains(&addr)))
+            .map(|p| p.key().clone())
+            .collect()
+    }
+
+    /// Connections we opened ourselves
+    pub fn outbound_count(&self) -> usize {
+        self.peers.iter().filter(|p| p.dialed).count()
     }
 
     pub fn peer_ids(&self) -> Vec<String> {
         self.peers.iter().map(|p| p.key().clone()).collect()
     }
 
+    /// Record the version a peer announced and switch to compact
+    /// messages if it supports them. Returns true if we still owe it
+    /// our own version.
+    pub fn record_version(&self, peer_id: &str, version: VersionInfo) -> bool {
+        match self.peers.get_mut(peer_id) {
+            Some(mut entry) => {
+                let compact = WireFormat::for_peer(version.protocol_version) == WireFormat::Compact;
+                entry.compact.store(compact, Ordering::Relaxed);
+                entry.version = Some(version);
+                !entry.sent_version
+            }
+            None => false,
+        }
+    }
+
+    pub fn mark_version_sent(&self, peer_id: &str) {
+        if let Some(mut entry) = self.peers.get_mut(peer_id) {
+            entry.sent_version = true;
+        }
+    }
+
+    /// The challenge we send a peer in our version
+    pub fn challenge(&self, peer_id: &str) -> Option<[u8; 32]> {
+        self.peers.get(peer_id).map(|entry| entry.challenge)
+    }
+
+    /// Our answer to a peer's challenge
+    pub fn prove_identity(&self, challenge: &[u8; 32]) -> (PublicKey, Signature) {
+        let key = &self.identity.key;
+        (key.public_key(), Signature::sign_output(&identity_digest(challenge), key))
+    }
+
+    /// Check a peer's answer to our challenge and remember its key.
+    /// Returns whether the key is trusted, or None if the signature
+    /// is wrong or the peer is gone.
+    pub fn authenticate(&self, peer_id: &str, key: PublicKey, signature: &Signature) -> Option<bool> {
+        let mut entry = self.peers.get_mut(peer_id)?;
+        if !signature.verify(&identity_digest(&entry.challenge), &key) {
+            return None;
+        }
+        let trusted = self.identity.trusted.contains(&key);
+        if trusted && !entry.trusted {
+            entry.inbound.add_permits(TRUSTED_INBOUND_BUFFER - PEER_INBOUND_BUFFER);
+        }
+        entry.identity = Some(key);
+        entry.trusted = trusted;
+        Some(trusted)
+    }
+
+    /// Whether blocks and transactions relayed by a peer are taken,
+    /// which with `trusted_only` needs a trusted key
+    pub fn admitted(&self, peer_id: &str) -> bool {
+        !self.identity.trusted_only || self.peers.get(peer_id).is_some_and(|entry| entry.trusted)
+    }
+
+    /// Whether new blocks are relayed to a peer as compact blocks
+    pub fn takes_compact_blocks(&self, peer_id: &str) -> bool {
+        self.peers.get(peer_id).is_some_and(|entry| {
+            entry
+                .version
+                .as_ref()
+                .is_some_and(|version| version.protocol_version >= COMPACT_BLOCKS_PROTOCOL_VERSION)
+        })
+    }
+
+    /// Whether our version may carry the UTXO set hash to a peer: it
+    /// reads it, or its version isn't known yet and ours goes out as
+    /// CBOR, which older peers read past it
+    pub fn takes_utxo_hash(&self, peer_id: &str) -> bool {
+        self.peers.get(peer_id).is_some_and(|entry| {
+            entry
+                .version
+                .as_ref()
+                .is_none_or(|version| version.protocol_version >= UTXO_HASH_PROTOCOL_VERSION)
+        })
+    }
+
+    /// Raise a peer's height past the one it announced in the
+    /// handshake, once it relays a block above it. Heights only go up,
+    /// as peers don't lose blocks they have.
+    pub fn update_height(&self, peer_id: &str, height: u64) {
+        if let Some(mut entry) = self.peers.get_mut(peer_id)
+            && let Some(version) = entry.version.as_mut()
+        {
+            version.height = version.height.max(height);
+        }
+    }
+
+    /// Peers that completed the handshake, with what they announced.
+    /// With `trusted_only`, only those that proved a trusted key.
+    pub fn handshaked_peers(&self) -> Vec<(PeerId, VersionInfo)> {
+        self.peers
+            .iter()
+            .filter(|p| p.trusted || !self.identity.trusted_only)
+            .filter_map(|p| p.version.clone().map(|version| (p.key().clone(), version)))
+            .collect()
+    }
+
+    /// Peers that completed the handshake and still serve the block
+    /// at the given height. Pruned peers only if no other one does,
+    /// they may have dropped it since the handshake.
+    pub fn peers_serving(&self, height: u64) -> Vec<String> {
+        let (pruned, full): (Vec<_>, Vec<_>) = self
+            .handshaked_peers()
+            .into_iter()
+            .filter(|(_, version)| version.lowest_block <= height && height < version.height)
+            .partition(|(_, version)| version.services.contains(Services::PRUNED));
+        let peers = if full.is_empty() { pruned } else { full };
+        peers.into_iter().map(|(peer_id, _)| peer_id).collect()
+    }
+
     /// Returns true if the id was not seen before.
     pub async fn track_if_new(&self, id: Uuid) -> bool {
         let mut seen = self.seen.lock().await;
@@ -67,3 +548,180 @@ impl NetworkHub {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use btclib::network::Message;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    fn add_peer(hub: &NetworkHub, peer_id: &str) -> mpsc::Receiver<Envelope> {
+        let (outbound, messages) = mpsc::channel(1);
+        hub.peers
+            .insert(peer_id.to_string(), PeerHandle::new(outbound, Arc::new(AtomicBool::new(false))));
+        messages
+    }
+
+    fn envelope() -> Envelope {
+        Envelope::new(String::new(), 0, Message::DiscoverNodes)
+    }
+
+    #[tokio::test]
+    async fn test_flooding_peer_waits_alone() {
+        let hub = NetworkHub::new("self".to_string());
+        let _flooding = add_peer(&hub, "flooding");
+        let _quiet = add_peer(&hub, "quiet");
+        for _ in 0..PEER_INBOUND_BUFFER {
+            assert!(hub.deliver("flooding", envelope()).await);
+        }
+        let blocked = timeout(Duration::from_millis(50), hub.deliver("flooding", envelope())).await;
+        assert!(blocked.is_err(), "a peer can't queue more than its share");
+        assert!(hub.deliver("quiet", envelope()).await);
+
+        // taking a message off the queue frees a slot
+        assert_eq!(hub.next_inbound().await.unwrap().0, "flooding");
+        assert!(hub.deliver("flooding", envelope()).await);
+        assert!(!hub.deliver("unknown", envelope()).await);
+    }
+
+    #[tokio::test]
+    async fn test_stalled_peer_dropped() {
+        let hub = NetworkHub::new("self".to_string());
+        let mut slow = add_peer(&hub, "slow");
+        assert!(hub.send_to("slow", envelope()));
+        // its queue holds one message, the next ones are dropped
+        assert!(!hub.send_to("slow", envelope()));
+        assert!(hub.peers.contains_key("slow"));
+        assert_eq!(hub.send_failures.load(Ordering::Relaxed), 1);
+
+        // reading frees the queue and forgives the stall
+        slow.recv().await.unwrap();
+        assert!(hub.send_to("slow", envelope()));
+        assert!(hub.peers.get("slow").unwrap().stalled_since.is_none());
+
+        assert!(!hub.send_to("slow", envelope()));
+        hub.peers.get_mut("slow").unwrap().stalled_since = Some(Instant::now() - OUTBOUND_STALL_TIMEOUT);
+        assert!(!hub.send_to("slow", envelope()));
+        assert!(!hub.peers.contains_key("slow"));
+        assert_eq!(hub.disconnects.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_denying_drops_connected_peers() {
+        let hub = NetworkHub::new("self".to_string());
+        for (peer_id, addr) in [("a", "10.0.0.5:9000"), ("b", "192.0.2.1:9000")] {
+            let _messages = add_peer(&hub, peer_id);
+            hub.peers.get_mut(peer_id).unwrap().addr = Some(addr.parse().unwrap());
+        }
+        let _simulated = add_peer(&hub, "c");
+        assert!(hub.restrict("10.0.0.0/24".parse().unwrap(), true));
+        assert!(!hub.peers.contains_key("a"));
+        assert!(hub.peers.contains_key("b") && hub.peers.contains_key("c"));
+        assert!(!hub.admits("10.0.0.7".parse().unwrap()));
+        assert!(hub.unrestrict("10.0.0.0/24".parse().unwrap(), true));
+        assert!(hub.admits("10.0.0.7".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_peer_heights_only_rise() {
+        let hub = NetworkHub::new("self".to_string());
+        let _messages = add_peer(&hub, "a");
+        let version = VersionInfo {
+            protocol_version: btclib::network::PROTOCOL_VERSION,
+            height: 5,
+            lowest_block: 0,
+            services: Default::default(),
+            challenge: None,
+            utxo_set_hash: None,
+        };
+        hub.record_version("a", version);
+        let height = || hub.peers.get("a").unwrap().version.as_ref().unwrap().height;
+
+        hub.update_height("a", 3);
+        assert_eq!(height(), 5);
+        // the answer is relative to the height asked about
+        hub.ping("a", 4);
+        hub.pong("a", 3);
+        assert_eq!(height(), 7);
+        assert!(hub.peers.get("a").unwrap().latency.is_some());
+        // unasked answers are ignored
+        hub.pong("a", 10);
+        assert_eq!(height(), 7);
+        hub.ping("a", 7);
+        hub.pong("a", -2);
+        assert_eq!(height(), 7);
+    }
+
+    #[test]
+    fn test_dialed_at() {
+        let hub = NetworkHub::new("self".to_string());
+        let addr: SocketAddr = "192.0.2.1:9000".parse().unwrap();
+        for (peer_id, dialed) in [("dialed", true), ("inbound", false)] {
+            let _messages = add_peer(&hub, peer_id);
+            let mut peer = hub.peers.get_mut(peer_id).unwrap();
+            peer.addr = Some(addr);
+            peer.dialed = dialed;
+        }
+        assert_eq!(hub.dialed_at(&[addr]), ["dialed"]);
+        assert!(hub.dialed_at(&["192.0.2.1:9001".parse().unwrap()]).is_empty());
+    }
+
+    #[test]
+    fn test_evicts_most_lopsided_downloader() {
+        let hub = NetworkHub::new("self".to_string());
+        let mut receivers = vec![];
+        for peer_id in ["leech", "worse", "balanced", "new", "dialed"] {
+            receivers.push(add_peer(&hub, peer_id));
+            let mut peer = hub.peers.get_mut(peer_id).unwrap();
+            peer.connected_at = Instant::now() - EVICTION_GRACE;
+            peer.dialed = peer_id == "dialed";
+        }
+        hub.peers.get_mut("new").unwrap().connected_at = Instant::now();
+        let sent = EVICTION_MIN_SENT as usize;
+        let received = [("leech", sent / 20), ("worse", sent / 50), ("balanced", sent), ("new", 0), ("dialed", 0)];
+        for (peer_id, received) in received {
+            hub.record_traffic(peer_id, "NewBlock", sent, true);
+            hub.record_traffic(peer_id, "FetchBlock", received, false);
+        }
+        assert_eq!(hub.eviction_candidate().as_deref(), Some("worse"));
+        hub.disconnect("worse");
+        assert_eq!(hub.eviction_candidate().as_deref(), Some("leech"));
+        hub.disconnect("leech");
+        // too few left to drop any
+        assert_eq!(hub.eviction_candidate(), None);
+
+        let totals = hub.traffic.lock().unwrap();
+        assert_eq!(totals.sent["NewBlock"].messages, 5);
+        assert_eq!(totals.total_received().messages, 5);
+    }
+
+    #[test]
+    fn test_evicts_longest_silent_unprotected() {
+        let hub = NetworkHub::new("self".to_string());
+        let mut receivers = vec![];
+        let start = Instant::now() - EVICTION_GRACE * 10;
+        // peer i was last heard from i seconds after the start
+        for i in 0..3 * EVICTION_PROTECTED + 2 {
+            let peer_id = format!("peer{i}");
+            receivers.push(add_peer(&hub, &peer_id));
+            let mut peer = hub.peers.get_mut(&peer_id).unwrap();
+            peer.connected_at = start;
+            peer.last_message = start + Duration::from_secs(i as u64);
+        }
+        // the quietest peers are kept for being useful and fast
+        for i in 0..EVICTION_PROTECTED {
+            hub.peers.get_mut(&format!("peer{i}")).unwrap().useful = 10;
+            let mut fast = hub.peers.get_mut(&format!("peer{}", EVICTION_PROTECTED + i)).unwrap();
+            fast.latency = Some(Duration::from_millis(5));
+        }
+        let mut silent = 2 * EVICTION_PROTECTED;
+        assert_eq!(hub.eviction_candidate(), Some(format!("peer{silent}")));
+        hub.peers.get_mut(&format!("peer{silent}")).unwrap().dialed = true;
+        silent += 1;
+        assert_eq!(hub.eviction_candidate(), Some(format!("peer{silent}")));
+        hub.peers.get_mut(&format!("peer{silent}")).unwrap().trusted = true;
+        // the rest are the most recently heard from
+        assert_eq!(hub.eviction_candidate(), None);
+    }
+}
@@ -0,0 +1,217 @@
+//! How fast blocks get through the node: when each was first seen,
+//! validated and relayed, and the compact blocks waiting for the
+//! transactions the mempool lacked
+use crate::network::PeerId;
+use btclib::network::Envelope;
+use btclib::sha256::Hash;
+use btclib::types::{BlockHeader, Transaction};
+use chrono::{DateTime, Utc};
+use lru::LruCache;
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Blocks whose timings are kept
+const TIMED_BLOCKS: usize = 64;
+/// Compact blocks waiting for transactions at once, the oldest is
+/// given up on past that
+const MAX_PENDING: usize = 16;
+/// Upper bounds of the latency histogram buckets, in seconds
+pub const LATENCY_BUCKETS: [f64; 10] = [0.001, 0.005, 0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0, 30.0];
+
+/// When a block got through each stage, keyed by its header hash,
+/// which unlike the block hash is known before a compact block is
+/// filled in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BlockTiming {
+    pub first_seen: DateTime<Utc>,
+    pub validated: Option<DateTime<Utc>>,
+    pub relayed: Option<DateTime<Utc>>,
+}
+
+/// Latencies counted into `LATENCY_BUCKETS`, for a Prometheus histogram
+#[derive(Default)]
+pub struct Histogram {
+    // the last one counts those past every bucket
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, seconds: f64) {
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add((seconds * 1e6) as u64, Ordering::Relaxed);
+    }
+
+    /// Cumulative count at or below each bucket's bound, the total
+    /// count and the sum in seconds
+    pub fn snapshot(&self) -> (Vec<(f64, u64)>, u64, f64) {
+        let mut cumulative = 0;
+        let mut buckets = vec![];
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            cumulative += count.load(Ordering::Relaxed);
+            buckets.push((*bound, cumulative));
+        }
+        let count = cumulative + self.buckets[LATENCY_BUCKETS.len()].load(Ordering::Relaxed);
+        (buckets, count, self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6)
+    }
+}
+
+/// A compact block some transactions of which the mempool lacked,
+/// asked for from the peer that relayed it
+pub struct PendingBlock {
+    pub header: BlockHeader,
+    /// Coinbase first, None where a transaction is missing
+    pub transactions: Vec<Option<Transaction>>,
+    pub peer: PeerId,
+    /// What the block came in, to relay it on under the same id
+    pub envelope: Envelope,
+}
+
+pub struct Relay {
+    timings: Mutex<LruCache<Hash, BlockTiming>>,
+    pending: Mutex<LruCache<Hash, PendingBlock>>,
+    /// From first seen to validated
+    pub validation: Histogram,
+    /// From first seen to relayed to peers
+    pub relay: Histogram,
+    /// Compact blocks filled in from the mempool alone
+    pub compact_complete: AtomicU64,
+    /// Compact blocks filled in after asking for transactions
+    pub compact_fetched: AtomicU64,
+    /// Compact blocks answered with the wrong transactions
+    pub compact_failed: AtomicU64,
+    /// Transactions asked for to fill in compact blocks
+    pub transactions_requested: AtomicU64,
+}
+
+impl Default for Relay {
+    fn default() -> Self {
+        Self {
+            timings: Mutex::new(LruCache::new(NonZeroUsize::new(TIMED_BLOCKS).unwrap())),
+            pending: Mutex::new(LruCache::new(NonZeroUsize::new(MAX_PENDING).unwrap())),
+            validation: Histogram::default(),
+            relay: Histogram::default(),
+            compact_complete: AtomicU64::new(0),
+            compact_fetched: AtomicU64::new(0),
+            compact_failed: AtomicU64::new(0),
+            transactions_requested: AtomicU64::new(0),
+        }
+    }
+}
+
+fn seconds_since(start: DateTime<Utc>, end: DateTime<Utc>) -> f64 {
+    (end - start).num_microseconds().unwrap_or(i64::MAX).max(0) as f64 / 1e6
+}
+
+impl Relay {
+    /// Note a block arriving, unless it was seen before
+    pub fn seen(&self, header_hash: Hash) {
+        let mut timings = self.timings.lock().expect("timings lock");
+        if !timings.contains(&header_hash) {
+            timings.put(
+                header_hash,
+                BlockTiming {
+                    first_seen: Utc::now(),
+                    validated: None,
+                    relayed: None,
+                },
+            );
+        }
+    }
+
+    pub fn validated(&self, header_hash: Hash) {
+        let mut timings = self.timings.lock().expect("timings lock");
+        if let Some(timing) = timings.get_mut(&header_hash)
+            && timing.validated.is_none()
+        {
+            let now = Utc::now();
+            timing.validated = Some(now);
+            self.validation.observe(seconds_since(timing.first_seen, now));
+        }
+    }
+
+    pub fn relayed(&self, header_hash: Hash) {
+        let mut timings = self.timings.lock().expect("timings lock");
+        if let Some(timing) = timings.get_mut(&header_hash)
+            && timing.relayed.is_none()
+        {
+            let now = Utc::now();
+            timing.relayed = Some(now);
+            self.relay.observe(seconds_since(timing.first_seen, now));
+        }
+    }
+
+    pub fn timing(&self, header_hash: &Hash) -> Option<BlockTiming> {
+        self.timings.lock().expect("timings lock").peek(header_hash).copied()
+    }
+
+    /// The block seen last, with its timing
+    pub fn last(&self) -> Option<(Hash, BlockTiming)> {
+        let timings = self.timings.lock().expect("timings lock");
+        timings.iter().max_by_key(|(_, timing)| timing.first_seen).map(|(hash, timing)| (*hash, *timing))
+    }
+
+    /// Keep a compact block until its peer sends the transactions
+    /// asked for
+    pub fn wait_for_transactions(&self, header_hash: Hash, block: PendingBlock) {
+        let missing = block.transactions.iter().filter(|transaction| transaction.is_none()).count();
+        self.transactions_requested.fetch_add(missing as u64, Ordering::Relaxed);
+        self.pending.lock().expect("pending lock").put(header_hash, block);
+    }
+
+    /// The compact block waiting for transactions from `peer`, if any
+    pub fn take_pending(&self, header_hash: &Hash, peer: &str) -> Option<PendingBlock> {
+        let mut pending = self.pending.lock().expect("pending lock");
+        match pending.peek(header_hash) {
+            Some(block) if block.peer == peer => pending.pop(header_hash),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_buckets() {
+        let histogram = Histogram::default();
+        for seconds in [0.0005, 0.02, 0.02, 60.0] {
+            histogram.observe(seconds);
+        }
+        let (buckets, count, sum) = histogram.snapshot();
+        assert_eq!(buckets[0], (0.001, 1));
+        assert_eq!(buckets[2], (0.01, 1));
+        assert_eq!(buckets[3], (0.05, 3));
+        assert_eq!(buckets.last(), Some(&(30.0, 3)));
+        assert_eq!(count, 4);
+        assert!((sum - 60.0405).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_block_timings() {
+        let relay = Relay::default();
+        let hash = Hash::hash_bytes(b"header");
+        // stages of blocks never seen aren't timed
+        relay.validated(hash);
+        assert_eq!(relay.timing(&hash), None);
+
+        relay.seen(hash);
+        let first_seen = relay.timing(&hash).unwrap().first_seen;
+        relay.seen(hash);
+        relay.validated(hash);
+        relay.relayed(hash);
+        let timing = relay.timing(&hash).unwrap();
+        assert_eq!(timing.first_seen, first_seen);
+        assert!(timing.validated.is_some_and(|validated| validated >= first_seen));
+        assert!(timing.relayed >= timing.validated);
+        assert_eq!(relay.validation.snapshot().1, 1);
+        assert_eq!(relay.relay.snapshot().1, 1);
+        assert_eq!(relay.last(), Some((hash, timing)));
+    }
+}
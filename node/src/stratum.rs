@@ -0,0 +1,340 @@
+//! Line-delimited JSON mining protocol after Stratum, so third-party
+//! miner frameworks can mine on the node with little glue. A miner
+//! subscribes with the address to pay, is notified of a job whenever
+//! there is new work and submits the nonces it finds:
+//!
+//! ```text
+//! > {"id": 1, "method": "subscribe", "params": {"address": "mx..."}}
+//! < {"id": 1, "result": true, "error": null}
+//! < {"id": null, "method": "notify", "params": {"job_id": "1", "height": 10, "clean_jobs": true, "header": {...}}}
+//! > {"id": 2, "method": "submit", "params": {"job_id": "1", "nonce": 81723}}
+//! < {"id": 2, "result": true, "error": null}
+//! ```
+//!
+//! A header is mined when the SHA-256 of its CBOR encoding is at most
+//! its target. Every connection gets templates of its own, with their
+//! own coinbase, so miners never search the same space.
+use crate::context::NodeContext;
+use crate::handler::{DEFAULT_TTL, accept_block, broadcast_except, build_template};
+use anyhow::Result;
+use btclib::events::ChainEvent;
+use btclib::network::{Envelope, Message};
+use btclib::types::{Block, BlockHeader};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::{Duration, interval};
+use tracing::{debug, info, warn};
+
+/// Seconds between jobs refreshed with the transactions that entered
+/// the mempool meanwhile
+const REFRESH_INTERVAL: u64 = 30;
+/// Jobs of a connection still taking submissions, older ones are
+/// forgotten
+const MAX_JOBS: usize = 4;
+
+/// Work sent to a miner in a `notify`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Job {
+    pub job_id: String,
+    /// Height of the block mined
+    pub height: u64,
+    /// Whether the previous jobs build on a replaced tip, their
+    /// blocks would be stale
+    pub clean_jobs: bool,
+    /// Header to find a nonce for
+    pub header: BlockHeader,
+}
+
+#[derive(Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Deserialize)]
+struct Subscription {
+    address: String,
+}
+
+#[derive(Deserialize)]
+struct Submission {
+    job_id: String,
+    nonce: u64,
+    /// Set by miners rolling the timestamp once out of nonces
+    timestamp: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+struct Response {
+    id: Value,
+    result: Value,
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Notification<'a> {
+    id: Value,
+    method: &'static str,
+    params: &'a Job,
+}
+
+/// A miner's connection
+struct Session {
+    ctx: NodeContext,
+    writer: OwnedWriteHalf,
+    /// Address the coinbase pays, once subscribed
+    address: Option<String>,
+    /// Recent jobs and their blocks, newest last
+    jobs: VecDeque<(String, Block)>,
+    next_job: u64,
+}
+
+impl Session {
+    async fn send<T: Serialize>(&mut self, message: &T) -> Result<()> {
+        let mut line = serde_json::to_vec(message)?;
+        line.push(b'\n');
+        self.writer.write_all(&line).await?;
+        Ok(())
+    }
+
+    async fn respond(&mut self, id: Value, result: std::result::Result<Value, String>) -> Result<()> {
+        let response = match result {
+            Ok(result) => Response { id, result, error: None },
+            Err(error) => Response { id, result: Value::Null, error: Some(error) },
+        };
+        self.send(&response).await
+    }
+
+    /// Build a job on the current tip and send it, dropping the older
+    /// ones when `clean` is set
+    async fn notify(&mut self, clean: bool) -> Result<()> {
+        let Some(address) = &self.address else {
+            return Ok(());
+        };
+        let (height, block) = {
+            let blockchain = self.ctx.blockchain.read().await;
+            (blockchain.block_height(), build_template(&blockchain, address)?)
+        };
+        self.next_job += 1;
+        let job = Job {
+            job_id: self.next_job.to_string(),
+            height,
+            clean_jobs: clean,
+            header: block.header.clone(),
+        };
+        if clean {
+            self.jobs.clear();
+        }
+        if self.jobs.len() == MAX_JOBS {
+            self.jobs.pop_front();
+        }
+        self.jobs.push_back((job.job_id.clone(), block));
+        self.send(&Notification {
+            id: Value::Null,
+            method: "notify",
+            params: &job,
+        })
+        .await
+    }
+
+    async fn subscribe(&mut self, params: Value) -> std::result::Result<Value, String> {
+        let subscription: Subscription = serde_json::from_value(params).map_err(|e| e.to_string())?;
+        let blockchain = self.ctx.blockchain.read().await;
+        // the miner's reward would be unspendable on this chain
+        btclib::address::Address::parse_for(&subscription.address, blockchain.params())
+            .map_err(|e| format!("can't pay to {}: {}", subscription.address, e))?;
+        self.address = Some(subscription.address);
+        Ok(Value::Bool(true))
+    }
+
+    async fn submit(&mut self, params: Value) -> std::result::Result<Value, String> {
+        let submission: Submission = serde_json::from_value(params).map_err(|e| e.to_string())?;
+        let mut block = self
+            .jobs
+            .iter()
+            .find(|(id, _)| *id == submission.job_id)
+            .map(|(_, block)| block.clone())
+            .ok_or_else(|| format!("unknown or stale job {}", submission.job_id))?;
+        block.header.nonce = submission.nonce;
+        if let Some(timestamp) = submission.timestamp {
+            block.header.timestamp = timestamp;
+        }
+        if !block.header.hash().matches_target(block.header.target) {
+            return Err("hash above target".to_string());
+        }
+        accept_block(&self.ctx, &block)
+            .await
+            .map_err(|e| format!("block rejected: {}", e))?;
+        info!("block {} mined over the mining protocol, broadcasting", block.hash());
+        let gossip = Envelope::new(self.ctx.network.self_id.clone(), DEFAULT_TTL, Message::NewBlock(block));
+        broadcast_except(&self.ctx, None, gossip);
+        Ok(Value::Bool(true))
+    }
+
+    async fn handle(&mut self, line: &str) -> Result<()> {
+        let request: Request = match serde_json::from_str(line) {
+            Ok(request) => request,
+            Err(e) => return self.respond(Value::Null, Err(format!("bad request: {}", e))).await,
+        };
+        match request.method.as_str() {
+            "subscribe" => {
+                let result = self.subscribe(request.params).await;
+                let subscribed = result.is_ok();
+                self.respond(request.id, result).await?;
+                if subscribed {
+                    self.notify(true).await?;
+                }
+                Ok(())
+            }
+            "submit" => {
+                let result = self.submit(request.params).await;
+                self.respond(request.id, result).await
+            }
+            method => {
+                let error = format!("unknown method {:?}", method);
+                self.respond(request.id, Err(error)).await
+            }
+        }
+    }
+}
+
+async fn serve_miner(ctx: NodeContext, socket: TcpStream) -> Result<()> {
+    let mut events = ctx.events.subscribe();
+    let (reader, writer) = socket.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    let mut session = Session {
+        ctx,
+        writer,
+        address: None,
+        jobs: VecDeque::new(),
+        next_job: 0,
+    };
+    let mut refresh = interval(Duration::from_secs(REFRESH_INTERVAL));
+    refresh.tick().await;
+    loop {
+        tokio::select! {
+            line = lines.next_line() => match line? {
+                Some(line) if line.trim().is_empty() => {}
+                Some(line) => session.handle(&line).await?,
+                None => return Ok(()),
+            },
+            event = events.recv() => match event {
+                Ok(ChainEvent::Block { .. } | ChainEvent::Reorg { .. }) => session.notify(true).await?,
+                Ok(ChainEvent::Transaction { .. }) => {}
+                Err(RecvError::Lagged(_)) => session.notify(true).await?,
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            _ = refresh.tick() => session.notify(false).await?,
+        }
+    }
+}
+
+/// Take miners on `listener` until it fails
+pub async fn accept_miners(ctx: NodeContext, listener: TcpListener) -> Result<()> {
+    loop {
+        let (socket, miner_addr) = listener.accept().await?;
+        if !ctx.network.admits(miner_addr.ip()) {
+            debug!("refusing miner {miner_addr}");
+            continue;
+        }
+        debug!("miner {miner_addr} connected");
+        let ctx = ctx.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_miner(ctx, socket).await {
+                warn!("miner {miner_addr} disconnected: {e}");
+            }
+        });
+    }
+}
+
+/// Serve the mining protocol until the listener fails
+pub async fn serve(ctx: NodeContext, addr: SocketAddr) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("Mining protocol on {}", addr);
+    accept_miners(ctx, listener).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::BlockchainDB;
+    use btclib::crypto::PrivateKey;
+    use btclib::params::ChainParams;
+    use tokio::io::Lines;
+    use tokio::net::tcp::OwnedReadHalf;
+
+    async fn receive(lines: &mut Lines<BufReader<OwnedReadHalf>>) -> Value {
+        let line = tokio::time::timeout(Duration::from_secs(5), lines.next_line())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+
+    async fn request(writer: &mut OwnedWriteHalf, request: Value) {
+        let mut line = serde_json::to_vec(&request).unwrap();
+        line.push(b'\n');
+        writer.write_all(&line).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_and_submit() {
+        let params = ChainParams::testnet();
+        let address = PrivateKey::new_key().public_key().to_address_for(&params);
+        let ctx = NodeContext::from_db(BlockchainDB::temporary().unwrap(), params, false).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(accept_miners(ctx.clone(), listener));
+        let (reader, mut writer) = TcpStream::connect(addr).await.unwrap().into_split();
+        let mut lines = BufReader::new(reader).lines();
+
+        request(&mut writer, serde_json::json!({"id": 1, "method": "mine"})).await;
+        let response = receive(&mut lines).await;
+        assert_eq!(response["id"], 1);
+        assert_eq!(response["error"], "unknown method \"mine\"");
+
+        let subscribe = serde_json::json!({"id": 2, "method": "subscribe", "params": {"address": "nonsense"}});
+        request(&mut writer, subscribe).await;
+        assert!(receive(&mut lines).await["error"].is_string());
+
+        let subscribe = serde_json::json!({"id": 3, "method": "subscribe", "params": {"address": address}});
+        request(&mut writer, subscribe).await;
+        assert_eq!(receive(&mut lines).await["result"], true);
+        let notification = receive(&mut lines).await;
+        assert_eq!(notification["method"], "notify");
+        let job: Job = serde_json::from_value(notification["params"].clone()).unwrap();
+        assert_eq!(job.height, 0);
+        assert!(job.clean_jobs);
+
+        let mut header = job.header;
+        while !header.mine(100_000) {}
+        let submit = serde_json::json!({
+            "id": 4,
+            "method": "submit",
+            "params": {"job_id": job.job_id, "nonce": header.nonce, "timestamp": header.timestamp},
+        });
+        request(&mut writer, submit.clone()).await;
+        let response = receive(&mut lines).await;
+        assert_eq!(response["result"], true, "{}", response);
+        assert_eq!(ctx.blockchain.read().await.block_height(), 1);
+
+        // the new tip replaces the job
+        let notification = receive(&mut lines).await;
+        let next: Job = serde_json::from_value(notification["params"].clone()).unwrap();
+        assert_eq!(next.height, 1);
+        assert_eq!(Some(next.header.prev_block_hash), ctx.blockchain.read().await.tip_hash());
+        request(&mut writer, submit).await;
+        assert_eq!(receive(&mut lines).await["error"], format!("unknown or stale job {}", job.job_id));
+    }
+}
@@ -0,0 +1,93 @@
+use crate::context::NodeContext;
+use crate::handler;
+use btclib::network::{Envelope, Message};
+use btclib::types::Block;
+use std::time::Instant;
+use tokio::time;
+use tracing::{debug, info, warn};
+
+mod scheduler;
+
+pub use scheduler::{DownloadScheduler, RANGE_SIZE};
+
+// how often peers are probed and new ranges requested
+const SYNC_INTERVAL_SECS: u64 = 2;
+
+/// Catch up with the peers that are ahead of us, downloading block
+/// ranges from several of them in parallel
+pub async fn sync(ctx: NodeContext) {
+    let mut interval = time::interval(time::Duration::from_secs(SYNC_INTERVAL_SECS));
+    loop {
+        interval.tick().await;
+        let peers = ctx.network.handshaked_peers();
+
+        // peer heights come from the handshake and the blocks they
+        // relay. Asking how far they are from our tip times how quickly
+        // they answer, and catches up on blocks we missed from them.
+        let height = ctx.blockchain.read().await.block_height();
+        for (peer, _) in &peers {
+            let env = Envelope::new(
+                ctx.network.self_id.clone(),
+                handler::DEFAULT_TTL,
+                Message::AskDifference(height as u32),
+            );
+            if ctx.network.send_to(peer, env) {
+                ctx.network.ping(peer, height);
+            }
+        }
+
+        let requests = ctx
+            .downloads
+            .lock()
+            .await
+            .schedule(height, &peers, Instant::now());
+        if !requests.is_empty() {
+            debug!("requesting {} block ranges above height {}", requests.len(), height);
+        }
+        for (peer, start) in requests {
+            let env = Envelope::new(
+                ctx.network.self_id.clone(),
+                handler::DEFAULT_TTL,
+                Message::FetchBlocks(start, RANGE_SIZE),
+            );
+            ctx.network.send_to(&peer, env);
+        }
+    }
+}
+
+/// Hand a downloaded range to the scheduler and apply every range
+/// that now connects to the tip, in order
+pub async fn receive_blocks(ctx: &NodeContext, peer: &str, start: u64, blocks: Vec<Block>) {
+    let mut downloads = ctx.downloads.lock().await;
+    if !downloads.receive(peer, start, blocks) {
+        debug!("ignoring unrequested blocks from {} at height {}", peer, start);
+        return;
+    }
+
+    let mut blockchain = ctx.blockchain.write().await;
+    let before = blockchain.block_height();
+    while let Some((from, start, blocks)) = downloads.take_ready(blockchain.block_height()) {
+        for (offset, block) in blocks.into_iter().enumerate() {
+            let height = start + offset as u64;
+            if height < blockchain.block_height() {
+                continue;
+            }
+            let old_tip = blockchain.tip_hash();
+            let update = match blockchain.submit_block(block) {
+                Ok(update) => update,
+                Err(e) => {
+                    warn!("block {} from {} rejected during sync: {}", height, from, e);
+                    downloads.reject(start, &from);
+                    break;
+                }
+            };
+            ctx.network.useful(&from);
+            for event in handler::chain_events(&blockchain, old_tip, &update) {
+                ctx.publish(event);
+            }
+        }
+    }
+    if blockchain.block_height() > before {
+        info!("synced to height {}", blockchain.block_height());
+    }
+}
@@ -0,0 +1,241 @@
+use crate::network::PeerId;
+use btclib::network::{Services, VersionInfo};
+use btclib::types::Block;
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+/// Number of blocks in each requested range
+pub const RANGE_SIZE: u64 = 16;
+// ranges a single peer may have in flight at once
+const PEER_WINDOW: usize = 4;
+// ranges downloaded ahead of the chain tip, in flight or buffered
+const MAX_AHEAD: usize = 64;
+// requests not answered within this are handed to another peer
+const STALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct Request {
+    peer: PeerId,
+    sent: Instant,
+}
+
+/// Splits the blocks between the local tip and the best peer into
+/// fixed ranges and spreads them over the peers that can serve them.
+/// Ranges are identified by their index, range `i` covering heights
+/// `i * RANGE_SIZE .. (i + 1) * RANGE_SIZE`.
+#[derive(Default)]
+pub struct DownloadScheduler {
+    in_flight: HashMap<u64, Request>,
+    ready: BTreeMap<u64, (PeerId, Vec<Block>)>,
+    // ranges a peer failed to deliver, retried elsewhere if possible
+    stalled: HashMap<u64, PeerId>,
+}
+
+impl DownloadScheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decide which ranges to request next given the local chain
+    /// height and the peers that completed the handshake. Returns the
+    /// peer and start height of each request to send.
+    pub fn schedule(
+        &mut self,
+        height: u64,
+        peers: &[(PeerId, VersionInfo)],
+        now: Instant,
+    ) -> Vec<(PeerId, u64)> {
+        let first = height / RANGE_SIZE;
+        self.in_flight.retain(|index, _| *index >= first);
+        self.ready.retain(|index, _| *index >= first);
+        self.stalled.retain(|index, _| *index >= first);
+
+        let expired: Vec<u64> = self
+            .in_flight
+            .iter()
+            .filter(|(_, request)| now.duration_since(request.sent) >= STALL_TIMEOUT)
+            .map(|(index, _)| *index)
+            .collect();
+        for index in expired {
+            if let Some(request) = self.in_flight.remove(&index) {
+                self.stalled.insert(index, request.peer);
+            }
+        }
+
+        let best = peers.iter().map(|(_, version)| version.height).max().unwrap_or(0);
+        let mut load: HashMap<PeerId, usize> = HashMap::new();
+        for request in self.in_flight.values() {
+            *load.entry(request.peer.clone()).or_default() += 1;
+        }
+
+        let mut requests = Vec::new();
+        let mut index = first;
+        while index * RANGE_SIZE < best && self.in_flight.len() + self.ready.len() < MAX_AHEAD {
+            if self.in_flight.contains_key(&index) || self.ready.contains_key(&index) {
+                index += 1;
+                continue;
+            }
+            let start = index * RANGE_SIZE;
+            let stalled = self.stalled.get(&index);
+            let candidate = peers
+                .iter()
+                .filter(|(peer, version)| {
+                    version.lowest_block <= start
+                        && start < version.height
+                        && load.get(peer).copied().unwrap_or(0) < PEER_WINDOW
+                })
+                // least loaded first, the peer that stalled on this range
+                // last; pruned peers only when no other one has room, the
+                // range may be gone by the time they get the request
+                .min_by_key(|(peer, version)| {
+                    (
+                        stalled == Some(peer),
+                        version.services.contains(Services::PRUNED),
+                        load.get(peer).copied().unwrap_or(0),
+                    )
+                });
+            let Some((peer, _)) = candidate else {
+                // no peer can take more work right now
+                break;
+            };
+
+            *load.entry(peer.clone()).or_default() += 1;
+            self.in_flight.insert(
+                index,
+                Request {
+                    peer: peer.clone(),
+                    sent: now,
+                },
+            );
+            requests.push((peer.clone(), start));
+            index += 1;
+        }
+        requests
+    }
+
+    /// Accept the response to a range request. Returns false for
+    /// blocks nobody asked this peer for.
+    pub fn receive(&mut self, peer: &str, start: u64, blocks: Vec<Block>) -> bool {
+        let index = start / RANGE_SIZE;
+        if !start.is_multiple_of(RANGE_SIZE) || self.in_flight.get(&index).is_none_or(|r| r.peer != peer) {
+            return false;
+        }
+        self.in_flight.remove(&index);
+        if blocks.is_empty() {
+            // the peer doesn't have the range after all
+            self.stalled.insert(index, peer.to_string());
+        } else {
+            self.ready.insert(index, (peer.to_string(), blocks));
+        }
+        true
+    }
+
+    /// Take the downloaded range containing the given height, if any.
+    /// Returns the peer it came from, its start height and its blocks.
+    pub fn take_ready(&mut self, height: u64) -> Option<(PeerId, u64, Vec<Block>)> {
+        let index = height / RANGE_SIZE;
+        self.ready
+            .remove(&index)
+            .map(|(peer, blocks)| (peer, index * RANGE_SIZE, blocks))
+    }
+
+    /// Mark a range as badly served so it is requested again,
+    /// preferably from a different peer
+    pub fn reject(&mut self, start: u64, peer: &str) {
+        let index = start / RANGE_SIZE;
+        self.ready.remove(&index);
+        self.stalled.insert(index, peer.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use btclib::network::PROTOCOL_VERSION;
+    use btclib::testing::empty_block;
+
+    fn peer(name: &str, height: u64) -> (PeerId, VersionInfo) {
+        (
+            name.to_string(),
+            VersionInfo {
+                protocol_version: PROTOCOL_VERSION,
+                height,
+                lowest_block: 0,
+                services: Services::default(),
+                challenge: None,
+                utxo_set_hash: None,
+            },
+        )
+    }
+
+    fn pruned_peer(name: &str, height: u64) -> (PeerId, VersionInfo) {
+        let (name, mut version) = peer(name, height);
+        version.services = Services::PRUNED | Services::WALLET;
+        (name, version)
+    }
+
+    #[test]
+    fn test_ranges_are_spread_over_peers() {
+        let mut scheduler = DownloadScheduler::new();
+        let peers = [peer("a", 1000), peer("b", 1000)];
+        let requests = scheduler.schedule(0, &peers, Instant::now());
+
+        assert_eq!(requests.len(), PEER_WINDOW * 2);
+        let starts: Vec<u64> = requests.iter().map(|(_, start)| *start).collect();
+        let expected: Vec<u64> = (0..requests.len() as u64).map(|i| i * RANGE_SIZE).collect();
+        assert_eq!(starts, expected);
+        assert_eq!(requests.iter().filter(|(p, _)| p == "a").count(), PEER_WINDOW);
+
+        // nothing more until some of the requests are answered
+        assert!(scheduler.schedule(0, &peers, Instant::now()).is_empty());
+    }
+
+    #[test]
+    fn test_pruned_peers_asked_last() {
+        let mut scheduler = DownloadScheduler::new();
+        let peers = [pruned_peer("pruned", 1000), peer("full", 1000)];
+        let requests = scheduler.schedule(0, &peers, Instant::now());
+        let asked = |name: &str| requests.iter().filter(|(p, _)| p == name).count();
+        // the full peer's window fills up first
+        assert_eq!(asked("full"), PEER_WINDOW);
+        assert_eq!(asked("pruned"), PEER_WINDOW);
+        assert!(requests[..PEER_WINDOW].iter().all(|(p, _)| p == "full"));
+    }
+
+    #[test]
+    fn test_only_peers_that_have_the_range_are_asked() {
+        let mut scheduler = DownloadScheduler::new();
+        let requests = scheduler.schedule(0, &[peer("a", 20)], Instant::now());
+        assert_eq!(requests, vec![("a".to_string(), 0), ("a".to_string(), RANGE_SIZE)]);
+    }
+
+    #[test]
+    fn test_stalled_range_moves_to_another_peer() {
+        let mut scheduler = DownloadScheduler::new();
+        let now = Instant::now();
+        let requests = scheduler.schedule(0, &[peer("a", 10)], now);
+        assert_eq!(requests, vec![("a".to_string(), 0)]);
+
+        let later = now + STALL_TIMEOUT;
+        let requests = scheduler.schedule(0, &[peer("a", 10), peer("b", 10)], later);
+        assert_eq!(requests, vec![("b".to_string(), 0)]);
+        // the late answer from the stalled peer is ignored
+        assert!(!scheduler.receive("a", 0, vec![]));
+    }
+
+    #[test]
+    fn test_received_ranges_are_taken_in_order() {
+        let mut scheduler = DownloadScheduler::new();
+        scheduler.schedule(0, &[peer("a", 100)], Instant::now());
+        assert!(!scheduler.receive("b", 0, vec![empty_block(0)]));
+        assert!(scheduler.receive("a", RANGE_SIZE, vec![]));
+        assert!(scheduler.take_ready(0).is_none());
+
+        assert!(scheduler.receive("a", 0, vec![empty_block(0)]));
+        let (from, start, blocks) = scheduler.take_ready(3).unwrap();
+        assert_eq!((from.as_str(), start, blocks.len()), ("a", 0, 1));
+
+        // once range 0 is applied, the one that came back empty is next
+        let requests = scheduler.schedule(RANGE_SIZE, &[peer("a", 100)], Instant::now());
+        assert_eq!(requests[0], ("a".to_string(), RANGE_SIZE));
+    }
+}
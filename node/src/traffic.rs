@@ -0,0 +1,57 @@
+//! Messages and bytes exchanged with peers, by message type
+use std::collections::BTreeMap;
+
+/// Messages and bytes in one direction
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Traffic {
+    pub messages: u64,
+    pub bytes: u64,
+}
+
+impl Traffic {
+    fn add(&mut self, other: Traffic) {
+        self.messages += other.messages;
+        self.bytes += other.bytes;
+    }
+}
+
+/// Traffic both ways, keyed by `Message::kind`
+#[derive(Clone, Debug, Default)]
+pub struct TrafficStats {
+    pub received: BTreeMap<&'static str, Traffic>,
+    pub sent: BTreeMap<&'static str, Traffic>,
+}
+
+impl TrafficStats {
+    pub fn record(&mut self, kind: &'static str, bytes: usize, sent: bool) {
+        let by_kind = if sent { &mut self.sent } else { &mut self.received };
+        by_kind.entry(kind).or_default().add(Traffic {
+            messages: 1,
+            bytes: bytes as u64,
+        });
+    }
+
+    pub fn total_received(&self) -> Traffic {
+        total(&self.received)
+    }
+
+    pub fn total_sent(&self) -> Traffic {
+        total(&self.sent)
+    }
+
+    /// How many bytes we sent for every byte received, which is high
+    /// for peers that only download from us
+    pub fn asymmetry(&self) -> f64 {
+        let sent = self.total_sent().bytes as f64;
+        let received = self.total_received().bytes.max(1) as f64;
+        sent / received
+    }
+}
+
+fn total(by_kind: &BTreeMap<&'static str, Traffic>) -> Traffic {
+    let mut total = Traffic::default();
+    for traffic in by_kind.values() {
+        total.add(*traffic);
+    }
+    total
+}
@@ -1,49 +1,215 @@
+use std::path::PathBuf;
 use std::sync::Arc;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+use btclib::crypto::PrivateKey;
+use btclib::network::{Envelope, Message};
+use btclib::params::{Checkpoint, SignedCheckpoint};
 use btclib::types::Blockchain;
 use tokio::net::TcpStream;
 use tokio::sync::RwLock;
 use tokio::time;
 use tracing::{debug, error, info, warn};
 
+use crate::addrbook;
 use crate::context::NodeContext;
-use crate::database::BlockchainDB;
+use crate::database::{BackupReport, BlockchainDB};
 use crate::handler;
+use crate::journal::Record;
+use crate::network::PeerId;
 
+// how long dialing another node may take
+const CONNECT_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+/// Connect to another node and start serving the connection, returning
+/// the new peer's id
+pub async fn connect_peer(ctx: &NodeContext, node: &str) -> Result<PeerId> {
+    let stream = time::timeout(CONNECT_TIMEOUT, TcpStream::connect(node))
+        .await
+        .with_context(|| format!("timed out connecting to {node}"))?
+        .with_context(|| format!("failed to connect to {node}"))?;
+    let peer_addr = stream.peer_addr().with_context(|| format!("missing peer addr for {node}"))?;
+    handler::accept_peer(ctx.clone(), stream, peer_addr, true, None).await?;
+    Ok(peer_addr.to_string())
+}
+
+/// Connect to other nodes, stopping at `ctx.max_outbound` connections
 pub async fn populate_connections(ctx: NodeContext, nodes: &[String]) -> Result<()> {
     debug!("trying to connect to other nodes...");
     for node in nodes {
+        if ctx.network.outbound_count() >= ctx.max_outbound {
+            info!("at {} outbound connections, not connecting to {}", ctx.max_outbound, node);
+            continue;
+        }
         debug!("connecting to {}", node);
-        match TcpStream::connect(&node).await {
-            Ok(stream) => {
-                info!("connected to {}", node);
-                let peer_addr = match stream.peer_addr() {
-                    Ok(addr) => addr,
-                    Err(err) => {
-                        warn!("missing peer addr for {}: {err}", node);
-                        continue;
+        match connect_peer(&ctx, node).await {
+            Ok(_) => info!("connected to {}", node),
+            Err(err) => warn!("{:#}", err),
+        }
+    }
+    Ok(())
+}
+
+// how often a seed node probes the nodes it knows
+const CRAWL_INTERVAL: time::Duration = time::Duration::from_secs(60);
+
+/// Probe every known node now and then, learning about the nodes they
+/// know, so a seed node lists only nodes that are up
+pub async fn crawl(ctx: NodeContext) {
+    let mut interval = time::interval(CRAWL_INTERVAL);
+    loop {
+        interval.tick().await;
+        let addresses = ctx.addresses.addresses();
+        debug!("probing {} known nodes", addresses.len());
+        for addr in addresses {
+            match addrbook::probe(&addr, &ctx.network.self_id).await {
+                Ok((version, listed)) => {
+                    ctx.addresses.mark_good(&addr, version.height);
+                    for node in listed {
+                        if ctx.addresses.add(node.clone()) {
+                            debug!("learned about {node} from {addr}");
+                        }
                     }
-                };
-                let ctx_clone = ctx.clone();
-                tokio::spawn(async move {
-                    let _ = handler::accept_peer(ctx_clone, stream, peer_addr).await;
-                });
+                }
+                Err(e) => {
+                    debug!("probe of {addr} failed: {e}");
+                    ctx.addresses.mark_failed(&addr);
+                }
             }
-            Err(err) => warn!("failed to connect to {}: {}", node, err),
         }
+        info!("{} nodes known, {} up", ctx.addresses.addresses().len(), ctx.addresses.good(usize::MAX).len());
     }
-    Ok(())
 }
 
-pub async fn cleanup(ctx: NodeContext) {
-    let mut interval = time::interval(time::Duration::from_secs(30));
+/// Drop mempool transactions past the maximum age every `seconds`
+pub async fn cleanup(ctx: NodeContext, seconds: u64) {
+    let mut interval = time::interval(time::Duration::from_secs(seconds));
     loop {
         interval.tick().await;
         debug!("cleaning the mempool from old transactions");
         let mut blockchain = ctx.blockchain.write().await;
-        blockchain.cleanup_mempool();
+        ctx.record(Record::Cleanup);
+        let cleanup = blockchain.cleanup_mempool();
+        drop(blockchain);
+        if cleanup.expired > 0 {
+            info!(
+                "mempool cleanup dropped {} expired transactions and {} descendants, unmarking {} outputs",
+                cleanup.expired, cleanup.descendants, cleanup.unmarked
+            );
+        }
+        ctx.mempool_stats.add_cleanup(cleanup);
+    }
+}
+
+/// How many blocks a block must be buried under before the checkpoint
+/// authority signs it
+pub const CHECKPOINT_DEPTH: u64 = 6;
+
+/// Sign a checkpoint every `interval` blocks once it is buried deep
+/// enough, and announce it to the network
+pub async fn sign_checkpoints(ctx: NodeContext, key: PrivateKey, interval: u64) {
+    let mut ticker = time::interval(time::Duration::from_secs(10));
+    loop {
+        ticker.tick().await;
+        let signed = {
+            let blockchain = ctx.blockchain.read().await;
+            let Some(buried) = blockchain.block_height().checked_sub(CHECKPOINT_DEPTH + 1) else {
+                continue;
+            };
+            let height = buried - buried % interval;
+            if blockchain.params().checkpoints.iter().any(|c| c.height == height) {
+                continue;
+            }
+            let Some(block) = blockchain.block_at(height) else {
+                continue;
+            };
+            let checkpoint = Checkpoint { height, hash: block.hash() };
+            SignedCheckpoint::sign(checkpoint, &key)
+        };
+        match handler::accept_checkpoint(&ctx, signed.clone()).await {
+            Ok(_) => {
+                info!("signed checkpoint {}", signed.checkpoint);
+                let msg = Message::Checkpoint(signed);
+                let env = Envelope::new(ctx.network.self_id.clone(), handler::DEFAULT_TTL, msg);
+                handler::broadcast_except(&ctx, None, env);
+            }
+            Err(e) => error!("failed to take our own checkpoint {}: {e}", signed.checkpoint),
+        }
+    }
+}
+
+// number of blocks requested per backfill round
+const BACKFILL_BATCH: u64 = 16;
+
+/// Fetch the blocks below the snapshot a node was started from,
+/// newest first, rotating through the connected peers
+pub async fn backfill(ctx: NodeContext) {
+    let mut interval = time::interval(time::Duration::from_secs(5));
+    let mut round = 0usize;
+    loop {
+        interval.tick().await;
+        let Some(next) = ctx.blockchain.read().await.next_backfill_height() else {
+            return;
+        };
+        // pruned peers advertise that they can't serve old blocks
+        let peers = ctx.network.peers_serving(next);
+        if peers.is_empty() {
+            debug!("no peers to backfill from");
+            continue;
+        }
+        let peer = &peers[round % peers.len()];
+        round += 1;
+
+        debug!("requesting blocks up to height {} from {}", next, peer);
+        for height in (next.saturating_sub(BACKFILL_BATCH - 1)..=next).rev() {
+            let env = Envelope::new(
+                ctx.network.self_id.clone(),
+                handler::DEFAULT_TTL,
+                Message::FetchBlock(height as usize),
+            );
+            ctx.network.send_to(peer, env);
+        }
+    }
+}
+
+// how often the block storage is checked against the prune target
+const PRUNE_INTERVAL_SECS: u64 = 60;
+
+/// Keep the stored block bodies under `target_bytes` by pruning the
+/// oldest ones, never touching the last `depth` blocks
+pub async fn prune(ctx: NodeContext, target_bytes: u64, depth: u64) {
+    let mut interval = time::interval(time::Duration::from_secs(PRUNE_INTERVAL_SECS));
+    loop {
+        interval.tick().await;
+        if let Err(e) = prune_blocks(&ctx, target_bytes, depth).await {
+            error!("error pruning blocks: {}", e);
+        }
+    }
+}
+
+async fn prune_blocks(ctx: &NodeContext, target_bytes: u64, depth: u64) -> Result<()> {
+    let mut size = ctx.db.block_storage_size()?;
+    if size <= target_bytes {
+        return Ok(());
+    }
+
+    let mut blockchain = ctx.blockchain.write().await;
+    let max_height = blockchain.block_height().saturating_sub(depth);
+    let mut height = blockchain.base_height();
+    while height < max_height && size > target_bytes {
+        size -= ctx.db.block_size(height)?.unwrap_or(0);
+        height += 1;
+    }
+    if height <= blockchain.base_height() {
+        debug!("block storage above prune target but no blocks are deep enough");
+        return Ok(());
     }
+
+    blockchain.prune(height)?;
+    ctx.db.save_blockchain(&blockchain)?;
+    let pruned = ctx.db.prune_blocks(height)?;
+    info!("pruned {} blocks below height {}", pruned, height);
+    Ok(())
 }
 
 pub async fn save(ctx: NodeContext) {
@@ -56,14 +222,32 @@ pub async fn save(ctx: NodeContext) {
     }
 }
 
+/// Save the chain and copy the database to `dest`, holding the chain
+/// so no save runs until the copy is done, then read the copy back
+pub async fn backup(ctx: &NodeContext, dest: PathBuf) -> Result<BackupReport> {
+    let blockchain = ctx.blockchain.write().await;
+    ctx.db.save_blockchain(&blockchain)?;
+    let db = ctx.db.clone();
+    let copy = {
+        let dest = dest.clone();
+        tokio::task::spawn_blocking(move || db.backup(&dest)).await??
+    };
+    drop(blockchain);
+    let report = tokio::task::spawn_blocking(move || copy.verify()).await??;
+    info!("backed up {} records, {} blocks to {}", report.records, report.height, dest.display());
+    Ok(report)
+}
+
 pub async fn save_blockchain(
     db: &Arc<BlockchainDB>,
     blockchain: &Arc<RwLock<Blockchain>>,
 ) -> Result<()> {
     debug!("saving blockchain to database...");
 
-    let blockchain = blockchain.read().await;
-    db.save_blockchain(&*blockchain)?;
+    db.save_blockchain(&*blockchain.read().await)?;
+    // sync to disk without holding the chain lock, a crash before
+    // this only loses the changes since the last save
+    db.flush_async().await?;
     debug!("blockchain saved to database");
     Ok(())
 }
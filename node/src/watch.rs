@@ -0,0 +1,251 @@
+//! Addresses operators asked the node to watch, with `--watch` or over
+//! gRPC, and the index of every confirmed transaction touching them.
+//! The index follows the main chain: blocks are indexed as they are
+//! connected and taken out again when a reorg disconnects them, so a
+//! history is complete without scanning the chain, also once the
+//! blocks are pruned. Addresses watched later get the stored blocks
+//! scanned for them once.
+use crate::context::NodeContext;
+use crate::database::{BlockchainDB, HistoryBatch, HistoryEntry, WatchedOutput};
+use anyhow::{Result, anyhow};
+use btclib::address::Address;
+use btclib::events::ChainEvent;
+use btclib::sha256::Hash;
+use btclib::types::Blockchain;
+use std::collections::{BTreeMap, HashMap};
+use tokio::sync::Mutex;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{info, warn};
+
+/// The watched addresses. Holding the lock keeps the index from
+/// changing.
+pub struct Watchlist {
+    addresses: Mutex<Vec<String>>,
+}
+
+impl Watchlist {
+    pub fn load(db: &BlockchainDB) -> Result<Self> {
+        Ok(Self {
+            addresses: Mutex::new(db.get_watched_addresses()?),
+        })
+    }
+
+    pub async fn addresses(&self) -> Vec<String> {
+        self.addresses.lock().await.clone()
+    }
+}
+
+// height of the first block not on the main chain any more, walking
+// back from a block the index holds through the branches the chain
+// keeps; 0 if the branch is forgotten, reindexing everything
+fn fork_height(blockchain: &Blockchain, mut hash: Hash) -> u64 {
+    loop {
+        if let Some(height) = blockchain.height_of(&hash) {
+            return height + 1;
+        }
+        match blockchain.side_blocks().find(|side| side.block.hash() == hash) {
+            Some(side) => hash = side.block.header.prev_block_hash,
+            None => return 0,
+        }
+    }
+}
+
+/// Index the transactions of the blocks from `from` up to but not
+/// including `to` touching `addresses`
+fn index_blocks(
+    db: &BlockchainDB,
+    blockchain: &Blockchain,
+    addresses: &[String],
+    from: u64,
+    to: u64,
+) -> Result<HistoryBatch> {
+    let mut batch = HistoryBatch::default();
+    if addresses.is_empty() {
+        return Ok(batch);
+    }
+    // outputs created by the blocks indexed so far, not written yet
+    let mut created: HashMap<Hash, WatchedOutput> = HashMap::new();
+    for height in from.max(blockchain.base_height())..to {
+        let Some(block) = blockchain.block_at(height) else {
+            break;
+        };
+        let block_hash = block.hash();
+        for transaction in &block.transactions {
+            // received and sent per address
+            let mut moved: BTreeMap<&str, (u64, u64)> = BTreeMap::new();
+            for input in &transaction.inputs {
+                let hash = &input.prev_transaction_output_hash;
+                let spent = match created.get(hash) {
+                    Some(output) => Some(output.clone()),
+                    None => db.get_watched_output(hash)?,
+                };
+                if let Some(output) = spent
+                    && let Some(address) = addresses.iter().find(|address| **address == output.address)
+                {
+                    moved.entry(address).or_default().1 += output.value;
+                }
+            }
+            for output in &transaction.outputs {
+                let Some(address) = addresses.iter().find(|address| Address::same(&output.address, address)) else {
+                    continue;
+                };
+                moved.entry(address).or_default().0 += output.value;
+                let watched = WatchedOutput {
+                    address: address.clone(),
+                    value: output.value,
+                    height,
+                };
+                created.insert(output.hash(), watched);
+            }
+            let txid = transaction.hash();
+            for (address, (received, sent)) in moved {
+                let entry = HistoryEntry {
+                    txid,
+                    height,
+                    block: block_hash,
+                    received,
+                    sent,
+                };
+                batch.entries.push((address.to_string(), entry));
+            }
+        }
+    }
+    batch.outputs = created.into_iter().collect();
+    Ok(batch)
+}
+
+// bring the index up to the chain's tip, with the watchlist locked
+fn catch_up_locked(db: &BlockchainDB, blockchain: &Blockchain, addresses: &[String]) -> Result<()> {
+    let (from, disconnected) = match db.get_history_tip()? {
+        Some((height, hash)) if blockchain.height_of(&hash) == Some(height) => (height + 1, false),
+        Some((height, hash)) => {
+            let fork = fork_height(blockchain, hash);
+            info!("history index disconnected from height {fork}, was indexed up to {height}");
+            db.remove_history_from(fork)?;
+            (fork, true)
+        }
+        None => (0, false),
+    };
+    let to = blockchain.block_height();
+    if from >= to && !disconnected {
+        return Ok(());
+    }
+    let batch = index_blocks(db, blockchain, addresses, from, to)?;
+    let tip = blockchain.tip_hash().map(|hash| (to - 1, hash));
+    db.put_history(batch, tip)
+}
+
+/// Index the blocks connected since the last call
+pub async fn catch_up(ctx: &NodeContext) -> Result<()> {
+    let addresses = ctx.watchlist.addresses.lock().await;
+    let blockchain = ctx.blockchain.read().await;
+    catch_up_locked(&ctx.db, &blockchain, &addresses)
+}
+
+/// Start watching `address`, indexing the stored blocks for it. False
+/// if it was watched already.
+pub async fn watch(ctx: &NodeContext, address: &str) -> Result<bool> {
+    let mut addresses = ctx.watchlist.addresses.lock().await;
+    let blockchain = ctx.blockchain.read().await;
+    Address::parse_for(address, blockchain.params()).map_err(|e| anyhow!("can't watch {address}: {e}"))?;
+    if addresses.iter().any(|watched| watched == address) {
+        return Ok(false);
+    }
+    catch_up_locked(&ctx.db, &blockchain, &addresses)?;
+    let new = [address.to_string()];
+    let batch = index_blocks(&ctx.db, &blockchain, &new, 0, blockchain.block_height())?;
+    ctx.db.put_watched_address(address)?;
+    ctx.db.put_history(batch, ctx.db.get_history_tip()?)?;
+    addresses.push(address.to_string());
+    info!("watching {address}, {} transactions so far", ctx.db.get_history(address)?.len());
+    Ok(true)
+}
+
+/// Stop watching `address`, dropping its history. False if it wasn't
+/// watched.
+pub async fn unwatch(ctx: &NodeContext, address: &str) -> Result<bool> {
+    let mut addresses = ctx.watchlist.addresses.lock().await;
+    let Some(position) = addresses.iter().position(|watched| watched == address) else {
+        return Ok(false);
+    };
+    ctx.db.remove_watched_address(address)?;
+    addresses.remove(position);
+    info!("stopped watching {address}");
+    Ok(true)
+}
+
+/// Confirmed transactions touching `address`, oldest first, or None
+/// if it isn't watched
+pub async fn history(ctx: &NodeContext, address: &str) -> Result<Option<Vec<HistoryEntry>>> {
+    let addresses = ctx.watchlist.addresses.lock().await;
+    if !addresses.iter().any(|watched| watched == address) {
+        return Ok(None);
+    }
+    let blockchain = ctx.blockchain.read().await;
+    catch_up_locked(&ctx.db, &blockchain, &addresses)?;
+    Ok(Some(ctx.db.get_history(address)?))
+}
+
+/// Keep the index up with the chain until the node stops
+pub async fn run(ctx: NodeContext) {
+    let mut events = ctx.events.subscribe();
+    loop {
+        match events.recv().await {
+            Ok(ChainEvent::Block { .. } | ChainEvent::Reorg { .. }) | Err(RecvError::Lagged(_)) => {
+                if let Err(e) = catch_up(&ctx).await {
+                    warn!("failed to index watched addresses: {e}");
+                }
+            }
+            Ok(ChainEvent::Transaction { .. }) => {}
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use btclib::crypto::PrivateKey;
+    use btclib::params::ChainParams;
+
+    #[tokio::test]
+    async fn test_history_follows_the_chain() {
+        let key = PrivateKey::new_key();
+        let address = key.public_key().to_address();
+        let chain = btclib::testing::build_chain(&key, &[vec![(0, 2, 10)], vec![(0, 1, 5)]]);
+        let ctx = NodeContext::from_db(BlockchainDB::temporary().unwrap(), ChainParams::default(), false).unwrap();
+        *ctx.blockchain.write().await = chain;
+        // indexed before anything is watched
+        catch_up(&ctx).await.unwrap();
+        assert_eq!(history(&ctx, &address).await.unwrap(), None);
+
+        assert!(watch(&ctx, &address).await.unwrap());
+        assert!(!watch(&ctx, &address).await.unwrap());
+        assert!(watch(&ctx, "nonsense").await.is_err());
+        let indexed = history(&ctx, &address).await.unwrap().unwrap();
+        // three coinbases and a spend in each block after the genesis
+        assert_eq!(indexed.len(), 5);
+        let blockchain = ctx.blockchain.read().await;
+        let spend = &blockchain.block_at(1).unwrap().transactions[1];
+        let entry = indexed.iter().find(|entry| entry.txid == spend.hash()).unwrap();
+        assert_eq!(entry.height, 1);
+        assert_eq!(entry.sent, ChainParams::reward_at_height(0));
+        assert_eq!(entry.received, entry.sent - 10);
+        let spent_output = spend.outputs[0].hash();
+        let tip = blockchain.tip_hash().unwrap();
+        drop(blockchain);
+
+        // a reorg takes the disconnected block out
+        ctx.blockchain.write().await.invalidate_block(&tip).unwrap();
+        let indexed = history(&ctx, &address).await.unwrap().unwrap();
+        assert_eq!(indexed.len(), 3);
+        assert!(indexed.iter().all(|entry| entry.height < 2));
+        ctx.blockchain.write().await.reconsider_block(&tip).unwrap();
+        assert_eq!(history(&ctx, &address).await.unwrap().unwrap().len(), 5);
+
+        assert!(unwatch(&ctx, &address).await.unwrap());
+        assert!(!unwatch(&ctx, &address).await.unwrap());
+        assert!(ctx.db.get_history(&address).unwrap().is_empty());
+        assert_eq!(ctx.db.get_watched_output(&spent_output).unwrap(), None);
+    }
+}
@@ -0,0 +1,427 @@
+//! Python bindings for btclib, to script the chain from Python: keys,
+//! addresses, building and signing transactions, and reading blocks.
+//! Amounts are satoshis and hashes are hex strings.
+use btclib::address::{Address, AddressFormat};
+use btclib::crypto;
+use btclib::encoding::{Decode, Encode};
+use btclib::params::{ChainParams, Network};
+use btclib::sha256::Hash;
+use btclib::types::{self, TransactionOutput, UnsignedInput, UnsignedTransaction};
+use btclib::util::{MerkleRoot, Saveable};
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use uuid::Uuid;
+
+fn value_error(e: impl ToString) -> PyErr {
+    PyValueError::new_err(e.to_string())
+}
+
+fn params(network: &str) -> PyResult<ChainParams> {
+    let network: Network = network.parse().map_err(value_error)?;
+    Ok(network.params())
+}
+
+fn parse_hash(hash: &str) -> PyResult<Hash> {
+    hash.parse()
+        .map_err(|_| value_error(format!("{} is not a hash", hash)))
+}
+
+fn address_format(format: &str) -> PyResult<AddressFormat> {
+    match format.to_ascii_lowercase().as_str() {
+        "base58" => Ok(AddressFormat::Base58),
+        "bech32" => Ok(AddressFormat::Bech32),
+        _ => Err(value_error(format!(
+            "unknown address format {:?}, expected base58 or bech32",
+            format
+        ))),
+    }
+}
+
+#[pyclass(module = "grapheno")]
+pub struct PrivateKey(crypto::PrivateKey);
+
+#[pymethods]
+impl PrivateKey {
+    /// A new random key
+    #[staticmethod]
+    fn generate() -> Self {
+        PrivateKey(crypto::PrivateKey::new_key())
+    }
+
+    #[staticmethod]
+    fn generate_mnemonic() -> String {
+        crypto::PrivateKey::generate_mnemonic()
+    }
+
+    /// The key `key_gen` derives from the same recovery phrase
+    #[staticmethod]
+    fn from_mnemonic(mnemonic: &str) -> PyResult<Self> {
+        crypto::PrivateKey::from_mnemonic(mnemonic)
+            .map(PrivateKey)
+            .map_err(value_error)
+    }
+
+    /// Load the CBOR form of the `.priv.cbor` key files
+    #[staticmethod]
+    fn from_cbor(data: &[u8]) -> PyResult<Self> {
+        crypto::PrivateKey::load(data)
+            .map(PrivateKey)
+            .map_err(value_error)
+    }
+
+    fn to_cbor(&self) -> PyResult<Vec<u8>> {
+        let mut bytes = vec![];
+        self.0.save(&mut bytes).map_err(value_error)?;
+        Ok(bytes)
+    }
+
+    fn public_key(&self) -> PublicKey {
+        PublicKey(self.0.public_key())
+    }
+
+    fn __repr__(&self) -> String {
+        "PrivateKey(<redacted>)".to_string()
+    }
+}
+
+#[pyclass(module = "grapheno", eq, frozen)]
+#[derive(Clone, PartialEq)]
+pub struct PublicKey(crypto::PublicKey);
+
+#[pymethods]
+impl PublicKey {
+    /// Load the PEM form of the `.pub.pem` key files
+    #[staticmethod]
+    fn from_pem(pem: &str) -> PyResult<Self> {
+        crypto::PublicKey::load(pem.as_bytes())
+            .map(PublicKey)
+            .map_err(value_error)
+    }
+
+    fn to_pem(&self) -> PyResult<String> {
+        let mut pem = vec![];
+        self.0.save(&mut pem).map_err(value_error)?;
+        String::from_utf8(pem).map_err(value_error)
+    }
+
+    /// Address of the key on `network`, in "base58" or "bech32"
+    #[pyo3(signature = (network = "mainnet", format = "base58"))]
+    fn address(&self, network: &str, format: &str) -> PyResult<String> {
+        Ok(self.0.to_address_as(address_format(format)?, &params(network)?))
+    }
+
+    fn __repr__(&self) -> String {
+        format!("PublicKey('{}')", self.0.to_address())
+    }
+}
+
+/// Whether `address` is a well-formed address of `network`
+#[pyfunction]
+#[pyo3(signature = (address, network = "mainnet"))]
+fn is_valid_address(address: &str, network: &str) -> PyResult<bool> {
+    Ok(Address::parse_for(address, &params(network)?).is_ok())
+}
+
+#[pyclass(module = "grapheno", frozen)]
+#[derive(Clone)]
+pub struct TxInput(types::TransactionInput);
+
+#[pymethods]
+impl TxInput {
+    /// Hash of the output this input spends
+    #[getter]
+    fn prev_output_hash(&self) -> String {
+        self.0.prev_transaction_output_hash.to_string()
+    }
+
+    #[getter]
+    fn public_key(&self) -> PublicKey {
+        PublicKey(self.0.public_key.clone())
+    }
+
+    #[getter]
+    fn is_multisig(&self) -> bool {
+        self.0.multisig.is_some()
+    }
+
+    /// Address of the spent output, the multisig address for multisig
+    /// inputs
+    #[pyo3(signature = (network = "mainnet"))]
+    fn address(&self, network: &str) -> PyResult<String> {
+        let params = params(network)?;
+        Ok(match &self.0.multisig {
+            Some(policy) => policy.to_address_for(&params),
+            None => self.0.public_key.to_address_for(&params),
+        })
+    }
+
+    fn __repr__(&self) -> String {
+        format!("TxInput(prev_output_hash='{}')", self.prev_output_hash())
+    }
+}
+
+#[pyclass(module = "grapheno", frozen)]
+#[derive(Clone)]
+pub struct TxOutput(TransactionOutput);
+
+#[pymethods]
+impl TxOutput {
+    /// An output paying `value` to `address`, with a fresh unique id
+    /// unless one is given
+    #[new]
+    #[pyo3(signature = (value, address, unique_id = None))]
+    fn new(value: u64, address: &str, unique_id: Option<&str>) -> PyResult<Self> {
+        Address::parse(address).map_err(value_error)?;
+        let unique_id = match unique_id {
+            Some(id) => Uuid::parse_str(id).map_err(value_error)?,
+            None => Uuid::new_v4(),
+        };
+        Ok(TxOutput(TransactionOutput {
+            value,
+            unique_id,
+            address: address.to_string(),
+        }))
+    }
+
+    #[getter]
+    fn value(&self) -> u64 {
+        self.0.value
+    }
+
+    #[getter]
+    fn unique_id(&self) -> String {
+        self.0.unique_id.to_string()
+    }
+
+    #[getter]
+    fn address(&self) -> String {
+        self.0.address.clone()
+    }
+
+    /// Hash of the output, which inputs spending it refer to
+    #[getter]
+    fn hash(&self) -> String {
+        self.0.hash().to_string()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("TxOutput(value={}, address='{}')", self.0.value, self.0.address)
+    }
+}
+
+/// Collects the inputs and outputs of a transaction before signing
+#[pyclass(module = "grapheno")]
+pub struct TransactionBuilder {
+    params: ChainParams,
+    unsigned: UnsignedTransaction,
+}
+
+#[pymethods]
+impl TransactionBuilder {
+    #[new]
+    #[pyo3(signature = (network = "mainnet"))]
+    fn new(network: &str) -> PyResult<Self> {
+        Ok(TransactionBuilder {
+            params: params(network)?,
+            unsigned: UnsignedTransaction {
+                inputs: vec![],
+                outputs: vec![],
+            },
+        })
+    }
+
+    /// Spend the output with the given hash and value, owned by `owner`
+    fn add_input(&mut self, output_hash: &str, value: u64, owner: &PublicKey) -> PyResult<()> {
+        self.unsigned.inputs.push(UnsignedInput {
+            prev_transaction_output_hash: parse_hash(output_hash)?,
+            public_key: owner.0.clone(),
+            value,
+            multisig: None,
+        });
+        Ok(())
+    }
+
+    /// Pay `value` to `address`, which must belong to the network.
+    /// Returns the new output, whose hash later transactions spend.
+    fn add_output(&mut self, address: &str, value: u64) -> PyResult<TxOutput> {
+        Address::parse_for(address, &self.params).map_err(value_error)?;
+        let output = TransactionOutput {
+            value,
+            unique_id: Uuid::new_v4(),
+            address: address.to_string(),
+        };
+        self.unsigned.outputs.push(output.clone());
+        Ok(TxOutput(output))
+    }
+
+    /// What the inputs hold beyond the outputs, paid to the miner
+    fn fee(&self) -> PyResult<u64> {
+        self.unsigned
+            .input_value()
+            .checked_sub(self.unsigned.output_value())
+            .ok_or_else(|| value_error("outputs exceed inputs"))
+    }
+
+    /// Sign every input with the key owning it
+    fn sign(&self, keys: Vec<PyRef<PrivateKey>>) -> PyResult<Transaction> {
+        self.fee()?;
+        let keys: Vec<crypto::PrivateKey> = keys.iter().map(|key| key.0.clone()).collect();
+        self.unsigned
+            .sign(&keys)
+            .map(Transaction)
+            .map_err(value_error)
+    }
+}
+
+#[pyclass(module = "grapheno", frozen)]
+#[derive(Clone)]
+pub struct Transaction(types::Transaction);
+
+#[pymethods]
+impl Transaction {
+    /// Parse the canonical encoding, as taken by the gRPC
+    /// `SubmitTransaction`
+    #[staticmethod]
+    fn from_bytes(data: &[u8]) -> PyResult<Self> {
+        types::Transaction::from_bytes(data)
+            .map(Transaction)
+            .map_err(value_error)
+    }
+
+    /// Load the CBOR transaction files of `tx_gen` and `tx_sign`
+    #[staticmethod]
+    fn from_cbor(data: &[u8]) -> PyResult<Self> {
+        types::Transaction::load(data)
+            .map(Transaction)
+            .map_err(value_error)
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        self.0.to_bytes()
+    }
+
+    fn to_cbor(&self) -> PyResult<Vec<u8>> {
+        let mut bytes = vec![];
+        self.0.save(&mut bytes).map_err(value_error)?;
+        Ok(bytes)
+    }
+
+    #[getter]
+    fn hash(&self) -> String {
+        self.0.hash().to_string()
+    }
+
+    /// Size in bytes of the canonical encoding
+    #[getter]
+    fn size(&self) -> usize {
+        self.0.size()
+    }
+
+    #[getter]
+    fn inputs(&self) -> Vec<TxInput> {
+        self.0.inputs.iter().cloned().map(TxInput).collect()
+    }
+
+    #[getter]
+    fn outputs(&self) -> Vec<TxOutput> {
+        self.0.outputs.iter().cloned().map(TxOutput).collect()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("Transaction('{}')", self.hash())
+    }
+}
+
+#[pyclass(module = "grapheno", frozen)]
+pub struct Block(types::Block);
+
+#[pymethods]
+impl Block {
+    /// Parse the compact wire encoding of a block
+    #[staticmethod]
+    fn from_bytes(data: &[u8]) -> PyResult<Self> {
+        types::Block::from_bytes(data)
+            .map(Block)
+            .map_err(value_error)
+    }
+
+    /// Load the CBOR block files of `block_gen`
+    #[staticmethod]
+    fn from_cbor(data: &[u8]) -> PyResult<Self> {
+        types::Block::load(data).map(Block).map_err(value_error)
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        self.0.to_bytes()
+    }
+
+    #[getter]
+    fn hash(&self) -> String {
+        self.0.hash().to_string()
+    }
+
+    /// Unix time of the block in seconds
+    #[getter]
+    fn timestamp(&self) -> i64 {
+        self.0.header.timestamp.timestamp()
+    }
+
+    #[getter]
+    fn nonce(&self) -> u64 {
+        self.0.header.nonce
+    }
+
+    #[getter]
+    fn prev_block_hash(&self) -> String {
+        self.0.header.prev_block_hash.to_string()
+    }
+
+    #[getter]
+    fn merkle_root(&self) -> String {
+        self.0.header.merkle_root.to_string()
+    }
+
+    /// The target in hex, which the block hash must not exceed
+    #[getter]
+    fn target(&self) -> String {
+        format!("{:x}", self.0.header.target)
+    }
+
+    #[getter]
+    fn transactions(&self) -> Vec<Transaction> {
+        self.0.transactions.iter().cloned().map(Transaction).collect()
+    }
+
+    /// Whether the header commits to the block's transactions and its
+    /// hash meets the target
+    fn is_well_formed(&self) -> bool {
+        let header = &self.0.header;
+        MerkleRoot::calculate(&self.0.transactions) == header.merkle_root
+            && self.0.hash().matches_target(header.target)
+    }
+
+    /// Sibling hashes linking the transaction at `index` to the merkle
+    /// root, from the bottom of the tree
+    fn merkle_branch(&self, index: usize) -> PyResult<Vec<String>> {
+        let proof = MerkleRoot::proof(&self.0.transactions, index)
+            .ok_or_else(|| value_error(format!("no transaction at index {}", index)))?;
+        Ok(proof.branch.iter().map(|hash| hash.to_string()).collect())
+    }
+
+    fn __repr__(&self) -> String {
+        format!("Block('{}')", self.hash())
+    }
+}
+
+#[pymodule]
+fn grapheno(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PrivateKey>()?;
+    m.add_class::<PublicKey>()?;
+    m.add_class::<TxInput>()?;
+    m.add_class::<TxOutput>()?;
+    m.add_class::<TransactionBuilder>()?;
+    m.add_class::<Transaction>()?;
+    m.add_class::<Block>()?;
+    m.add_function(wrap_pyfunction!(is_valid_address, m)?)?;
+    Ok(())
+}
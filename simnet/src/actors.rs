@@ -0,0 +1,213 @@
+//! Scripted miners and wallets, talking to a node with the messages
+//! the real ones use
+use anyhow::{Result, anyhow, bail};
+use btclib::crypto::PrivateKey;
+use btclib::network::{Envelope, Message};
+use btclib::params::ChainParams;
+use btclib::sha256::Hash;
+use btclib::types::{Block, TransactionOutput, UnsignedInput, UnsignedTransaction};
+use node::context::NodeContext;
+use node::handler::{self, DEFAULT_TTL};
+use node::network::PeerId;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time;
+use uuid::Uuid;
+
+// how long a node gets to answer a request or take a submission
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+// nonces tried between checks of the mined block
+const MINE_STEPS: usize = 100_000;
+
+/// A connection to a node, as a wallet or miner has
+struct Client {
+    id: PeerId,
+    node: NodeContext,
+    outbox: mpsc::Receiver<Envelope>,
+}
+
+impl Client {
+    async fn connect(node: &NodeContext, id: PeerId) -> Self {
+        let outbox = handler::attach_peer(node, id.clone(), false).await.messages;
+        Client {
+            id,
+            node: node.clone(),
+            outbox,
+        }
+    }
+
+    async fn send(&self, msg: Message) -> Result<()> {
+        let env = Envelope::new(self.id.clone(), DEFAULT_TTL, msg);
+        if !self.node.network.deliver(&self.id, env).await {
+            bail!("node {} stopped", self.node.network.self_id);
+        }
+        Ok(())
+    }
+
+    /// Send a request and wait for the reply `pick` accepts, skipping
+    /// the blocks and transactions the node gossips meanwhile
+    async fn request<T>(&mut self, msg: Message, pick: impl Fn(Message) -> Option<T>) -> Result<T> {
+        self.send(msg).await?;
+        let reply = async {
+            while let Some(env) = self.outbox.recv().await {
+                if let Some(reply) = pick(env.msg) {
+                    return Ok(reply);
+                }
+            }
+            bail!("node {} dropped the connection", self.node.network.self_id)
+        };
+        time::timeout(REPLY_TIMEOUT, reply)
+            .await
+            .map_err(|_| anyhow!("node {} didn't answer", self.node.network.self_id))?
+    }
+
+    /// Wait until `done` holds for the node's chain
+    async fn wait_until(&self, done: impl Fn(&btclib::types::Blockchain) -> bool) -> bool {
+        let wait = async {
+            while !done(&*self.node.blockchain.read().await) {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        time::timeout(REPLY_TIMEOUT, wait).await.is_ok()
+    }
+}
+
+/// Mines blocks on a node's templates
+pub struct Miner {
+    client: Client,
+    address: String,
+}
+
+impl Miner {
+    pub(crate) async fn connect(node: &NodeContext, id: PeerId, address: &str) -> Self {
+        Miner {
+            client: Client::connect(node, id).await,
+            address: address.to_string(),
+        }
+    }
+
+    /// Address the block rewards go to
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// Fetch a template, mine it and submit it, returning once the node
+    /// accepted the block
+    pub async fn mine_block(&mut self) -> Result<Block> {
+        let mut block = self
+            .client
+            .request(Message::FetchTemplate(self.address.clone()), |msg| match msg {
+                Message::Template(block) => Some(block),
+                _ => None,
+            })
+            .await?;
+        // CPU work, so no simulated time passes meanwhile
+        while !block.header.mine(MINE_STEPS) {}
+        let hash = block.hash();
+        self.client.send(Message::SubmitTemplate(block.clone())).await?;
+        if !self.client.wait_until(|chain| chain.tip_hash() == Some(hash)).await {
+            bail!("node rejected block {}", hash);
+        }
+        Ok(block)
+    }
+
+    /// Mine `count` blocks in a row
+    pub async fn mine_blocks(&mut self, count: usize) -> Result<Vec<Block>> {
+        let mut blocks = Vec::with_capacity(count);
+        for _ in 0..count {
+            blocks.push(self.mine_block().await?);
+        }
+        Ok(blocks)
+    }
+}
+
+/// A single key wallet, sending from the outputs the node reports
+pub struct Wallet {
+    client: Client,
+    key: PrivateKey,
+    address: String,
+}
+
+impl Wallet {
+    pub(crate) async fn connect(node: &NodeContext, id: PeerId, key: PrivateKey, params: &ChainParams) -> Self {
+        let address = key.public_key().to_address_for(params);
+        Wallet {
+            client: Client::connect(node, id).await,
+            key,
+            address,
+        }
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// The wallet's outputs, with whether a pending transaction spends them
+    pub async fn utxos(&mut self) -> Result<Vec<(TransactionOutput, bool)>> {
+        self.client
+            .request(Message::FetchUTXOs(self.address.clone(), None), |msg| match msg {
+                Message::UTXOs(utxos) => Some(utxos),
+                _ => None,
+            })
+            .await
+    }
+
+    /// Value of the outputs not spent by a pending transaction
+    pub async fn balance(&mut self) -> Result<u64> {
+        Ok(self
+            .utxos()
+            .await?
+            .iter()
+            .filter(|(_, marked)| !marked)
+            .map(|(output, _)| output.value)
+            .sum())
+    }
+
+    /// Pay `amount` to `address`, leaving `fee` to the miner and the
+    /// change to the wallet. Returns the transaction's hash once it is
+    /// in the node's mempool.
+    pub async fn send(&mut self, address: &str, amount: u64, fee: u64) -> Result<Hash> {
+        let needed = amount + fee;
+        let mut inputs = vec![];
+        let mut input_value = 0;
+        for (output, _) in self.utxos().await?.into_iter().filter(|(_, marked)| !marked) {
+            if input_value >= needed {
+                break;
+            }
+            input_value += output.value;
+            inputs.push(UnsignedInput {
+                prev_transaction_output_hash: output.hash(),
+                public_key: self.key.public_key(),
+                value: output.value,
+                multisig: None,
+            });
+        }
+        if input_value < needed {
+            bail!("wallet holds {}, {} needed", input_value, needed);
+        }
+
+        let mut outputs = vec![TransactionOutput {
+            value: amount,
+            unique_id: Uuid::new_v4(),
+            address: address.to_string(),
+        }];
+        if input_value > needed {
+            outputs.push(TransactionOutput {
+                value: input_value - needed,
+                unique_id: Uuid::new_v4(),
+                address: self.address.clone(),
+            });
+        }
+        let transaction = UnsignedTransaction { inputs, outputs }.sign(std::slice::from_ref(&self.key))?;
+        let hash = transaction.hash();
+        self.client.send(Message::SubmitTransaction(transaction)).await?;
+        let accepted = self
+            .client
+            .wait_until(|chain| chain.mempool().iter().any(|(_, tx)| tx.hash() == hash))
+            .await;
+        if !accepted {
+            bail!("node rejected transaction {}", hash);
+        }
+        Ok(hash)
+    }
+}
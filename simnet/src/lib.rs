@@ -0,0 +1,359 @@
+//! Simulated networks of in-process nodes, for integration tests of
+//! sync and relay.
+//!
+//! Nodes run the real handler and sync tasks over temporary databases
+//! and are linked in memory, each direction of a link with its own
+//! latency, jitter and loss. Nodes can be partitioned and healed, and
+//! scripted miners and wallets talk to them with the messages the real
+//! ones use. Randomness comes from the seed of the simulation, so runs
+//! under tokio's paused clock are reproducible.
+use anyhow::{Result, bail};
+use btclib::crypto::PrivateKey;
+use btclib::params::ChainParams;
+use btclib::sha256::Hash;
+use node::context::NodeContext;
+use node::database::BlockchainDB;
+use node::{handler, sync};
+use rand::Rng;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::AbortHandle;
+use tokio::time;
+use tracing::error;
+
+mod actors;
+mod link;
+
+pub use actors::{Miner, Wallet};
+pub use link::LinkConditions;
+
+// how often the waits check the nodes
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+// how long linked nodes get to exchange versions
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How the nodes are linked when the simulation starts
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Topology {
+    /// Every node to every other
+    #[default]
+    Full,
+    /// Every node to the next one
+    Line,
+    /// A line with the last node linked back to the first
+    Ring,
+    /// No links, see `Simnet::connect`
+    Disconnected,
+}
+
+#[derive(Clone, Debug)]
+pub struct SimConfig {
+    pub nodes: usize,
+    pub topology: Topology,
+    /// Conditions of every link not given its own
+    pub conditions: LinkConditions,
+    pub seed: u64,
+    pub params: ChainParams,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        SimConfig {
+            nodes: 3,
+            topology: Topology::Full,
+            conditions: LinkConditions::default(),
+            seed: 0,
+            params: ChainParams::default(),
+        }
+    }
+}
+
+/// A running simulation. Dropping it stops its nodes.
+pub struct Simnet {
+    nodes: Vec<NodeContext>,
+    params: ChainParams,
+    conditions: Arc<Mutex<link::Conditions>>,
+    tasks: Mutex<Vec<AbortHandle>>,
+    clients: usize,
+}
+
+impl Simnet {
+    pub async fn start(config: SimConfig) -> Result<Self> {
+        let mut simnet = Simnet {
+            nodes: vec![],
+            params: config.params.clone(),
+            conditions: Arc::new(Mutex::new(link::Conditions::new(config.conditions, config.seed))),
+            tasks: Mutex::new(vec![]),
+            clients: 0,
+        };
+        for _ in 0..config.nodes {
+            let ctx = NodeContext::from_db(BlockchainDB::temporary()?, config.params.clone(), false)?;
+            let dispatcher = ctx.clone();
+            let tasks = [
+                tokio::spawn(async move {
+                    if let Err(err) = handler::dispatcher_loop(dispatcher).await {
+                        error!("dispatcher exited: {err}");
+                    }
+                }),
+                tokio::spawn(sync::sync(ctx.clone())),
+            ];
+            simnet.track(tasks.iter().map(|task| task.abort_handle()));
+            simnet.nodes.push(ctx);
+        }
+
+        let count = config.nodes;
+        let links: Vec<(usize, usize)> = match config.topology {
+            Topology::Full => (0..count)
+                .flat_map(|a| (a + 1..count).map(move |b| (a, b)))
+                .collect(),
+            Topology::Line => (1..count).map(|b| (b - 1, b)).collect(),
+            Topology::Ring if count > 2 => (0..count).map(|a| (a, (a + 1) % count)).collect(),
+            Topology::Ring => (1..count).map(|b| (b - 1, b)).collect(),
+            Topology::Disconnected => vec![],
+        };
+        for (a, b) in links {
+            simnet.connect(a, b).await?;
+        }
+        Ok(simnet)
+    }
+
+    fn track(&self, tasks: impl IntoIterator<Item = AbortHandle>) {
+        self.tasks.lock().unwrap().extend(tasks);
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn node(&self, index: usize) -> &NodeContext {
+        &self.nodes[index]
+    }
+
+    /// Link two nodes, `a` opening the connection, and wait for their
+    /// version handshake
+    pub async fn connect(&self, a: usize, b: usize) -> Result<()> {
+        let tasks = link::connect(&self.conditions, (a, &self.nodes[a]), (b, &self.nodes[b])).await;
+        self.track(tasks);
+        let knows = |from: usize, to: usize| {
+            let id = &self.nodes[to].network.self_id;
+            self.nodes[from]
+                .network
+                .handshaked_peers()
+                .iter()
+                .any(|(peer, _)| peer == id)
+        };
+        if !self.poll(HANDSHAKE_TIMEOUT, async || knows(a, b) && knows(b, a)).await {
+            bail!("nodes {} and {} did not complete the handshake", a, b);
+        }
+        Ok(())
+    }
+
+    /// Drop the link between two nodes
+    pub fn disconnect(&self, a: usize, b: usize) {
+        self.nodes[a].network.peers.remove(&self.nodes[b].network.self_id);
+        self.nodes[b].network.peers.remove(&self.nodes[a].network.self_id);
+    }
+
+    /// Change the conditions of the link from one node to another, for
+    /// messages sent from now on
+    pub fn set_link(&self, from: usize, to: usize, conditions: LinkConditions) {
+        self.conditions.lock().unwrap().set_link(from, to, conditions);
+    }
+
+    /// Split the nodes into groups that can't reach each other. Nodes
+    /// not in any group are cut off on their own.
+    pub fn partition(&self, groups: &[&[usize]]) {
+        let mut partitions: Vec<usize> = (groups.len()..groups.len() + self.len()).collect();
+        for (group, nodes) in groups.iter().enumerate() {
+            for node in *nodes {
+                partitions[*node] = group;
+            }
+        }
+        self.conditions.lock().unwrap().set_partitions(Some(partitions));
+    }
+
+    /// Undo `partition`
+    pub fn heal(&self) {
+        self.conditions.lock().unwrap().set_partitions(None);
+    }
+
+    /// A key derived from the seed of the simulation
+    pub fn key(&self) -> PrivateKey {
+        let seed: [u8; 32] = self.conditions.lock().unwrap().rng().random();
+        PrivateKey::from_seed(&seed).expect("seeds make valid keys")
+    }
+
+    /// A miner connected to the given node, paying the block rewards
+    /// to `address`
+    pub async fn miner(&mut self, node: usize, address: &str) -> Miner {
+        let id = self.client_id("miner");
+        Miner::connect(&self.nodes[node], id, address).await
+    }
+
+    /// A wallet with a fresh key, connected to the given node
+    pub async fn wallet(&mut self, node: usize) -> Wallet {
+        let id = self.client_id("wallet");
+        Wallet::connect(&self.nodes[node], id, self.key(), &self.params).await
+    }
+
+    fn client_id(&mut self, kind: &str) -> String {
+        self.clients += 1;
+        format!("{}-{}", kind, self.clients)
+    }
+
+    pub async fn heights(&self) -> Vec<u64> {
+        let mut heights = vec![];
+        for ctx in &self.nodes {
+            heights.push(ctx.blockchain.read().await.block_height());
+        }
+        heights
+    }
+
+    pub async fn tips(&self) -> Vec<Option<Hash>> {
+        let mut tips = vec![];
+        for ctx in &self.nodes {
+            tips.push(ctx.blockchain.read().await.tip_hash());
+        }
+        tips
+    }
+
+    pub async fn utxo_set_hashes(&self) -> Vec<Hash> {
+        let mut hashes = vec![];
+        for ctx in &self.nodes {
+            hashes.push(ctx.blockchain.read().await.utxo_set_hash());
+        }
+        hashes
+    }
+
+    /// Whether every node has the same tip
+    pub async fn converged(&self) -> bool {
+        self.tips().await.windows(2).all(|pair| pair[0] == pair[1])
+    }
+
+    /// Wait until every node has the same tip, returning the height.
+    /// Fails if they got there with different UTXO sets.
+    pub async fn wait_for_convergence(&self, timeout: Duration) -> Result<u64> {
+        if self.poll(timeout, || self.converged()).await {
+            let hashes = self.utxo_set_hashes().await;
+            if hashes.windows(2).any(|pair| pair[0] != pair[1]) {
+                bail!("nodes share their tip but not their UTXO sets, hashes {:?}", hashes);
+            }
+            return Ok(self.heights().await[0]);
+        }
+        bail!(
+            "nodes did not converge within {:?}, heights {:?}",
+            timeout,
+            self.heights().await
+        )
+    }
+
+    /// Wait until every node reached `height`
+    pub async fn wait_for_height(&self, height: u64, timeout: Duration) -> Result<()> {
+        let reached = async || self.heights().await.iter().all(|h| *h >= height);
+        if self.poll(timeout, reached).await {
+            return Ok(());
+        }
+        bail!(
+            "nodes did not reach height {} within {:?}, heights {:?}",
+            height,
+            timeout,
+            self.heights().await
+        )
+    }
+
+    /// Wait until every node has the transaction, in its mempool or in
+    /// a block
+    pub async fn wait_for_transaction(&self, hash: Hash, timeout: Duration) -> Result<()> {
+        let known = async || {
+            for ctx in &self.nodes {
+                let chain = ctx.blockchain.read().await;
+                let pending = chain.mempool().iter().any(|(_, tx)| tx.hash() == hash);
+                if !pending && chain.transaction_by_id(&hash).is_none() {
+                    return false;
+                }
+            }
+            true
+        };
+        if self.poll(timeout, known).await {
+            return Ok(());
+        }
+        bail!("transaction {} did not reach every node within {:?}", hash, timeout)
+    }
+
+    async fn poll(&self, timeout: Duration, done: impl AsyncFn() -> bool) -> bool {
+        let wait = async {
+            while !done().await {
+                time::sleep(POLL_INTERVAL).await;
+            }
+        };
+        time::timeout(timeout, wait).await.is_ok()
+    }
+}
+
+impl Drop for Simnet {
+    fn drop(&mut self) {
+        for task in self.tasks.lock().unwrap().drain(..) {
+            task.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(60);
+
+    #[tokio::test(start_paused = true)]
+    async fn test_blocks_reach_every_node() {
+        let config = SimConfig {
+            topology: Topology::Line,
+            conditions: LinkConditions {
+                latency: Duration::from_millis(50),
+                jitter: Duration::from_millis(20),
+                loss: 0.0,
+            },
+            ..SimConfig::default()
+        };
+        let mut sim = Simnet::start(config).await.unwrap();
+        let wallet = sim.wallet(0).await;
+        let mut miner = sim.miner(0, wallet.address()).await;
+        miner.mine_blocks(3).await.unwrap();
+        assert_eq!(sim.wait_for_convergence(TIMEOUT).await.unwrap(), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_transactions_relay() {
+        let mut sim = Simnet::start(SimConfig::default()).await.unwrap();
+        let mut sender = sim.wallet(0).await;
+        let mut recipient = sim.wallet(2).await;
+        let mut miner = sim.miner(1, sender.address()).await;
+        miner.mine_block().await.unwrap();
+        sim.wait_for_convergence(TIMEOUT).await.unwrap();
+
+        let recipient_address = recipient.address().to_string();
+        let hash = sender.send(&recipient_address, 1_000, 100).await.unwrap();
+        sim.wait_for_transaction(hash, TIMEOUT).await.unwrap();
+        miner.mine_block().await.unwrap();
+        sim.wait_for_convergence(TIMEOUT).await.unwrap();
+        assert_eq!(recipient.balance().await.unwrap(), 1_000);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_partitioned_node_catches_up() {
+        let mut sim = Simnet::start(SimConfig::default()).await.unwrap();
+        let wallet = sim.wallet(0).await;
+        let mut miner = sim.miner(0, wallet.address()).await;
+        sim.partition(&[&[0, 1]]);
+        miner.mine_blocks(2).await.unwrap();
+        sim.wait_for_height(2, TIMEOUT).await.unwrap_err();
+        assert_eq!(sim.heights().await, vec![2, 2, 0]);
+
+        sim.heal();
+        assert_eq!(sim.wait_for_convergence(TIMEOUT).await.unwrap(), 2);
+    }
+}
@@ -0,0 +1,132 @@
+//! In-memory links between simulated nodes
+use btclib::network::Envelope;
+use node::context::NodeContext;
+use node::handler;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::AbortHandle;
+use tokio::time::{self, Instant};
+
+/// How messages travel over a link, in one direction
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LinkConditions {
+    /// Delay before a message arrives
+    pub latency: Duration,
+    /// Extra delay of up to this, picked per message. Messages on a
+    /// link still arrive in the order they were sent.
+    pub jitter: Duration,
+    /// Chance of a message being lost, from 0 to 1
+    pub loss: f64,
+}
+
+/// Conditions of every link, shared with the tasks carrying messages
+pub(crate) struct Conditions {
+    default: LinkConditions,
+    links: HashMap<(usize, usize), LinkConditions>,
+    /// Partition of each node, nodes only reach nodes in the same one
+    partitions: Option<Vec<usize>>,
+    rng: StdRng,
+}
+
+impl Conditions {
+    pub fn new(default: LinkConditions, seed: u64) -> Self {
+        Conditions {
+            default,
+            links: HashMap::new(),
+            partitions: None,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    pub fn set_link(&mut self, from: usize, to: usize, conditions: LinkConditions) {
+        self.links.insert((from, to), conditions);
+    }
+
+    pub fn set_partitions(&mut self, partitions: Option<Vec<usize>>) {
+        self.partitions = partitions;
+    }
+
+    /// Randomness of the simulation, derived from its seed
+    pub fn rng(&mut self) -> &mut StdRng {
+        &mut self.rng
+    }
+
+    fn reachable(&self, from: usize, to: usize) -> bool {
+        self.partitions
+            .as_ref()
+            .is_none_or(|partitions| partitions[from] == partitions[to])
+    }
+
+    /// Delay of a message sent now, None if it is lost
+    fn route(&mut self, from: usize, to: usize) -> Option<Duration> {
+        let conditions = self.links.get(&(from, to)).copied().unwrap_or(self.default);
+        if !self.reachable(from, to) || self.rng.random_bool(conditions.loss.clamp(0.0, 1.0)) {
+            return None;
+        }
+        let jitter = self.rng.random_range(0..=conditions.jitter.as_micros() as u64);
+        Some(conditions.latency + Duration::from_micros(jitter))
+    }
+}
+
+/// Connect two nodes both ways, `a` opening the connection. Returns
+/// the tasks carrying the messages.
+pub(crate) async fn connect(
+    conditions: &Arc<Mutex<Conditions>>,
+    (a, a_ctx): (usize, &NodeContext),
+    (b, b_ctx): (usize, &NodeContext),
+) -> Vec<AbortHandle> {
+    // b has to know a before a's version arrives
+    let to_a = handler::attach_peer(b_ctx, a_ctx.network.self_id.clone(), false).await;
+    let to_b = handler::attach_peer(a_ctx, b_ctx.network.self_id.clone(), true).await;
+    let mut tasks = carry(conditions.clone(), a, b, to_b.messages, a_ctx, b_ctx.clone());
+    tasks.extend(carry(conditions.clone(), b, a, to_a.messages, b_ctx, a_ctx.clone()));
+    tasks
+}
+
+/// Move the messages `from` sends to `to` into `to`'s inbound queue,
+/// applying the link's conditions
+fn carry(
+    conditions: Arc<Mutex<Conditions>>,
+    from: usize,
+    to: usize,
+    mut outbox: mpsc::Receiver<Envelope>,
+    sender: &NodeContext,
+    receiver: NodeContext,
+) -> Vec<AbortHandle> {
+    let sender_id = sender.network.self_id.clone();
+    let (in_flight, mut arriving) = mpsc::unbounded_channel::<(Instant, Envelope)>();
+
+    let deliver_conditions = conditions.clone();
+    let deliver = tokio::spawn(async move {
+        while let Some((at, env)) = arriving.recv().await {
+            time::sleep_until(at).await;
+            // messages in flight when a partition starts are lost
+            if !deliver_conditions.lock().unwrap().reachable(from, to) {
+                continue;
+            }
+            if !receiver.network.deliver(&sender_id, env).await {
+                break;
+            }
+        }
+    });
+
+    let send = tokio::spawn(async move {
+        let mut last = Instant::now();
+        // ends when the peer is removed from the sender's hub
+        while let Some(env) = outbox.recv().await {
+            let Some(delay) = conditions.lock().unwrap().route(from, to) else {
+                continue;
+            };
+            last = last.max(Instant::now() + delay);
+            if in_flight.send((last, env)).is_err() {
+                break;
+            }
+        }
+    });
+
+    vec![deliver.abort_handle(), send.abort_handle()]
+}
@@ -0,0 +1,186 @@
+use crate::core::{Account, Config, connect_node};
+use crate::keys::{DEFAULT_GAP_LIMIT, fetch_usage, read_mnemonic, save_new, scan_chain};
+use anyhow::{Context, Result, anyhow, bail};
+use btclib::bip32::{DerivationPath, ExtendedPrivateKey};
+use btclib::crypto::PrivateKey;
+use btclib::params::Network;
+use btclib::util::Saveable;
+use clap::Subcommand;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tokio::sync::Mutex;
+use tracing::*;
+use zeroize::Zeroizing;
+
+/// Manage the seed and the BIP44 accounts derived from it
+#[derive(Subcommand)]
+pub enum AccountCommand {
+    /// Write the seed the accounts are derived from, made from a fresh
+    /// mnemonic or the one given
+    Init {
+        /// The mnemonic to use, `-` reads it from stdin
+        #[arg(short, long)]
+        mnemonic: Option<String>,
+        #[arg(short, long, value_name = "FILE", default_value = "keys/seed.cbor")]
+        output: PathBuf,
+    },
+    /// Add an account and show its first receive address
+    Add {
+        #[arg(short, long)]
+        name: String,
+        /// Account number, the next unused one by default
+        #[arg(short, long)]
+        account: Option<u32>,
+        /// Path of the account key instead of `m/44'/<coin>'/<account>'`
+        #[arg(long)]
+        path: Option<DerivationPath>,
+    },
+    /// Hand out a new receive address of an account
+    Receive {
+        name: String,
+    },
+    /// List the accounts with their paths and latest receive address
+    List,
+}
+
+/// Run an account command against the config file at `config_path`
+pub async fn run(config_path: &Path, command: AccountCommand) -> Result<()> {
+    let mut config = Config::load(config_path)?;
+    match command {
+        AccountCommand::Init { mnemonic, output } => {
+            if let Some(seed) = &config.seed {
+                bail!("The wallet already has a seed, {}", seed.display());
+            }
+            let mnemonic = match mnemonic {
+                Some(mnemonic) => read_mnemonic(mnemonic)?,
+                None => {
+                    let mnemonic = Zeroizing::new(PrivateKey::generate_mnemonic());
+                    println!("Mnemonic phrase: {}", mnemonic.as_str());
+                    println!("Save this phrase in a secure location, it is needed to recover the accounts.");
+                    mnemonic
+                }
+            };
+            let seed = ExtendedPrivateKey::from_mnemonic(&mnemonic).map_err(|e| anyhow!(e))?;
+            if let Some(dir) = output.parent() {
+                fs::create_dir_all(dir).context(anyhow!("Failed to create {}", dir.display()))?;
+            }
+            save_new(&seed, &output).await?;
+            println!("Seed: {}", output.display());
+            info!("Setting the seed of {:?} to {:?}", config_path, output);
+            config.seed = Some(output);
+            config.save(config_path)?;
+        }
+        AccountCommand::Add { name, account, path } => {
+            let seed = load_seed(&config)?;
+            if config.accounts.iter().any(|account| account.name == name) {
+                bail!("An account named {} already exists", name);
+            }
+            let number = match account {
+                Some(number) => number,
+                None => config.accounts.iter().map(|account| account.account + 1).max().unwrap_or(0),
+            };
+            let mut account = Account {
+                name,
+                account: number,
+                path,
+                receive: 1,
+                change: 0,
+            };
+            // the seed may have been used elsewhere, as when it was
+            // made from the mnemonic of another wallet
+            match connect_node(&config).await {
+                Ok((stream, _)) => {
+                    let stream = &Mutex::new(stream);
+                    let (receive, change) = scan_account(
+                        &account,
+                        &seed,
+                        config.network,
+                        DEFAULT_GAP_LIMIT,
+                        |addresses| async move { fetch_usage(&mut *stream.lock().await, addresses).await },
+                        |chain, checked, used| println!("Checked {} addresses of {}, {} used", checked, chain, used),
+                    )
+                    .await?;
+                    account.receive = receive.max(1);
+                    account.change = change;
+                }
+                Err(e) => println!(
+                    "Not checking the node for used addresses ({}), `Scan for used addresses` in the wallet does it later",
+                    e
+                ),
+            }
+            let address = account.key(&seed, config.network, false, 0)?.public_key().to_address();
+            println!("Account {} at {}", account.name, account.path(config.network));
+            println!("Address: {}", config.format_address(&address));
+            if account.receive > 1 || account.change > 0 {
+                println!("{} receive and {} change addresses were used already", account.receive, account.change);
+            }
+            info!("Adding account {} to {:?}", account.name, config_path);
+            config.accounts.push(account);
+            config.save(config_path)?;
+        }
+        AccountCommand::Receive { name } => {
+            let seed = load_seed(&config)?;
+            let network = config.network;
+            let account = config
+                .accounts
+                .iter_mut()
+                .find(|account| account.name == name)
+                .ok_or_else(|| anyhow!("No account named {}", name))?;
+            let address = account.key(&seed, network, false, account.receive)?.public_key().to_address();
+            account.receive += 1;
+            config.save(config_path)?;
+            println!("Address: {}", config.format_address(&address));
+            println!("A running wallet watches it after a restart.");
+        }
+        AccountCommand::List => {
+            if config.accounts.is_empty() {
+                println!("No accounts configured");
+                return Ok(());
+            }
+            let seed = load_seed(&config)?;
+            for account in &config.accounts {
+                let latest = account.key(&seed, config.network, false, account.receive.max(1) - 1)?;
+                println!(
+                    "{}\t{}\t{} receive, {} change\t{}",
+                    account.name,
+                    account.path(config.network),
+                    account.receive,
+                    account.change,
+                    config.format_address(&latest.public_key().to_address())
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Numbers of the receive and change addresses of an account up to the
+/// last one the node saw paid, each chain scanned like `scan_chain`
+pub(crate) async fn scan_account<F: Future<Output = Result<Vec<bool>>>>(
+    account: &Account,
+    seed: &ExtendedPrivateKey,
+    network: Network,
+    gap_limit: u32,
+    mut is_used: impl FnMut(Vec<String>) -> F,
+    mut progress: impl FnMut(&DerivationPath, u32, usize),
+) -> Result<(u32, u32)> {
+    let mut used = [0; 2];
+    for (change, used) in used.iter_mut().enumerate() {
+        let chain = account.path(network).child(change as u32);
+        let chain_key = seed.derive(&chain).context(anyhow!("Failed to derive {}", chain))?;
+        let keys = scan_chain(&chain_key, gap_limit, &mut is_used, |checked, count| {
+            progress(&chain, checked, count)
+        })
+        .await?;
+        *used = keys.last().map_or(0, |(index, _)| index + 1);
+    }
+    Ok((used[0], used[1]))
+}
+
+fn load_seed(config: &Config) -> Result<ExtendedPrivateKey> {
+    let path = config
+        .seed
+        .as_ref()
+        .ok_or_else(|| anyhow!("The wallet has no seed yet, create it with `wallet account init`"))?;
+    ExtendedPrivateKey::load_from_file(path).context(anyhow!("Failed to load the seed {}", path.display()))
+}
@@ -0,0 +1,157 @@
+use anyhow::{Context, Result, anyhow};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use crate::core::DisplayUnit;
+use crate::util::{format_amount, write_atomic};
+
+// oldest snapshots are dropped beyond this
+const MAX_SNAPSHOTS: usize = 10_000;
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// The balance after it changed
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct Snapshot {
+    /// Unix time it was seen at
+    pub time: u64,
+    /// Height of the node's chain at the time
+    pub height: u64,
+    pub balance: u64,
+}
+
+/// How far back the chart goes
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Range {
+    Day,
+    Week,
+    All,
+}
+
+impl Range {
+    pub fn seconds(self) -> Option<u64> {
+        match self {
+            Range::Day => Some(24 * 60 * 60),
+            Range::Week => Some(7 * 24 * 60 * 60),
+            Range::All => None,
+        }
+    }
+}
+
+/// Balances seen by the wallet over time, saved next to the config
+/// like the UTXO cache
+pub struct BalanceHistory {
+    path: PathBuf,
+    snapshots: Vec<Snapshot>,
+}
+
+impl BalanceHistory {
+    /// Read the history at `path`, which is empty if the file is missing
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let snapshots = match fs::read(&path) {
+            Ok(bytes) => ciborium::from_reader(bytes.as_slice())
+                .context(anyhow!("Failed to parse balance history {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(e) => {
+                return Err(e).context(anyhow!("Failed to read balance history {}", path.display()));
+            }
+        };
+        Ok(Self { path, snapshots })
+    }
+
+    fn save(&self) -> Result<()> {
+        let mut bytes = vec![];
+        ciborium::into_writer(&self.snapshots, &mut bytes)?;
+        write_atomic(&self.path, &bytes).context(anyhow!("Failed to write balance history"))
+    }
+
+    /// Remember the balance if it changed since the last snapshot
+    pub fn record(&mut self, snapshot: Snapshot) -> Result<()> {
+        if self.snapshots.last().is_some_and(|last| last.balance == snapshot.balance) {
+            return Ok(());
+        }
+        self.snapshots.push(snapshot);
+        if self.snapshots.len() > MAX_SNAPSHOTS {
+            self.snapshots.remove(0);
+        }
+        self.save()
+    }
+
+    pub fn snapshots(&self) -> &[Snapshot] {
+        &self.snapshots
+    }
+
+    /// The balance at `width` evenly spaced times from the start of
+    /// `range` to `now`, carrying each snapshot forward until the next.
+    /// Times before the first snapshot are left out.
+    pub fn sample(&self, range: Range, now: u64, width: usize) -> Vec<u64> {
+        let Some(first) = self.snapshots.first() else {
+            return vec![];
+        };
+        let start = match range.seconds() {
+            Some(seconds) => now.saturating_sub(seconds).max(first.time),
+            None => first.time,
+        };
+        let width = width.max(1) as u64;
+        let step = (now.saturating_sub(start) / width).max(1);
+        (1..=width)
+            .map(|i| (start + step * i).min(now))
+            .filter_map(|time| {
+                let seen = self.snapshots.partition_point(|snapshot| snapshot.time <= time);
+                seen.checked_sub(1).map(|index| self.snapshots[index].balance)
+            })
+            .collect()
+    }
+}
+
+/// One bar per value, scaled between the smallest and largest
+pub fn sparkline(values: &[u64]) -> String {
+    let (Some(min), Some(max)) = (values.iter().min(), values.iter().max()) else {
+        return String::new();
+    };
+    values
+        .iter()
+        .map(|value| {
+            let level = match max - min {
+                0 => SPARKS.len() / 2,
+                spread => ((value - min) * (SPARKS.len() as u64 - 1) / spread) as usize,
+            };
+            SPARKS[level]
+        })
+        .collect()
+}
+
+/// A bar chart `rows` lines high with the largest and smallest value
+/// written next to the top and bottom line
+pub fn chart(values: &[u64], rows: usize, unit: DisplayUnit) -> String {
+    let (Some(min), Some(max)) = (values.iter().copied().min(), values.iter().copied().max()) else {
+        return "(No balance history yet)".to_string();
+    };
+    let rows = rows.max(2);
+    // bars start one row below the smallest value so it stays visible
+    let floor = min.saturating_sub((max - min) / rows as u64);
+    let heights: Vec<usize> = values
+        .iter()
+        .map(|value| match max - floor {
+            0 => rows,
+            spread => ((value - floor) * rows as u64).div_ceil(spread) as usize,
+        })
+        .collect();
+    let (top, bottom) = (format_amount(max, unit), format_amount(min, unit));
+    let label_width = top.len().max(bottom.len());
+    (0..rows)
+        .map(|row| {
+            let level = rows - row;
+            let label = match row {
+                0 => top.as_str(),
+                _ if row == rows - 1 => bottom.as_str(),
+                _ => "",
+            };
+            let bars: String = heights
+                .iter()
+                .map(|height| if *height >= level { '█' } else { ' ' })
+                .collect();
+            format!("{:>width$} │{}", label, bars, width = label_width)
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
@@ -0,0 +1,39 @@
+use anyhow::{Context, Result};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use std::io::Write;
+use tracing::*;
+
+// the system clipboard belongs to the remote machine in SSH sessions
+fn in_ssh_session() -> bool {
+    std::env::var_os("SSH_CONNECTION").is_some() || std::env::var_os("SSH_TTY").is_some()
+}
+
+/// Copy text to the clipboard. Uses the system clipboard when running
+/// locally, and the OSC 52 escape sequence over SSH or when no system
+/// clipboard is available, which makes the terminal emulator do it.
+pub fn copy(text: &str) -> Result<()> {
+    if !in_ssh_session() {
+        match arboard::Clipboard::new().and_then(|mut clipboard| clipboard.set_text(text)) {
+            Ok(()) => return Ok(()),
+            Err(e) => debug!("system clipboard unavailable, falling back to OSC 52: {}", e),
+        }
+    }
+    copy_osc52(text)
+}
+
+fn copy_osc52(text: &str) -> Result<()> {
+    let mut stdout = std::io::stdout();
+    write!(stdout, "\x1b]52;c;{}\x07", STANDARD.encode(text))?;
+    stdout.flush()?;
+    Ok(())
+}
+
+/// Read text from the system clipboard. Over SSH the terminal's own
+/// paste shortcut has to be used instead.
+pub fn paste() -> Result<String> {
+    let mut clipboard = arboard::Clipboard::new().context("System clipboard unavailable")?;
+    clipboard
+        .get_text()
+        .context("Clipboard does not contain text")
+}
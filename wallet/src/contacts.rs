@@ -0,0 +1,314 @@
+use crate::core::{Config, Core, Recipient};
+use anyhow::{Context, Result, anyhow, bail};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use btclib::address::Address;
+use btclib::crypto::{PrivateKey, PublicKey, Signature};
+use clap::{Subcommand, ValueEnum};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use tracing::*;
+
+/// Manage the contacts listed in the wallet config
+#[derive(Subcommand)]
+pub enum ContactCommand {
+    /// Add the contacts in a CSV or JSON file, skipping invalid ones
+    /// and ones already known
+    Import {
+        #[arg(value_name = "FILE")]
+        file: PathBuf,
+        /// Defaults to the file extension
+        #[arg(short, long)]
+        format: Option<ContactFormat>,
+    },
+    /// Write the contacts to a CSV or JSON file
+    Export {
+        #[arg(value_name = "FILE")]
+        file: PathBuf,
+        /// Defaults to the file extension
+        #[arg(short, long)]
+        format: Option<ContactFormat>,
+    },
+    /// List the contacts, their addresses and notes
+    List,
+    /// Print a proof that one of this wallet's addresses is yours, to
+    /// give to the people who pay you
+    Prove {
+        /// Name to be known by
+        #[arg(short, long)]
+        name: String,
+        /// Defaults to the address of the first key
+        #[arg(short, long)]
+        address: Option<String>,
+    },
+    /// Add a contact from the proof they sent, or mark the contact
+    /// with that address as verified
+    AddProof {
+        /// The proof, or a file it is in
+        proof: String,
+    },
+}
+
+const PROOF_PREFIX: &str = "grapheno-proof:";
+
+/// A signed statement that an address belongs to whoever holds its
+/// key, which the people paying it can check
+#[derive(Serialize, Deserialize, Clone)]
+pub struct OwnershipProof {
+    pub name: String,
+    pub address: String,
+    pub public_key: PublicKey,
+    pub signature: Signature,
+}
+
+impl OwnershipProof {
+    pub fn new(name: String, address: String, key: &PrivateKey) -> Self {
+        let signature = Signature::sign_message(&proof_message(&name, &address), key);
+        Self {
+            name,
+            address,
+            public_key: key.public_key(),
+            signature,
+        }
+    }
+
+    /// Whether the key signed this and `address` is the key's
+    pub fn is_valid(&self) -> bool {
+        Address::parse(&self.address).is_ok_and(|address| address == Address::from_public_key(&self.public_key))
+            && self
+                .signature
+                .verify_message(&proof_message(&self.name, &self.address), &self.public_key)
+    }
+
+    /// Text that can be pasted or sent around
+    pub fn encode(&self) -> String {
+        let mut bytes = vec![];
+        ciborium::into_writer(self, &mut bytes).expect("proof serializes");
+        format!("{}{}", PROOF_PREFIX, STANDARD.encode(bytes))
+    }
+
+    /// Read a proof from `encode`, checking its signature
+    pub fn decode(text: &str) -> Result<Self> {
+        let encoded = text
+            .trim()
+            .strip_prefix(PROOF_PREFIX)
+            .ok_or_else(|| anyhow!("Not an ownership proof"))?;
+        let bytes = STANDARD.decode(encoded).context("Malformed ownership proof")?;
+        let proof: Self = ciborium::from_reader(bytes.as_slice()).context("Malformed ownership proof")?;
+        if !proof.is_valid() {
+            bail!("The ownership proof for {} has a bad signature", proof.address);
+        }
+        Ok(proof)
+    }
+}
+
+fn proof_message(name: &str, address: &str) -> String {
+    format!("I, {}, own the Grapheno address {}", name, address)
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum ContactFormat {
+    Csv,
+    Json,
+}
+
+impl ContactFormat {
+    /// The format a file is in, by its extension
+    pub fn of(path: &Path) -> Result<Self> {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some(extension) if extension.eq_ignore_ascii_case("csv") => Ok(Self::Csv),
+            Some(extension) if extension.eq_ignore_ascii_case("json") => Ok(Self::Json),
+            _ => bail!("Can't tell the format of {}, expected a .csv or .json file", path.display()),
+        }
+    }
+}
+
+/// A contact as a CSV line, with its addresses separated by spaces
+#[derive(Serialize, Deserialize)]
+struct CsvContact {
+    name: String,
+    #[serde(alias = "address")]
+    addresses: String,
+    #[serde(default)]
+    notes: String,
+}
+
+/// What importing contacts did
+#[derive(Default)]
+pub struct Import {
+    pub added: usize,
+    /// Contacts left out, with the reason
+    pub skipped: Vec<String>,
+}
+
+pub fn read(path: &Path, format: ContactFormat) -> Result<Vec<Recipient>> {
+    let context = || anyhow!("Failed to read contacts from {}", path.display());
+    match format {
+        ContactFormat::Json => {
+            let text = fs::read_to_string(path).with_context(context)?;
+            serde_json::from_str(&text).with_context(context)
+        }
+        ContactFormat::Csv => {
+            let mut reader = csv::Reader::from_path(path).with_context(context)?;
+            let mut contacts = vec![];
+            for record in reader.deserialize() {
+                let record: CsvContact = record.with_context(context)?;
+                let mut addresses = record.addresses.split_whitespace().map(str::to_string);
+                let address = addresses.next().unwrap_or_default();
+                contacts.push(Recipient {
+                    name: record.name,
+                    address,
+                    other_addresses: addresses.collect(),
+                    notes: Some(record.notes).filter(|notes| !notes.is_empty()),
+                    proof: None,
+                });
+            }
+            Ok(contacts)
+        }
+    }
+}
+
+pub fn write(path: &Path, format: ContactFormat, contacts: &[Recipient]) -> Result<()> {
+    let context = || anyhow!("Failed to write contacts to {}", path.display());
+    match format {
+        ContactFormat::Json => {
+            let file = File::create(path).with_context(context)?;
+            serde_json::to_writer_pretty(file, contacts).with_context(context)
+        }
+        ContactFormat::Csv => {
+            let mut writer = csv::Writer::from_path(path).with_context(context)?;
+            for contact in contacts {
+                writer
+                    .serialize(CsvContact {
+                        name: contact.name.clone(),
+                        addresses: contact.addresses().cloned().collect::<Vec<String>>().join(" "),
+                        notes: contact.notes.clone().unwrap_or_default(),
+                    })
+                    .with_context(context)?;
+            }
+            writer.flush().with_context(context)
+        }
+    }
+}
+
+/// Add the contacts to the config, leaving out those with an invalid
+/// address or a name or address another contact has
+pub fn merge(config: &mut Config, incoming: Vec<Recipient>) -> Import {
+    let params = config.network.params();
+    let mut import = Import::default();
+    for contact in incoming {
+        let name = contact.name.trim();
+        if name.is_empty() {
+            import.skipped.push(format!("{}: no name", contact.address));
+            continue;
+        }
+        if let Some(address) = contact
+            .addresses()
+            .find(|address| !PublicKey::validate_address(address, &params).unwrap_or(false))
+        {
+            import.skipped.push(format!("{}: invalid address {:?}", name, address));
+            continue;
+        }
+        if config.contacts.iter().any(|known| known.name == name) {
+            import.skipped.push(format!("{}: name already used", name));
+            continue;
+        }
+        if let Some((known, address)) = config
+            .contacts
+            .iter()
+            .find_map(|known| contact.addresses().find(|address| known.has_address(address)).map(|address| (known, address)))
+        {
+            import
+                .skipped
+                .push(format!("{}: {} is already {}'s", name, address, known.name));
+            continue;
+        }
+        config.contacts.push(Recipient {
+            name: name.to_string(),
+            ..contact
+        });
+        import.added += 1;
+    }
+    import
+}
+
+/// Add the contact a proof is for, or attach the proof to the contact
+/// that already has its address. Returns the contact's name.
+pub fn add_proof(config: &mut Config, proof: OwnershipProof) -> Result<String> {
+    let params = config.network.params();
+    if !PublicKey::validate_address(&proof.address, &params).unwrap_or(false) {
+        bail!("{} is not a {} address", proof.address, config.network);
+    }
+    if let Some(contact) = config.contacts.iter_mut().find(|contact| contact.has_address(&proof.address)) {
+        contact.proof = Some(proof.encode());
+        return Ok(contact.name.clone());
+    }
+    if config.contacts.iter().any(|contact| contact.name == proof.name) {
+        bail!("Contact with name '{}' already exists", proof.name);
+    }
+    let mut contact = Recipient::new(proof.name.clone(), proof.address.clone());
+    contact.proof = Some(proof.encode());
+    config.contacts.push(contact);
+    Ok(proof.name)
+}
+
+/// Read a proof given as is or in a file
+pub fn proof_argument(proof: &str) -> Result<OwnershipProof> {
+    if proof.trim().starts_with(PROOF_PREFIX) {
+        return OwnershipProof::decode(proof);
+    }
+    let text = fs::read_to_string(proof).with_context(|| anyhow!("Failed to read proof from {}", proof))?;
+    OwnershipProof::decode(&text)
+}
+
+/// Run a contact command against the config file at `config_path`
+pub fn run(config_path: &Path, command: ContactCommand) -> Result<()> {
+    let mut config = Config::load(config_path)?;
+    match command {
+        ContactCommand::Prove { name, address } => {
+            let core = Core::load_offline(config_path.to_path_buf())?;
+            println!("{}", core.prove_address(name, address.as_deref())?.encode());
+        }
+        ContactCommand::AddProof { proof } => {
+            let proof = proof_argument(&proof)?;
+            let name = add_proof(&mut config, proof)?;
+            config.save(config_path)?;
+            println!("Contact {} verified", name);
+        }
+        ContactCommand::Import { file, format } => {
+            let format = format.map_or_else(|| ContactFormat::of(&file), Ok)?;
+            let import = merge(&mut config, read(&file, format)?);
+            for skipped in &import.skipped {
+                println!("Skipped {}", skipped);
+            }
+            println!("Imported {} contacts from {}", import.added, file.display());
+            if import.added > 0 {
+                info!("Adding {} contacts to {:?}", import.added, config_path);
+                config.save(config_path)?;
+            }
+        }
+        ContactCommand::Export { file, format } => {
+            let format = format.map_or_else(|| ContactFormat::of(&file), Ok)?;
+            write(&file, format, &config.contacts)?;
+            println!("Exported {} contacts to {}", config.contacts.len(), file.display());
+        }
+        ContactCommand::List => {
+            if config.contacts.is_empty() {
+                println!("No contacts configured");
+            }
+            for contact in &config.contacts {
+                let addresses: Vec<&str> = contact.addresses().map(String::as_str).collect();
+                let name = match contact.verified() {
+                    true => format!("{} (verified)", contact.name),
+                    false => contact.name.clone(),
+                };
+                match &contact.notes {
+                    Some(notes) => println!("{}\t{}\t{}", name, addresses.join(" "), notes),
+                    None => println!("{}\t{}", name, addresses.join(" ")),
+                }
+            }
+        }
+    }
+    Ok(())
+}
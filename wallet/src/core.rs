@@ -1,41 +1,132 @@
 use anyhow::{Context, Result, anyhow};
-use btclib::crypto::{PrivateKey, PublicKey, Signature};
-use btclib::network::{Envelope, Message};
-use btclib::types::{Transaction, TransactionInput, TransactionOutput};
+use btclib::address::{Address, AddressFormat};
+use btclib::bip32::{DerivationPath, ExtendedPrivateKey};
+use btclib::crypto::{PrivateKey, PublicKey};
+use btclib::error::BtcError;
+use btclib::multisig::MultisigPolicy;
+use btclib::network::{Envelope, Message, PROOF_PROTOCOL_VERSION, PROTOCOL_VERSION, Services, VersionInfo};
+use btclib::params::{ChainParams, Network};
+use btclib::sha256::Hash;
+use btclib::types::{
+    PartiallySignedTransaction, Transaction, TransactionOutput, TransactionProof, UnsignedInput,
+    UnsignedTransaction,
+};
 use btclib::util::Saveable;
 use crossbeam_skiplist::SkipMap;
 use kanal::Sender;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::net::TcpStream;
-use tokio::sync::{Mutex, oneshot};
+use tokio::sync::{Mutex, broadcast, oneshot};
 use tokio::io::AsyncReadExt;
 use tracing::*;
+use crate::balances::{BalanceHistory, Snapshot};
+use crate::contacts::{self, ContactFormat, Import, OwnershipProof};
+use crate::accounts::scan_account;
+use crate::keys::{DEFAULT_GAP_LIMIT, fetch_usage};
+use crate::outbox::{Outbox, QueuedTransaction};
+use crate::signer::{DaemonSigner, FileSigner, LocalSigner, Signed, Signer, SignerConfig};
+use crate::util::write_atomic;
+use crate::validate;
 use uuid::Uuid;
 
-const DEFAULT_TTL: u8 = 8;
+pub(crate) const DEFAULT_TTL: u8 = 8;
+// how long a node gets to announce its services
+const PROBE_TIMEOUT: tokio::time::Duration = tokio::time::Duration::from_secs(2);
+const UTXO_UPDATE_BUFFER: usize = 16;
+/// Change addresses of an account watched past the used ones, so change
+/// sent to them is seen without a restart
+const CHANGE_LOOKAHEAD: u32 = 5;
 
 /// Represent a key pair with paths to public and private keys
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Key {
+    /// Name given to the key by `wallet key` commands
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
     pub public: PathBuf,
-    pub private: PathBuf,
+    /// Missing for watch-only keys whose transactions are signed by an
+    /// external signer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<PathBuf>,
 }
 
-/// Represent a loaded key pair with actual public and private keys
+impl Key {
+    /// The configured name, or the public key file name without its
+    /// `.pub.pem` extension for keys added by hand
+    pub fn display_name(&self) -> String {
+        if let Some(name) = &self.name {
+            return name.clone();
+        }
+        let file_name = self
+            .public
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        file_name
+            .strip_suffix(".pub.pem")
+            .map(str::to_string)
+            .unwrap_or(file_name)
+    }
+}
+
+/// Represent a loaded key pair with actual public and private keys.
+/// The private key wipes itself when the last copy is dropped.
 #[derive(Clone)]
 struct LoadedKey {
     public: PublicKey,
-    private: PrivateKey,
+    private: Option<PrivateKey>,
 }
 
 /// Represent a recipient with a name and Bitcoin address
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Recipient {
     pub name: String,
+    /// Address paid when sending to the contact by name
     pub address: String,
+    /// Other addresses of the contact, recognised but never paid to
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub other_addresses: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notes: Option<String>,
+    /// Ownership proof the contact sent for their address
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proof: Option<String>,
+}
+
+impl Recipient {
+    pub fn new(name: String, address: String) -> Self {
+        Self {
+            name,
+            address,
+            other_addresses: vec![],
+            notes: None,
+            proof: None,
+        }
+    }
+
+    /// Whether the contact proved that their address is theirs
+    pub fn verified(&self) -> bool {
+        self.proof
+            .as_deref()
+            .and_then(|proof| OwnershipProof::decode(proof).ok())
+            .is_some_and(|proof| Address::same(&proof.address, &self.address))
+    }
+
+    /// The address paid first, then the others
+    pub fn addresses(&self) -> impl Iterator<Item = &String> {
+        std::iter::once(&self.address).chain(&self.other_addresses)
+    }
+
+    /// Whether any of the contact's addresses is `address`, in either
+    /// format
+    pub fn has_address(&self, address: &str) -> bool {
+        self.addresses().any(|own| Address::same(own, address))
+    }
 }
 
 /// Define the type of fee calculation
@@ -52,13 +143,300 @@ pub struct FeeConfig {
     pub value: f64,
 }
 
+/// Unit amounts are shown and entered in
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum DisplayUnit {
+    #[default]
+    Btc,
+    Sats,
+}
+
+impl DisplayUnit {
+    pub fn toggled(self) -> Self {
+        match self {
+            DisplayUnit::Btc => DisplayUnit::Sats,
+            DisplayUnit::Sats => DisplayUnit::Btc,
+        }
+    }
+}
+
+/// Where the value of the balance in another currency comes from
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Debug)]
+#[serde(tag = "type")]
+pub enum PriceSource {
+    /// Only show amounts in BTC or sats
+    #[default]
+    None,
+    /// A rate set by hand, there is no market for this chain to ask
+    Fixed { currency: String, per_btc: f64 },
+}
+
+impl PriceSource {
+    /// `sats` in the other currency, if there is one
+    pub fn convert(&self, sats: u64) -> Option<String> {
+        match self {
+            PriceSource::None => None,
+            PriceSource::Fixed { currency, per_btc } => Some(format!(
+                "{:.2} {}",
+                sats as f64 / btclib::amount::SATS_PER_BTC as f64 * per_btc,
+                currency
+            )),
+        }
+    }
+}
+
+/// Colors of the wallet UI
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum ThemeName {
+    /// Blue background with white windows
+    #[default]
+    Default,
+    Dark,
+    Light,
+    /// The terminal's own colors
+    Terminal,
+}
+
+impl ThemeName {
+    pub const ALL: [ThemeName; 4] = [
+        ThemeName::Default,
+        ThemeName::Dark,
+        ThemeName::Light,
+        ThemeName::Terminal,
+    ];
+}
+
+/// Keys moving around the UI
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum Keybindings {
+    /// Arrow keys, Tab and Enter
+    #[default]
+    Default,
+    /// Also h, j, k and l to move, g and G for the first and last item
+    Vim,
+}
+
+/// Configure the look and feel of the UI
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct UiConfig {
+    #[serde(default)]
+    pub theme: ThemeName,
+    /// Colors replacing those of the theme, by palette entry such as
+    /// `background` or `highlight`, as names like `light blue` or
+    /// `#rrggbb`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub palette: BTreeMap<String, String>,
+    #[serde(default)]
+    pub keybindings: Keybindings,
+    /// Times per second the screen is redrawn and balances and
+    /// notifications are picked up, 0 to only redraw on input
+    #[serde(default = "default_refresh_rate")]
+    pub refresh_rate: u32,
+}
+
+fn default_refresh_rate() -> u32 {
+    30
+}
+
+impl Default for UiConfig {
+    fn default() -> Self {
+        Self {
+            theme: ThemeName::default(),
+            palette: BTreeMap::new(),
+            keybindings: Keybindings::default(),
+            refresh_rate: default_refresh_rate(),
+        }
+    }
+}
+
+/// Configure how the user is told about received funds
+#[derive(Serialize, Deserialize, Clone)]
+pub struct NotificationConfig {
+    /// Show a popup in the wallet UI
+    pub popup: bool,
+    /// Also send a desktop notification
+    pub desktop: bool,
+}
+
+impl Default for NotificationConfig {
+    fn default() -> Self {
+        Self {
+            popup: true,
+            desktop: false,
+        }
+    }
+}
+
 /// Store the configuration for the Core
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Config {
     pub my_keys: Vec<Key>,
     pub contacts: Vec<Recipient>,
     pub default_node: String,
+    /// More nodes to use. When set, the wallet connects to the first
+    /// of these and `default_node` that answers wallet queries.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub nodes: Vec<String>,
+    pub fee_config: FeeConfig,
+    #[serde(default)]
+    pub notifications: NotificationConfig,
+    #[serde(default)]
+    pub display_unit: DisplayUnit,
+    #[serde(default)]
+    pub price_source: PriceSource,
+    #[serde(default)]
+    pub ui: UiConfig,
+    /// Format addresses are shown in, both are accepted as recipients
+    #[serde(default)]
+    pub address_format: AddressFormat,
+    /// Network the node runs on, addresses of other networks are refused
+    #[serde(default)]
+    pub network: Network,
+    #[serde(default)]
+    pub signer: SignerConfig,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub multisig: Vec<MultisigAccount>,
+    /// Only fetch outputs with at least this many confirmations
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_confirmations: Option<u64>,
+    /// Extended key file the accounts are derived from, written by
+    /// `wallet account init`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub accounts: Vec<Account>,
+}
+
+/// Sections of the config only read when the wallet starts
+const RESTART_SECTIONS: [&str; 6] = ["my_keys", "network", "signer", "multisig", "seed", "accounts"];
+
+/// The settings that can be changed from the UI
+#[derive(Clone)]
+pub struct Settings {
     pub fee_config: FeeConfig,
+    pub default_node: String,
+    pub display_unit: DisplayUnit,
+    pub price_source: PriceSource,
+    pub theme: ThemeName,
+    pub keybindings: Keybindings,
+}
+
+/// An m-of-n account shared with cosigners
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MultisigAccount {
+    pub name: String,
+    /// Number of cosigners that have to sign
+    pub threshold: usize,
+    /// Public key files of all cosigners, ours included
+    pub cosigners: Vec<PathBuf>,
+}
+
+impl MultisigAccount {
+    /// Load the cosigner keys and build the account's policy
+    pub fn policy(&self) -> Result<MultisigPolicy> {
+        let keys = self
+            .cosigners
+            .iter()
+            .map(|path| {
+                PublicKey::load_from_file(path)
+                    .context(anyhow!("Failed to load cosigner key {}", path.display()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        MultisigPolicy::new(self.threshold, keys)
+            .context(anyhow!("Invalid multisig account {}", self.name))
+    }
+}
+
+/// A BIP44 account, with its own receive and change addresses derived
+/// from the seed
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Account {
+    pub name: String,
+    /// Account number in the derivation path
+    pub account: u32,
+    /// Path of the account key when not `m/44'/<coin>'/<account>'`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<DerivationPath>,
+    /// Number of receive addresses handed out
+    #[serde(default = "default_receive")]
+    pub receive: u32,
+    /// Number of change addresses used
+    #[serde(default)]
+    pub change: u32,
+}
+
+fn default_receive() -> u32 {
+    1
+}
+
+impl Account {
+    /// Path of the account key
+    pub fn path(&self, network: Network) -> DerivationPath {
+        self.path
+            .clone()
+            .unwrap_or_else(|| DerivationPath::bip44_account(network, self.account))
+    }
+
+    /// Key of the `index`th receive or change address
+    pub fn key(&self, seed: &ExtendedPrivateKey, network: Network, change: bool, index: u32) -> Result<PrivateKey> {
+        let path = self.path(network).child(change as u32).child(index);
+        let key = seed.derive(&path).context(anyhow!("Failed to derive {}", path))?;
+        Ok(key.private_key().clone())
+    }
+}
+
+impl Config {
+    /// Write an address in the preferred format. Addresses are kept in
+    /// Base58 internally and only converted for display.
+    pub fn format_address(&self, address: &str) -> String {
+        match Address::parse(address) {
+            Ok(parsed) => parsed.encode(self.address_format, &self.network.params()),
+            Err(_) => address.to_string(),
+        }
+    }
+
+    /// Names of the top level settings that differ between two configs
+    pub fn changed_sections(&self, other: &Config) -> Result<Vec<String>> {
+        let (toml::Value::Table(ours), toml::Value::Table(theirs)) =
+            (toml::Value::try_from(self)?, toml::Value::try_from(other)?)
+        else {
+            return Err(anyhow!("Config is not a table"));
+        };
+        let mut changed: Vec<String> = ours
+            .iter()
+            .filter(|(name, value)| theirs.get(*name) != Some(value))
+            .map(|(name, _)| name.clone())
+            .chain(theirs.keys().filter(|name| !ours.contains_key(*name)).cloned())
+            .collect();
+        changed.sort();
+        Ok(changed)
+    }
+
+    /// Read a config file
+    pub fn load(path: &Path) -> Result<Self> {
+        let config_str =
+            fs::read_to_string(path).context(anyhow!("Failed to read config file"))?;
+        validate::parse(&config_str).map_err(|problems| validate::problems_error(path, &problems))
+    }
+
+    /// Read a config file and check the files and values it refers to
+    pub fn load_checked(path: &Path) -> Result<Self> {
+        let config = Self::load(path)?;
+        let problems = validate::check(&config);
+        if !problems.is_empty() {
+            return Err(validate::problems_error(path, &problems));
+        }
+        Ok(config)
+    }
+
+    /// Write the config file, replacing the old one atomically so an
+    /// interrupted write never leaves a truncated config behind
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let config_str = toml::to_string_pretty(self)?;
+        write_atomic(path, config_str.as_bytes())
+            .context(anyhow!("Failed to write config file"))?;
+        Ok(())
+    }
 }
 
 /// Store and manage Unspent Transaction Outputs (UTXOs) for the Core
@@ -69,21 +447,86 @@ struct UtxoStore {
     utxos: Arc<SkipMap<String, Vec<(bool, TransactionOutput)>>>,
     // Map from address to the public key that owns it (for signing)
     address_to_key: Arc<SkipMap<String, PublicKey>>,
+    // multisig accounts by name
+    multisig: Vec<(String, MultisigPolicy)>,
+    // HD accounts in config order
+    accounts: Vec<LoadedAccount>,
+    // addresses are kept in Base58 for this chain
+    params: ChainParams,
 }
 
 impl UtxoStore {
-    fn new() -> Self {
+    fn new(params: ChainParams) -> Self {
         Self {
             my_keys: vec![],
             utxos: Arc::new(SkipMap::new()),
             address_to_key: Arc::new(SkipMap::new()),
+            multisig: vec![],
+            accounts: vec![],
+            params,
         }
     }
     fn add_key(&mut self, key: LoadedKey) {
-        let address = key.public.to_address();
+        let address = key.public.to_address_for(&self.params);
         self.address_to_key.insert(address.clone(), key.public.clone());
         self.my_keys.push(key);
     }
+
+    /// Derive the keys of an account's addresses: the receive ones
+    /// handed out, the change ones used and `CHANGE_LOOKAHEAD` more
+    fn add_account(&mut self, account: &Account, seed: &ExtendedPrivateKey, network: Network) -> Result<()> {
+        let mut loaded = LoadedAccount {
+            name: account.name.clone(),
+            receive: vec![],
+            change: vec![],
+        };
+        for (change, count) in [(false, account.receive.max(1)), (true, account.change + CHANGE_LOOKAHEAD)] {
+            for index in 0..count {
+                let private = account.key(seed, network, change, index)?;
+                let public = private.public_key();
+                let addresses = if change { &mut loaded.change } else { &mut loaded.receive };
+                addresses.push(public.to_address_for(&self.params));
+                self.add_key(LoadedKey {
+                    public,
+                    private: Some(private),
+                });
+            }
+        }
+        self.accounts.push(loaded);
+        Ok(())
+    }
+}
+
+/// Addresses of a loaded account as the UTXOs are stored under
+#[derive(Clone)]
+struct LoadedAccount {
+    name: String,
+    receive: Vec<String>,
+    change: Vec<String>,
+}
+
+/// Balance of a single wallet address
+#[derive(Clone, PartialEq)]
+pub struct AddressBalance {
+    /// In the preferred address format
+    pub address: String,
+    /// Value of all UTXOs the node reports for the address
+    pub confirmed: u64,
+    /// Change still waiting for confirmation, negative while UTXOs of
+    /// the address are being spent by mempool transactions
+    pub unconfirmed_delta: i64,
+    pub utxo_count: usize,
+}
+
+/// Result of sending from the wallet
+pub enum SendOutcome {
+    /// Accepted by the node
+    Sent(Hash),
+    /// Written to a file for an external signer
+    Exported(PathBuf),
+    /// Signed, but the node couldn't be reached. It is broadcast from
+    /// the outbox once the node is back.
+    Queued(Hash),
 }
 
 /// Transaction result for reporting back to UI
@@ -94,80 +537,220 @@ pub enum TransactionResult {
     Error(String),
 }
 
+/// A transaction sent from the wallet in this session
+#[derive(Clone)]
+pub struct SentTransaction {
+    pub txid: Hash,
+    pub recipient: String,
+    pub amount: u64,
+    pub fee: u64,
+    /// The transaction before signing, kept to rebuild it with a
+    /// higher fee
+    psbt: PartiallySignedTransaction,
+    replaced_by: Option<Hash>,
+    /// Height and hash of the block it was mined in, as last checked
+    /// against the node's main chain
+    pub confirmed_in: Option<(u64, Hash)>,
+    /// Merkle proof of the transaction in the block of `confirmed_in`,
+    /// checked against the block's header
+    pub proof: Option<TransactionProof>,
+    // the node's proof for confirmed_in didn't hold, so it isn't
+    // asked again
+    disproved: bool,
+    // its inputs were spent by another transaction that got mined
+    conflicted: bool,
+}
+
+impl SentTransaction {
+    /// Whether its block was shown to hold it, rather than the node
+    /// only saying so
+    pub fn verified(&self) -> bool {
+        self.proof.is_some()
+    }
+}
+
+/// Where a sent transaction stands according to the node
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SendStatus {
+    /// In the mempool, its inputs are still marked as spent
+    Pending,
+    /// Its inputs are gone from the UTXO set
+    Confirmed,
+    /// Superseded by a fee bump
+    Replaced(Hash),
+    /// Its inputs are spendable again, the node dropped it
+    Dropped,
+    /// Another transaction spending its inputs was mined instead, e.g.
+    /// after a reorg took its block off the chain
+    Conflicted,
+    /// Waiting in the outbox for the node to be reachable
+    PendingBroadcast,
+}
+
+/// UTXOs of an address as last reported by the node
+pub type UtxoUpdate = (String, Vec<(bool, TransactionOutput)>);
+
 /// Core functionality for the wallet
 pub struct Core {
     pub config: Arc<RwLock<Config>>,
     config_path: PathBuf,
     utxos: UtxoStore,
+    utxo_updates: broadcast::Sender<UtxoUpdate>,
+    // messages waiting to be shown as popups by the UI
+    popup_sender: Sender<String>,
+    popup_receiver: kanal::Receiver<String>,
     pub tx_sender: Sender<(Transaction, Option<oneshot::Sender<TransactionResult>>)>,
-    pub stream: Mutex<TcpStream>,
+    /// None while offline, see `Core::load_offline`
+    pub stream: Mutex<Option<TcpStream>>,
+    // address of the node connected to, and the protocol version it
+    // announced once asked
+    node: RwLock<Option<(String, Option<u32>)>>,
     wallet_id: String,
+    signer: Box<dyn Signer>,
+    sent: RwLock<Vec<SentTransaction>>,
+    outbox: RwLock<Outbox>,
+    balances: RwLock<BalanceHistory>,
+    // account the balance, addresses and history are shown for, all
+    // of them when None
+    selected_account: RwLock<Option<usize>>,
 }
 
 impl Core {
-    fn new(config: Config, config_path: PathBuf, utxos: UtxoStore, stream: TcpStream) -> Self {
+    fn new(
+        config: Config,
+        config_path: PathBuf,
+        utxos: UtxoStore,
+        stream: Option<TcpStream>,
+        signer: Box<dyn Signer>,
+        outbox: Outbox,
+        balances: BalanceHistory,
+    ) -> Self {
         let (tx_sender, _) = kanal::bounded(10);
+        let (utxo_updates, _) = broadcast::channel(UTXO_UPDATE_BUFFER);
+        let (popup_sender, popup_receiver) = kanal::unbounded();
         Self {
             config: Arc::new(RwLock::new(config)),
             config_path,
             utxos,
+            utxo_updates,
+            popup_sender,
+            popup_receiver,
             tx_sender,
             stream: Mutex::new(stream),
+            node: RwLock::new(None),
             wallet_id: Uuid::new_v4().to_string(),
+            signer,
+            sent: RwLock::new(vec![]),
+            outbox: RwLock::new(outbox),
+            balances: RwLock::new(balances),
+            selected_account: RwLock::new(None),
         }
     }
 
-    /// Load the core from a config file
+    /// Load the core from a config file and connect to the node
     #[tracing::instrument(skip(config_path))]
     pub async fn load(config_path: PathBuf) -> Result<Self> {
-        let config_str =
-            fs::read_to_string(&config_path).context(anyhow!("Failed to read config file"))?;
-        let config: Config =
-            toml::from_str(&config_str).context(anyhow!("Failed to parse config file"))?;
+        let mut core = Self::load_offline(config_path)?;
+        let config = core.config.read().unwrap().clone();
+        let (stream, node) = connect_node(&config).await?;
+        *core.stream.get_mut() = Some(stream);
+        *core.node.get_mut().unwrap() = Some((node, None));
+        Ok(core)
+    }
 
-        let mut utxos = UtxoStore::new();
-        let stream = TcpStream::connect(&config.default_node)
-            .await
-            .context(format!("Failed to connect to node: {}", config.default_node))?;
+    /// Load the core from a config file without a node, with the UTXOs
+    /// cached by the last fetch
+    pub fn load_offline(config_path: PathBuf) -> Result<Self> {
+        let config = Config::load_checked(&config_path)?;
 
+        let mut utxos = UtxoStore::new(config.network.params());
         for key in &config.my_keys {
             let public = PublicKey::load_from_file(&key.public)
                 .context(anyhow!("Failed to load public key"))?;
-            let private = PrivateKey::load_from_file(&key.private)
-                .context(anyhow!("Failed to load private key"))?;
+            let private = match &key.private {
+                Some(path) => Some(
+                    PrivateKey::load_from_file(path)
+                        .context(anyhow!("Failed to load private key"))?,
+                ),
+                None => None,
+            };
             utxos.add_key(LoadedKey { public, private });
         }
-        Ok(Core::new(config, config_path, utxos, stream))
+        for account in &config.multisig {
+            utxos.multisig.push((account.name.clone(), account.policy()?));
+        }
+        if let Some(path) = &config.seed {
+            let seed = ExtendedPrivateKey::load_from_file(path)
+                .context(anyhow!("Failed to load the seed {}", path.display()))?;
+            for account in &config.accounts {
+                utxos.add_account(account, &seed, config.network)?;
+            }
+        }
+        let signer: Box<dyn Signer> = match &config.signer {
+            SignerConfig::Local => Box::new(LocalSigner::new(
+                utxos.my_keys.iter().filter_map(|key| key.private.clone()).collect(),
+            )),
+            SignerConfig::External { dir } => Box::new(FileSigner::new(dir.clone())),
+            SignerConfig::Daemon { address } => Box::new(DaemonSigner::new(address.clone())),
+        };
+        let outbox = Outbox::load(config_path.with_extension("outbox.cbor"))?;
+        if !outbox.entries().is_empty() {
+            info!("{} transactions waiting to be broadcast", outbox.entries().len());
+        }
+        let cache_path = utxo_cache_path(&config_path);
+        if cache_path.exists() {
+            let cached: Vec<UtxoUpdate> = ciborium::from_reader(fs::read(&cache_path)?.as_slice())
+                .context(anyhow!("Failed to parse UTXO cache {}", cache_path.display()))?;
+            for (address, outputs) in cached {
+                utxos.utxos.insert(address, outputs);
+            }
+        }
+        let balances = BalanceHistory::load(config_path.with_extension("balances.cbor"))?;
+        Ok(Core::new(config, config_path, utxos, None, signer, outbox, balances))
+    }
+
+    /// Remember the UTXOs for `load_offline`
+    fn save_utxo_cache(&self) -> Result<()> {
+        let cached: Vec<UtxoUpdate> = self
+            .utxos
+            .utxos
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect();
+        let mut bytes = vec![];
+        ciborium::into_writer(&cached, &mut bytes)?;
+        write_atomic(&utxo_cache_path(&self.config_path), &bytes)
+            .context(anyhow!("Failed to write UTXO cache"))
     }
     
     /// Reconnect to the node
-    async fn reconnect(&self) -> Result<()> {
-        let node_address = {
-            let config = self.config.read().unwrap();
-            config.default_node.clone()
-        };
-        
-        info!("Reconnecting to node: {}", node_address);
-        let new_stream = tokio::net::TcpStream::connect(&node_address).await?;
-        *self.stream.lock().await = new_stream;
+    pub async fn reconnect(&self) -> Result<()> {
+        let config = self.config.read().unwrap().clone();
+        info!("Reconnecting to node");
+        let (new_stream, node) = connect_node(&config).await?;
+        *self.stream.lock().await = Some(new_stream);
+        *self.node.write().unwrap() = Some((node, None));
         info!("Reconnected successfully");
         Ok(())
     }
 
     /// Fetch UTXOs from the node for all loaded keys
     pub async fn fetch_utxos(&self) -> Result<()> {
-        info!("Starting UTXO fetch for {} keys", self.utxos.my_keys.len());
-        for key in &self.utxos.my_keys {
-            let address = key.public.to_address();
+        let addresses = self.account_addresses(None);
+        let min_confirmations = self.config.read().unwrap().min_confirmations;
+        info!("Starting UTXO fetch for {} addresses", addresses.len());
+        for address in addresses {
             info!("Fetching UTXOs for address: {}", address);
-            let message = Message::FetchUTXOs(address.clone());
+            let message = Message::FetchUTXOs(address.clone(), min_confirmations);
             let envelope = Envelope::new(self.wallet_id.clone(), DEFAULT_TTL, message);
+            let mut stream = self.stream.lock().await;
+            let stream = connected(&mut stream)?;
             envelope
-                .send_async(&mut *self.stream.lock().await)
+                .send_async(stream)
                 .await
                 .context("Failed to send FetchUTXOs message")?;
 
-            let response_envelope = Envelope::receive_async(&mut *self.stream.lock().await)
+            let response_envelope = Envelope::receive_async(stream)
                 .await
                 .context("Failed to receive UTXOs response")?;
 
@@ -193,6 +776,8 @@ impl Core {
                     address.clone(),
                     new_utxos.clone(),
                 );
+                // nobody listening is fine
+                let _ = self.utxo_updates.send((address.clone(), new_utxos.clone()));
                 
                 // Compare with old UTXOs if they existed
                 if let Some(old_utxos_vec) = old_utxos {
@@ -223,9 +808,56 @@ impl Core {
             }
         }
         info!("UTXO fetch completed");
+        if let Err(e) = self.save_utxo_cache() {
+            warn!("{}", e);
+        }
+        let height = self.fetch_height().await?;
+        let snapshot = Snapshot {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            height,
+            balance: self.balance_of(None),
+        };
+        if let Err(e) = self.balances.write().unwrap().record(snapshot) {
+            warn!("{}", e);
+        }
         Ok(())
     }
 
+    /// Ask the node how long its chain is
+    async fn fetch_height(&self) -> Result<u64> {
+        let envelope = Envelope::new(self.wallet_id.clone(), DEFAULT_TTL, Message::AskDifference(0));
+        let mut stream = self.stream.lock().await;
+        let stream = connected(&mut stream)?;
+        envelope.send_async(stream).await.context("Failed to ask for the height")?;
+        match Envelope::receive_async(stream).await?.msg {
+            Message::Difference(height) => Ok(height.max(0) as u64),
+            _ => Err(anyhow!("Unexpected response from node")),
+        }
+    }
+
+    /// Balances seen so far, oldest first
+    pub fn balance_history(&self) -> std::sync::RwLockReadGuard<'_, BalanceHistory> {
+        self.balances.read().unwrap()
+    }
+
+    /// Subscribe to the UTXO sets received from the node
+    pub fn subscribe_utxo_updates(&self) -> broadcast::Receiver<UtxoUpdate> {
+        self.utxo_updates.subscribe()
+    }
+
+    /// Queue a message for the UI to show as a popup
+    pub fn queue_popup(&self, message: String) {
+        let _ = self.popup_sender.send(message);
+    }
+
+    /// Take the next message waiting to be shown as a popup
+    pub fn next_popup(&self) -> Option<String> {
+        self.popup_receiver.try_recv().ok().flatten()
+    }
+
     /// Send a transaction to the node and wait to detect if it was rejected
     pub async fn send_transaction(&self, transaction: Transaction) -> Result<TransactionResult> {
         info!("=== SENDING TRANSACTION TO NODE ===");
@@ -247,7 +879,11 @@ impl Core {
         
         // Send the transaction
         info!("Sending SubmitTransaction message to node...");
-        if let Err(e) = envelope.send_async(&mut *stream).await {
+        let sent = match connected(&mut stream) {
+            Ok(stream) => envelope.send_async(stream).await.map_err(anyhow::Error::from),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = sent {
             error!("Failed to send transaction: {}", e);
             // Try to reconnect
             drop(stream);
@@ -267,18 +903,23 @@ impl Core {
         
         // Try to read with a very short timeout - if connection is closed, this will fail
         let mut buf = [0u8; 1];
-        match tokio::time::timeout(
-            tokio::time::Duration::from_millis(50),
-            stream.read(&mut buf)
-        ).await {
+        let read = match connected(&mut stream) {
+            Ok(connection) => {
+                tokio::time::timeout(tokio::time::Duration::from_millis(50), connection.read(&mut buf)).await
+            }
+            Err(e) => Ok(Err(std::io::Error::other(e))),
+        };
+        match read {
             Ok(Ok(0)) | Ok(Err(_)) => {
                 // Connection was closed - transaction was likely rejected
                 warn!("Connection closed after sending transaction - likely rejected");
                 drop(stream);
                 
-                // Reconnect for future operations
+                // Reconnect for future operations. If that fails the
+                // node went away rather than rejecting the transaction.
                 if let Err(e) = self.reconnect().await {
                     error!("Failed to reconnect after transaction rejection: {}", e);
+                    return Err(anyhow!("Node unreachable: {}", e));
                 }
                 
                 Ok(TransactionResult::Rejected(
@@ -300,25 +941,101 @@ impl Core {
         }
     }
 
+    /// Broadcast a transaction signed by external signers, merging the
+    /// copies signed by each of them. The signatures are checked first
+    /// so a wrong file is caught before the node drops the connection
+    /// over it. A single file may also hold a transaction signed by
+    /// `create-tx`.
+    pub async fn broadcast_signed(&self, paths: &[PathBuf]) -> Result<Hash> {
+        if let [path] = paths
+            && let Ok(transaction) = Transaction::load_from_file(path)
+        {
+            let txid = transaction.hash();
+            return match self.send_transaction(transaction).await? {
+                TransactionResult::Success => Ok(txid),
+                TransactionResult::Rejected(reason) => Err(anyhow!("Transaction rejected: {}", reason)),
+                TransactionResult::Error(e) => Err(anyhow!("Transaction error: {}", e)),
+            };
+        }
+        let mut psbt: Option<PartiallySignedTransaction> = None;
+        for path in paths {
+            let signed = PartiallySignedTransaction::load_from_file(path)
+                .context(anyhow!("Failed to load {}", path.display()))?;
+            match &mut psbt {
+                Some(psbt) => psbt.merge(signed)?,
+                None => psbt = Some(signed),
+            }
+        }
+        let mut psbt = psbt.ok_or_else(|| anyhow!("No signed transaction given"))?;
+        psbt.finalize()?;
+        let transaction = psbt.extract()?;
+        let txid = transaction.hash();
+        match self.send_transaction(transaction).await? {
+            TransactionResult::Success => Ok(txid),
+            TransactionResult::Rejected(reason) => Err(anyhow!("Transaction rejected: {}", reason)),
+            TransactionResult::Error(e) => Err(anyhow!("Transaction error: {}", e)),
+        }
+    }
+
+    /// Build and sign a transaction from the cached UTXOs without the
+    /// node, to be sent later with `broadcast`. Its inputs are marked
+    /// spent in the cache so the next one doesn't spend them again.
+    pub fn create_offline(&self, recipient: &str, amount: u64, from: Option<&str>) -> Result<Signed> {
+        let recipient_address = self.resolve_recipient_address(recipient)?;
+        let psbt = self.create_transaction(&recipient_address, amount, from)?;
+        let spent: Vec<Hash> = psbt
+            .unsigned
+            .inputs
+            .iter()
+            .map(|input| input.prev_transaction_output_hash)
+            .collect();
+        let signed = self.signer.sign(psbt)?;
+        for entry in self.utxos.utxos.iter() {
+            if !entry.value().iter().any(|(_, utxo)| spent.contains(&utxo.hash())) {
+                continue;
+            }
+            let outputs = entry
+                .value()
+                .iter()
+                .map(|(marked, utxo)| (*marked || spent.contains(&utxo.hash()), utxo.clone()))
+                .collect();
+            self.utxos.utxos.insert(entry.key().clone(), outputs);
+        }
+        self.save_utxo_cache()?;
+        Ok(signed)
+    }
+
     /// Resolve recipient string to address (handles contact names or addresses)
     pub fn resolve_recipient_address(&self, recipient: &str) -> Result<String> {
         let config = self.config.read().unwrap();
         
         // First try contact name lookup
-        if let Some(contact) = config.contacts.iter().find(|r| r.name == recipient) {
-            return Ok(contact.address.clone());
-        }
+        let address = match config.contacts.iter().find(|r| r.name == recipient) {
+            Some(contact) => &contact.address,
+            None => recipient,
+        };
 
-        // If not found, validate as address
-        if PublicKey::validate_address(recipient)
-            .map_err(|e| anyhow!("Invalid address format: {}", e))? {
-            return Ok(recipient.to_string());
+        // Pay to the canonical Base58 form whatever format was given
+        match Address::parse_for(address, &self.utxos.params) {
+            Ok(address) => return Ok(address.to_base58(&self.utxos.params)),
+            Err(BtcError::WrongNetwork) => {
+                return Err(anyhow!("{} is not a {} address", address, config.network));
+            }
+            Err(_) => {}
         }
 
         Err(anyhow!("Recipient '{}' is neither a contact name nor a valid Bitcoin address", recipient))
     }
 
-    pub fn send_transaction_async(self: Arc<Self>, recipient: &str, amount: u64) -> Result<()> {
+    /// Create, sign and send a transaction, returning its id once the
+    /// node accepted it, or the file it was exported to when signing
+    /// happens elsewhere
+    pub fn send_transaction_async(
+        self: Arc<Self>,
+        recipient: &str,
+        amount: u64,
+        from: Option<String>,
+    ) -> Result<SendOutcome> {
         info!("Preparing to send {} satoshis to {}", amount, recipient);
 
         let recipient_address = self.resolve_recipient_address(recipient)?;
@@ -326,7 +1043,7 @@ impl Core {
         let tx_sender = self.tx_sender.clone();
         
         // Create a channel to receive the result from the async task
-        let (result_tx, result_rx) = oneshot::channel::<Result<()>>();
+        let (result_tx, result_rx) = oneshot::channel::<Result<SendOutcome>>();
         let result_tx = Arc::new(Mutex::new(Some(result_tx)));
         
         // Spawn async task to refresh UTXOs and create transaction
@@ -335,12 +1052,18 @@ impl Core {
             // Refresh UTXOs to ensure we have the latest state
             info!("Refreshing UTXOs before creating transaction");
             if let Err(e) = core.fetch_utxos().await {
-                let error_msg = format!("Failed to refresh UTXOs: {}", e);
-                error!("{}", error_msg);
-                if let Some(tx) = result_tx_clone.lock().await.take() {
-                    let _ = tx.send(Err(anyhow!("{}", error_msg)));
+                // the node may be down, the outbox broadcasts a
+                // transaction built from the last known UTXOs later
+                if core.get_balance() > 0 {
+                    warn!("Failed to refresh UTXOs, using the cached ones: {}", e);
+                } else {
+                    let error_msg = format!("Failed to refresh UTXOs: {}", e);
+                    error!("{}", error_msg);
+                    if let Some(tx) = result_tx_clone.lock().await.take() {
+                        let _ = tx.send(Err(anyhow!("{}", error_msg)));
+                    }
+                    return;
                 }
-                return;
             }
             
             // Small delay to ensure blockchain state is consistent
@@ -348,7 +1071,7 @@ impl Core {
             
             // Create transaction with fresh UTXOs
             info!("Creating transaction for {} satoshis to {}", amount, recipient_address);
-            let transaction = match core.create_transaction(&recipient_address, amount) {
+            let psbt = match core.create_transaction(&recipient_address, amount, from.as_deref()) {
                 Ok(tx) => {
                     info!("Transaction created successfully with {} inputs", tx.inputs.len());
                     tx
@@ -362,6 +1085,24 @@ impl Core {
                     return;
                 }
             };
+            let unsigned = psbt.clone();
+            let transaction = match core.signer.sign(psbt) {
+                Ok(Signed::Transaction(tx)) => tx,
+                Ok(Signed::Exported(path)) => {
+                    if let Some(tx) = result_tx_clone.lock().await.take() {
+                        let _ = tx.send(Ok(SendOutcome::Exported(path)));
+                    }
+                    return;
+                }
+                Err(e) => {
+                    let error_msg = format!("Failed to sign transaction: {}", e);
+                    error!("{}", error_msg);
+                    if let Some(tx) = result_tx_clone.lock().await.take() {
+                        let _ = tx.send(Err(anyhow!("{}", error_msg)));
+                    }
+                    return;
+                }
+            };
             
             // Log transaction details for debugging
             info!("Transaction created with {} inputs:", transaction.inputs.len());
@@ -374,10 +1115,11 @@ impl Core {
             }
             
             info!("Sending transaction to handler");
+            let txid = transaction.hash();
             
             // Create a result channel to get the transaction result
             let (tx_result_tx, tx_result_rx) = oneshot::channel::<TransactionResult>();
-            if let Err(e) = tx_sender.send((transaction, Some(tx_result_tx))) {
+            if let Err(e) = tx_sender.send((transaction.clone(), Some(tx_result_tx))) {
                 let error_msg = format!("Failed to send transaction to channel: {}", e);
                 error!("{}", error_msg);
                 if let Some(tx) = result_tx_clone.lock().await.take() {
@@ -390,8 +1132,9 @@ impl Core {
             match tx_result_rx.await {
                 Ok(TransactionResult::Success) => {
                     info!("Transaction accepted by node");
+                    core.record_sent(txid, recipient_address, amount, unsigned);
                     if let Some(tx) = result_tx_clone.lock().await.take() {
-                        let _ = tx.send(Ok(()));
+                        let _ = tx.send(Ok(SendOutcome::Sent(txid)));
                     }
                 }
                 Ok(TransactionResult::Rejected(reason)) => {
@@ -402,10 +1145,11 @@ impl Core {
                     }
                 }
                 Ok(TransactionResult::Error(e)) => {
-                    let error_msg = format!("Transaction error: {}", e);
-                    error!("{}", error_msg);
+                    warn!("Failed to broadcast transaction, queueing it: {}", e);
+                    let entry = QueuedTransaction::new(transaction, recipient_address, amount, unsigned, e);
+                    let result = core.outbox.write().unwrap().push(entry).map(|_| SendOutcome::Queued(txid));
                     if let Some(tx) = result_tx_clone.lock().await.take() {
-                        let _ = tx.send(Err(anyhow!("{}", error_msg)));
+                        let _ = tx.send(result);
                     }
                 }
                 Err(_) => {
@@ -435,27 +1179,690 @@ impl Core {
         })
     }
 
-    pub fn get_balance(&self) -> u64 {
-        self.utxos
+    fn record_sent(
+        &self,
+        txid: Hash,
+        recipient: String,
+        amount: u64,
+        psbt: PartiallySignedTransaction,
+    ) {
+        let fee = psbt.unsigned.input_value() - psbt.unsigned.output_value();
+        self.sent.write().unwrap().push(SentTransaction {
+            txid,
+            recipient,
+            amount,
+            fee,
+            psbt,
+            replaced_by: None,
+            confirmed_in: None,
+            proof: None,
+            disproved: false,
+            conflicted: false,
+        });
+    }
+
+    /// Transactions waiting in the outbox, then those sent in this
+    /// session, newest first
+    pub fn sent_transactions(&self) -> Vec<(SentTransaction, SendStatus)> {
+        let outbox = self.outbox.read().unwrap();
+        let queued = outbox.entries().iter().rev().map(|entry| {
+            let sent = SentTransaction {
+                txid: entry.txid(),
+                recipient: entry.recipient.clone(),
+                amount: entry.amount,
+                fee: entry.psbt.unsigned.input_value() - entry.psbt.unsigned.output_value(),
+                psbt: entry.psbt.clone(),
+                replaced_by: None,
+                confirmed_in: None,
+                proof: None,
+                disproved: false,
+                conflicted: false,
+            };
+            (sent, SendStatus::PendingBroadcast)
+        });
+        let sent = self.sent.read().unwrap();
+        // an account's history holds what was spent from its addresses
+        let addresses = self.selected_account().map(|account| self.account_addresses(Some(account)));
+        queued
+            .chain(sent.iter().rev().map(|entry| (entry.clone(), self.send_status(entry))))
+            .filter(|(entry, _)| {
+                addresses.as_ref().is_none_or(|addresses| {
+                    entry.psbt.inputs.iter().any(|input| addresses.contains(&input.utxo.address))
+                })
+            })
+            .collect()
+    }
+
+    /// Try to broadcast the transactions of the outbox whose backoff is
+    /// over. Stops at the first that fails, the node is likely down.
+    pub async fn retry_queued(&self) {
+        let due = self.outbox.read().unwrap().due();
+        if due.is_empty() {
+            return;
+        }
+        // the old connection may be dead without us knowing, which
+        // would look like a rejection
+        if let Err(e) = self.reconnect().await {
+            for entry in &due {
+                self.requeue(entry.txid(), format!("Node unreachable: {}", e));
+            }
+            return;
+        }
+        for entry in due {
+            let txid = entry.txid();
+            match self.send_transaction(entry.transaction.clone()).await {
+                Ok(TransactionResult::Success) => {
+                    info!("Broadcast queued transaction {}", txid);
+                    if let Err(e) = self.outbox.write().unwrap().remove(txid) {
+                        error!("{}", e);
+                    }
+                    self.record_sent(txid, entry.recipient, entry.amount, entry.psbt);
+                    self.queue_popup(format!("Queued transaction {} was broadcast", txid));
+                }
+                Ok(TransactionResult::Rejected(reason)) => {
+                    warn!("Queued transaction {} rejected: {}", txid, reason);
+                    if let Err(e) = self.outbox.write().unwrap().remove(txid) {
+                        error!("{}", e);
+                    }
+                    self.queue_popup(format!("Queued transaction {} was rejected: {}", txid, reason));
+                }
+                Ok(TransactionResult::Error(e)) => {
+                    self.requeue(txid, e);
+                    return;
+                }
+                Err(e) => {
+                    self.requeue(txid, e.to_string());
+                    return;
+                }
+            }
+        }
+    }
+
+    fn requeue(&self, txid: Hash, error: String) {
+        debug!("Broadcast of queued transaction {} failed: {}", txid, error);
+        if let Err(e) = self.outbox.write().unwrap().failed(txid, error) {
+            error!("{}", e);
+        }
+    }
+
+    fn send_status(&self, entry: &SentTransaction) -> SendStatus {
+        if let Some(txid) = entry.replaced_by {
+            return SendStatus::Replaced(txid);
+        }
+        // Our inputs stay in the UTXO set, marked, until the block
+        // spending them is mined
+        let mut status = SendStatus::Confirmed;
+        for input in &entry.psbt.unsigned.inputs {
+            let hash = input.prev_transaction_output_hash;
+            for utxos in self.utxos.utxos.iter() {
+                if let Some((marked, _)) = utxos.value().iter().find(|(_, utxo)| utxo.hash() == hash) {
+                    if *marked {
+                        return SendStatus::Pending;
+                    }
+                    status = SendStatus::Dropped;
+                }
+            }
+        }
+        if status == SendStatus::Confirmed && entry.conflicted {
+            return SendStatus::Conflicted;
+        }
+        status
+    }
+
+    /// Check the blocks the sent transactions were mined in against the
+    /// node's main chain. When a reorg takes one off it, the transaction
+    /// is pending again if it went back to the mempool, or conflicted if
+    /// another spending the same inputs got mined instead. Confirmed
+    /// transactions are verified with a merkle proof once, if the node
+    /// can send one.
+    pub async fn reconcile_history(&self) -> Result<()> {
+        let entries: Vec<SentTransaction> = self.sent.read().unwrap().clone();
+        for entry in entries {
+            if entry.replaced_by.is_some() {
+                continue;
+            }
+            let reorged = match entry.confirmed_in {
+                Some((height, hash)) => {
+                    if self.fetch_header_hash(height).await? == Some(hash) {
+                        if !entry.verified() && !entry.disproved {
+                            self.verify_inclusion(entry.txid, height, hash).await?;
+                        }
+                        continue;
+                    }
+                    info!("Block {} of transaction {} left the main chain", hash, entry.txid);
+                    true
+                }
+                None => false,
+            };
+            let status = self.send_status(&entry);
+            if !reorged && !matches!(status, SendStatus::Confirmed | SendStatus::Conflicted) {
+                continue;
+            }
+            let confirmed_in = match self.fetch_transaction_height(entry.txid).await? {
+                Some(height) => self.fetch_header_hash(height).await?.map(|hash| (height, hash)),
+                None => None,
+            };
+            // spent inputs and the transaction nowhere in the chain
+            let conflicted =
+                confirmed_in.is_none() && matches!(status, SendStatus::Confirmed | SendStatus::Conflicted);
+            if let Some(sent) = self.sent.write().unwrap().iter_mut().find(|sent| sent.txid == entry.txid) {
+                sent.confirmed_in = confirmed_in;
+                sent.proof = None;
+                sent.disproved = false;
+                sent.conflicted = conflicted;
+            }
+            let txid = entry.txid;
+            if let Some((height, hash)) = confirmed_in {
+                self.verify_inclusion(txid, height, hash).await?;
+            }
+            if conflicted && !entry.conflicted {
+                warn!("Transaction {} conflicts with a mined transaction", txid);
+                self.queue_popup(format!(
+                    "Transaction {} conflicts with a transaction mined instead, its payment won't go through",
+                    txid
+                ));
+            } else if reorged && let Some((height, hash)) = confirmed_in {
+                info!("Transaction {} was mined again in block {} at height {}", txid, hash, height);
+            } else if reorged && status == SendStatus::Pending {
+                self.queue_popup(format!(
+                    "Transaction {} is unconfirmed again, a reorg took its block off the chain",
+                    txid
+                ));
+            } else if reorged && status == SendStatus::Dropped {
+                self.queue_popup(format!(
+                    "Transaction {} was dropped after a reorg took its block off the chain",
+                    txid
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Ask the node to prove a transaction is in the block at `height`
+    /// with hash `hash`, taken from its header, and keep the proof in the
+    /// history if it holds. A proof that doesn't is reported, the
+    /// transaction stays unverified.
+    async fn verify_inclusion(&self, txid: Hash, height: u64, hash: Hash) -> Result<()> {
+        if !self.node_serves_proofs().await {
+            return Ok(());
+        }
+        let proof = match self.fetch_transaction_proof(txid).await? {
+            Some(proof) => proof,
+            None => {
+                debug!("The node has no proof of transaction {} in block {}", txid, hash);
+                return Ok(());
+            }
+        };
+        let mut sent = self.sent.write().unwrap();
+        let Some(entry) = sent.iter_mut().find(|entry| entry.txid == txid && entry.confirmed_in == Some((height, hash)))
+        else {
+            return Ok(());
+        };
+        if let Err(e) = check_proof(&proof, txid, height, hash) {
+            warn!("Proof of transaction {} from the node doesn't hold: {}", txid, e);
+            entry.disproved = true;
+            drop(sent);
+            self.queue_popup(format!(
+                "The node claims transaction {} is confirmed, but its proof doesn't hold: {}",
+                txid, e
+            ));
+            return Ok(());
+        }
+        debug!("Verified transaction {} in block {} at height {}", txid, hash, height);
+        entry.proof = Some(proof);
+        Ok(())
+    }
+
+    /// Whether the node is recent enough to answer FetchTransactionProof,
+    /// which older ones take for a misbehaving peer. Asked once per
+    /// connection.
+    async fn node_serves_proofs(&self) -> bool {
+        let Some((node, known)) = self.node.read().unwrap().clone() else {
+            return false;
+        };
+        let protocol_version = match known {
+            Some(protocol_version) => protocol_version,
+            None => match probe_version(&node).await {
+                Ok(version) => {
+                    if let Some((current, known)) = self.node.write().unwrap().as_mut()
+                        && *current == node
+                    {
+                        *known = Some(version.protocol_version);
+                    }
+                    version.protocol_version
+                }
+                Err(e) => {
+                    debug!("Failed to ask node {} for its version: {}", node, e);
+                    return false;
+                }
+            },
+        };
+        protocol_version >= PROOF_PROTOCOL_VERSION
+    }
+
+    /// Merkle proof of a confirmed transaction, None if the node has no
+    /// stored block holding it
+    async fn fetch_transaction_proof(&self, txid: Hash) -> Result<Option<TransactionProof>> {
+        let envelope = Envelope::new(self.wallet_id.clone(), DEFAULT_TTL, Message::FetchTransactionProof(txid));
+        let mut stream = self.stream.lock().await;
+        let stream = connected(&mut stream)?;
+        envelope.send_async(stream).await.context("Failed to ask for a transaction proof")?;
+        match Envelope::receive_async(stream).await?.msg {
+            Message::TransactionProof(_, proof) => Ok(proof),
+            _ => Err(anyhow!("Unexpected response from node")),
+        }
+    }
+
+    /// Height of the block holding a transaction, None if it isn't in
+    /// the node's main chain
+    async fn fetch_transaction_height(&self, txid: Hash) -> Result<Option<u64>> {
+        let envelope = Envelope::new(self.wallet_id.clone(), DEFAULT_TTL, Message::FetchTransaction(txid));
+        let mut stream = self.stream.lock().await;
+        let stream = connected(&mut stream)?;
+        envelope.send_async(stream).await.context("Failed to ask for a transaction")?;
+        match Envelope::receive_async(stream).await?.msg {
+            Message::TransactionInfo(_, found) => Ok(found.map(|(height, _)| height)),
+            _ => Err(anyhow!("Unexpected response from node")),
+        }
+    }
+
+    /// Hash of the node's main chain block at `height`, from its header
+    async fn fetch_header_hash(&self, height: u64) -> Result<Option<Hash>> {
+        let envelope = Envelope::new(self.wallet_id.clone(), DEFAULT_TTL, Message::FetchHeaders(height, 1));
+        let mut stream = self.stream.lock().await;
+        let stream = connected(&mut stream)?;
+        envelope.send_async(stream).await.context("Failed to ask for a block header")?;
+        match Envelope::receive_async(stream).await?.msg {
+            Message::Headers(_, headers) => Ok(headers.first().map(|header| header.hash())),
+            _ => Err(anyhow!("Unexpected response from node")),
+        }
+    }
+
+    /// Replace a pending transaction by one spending the same inputs
+    /// with its fee raised by `extra_fee`, taken from the change
+    pub async fn bump_fee(&self, txid: Hash, extra_fee: u64) -> Result<SendOutcome> {
+        let entry = self.pending_entry(txid, extra_fee).await?;
+        let mut unsigned = entry.psbt.unsigned.clone();
+        // create_transaction puts the change after the payment
+        let change = unsigned
+            .outputs
+            .get_mut(1)
+            .filter(|output| self.utxos.address_to_key.contains_key(&output.address))
+            .ok_or_else(|| anyhow!("Transaction {} has no change to pay a higher fee from", txid))?;
+        if change.value < extra_fee {
+            return Err(anyhow!(
+                "The change of {} is too small to raise the fee by {}",
+                change.value,
+                extra_fee
+            ));
+        }
+        change.value -= extra_fee;
+        change.unique_id = Uuid::new_v4();
+        if change.value == 0 {
+            unsigned.outputs.truncate(1);
+        }
+        let (recipient, amount) = (entry.recipient.clone(), entry.amount);
+        self.replace(entry, unsigned, recipient, amount).await
+    }
+
+    /// Invalidate a pending transaction by spending its inputs back to
+    /// the wallet, paying `extra_fee` more so the node replaces it
+    pub async fn cancel_transaction(&self, txid: Hash, extra_fee: u64) -> Result<SendOutcome> {
+        let entry = self.pending_entry(txid, extra_fee).await?;
+        let (unsigned, address, value) = self.cancellation(&entry, extra_fee)?;
+        self.replace(entry, unsigned, address, value).await
+    }
+
+    // the transaction spending the inputs of `entry` back to where
+    // they came from, with the address and value sent back
+    fn cancellation(&self, entry: &SentTransaction, extra_fee: u64) -> Result<(UnsignedTransaction, String, u64)> {
+        let mut unsigned = entry.psbt.unsigned.clone();
+        let input_value = unsigned.input_value();
+        let fee = entry.fee + extra_fee;
+        if fee >= input_value {
+            return Err(anyhow!(
+                "A fee of {} leaves nothing of the {} spent to send back",
+                fee,
+                input_value
+            ));
+        }
+        let address = self.refund_address(&unsigned)?;
+        unsigned.outputs = vec![TransactionOutput {
+            value: input_value - fee,
+            unique_id: Uuid::new_v4(),
+            address: address.clone(),
+        }];
+        Ok((unsigned, address, input_value - fee))
+    }
+
+    // where cancelling `unsigned` sends its inputs: the change chain of
+    // the account they were spent from, or the key that spent them
+    fn refund_address(&self, unsigned: &UnsignedTransaction) -> Result<String> {
+        let input = unsigned
+            .inputs
+            .first()
+            .ok_or_else(|| anyhow!("The transaction spends nothing to send back"))?;
+        let spender = input.public_key.to_address_for(&self.utxos.params);
+        match self
             .utxos
+            .accounts
             .iter()
+            .position(|account| account.receive.contains(&spender) || account.change.contains(&spender))
+        {
+            Some(index) => self.next_change_address(index),
+            None => Ok(spender),
+        }
+    }
+
+    /// Find a transaction of the history that can still be replaced
+    async fn pending_entry(&self, txid: Hash, extra_fee: u64) -> Result<SentTransaction> {
+        if extra_fee == 0 {
+            return Err(anyhow!("The fee has to increase"));
+        }
+        if self.outbox.read().unwrap().entries().iter().any(|entry| entry.txid() == txid) {
+            return Err(anyhow!("Transaction {} is still waiting to be broadcast", txid));
+        }
+        self.fetch_utxos().await?;
+        let entry = self
+            .sent
+            .read()
+            .unwrap()
+            .iter()
+            .find(|entry| entry.txid == txid)
+            .cloned()
+            .ok_or_else(|| anyhow!("Transaction {} was not sent from this wallet", txid))?;
+        match self.send_status(&entry) {
+            SendStatus::Pending => Ok(entry),
+            status => Err(anyhow!("Transaction {} is no longer pending ({:?})", txid, status)),
+        }
+    }
+
+    /// Sign and submit `unsigned` in place of `entry`, which spends the
+    /// same inputs, and record the replacement in the history
+    async fn replace(
+        &self,
+        entry: SentTransaction,
+        unsigned: UnsignedTransaction,
+        recipient: String,
+        amount: u64,
+    ) -> Result<SendOutcome> {
+        let spent = entry.psbt.inputs.iter().map(|input| input.utxo.clone()).collect();
+        let psbt = PartiallySignedTransaction::new(unsigned, spent)?;
+        let transaction = match self.signer.sign(psbt.clone())? {
+            Signed::Transaction(transaction) => transaction,
+            Signed::Exported(path) => return Ok(SendOutcome::Exported(path)),
+        };
+        let txid = transaction.hash();
+        match self.send_transaction(transaction).await? {
+            TransactionResult::Success => {}
+            TransactionResult::Rejected(reason) => {
+                return Err(anyhow!("Replacement rejected: {}", reason));
+            }
+            TransactionResult::Error(e) => return Err(anyhow!("Replacement error: {}", e)),
+        }
+        info!("Replaced {} by {}", entry.txid, txid);
+        if let Some(replaced) = self.sent.write().unwrap().iter_mut().find(|e| e.txid == entry.txid) {
+            replaced.replaced_by = Some(txid);
+        }
+        self.record_sent(txid, recipient, amount, psbt);
+        Ok(SendOutcome::Sent(txid))
+    }
+
+    /// Balance of the selected account
+    pub fn get_balance(&self) -> u64 {
+        self.balance_of(self.selected_account())
+    }
+
+    // balance of an account, or of the whole wallet with None
+    fn balance_of(&self, account: Option<usize>) -> u64 {
+        self.account_addresses(account)
+            .iter()
+            .filter_map(|address| self.utxos.utxos.get(address))
             .map(|entry| entry.value().iter().map(|utxo| utxo.1.value).sum::<u64>())
             .sum()
     }
 
-    /// Get all addresses for the loaded keys
+    /// Get the balance of every loaded address, in key order
+    pub fn get_address_balances(&self) -> Vec<AddressBalance> {
+        self.get_addresses()
+            .into_iter()
+            .map(|address| {
+                let utxos = self
+                    .utxos
+                    .utxos
+                    .get(&address)
+                    .map(|entry| entry.value().clone())
+                    .unwrap_or_default();
+                let confirmed = utxos.iter().map(|(_, utxo)| utxo.value).sum();
+                let pending_spend: u64 = utxos
+                    .iter()
+                    .filter(|(marked, _)| *marked)
+                    .map(|(_, utxo)| utxo.value)
+                    .sum();
+                AddressBalance {
+                    address: self.format_address(&address),
+                    confirmed,
+                    unconfirmed_delta: -(pending_spend as i64),
+                    utxo_count: utxos.len(),
+                }
+            })
+            .collect()
+    }
+
+    /// Get the addresses of the selected account
     pub fn get_addresses(&self) -> Vec<String> {
+        self.account_addresses(self.selected_account())
+    }
+
+    /// Addresses to receive to: those handed out by the selected
+    /// account, or all of the wallet's
+    pub fn receive_addresses(&self) -> Vec<String> {
+        match self.selected_account().and_then(|index| self.utxos.accounts.get(index)) {
+            Some(account) => account.receive.clone(),
+            None => self.account_addresses(None),
+        }
+    }
+
+    // the receive then change addresses of an account, or with None
+    // all addresses of the loaded keys followed by those of the
+    // multisig accounts
+    fn account_addresses(&self, account: Option<usize>) -> Vec<String> {
+        if let Some(account) = account.and_then(|index| self.utxos.accounts.get(index)) {
+            return account.receive.iter().chain(&account.change).cloned().collect();
+        }
         self.utxos
             .my_keys
             .iter()
-            .map(|key| key.public.to_address())
+            .map(|key| key.public.to_address_for(&self.utxos.params))
+            .chain(
+                self.utxos
+                    .multisig
+                    .iter()
+                    .map(|(_, policy)| policy.to_address_for(&self.utxos.params)),
+            )
+            .collect()
+    }
+
+    /// Names of the HD accounts, in config order
+    pub fn accounts(&self) -> Vec<String> {
+        self.utxos.accounts.iter().map(|account| account.name.clone()).collect()
+    }
+
+    /// Index of the account shown, None when showing the whole wallet
+    pub fn selected_account(&self) -> Option<usize> {
+        *self.selected_account.read().unwrap()
+    }
+
+    /// Show one account, or the whole wallet with None. Sending then
+    /// spends only from that account and sends the change back to it.
+    pub fn select_account(&self, account: Option<usize>) {
+        *self.selected_account.write().unwrap() = account.filter(|index| *index < self.utxos.accounts.len());
+    }
+
+    /// Look on the node for addresses of the HD accounts paid past the
+    /// ones the config counts as handed out, as after a restore, and
+    /// count them in. `progress` hears how the scan of each chain goes.
+    /// Returns how many more addresses the accounts have, which the
+    /// wallet watches once restarted.
+    pub async fn scan_accounts(&self, mut progress: impl FnMut(String)) -> Result<u32> {
+        let config = self.config.read().unwrap().clone();
+        let Some(path) = &config.seed else {
+            return Ok(0);
+        };
+        let seed = ExtendedPrivateKey::load_from_file(path)
+            .context(anyhow!("Failed to load the seed {}", path.display()))?;
+        let mut found = 0;
+        for (index, account) in config.accounts.iter().enumerate() {
+            let used = scan_account(
+                account,
+                &seed,
+                config.network,
+                DEFAULT_GAP_LIMIT,
+                |addresses| async move {
+                    let mut stream = self.stream.lock().await;
+                    fetch_usage(connected(&mut stream)?, addresses).await
+                },
+                |chain, checked, used| {
+                    progress(format!("{}: checked {} addresses of {}, {} used", account.name, checked, chain, used))
+                },
+            )
+            .await?;
+            let mut config = self.config.write().unwrap();
+            if let Some(account) = config.accounts.get_mut(index) {
+                for (handed_out, used) in [(&mut account.receive, used.0), (&mut account.change, used.1)] {
+                    found += used.saturating_sub(*handed_out);
+                    *handed_out = used.max(*handed_out);
+                }
+            }
+        }
+        self.save_config()?;
+        Ok(found)
+    }
+
+    /// Hand out the next change address of an account and record it
+    /// as used. Past the watched ones the last is reused until the
+    /// wallet restarts and derives more.
+    fn next_change_address(&self, index: usize) -> Result<String> {
+        let loaded = &self.utxos.accounts[index];
+        let address = {
+            let mut config = self.config.write().unwrap();
+            let account = config
+                .accounts
+                .get_mut(index)
+                .ok_or_else(|| anyhow!("Account {} is gone from the config", loaded.name))?;
+            let used = (account.change as usize).min(loaded.change.len() - 1);
+            account.change = (used + 1) as u32;
+            loaded.change[used].clone()
+        };
+        self.save_config()?;
+        Ok(address)
+    }
+
+    /// Names and addresses of the multisig accounts
+    pub fn multisig_accounts(&self) -> Vec<(String, String)> {
+        self.utxos
+            .multisig
+            .iter()
+            .map(|(name, policy)| {
+                let address = policy.to_address_for(&self.utxos.params);
+                (name.clone(), self.format_address(&address))
+            })
             .collect()
     }
 
-    pub fn create_transaction(&self, recipient_address: &str, amount: u64) -> Result<Transaction> {
+    /// Build a transaction spending from a multisig account, signed
+    /// with whichever of its keys this wallet holds. The result still
+    /// needs the cosigners' signatures.
+    pub fn create_multisig_transaction(
+        &self,
+        account: &str,
+        recipient_address: &str,
+        amount: u64,
+    ) -> Result<PartiallySignedTransaction> {
+        let policy = self
+            .utxos
+            .multisig
+            .iter()
+            .find(|(name, _)| name == account)
+            .map(|(_, policy)| policy.clone())
+            .ok_or_else(|| anyhow!("No multisig account named {}", account))?;
+        let address = policy.to_address_for(&self.utxos.params);
+        let total_amount = amount + self.calculate_fee(amount);
+
+        let mut spent = Vec::new();
+        let mut input_sum = 0;
+        let utxos = self
+            .utxos
+            .utxos
+            .get(&address)
+            .map(|entry| entry.value().clone())
+            .unwrap_or_default();
+        for (_, utxo) in utxos.into_iter().filter(|(marked, _)| !marked) {
+            if input_sum >= total_amount {
+                break;
+            }
+            input_sum += utxo.value;
+            spent.push(utxo);
+        }
+        if input_sum < total_amount {
+            return Err(anyhow!("Insufficient funds in multisig account {}", account));
+        }
+
+        let inputs = spent
+            .iter()
+            .map(|utxo| UnsignedInput {
+                prev_transaction_output_hash: utxo.hash(),
+                public_key: policy.public_keys()[0].clone(),
+                value: utxo.value,
+                multisig: Some(policy.clone()),
+            })
+            .collect();
+        let mut outputs = vec![TransactionOutput {
+            value: amount,
+            unique_id: Uuid::new_v4(),
+            address: recipient_address.to_string(),
+        }];
+        if input_sum > total_amount {
+            // change stays in the multisig account
+            outputs.push(TransactionOutput {
+                value: input_sum - total_amount,
+                unique_id: Uuid::new_v4(),
+                address,
+            });
+        }
+
+        let mut psbt =
+            PartiallySignedTransaction::new(UnsignedTransaction { inputs, outputs }, spent)?;
+        let signed: usize = self
+            .utxos
+            .my_keys
+            .iter()
+            .filter_map(|key| key.private.as_ref())
+            .map(|key| psbt.sign(key))
+            .sum();
+        info!("Created multisig transaction {} with {} local signatures", psbt.id(), signed);
+        Ok(psbt)
+    }
+
+    /// Select UTXOs and build the transaction paying `amount` to the
+    /// recipient, leaving the signing to the configured signer. With
+    /// `from`, only that address's UTXOs are spent and the change goes
+    /// back to it, so funds of different addresses aren't mixed. With an
+    /// account selected instead, only its UTXOs are spent and the change
+    /// goes to its next change address.
+    pub fn create_transaction(
+        &self,
+        recipient_address: &str,
+        amount: u64,
+        from: Option<&str>,
+    ) -> Result<PartiallySignedTransaction> {
+        let from = from.map(|address| self.own_key_address(address)).transpose()?;
+        let account = self.selected_account().filter(|_| from.is_none());
+        let account_addresses = account.map(|index| self.account_addresses(Some(index)));
         let fee = self.calculate_fee(amount);
         let total_amount = amount + fee;
         let mut inputs = Vec::new();
+        let mut spent = Vec::new();
         let mut input_sum = 0;
 
         // Check if we have any UTXOs at all
@@ -488,25 +1895,28 @@ impl Core {
             let address = entry.key();
             let utxos = entry.value();
 
-            // Get the public key for this address (needed for signing)
-            let pubkey = self.utxos.address_to_key
-                .get(address)
-                .ok_or_else(|| anyhow!("No public key found for address {}", address))?
-                .value()
-                .clone();
-
-            // Find the corresponding private key
-            let private_key = self.utxos.my_keys
-                .iter()
-                .find(|k| k.public == pubkey)
-                .ok_or_else(|| anyhow!("No private key found for address {}", address))?
-                .private.clone();
+            if from.as_ref().is_some_and(|from| from != address)
+                || account_addresses.as_ref().is_some_and(|addresses| !addresses.contains(address))
+            {
+                continue;
+            }
+            // Get the public key for this address (needed for signing),
+            // multisig addresses are only spent from their own account
+            let Some(pubkey) = self.utxos.address_to_key.get(address) else {
+                continue;
+            };
+            let pubkey = pubkey.value().clone();
 
             for (marked, utxo) in utxos.iter() {
                 if *marked {
                     info!("Skipping marked UTXO: {}", utxo.hash());
                     continue;
                 }
+                // the node hasn't seen the queued transactions yet
+                if self.outbox.read().unwrap().spends(&utxo.hash()) {
+                    info!("Skipping UTXO spent by a queued transaction: {}", utxo.hash());
+                    continue;
+                }
 
                 if input_sum >= total_amount {
                     info!("Sufficient funds collected: {} >= {}", input_sum, total_amount);
@@ -528,14 +1938,13 @@ impl Core {
                 info!("  Public key address: {}", pubkey.to_address());
                 info!("  UTXO address: {}", utxo.address);
                 
-                inputs.push(TransactionInput {
+                inputs.push(UnsignedInput {
                     prev_transaction_output_hash: utxo_hash,
                     public_key: pubkey.clone(),
-                    signature: Signature::sign_output(
-                        &utxo_hash,
-                        &private_key,
-                    ),
+                    value: utxo.value,
+                    multisig: None,
                 });
+                spent.push(utxo.clone());
                 input_sum += utxo.value;
                 info!("  Input added successfully. Total input_sum: {}", input_sum);
             }
@@ -546,7 +1955,13 @@ impl Core {
         }
 
         if input_sum < total_amount {
-            return Err(anyhow!("Insufficient funds"));
+            return match (from, account) {
+                (Some(from), _) => Err(anyhow!("Insufficient funds in {}", self.format_address(&from))),
+                (None, Some(index)) => {
+                    Err(anyhow!("Insufficient funds in account {}", self.utxos.accounts[index].name))
+                }
+                (None, None) => Err(anyhow!("Insufficient funds")),
+            };
         }
 
         let mut outputs = vec![TransactionOutput {
@@ -556,8 +1971,13 @@ impl Core {
         }];
 
         if input_sum > total_amount {
-            // Change output goes to first address we own
-            let change_address = self.utxos.my_keys[0].public.to_address();
+            // Change output goes to the address spent from, the
+            // account's change chain or the first address we own
+            let change_address = match (from, account) {
+                (Some(from), _) => from,
+                (None, Some(index)) => self.next_change_address(index)?,
+                (None, None) => self.utxos.my_keys[0].public.to_address_for(&self.utxos.params),
+            };
             outputs.push(TransactionOutput {
                 value: input_sum - total_amount,
                 unique_id: Uuid::new_v4(),
@@ -565,7 +1985,19 @@ impl Core {
             })
         }
 
-        Ok(Transaction::new(inputs, outputs))
+        let unsigned = UnsignedTransaction { inputs, outputs };
+        Ok(PartiallySignedTransaction::new(unsigned, spent)?)
+    }
+
+    /// The address of one of the loaded keys as the UTXOs are stored
+    /// under, given in any format
+    fn own_key_address(&self, address: &str) -> Result<String> {
+        self.utxos
+            .my_keys
+            .iter()
+            .map(|key| key.public.to_address_for(&self.utxos.params))
+            .find(|own| Address::same(own, address))
+            .ok_or_else(|| anyhow!("{} is not the address of one of this wallet's keys", address))
     }
 
     fn calculate_fee(&self, amount: u64) -> u64 {
@@ -576,6 +2008,26 @@ impl Core {
         }
     }
 
+    pub fn display_unit(&self) -> DisplayUnit {
+        self.config.read().unwrap().display_unit
+    }
+
+    /// Write an address in the format chosen in the config
+    pub fn format_address(&self, address: &str) -> String {
+        self.config.read().unwrap().format_address(address)
+    }
+
+    /// Switch between BTC and sats and remember the choice
+    pub fn toggle_display_unit(&self) -> Result<DisplayUnit> {
+        let unit = {
+            let mut config = self.config.write().unwrap();
+            config.display_unit = config.display_unit.toggled();
+            config.display_unit
+        };
+        self.save_config()?;
+        Ok(unit)
+    }
+
     /// Find contact by name
     pub fn find_contact_by_name(&self, name: &str) -> Option<Recipient> {
         let config = self.config.read().unwrap();
@@ -585,14 +2037,19 @@ impl Core {
     /// Find contact by address
     pub fn find_contact_by_address(&self, address: &str) -> Option<Recipient> {
         let config = self.config.read().unwrap();
-        config.contacts.iter().find(|r| r.address == address).cloned()
+        config
+            .contacts
+            .iter()
+            .find(|r| r.has_address(address))
+            .cloned()
     }
 
     /// Add a new contact
-    pub fn add_contact(&self, name: String, address: String) -> Result<()> {
+    pub fn add_contact(&self, name: String, address: String, notes: Option<String>) -> Result<()> {
         // Validate address format
-        PublicKey::validate_address(&address)
-            .map_err(|e| anyhow!("Invalid address format: {}", e))?;
+        if !PublicKey::validate_address(&address, &self.utxos.params).map_err(|e| anyhow!(e))? {
+            return Err(anyhow!("Invalid address format: {}", address));
+        }
 
         let mut config = self.config.write().unwrap();
         
@@ -602,11 +2059,13 @@ impl Core {
         }
 
         // Check if contact with this address already exists
-        if config.contacts.iter().any(|r| r.address == address) {
+        if config.contacts.iter().any(|r| r.has_address(&address)) {
             return Err(anyhow!("Contact with address '{}' already exists", address));
         }
 
-        config.contacts.push(Recipient { name, address: address.to_string() });
+        let mut contact = Recipient::new(name, address);
+        contact.notes = notes;
+        config.contacts.push(contact);
         drop(config); // Release lock before saving
         self.save_config()?;
         Ok(())
@@ -627,13 +2086,285 @@ impl Core {
         Ok(())
     }
 
+    /// Add the contacts in `path` that are valid and not known yet
+    pub fn import_contacts(&self, path: &Path) -> Result<Import> {
+        let incoming = contacts::read(path, ContactFormat::of(path)?)?;
+        let import = {
+            let mut config = self.config.write().unwrap();
+            contacts::merge(&mut config, incoming)
+        };
+        if import.added > 0 {
+            self.save_config()?;
+        }
+        Ok(import)
+    }
+
+    /// Sign a proof that `address`, or the first key's address, is
+    /// this wallet's
+    pub fn prove_address(&self, name: String, address: Option<&str>) -> Result<OwnershipProof> {
+        let key = match address {
+            Some(address) => self
+                .utxos
+                .my_keys
+                .iter()
+                .find(|key| Address::same(&key.public.to_address_for(&self.utxos.params), address))
+                .ok_or_else(|| anyhow!("{} is not one of this wallet's addresses", address))?,
+            None => self.utxos.my_keys.first().ok_or_else(|| anyhow!("The wallet has no keys"))?,
+        };
+        let private = key
+            .private
+            .as_ref()
+            .ok_or_else(|| anyhow!("The key of {} is watch-only", key.public.to_address_for(&self.utxos.params)))?;
+        let address = key.public.to_address_for(&self.utxos.params);
+        Ok(OwnershipProof::new(name, address, private))
+    }
+
+    /// Add or verify the contact an ownership proof is for, returning
+    /// the contact's name
+    pub fn add_contact_proof(&self, proof: &str) -> Result<String> {
+        let proof = contacts::proof_argument(proof)?;
+        let name = contacts::add_proof(&mut self.config.write().unwrap(), proof)?;
+        self.save_config()?;
+        Ok(name)
+    }
+
+    pub fn export_contacts(&self, path: &Path) -> Result<()> {
+        let contacts = self.config.read().unwrap().contacts.clone();
+        contacts::write(path, ContactFormat::of(path)?, &contacts)
+    }
+
+    pub fn config_path(&self) -> &Path {
+        &self.config_path
+    }
+
+    pub fn settings(&self) -> Settings {
+        let config = self.config.read().unwrap();
+        Settings {
+            fee_config: config.fee_config.clone(),
+            default_node: config.default_node.clone(),
+            display_unit: config.display_unit,
+            price_source: config.price_source.clone(),
+            theme: config.ui.theme,
+            keybindings: config.ui.keybindings,
+        }
+    }
+
+    /// Apply settings changed in the UI and save them, returning
+    /// whether the node changed
+    pub fn update_settings(&self, settings: Settings) -> Result<bool> {
+        let node_changed = {
+            let mut config = self.config.write().unwrap();
+            let node_changed = config.default_node != settings.default_node;
+            config.fee_config = settings.fee_config;
+            config.default_node = settings.default_node;
+            config.display_unit = settings.display_unit;
+            config.price_source = settings.price_source;
+            config.ui.theme = settings.theme;
+            config.ui.keybindings = settings.keybindings;
+            node_changed
+        };
+        self.save_config()?;
+        Ok(node_changed)
+    }
+
+    /// Take over the config file after it was edited while the wallet
+    /// runs, returning the names of the sections that changed. Keys,
+    /// signer, network and multisig accounts are only loaded at start,
+    /// changes to them are picked up after a restart.
+    pub fn reload_config(&self) -> Result<Vec<String>> {
+        let reloaded = Config::load_checked(&self.config_path)?;
+        let changed = self.config.read().unwrap().changed_sections(&reloaded)?;
+        if changed.is_empty() {
+            return Ok(changed);
+        }
+        info!("Config reloaded, changed: {}", changed.join(", "));
+        let restart: Vec<&str> = RESTART_SECTIONS
+            .into_iter()
+            .filter(|section| changed.iter().any(|name| name == section))
+            .collect();
+        if !restart.is_empty() {
+            warn!("Changes to {} apply after a restart", restart.join(", "));
+            self.queue_popup(format!(
+                "The config file changed. Changes to {} apply after a restart.",
+                restart.join(", ")
+            ));
+        }
+        *self.config.write().unwrap() = reloaded;
+        Ok(changed)
+    }
+
     /// Save config to file
     pub fn save_config(&self) -> Result<()> {
-        let config = self.config.read().unwrap();
-        let config_str = toml::to_string_pretty(&*config)?;
-        drop(config); // Release lock before writing
-        fs::write(&self.config_path, config_str)?;
+        let config = self.config.read().unwrap().clone();
+        config.save(&self.config_path)?;
         info!("Config saved to {:?}", self.config_path);
         Ok(())
     }
 }
+
+/// Check a proof from the node shows `txid` in the block at `height`
+/// whose header hashes to `hash`, and that the header's proof of work
+/// holds, so a node would have to mine a block to fake one
+fn check_proof(proof: &TransactionProof, txid: Hash, height: u64, hash: Hash) -> Result<()> {
+    if proof.txid != txid {
+        return Err(anyhow!("it is for transaction {}", proof.txid));
+    }
+    if proof.height != height || proof.header_hash() != hash {
+        return Err(anyhow!("it is for block {} at height {}", proof.header_hash(), proof.height));
+    }
+    if !proof.verify() {
+        return Err(anyhow!("the merkle branch doesn't lead to the block's merkle root"));
+    }
+    if proof.header.target > btclib::MIN_TARGET || !hash.matches_target(proof.header.target) {
+        return Err(anyhow!("the block's header lacks proof of work"));
+    }
+    Ok(())
+}
+
+fn utxo_cache_path(config_path: &Path) -> PathBuf {
+    config_path.with_extension("utxos.cbor")
+}
+
+/// The connection to the node, unless offline
+fn connected(stream: &mut Option<TcpStream>) -> Result<&mut TcpStream> {
+    stream.as_mut().ok_or_else(|| anyhow!("Not connected to a node"))
+}
+
+/// Ask a node what it offers, over a connection of its own
+pub async fn probe_services(node: &str) -> Result<Services> {
+    Ok(probe_version(node).await?.services)
+}
+
+/// The version a node announces, over a connection of its own
+pub async fn probe_version(node: &str) -> Result<VersionInfo> {
+    let mut stream = TcpStream::connect(node).await?;
+    let version = VersionInfo {
+        protocol_version: PROTOCOL_VERSION,
+        height: 0,
+        lowest_block: 0,
+        services: Services::NONE,
+        challenge: None,
+        utxo_set_hash: None,
+    };
+    Envelope::new(Uuid::new_v4().to_string(), DEFAULT_TTL, Message::Version(version))
+        .send_async(&mut stream)
+        .await?;
+    let reply = async {
+        loop {
+            if let Message::Version(version) = Envelope::receive_async(&mut stream).await?.msg {
+                return Ok(version);
+            }
+        }
+    };
+    tokio::time::timeout(PROBE_TIMEOUT, reply)
+        .await
+        .map_err(|_| anyhow!("no version announced"))?
+}
+
+/// Connect to the first configured node that answers wallet queries,
+/// or else to the first one reachable, returning its address too. With
+/// a single node there is nothing to choose from, so it isn't asked.
+pub(crate) async fn connect_node(config: &Config) -> Result<(TcpStream, String)> {
+    if config.nodes.is_empty() {
+        let stream = TcpStream::connect(&config.default_node)
+            .await
+            .context(format!("Failed to connect to node: {}", config.default_node))?;
+        return Ok((stream, config.default_node.clone()));
+    }
+    let mut fallback = None;
+    for node in std::iter::once(&config.default_node).chain(&config.nodes) {
+        match probe_services(node).await {
+            Ok(services) if services.contains(Services::WALLET) => {
+                info!("Using node {}", node);
+                let stream = TcpStream::connect(node)
+                    .await
+                    .context(format!("Failed to connect to node: {}", node))?;
+                return Ok((stream, node.clone()));
+            }
+            Ok(services) => {
+                info!("Node {} doesn't serve wallets, it offers {}", node, services);
+                fallback.get_or_insert(node);
+            }
+            Err(e) => warn!("Node {} unreachable: {}", node, e),
+        }
+    }
+    let node = fallback.ok_or_else(|| anyhow!("None of the configured nodes is reachable"))?;
+    warn!("No node serves wallets, using {}", node);
+    let stream = TcpStream::connect(node)
+        .await
+        .context(format!("Failed to connect to node: {}", node))?;
+    Ok((stream, node.clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a wallet of two HD accounts, without a node, whose config is
+    // written to a temporary directory
+    fn accounts_core() -> Core {
+        let dir = std::env::temp_dir().join(format!("wallet-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let config_path = dir.join("wallet_config.toml");
+        let mut config: Config = toml::from_str(
+            r#"
+            my_keys = []
+            contacts = []
+            default_node = "127.0.0.1:9000"
+            fee_config = { fee_type = "Fixed", value = 10.0 }
+            "#,
+        )
+        .unwrap();
+        config.accounts = (0..2)
+            .map(|account| Account {
+                name: format!("account {}", account),
+                account,
+                path: None,
+                receive: 1,
+                change: 0,
+            })
+            .collect();
+        let seed = ExtendedPrivateKey::from_mnemonic(&PrivateKey::generate_mnemonic()).unwrap();
+        let mut utxos = UtxoStore::new(config.network.params());
+        for account in &config.accounts {
+            utxos.add_account(account, &seed, config.network).unwrap();
+        }
+        let signer = LocalSigner::new(utxos.my_keys.iter().filter_map(|key| key.private.clone()).collect());
+        let outbox = Outbox::load(config_path.with_extension("outbox.cbor")).unwrap();
+        let balances = BalanceHistory::load(config_path.with_extension("balances.cbor")).unwrap();
+        Core::new(config, config_path, utxos, None, Box::new(signer), outbox, balances)
+    }
+
+    #[test]
+    fn test_cancel_refunds_the_spending_account() {
+        let core = accounts_core();
+        let funded = core.utxos.accounts[1].receive[0].clone();
+        core.utxos.utxos.insert(
+            funded.clone(),
+            vec![(
+                false,
+                TransactionOutput {
+                    value: 100,
+                    unique_id: Uuid::new_v4(),
+                    address: funded.clone(),
+                },
+            )],
+        );
+        core.select_account(Some(1));
+        let recipient = PrivateKey::new_key().public_key().to_address();
+        let psbt = core.create_transaction(&recipient, 40, None).unwrap();
+        core.record_sent(Hash::zero(), recipient, 40, psbt);
+        let entry = core.sent.read().unwrap()[0].clone();
+
+        let (unsigned, address, value) = core.cancellation(&entry, 5).unwrap();
+        assert_eq!(unsigned.outputs.len(), 1);
+        assert!(core.utxos.accounts[1].change.contains(&address));
+
+        // once the cancellation is mined, the funds are back in account 1
+        core.utxos.utxos.remove(&funded);
+        core.utxos.utxos.insert(address, vec![(false, unsigned.outputs[0].clone())]);
+        assert_eq!(core.balance_of(Some(1)), value);
+        assert_eq!(core.balance_of(Some(0)), 0);
+        assert_eq!(value, 100 - 10 - 5);
+    }
+}
@@ -0,0 +1,517 @@
+use crate::accounts::scan_account;
+use crate::core::{Account, Config, DEFAULT_TTL, Key, connect_node};
+use anyhow::{Context, Result, anyhow, bail};
+use btclib::bip32::{DerivationPath, ExtendedPrivateKey};
+use btclib::crypto::{PrivateKey, PublicKey};
+use btclib::error::BtcError;
+use btclib::network::{Envelope, Message};
+use btclib::util::Saveable;
+use clap::Subcommand;
+use std::fs;
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tracing::*;
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+/// Unused addresses in a row after which a restore stops scanning
+pub(crate) const DEFAULT_GAP_LIMIT: u32 = 20;
+
+/// Manage the keys listed in the wallet config
+#[derive(Subcommand)]
+pub enum KeyCommand {
+    /// Generate a new key pair from a fresh mnemonic and add it
+    Generate {
+        #[arg(short, long)]
+        name: String,
+        /// Directory the key files are written to
+        #[arg(short, long, value_name = "DIR", default_value = "keys")]
+        dir: PathBuf,
+    },
+    /// Import an existing key file and add it. A public key alone is
+    /// added watch-only, to be used with an external signer.
+    Import {
+        #[arg(
+            short,
+            long,
+            value_name = "FILE",
+            required_unless_present = "public",
+            conflicts_with = "public"
+        )]
+        private: Option<PathBuf>,
+        #[arg(long, value_name = "FILE")]
+        public: Option<PathBuf>,
+        /// Defaults to the imported file name
+        #[arg(short, long)]
+        name: Option<String>,
+        #[arg(short, long, value_name = "DIR", default_value = "keys")]
+        dir: PathBuf,
+    },
+    /// Recover what a mnemonic was used for: its own key, and the
+    /// BIP44 accounts of its seed whose receive or change addresses
+    /// the node saw paid, each chain scanned in batches until
+    /// `gap-limit` unused addresses in a row
+    Restore {
+        /// The mnemonic, `-` reads it from stdin so it stays out of the
+        /// shell history
+        #[arg(short, long)]
+        mnemonic: String,
+        /// Name of the own key, accounts are named `<name>-<account>`
+        #[arg(short, long)]
+        name: String,
+        /// Unused addresses in a row after which the scan stops
+        #[arg(long, default_value_t = DEFAULT_GAP_LIMIT)]
+        gap_limit: u32,
+        #[arg(short, long, value_name = "DIR", default_value = "keys")]
+        dir: PathBuf,
+    },
+    /// List the configured keys and their addresses
+    List,
+    /// Write copies of a key's files to another directory
+    Export {
+        #[arg(short, long)]
+        name: String,
+        #[arg(short, long, value_name = "DIR")]
+        output: PathBuf,
+        /// Also export the private key
+        #[arg(long)]
+        include_private: bool,
+    },
+    /// Rename a key and its files
+    Rename {
+        name: String,
+        new_name: String,
+    },
+}
+
+/// Run a key command against the config file at `config_path`
+pub async fn run(config_path: &Path, command: KeyCommand) -> Result<()> {
+    let mut config = Config::load(config_path)?;
+    match command {
+        KeyCommand::Generate { name, dir } => {
+            check_new_name(&config, &name)?;
+            let mnemonic = Zeroizing::new(PrivateKey::generate_mnemonic());
+            let private = PrivateKey::from_mnemonic(&mnemonic).map_err(|e| anyhow!(e))?;
+            let key = write_key_files(&private.public_key(), Some(&private), &name, &dir).await?;
+            println!("Mnemonic phrase: {}", mnemonic.as_str());
+            println!("Save this phrase in a secure location, it is needed to recover the key.");
+            add_key(&mut config, config_path, key, &private.public_key())?;
+        }
+        KeyCommand::Import {
+            private,
+            public,
+            name,
+            dir,
+        } => {
+            let source = private
+                .as_ref()
+                .or(public.as_ref())
+                .ok_or_else(|| anyhow!("A private or public key file is required"))?;
+            let name = match name {
+                Some(name) => name,
+                None => key_name_from_file(source)?,
+            };
+            check_new_name(&config, &name)?;
+            let (public_key, private_key) = match &private {
+                Some(path) => {
+                    let private_key = PrivateKey::load_from_file(path)
+                        .context(anyhow!("Failed to load private key {}", path.display()))?;
+                    (private_key.public_key(), Some(private_key))
+                }
+                None => {
+                    let public_key = PublicKey::load_from_file(source)
+                        .context(anyhow!("Failed to load public key {}", source.display()))?;
+                    (public_key, None)
+                }
+            };
+            let address = public_key.to_address();
+            if let Some(existing) = config.my_keys.iter().find(|key| {
+                PublicKey::load_from_file(&key.public)
+                    .is_ok_and(|public| public.to_address() == address)
+            }) {
+                bail!("This key is already configured as {}", existing.display_name());
+            }
+            let key = write_key_files(&public_key, private_key.as_ref(), &name, &dir).await?;
+            add_key(&mut config, config_path, key, &public_key)?;
+        }
+        KeyCommand::Restore {
+            mnemonic,
+            name,
+            gap_limit,
+            dir,
+        } => {
+            let mnemonic = read_mnemonic(mnemonic)?;
+            // the key `key generate` and key_gen make of the mnemonic
+            let own = PrivateKey::from_mnemonic(&mnemonic).map_err(|e| anyhow!(e))?;
+            let master = ExtendedPrivateKey::from_mnemonic(&mnemonic).map_err(|e| anyhow!(e))?;
+            let (mut stream, _) = connect_node(&config).await?;
+            if fetch_usage(&mut stream, vec![own.public_key().to_address()]).await?[0] {
+                restore_key(&mut config, config_path, &name, &own, &dir).await?;
+            }
+            let accounts = scan_accounts(&mut stream, &config, &master, &name, gap_limit).await?;
+            if accounts.is_empty() {
+                println!("None of the accounts of the mnemonic was used");
+                return Ok(());
+            }
+            let network = config.network;
+            for account in &accounts {
+                let path = account.path(network);
+                if config.accounts.iter().any(|known| known.name == account.name && known.path(network) != path) {
+                    bail!("An account named {} already exists", account.name);
+                }
+            }
+            match &config.seed {
+                Some(path) => {
+                    let seed = ExtendedPrivateKey::load_from_file(path)
+                        .context(anyhow!("Failed to load the seed {}", path.display()))?;
+                    if account_key(&seed, &config)? != account_key(&master, &config)? {
+                        bail!("The wallet already has the seed of another mnemonic, {}", path.display());
+                    }
+                }
+                None => {
+                    let path = dir.join("seed.cbor");
+                    fs::create_dir_all(&dir).context(anyhow!("Failed to create {}", dir.display()))?;
+                    save_new(&master, &path).await?;
+                    println!("Seed: {}", path.display());
+                    config.seed = Some(path);
+                }
+            }
+            for account in accounts {
+                let path = account.path(network);
+                println!(
+                    "Account {} at {}, {} receive and {} change addresses used",
+                    account.name, path, account.receive, account.change
+                );
+                match config.accounts.iter_mut().find(|known| known.path(network) == path) {
+                    Some(known) => {
+                        known.receive = known.receive.max(account.receive);
+                        known.change = known.change.max(account.change);
+                    }
+                    None => config.accounts.push(account),
+                }
+            }
+            info!("Restoring the accounts of {} to {:?}", name, config_path);
+            config.save(config_path)?;
+        }
+        KeyCommand::List => {
+            if config.my_keys.is_empty() {
+                println!("No keys configured");
+            }
+            for key in &config.my_keys {
+                let address = PublicKey::load_from_file(&key.public)
+                    .map(|public| config.format_address(&public.to_address()))
+                    .unwrap_or_else(|_| "<unreadable public key>".to_string());
+                let watch_only = if key.private.is_none() { "\twatch-only" } else { "" };
+                println!(
+                    "{}\t{}\t{}{}",
+                    key.display_name(),
+                    address,
+                    key.public.display(),
+                    watch_only
+                );
+            }
+        }
+        KeyCommand::Export {
+            name,
+            output,
+            include_private,
+        } => {
+            let key = find_key(&config, &name)?;
+            let public = PublicKey::load_from_file(&key.public)
+                .context(anyhow!("Failed to load public key"))?;
+            fs::create_dir_all(&output)?;
+            let public_path = output.join(format!("{}.pub.pem", name));
+            save_new(&public, &public_path).await?;
+            println!("Public key: {}", public_path.display());
+            if include_private {
+                let private_path = key
+                    .private
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("{} is a watch-only key", name))?;
+                let private = PrivateKey::load_from_file(private_path)
+                    .context(anyhow!("Failed to load private key"))?;
+                let private_path = output.join(format!("{}.priv.cbor", name));
+                save_new(&private, &private_path).await?;
+                println!("Private key: {}", private_path.display());
+            }
+            println!("Address: {}", config.format_address(&public.to_address()));
+        }
+        KeyCommand::Rename { name, new_name } => {
+            check_new_name(&config, &new_name)?;
+            let index = config
+                .my_keys
+                .iter()
+                .position(|key| key.display_name() == name)
+                .ok_or_else(|| anyhow!("No key named {}", name))?;
+            let key = &mut config.my_keys[index];
+            // only files following the `<name>.pub.pem` scheme are renamed
+            key.public = rename_file(&key.public, &name, &new_name, "pub.pem")?;
+            if let Some(private) = &key.private {
+                key.private = Some(rename_file(private, &name, &new_name, "priv.cbor")?);
+            }
+            key.name = Some(new_name.clone());
+            config.save(config_path)?;
+            info!("Renamed key {} to {}", name, new_name);
+            println!("Renamed {} to {}", name, new_name);
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn read_mnemonic(mnemonic: String) -> Result<Zeroizing<String>> {
+    if mnemonic != "-" {
+        return Ok(Zeroizing::new(mnemonic));
+    }
+    let mut line = Zeroizing::new(String::new());
+    io::stdin().lock().read_line(&mut line).context("Failed to read the mnemonic")?;
+    Ok(Zeroizing::new(line.trim().to_string()))
+}
+
+// add a key restored from a mnemonic unless it is configured already
+async fn restore_key(config: &mut Config, config_path: &Path, name: &str, private: &PrivateKey, dir: &Path) -> Result<()> {
+    let address = private.public_key().to_address();
+    if let Some(existing) = config
+        .my_keys
+        .iter()
+        .find(|key| PublicKey::load_from_file(&key.public).is_ok_and(|public| public.to_address() == address))
+    {
+        println!("{} is already configured as {}", name, existing.display_name());
+        return Ok(());
+    }
+    check_new_name(config, name)?;
+    let key = write_key_files(&private.public_key(), Some(private), name, dir).await?;
+    add_key(config, config_path, key, &private.public_key())
+}
+
+/// The BIP44 accounts of a seed the node saw paid, named `<name>-<n>`,
+/// up to the first without a used receive or change address
+async fn scan_accounts(
+    stream: &mut TcpStream,
+    config: &Config,
+    master: &ExtendedPrivateKey,
+    name: &str,
+    gap_limit: u32,
+) -> Result<Vec<Account>> {
+    let stream = &Mutex::new(stream);
+    let mut accounts = vec![];
+    for number in 0.. {
+        let account = Account {
+            name: format!("{}-{}", name, number),
+            account: number,
+            path: None,
+            receive: 1,
+            change: 0,
+        };
+        let (receive, change) = scan_account(
+            &account,
+            master,
+            config.network,
+            gap_limit,
+            |addresses| async move { fetch_usage(*stream.lock().await, addresses).await },
+            |chain, checked, used| println!("Checked {} addresses of {}, {} used", checked, chain, used),
+        )
+        .await?;
+        if receive == 0 && change == 0 {
+            break;
+        }
+        accounts.push(Account {
+            receive: receive.max(1),
+            change,
+            ..account
+        });
+    }
+    Ok(accounts)
+}
+
+// public key of the first account of a seed, telling seeds apart
+fn account_key(seed: &ExtendedPrivateKey, config: &Config) -> Result<PublicKey> {
+    let path = DerivationPath::bip44_account(config.network, 0);
+    let key = seed.derive(&path).context(anyhow!("Failed to derive {}", path))?;
+    Ok(key.private_key().public_key())
+}
+
+/// Keys of the used addresses on a derivation chain, by index. The
+/// addresses are checked in batches until `gap_limit` of them in a row
+/// were never paid, `progress` hearing after each batch how many were
+/// checked and used.
+pub(crate) async fn scan_chain<F: Future<Output = Result<Vec<bool>>>>(
+    chain_key: &ExtendedPrivateKey,
+    gap_limit: u32,
+    mut is_used: impl FnMut(Vec<String>) -> F,
+    mut progress: impl FnMut(u32, usize),
+) -> Result<Vec<(u32, PrivateKey)>> {
+    let gap_limit = gap_limit.max(1);
+    let mut used = vec![];
+    let mut checked = 0;
+    // index after the last used address, where the gap starts
+    let mut gap_start = 0;
+    while checked < gap_start + gap_limit {
+        let batch = (checked..checked + gap_limit)
+            .map(|index| Ok((index, chain_key.child(index)?.private_key().clone())))
+            .collect::<Result<Vec<_>, BtcError>>()
+            .map_err(|e| anyhow!(e))?;
+        let addresses = batch.iter().map(|(_, key)| key.public_key().to_address()).collect();
+        let usage = is_used(addresses).await?;
+        for ((index, key), _) in batch.into_iter().zip(usage).filter(|(_, used)| *used) {
+            gap_start = index + 1;
+            used.push((index, key));
+        }
+        checked += gap_limit;
+        progress(checked, used.len());
+    }
+    Ok(used)
+}
+
+/// Whether the node saw each address paid
+pub(crate) async fn fetch_usage(stream: &mut TcpStream, addresses: Vec<String>) -> Result<Vec<bool>> {
+    let count = addresses.len();
+    Envelope::new(Uuid::new_v4().to_string(), DEFAULT_TTL, Message::FetchAddressUsage(addresses))
+        .send_async(stream)
+        .await
+        .context("Failed to ask for address usage")?;
+    match Envelope::receive_async(stream).await?.msg {
+        Message::AddressUsage(used) if used.len() == count => Ok(used),
+        _ => Err(anyhow!("Unexpected response from node")),
+    }
+}
+
+fn check_new_name(config: &Config, name: &str) -> Result<()> {
+    if name.is_empty() || name.contains(['/', '\\']) {
+        bail!("Invalid key name: {:?}", name);
+    }
+    if config.my_keys.iter().any(|key| key.display_name() == name) {
+        bail!("A key named {} already exists", name);
+    }
+    Ok(())
+}
+
+fn find_key<'a>(config: &'a Config, name: &str) -> Result<&'a Key> {
+    config
+        .my_keys
+        .iter()
+        .find(|key| key.display_name() == name)
+        .ok_or_else(|| anyhow!("No key named {}", name))
+}
+
+fn key_name_from_file(path: &Path) -> Result<String> {
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .ok_or_else(|| anyhow!("Invalid private key path"))?;
+    let name = file_name.split('.').next().unwrap_or_default();
+    Ok(name.to_string())
+}
+
+/// Write `<dir>/<name>.pub.pem` and, unless the key is watch-only,
+/// `<dir>/<name>.priv.cbor`
+async fn write_key_files(
+    public: &PublicKey,
+    private: Option<&PrivateKey>,
+    name: &str,
+    dir: &Path,
+) -> Result<Key> {
+    fs::create_dir_all(dir).context(anyhow!("Failed to create {}", dir.display()))?;
+    let key = Key {
+        name: Some(name.to_string()),
+        public: dir.join(format!("{}.pub.pem", name)),
+        private: private.map(|_| dir.join(format!("{}.priv.cbor", name))),
+    };
+    if let (Some(private), Some(path)) = (private, &key.private) {
+        save_new(private, path).await?;
+    }
+    save_new(public, &key.public).await?;
+    Ok(key)
+}
+
+/// Save to a file that must not exist yet
+pub(crate) async fn save_new<S: Saveable>(value: &S, path: &Path) -> Result<()> {
+    if path.exists() {
+        bail!("{} already exists", path.display());
+    }
+    value
+        .save_to_file_async(path)
+        .await
+        .context(anyhow!("Failed to write {}", path.display()))?;
+    Ok(())
+}
+
+fn add_key(config: &mut Config, config_path: &Path, key: Key, public: &PublicKey) -> Result<()> {
+    if let Some(private) = &key.private {
+        println!("Private key: {}", private.display());
+    }
+    println!("Public key: {}", key.public.display());
+    println!("Address: {}", config.format_address(&public.to_address()));
+    info!("Adding key {} to {:?}", key.display_name(), config_path);
+    config.my_keys.push(key);
+    config.save(config_path)
+}
+
+fn rename_file(path: &Path, name: &str, new_name: &str, extension: &str) -> Result<PathBuf> {
+    if path.file_name() != Some(format!("{}.{}", name, extension).as_ref()) {
+        return Ok(path.to_path_buf());
+    }
+    let new_path = path.with_file_name(format!("{}.{}", new_name, extension));
+    if new_path.exists() {
+        bail!("{} already exists", new_path.display());
+    }
+    fs::rename(path, &new_path).context(anyhow!("Failed to rename {}", path.display()))?;
+    Ok(new_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use btclib::params::Network;
+    use std::collections::HashSet;
+
+    // a chain on which the addresses at `indexes` were paid, and a
+    // check of addresses against it counting the batches asked for
+    fn chain_with_used(indexes: &[u32]) -> (ExtendedPrivateKey, HashSet<String>) {
+        let master = ExtendedPrivateKey::from_mnemonic(&PrivateKey::generate_mnemonic()).unwrap();
+        let chain_key = master.derive(&DerivationPath::bip44_account(Network::default(), 0).child(0)).unwrap();
+        let used = indexes
+            .iter()
+            .map(|index| chain_key.child(*index).unwrap().private_key().public_key().to_address())
+            .collect();
+        (chain_key, used)
+    }
+
+    async fn scan(chain_key: &ExtendedPrivateKey, used: &HashSet<String>, gap_limit: u32) -> (Vec<u32>, u32) {
+        let mut batches = 0;
+        let mut last_progress = (0, 0);
+        let found = scan_chain(
+            chain_key,
+            gap_limit,
+            |addresses| {
+                batches += 1;
+                async move { Ok(addresses.iter().map(|address| used.contains(address)).collect()) }
+            },
+            |checked, used| last_progress = (checked, used),
+        )
+        .await
+        .unwrap();
+        assert_eq!(last_progress, (batches * gap_limit, found.len()));
+        (found.into_iter().map(|(index, _)| index).collect(), batches)
+    }
+
+    #[tokio::test]
+    async fn test_nothing_used_restores_nothing() {
+        let (chain_key, used) = chain_with_used(&[]);
+        assert_eq!(scan(&chain_key, &used, 5).await, (vec![], 1));
+    }
+
+    #[tokio::test]
+    async fn test_scan_stops_after_a_full_gap() {
+        // each used address comes before 5 unused ones in a row
+        let (chain_key, used) = chain_with_used(&[2, 8, 12]);
+        assert_eq!(scan(&chain_key, &used, 5).await, (vec![2, 8, 12], 4));
+        // 9 unused addresses after 2 are a full gap, 11 isn't looked at
+        let (chain_key, used) = chain_with_used(&[2, 11]);
+        assert_eq!(scan(&chain_key, &used, 5).await, (vec![2], 2));
+        // the last address of a batch being used asks for another
+        let (chain_key, used) = chain_with_used(&[4]);
+        assert_eq!(scan(&chain_key, &used, 5).await, (vec![4], 2));
+    }
+}
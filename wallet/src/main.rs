@@ -1,18 +1,32 @@
-use anyhow::Result;
+use anyhow::{Result, anyhow};
+use btclib::amount::parse_btc;
+use btclib::util::Saveable;
 use cursive::views::TextContent;
 use tracing::*;
 use clap::{Parser, Subcommand};
 use core::Core;
-use kanal;
+use signer::Signed;
 use std::path::PathBuf;
 use std::sync::Arc;
 use util::{generate_dummy_config, init_tracing, setup_panic_hook, big_mode_btc};
-use tasks::{update_utxos, handle_transactions, ui_task, update_balance};
+use tasks::{
+    update_utxos, handle_transactions, ui_task, update_balance, notify_received, retry_broadcasts,
+    watch_config,
+};
 
+mod accounts;
+mod balances;
+mod clipboard;
+mod contacts;
 mod core;
+mod keys;
+mod multisig;
+mod outbox;
+mod signer;
 mod util;
 mod tasks;
 mod ui;
+mod validate;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -30,6 +44,51 @@ enum Commands {
         #[arg(short, long, value_name = "FILE")]
         output: PathBuf,
     },
+    /// Build and sign a transaction from the UTXOs cached by the last
+    /// run, without connecting to the node
+    CreateTx {
+        /// Contact name or address to pay
+        #[arg(short, long)]
+        to: String,
+        /// Amount in BTC
+        #[arg(short, long)]
+        amount: String,
+        /// File the signed transaction is written to
+        #[arg(short, long, value_name = "FILE")]
+        output: PathBuf,
+        /// Spend only the UTXOs of this address and send the change
+        /// back to it
+        #[arg(long, value_name = "ADDRESS")]
+        from: Option<String>,
+    },
+    /// Send a transaction signed with tx_sign, merging the copies
+    /// signed by different signers, or one saved by create-tx
+    Broadcast {
+        #[arg(value_name = "FILE", required = true)]
+        files: Vec<PathBuf>,
+    },
+    /// Register or list multisig accounts
+    Multisig {
+        #[command(subcommand)]
+        command: multisig::MultisigCommand,
+    },
+    /// Check the config, the files it refers to and the nodes
+    Doctor,
+    /// Import, export or list contacts
+    Contacts {
+        #[command(subcommand)]
+        command: contacts::ContactCommand,
+    },
+    /// Generate, import, list, export or rename keys
+    Key {
+        #[command(subcommand)]
+        command: keys::KeyCommand,
+    },
+    /// Create the seed, add accounts or hand out receive addresses
+    Account {
+        #[command(subcommand)]
+        command: accounts::AccountCommand,
+    },
 }
 
 #[tokio::main]
@@ -39,11 +98,42 @@ async fn main() -> Result<()> {
     info!("Starting wallet app");
 
     let cli = Cli::parse();
-    match &cli.command {
+    match cli.command {
         Some(Commands::GenerateConfig { output }) => {
-            return generate_dummy_config(output);
+            return generate_dummy_config(&output);
+        }
+        Some(Commands::Key { command }) => {
+            return keys::run(&cli.config, command).await;
         }
-        None => {}
+        Some(Commands::Account { command }) => {
+            return accounts::run(&cli.config, command).await;
+        }
+        Some(Commands::Doctor) => {
+            return validate::doctor(&cli.config).await;
+        }
+        Some(Commands::Multisig { command }) => {
+            return multisig::run(&cli.config, command);
+        }
+        Some(Commands::Contacts { command }) => {
+            return contacts::run(&cli.config, command);
+        }
+        Some(Commands::CreateTx { to, amount, output, from }) => {
+            let amount = parse_btc(&amount).map_err(|_| anyhow!("Invalid amount {}", amount))?;
+            let core = Core::load_offline(cli.config.clone())?;
+            match core.create_offline(&to, amount, from.as_deref())? {
+                Signed::Transaction(transaction) => {
+                    transaction.save_to_file(&output)?;
+                    println!("Transaction {} saved to {}", transaction.hash(), output.display());
+                    println!("Send it with `wallet broadcast {}`", output.display());
+                }
+                Signed::Exported(path) => {
+                    println!("Transaction exported for signing to {}", path.display());
+                    println!("Sign it with tx_sign and send the result with `wallet broadcast`");
+                }
+            }
+            return Ok(());
+        }
+        Some(Commands::Broadcast { .. }) | None => {}
     }
 
     info!("Loading config from: {:?}", cli.config);
@@ -55,12 +145,21 @@ async fn main() -> Result<()> {
         config.default_node = node;
     }
 
+    if let Some(Commands::Broadcast { files }) = &cli.command {
+        let txid = core.broadcast_signed(files).await?;
+        println!("Transaction {} sent", txid);
+        return Ok(());
+    }
+
     let (tx_sender, tx_receiver) = kanal::bounded(10);
     core.tx_sender = tx_sender.clone();
 
     let core = Arc::new(core);
     info!("Starting background tasks");
     
+    // subscribe before the first fetch so it seeds the known UTXOs
+    let notifier = notify_received(core.clone(), core.subscribe_utxo_updates());
+
     // Fetch UTXOs immediately on startup
     info!("Fetching initial UTXOs...");
     if let Err(e) = core.fetch_utxos().await {
@@ -75,6 +174,9 @@ async fn main() -> Result<()> {
         _ = update_utxos(core.clone()) => (),
         _ = handle_transactions(tx_receiver.clone_async(), core.clone()) => (),
         _ = update_balance(core.clone(), balance_content.clone()) => (),
+        _ = notifier => (),
+        _ = retry_broadcasts(core.clone()) => (),
+        _ = watch_config(core.clone()) => (),
     }
     info!("App shutting down");
     Ok(())
@@ -0,0 +1,74 @@
+use crate::core::{Config, MultisigAccount};
+use anyhow::{Result, bail};
+use clap::Subcommand;
+use std::path::{Path, PathBuf};
+use tracing::*;
+
+/// Manage the multisig accounts listed in the wallet config
+#[derive(Subcommand)]
+pub enum MultisigCommand {
+    /// Register an m-of-n account from the public keys of its cosigners
+    Add {
+        #[arg(short, long)]
+        name: String,
+        /// Number of cosigners that have to sign
+        #[arg(short, long)]
+        threshold: usize,
+        /// Public key file of a cosigner, repeated for each of them
+        /// including this wallet's own key
+        #[arg(short, long = "cosigner", value_name = "FILE", required = true)]
+        cosigners: Vec<PathBuf>,
+    },
+    /// List the multisig accounts and their addresses
+    List,
+}
+
+/// Run a multisig command against the config file at `config_path`
+pub fn run(config_path: &Path, command: MultisigCommand) -> Result<()> {
+    let mut config = Config::load(config_path)?;
+    match command {
+        MultisigCommand::Add {
+            name,
+            threshold,
+            cosigners,
+        } => {
+            if config.multisig.iter().any(|account| account.name == name) {
+                bail!("A multisig account named {} already exists", name);
+            }
+            let account = MultisigAccount {
+                name,
+                threshold,
+                cosigners,
+            };
+            let policy = account.policy()?;
+            println!(
+                "{}-of-{} account {}",
+                policy.threshold(),
+                policy.public_keys().len(),
+                account.name
+            );
+            println!("Address: {}", config.format_address(&policy.to_address()));
+            info!("Adding multisig account {} to {:?}", account.name, config_path);
+            config.multisig.push(account);
+            config.save(config_path)?;
+        }
+        MultisigCommand::List => {
+            if config.multisig.is_empty() {
+                println!("No multisig accounts configured");
+            }
+            for account in &config.multisig {
+                match account.policy() {
+                    Ok(policy) => println!(
+                        "{}\t{}-of-{}\t{}",
+                        account.name,
+                        policy.threshold(),
+                        policy.public_keys().len(),
+                        config.format_address(&policy.to_address())
+                    ),
+                    Err(e) => println!("{}\t<invalid: {}>", account.name, e),
+                }
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,132 @@
+use anyhow::{Context, Result, anyhow};
+use btclib::sha256::Hash;
+use btclib::types::{PartiallySignedTransaction, Transaction};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use crate::util::write_atomic;
+
+// first retry after this, doubling up to MAX_BACKOFF
+const FIRST_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(600);
+
+/// A signed transaction waiting for the node to be reachable
+#[derive(Serialize, Deserialize, Clone)]
+pub struct QueuedTransaction {
+    pub transaction: Transaction,
+    pub recipient: String,
+    pub amount: u64,
+    /// Kept for the history once the transaction is sent
+    pub psbt: PartiallySignedTransaction,
+    /// Unix time the transaction was queued at
+    pub queued_at: u64,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    // not persisted, after a restart everything is retried right away
+    #[serde(skip)]
+    retry_at: Option<Instant>,
+}
+
+impl QueuedTransaction {
+    pub fn new(
+        transaction: Transaction,
+        recipient: String,
+        amount: u64,
+        psbt: PartiallySignedTransaction,
+        error: String,
+    ) -> Self {
+        let queued_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        Self {
+            transaction,
+            recipient,
+            amount,
+            psbt,
+            queued_at,
+            attempts: 1,
+            last_error: Some(error),
+            retry_at: Some(Instant::now() + FIRST_BACKOFF),
+        }
+    }
+
+    pub fn txid(&self) -> Hash {
+        self.transaction.hash()
+    }
+}
+
+/// Transactions that couldn't be broadcast, saved next to the config
+/// so they survive a restart of the wallet
+pub struct Outbox {
+    path: PathBuf,
+    entries: Vec<QueuedTransaction>,
+}
+
+impl Outbox {
+    /// Read the outbox at `path`, which is empty if the file is missing
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let entries = match fs::read(&path) {
+            Ok(bytes) => ciborium::from_reader(bytes.as_slice())
+                .context(anyhow!("Failed to parse outbox {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e).context(anyhow!("Failed to read outbox {}", path.display())),
+        };
+        Ok(Self { path, entries })
+    }
+
+    fn save(&self) -> Result<()> {
+        let mut bytes = vec![];
+        ciborium::into_writer(&self.entries, &mut bytes)?;
+        write_atomic(&self.path, &bytes).context(anyhow!("Failed to write outbox"))
+    }
+
+    pub fn entries(&self) -> &[QueuedTransaction] {
+        &self.entries
+    }
+
+    pub fn push(&mut self, entry: QueuedTransaction) -> Result<()> {
+        self.entries.push(entry);
+        self.save()
+    }
+
+    /// Whether a queued transaction spends the output `hash`
+    pub fn spends(&self, hash: &Hash) -> bool {
+        self.entries.iter().any(|entry| {
+            entry
+                .transaction
+                .inputs
+                .iter()
+                .any(|input| input.prev_transaction_output_hash == *hash)
+        })
+    }
+
+    /// Entries whose backoff is over
+    pub fn due(&self) -> Vec<QueuedTransaction> {
+        let now = Instant::now();
+        self.entries
+            .iter()
+            .filter(|entry| entry.retry_at.is_none_or(|at| at <= now))
+            .cloned()
+            .collect()
+    }
+
+    pub fn remove(&mut self, txid: Hash) -> Result<()> {
+        self.entries.retain(|entry| entry.txid() != txid);
+        self.save()
+    }
+
+    /// Note a failed attempt and wait longer before the next one
+    pub fn failed(&mut self, txid: Hash, error: String) -> Result<()> {
+        if let Some(entry) = self.entries.iter_mut().find(|entry| entry.txid() == txid) {
+            let backoff = FIRST_BACKOFF
+                .saturating_mul(2u32.saturating_pow(entry.attempts))
+                .min(MAX_BACKOFF);
+            entry.attempts += 1;
+            entry.last_error = Some(error);
+            entry.retry_at = Some(Instant::now() + backoff);
+        }
+        self.save()
+    }
+}
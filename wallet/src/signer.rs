@@ -0,0 +1,133 @@
+use crate::util::write_atomic;
+use anyhow::{Context, Result, anyhow};
+use btclib::approval::{self, SigningRequest, SigningResponse};
+use btclib::crypto::PrivateKey;
+use btclib::types::{PartiallySignedTransaction, Transaction};
+use btclib::util::Saveable;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+use tracing::*;
+
+/// How long to wait for the user of a signing daemon to decide
+const APPROVAL_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Configure where transactions are signed
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(tag = "type")]
+pub enum SignerConfig {
+    /// Sign with the private keys listed in the config
+    #[default]
+    Local,
+    /// Write partially signed transactions to `dir` for `tx_sign` on
+    /// an offline machine
+    External { dir: PathBuf },
+    /// Ask `approve-signerd` listening on `address`, either
+    /// `unix:<socket path>` or `<host>:<port>`, which signs once its
+    /// user approves
+    Daemon { address: String },
+}
+
+/// What became of a transaction handed to a signer
+pub enum Signed {
+    /// Signed and ready to broadcast
+    Transaction(Transaction),
+    /// Written to a file to be signed elsewhere
+    Exported(PathBuf),
+}
+
+/// Turns the transactions built by the wallet into broadcastable ones
+pub trait Signer: Send + Sync {
+    fn sign(&self, psbt: PartiallySignedTransaction) -> Result<Signed>;
+}
+
+/// Signs with keys loaded into the wallet
+pub struct LocalSigner {
+    keys: Vec<PrivateKey>,
+}
+
+impl LocalSigner {
+    pub fn new(keys: Vec<PrivateKey>) -> Self {
+        Self { keys }
+    }
+}
+
+impl Signer for LocalSigner {
+    fn sign(&self, mut psbt: PartiallySignedTransaction) -> Result<Signed> {
+        for key in &self.keys {
+            psbt.sign(key);
+        }
+        psbt.finalize()
+            .map_err(|e| anyhow!("{} (watch-only keys need an external signer)", e))?;
+        Ok(Signed::Transaction(psbt.extract()?))
+    }
+}
+
+/// Leaves signing to an air-gapped machine: transactions are written to
+/// a directory, signed there with `tx_sign`, and the result is broadcast
+/// with `wallet broadcast`
+pub struct FileSigner {
+    dir: PathBuf,
+}
+
+impl FileSigner {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+}
+
+impl Signer for FileSigner {
+    fn sign(&self, psbt: PartiallySignedTransaction) -> Result<Signed> {
+        fs::create_dir_all(&self.dir)
+            .context(anyhow!("Failed to create {}", self.dir.display()))?;
+        let path = self.dir.join(format!("{:.16}.psbt", psbt.id().to_string()));
+        let mut bytes = Vec::new();
+        psbt.save(&mut bytes)?;
+        write_atomic(&path, &bytes).context(anyhow!("Failed to write {}", path.display()))?;
+        info!("Transaction to sign written to {:?}", path);
+        Ok(Signed::Exported(path))
+    }
+}
+
+/// Sends the sighashes and a summary of each transaction to a signer
+/// in another process, which holds the keys and signs only what its
+/// user approves
+pub struct DaemonSigner {
+    address: String,
+}
+
+impl DaemonSigner {
+    pub fn new(address: String) -> Self {
+        Self { address }
+    }
+}
+
+impl Signer for DaemonSigner {
+    fn sign(&self, mut psbt: PartiallySignedTransaction) -> Result<Signed> {
+        let request = SigningRequest::for_psbt(&psbt);
+        info!("Waiting for {} to approve the transaction", self.address);
+        // the user may take a while, don't hold up other tasks meanwhile
+        let response = tokio::task::block_in_place(|| {
+            approval::request_signatures(&self.address, &request, APPROVAL_TIMEOUT)
+        })
+        .context(anyhow!("Failed to reach the signer at {}", self.address))?;
+        let signatures = match response {
+            SigningResponse::Approved(signatures) => signatures,
+            SigningResponse::Rejected(reason) => {
+                return Err(anyhow!("The signer refused: {}", reason));
+            }
+        };
+        for (index, signature) in signatures {
+            let sighash = request
+                .sighashes
+                .get(index)
+                .ok_or(anyhow!("The signer answered for an unknown input"))?;
+            if psbt.add_signature(&sighash.key, &signature) == 0 {
+                return Err(anyhow!("The signer returned an invalid signature"));
+            }
+        }
+        psbt.finalize()?;
+        Ok(Signed::Transaction(psbt.extract()?))
+    }
+}
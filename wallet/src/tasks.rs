@@ -1,9 +1,14 @@
-use crate::core::{Core, TransactionResult};
+use crate::core::{Core, TransactionResult, UtxoUpdate};
 use crate::ui::run_ui;
-use crate::util::big_mode_btc;
+use crate::util::{big_mode_btc, format_amount};
+use notify::{RecursiveMode, Watcher};
+use btclib::sha256::Hash;
 use btclib::types::Transaction;
 use cursive::views::TextContent;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc;
 use tokio::task::JoinHandle;
 use tokio::time::{self, Duration};
 use tokio::sync::oneshot;
@@ -16,6 +21,140 @@ pub fn update_utxos(core: Arc<Core>) -> JoinHandle<()> {
             interval.tick().await;
             if let Err(e) = core.fetch_utxos().await {
                 error!("Failed to update UTXOs: {}", e);
+                continue;
+            }
+            if let Err(e) = core.reconcile_history().await {
+                error!("Failed to check the history against the chain: {}", e);
+            }
+        }
+    })
+}
+
+/// Broadcast the transactions of the outbox once the node is back
+pub fn retry_broadcasts(core: Arc<Core>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = time::interval(Duration::from_secs(5));
+        loop {
+            interval.tick().await;
+            core.retry_queued().await;
+        }
+    })
+}
+
+/// Watch the config file and apply edits made while the wallet runs
+pub fn watch_config(core: Arc<Core>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let path = core.config_path().to_path_buf();
+        let file_name = path.file_name().map(|name| name.to_os_string()).unwrap_or_default();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let watcher = notify::recommended_watcher(move |event| {
+            let _ = tx.send(event);
+        });
+        // editors and `save_config` replace the file rather than write
+        // to it, so watch the directory holding it
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => ".".into(),
+        };
+        let _watcher = match watcher.and_then(|mut watcher| {
+            watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+            Ok(watcher)
+        }) {
+            Ok(watcher) => watcher,
+            Err(e) => {
+                // the wallet works without, it just won't see edits
+                warn!("Failed to watch {}: {}", dir.display(), e);
+                return std::future::pending().await;
+            }
+        };
+        while let Some(event) = rx.recv().await {
+            let event: notify::Event = match event {
+                Ok(event) => event,
+                Err(e) => {
+                    warn!("Config watcher error: {}", e);
+                    continue;
+                }
+            };
+            let ours = event
+                .paths
+                .iter()
+                .any(|changed| changed.file_name() == Some(file_name.as_os_str()));
+            if !ours || !(event.kind.is_create() || event.kind.is_modify()) {
+                continue;
+            }
+            // a save comes as several events, let them settle
+            time::sleep(Duration::from_millis(200)).await;
+            while rx.try_recv().is_ok() {}
+            match core.reload_config() {
+                Ok(changed) if changed.iter().any(|name| name == "default_node" || name == "nodes") => {
+                    if let Err(e) = core.reconnect().await {
+                        warn!("Failed to connect to the new node: {}", e);
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("Keeping the current config: {:#}", e);
+                    core.queue_popup(format!(
+                        "The config file has errors, keeping the current settings: {:#}",
+                        e
+                    ));
+                }
+            }
+        }
+    })
+}
+
+/// Watch the UTXO updates for outputs that weren't there before and
+/// tell the user about the received funds
+pub fn notify_received(
+    core: Arc<Core>,
+    mut updates: broadcast::Receiver<UtxoUpdate>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut known: HashMap<String, HashSet<Hash>> = HashMap::new();
+        loop {
+            let (address, utxos) = match updates.recv().await {
+                Ok(update) => update,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Notifier skipped {} UTXO updates", skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+            let hashes: HashSet<Hash> = utxos.iter().map(|(_, utxo)| utxo.hash()).collect();
+            // the first update for an address only seeds the known set
+            let Some(previous) = known.insert(address.clone(), hashes) else {
+                continue;
+            };
+            let received: u64 = utxos
+                .iter()
+                .filter(|(_, utxo)| !previous.contains(&utxo.hash()))
+                .map(|(_, utxo)| utxo.value)
+                .sum();
+            if received == 0 {
+                continue;
+            }
+
+            let message = format!(
+                "Received {} to {}",
+                format_amount(received, core.display_unit()),
+                address
+            );
+            info!("{}", message);
+            let config = core.config.read().unwrap().notifications.clone();
+            if config.popup {
+                core.queue_popup(message.clone());
+            }
+            if config.desktop {
+                tokio::task::spawn_blocking(move || {
+                    if let Err(e) = notify_rust::Notification::new()
+                        .summary("Funds received")
+                        .body(&message)
+                        .show()
+                    {
+                        warn!("Failed to show desktop notification: {}", e);
+                    }
+                });
             }
         }
     })
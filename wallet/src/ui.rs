@@ -1,28 +1,33 @@
-use crate::core::Core;
+use crate::balances::{self, Range};
+use crate::clipboard;
+use crate::core::{
+    AddressBalance, Core, DisplayUnit, FeeConfig, FeeType, Keybindings, PriceSource, SendOutcome,
+    SendStatus, Settings, ThemeName, UiConfig,
+};
+use crate::util::{big_mode_btc, format_amount};
 use anyhow::Result;
-use bigdecimal::{BigDecimal, ToPrimitive};
+use btclib::amount::{parse_btc, parse_sats};
+use btclib::sha256::Hash;
+use btclib::types::PartiallySignedTransaction;
+use btclib::util::Saveable;
 use cursive::Cursive;
-use cursive::event::{Event, Key};
+use cursive::menu;
+use cursive::event::{Event, EventResult, Key};
+use cursive::theme::{BaseColor, Color, PaletteColor, Theme};
 use cursive::traits::*;
 use cursive::views::{
-    Button, Dialog, EditView, LinearLayout, Panel, ResizedView, TextContent, TextView,
+    Button, Checkbox, Dialog, EditView, LinearLayout, NamedView, OnEventView, Panel, ResizedView,
+    SelectView, TextContent, TextView,
 };
-use std::str::FromStr;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use tracing::*;
 
-#[derive(Clone, Copy)]
-enum Unit {
-    Btc,
-    Sats,
-}
-
-/// Convert an amount between BTC and Satoshi units.
-fn convert_amount(amount: &BigDecimal, from: Unit, to: Unit) -> BigDecimal {
-    match (from, to) {
-        (Unit::Btc, Unit::Sats) => amount * BigDecimal::from(100_000_000u64),
-        (Unit::Sats, Unit::Btc) => amount / BigDecimal::from(100_000_000u64),
-        _ => amount.clone(),
+/// Parse an amount entered in the given unit into satoshis.
+fn parse_amount(amount: &str, unit: DisplayUnit) -> Option<u64> {
+    match unit {
+        DisplayUnit::Btc => parse_btc(amount).ok(),
+        DisplayUnit::Sats => parse_sats(amount).ok(),
     }
 }
 
@@ -39,19 +44,100 @@ pub fn run_ui(core: Arc<Core>, balance_content: TextContent) -> Result<()> {
 
 /// Set up the Cursive interface with all necessary components and callbacks.
 fn setup_siv(siv: &mut Cursive, core: Arc<Core>, balance_content: TextContent) {
-    siv.set_autorefresh(true);
+    let ui = core.config.read().unwrap().ui.clone();
+    apply_ui(siv, &ui);
     siv.set_window_title("BTC wallet".to_string());
     siv.set_user_data(core.clone());
     siv.add_global_callback('q', |s| {
         info!("Quit command received");
         s.quit()
     });
+    siv.add_global_callback('u', toggle_display_unit);
+    siv.add_global_callback('a', cycle_account);
     setup_menubar(siv);
     setup_layout(siv, balance_content);
+    // pick up [ui] changes made to the config file while running
+    let applied = Mutex::new(ui);
+    siv.add_global_callback(Event::Refresh, move |s| {
+        refresh_address_balances(s);
+        let Some(core) = s.user_data::<Arc<Core>>().cloned() else {
+            return;
+        };
+        let ui = core.config.read().unwrap().ui.clone();
+        let mut applied = applied.lock().unwrap();
+        if *applied != ui {
+            apply_ui(s, &ui);
+            *applied = ui;
+        }
+    });
     siv.add_global_callback(Event::Key(Key::Esc), |siv| siv.select_menubar());
     siv.select_menubar();
 }
 
+/// Build the theme chosen in the config, with its palette overrides
+pub fn build_theme(config: &UiConfig) -> Result<Theme, String> {
+    use PaletteColor::*;
+    let mut theme = match config.theme {
+        ThemeName::Default => Theme::retro(),
+        ThemeName::Terminal => Theme::terminal_default(),
+        ThemeName::Dark | ThemeName::Light => {
+            let mut theme = Theme::retro();
+            theme.shadow = false;
+            let (back, front) = match config.theme {
+                ThemeName::Dark => (Color::Dark(BaseColor::Black), Color::Light(BaseColor::White)),
+                _ => (Color::Light(BaseColor::White), Color::Dark(BaseColor::Black)),
+            };
+            let palette = &mut theme.palette;
+            palette[Background] = back;
+            palette[View] = back;
+            palette[Primary] = front;
+            palette[Secondary] = Color::Dark(BaseColor::Cyan);
+            palette[Tertiary] = Color::Dark(BaseColor::Magenta);
+            palette[TitlePrimary] = Color::Dark(BaseColor::Blue);
+            palette[TitleSecondary] = Color::Dark(BaseColor::Yellow);
+            palette[Highlight] = Color::Dark(BaseColor::Blue);
+            palette[HighlightInactive] = Color::Light(BaseColor::Black);
+            palette[HighlightText] = Color::Light(BaseColor::White);
+            theme
+        }
+    };
+    for (name, value) in &config.palette {
+        let color = Color::parse(value).ok_or_else(|| format!("{} is not a color", value))?;
+        theme
+            .palette
+            .set_basic_color(name, color)
+            .map_err(|_| format!("{} is not a palette entry", name))?;
+    }
+    Ok(theme)
+}
+
+/// Keys vim users move with, sent on as the keys they stand for
+const VIM_KEYS: [(char, Key); 6] = [
+    ('h', Key::Left),
+    ('j', Key::Down),
+    ('k', Key::Up),
+    ('l', Key::Right),
+    ('g', Key::Home),
+    ('G', Key::End),
+];
+
+/// Apply the theme, refresh rate and keybindings of the config.
+fn apply_ui(siv: &mut Cursive, config: &UiConfig) {
+    match build_theme(config) {
+        Ok(theme) => siv.set_theme(theme),
+        Err(e) => warn!("Keeping the current theme: {}", e),
+    }
+    siv.set_fps(config.refresh_rate);
+    for (ch, key) in VIM_KEYS {
+        siv.clear_global_callbacks(ch);
+        // only reached when the focused view doesn't take the letter,
+        // so typing in edit fields is unaffected
+        if config.keybindings == Keybindings::Vim {
+            siv.add_global_callback(ch, move |s| s.on_event(Event::Key(key)));
+        }
+    }
+}
+
 /// Show contacts management dialog with table view and pagination
 fn show_contacts_dialog(s: &mut Cursive) {
     const ITEMS_PER_PAGE: usize = 10;
@@ -72,6 +158,9 @@ fn show_contacts_dialog(s: &mut Cursive) {
                     siv.pop_layer();
                     show_add_contact_standalone(siv);
                 })
+                .button("Import", |siv| show_contacts_file_dialog(siv, true))
+                .button("Add Proof", show_add_proof_dialog)
+                .button("My Proof", show_my_proof_dialog)
                 .button("Close", |siv| {
                     siv.pop_layer();
                 }),
@@ -81,7 +170,7 @@ fn show_contacts_dialog(s: &mut Cursive) {
 
     // Create table rows for current page
     let current_page = 0;
-    let total_pages = (contacts.len() + ITEMS_PER_PAGE - 1) / ITEMS_PER_PAGE;
+    let total_pages = contacts.len().div_ceil(ITEMS_PER_PAGE);
 
     create_contacts_table_page(s, contacts, current_page, total_pages, ITEMS_PER_PAGE);
 }
@@ -110,6 +199,10 @@ fn create_contacts_table_page(
     for contact in page_contacts {
         let contact_name = contact.name.clone();
         let contact_address = contact.address.clone();
+        let display_name = match contact.verified() {
+            true => format!("✓ {}", contact_name),
+            false => contact_name.clone(),
+        };
 
         // Format address to fit in column (truncate if too long)
         let display_address = if contact_address.len() > 35 {
@@ -121,7 +214,7 @@ fn create_contacts_table_page(
         let row = LinearLayout::horizontal()
             .child(ResizedView::with_fixed_width(
                 20,
-                TextView::new(&contact_name),
+                TextView::new(&display_name),
             ))
             .child(ResizedView::with_fixed_width(
                 40,
@@ -215,12 +308,147 @@ fn create_contacts_table_page(
                 siv.pop_layer();
                 show_add_contact_standalone(siv);
             })
+            .button("Import", |siv| show_contacts_file_dialog(siv, true))
+            .button("Export", |siv| show_contacts_file_dialog(siv, false))
+            .button("Add Proof", show_add_proof_dialog)
+            .button("My Proof", show_my_proof_dialog)
             .button("Close", |siv| {
                 siv.pop_layer();
             }),
     );
 }
 
+/// Ask for the CSV or JSON file to import contacts from or export
+/// them to
+fn show_contacts_file_dialog(s: &mut Cursive, import: bool) {
+    let core = s
+        .user_data::<Arc<Core>>()
+        .expect("Core missing from user_data")
+        .clone();
+    let default = if import { "" } else { "contacts.csv" };
+    s.add_layer(
+        Dialog::around(
+            LinearLayout::vertical()
+                .child(TextView::new("File (.csv or .json):"))
+                .child(with_paste(EditView::new().content(default).with_name("contacts_file"))),
+        )
+        .title(if import { "Import Contacts" } else { "Export Contacts" })
+        .button(if import { "Import" } else { "Export" }, move |siv| {
+            let path = siv
+                .call_on_name("contacts_file", |view: &mut EditView| view.get_content())
+                .unwrap();
+            let path = PathBuf::from(path.trim());
+            if !import {
+                match core.export_contacts(&path) {
+                    Ok(()) => {
+                        siv.pop_layer();
+                        show_success_dialog(siv, format!("Contacts exported to {}", path.display()));
+                    }
+                    Err(e) => show_error_dialog(siv, format!("{:#}", e)),
+                }
+                return;
+            }
+            match core.import_contacts(&path) {
+                Ok(import) => {
+                    siv.pop_layer(); // Close file dialog
+                    siv.pop_layer(); // Close contacts dialog
+                    show_contacts_dialog(siv);
+                    let mut message = format!("Imported {} contacts", import.added);
+                    if !import.skipped.is_empty() {
+                        message.push_str(&format!("\n\nSkipped:\n{}", import.skipped.join("\n")));
+                    }
+                    show_success_dialog(siv, message);
+                }
+                Err(e) => show_error_dialog(siv, format!("{:#}", e)),
+            }
+        })
+        .button("Cancel", |siv| {
+            siv.pop_layer();
+        }),
+    );
+}
+
+/// Paste the ownership proof a contact sent to add or verify them
+fn show_add_proof_dialog(s: &mut Cursive) {
+    let core = s
+        .user_data::<Arc<Core>>()
+        .expect("Core missing from user_data")
+        .clone();
+    s.add_layer(
+        Dialog::around(
+            LinearLayout::vertical()
+                .child(TextView::new("Ownership proof or file (Ctrl+V to paste):"))
+                .child(with_paste(EditView::new().with_name("ownership_proof")).fixed_width(60)),
+        )
+        .title("Add Proof")
+        .button("Verify", move |siv| {
+            let proof = siv
+                .call_on_name("ownership_proof", |view: &mut EditView| view.get_content())
+                .unwrap();
+            match core.add_contact_proof(&proof) {
+                Ok(name) => {
+                    siv.pop_layer(); // Close proof dialog
+                    siv.pop_layer(); // Close contacts dialog
+                    show_contacts_dialog(siv);
+                    show_success_dialog(siv, format!("Contact '{}' verified", name));
+                }
+                Err(e) => show_error_dialog(siv, format!("{:#}", e)),
+            }
+        })
+        .button("Cancel", |siv| {
+            siv.pop_layer();
+        }),
+    );
+}
+
+/// Sign a proof that the first address is ours, to send to contacts
+fn show_my_proof_dialog(s: &mut Cursive) {
+    let core = s
+        .user_data::<Arc<Core>>()
+        .expect("Core missing from user_data")
+        .clone();
+    s.add_layer(
+        Dialog::around(
+            LinearLayout::vertical()
+                .child(TextView::new("Name to be known by:"))
+                .child(EditView::new().with_name("proof_name")),
+        )
+        .title("My Proof")
+        .button("Sign", move |siv| {
+            let name = siv
+                .call_on_name("proof_name", |view: &mut EditView| view.get_content())
+                .unwrap();
+            if name.trim().is_empty() {
+                show_error_dialog(siv, "Name cannot be empty");
+                return;
+            }
+            let proof = match core.prove_address(name.trim().to_string(), None) {
+                Ok(proof) => proof.encode(),
+                Err(e) => {
+                    show_error_dialog(siv, format!("{:#}", e));
+                    return;
+                }
+            };
+            siv.pop_layer();
+            siv.add_layer(
+                Dialog::around(TextView::new(proof.clone()).fixed_width(60))
+                    .title("My Proof")
+                    .button("Copy", move |siv| {
+                        if let Err(e) = clipboard::copy(&proof) {
+                            siv.add_layer(Dialog::info(format!("Failed to copy proof: {}", e)));
+                        }
+                    })
+                    .button("Close", |siv| {
+                        siv.pop_layer();
+                    }),
+            );
+        })
+        .button("Cancel", |siv| {
+            siv.pop_layer();
+        }),
+    );
+}
+
 /// Delete a contact with confirmation
 fn delete_contact(s: &mut Cursive, name: String, address: String) {
     s.add_layer(
@@ -264,8 +492,10 @@ fn show_add_contact_standalone(s: &mut Cursive) {
             LinearLayout::vertical()
                 .child(TextView::new("Contact name:"))
                 .child(EditView::new().with_name("contact_name"))
-                .child(TextView::new("Bitcoin address:"))
-                .child(EditView::new().with_name("contact_address")),
+                .child(TextView::new("Bitcoin address (Ctrl+V to paste):"))
+                .child(with_paste(EditView::new().with_name("contact_address")))
+                .child(TextView::new("Notes (optional):"))
+                .child(EditView::new().with_name("contact_notes")),
         )
         .title("Add Contact")
         .button("Save", move |siv| {
@@ -275,6 +505,9 @@ fn show_add_contact_standalone(s: &mut Cursive) {
             let address = siv
                 .call_on_name("contact_address", |view: &mut EditView| view.get_content())
                 .unwrap();
+            let notes = siv
+                .call_on_name("contact_notes", |view: &mut EditView| view.get_content())
+                .unwrap();
 
             if name.trim().is_empty() {
                 show_error_dialog(siv, "Contact name cannot be empty");
@@ -286,7 +519,8 @@ fn show_add_contact_standalone(s: &mut Cursive) {
                 return;
             }
 
-            match core.add_contact(name.trim().to_string(), address.trim().to_string()) {
+            let notes = Some(notes.trim().to_string()).filter(|notes| !notes.is_empty());
+            match core.add_contact(name.trim().to_string(), address.trim().to_string(), notes) {
                 Ok(_) => {
                     siv.pop_layer();
                     show_success_dialog(siv, "Contact added successfully".to_string());
@@ -302,11 +536,31 @@ fn show_add_contact_standalone(s: &mut Cursive) {
     );
 }
 
-/// Set up the menu bar with "Send", "Contacts", and "Quit" options.
+/// Set up the menu bar with "Send", "History", "Balance", "Contacts",
+/// "Multisig", "Settings" and "Quit" options.
 fn setup_menubar(siv: &mut Cursive) {
+    let accounts = siv.user_data::<Arc<Core>>().map(|core| core.accounts()).unwrap_or_default();
+    if !accounts.is_empty() {
+        let mut tree = menu::Tree::new().leaf("All accounts", |s| select_account(s, None));
+        for (index, name) in accounts.into_iter().enumerate() {
+            tree.add_leaf(name, move |s| select_account(s, Some(index)));
+        }
+        tree.add_delimiter();
+        tree.add_leaf("Scan for used addresses", show_scan_dialog);
+        siv.menubar().add_subtree("Account", tree);
+    }
     siv.menubar()
         .add_leaf("Send", |s| show_transaction_dialog(s, None))
-        .add_leaf("Contacts", |s| show_contacts_dialog(s))
+        .add_leaf("History", show_history_dialog)
+        .add_leaf("Balance", |s| show_balance_chart(s, Range::Week))
+        .add_leaf("Contacts", show_contacts_dialog)
+        .add_subtree(
+            "Multisig",
+            menu::Tree::new()
+                .leaf("Send from account", show_multisig_send_dialog)
+                .leaf("Import signatures", show_import_signatures_dialog),
+        )
+        .add_leaf("Settings", show_settings_dialog)
         .add_leaf("Quit", |s| s.quit());
 
     siv.set_autohide_menu(false);
@@ -314,17 +568,23 @@ fn setup_menubar(siv: &mut Cursive) {
 
 /// Set up the main layout of the application.
 fn setup_layout(siv: &mut Cursive, balance_content: TextContent) {
-    let instruction = TextView::new("Press Escape to select the top menu");
-    let balance_panel = Panel::new(TextView::new_with_content(balance_content)).title("Balance");
-
-    // Create wallet address panel
     let core = siv
         .user_data::<Arc<Core>>()
         .expect("Core missing from user_data")
         .clone();
-    let wallet_address_content = TextContent::new(create_wallet_address_text(&core));
+    let instruction = match core.accounts().is_empty() {
+        true => "Press Escape to select the top menu, u to switch between BTC and sats",
+        false => "Press Escape to select the top menu, u to switch between BTC and sats, a to switch accounts",
+    };
+    let instruction = TextView::new(instruction);
+    let balance_panel = Panel::new(TextView::new_with_content(balance_content))
+        .title(balance_title(&core))
+        .with_name("balance_panel");
+
+    // Create wallet address panel
     let wallet_address_panel =
-        Panel::new(TextView::new_with_content(wallet_address_content)).title("Wallet Address");
+        Panel::new(TextView::new(create_wallet_address_text(&core)).with_name("wallet_addresses"))
+            .title("Wallet Address");
 
     let info_layout = create_info_layout(&core);
     let layout = LinearLayout::vertical()
@@ -336,9 +596,81 @@ fn setup_layout(siv: &mut Cursive, balance_content: TextContent) {
     //siv.add_fullscreen_layer(layout);
 }
 
+/// Title of the balance panel, naming the account shown
+fn balance_title(core: &Core) -> String {
+    match core.selected_account() {
+        Some(index) => format!("Balance of {}", core.accounts()[index]),
+        None if core.accounts().is_empty() => "Balance".to_string(),
+        None => "Balance of all accounts".to_string(),
+    }
+}
+
+/// Show the next account, and the whole wallet again after the last
+fn cycle_account(s: &mut Cursive) {
+    let Some(core) = s.user_data::<Arc<Core>>().cloned() else {
+        return;
+    };
+    let count = core.accounts().len();
+    if count == 0 {
+        return;
+    }
+    let next = match core.selected_account() {
+        None => Some(0),
+        Some(index) if index + 1 < count => Some(index + 1),
+        Some(_) => None,
+    };
+    select_account(s, next);
+}
+
+/// Scan the chains of the HD accounts for addresses paid past the
+/// ones handed out, showing how far the scan got
+fn show_scan_dialog(s: &mut Cursive) {
+    let core = s
+        .user_data::<Arc<Core>>()
+        .expect("Core missing from user_data")
+        .clone();
+    let status = TextContent::new("Asking the node...");
+    s.add_layer(
+        Dialog::around(TextView::new_with_content(status.clone()))
+            .title("Scanning addresses")
+            .button("Close", |s| {
+                s.pop_layer();
+            }),
+    );
+    tokio::runtime::Handle::current().spawn(async move {
+        let result = core.scan_accounts(|line| status.set_content(line)).await;
+        status.set_content(match result {
+            Ok(0) => "No used addresses beyond the ones handed out".to_string(),
+            Ok(found) => format!("Found {} more used addresses, restart the wallet to watch them", found),
+            Err(e) => format!("Scan failed: {}", e),
+        });
+    });
+}
+
+/// Show the balance, addresses and history of an account, or of the
+/// whole wallet with None
+fn select_account(s: &mut Cursive, account: Option<usize>) {
+    let Some(core) = s.user_data::<Arc<Core>>().cloned() else {
+        return;
+    };
+    core.select_account(account);
+    s.call_on_name("balance_panel", |panel: &mut Panel<TextView>| {
+        panel.set_title(balance_title(&core));
+        panel.get_inner_mut().set_content(big_mode_btc(&core));
+    });
+    s.call_on_name("wallet_addresses", |view: &mut TextView| {
+        view.set_content(create_wallet_address_text(&core));
+    });
+    refresh_address_balances(s);
+}
+
 /// Create the wallet address text
 fn create_wallet_address_text(core: &Arc<Core>) -> String {
-    let addresses = core.get_addresses();
+    let addresses: Vec<String> = core
+        .receive_addresses()
+        .iter()
+        .map(|address| core.format_address(address))
+        .collect();
     if addresses.is_empty() {
         "(No wallet addresses)".to_string()
     } else if addresses.len() == 1 {
@@ -353,32 +685,92 @@ fn create_wallet_address_text(core: &Arc<Core>) -> String {
     }
 }
 
-/// Create the information layout containing keys and contacts.
+/// Format a row of the per-address balance panel.
+fn format_address_balance(balance: &AddressBalance, unit: DisplayUnit) -> String {
+    let delta = match balance.unconfirmed_delta {
+        0 => String::new(),
+        delta if delta > 0 => format!("+{}", format_amount(delta as u64, unit)),
+        delta => format!("-{}", format_amount(delta.unsigned_abs(), unit)),
+    };
+    format!(
+        "{}  {:>20}  {:>20}  {} UTXOs",
+        balance.address,
+        format_amount(balance.confirmed, unit),
+        delta,
+        balance.utxo_count
+    )
+}
+
+/// Create the panel listing every address with its balance.
+fn create_address_panel(core: &Arc<Core>) -> impl View {
+    let mut view = SelectView::<String>::new();
+    for balance in core.get_address_balances() {
+        view.add_item(format_address_balance(&balance, core.display_unit()), balance.address);
+    }
+    if view.is_empty() {
+        view.add_item("(No keys configured)", String::new());
+    }
+    let view = OnEventView::new(view.with_name("address_balances"))
+        .on_event('c', copy_selected_address);
+    Panel::new(view).title("Addresses (c: copy)")
+}
+
+/// Refresh the per-address balances, keeping the selection, and show
+/// any queued notifications.
+fn refresh_address_balances(s: &mut Cursive) {
+    let Some(core) = s.user_data::<Arc<Core>>().cloned() else {
+        return;
+    };
+    while let Some(message) = core.next_popup() {
+        s.add_layer(Dialog::info(message).title("Notice"));
+    }
+    let balances = core.get_address_balances();
+    if balances.is_empty() {
+        return;
+    }
+    s.call_on_name("address_balances", |view: &mut SelectView<String>| {
+        let unit = core.display_unit();
+        let rows: Vec<String> = balances
+            .iter()
+            .map(|balance| format_address_balance(balance, unit))
+            .collect();
+        let current: Vec<String> = view.iter().map(|(label, _)| label.to_string()).collect();
+        // only rebuild on changes so the selection doesn't flicker
+        if rows != current {
+            let selected = view.selected_id();
+            view.clear();
+            for (row, balance) in rows.into_iter().zip(balances) {
+                view.add_item(row, balance.address);
+            }
+            if let Some(selected) = selected {
+                let _ = view.set_selection(selected);
+            }
+        }
+    });
+}
+
+/// Copy the address selected in the balance panel to the clipboard.
+fn copy_selected_address(s: &mut Cursive) {
+    let selected = s
+        .call_on_name("address_balances", |view: &mut SelectView<String>| {
+            view.selection()
+        })
+        .flatten();
+    let Some(address) = selected.filter(|address| !address.is_empty()) else {
+        return;
+    };
+    match clipboard::copy(&address) {
+        Ok(()) => show_success_dialog(s, format!("Copied {} to the clipboard", address)),
+        Err(e) => s.add_layer(Dialog::info(format!("Failed to copy address: {}", e))),
+    }
+}
+
+/// Create the information layout containing addresses and contacts.
 fn create_info_layout(core: &Arc<Core>) -> LinearLayout {
     let mut info_layout = LinearLayout::horizontal();
     let config = core.config.read().unwrap();
 
-    let keys_content = if config.my_keys.is_empty() {
-        "(No keys configured)".to_string()
-    } else {
-        let addresses = core.get_addresses();
-        config
-            .my_keys
-            .iter()
-            .enumerate()
-            .map(|(idx, key)| {
-                let address = addresses
-                    .get(idx)
-                    .map(|a| a.as_str())
-                    .unwrap_or("(address unavailable)");
-                format!("{}\n  Address: {}", key.private.display(), address)
-            })
-            .collect::<Vec<String>>()
-            .join("\n\n")
-    };
-    info_layout.add_child(ResizedView::with_full_width(
-        Panel::new(TextView::new(keys_content)).title("Your keys"),
-    ));
+    info_layout.add_child(ResizedView::with_full_width(create_address_panel(core)));
 
     let contacts_content = if config.contacts.is_empty() {
         "(No contacts)".to_string()
@@ -386,7 +778,19 @@ fn create_info_layout(core: &Arc<Core>) -> LinearLayout {
         config
             .contacts
             .iter()
-            .map(|contact| format!("{}\n  Address: {}", contact.name, contact.address))
+            .map(|contact| {
+                let mut text = format!("{}\n  Address: {}", contact.name, contact.address);
+                if contact.verified() {
+                    text.push_str(" (verified)");
+                }
+                for address in &contact.other_addresses {
+                    text.push_str(&format!("\n  Also: {}", address));
+                }
+                if let Some(notes) = &contact.notes {
+                    text.push_str(&format!("\n  Notes: {}", notes));
+                }
+                text
+            })
             .collect::<Vec<String>>()
             .join("\n\n")
     };
@@ -399,68 +803,316 @@ fn create_info_layout(core: &Arc<Core>) -> LinearLayout {
 /// Display the transaction dialog with optional pre-filled recipient.
 fn show_transaction_dialog(s: &mut Cursive, recipient: Option<(String, String)>) {
     info!("Showing send transaction dialog");
-    let unit = Arc::new(Mutex::new(Unit::Btc));
+    let core = s
+        .user_data::<Arc<Core>>()
+        .expect("Core missing from user_data")
+        .clone();
 
     // Pre-fill recipient if provided
     let initial_recipient = recipient.map(|(name, _address)| name);
-    let layout = create_transaction_layout(unit.clone(), initial_recipient);
+    let layout = create_transaction_layout(core.display_unit(), initial_recipient.clone())
+        .child(create_spend_from_layout(&core));
 
     s.add_layer(
         Dialog::around(layout)
             .title("Send Transaction")
-            .button("Send", move |siv| {
-                send_transaction(siv, *unit.lock().unwrap())
-            })
+            .button("Send", move |siv| send_transaction(siv, core.display_unit()))
             .button("Cancel", |siv| {
                 debug!("Transaction cancelled");
                 siv.pop_layer();
             }),
     );
+    if let Some(recipient) = initial_recipient {
+        update_recipient_status(s, &recipient);
+    }
 }
 
 /// Create the layout for the transaction dialog.
 fn create_transaction_layout(
-    unit: Arc<Mutex<Unit>>,
+    unit: DisplayUnit,
     initial_recipient: Option<String>,
 ) -> LinearLayout {
-    let mut recipient_view = EditView::new();
+    let mut recipient_view = EditView::new().on_edit(|s, text, _| update_recipient_status(s, text));
     if let Some(recipient) = initial_recipient {
         recipient_view.set_content(recipient);
     }
     LinearLayout::vertical()
-        .child(TextView::new("Recipient (name or address):"))
-        .child(recipient_view.with_name("recipient"))
+        .child(TextView::new("Recipient (name or address, Ctrl+V to paste):"))
+        .child(with_paste(recipient_view.with_name("recipient")))
         .child(TextView::new("").with_name("recipient_status"))
         .child(TextView::new("Amount:"))
-        .child(EditView::new().with_name("amount"))
+        .child(with_paste(EditView::new().with_name("amount")))
         .child(create_unit_layout(unit))
 }
 
-/// Create the layout for selecting the transaction unit (BTC orSats).
-fn create_unit_layout(unit: Arc<Mutex<Unit>>) -> LinearLayout {
+/// Advanced option keeping the funds of addresses apart: spend only
+/// the UTXOs of the chosen address
+fn create_spend_from_layout(core: &Core) -> LinearLayout {
+    let multisig: Vec<String> = core
+        .multisig_accounts()
+        .into_iter()
+        .map(|(_, address)| address)
+        .collect();
+    let mut select = SelectView::<String>::new().popup().disabled();
+    for balance in core.get_address_balances() {
+        if multisig.contains(&balance.address) {
+            continue;
+        }
+        let label = format!("{} ({})", balance.address, format_amount(balance.confirmed, core.display_unit()));
+        select.add_item(label, balance.address);
+    }
+    LinearLayout::horizontal()
+        .child(Checkbox::new().on_change(|s, checked| {
+            s.call_on_name("spend_from", |view: &mut SelectView<String>| view.set_enabled(checked));
+        }))
+        .child(TextView::new(" Spend only from "))
+        .child(select.with_name("spend_from"))
+}
+
+/// Say under the recipient field which contact it is and whether they
+/// proved their address
+fn update_recipient_status(s: &mut Cursive, recipient: &str) {
+    let core = s
+        .user_data::<Arc<Core>>()
+        .expect("Core missing from user_data")
+        .clone();
+    let recipient = recipient.trim();
+    let contact = core
+        .find_contact_by_name(recipient)
+        .or_else(|| core.find_contact_by_address(recipient));
+    let status = match contact {
+        Some(contact) if contact.verified() => format!("✓ {} (verified)", contact.name),
+        Some(contact) => format!("{} (not verified)", contact.name),
+        None => String::new(),
+    };
+    s.call_on_name("recipient_status", |view: &mut TextView| view.set_content(status));
+}
+
+/// Let Ctrl+V paste the clipboard into an edit field.
+fn with_paste(view: NamedView<EditView>) -> OnEventView<NamedView<EditView>> {
+    OnEventView::new(view).on_event_inner(Event::CtrlChar('v'), |view, _| {
+        let text = match clipboard::paste() {
+            Ok(text) => text,
+            Err(e) => {
+                warn!("Failed to paste: {}", e);
+                return None;
+            }
+        };
+        let mut view = view.get_mut();
+        let callback = text.trim().chars().map(|ch| view.insert(ch)).last();
+        Some(EventResult::Consumed(callback))
+    })
+}
+
+fn unit_label(unit: DisplayUnit) -> &'static str {
+    match unit {
+        DisplayUnit::Btc => "BTC",
+        DisplayUnit::Sats => "Sats",
+    }
+}
+
+/// Create the layout for selecting the transaction unit (BTC or Sats).
+fn create_unit_layout(unit: DisplayUnit) -> LinearLayout {
     LinearLayout::horizontal()
         .child(TextView::new("Unit: "))
-        .child(TextView::new_with_content(TextContent::new("BTC")).with_name("unit_display"))
-        .child(Button::new("Switch", move |s| switch_unit(s, unit.clone())))
+        .child(TextView::new(unit_label(unit)).with_name("unit_display"))
+        .child(Button::new("Switch", toggle_display_unit))
 }
 
-/// Switch the transaction unit between BTC and Sats.
-fn switch_unit(s: &mut Cursive, unit: Arc<Mutex<Unit>>) {
-    let mut unit = unit.lock().unwrap();
-    *unit = match *unit {
-        Unit::Btc => Unit::Sats,
-        Unit::Sats => Unit::Btc,
+/// Switch the display unit between BTC and Sats everywhere.
+fn toggle_display_unit(s: &mut Cursive) {
+    let core = s
+        .user_data::<Arc<Core>>()
+        .expect("Core missing from user_data")
+        .clone();
+    let unit = match core.toggle_display_unit() {
+        Ok(unit) => unit,
+        Err(e) => {
+            warn!("Failed to save display unit: {}", e);
+            core.display_unit()
+        }
     };
     s.call_on_name("unit_display", |view: &mut TextView| {
-        view.set_content(match *unit {
-            Unit::Btc => "BTC",
-            Unit::Sats => "Sats",
-        });
+        view.set_content(unit_label(unit));
     });
 }
 
+/// Edit the fee, node, display unit, price source, theme and keys,
+/// saved to the config file.
+fn show_settings_dialog(s: &mut Cursive) {
+    let core = s
+        .user_data::<Arc<Core>>()
+        .expect("Core missing from user_data")
+        .clone();
+    let settings = core.settings();
+
+    let mut fee_type = SelectView::new().popup();
+    fee_type.add_item("Fixed (sats)", "fixed");
+    fee_type.add_item("Percent of amount", "percent");
+    fee_type.set_selection(match settings.fee_config.fee_type {
+        FeeType::Fixed => 0,
+        FeeType::Percent => 1,
+    });
+    let mut unit = SelectView::new().popup();
+    unit.add_item("BTC", DisplayUnit::Btc);
+    unit.add_item("Sats", DisplayUnit::Sats);
+    unit.set_selection(match settings.display_unit {
+        DisplayUnit::Btc => 0,
+        DisplayUnit::Sats => 1,
+    });
+    let mut price = SelectView::new().popup();
+    price.add_item("None", false);
+    price.add_item("Fixed rate", true);
+    let (currency, rate) = match &settings.price_source {
+        PriceSource::None => ("USD".to_string(), String::new()),
+        PriceSource::Fixed { currency, per_btc } => {
+            price.set_selection(1);
+            (currency.clone(), per_btc.to_string())
+        }
+    };
+
+    let mut theme = SelectView::new().popup();
+    for name in ThemeName::ALL {
+        theme.add_item(format!("{:?}", name), name);
+    }
+    theme.set_selection(ThemeName::ALL.iter().position(|name| *name == settings.theme).unwrap_or(0));
+    let mut keys = SelectView::new().popup();
+    keys.add_item("Default", Keybindings::Default);
+    keys.add_item("Vim", Keybindings::Vim);
+    keys.set_selection(usize::from(settings.keybindings == Keybindings::Vim));
+
+    let layout = LinearLayout::vertical()
+        .child(TextView::new("Fee:"))
+        .child(
+            LinearLayout::horizontal()
+                .child(fee_type.with_name("settings_fee_type"))
+                .child(TextView::new(" "))
+                .child(
+                    EditView::new()
+                        .content(settings.fee_config.value.to_string())
+                        .with_name("settings_fee_value")
+                        .fixed_width(12),
+                ),
+        )
+        .child(TextView::new("Default node:"))
+        .child(
+            EditView::new()
+                .content(settings.default_node)
+                .with_name("settings_node"),
+        )
+        .child(TextView::new("Display unit:"))
+        .child(unit.with_name("settings_unit"))
+        .child(TextView::new("Price source:"))
+        .child(
+            LinearLayout::horizontal()
+                .child(price.with_name("settings_price"))
+                .child(TextView::new(" 1 BTC = "))
+                .child(EditView::new().content(rate).with_name("settings_rate").fixed_width(12))
+                .child(TextView::new(" "))
+                .child(EditView::new().content(currency).with_name("settings_currency").fixed_width(6)),
+        )
+        .child(TextView::new("Theme:"))
+        .child(theme.with_name("settings_theme"))
+        .child(TextView::new("Keys:"))
+        .child(keys.with_name("settings_keys"));
+
+    s.add_layer(
+        Dialog::around(layout)
+            .title("Settings")
+            .button("Save", save_settings)
+            .button("Cancel", |siv| {
+                siv.pop_layer();
+            }),
+    );
+}
+
+/// Check and save the settings dialog, reconnecting if the node changed.
+fn save_settings(s: &mut Cursive) {
+    let core = s
+        .user_data::<Arc<Core>>()
+        .expect("Core missing from user_data")
+        .clone();
+    let text = |s: &mut Cursive, name: &str| {
+        s.call_on_name(name, |view: &mut EditView| view.get_content().trim().to_string())
+            .unwrap_or_default()
+    };
+    let fee_value = text(s, "settings_fee_value");
+    let default_node = text(s, "settings_node");
+    let rate = text(s, "settings_rate");
+    let currency = text(s, "settings_currency");
+    let fee_type = s
+        .call_on_name("settings_fee_type", |view: &mut SelectView<&str>| view.selection())
+        .flatten();
+    let unit = s
+        .call_on_name("settings_unit", |view: &mut SelectView<DisplayUnit>| view.selection())
+        .flatten();
+    let priced = s
+        .call_on_name("settings_price", |view: &mut SelectView<bool>| view.selection())
+        .flatten();
+    let theme = s
+        .call_on_name("settings_theme", |view: &mut SelectView<ThemeName>| view.selection())
+        .flatten()
+        .map_or(ThemeName::default(), |theme| *theme);
+    let keybindings = s
+        .call_on_name("settings_keys", |view: &mut SelectView<Keybindings>| view.selection())
+        .flatten()
+        .map_or(Keybindings::default(), |keys| *keys);
+
+    let Some(value) = fee_value.parse::<f64>().ok().filter(|value| value.is_finite() && *value >= 0.0) else {
+        s.add_layer(Dialog::info("The fee must be a number of at least 0"));
+        return;
+    };
+    let fee_type = match fee_type.as_deref() {
+        Some(&"percent") => FeeType::Percent,
+        _ => FeeType::Fixed,
+    };
+    if default_node.rsplit_once(':').is_none_or(|(host, port)| host.is_empty() || port.parse::<u16>().is_err()) {
+        s.add_layer(Dialog::info("The node must be given as host:port"));
+        return;
+    }
+    let price_source = if priced.is_some_and(|priced| *priced) {
+        let Some(per_btc) = rate.parse::<f64>().ok().filter(|rate| rate.is_finite() && *rate > 0.0) else {
+            s.add_layer(Dialog::info("The rate must be a number above 0"));
+            return;
+        };
+        if currency.is_empty() {
+            s.add_layer(Dialog::info("The rate needs a currency"));
+            return;
+        }
+        PriceSource::Fixed { currency, per_btc }
+    } else {
+        PriceSource::None
+    };
+    let settings = Settings {
+        fee_config: FeeConfig { fee_type, value },
+        default_node,
+        display_unit: unit.map_or(DisplayUnit::default(), |unit| *unit),
+        price_source,
+        theme,
+        keybindings,
+    };
+
+    match core.update_settings(settings) {
+        Ok(node_changed) => {
+            s.pop_layer();
+            let ui = core.config.read().unwrap().ui.clone();
+            apply_ui(s, &ui);
+            if node_changed {
+                let core = core.clone();
+                tokio::runtime::Handle::current().spawn(async move {
+                    if let Err(e) = core.reconnect().await {
+                        warn!("Failed to connect to the new node: {}", e);
+                    }
+                });
+            }
+            s.add_layer(Dialog::info("Settings saved").title("Settings"));
+        }
+        Err(e) => s.add_layer(Dialog::info(format!("Failed to save settings: {}", e))),
+    }
+}
+
 /// Process the send transaction request.
-fn send_transaction(s: &mut Cursive, unit: Unit) {
+fn send_transaction(s: &mut Cursive, unit: DisplayUnit) {
     debug!("Send button pressed");
     let recipient = s
         .call_on_name("recipient", |view: &mut EditView| view.get_content())
@@ -468,11 +1120,16 @@ fn send_transaction(s: &mut Cursive, unit: Unit) {
     let amount = s
         .call_on_name("amount", |view: &mut EditView| view.get_content())
         .unwrap();
-    let amount_decimal =
-        BigDecimal::from_str(amount.as_ref()).unwrap_or_else(|_| BigDecimal::from(0u32));
-    let amount_sats = convert_amount(&amount_decimal, unit, Unit::Sats)
-        .to_u64()
-        .unwrap_or(0);
+    let Some(amount_sats) = parse_amount(amount.as_str(), unit) else {
+        show_error_dialog(s, "Invalid amount");
+        return;
+    };
+    let from = s
+        .call_on_name("spend_from", |view: &mut SelectView<String>| {
+            view.is_enabled().then(|| view.selection()).flatten()
+        })
+        .flatten()
+        .map(|address| (*address).clone());
 
     if amount_sats == 0 {
         show_error_dialog(s, "Amount must be greater than 0");
@@ -503,15 +1160,15 @@ fn send_transaction(s: &mut Cursive, unit: Unit) {
         && core.find_contact_by_name(recipient.as_str()).is_none()
     {
         // Prompt to add as contact
-        prompt_add_contact(s, recipient_address.clone(), amount_sats, unit);
+        prompt_add_contact(s, recipient_address.clone(), amount_sats, from);
     } else {
         // Address is in contacts or was resolved from name, proceed
-        proceed_with_transaction(s, &recipient_address, amount_sats);
+        proceed_with_transaction(s, &recipient_address, amount_sats, from);
     }
 }
 
 /// Prompt user to add address as contact
-fn prompt_add_contact(s: &mut Cursive, address: String, amount: u64, _unit: Unit) {
+fn prompt_add_contact(s: &mut Cursive, address: String, amount: u64, from: Option<String>) {
     s.add_layer(
         Dialog::text(format!(
             "Address '{}' is not in your contacts.\n\nWould you like to add it?",
@@ -520,16 +1177,17 @@ fn prompt_add_contact(s: &mut Cursive, address: String, amount: u64, _unit: Unit
         .title("Add Contact?")
         .button("Add Contact", {
             let address = address.clone();
+            let from = from.clone();
             move |siv| {
                 siv.pop_layer();
-                show_add_contact_dialog(siv, &address, amount);
+                show_add_contact_dialog(siv, &address, amount, from.clone());
             }
         })
         .button("Send Anyway", {
             let address = address.clone();
             move |siv| {
                 siv.pop_layer();
-                proceed_with_transaction(siv, &address, amount);
+                proceed_with_transaction(siv, &address, amount, from.clone());
             }
         })
         .button("Cancel", |siv| {
@@ -539,7 +1197,7 @@ fn prompt_add_contact(s: &mut Cursive, address: String, amount: u64, _unit: Unit
 }
 
 /// Show dialog to add contact
-fn show_add_contact_dialog(s: &mut Cursive, address: &str, amount: u64) {
+fn show_add_contact_dialog(s: &mut Cursive, address: &str, amount: u64, from: Option<String>) {
     let address = address.to_owned();
     let core = s
         .user_data::<Arc<Core>>()
@@ -554,6 +1212,7 @@ fn show_add_contact_dialog(s: &mut Cursive, address: &str, amount: u64) {
         .title("Add Contact")
         .button("Save", {
             let address = address.clone();
+            let from = from.clone();
             move |siv| {
                 let name = siv
                     .call_on_name("contact_name", |view: &mut EditView| view.get_content())
@@ -564,10 +1223,10 @@ fn show_add_contact_dialog(s: &mut Cursive, address: &str, amount: u64) {
                     return;
                 }
 
-                match core.add_contact(name.trim().to_string(), address.to_string()) {
+                match core.add_contact(name.trim().to_string(), address.to_string(), None) {
                     Ok(_) => {
                         siv.pop_layer();
-                        proceed_with_transaction(siv, &address, amount);
+                        proceed_with_transaction(siv, &address, amount, from.clone());
                     }
                     Err(e) => {
                         show_error_dialog(siv, format!("{}", e));
@@ -579,26 +1238,245 @@ fn show_add_contact_dialog(s: &mut Cursive, address: &str, amount: u64) {
             let address = address.clone();
             move |siv| {
                 siv.pop_layer();
-                proceed_with_transaction(siv, &address, amount);
+                proceed_with_transaction(siv, &address, amount, from.clone());
             }
         }),
     );
 }
 
 /// Proceed with transaction after contact handling
-fn proceed_with_transaction(s: &mut Cursive, address: &str, amount: u64) {
+fn proceed_with_transaction(s: &mut Cursive, address: &str, amount: u64, from: Option<String>) {
     let core = s
         .user_data::<Arc<Core>>()
         .expect("Core missing from user_data")
         .clone();
-    match core.send_transaction_async(&address, amount) {
-        Ok(_) => {
-            show_success_dialog(s, "Transaction sent successfully".to_string());
-        }
+    let sent = format_amount(amount, core.display_unit());
+    match core.send_transaction_async(address, amount, from) {
+        Ok(SendOutcome::Sent(txid)) => show_transaction_sent_dialog(s, sent, txid.to_string()),
+        Ok(SendOutcome::Exported(path)) => show_success_dialog(
+            s,
+            format!(
+                "Transaction of {} exported for signing to\n{}\n\n\
+                 Sign it with tx_sign and send the result with `wallet broadcast`",
+                sent,
+                path.display()
+            ),
+        ),
+        Ok(SendOutcome::Queued(txid)) => show_success_dialog(
+            s,
+            format!(
+                "The node can't be reached. Transaction {} of {} is queued\n\
+                 and will be broadcast when the node is back.",
+                txid, sent
+            ),
+        ),
         Err(e) => show_error_dialog(s, format!("{}", e)),
     }
 }
 
+/// Display the id of a sent transaction, with a button to copy it.
+fn show_transaction_sent_dialog(s: &mut Cursive, amount: String, txid: String) {
+    info!("Transaction {} sent successfully", txid);
+    s.add_layer(
+        Dialog::text(format!(
+            "Sent {} successfully\n\nTransaction id:\n{}",
+            amount, txid
+        ))
+            .title("Success")
+            .button("Copy txid", move |s| {
+                if let Err(e) = clipboard::copy(&txid) {
+                    s.add_layer(Dialog::info(format!("Failed to copy transaction id: {}", e)));
+                }
+            })
+            .button("OK", |s| {
+                s.pop_layer(); // Close success dialog
+                s.pop_layer(); // Close the transaction dialog that's still on the stack
+            }),
+    );
+}
+
+/// Chart how the balance evolved over `range`, with buttons to pick
+/// another range.
+fn show_balance_chart(s: &mut Cursive, range: Range) {
+    const WIDTH: usize = 60;
+    const ROWS: usize = 10;
+    let core = s
+        .user_data::<Arc<Core>>()
+        .expect("Core missing from user_data")
+        .clone();
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let unit = core.display_unit();
+    let text = {
+        let history = core.balance_history();
+        let values = history.sample(range, now, WIDTH);
+        let mut text = balances::chart(&values, ROWS, unit);
+        if let (Some(first), Some(last)) = (values.first(), values.last()) {
+            let changes = history
+                .snapshots()
+                .iter()
+                .filter(|snapshot| range.seconds().is_none_or(|seconds| snapshot.time + seconds >= now))
+                .count();
+            text.push_str(&format!(
+                "\n\n{}\n{} -> {}, {} changes",
+                balances::sparkline(&values),
+                format_amount(*first, unit),
+                format_amount(*last, unit),
+                changes
+            ));
+        }
+        text
+    };
+    if let Some(position) = s.screen_mut().find_layer_from_name("balance_chart") {
+        s.screen_mut().remove_layer(position);
+    }
+    s.add_layer(
+        Dialog::around(TextView::new(text))
+            .title(format!("Balance ({:?})", range))
+            .button("Day", |s| show_balance_chart(s, Range::Day))
+            .button("Week", |s| show_balance_chart(s, Range::Week))
+            .button("All", |s| show_balance_chart(s, Range::All))
+            .button("Close", |s| {
+                s.pop_layer();
+            })
+            .with_name("balance_chart"),
+    );
+}
+
+/// List the transactions sent in this session, pending ones can have
+/// their fee bumped or be cancelled.
+fn show_history_dialog(s: &mut Cursive) {
+    let core = s
+        .user_data::<Arc<Core>>()
+        .expect("Core missing from user_data")
+        .clone();
+    let sent = core.sent_transactions();
+    if sent.is_empty() {
+        s.add_layer(Dialog::info("No transactions sent yet"));
+        return;
+    }
+    let title = match core.selected_account() {
+        Some(index) => format!("History of {}", core.accounts()[index]),
+        None => "History".to_string(),
+    };
+    let unit = core.display_unit();
+    let mut history = SelectView::new();
+    for (entry, status) in sent {
+        let status = match status {
+            SendStatus::Pending => "pending".to_string(),
+            SendStatus::Confirmed => match entry.confirmed_in {
+                // its block's header and merkle branch were checked
+                Some((height, _)) if entry.verified() => format!("confirmed at height {}, verified", height),
+                Some((height, _)) => format!("confirmed at height {}", height),
+                None => "confirmed".to_string(),
+            },
+            SendStatus::Conflicted => "conflicted".to_string(),
+            SendStatus::Replaced(txid) => format!("replaced by {}", &txid.to_string()[..16]),
+            SendStatus::Dropped => "dropped".to_string(),
+            SendStatus::PendingBroadcast => "pending broadcast".to_string(),
+        };
+        let label = format!(
+            "{}  {} to {}  fee {}  {}",
+            &entry.txid.to_string()[..16],
+            format_amount(entry.amount, unit),
+            core.format_address(&entry.recipient),
+            format_amount(entry.fee, unit),
+            status
+        );
+        history.add_item(label, (entry.txid, entry.fee));
+    }
+    s.add_layer(
+        Dialog::around(history.with_name("history").scrollable())
+            .title(title)
+            .button("Bump fee", |siv| replace_selected(siv, false))
+            .button("Cancel tx", |siv| replace_selected(siv, true))
+            .button("Close", |siv| {
+                siv.pop_layer();
+            }),
+    );
+}
+
+fn replace_selected(s: &mut Cursive, cancel: bool) {
+    let selected = s
+        .call_on_name("history", |view: &mut SelectView<(Hash, u64)>| view.selection())
+        .flatten();
+    if let Some(selected) = selected {
+        let (txid, fee) = *selected;
+        show_replace_dialog(s, txid, fee, cancel);
+    }
+}
+
+/// Ask by how much to raise the fee of `txid`, suggesting to double it,
+/// then bump it or, with `cancel`, send its inputs back to the wallet.
+fn show_replace_dialog(s: &mut Cursive, txid: Hash, fee: u64, cancel: bool) {
+    let core = s
+        .user_data::<Arc<Core>>()
+        .expect("Core missing from user_data")
+        .clone();
+    let unit = core.display_unit();
+    let suggested = match unit {
+        DisplayUnit::Btc => btclib::amount::format_btc(fee.max(1)),
+        DisplayUnit::Sats => fee.max(1).to_string(),
+    };
+    let mut layout = LinearLayout::vertical();
+    if cancel {
+        layout.add_child(TextView::new(
+            "The inputs are spent back to your first address instead\n",
+        ));
+    }
+    let layout = layout
+        .child(TextView::new(format!("Current fee: {}", format_amount(fee, unit))))
+        .child(TextView::new(format!("Raise the fee by ({}):", unit_label(unit))))
+        .child(EditView::new().content(suggested).with_name("extra_fee"));
+    s.add_layer(
+        Dialog::around(layout)
+            .title(if cancel { "Cancel Transaction" } else { "Bump Fee" })
+            .button("Replace", move |siv| {
+                let extra_fee = siv
+                    .call_on_name("extra_fee", |view: &mut EditView| view.get_content())
+                    .unwrap();
+                let Some(extra_fee) = parse_amount(extra_fee.as_str(), unit) else {
+                    show_error_dialog(siv, "Invalid amount");
+                    return;
+                };
+                let result = tokio::task::block_in_place(|| {
+                    let handle = tokio::runtime::Handle::current();
+                    if cancel {
+                        handle.block_on(core.cancel_transaction(txid, extra_fee))
+                    } else {
+                        handle.block_on(core.bump_fee(txid, extra_fee))
+                    }
+                });
+                siv.pop_layer(); // Close this dialog
+                siv.pop_layer(); // and the stale history
+                match result {
+                    Ok(SendOutcome::Sent(new_txid)) => show_success_dialog(
+                        siv,
+                        format!("Replaced {}, the replacement is\n{}", &txid.to_string()[..16], new_txid),
+                    ),
+                    Ok(SendOutcome::Exported(path)) => show_success_dialog(
+                        siv,
+                        format!(
+                            "Replacement exported for signing to\n{}\n\n\
+                             Sign it with tx_sign and send the result with `wallet broadcast`",
+                            path.display()
+                        ),
+                    ),
+                    Ok(SendOutcome::Queued(new_txid)) => show_success_dialog(
+                        siv,
+                        format!("The replacement {} is queued until the node can be reached", new_txid),
+                    ),
+                    Err(e) => show_error_dialog(siv, e),
+                }
+            })
+            .button("Back", |siv| {
+                siv.pop_layer();
+            }),
+    );
+}
+
 /// Display a success dialog after a successful transaction.
 fn show_success_dialog(s: &mut Cursive, message: String) {
     let is_transaction = message.contains("Transaction");
@@ -628,3 +1506,165 @@ fn show_error_dialog(s: &mut Cursive, error: impl std::fmt::Display) {
             }),
     );
 }
+
+/// Start a transaction from a multisig account and save it as a PSBT
+/// for the cosigners to sign.
+fn show_multisig_send_dialog(s: &mut Cursive) {
+    let core = s
+        .user_data::<Arc<Core>>()
+        .expect("Core missing from user_data")
+        .clone();
+    let accounts = core.multisig_accounts();
+    if accounts.is_empty() {
+        s.add_layer(Dialog::info(
+            "No multisig accounts configured, add one with `wallet multisig add`",
+        ));
+        return;
+    }
+    let mut account_view = SelectView::new().popup();
+    for (name, address) in accounts {
+        account_view.add_item(format!("{} ({})", name, address), name);
+    }
+    let layout = LinearLayout::vertical()
+        .child(TextView::new("Account:"))
+        .child(account_view.with_name("multisig_account"))
+        .child(create_transaction_layout(core.display_unit(), None))
+        .child(TextView::new("Save PSBT to:"))
+        .child(EditView::new().content("multisig.psbt").with_name("psbt_path"));
+    s.add_layer(
+        Dialog::around(layout)
+            .title("Send from Multisig")
+            .button("Create", move |siv| create_multisig_psbt(siv, core.display_unit()))
+            .button("Cancel", |siv| {
+                siv.pop_layer();
+            }),
+    );
+}
+
+fn create_multisig_psbt(s: &mut Cursive, unit: DisplayUnit) {
+    let core = s
+        .user_data::<Arc<Core>>()
+        .expect("Core missing from user_data")
+        .clone();
+    let account = s
+        .call_on_name("multisig_account", |view: &mut SelectView<String>| view.selection())
+        .flatten();
+    let Some(account) = account else {
+        show_error_dialog(s, "No account selected");
+        return;
+    };
+    let recipient = s
+        .call_on_name("recipient", |view: &mut EditView| view.get_content())
+        .unwrap();
+    let amount = s
+        .call_on_name("amount", |view: &mut EditView| view.get_content())
+        .unwrap();
+    let path = s
+        .call_on_name("psbt_path", |view: &mut EditView| view.get_content())
+        .unwrap();
+    let Some(amount) = parse_amount(amount.as_str(), unit).filter(|amount| *amount > 0) else {
+        show_error_dialog(s, "Invalid amount");
+        return;
+    };
+
+    let psbt = core
+        .resolve_recipient_address(recipient.as_str())
+        .and_then(|address| core.create_multisig_transaction(&account, &address, amount));
+    let psbt = match psbt {
+        Ok(psbt) => psbt,
+        Err(e) => {
+            show_error_dialog(s, e);
+            return;
+        }
+    };
+    if let Err(e) = psbt.save_to_file(path.as_str()) {
+        show_error_dialog(s, format!("Failed to save {}: {}", path, e));
+        return;
+    }
+    s.pop_layer();
+    s.add_layer(Dialog::info(format!(
+        "Saved to {}\n\nHave the cosigners sign it with tx_sign, then import their \
+         copies with Multisig > Import signatures",
+        path
+    )));
+}
+
+/// Merge a cosigner's signed copy into a PSBT and broadcast it once
+/// it has enough signatures.
+fn show_import_signatures_dialog(s: &mut Cursive) {
+    let layout = LinearLayout::vertical()
+        .child(TextView::new("PSBT file:"))
+        .child(EditView::new().content("multisig.psbt").with_name("psbt_path"))
+        .child(TextView::new("Copy signed by a cosigner:"))
+        .child(with_paste(EditView::new().with_name("signed_path")));
+    s.add_layer(
+        Dialog::around(layout)
+            .title("Import Signatures")
+            .button("Import", import_signatures)
+            .button("Cancel", |siv| {
+                siv.pop_layer();
+            }),
+    );
+}
+
+fn import_signatures(s: &mut Cursive) {
+    let path = s
+        .call_on_name("psbt_path", |view: &mut EditView| view.get_content())
+        .unwrap();
+    let signed_path = s
+        .call_on_name("signed_path", |view: &mut EditView| view.get_content())
+        .unwrap();
+    let merged = PartiallySignedTransaction::load_from_file(path.as_str())
+        .map_err(anyhow::Error::from)
+        .and_then(|mut psbt| {
+            let signed = PartiallySignedTransaction::load_from_file(signed_path.as_str())?;
+            psbt.merge(signed)?;
+            psbt.save_to_file(path.as_str())?;
+            Ok(psbt)
+        });
+    let psbt = match merged {
+        Ok(psbt) => psbt,
+        Err(e) => {
+            show_error_dialog(s, format!("Failed to import signatures: {}", e));
+            return;
+        }
+    };
+    s.pop_layer();
+
+    if !psbt.is_complete() {
+        s.add_layer(Dialog::info(format!(
+            "Signatures imported into {}\n\nMore cosigners still have to sign",
+            path
+        )));
+        return;
+    }
+    let core = s
+        .user_data::<Arc<Core>>()
+        .expect("Core missing from user_data")
+        .clone();
+    let amount = psbt
+        .unsigned
+        .outputs
+        .first()
+        .map(|output| format_amount(output.value, core.display_unit()))
+        .unwrap_or_default();
+    let path = PathBuf::from(path.as_str());
+    s.add_layer(
+        Dialog::text(format!("{} is fully signed", path.display()))
+            .title("Ready to Broadcast")
+            .button("Broadcast", move |siv| {
+                let result = tokio::task::block_in_place(|| {
+                    tokio::runtime::Handle::current()
+                        .block_on(core.broadcast_signed(std::slice::from_ref(&path)))
+                });
+                match result {
+                    // the sent dialog closes this one along with itself
+                    Ok(txid) => show_transaction_sent_dialog(siv, amount.clone(), txid.to_string()),
+                    Err(e) => show_error_dialog(siv, e),
+                }
+            })
+            .button("Later", |siv| {
+                siv.pop_layer();
+            }),
+    );
+}
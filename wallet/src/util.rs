@@ -1,7 +1,15 @@
-use crate::core::{Config, Core, FeeConfig, FeeType, Recipient};
+use crate::core::{
+    Config, Core, DisplayUnit, FeeConfig, FeeType, NotificationConfig, PriceSource, Recipient,
+    UiConfig,
+};
+use crate::signer::SignerConfig;
 use anyhow::Result;
+use btclib::address::AddressFormat;
+use btclib::params::Network;
+use btclib::amount::{format_btc, format_sats};
 use std::panic;
-use std::path::PathBuf;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use tracing::*;
 use tracing_subscriber::{EnvFilter, fmt, prelude::*};
 use tracing_appender::{rolling, non_blocking};
@@ -64,38 +72,63 @@ pub fn setup_panic_hook() {
     }));
 }
 
-pub fn generate_dummy_config(path: &PathBuf) -> Result<()> {
+pub fn generate_dummy_config(path: &Path) -> Result<()> {
     let dummy_config = Config {
         my_keys: vec![],
         contacts: vec![
-            Recipient {
-                name: "Alice".to_string(),
-                address: "18VvDB8FnwU4symRpFSjbFoDJFyzQyHWVV".to_string(),
-            },
-            Recipient {
-                name: "Bob".to_string(),
-                address: "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa".to_string(),
-            },
+            Recipient::new("Alice".to_string(), "18VvDB8FnwU4symRpFSjbFoDJFyzQyHWVV".to_string()),
+            Recipient::new("Bob".to_string(), "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa".to_string()),
         ],
         default_node: "127.0.0.1:9000".to_string(),
+        nodes: vec![],
         fee_config: FeeConfig {
             fee_type: FeeType::Percent,
             value: 0.1,
         },
+        notifications: NotificationConfig::default(),
+        display_unit: DisplayUnit::default(),
+        price_source: PriceSource::default(),
+        ui: UiConfig::default(),
+        address_format: AddressFormat::default(),
+        network: Network::default(),
+        signer: SignerConfig::default(),
+        multisig: vec![],
+        min_confirmations: None,
+        seed: None,
+        accounts: vec![],
     };
-    let config_str = toml::to_string_pretty(&dummy_config)?;
-    std::fs::write(path, config_str)?;
+    dummy_config.save(path)?;
     info!("Dummy config generated at: {}", path.display());
     Ok(())
 }
 
-/// Convert satoshis to a BTC string
-pub fn sats_to_btc(sats: u64) -> String {
-    let btc = sats as f64 / 100_000_000.0;
-    format!("{} BTC", btc)
+/// Write a file by writing a temporary sibling and renaming it over
+/// the target, so readers see either the old or the new content
+pub fn write_atomic(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+    let mut file = fs::File::create(&tmp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)
+}
+
+/// Format satoshis in the given unit
+pub fn format_amount(sats: u64, unit: DisplayUnit) -> String {
+    match unit {
+        DisplayUnit::Btc => format!("{} BTC", format_btc(sats)),
+        DisplayUnit::Sats => format!("{} sats", format_sats(sats)),
+    }
 }
 
 /// Make it big lmao
 pub fn big_mode_btc(core: &Core) -> String {
-    text_to_ascii_art::to_art(sats_to_btc(core.get_balance()), "big", 0, 1, 0).unwrap()
+    let sats = core.get_balance();
+    let balance = format_amount(sats, core.display_unit());
+    let art = text_to_ascii_art::to_art(balance, "big", 0, 1, 0).unwrap();
+    match core.config.read().unwrap().price_source.convert(sats) {
+        Some(value) => format!("{}\n≈ {}", art, value),
+        None => art,
+    }
 }
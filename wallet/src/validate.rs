@@ -0,0 +1,288 @@
+use crate::core::{Config, FeeType, probe_services};
+use crate::signer::SignerConfig;
+use crate::ui::build_theme;
+use anyhow::{Result, anyhow, bail};
+use btclib::bip32::ExtendedPrivateKey;
+use btclib::crypto::{PrivateKey, PublicKey};
+use btclib::network::Services;
+use btclib::util::Saveable;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use toml_edit::{ImDocument, Item, TableLike, Value};
+
+/// Something wrong with the config, with the line it is on when known
+pub struct Problem {
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl Problem {
+    fn new(message: impl Into<String>) -> Self {
+        Self {
+            line: None,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "line {}: {}", line, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+/// Turn a list of problems into one error naming the config file
+pub fn problems_error(path: &Path, problems: &[Problem]) -> anyhow::Error {
+    let list: Vec<String> = problems.iter().map(|problem| format!("  {}", problem)).collect();
+    anyhow!("Invalid config file {}:\n{}", path.display(), list.join("\n"))
+}
+
+// where a field sits in the config, as serde_ignored reports it
+enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+fn segments(path: &serde_ignored::Path, out: &mut Vec<Segment>) {
+    use serde_ignored::Path;
+    match path {
+        Path::Root => {}
+        Path::Seq { parent, index } => {
+            segments(parent, out);
+            out.push(Segment::Index(*index));
+        }
+        Path::Map { parent, key } => {
+            segments(parent, out);
+            out.push(Segment::Key(key.clone()));
+        }
+        Path::Some { parent } | Path::NewtypeStruct { parent } | Path::NewtypeVariant { parent } => {
+            segments(parent, out)
+        }
+    }
+}
+
+/// 1-based line of a byte offset
+fn line_at(text: &str, offset: usize) -> usize {
+    text.as_bytes()[..offset.min(text.len())]
+        .iter()
+        .filter(|byte| **byte == b'\n')
+        .count()
+        + 1
+}
+
+/// Line of the key at `path`
+fn key_line(text: &str, path: &[Segment]) -> Option<usize> {
+    let document = ImDocument::parse(text).ok()?;
+    let mut table: &dyn TableLike = document.as_table();
+    let mut segments = path.iter().peekable();
+    while let Some(segment) = segments.next() {
+        let Segment::Key(key) = segment else {
+            return None;
+        };
+        let (key, item) = table.get_key_value(key)?;
+        table = match segments.peek() {
+            None => return key.span().map(|span| line_at(text, span.start)),
+            Some(Segment::Index(index)) => {
+                segments.next();
+                match item {
+                    Item::ArrayOfTables(tables) => tables.get(*index)?,
+                    Item::Value(Value::Array(array)) => array.get(*index)?.as_inline_table()?,
+                    _ => return None,
+                }
+            }
+            Some(Segment::Key(_)) => item.as_table_like()?,
+        };
+    }
+    None
+}
+
+/// Parse a config, refusing unknown fields so typos don't go unnoticed
+pub fn parse(text: &str) -> Result<Config, Vec<Problem>> {
+    let mut unknown = vec![];
+    let parsed: Result<Config, _> = serde_ignored::deserialize(toml::Deserializer::new(text), |path| {
+        let mut found = vec![];
+        segments(&path, &mut found);
+        unknown.push((path.to_string(), found));
+    });
+    let mut problems: Vec<Problem> = unknown
+        .into_iter()
+        .map(|(name, path)| Problem {
+            line: key_line(text, &path),
+            message: format!("unknown field `{}`", name),
+        })
+        .collect();
+    match parsed {
+        Ok(config) if problems.is_empty() => Ok(config),
+        Ok(_) => Err(problems),
+        Err(e) => {
+            // a misspelt field is often also a missing one
+            problems.push(Problem {
+                line: e.span().map(|span| line_at(text, span.start)),
+                message: e.message().to_string(),
+            });
+            problems.sort_by_key(|problem| problem.line);
+            Err(problems)
+        }
+    }
+}
+
+/// Check what parsing can't: that the key files are there and match,
+/// and that addresses and numbers make sense
+pub fn check(config: &Config) -> Vec<Problem> {
+    let mut problems = vec![];
+    let params = config.network.params();
+    for (index, key) in config.my_keys.iter().enumerate() {
+        let name = format!("my_keys[{}] ({})", index, key.display_name());
+        let public = match PublicKey::load_from_file(&key.public) {
+            Ok(public) => Some(public),
+            Err(e) => {
+                problems.push(Problem::new(format!(
+                    "{}: can't load public key {}: {}",
+                    name,
+                    key.public.display(),
+                    e
+                )));
+                None
+            }
+        };
+        let Some(path) = &key.private else {
+            continue;
+        };
+        match PrivateKey::load_from_file(path) {
+            Ok(private) if public.as_ref().is_some_and(|public| *public != private.public_key()) => {
+                problems.push(Problem::new(format!(
+                    "{}: {} is not the private key of {}",
+                    name,
+                    path.display(),
+                    key.public.display()
+                )));
+            }
+            Ok(_) => {}
+            Err(e) => problems.push(Problem::new(format!(
+                "{}: can't load private key {}: {}",
+                name,
+                path.display(),
+                e
+            ))),
+        }
+    }
+
+    let mut names = HashSet::new();
+    for (index, contact) in config.contacts.iter().enumerate() {
+        let name = format!("contacts[{}] ({})", index, contact.name);
+        if !names.insert(contact.name.as_str()) {
+            problems.push(Problem::new(format!("{}: name used by another contact", name)));
+        }
+        for address in contact.addresses() {
+            match PublicKey::validate_address(address, &params) {
+                Ok(true) => {}
+                Ok(false) => problems.push(Problem::new(format!(
+                    "{}: {} is not a valid address",
+                    name, address
+                ))),
+                Err(e) => problems.push(Problem::new(format!("{}: {}", name, e))),
+            }
+        }
+    }
+
+    for node in std::iter::once(&config.default_node).chain(&config.nodes) {
+        let valid = node
+            .rsplit_once(':')
+            .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok());
+        if !valid {
+            problems.push(Problem::new(format!("node {}: expected host:port", node)));
+        }
+    }
+
+    let fee = &config.fee_config;
+    if !fee.value.is_finite() || fee.value < 0.0 {
+        problems.push(Problem::new("fee_config.value: must be a number of at least 0"));
+    } else if matches!(fee.fee_type, FeeType::Percent) && fee.value > 100.0 {
+        problems.push(Problem::new("fee_config.value: a percent fee can't be over 100"));
+    }
+
+    for account in &config.multisig {
+        if let Err(e) = account.policy() {
+            problems.push(Problem::new(format!("multisig {}: {:#}", account.name, e)));
+        }
+    }
+    let mut names = HashSet::new();
+    for (index, account) in config.accounts.iter().enumerate() {
+        if !names.insert(account.name.as_str()) {
+            problems.push(Problem::new(format!(
+                "accounts[{}] ({}): name used by another account",
+                index, account.name
+            )));
+        }
+    }
+    match &config.seed {
+        None if !config.accounts.is_empty() => {
+            problems.push(Problem::new("accounts: no seed to derive them from, see `wallet account init`"));
+        }
+        Some(path) => {
+            if let Err(e) = ExtendedPrivateKey::load_from_file(path) {
+                problems.push(Problem::new(format!("seed: can't load {}: {}", path.display(), e)));
+            }
+        }
+        None => {}
+    }
+    if let Err(e) = build_theme(&config.ui) {
+        problems.push(Problem::new(format!("ui.palette: {}", e)));
+    }
+    if let SignerConfig::Daemon { address } = &config.signer
+        && address.is_empty()
+    {
+        problems.push(Problem::new("signer.address: the signing daemon needs an address"));
+    }
+    problems
+}
+
+/// Run every check on the config at `path` and try the nodes
+pub async fn doctor(path: &Path) -> Result<()> {
+    let text = fs::read_to_string(path).map_err(|e| anyhow!("Can't read {}: {}", path.display(), e))?;
+    let config = match parse(&text) {
+        Ok(config) => config,
+        Err(problems) => return Err(problems_error(path, &problems)),
+    };
+    println!("Config {}: parsed", path.display());
+
+    let mut failures = 0;
+    let problems = check(&config);
+    if problems.is_empty() {
+        println!(
+            "Keys, contacts and settings: ok ({} keys, {} contacts)",
+            config.my_keys.len(),
+            config.contacts.len()
+        );
+    }
+    for problem in &problems {
+        println!("Problem: {}", problem);
+    }
+    failures += problems.len();
+
+    let mut serving = false;
+    for node in std::iter::once(&config.default_node).chain(&config.nodes) {
+        match probe_services(node).await {
+            Ok(services) => {
+                println!("Node {}: reachable, offers {}", node, services);
+                serving |= services.contains(Services::WALLET);
+            }
+            Err(e) => println!("Node {}: unreachable ({})", node, e),
+        }
+    }
+    if !serving {
+        println!("Problem: none of the configured nodes serves wallets");
+        failures += 1;
+    }
+
+    if failures > 0 {
+        bail!("{} problems found", failures);
+    }
+    println!("No problems found");
+    Ok(())
+}
@@ -0,0 +1,225 @@
+//! WebAssembly bindings for btclib, to build and sign transactions in
+//! the browser. Amounts are satoshis (BigInt in JavaScript) and hashes
+//! are hex strings.
+use btclib::address::Address;
+use btclib::crypto;
+use btclib::encoding::Encode;
+use btclib::params::{ChainParams, Network};
+use btclib::sha256::Hash;
+use btclib::types::{self, TransactionOutput, UnsignedInput, UnsignedTransaction};
+use btclib::util::{MerkleProof, Saveable};
+use uuid::Uuid;
+use wasm_bindgen::prelude::*;
+
+fn params(network: &str) -> Result<ChainParams, JsError> {
+    let network: Network = network.parse().map_err(|e: String| JsError::new(&e))?;
+    Ok(network.params())
+}
+
+fn parse_hash(hash: &str) -> Result<Hash, JsError> {
+    hash.parse()
+        .map_err(|_| JsError::new(&format!("{} is not a hash", hash)))
+}
+
+#[wasm_bindgen]
+pub struct PrivateKey(crypto::PrivateKey);
+
+#[wasm_bindgen]
+impl PrivateKey {
+    /// A new random key
+    pub fn generate() -> PrivateKey {
+        PrivateKey(crypto::PrivateKey::new_key())
+    }
+
+    #[wasm_bindgen(js_name = generateMnemonic)]
+    pub fn generate_mnemonic() -> String {
+        crypto::PrivateKey::generate_mnemonic()
+    }
+
+    /// The key `key_gen` derives from the same recovery phrase
+    #[wasm_bindgen(js_name = fromMnemonic)]
+    pub fn from_mnemonic(mnemonic: &str) -> Result<PrivateKey, JsError> {
+        crypto::PrivateKey::from_mnemonic(mnemonic)
+            .map(PrivateKey)
+            .map_err(|e| JsError::new(&e))
+    }
+
+    /// Load the CBOR form of the `.priv.cbor` key files
+    #[wasm_bindgen(js_name = fromCbor)]
+    pub fn from_cbor(bytes: &[u8]) -> Result<PrivateKey, JsError> {
+        crypto::PrivateKey::load(bytes)
+            .map(PrivateKey)
+            .map_err(|e| JsError::new(&e.to_string()))
+    }
+
+    #[wasm_bindgen(js_name = toCbor)]
+    pub fn to_cbor(&self) -> Result<Vec<u8>, JsError> {
+        let mut bytes = vec![];
+        self.0
+            .save(&mut bytes)
+            .map_err(|e| JsError::new(&e.to_string()))?;
+        Ok(bytes)
+    }
+
+    #[wasm_bindgen(js_name = publicKey)]
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(self.0.public_key())
+    }
+}
+
+#[wasm_bindgen]
+#[derive(Clone)]
+pub struct PublicKey(crypto::PublicKey);
+
+#[wasm_bindgen]
+impl PublicKey {
+    /// Load the PEM form of the `.pub.pem` key files
+    #[wasm_bindgen(js_name = fromPem)]
+    pub fn from_pem(pem: &str) -> Result<PublicKey, JsError> {
+        crypto::PublicKey::load(pem.as_bytes())
+            .map(PublicKey)
+            .map_err(|e| JsError::new(&e.to_string()))
+    }
+
+    #[wasm_bindgen(js_name = toPem)]
+    pub fn to_pem(&self) -> Result<String, JsError> {
+        let mut pem = vec![];
+        self.0
+            .save(&mut pem)
+            .map_err(|e| JsError::new(&e.to_string()))?;
+        String::from_utf8(pem).map_err(|e| JsError::new(&e.to_string()))
+    }
+
+    /// Base58 address of the key on `network`, "mainnet" or "testnet"
+    pub fn address(&self, network: &str) -> Result<String, JsError> {
+        Ok(self.0.to_address_for(&params(network)?))
+    }
+}
+
+/// Whether `address` is a well-formed address of `network`
+#[wasm_bindgen(js_name = isValidAddress)]
+pub fn is_valid_address(address: &str, network: &str) -> Result<bool, JsError> {
+    Ok(Address::parse_for(address, &params(network)?).is_ok())
+}
+
+/// Hash of an output, which inputs spending it refer to
+#[wasm_bindgen(js_name = outputHash)]
+pub fn output_hash(value: u64, unique_id: &str, address: &str) -> Result<String, JsError> {
+    let unique_id = Uuid::parse_str(unique_id).map_err(|e| JsError::new(&e.to_string()))?;
+    let output = TransactionOutput {
+        value,
+        unique_id,
+        address: address.to_string(),
+    };
+    Ok(output.hash().to_string())
+}
+
+/// Collects the inputs and outputs of a transaction before signing
+#[wasm_bindgen]
+pub struct TransactionBuilder {
+    params: ChainParams,
+    unsigned: UnsignedTransaction,
+}
+
+#[wasm_bindgen]
+impl TransactionBuilder {
+    #[wasm_bindgen(constructor)]
+    pub fn new(network: &str) -> Result<TransactionBuilder, JsError> {
+        Ok(TransactionBuilder {
+            params: params(network)?,
+            unsigned: UnsignedTransaction {
+                inputs: vec![],
+                outputs: vec![],
+            },
+        })
+    }
+
+    /// Spend the output with the given hash and value, owned by `owner`
+    #[wasm_bindgen(js_name = addInput)]
+    pub fn add_input(&mut self, output_hash: &str, value: u64, owner: &PublicKey) -> Result<(), JsError> {
+        self.unsigned.inputs.push(UnsignedInput {
+            prev_transaction_output_hash: parse_hash(output_hash)?,
+            public_key: owner.0.clone(),
+            value,
+            multisig: None,
+        });
+        Ok(())
+    }
+
+    /// Pay `value` to `address`, which must belong to the network
+    #[wasm_bindgen(js_name = addOutput)]
+    pub fn add_output(&mut self, address: &str, value: u64) -> Result<(), JsError> {
+        Address::parse_for(address, &self.params).map_err(|e| JsError::new(&e.to_string()))?;
+        self.unsigned.outputs.push(TransactionOutput {
+            value,
+            unique_id: Uuid::new_v4(),
+            address: address.to_string(),
+        });
+        Ok(())
+    }
+
+    /// What the inputs hold beyond the outputs, paid to the miner
+    pub fn fee(&self) -> Result<u64, JsError> {
+        self.unsigned
+            .input_value()
+            .checked_sub(self.unsigned.output_value())
+            .ok_or_else(|| JsError::new("outputs exceed inputs"))
+    }
+
+    /// Sign every input with the key owning it
+    pub fn sign(&self, keys: Vec<PrivateKey>) -> Result<Transaction, JsError> {
+        self.fee()?;
+        let keys: Vec<crypto::PrivateKey> = keys.into_iter().map(|key| key.0).collect();
+        self.unsigned
+            .sign(&keys)
+            .map(Transaction)
+            .map_err(|e| JsError::new(&e.to_string()))
+    }
+}
+
+/// A signed transaction, ready to submit to a node
+#[wasm_bindgen]
+pub struct Transaction(types::Transaction);
+
+#[wasm_bindgen]
+impl Transaction {
+    pub fn hash(&self) -> String {
+        self.0.hash().to_string()
+    }
+
+    /// Canonical encoding, as taken by the gRPC `SubmitTransaction`
+    #[wasm_bindgen(js_name = toBytes)]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.0.to_bytes()
+    }
+
+    /// Hashes of the outputs, in order
+    #[wasm_bindgen(js_name = outputHashes)]
+    pub fn output_hashes(&self) -> Vec<String> {
+        self.0
+            .outputs
+            .iter()
+            .map(|output| output.hash().to_string())
+            .collect()
+    }
+}
+
+/// Check that the transaction `txid` is at `index` in the block with
+/// the given merkle root, `branch` holding the sibling hashes from the
+/// bottom of the tree
+#[wasm_bindgen(js_name = verifyMerkleProof)]
+pub fn verify_merkle_proof(
+    txid: &str,
+    index: usize,
+    branch: Vec<String>,
+    merkle_root: &str,
+) -> Result<bool, JsError> {
+    let proof = MerkleProof {
+        index,
+        branch: branch
+            .iter()
+            .map(|hash| parse_hash(hash))
+            .collect::<Result<_, _>>()?,
+    };
+    Ok(proof.root(parse_hash(txid)?).hash() == parse_hash(merkle_root)?)
+}